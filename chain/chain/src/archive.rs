@@ -0,0 +1,141 @@
+//! Archive of the main chain blocks in a local directory, one borsh serialized block per file.
+//! Nodes can catch up from a downloaded archive before syncing the rest from the network.
+use std::collections::VecDeque;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use near_primitives::types::BlockIndex;
+
+use crate::error::Error;
+use crate::store::{ChainStore, ChainStoreAccess};
+use crate::types::Block;
+
+const BLOCK_FILE_EXTENSION: &str = "block";
+
+/// Name of the file of the block, zero padded so that files sort by height.
+fn block_file_name(height: BlockIndex) -> String {
+    format!("{:020}.{}", height, BLOCK_FILE_EXTENSION)
+}
+
+/// Writes blocks of the main chain at heights `from..=to` into the directory, creating it if
+/// needed. Heights without a block are skipped. Returns number of written blocks.
+pub fn export_blocks(
+    chain_store: &mut ChainStore,
+    from: BlockIndex,
+    to: BlockIndex,
+    dir: &Path,
+) -> Result<u64, Error> {
+    fs::create_dir_all(dir)?;
+    let mut num_blocks = 0;
+    for height in from..=to {
+        let hash = match chain_store.get_block_hash_by_height(height) {
+            Ok(hash) => hash,
+            Err(_) => continue,
+        };
+        let block = chain_store.get_block(&hash)?;
+        fs::write(dir.join(block_file_name(height)), block.try_to_vec()?)?;
+        num_blocks += 1;
+    }
+    Ok(num_blocks)
+}
+
+/// Blocks of the archive in the order of their heights, read from the disk one at a time.
+pub struct BlockArchive {
+    dir: PathBuf,
+    files: VecDeque<PathBuf>,
+}
+
+impl BlockArchive {
+    /// Lists block files of the directory. Other files are ignored.
+    pub fn open(dir: &Path) -> Result<Self, Error> {
+        let mut files = vec![];
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().map_or(false, |ext| ext == BLOCK_FILE_EXTENSION) {
+                files.push(path);
+            }
+        }
+        files.sort();
+        Ok(BlockArchive { dir: dir.to_path_buf(), files: files.into() })
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Number of blocks not read yet.
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+}
+
+impl Iterator for BlockArchive {
+    type Item = Result<Block, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let path = self.files.pop_front()?;
+        Some(fs::read(&path).and_then(|data| Block::try_from_slice(&data)).map_err(|err| {
+            format!("Failed to read block from {}: {}", path.display(), err).into()
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::{DateTime, Utc};
+    use tempdir::TempDir;
+
+    use near_crypto::{InMemorySigner, KeyType};
+    use near_store::test_utils::create_test_store;
+
+    use crate::test_utils::KeyValueRuntime;
+    use crate::{Chain, Provenance};
+
+    use super::*;
+
+    fn setup_chain(genesis_time: DateTime<Utc>) -> Chain {
+        let store = create_test_store();
+        let runtime = Arc::new(KeyValueRuntime::new(store.clone()));
+        Chain::new(store, runtime, genesis_time).unwrap()
+    }
+
+    #[test]
+    fn test_export_and_import_blocks() {
+        let dir = TempDir::new("archive").unwrap();
+        let genesis_time = Utc::now();
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let mut chain = setup_chain(genesis_time);
+        for _ in 0..5 {
+            let prev = chain.head_header().unwrap();
+            let block = Block::empty(&prev, signer.clone());
+            chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+        }
+        let head = chain.head().unwrap();
+        assert_eq!(export_blocks(chain.mut_store(), 1, 10, dir.path()).unwrap(), 5);
+        fs::write(dir.path().join("README"), b"not a block").unwrap();
+
+        // Node with the same genesis processes the archived blocks.
+        let mut new_chain = setup_chain(genesis_time);
+        let archive = BlockArchive::open(dir.path()).unwrap();
+        assert_eq!(archive.len(), 5);
+        for block in archive {
+            new_chain.process_block(block.unwrap(), Provenance::SYNC, |_, _, _| {}).unwrap();
+        }
+        assert_eq!(new_chain.head().unwrap(), head);
+
+        // Corrupted files fail to read.
+        fs::write(dir.path().join(block_file_name(6)), b"garbage").unwrap();
+        let mut archive = BlockArchive::open(dir.path()).unwrap();
+        assert!(archive.by_ref().take(5).all(|block| block.is_ok()));
+        assert!(archive.next().unwrap().is_err());
+        assert!(archive.next().is_none());
+    }
+}
@@ -1,28 +1,43 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::{Duration as TimeDuration, Instant};
 
 use chrono::prelude::{DateTime, Utc};
 use chrono::Duration;
-use log::{debug, info};
+use log::{debug, error, info, warn};
+use rayon::prelude::*;
 
 use near_primitives::hash::CryptoHash;
+use near_primitives::merkle::{combine_hash, Direction, MerklePath};
 use near_primitives::receipt::Receipt;
-use near_primitives::transaction::{check_tx_history, TransactionResult};
-use near_primitives::types::{BlockIndex, MerkleHash, ShardId, ValidatorStake};
+use near_primitives::transaction::{check_tx_history, ReceiptOrigin, TransactionResult};
+use near_primitives::types::{
+    AccountId, BlockIndex, MerkleHash, ShardId, StatePart, ValidatorStake,
+};
 use near_store::Store;
 
 use crate::error::{Error, ErrorKind};
+use crate::metrics::DEEP_REORGS;
 use crate::store::{ChainStore, ChainStoreAccess, ChainStoreUpdate};
-use crate::types::{Block, BlockHeader, BlockStatus, Provenance, RuntimeAdapter, Tip};
+use crate::types::{
+    ApprovalSignature, Block, BlockApprovals, BlockHeader, BlockProfile, BlockStatus, ChainEvent,
+    GenesisIdentity, Provenance, RuntimeAdapter, Tip,
+};
 
 /// Maximum number of orphans chain can store.
 pub const MAX_ORPHAN_SIZE: usize = 1024;
 
+/// Number of the last processed blocks which processing time breakdown is kept.
+const MAX_BLOCK_PROFILES: usize = 100;
+
+/// Maximum number of invalid blocks which hashes are remembered to refuse them without validation.
+pub const MAX_BAD_BLOCKS: usize = 1024;
+
 /// Maximum age of orhpan to store in the chain.
 const MAX_ORPHAN_AGE_SECS: u64 = 300;
 
-/// Refuse blocks more than this many block intervals in the future (as in bitcoin).
+/// By default refuse blocks more than this many seconds in the future (as in bitcoin).
 const ACCEPTABLE_TIME_DIFFERENCE: i64 = 12 * 10;
 
 pub struct Orphan {
@@ -56,7 +71,8 @@ impl OrphanBlockPool {
         self.evicted
     }
 
-    fn add(&mut self, orphan: Orphan) {
+    /// Adds orphan to the pool, returns hashes of the orphans evicted to keep the pool bounded.
+    fn add(&mut self, orphan: Orphan) -> Vec<CryptoHash> {
         let height_hashes =
             self.height_idx.entry(orphan.block.header.inner.height).or_insert(vec![]);
         height_hashes.push(orphan.block.hash());
@@ -65,15 +81,22 @@ impl OrphanBlockPool {
         prev_hash_entries.push(orphan.block.hash());
         self.orphans.insert(orphan.block.hash(), orphan);
 
+        let mut removed_hashes: HashSet<CryptoHash> = HashSet::default();
         if self.orphans.len() > MAX_ORPHAN_SIZE {
             let old_len = self.orphans.len();
 
-            self.orphans.retain(|_, ref mut x| {
-                x.added.elapsed() < TimeDuration::from_secs(MAX_ORPHAN_AGE_SECS)
-            });
+            let expired = self
+                .orphans
+                .iter()
+                .filter(|(_, x)| x.added.elapsed() >= TimeDuration::from_secs(MAX_ORPHAN_AGE_SECS))
+                .map(|(h, _)| *h)
+                .collect::<Vec<_>>();
+            for h in expired {
+                let _ = self.orphans.remove(&h);
+                removed_hashes.insert(h);
+            }
             let mut heights = self.height_idx.keys().cloned().collect::<Vec<u64>>();
             heights.sort_unstable();
-            let mut removed_hashes: HashSet<CryptoHash> = HashSet::default();
             for h in heights.iter().rev() {
                 if let Some(hash) = self.height_idx.remove(h) {
                     for h in hash {
@@ -91,12 +114,17 @@ impl OrphanBlockPool {
 
             self.evicted += old_len - self.orphans.len();
         }
+        removed_hashes.into_iter().collect()
     }
 
     pub fn contains(&self, hash: &CryptoHash) -> bool {
         self.orphans.contains_key(hash)
     }
 
+    pub fn get(&self, hash: &CryptoHash) -> Option<&Orphan> {
+        self.orphans.get(hash)
+    }
+
     pub fn remove_by_prev_hash(&mut self, prev_hash: CryptoHash) -> Option<Vec<Orphan>> {
         let mut removed_hashes: HashSet<CryptoHash> = HashSet::default();
         let ret = self.prev_hash_idx.remove(&prev_hash).map(|hs| {
@@ -113,14 +141,52 @@ impl OrphanBlockPool {
     }
 }
 
+/// Checks that the header is signed by given validator.
+fn verify_header_signature(
+    runtime_adapter: &dyn RuntimeAdapter,
+    validator: &AccountId,
+    header: &BlockHeader,
+) -> Result<(), Error> {
+    if runtime_adapter.check_validator_signature(
+        &header.inner.epoch_hash,
+        validator,
+        header.hash().as_ref(),
+        &header.signature,
+    ) {
+        Ok(())
+    } else {
+        Err(ErrorKind::InvalidSignature.into())
+    }
+}
+
 /// Facade to the blockchain block processing and storage.
 /// Provides current view on the state according to the chain state.
 pub struct Chain {
     store: ChainStore,
     runtime_adapter: Arc<dyn RuntimeAdapter>,
     orphans: OrphanBlockPool,
+    /// Blocks which previous header is known, waiting for the state sync to finish.
+    blocks_pending_state: OrphanBlockPool,
+    /// Block the state is being synced for, if state sync is in progress.
+    state_sync_hash: Option<CryptoHash>,
     genesis: BlockHeader,
+    /// Number of blocks a transaction is valid for, as given by the runtime's protocol config.
     transaction_validity_period: BlockIndex,
+    /// Blocks with timestamp further than this in the future are refused.
+    max_future_time: Duration,
+    /// Reorgs reverting more than this number of blocks are refused, unlimited if not set.
+    max_reorg_depth: Option<BlockIndex>,
+    /// Number of blocks behind the final head to keep, older ones are garbage collected.
+    /// Nothing is collected if not set.
+    gc_blocks_to_keep: Option<BlockIndex>,
+    /// Callbacks notified about the changes of the chain.
+    event_listeners: Vec<Box<dyn FnMut(&ChainEvent) + Send>>,
+    /// Processing time breakdown of the last accepted blocks, oldest first.
+    block_profiles: VecDeque<BlockProfile>,
+    /// Shards which state is maintained by this node, all the shards if empty.
+    tracked_shards: Vec<ShardId>,
+    /// Hashes of the blocks known to be invalid with the order they were added in, oldest first.
+    bad_blocks: VecDeque<(u64, CryptoHash)>,
 }
 
 impl Chain {
@@ -128,9 +194,18 @@ impl Chain {
         store: Arc<Store>,
         runtime_adapter: Arc<dyn RuntimeAdapter>,
         genesis_time: DateTime<Utc>,
-        transaction_validity_period: BlockIndex,
     ) -> Result<Chain, Error> {
         let mut store = ChainStore::new(store);
+        let protocol_config = runtime_adapter.get_protocol_config();
+        let transaction_validity_period = protocol_config.transaction_validity_period;
+
+        // Check that the store was initialized for the same network, or record it on the first
+        // start. Stores of the older versions get the network recorded as well.
+        let genesis_identity = GenesisIdentity::from(&protocol_config);
+        let stored_identity = store.get_genesis_identity()?;
+        if let Some(stored_identity) = &stored_identity {
+            stored_identity.check(&genesis_identity)?;
+        }
 
         // Get runtime initial state and create genesis block out of it.
         let (state_store_update, state_roots) = runtime_adapter.genesis_state();
@@ -138,6 +213,9 @@ impl Chain {
 
         // Check if we have a head in the store, otherwise pick genesis block.
         let mut store_update = store.store_update();
+        if stored_identity.is_none() {
+            store_update.save_genesis_identity(genesis_identity);
+        }
         let head_res = store_update.head();
         let head: Tip;
         match head_res {
@@ -185,12 +263,28 @@ impl Chain {
                     );
                     store_update.save_post_validator_proposals(&genesis.hash(), vec![]);
                     store_update.save_block_header(genesis.header.clone());
+                    store_update.save_block_merkle_tree(&genesis.header)?;
                     store_update.save_block(genesis.clone());
-                    store_update.save_receipt(&genesis.header.hash(), vec![]);
+                    for shard_id in 0..runtime_adapter.num_shards() {
+                        store_update.save_receipt(&genesis.hash(), shard_id, vec![]);
+                    }
 
                     head = Tip::from_header(&genesis.header);
-                    store_update.save_head(&head)?;
-                    store_update.save_sync_head(&head);
+                    store_update.save_body_head(&head);
+                    match store_update.header_head() {
+                        // Header chain was kept on data reset, sync blocks on top of it.
+                        Ok(header_head)
+                            if store_update
+                                .get_block_header(&header_head.last_block_hash)
+                                .is_ok() =>
+                        {
+                            store_update.save_sync_head(&header_head);
+                        }
+                        _ => {
+                            store_update.save_header_head(&head)?;
+                            store_update.save_sync_head(&head);
+                        }
+                    }
 
                     store_update.merge(state_store_update);
 
@@ -203,13 +297,129 @@ impl Chain {
 
         info!(target: "chain", "Init: head: {} @ {} [{}]", head.total_weight.to_num(), head.height, head.last_block_hash);
 
-        Ok(Chain {
+        let mut chain = Chain {
             store,
             runtime_adapter,
             orphans: OrphanBlockPool::new(),
+            blocks_pending_state: OrphanBlockPool::new(),
+            state_sync_hash: None,
             genesis: genesis.header,
             transaction_validity_period,
-        })
+            max_future_time: Duration::seconds(ACCEPTABLE_TIME_DIFFERENCE),
+            max_reorg_depth: None,
+            gc_blocks_to_keep: None,
+            event_listeners: vec![],
+            block_profiles: VecDeque::with_capacity(MAX_BLOCK_PROFILES),
+            tracked_shards: vec![],
+            bad_blocks: VecDeque::new(),
+        };
+        chain.bad_blocks = chain.store.get_bad_blocks()?.into_iter().collect();
+        chain.init_flat_state();
+        chain.load_orphans()?;
+        Ok(chain)
+    }
+
+    /// Builds the flat state at the head, unless it's there already. The state is read from the
+    /// trie without it, so the failure is not fatal.
+    fn init_flat_state(&mut self) {
+        if let Err(err) = self.store.init_flat_state() {
+            warn!(target: "chain", "Failed to build flat state: {}", err);
+        }
+    }
+
+    /// Restores orphans saved before the restart and processes the ones which parent is known.
+    fn load_orphans(&mut self) -> Result<(), Error> {
+        let blocks = self.store.get_orphans()?;
+        if blocks.is_empty() {
+            return Ok(());
+        }
+        let mut store_update = self.store.store_update();
+        let mut prev_hashes = HashSet::new();
+        for block in blocks {
+            prev_hashes.insert(block.header.inner.prev_hash);
+            let orphan = Orphan { block, provenance: Provenance::NONE, added: Instant::now() };
+            for hash in self.orphans.add(orphan) {
+                store_update.delete_orphan(&hash);
+            }
+        }
+        store_update.commit()?;
+        info!(target: "chain", "Init: loaded {} orphans", self.orphans.len());
+
+        for prev_hash in prev_hashes {
+            if self.store.block_exists(&prev_hash)? {
+                self.check_orphans(prev_hash, |_, _, _| {});
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets how far in the future block timestamps are accepted, usually derived from the
+    /// expected block production time.
+    pub fn set_max_future_time(&mut self, max_future_time: TimeDuration) {
+        self.max_future_time =
+            Duration::from_std(max_future_time).expect("Max future time is out of range");
+    }
+
+    /// Sets the maximum number of blocks a reorg is allowed to revert, `None` for unlimited.
+    pub fn set_max_reorg_depth(&mut self, max_reorg_depth: Option<BlockIndex>) {
+        self.max_reorg_depth = max_reorg_depth;
+    }
+
+    /// Sets the number of epochs of blocks and state to keep behind the final head, `None` to
+    /// keep everything. Blocks further behind the head than the maximum reorg depth are final.
+    /// If reorgs are unlimited, the kept epochs are counted from the head, and reorgs can only
+    /// revert the blocks which are kept.
+    pub fn set_gc_num_epochs_to_keep(&mut self, gc_num_epochs_to_keep: Option<u64>) {
+        let epoch_length = self.runtime_adapter.get_protocol_config().epoch_length;
+        self.gc_blocks_to_keep = gc_num_epochs_to_keep.map(|num_epochs| num_epochs * epoch_length);
+    }
+
+    /// Sets the shards which state this node maintains, empty to maintain all of them.
+    /// State of all the shards is kept under the single state root of the block, so blocks are
+    /// applied if any of the shards is tracked. Otherwise only headers and transactions are kept.
+    /// Fails if the state of some of the shards is missing, because the state sync only
+    /// downloaded the shards tracked at the time.
+    pub fn set_tracked_shards(&mut self, tracked_shards: Vec<ShardId>) -> Result<(), Error> {
+        if let Some(synced_shards) = self.store.get_synced_shards()? {
+            let missing: Vec<_> = (0..self.runtime_adapter.num_shards())
+                .filter(|shard_id| tracked_shards.is_empty() || tracked_shards.contains(shard_id))
+                .filter(|shard_id| !synced_shards.contains(shard_id))
+                .collect();
+            if !missing.is_empty() {
+                return Err(ErrorKind::Other(format!(
+                    "State of shards {:?} is missing, the state was synced for shards {:?} only",
+                    missing, synced_shards
+                ))
+                .into());
+            }
+        }
+        self.tracked_shards = tracked_shards;
+        Ok(())
+    }
+
+    /// Returns true if this node maintains the state of given shard.
+    pub fn cares_about_shard(&self, shard_id: ShardId) -> bool {
+        self.tracked_shards.is_empty() || self.tracked_shards.contains(&shard_id)
+    }
+
+    /// Ids of the shards which state this node maintains.
+    pub fn tracked_shard_ids(&self) -> Vec<ShardId> {
+        (0..self.runtime_adapter.num_shards())
+            .filter(|shard_id| self.cares_about_shard(*shard_id))
+            .collect()
+    }
+
+    /// Returns true if blocks are applied to the state, i.e. any of the shards is tracked.
+    fn applies_blocks(&self) -> bool {
+        !self.tracked_shard_ids().is_empty()
+    }
+
+    /// Registers callback called with every event of the chain, after the change is committed.
+    pub fn subscribe_events<F>(&mut self, listener: F)
+    where
+        F: FnMut(&ChainEvent) + Send + 'static,
+    {
+        self.event_listeners.push(Box::new(listener));
     }
 
     /// Reset "sync" head to current header head.
@@ -224,12 +434,19 @@ impl Chain {
 
     /// Process a block header received during "header first" propagation.
     pub fn process_block_header(&mut self, header: &BlockHeader) -> Result<(), Error> {
+        if self.is_bad_block(&header.hash()) {
+            return Err(ErrorKind::KnownBadBlock.into());
+        }
         // We create new chain update, but it's not going to be committed so it's read only.
+        let apply_blocks = self.applies_blocks();
         let mut chain_update = ChainUpdate::new(
             &mut self.store,
             self.runtime_adapter.clone(),
             &self.orphans,
             self.transaction_validity_period,
+            self.max_future_time,
+            self.max_reorg_depth,
+            apply_blocks,
         );
         chain_update.process_block_header(header)?;
         Ok(())
@@ -258,11 +475,15 @@ impl Chain {
 
     /// Processes headers and adds them to store for syncing.
     pub fn sync_block_headers(&mut self, headers: Vec<BlockHeader>) -> Result<(), Error> {
+        let apply_blocks = self.applies_blocks();
         let mut chain_update = ChainUpdate::new(
             &mut self.store,
             self.runtime_adapter.clone(),
             &self.orphans,
             self.transaction_validity_period,
+            self.max_future_time,
+            self.max_reorg_depth,
+            apply_blocks,
         );
         chain_update.sync_block_headers(headers)?;
         chain_update.commit()
@@ -353,34 +574,90 @@ impl Chain {
     where
         F: FnMut(&Block, BlockStatus, Provenance) -> (),
     {
+        if self.is_bad_block(&block.hash()) {
+            return Err(ErrorKind::KnownBadBlock.into());
+        }
         let prev_head = self.store.head()?;
+        let apply_blocks = self.applies_blocks();
         let mut chain_update = ChainUpdate::new(
             &mut self.store,
             self.runtime_adapter.clone(),
             &self.orphans,
             self.transaction_validity_period,
+            self.max_future_time,
+            self.max_reorg_depth,
+            apply_blocks,
         );
+        let start = Instant::now();
         let maybe_new_head = chain_update.process_block(&block, &provenance);
+        let events = std::mem::replace(&mut chain_update.events, vec![]);
+        let mut profile = std::mem::replace(&mut chain_update.profile, BlockProfile::default());
 
         if let Ok(_) = maybe_new_head {
-            chain_update.commit()?;
+            if let Some(gc_blocks_to_keep) = self.gc_blocks_to_keep {
+                let final_height = chain_update
+                    .chain_store_update
+                    .head()?
+                    .height
+                    .saturating_sub(self.max_reorg_depth.unwrap_or(0));
+                chain_update
+                    .chain_store_update
+                    .clear_old_data(final_height.saturating_sub(gc_blocks_to_keep));
+            }
+            let (trie_commit, store_commit) = chain_update.chain_store_update.commit_timed()?;
+            profile.trie_commit = trie_commit;
+            profile.store_commit = store_commit;
         }
 
         match maybe_new_head {
             Ok(head) => {
                 let status = self.determine_status(head.clone(), prev_head);
 
+                for event in events.iter() {
+                    if let ChainEvent::Reorg { common_ancestor, abandoned, canonical } = event {
+                        info!(target: "chain", "Reorg to {} at {}: common ancestor {}, {} blocks abandoned, {} blocks canonical", block.hash(), block.header.inner.height, common_ancestor, abandoned.len(), canonical.len());
+                    }
+                    for listener in self.event_listeners.iter_mut() {
+                        listener(event);
+                    }
+                }
+
+                profile.hash = block.hash();
+                profile.height = block.header.inner.height;
+                profile.total = start.elapsed();
+                if self.block_profiles.len() == MAX_BLOCK_PROFILES {
+                    self.block_profiles.pop_front();
+                }
+                self.block_profiles.push_back(profile);
+
                 // Notify other parts of the system of the update.
                 block_accepted(&block, status, provenance);
 
                 Ok(head)
             }
             Err(e) => match e.kind() {
+                ErrorKind::Orphan
+                    if self.state_sync_hash.is_some()
+                        && self.store.get_block_header(&block.header.inner.prev_hash).is_ok() =>
+                {
+                    let block_hash = block.hash();
+                    if !self.blocks_pending_state.contains(&block_hash) {
+                        let orphan = Orphan { block, provenance, added: Instant::now() };
+                        self.blocks_pending_state.add(orphan);
+                    }
+                    debug!(target: "chain", "Process block: {:?} is waiting for state sync, # pending {}", block_hash, self.blocks_pending_state.len());
+                    Err(ErrorKind::BlockPendingState.into())
+                }
                 ErrorKind::Orphan => {
                     let block_hash = block.hash();
+                    let mut store_update = self.store.store_update();
+                    store_update.save_orphan(block.clone());
                     let orphan = Orphan { block, provenance, added: Instant::now() };
 
-                    self.orphans.add(orphan);
+                    for hash in self.orphans.add(orphan) {
+                        store_update.delete_orphan(&hash);
+                    }
+                    store_update.commit()?;
 
                     debug!(
                         target: "chain",
@@ -405,11 +682,41 @@ impl Chain {
                     );
                     Err(ErrorKind::Unfit(msg.clone()).into())
                 }
+                _ if e.is_bad_data() => {
+                    if e.is_invalid_block() {
+                        self.save_bad_block(block.hash())?;
+                    }
+                    Err(e)
+                }
                 _ => Err(ErrorKind::Other(format!("{:?}", e)).into()),
             },
         }
     }
 
+    /// Returns true if the block with given hash is known to be invalid.
+    pub fn is_bad_block(&self, hash: &CryptoHash) -> bool {
+        self.bad_blocks.iter().any(|(_, bad_hash)| bad_hash == hash)
+    }
+
+    /// Remembers the block as invalid, forgetting the oldest one if there are too many of them.
+    fn save_bad_block(&mut self, hash: CryptoHash) -> Result<(), Error> {
+        if self.is_bad_block(&hash) {
+            return Ok(());
+        }
+        let index = self.bad_blocks.back().map_or(0, |(index, _)| index + 1);
+        let mut store_update = self.store.store_update();
+        if self.bad_blocks.len() == MAX_BAD_BLOCKS {
+            if let Some((_, oldest)) = self.bad_blocks.pop_front() {
+                store_update.delete_bad_block(&oldest);
+            }
+        }
+        store_update.save_bad_block(&hash, index);
+        store_update.commit()?;
+        self.bad_blocks.push_back((index, hash));
+        warn!(target: "chain", "Block {} is invalid, # known bad blocks {}", hash, self.bad_blocks.len());
+        Ok(())
+    }
+
     /// Check for orphans, once a block is successfully added.
     pub fn check_orphans<F>(&mut self, prev_hash: CryptoHash, block_accepted: F) -> Option<Tip>
     where
@@ -425,6 +732,13 @@ impl Chain {
         while queue_idx < queue.len() {
             if let Some(orphans) = self.orphans.remove_by_prev_hash(queue[queue_idx]) {
                 debug!(target: "chain", "Check orphans: found {} orphans", orphans.len());
+                let mut store_update = self.store.store_update();
+                for orphan in orphans.iter() {
+                    store_update.delete_orphan(&orphan.block.hash());
+                }
+                if let Err(err) = store_update.commit() {
+                    error!(target: "chain", "Failed to remove processed orphans from the store: {}", err);
+                }
                 for orphan in orphans.into_iter() {
                     let block_hash = orphan.block.hash();
                     let res =
@@ -455,6 +769,119 @@ impl Chain {
         maybe_new_head
     }
 
+    /// Checks that the state part is proven against the state root the block at `hash` builds on.
+    pub fn validate_state_part(
+        &mut self,
+        shard_id: ShardId,
+        hash: CryptoHash,
+        part: &StatePart,
+    ) -> Result<(), Error> {
+        let state_root = self.get_block_header(&hash)?.inner.prev_state_root;
+        self.runtime_adapter
+            .validate_state_part(shard_id, state_root, part)
+            .map_err(|err| ErrorKind::InvalidStatePayload(err.to_string()).into())
+    }
+
+    /// Rolls head, header head and sync head back to the block of the main chain at given height,
+    /// removing the newer blocks and their state. Used to recover from a corrupted tip without
+    /// syncing the chain from scratch.
+    pub fn reset_to_height(&mut self, height: BlockIndex) -> Result<Tip, Error> {
+        let (tip, removed) = self.store.reset_to_height(height)?;
+        info!(target: "chain", "Reset chain to #{} {}, removed {} blocks", tip.height, tip.last_block_hash, removed.len());
+        Ok(tip)
+    }
+
+    /// Tips of the known forks at most `num_heights` below the head with their total weights,
+    /// starting from the heaviest one.
+    pub fn fork_tree(&mut self, num_heights: BlockIndex) -> Result<Vec<Tip>, Error> {
+        self.store.get_fork_tips(num_heights)
+    }
+
+    /// Marks the start of the state sync for given block. Until the sync is finished, blocks which
+    /// previous header is known are kept aside instead of the orphan pool.
+    pub fn start_state_sync(&mut self, sync_hash: CryptoHash) -> Result<(), Error> {
+        let mut store_update = self.store.store_update();
+        store_update.save_state_sync_hash(&sync_hash)?;
+        store_update.commit()?;
+        self.state_sync_hash = Some(sync_hash);
+        Ok(())
+    }
+
+    /// Block the state was being synced for when the node stopped, so that the sync can resume
+    /// with the already downloaded state parts.
+    pub fn get_state_sync_hash(&self) -> Result<Option<CryptoHash>, Error> {
+        self.store.get_state_sync_hash()
+    }
+
+    /// Saves validated state part of the shard, so that it's not downloaded again after restart.
+    pub fn save_state_part(
+        &mut self,
+        sync_hash: &CryptoHash,
+        shard_id: ShardId,
+        part: &StatePart,
+        receipts: &Vec<Receipt>,
+    ) -> Result<(), Error> {
+        let mut store_update = self.store.store_update();
+        store_update.save_state_part(sync_hash, shard_id, part, receipts)?;
+        store_update.commit()
+    }
+
+    /// Removes saved state parts that turned out to be wrong, so that they are downloaded again.
+    /// Without `part_ids` all the parts of the shard are removed together with the receipts.
+    pub fn delete_state_parts(
+        &mut self,
+        sync_hash: &CryptoHash,
+        shard_id: ShardId,
+        part_ids: Option<&[u64]>,
+    ) -> Result<(), Error> {
+        let mut store_update = self.store.store_update();
+        match part_ids {
+            Some(part_ids) => store_update.delete_state_parts(sync_hash, shard_id, part_ids),
+            None => store_update.clear_shard_state_parts(sync_hash, shard_id),
+        }
+        store_update.commit()
+    }
+
+    /// Returns state parts of the shard saved for the state sync at given block and the receipts.
+    pub fn get_state_parts(
+        &self,
+        sync_hash: &CryptoHash,
+        shard_id: ShardId,
+    ) -> Result<(Vec<StatePart>, Vec<Receipt>), Error> {
+        self.store.get_state_parts(sync_hash, shard_id)
+    }
+
+    /// Finishes the state sync once the state of all the shards is set: moves heads to the block
+    /// preceding the sync block and processes the blocks that were waiting for the state.
+    pub fn finish_state_sync<F>(&mut self, block_accepted: F) -> Result<Option<Tip>, Error>
+    where
+        F: Copy + FnMut(&Block, BlockStatus, Provenance) -> (),
+    {
+        let sync_hash = self
+            .state_sync_hash
+            .take()
+            .ok_or_else(|| ErrorKind::Other("State sync is not in progress".to_string()))?;
+        let prev_hash = self.get_block_header(&sync_hash)?.inner.prev_hash;
+        let tip = Tip::from_header(self.get_block_header(&prev_hash)?);
+        let mut store_update = self.store.store_update();
+        store_update.save_body_head(&tip);
+        store_update.save_body_tail(&tip);
+        store_update.save_synced_shards(&self.tracked_shard_ids())?;
+        store_update.clear_state_sync();
+        // Pending blocks become orphans, that are unrolled starting from the synced state.
+        let pending = std::mem::replace(&mut self.blocks_pending_state, OrphanBlockPool::new());
+        for (_, orphan) in pending.orphans.into_iter() {
+            store_update.save_orphan(orphan.block.clone());
+            for hash in self.orphans.add(orphan) {
+                store_update.delete_orphan(&hash);
+            }
+        }
+        store_update.commit()?;
+        // Changes of the blocks before the synced state are unknown, the flat state is rebuilt.
+        self.init_flat_state();
+        Ok(self.check_orphans(prev_hash, block_accepted))
+    }
+
     pub fn set_shard_state(
         &mut self,
         shard_id: ShardId,
@@ -474,7 +901,7 @@ impl Chain {
         // Update pointers to state root and receipts.
         let mut chain_store_update = self.store.store_update();
         chain_store_update.save_post_state_root(&prev_hash, &state_root);
-        chain_store_update.save_receipt(&prev_hash, receipts);
+        chain_store_update.save_receipt(&prev_hash, shard_id, receipts);
         chain_store_update.commit()?;
 
         Ok(())
@@ -551,10 +978,14 @@ impl Chain {
         self.store.get_post_state_root(hash)
     }
 
-    /// Get receipts stored for the given hash.
+    /// Get receipts the block with given hash sent to the shard.
     #[inline]
-    pub fn get_receipts(&mut self, hash: &CryptoHash) -> Result<&Vec<Receipt>, Error> {
-        self.store.get_receipts(hash)
+    pub fn get_receipts(
+        &mut self,
+        hash: &CryptoHash,
+        shard_id: ShardId,
+    ) -> Result<&Vec<Receipt>, Error> {
+        self.store.get_receipts(hash, shard_id)
     }
 
     /// Get transaction result for given hash of transaction.
@@ -566,6 +997,18 @@ impl Chain {
         self.store.get_transaction_result(hash)
     }
 
+    /// Get signatures of the block producers that approved the block with given hash.
+    #[inline]
+    pub fn get_block_approvals(&mut self, hash: &CryptoHash) -> Result<&BlockApprovals, Error> {
+        self.store.get_block_approvals(hash)
+    }
+
+    /// Get transaction that created receipt with given id.
+    #[inline]
+    pub fn get_receipt_origin(&mut self, receipt_id: &CryptoHash) -> Result<&ReceiptOrigin, Error> {
+        self.store.get_receipt_origin(receipt_id)
+    }
+
     #[inline]
     pub fn get_post_validator_proposals(
         &mut self,
@@ -574,6 +1017,89 @@ impl Chain {
         self.store.get_post_validator_proposals(hash)
     }
 
+    /// Returns root of the merkle accumulator of all blocks on the chain up to and including given block.
+    #[inline]
+    pub fn get_block_merkle_root(&mut self, hash: &CryptoHash) -> Result<MerkleHash, Error> {
+        Ok(self.store.get_block_merkle_tree(hash)?.root())
+    }
+
+    /// Returns merkle path of block `hash` in the merkle accumulator of block `head_hash`, which proves
+    /// that the first block is an ancestor of the second one. Both blocks must be on the main chain.
+    /// Proof can be checked with `verify_hash(get_block_merkle_root(head_hash), path, hash)`.
+    pub fn get_block_proof(
+        &mut self,
+        hash: &CryptoHash,
+        head_hash: &CryptoHash,
+    ) -> Result<MerklePath, Error> {
+        let leaf_index = self.store.get_block_merkle_tree(hash)?.size() - 1;
+        let merkle_tree = self.store.get_block_merkle_tree(head_hash)?.clone();
+        let size = merkle_tree.size();
+        if self.store.get_block_hash_from_ordinal(size - 1)? != *head_hash {
+            return Err(ErrorKind::Other(format!("Block {} is not on the main chain", head_hash))
+                .into());
+        }
+        if leaf_index >= size || self.store.get_block_hash_from_ordinal(leaf_index)? != *hash {
+            return Err(ErrorKind::Other(format!(
+                "Block {} is not an ancestor of {}",
+                hash, head_hash
+            ))
+            .into());
+        }
+
+        // Find complete subtree that contains the leaf. Subtrees correspond to set bits of the size.
+        let peaks = merkle_tree.path();
+        let mut peak_start = 0;
+        let mut peak_index = 0;
+        for level in (0..64).rev() {
+            let peak_size = 1u64 << level;
+            if size & peak_size == 0 {
+                continue;
+            }
+            if leaf_index >= peak_start + peak_size {
+                peak_start += peak_size;
+                peak_index += 1;
+                continue;
+            }
+            let mut path = vec![];
+            for l in 0..level {
+                let node_index = leaf_index >> l;
+                let sibling = self.get_block_merkle_node(node_index ^ 1, l)?;
+                let direction = if node_index % 2 == 0 { Direction::Right } else { Direction::Left };
+                path.push((sibling, direction));
+            }
+            if peak_index + 1 < peaks.len() {
+                let mut right = peaks[peaks.len() - 1];
+                for i in (peak_index + 1..peaks.len() - 1).rev() {
+                    right = combine_hash(peaks[i], right);
+                }
+                path.push((right, Direction::Right));
+            }
+            for i in (0..peak_index).rev() {
+                path.push((peaks[i], Direction::Left));
+            }
+            return Ok(path);
+        }
+        unreachable!("Leaf index is smaller than the size of the accumulator")
+    }
+
+    /// Returns root of the complete subtree of the main chain block accumulator at given level and index.
+    fn get_block_merkle_node(&mut self, index: u64, level: u64) -> Result<MerkleHash, Error> {
+        if level == 0 {
+            return self.store.get_block_hash_from_ordinal(index);
+        }
+        if (index + 1) % 2 == 1 {
+            // Accumulator right after the last leaf of this subtree has it as the smallest complete subtree.
+            let last_leaf = self.store.get_block_hash_from_ordinal(((index + 1) << level) - 1)?;
+            let merkle_tree = self.store.get_block_merkle_tree(&last_leaf)?;
+            Ok(*merkle_tree.path().last().expect("Accumulator is not empty"))
+        } else {
+            // Subtree was already merged into the bigger one, combine it from the children.
+            let left = self.get_block_merkle_node(index * 2, level - 1)?;
+            let right = self.get_block_merkle_node(index * 2 + 1, level - 1)?;
+            Ok(combine_hash(left, right))
+        }
+    }
+
     /// Returns underlying ChainStore.
     #[inline]
     pub fn store(&self) -> &ChainStore {
@@ -592,6 +1118,18 @@ impl Chain {
         &self.genesis
     }
 
+    /// Returns number of blocks for which a transaction is valid.
+    #[inline]
+    pub fn transaction_validity_period(&self) -> BlockIndex {
+        self.transaction_validity_period
+    }
+
+    /// Returns processing time breakdown of the last accepted blocks, oldest first.
+    #[inline]
+    pub fn block_profiles(&self) -> &VecDeque<BlockProfile> {
+        &self.block_profiles
+    }
+
     /// Returns number of orphans currently in the orphan pool.
     #[inline]
     pub fn orphans_len(&self) -> usize {
@@ -604,11 +1142,23 @@ impl Chain {
         self.orphans.len_evicted()
     }
 
+    /// Returns number of blocks waiting for the state sync.
+    #[inline]
+    pub fn blocks_pending_state_len(&self) -> usize {
+        self.blocks_pending_state.len()
+    }
+
     /// Check if hash is for a known orphan.
     #[inline]
     pub fn is_orphan(&self, hash: &CryptoHash) -> bool {
         self.orphans.contains(hash)
     }
+
+    /// Returns hash of the previous block of the known orphan.
+    #[inline]
+    pub fn orphan_prev_hash(&self, hash: &CryptoHash) -> Option<CryptoHash> {
+        self.orphans.get(hash).map(|orphan| orphan.block.header.inner.prev_hash)
+    }
 }
 
 /// Chain update helper, contains information that is needed to process block
@@ -620,6 +1170,14 @@ struct ChainUpdate<'a> {
     chain_store_update: ChainStoreUpdate<'a, ChainStore>,
     orphans: &'a OrphanBlockPool,
     transaction_validity_period: BlockIndex,
+    max_future_time: Duration,
+    max_reorg_depth: Option<BlockIndex>,
+    /// Whether blocks are applied to the state, false if none of the shards is tracked.
+    apply_blocks: bool,
+    /// Events to report once the update is committed.
+    events: Vec<ChainEvent>,
+    /// Time spent on the stages of processing the block, filled in as they complete.
+    profile: BlockProfile,
 }
 
 impl<'a> ChainUpdate<'a> {
@@ -628,9 +1186,22 @@ impl<'a> ChainUpdate<'a> {
         runtime_adapter: Arc<dyn RuntimeAdapter>,
         orphans: &'a OrphanBlockPool,
         transaction_validity_period: BlockIndex,
+        max_future_time: Duration,
+        max_reorg_depth: Option<BlockIndex>,
+        apply_blocks: bool,
     ) -> Self {
         let chain_store_update = store.store_update();
-        ChainUpdate { runtime_adapter, chain_store_update, orphans, transaction_validity_period }
+        ChainUpdate {
+            runtime_adapter,
+            chain_store_update,
+            orphans,
+            transaction_validity_period,
+            max_future_time,
+            max_reorg_depth,
+            apply_blocks,
+            events: vec![],
+            profile: BlockProfile::default(),
+        }
     }
 
     /// Commit changes to the chain into the database.
@@ -646,7 +1217,7 @@ impl<'a> ChainUpdate<'a> {
         debug!(target: "chain", "Process block header: {} at {}", header.hash(), header.inner.height);
 
         self.check_header_known(header)?;
-        self.validate_header(header, &Provenance::NONE)?;
+        self.validate_header(header, &Provenance::NONE, true)?;
         Ok(())
     }
 
@@ -675,7 +1246,9 @@ impl<'a> ChainUpdate<'a> {
         let is_next = block.header.inner.prev_hash == head.last_block_hash;
 
         // First real I/O expense.
+        let start = Instant::now();
         self.check_header_signature(&block.header)?;
+        self.profile.signature_check = start.elapsed();
         let prev = self.get_previous_header(&block.header)?;
         let prev_hash = prev.hash();
 
@@ -690,11 +1263,19 @@ impl<'a> ChainUpdate<'a> {
 
         // Check the header is valid before we proceed with the full block.
         self.process_header_for_block(&block.header, provenance)?;
+        self.save_block_approvals(&block.header)?;
 
         // Check that state root we computed from previous block matches recorded in this block.
-        let state_root = self.chain_store_update.get_post_state_root(&prev_hash)?;
-        if &block.header.inner.prev_state_root != state_root {
-            return Err(ErrorKind::InvalidStateRoot.into());
+        if self.apply_blocks {
+            let state_root = self.chain_store_update.get_post_state_root(&prev_hash)?;
+            if &block.header.inner.prev_state_root != state_root {
+                return Err(ErrorKind::InvalidStateRoot.into());
+            }
+        }
+
+        // Check that header commits to the transactions of the block.
+        if block.header.inner.tx_root != Block::compute_tx_root(&block.transactions) {
+            return Err(ErrorKind::InvalidTxRoot.into());
         }
 
         if block.transactions.iter().any(|t| {
@@ -711,10 +1292,33 @@ impl<'a> ChainUpdate<'a> {
             .into());
         }
 
-        // Retrieve receipts from the previous block.
-        let receipts = self.chain_store_update.get_receipts(&prev_hash)?.clone();
+        if !self.apply_blocks {
+            // State is not maintained, so validator proposals are taken from the header, as it's
+            // done during header sync.
+            self.runtime_adapter
+                .add_validator_proposals(
+                    block.header.inner.prev_hash,
+                    block.hash(),
+                    block.header.inner.height,
+                    block.header.inner.validator_proposals.clone(),
+                    vec![],
+                    vec![],
+                )
+                .map_err(|err| ErrorKind::Other(err.to_string()))?;
+            self.chain_store_update.save_block(block.clone());
+            return self.update_head(block);
+        }
+
+        // Retrieve receipts the previous block sent to each shard. State of all the shards is
+        // kept under the single state root of the block, so they are applied together.
+        let num_shards = self.runtime_adapter.num_shards();
+        let mut receipts = Vec::with_capacity(num_shards as usize);
+        for shard_id in 0..num_shards {
+            receipts.push(self.chain_store_update.get_receipts(&prev_hash, shard_id)?.clone());
+        }
 
         // Apply block to runtime.
+        let start = Instant::now();
         let (trie_changes, state_root, tx_results, new_receipts, validator_proposals) = self
             .runtime_adapter
             .apply_transactions(
@@ -723,10 +1327,11 @@ impl<'a> ChainUpdate<'a> {
                 block.header.inner.height,
                 &block.header.inner.prev_hash,
                 &block.header.hash(),
-                &vec![receipts], // TODO: currently only taking into account one shard.
+                &receipts,
                 &block.transactions,
             )
             .map_err(|e| ErrorKind::Other(e.to_string()))?;
+        self.profile.apply_transactions = start.elapsed();
 
         // Save state root after applying transactions.
         self.chain_store_update.save_post_state_root(&block.hash(), &state_root);
@@ -745,14 +1350,52 @@ impl<'a> ChainUpdate<'a> {
             )
             .map_err(|err| ErrorKind::Other(err.to_string()))?;
 
-        self.chain_store_update.save_trie_changes(trie_changes);
+        self.chain_store_update.save_trie_changes(
+            block.header.inner.height,
+            &block.hash(),
+            trie_changes,
+        );
 
-        // Save resulting receipts.
-        // TODO: currently only taking into account one shard.
-        self.chain_store_update
-            .save_receipt(&block.hash(), new_receipts.get(&0).unwrap_or(&vec![]).to_vec());
-        // Save receipt and transaction results.
+        // Route resulting receipts to the shards of their receivers and save them for every
+        // shard, keeping the order in which the runtime produced them.
+        let mut new_receipts: Vec<_> = new_receipts.into_iter().collect();
+        new_receipts.sort_by_key(|(shard_id, _)| *shard_id);
+        let mut receipts_by_shard: HashMap<ShardId, Vec<Receipt>> = HashMap::default();
+        for receipt in new_receipts.into_iter().flat_map(|(_, receipts)| receipts) {
+            let shard_id = self.runtime_adapter.account_id_to_shard_id(&receipt.receiver_id);
+            receipts_by_shard.entry(shard_id).or_insert_with(Vec::new).push(receipt);
+        }
+        for shard_id in 0..num_shards {
+            let receipts = receipts_by_shard.remove(&shard_id).unwrap_or_default();
+            self.chain_store_update.save_receipt(&block.hash(), shard_id, receipts);
+        }
+        // Save receipt and transaction results, and record originating transaction of every new receipt.
+        let tx_hashes: HashSet<CryptoHash> =
+            block.transactions.iter().map(|tx| tx.get_hash()).collect();
         for tx_result in tx_results.into_iter() {
+            if !tx_result.result.receipts.is_empty() {
+                let origin = if tx_hashes.contains(&tx_result.hash) {
+                    Some(ReceiptOrigin {
+                        transaction_hash: tx_result.hash,
+                        block_hash: block.hash(),
+                        block_index: block.header.inner.height,
+                    })
+                } else {
+                    match self.chain_store_update.get_receipt_origin(&tx_result.hash) {
+                        Ok(origin) => Some(origin.clone()),
+                        // Receipts stored before the origin index was introduced have no origin.
+                        Err(err) => match err.kind() {
+                            ErrorKind::DBNotFoundErr(_) => None,
+                            _ => return Err(err),
+                        },
+                    }
+                };
+                if let Some(origin) = origin {
+                    for receipt_id in tx_result.result.receipts.iter() {
+                        self.chain_store_update.save_receipt_origin(receipt_id, origin.clone());
+                    }
+                }
+            }
             self.chain_store_update.save_transaction_result(&tx_result.hash, tx_result.result);
         }
 
@@ -771,12 +1414,69 @@ impl<'a> ChainUpdate<'a> {
         header: &BlockHeader,
         provenance: &Provenance,
     ) -> Result<(), Error> {
-        self.validate_header(header, provenance)?;
+        // Signature is checked by `process_block` before looking for the previous block.
+        self.validate_header(header, provenance, false)?;
         self.chain_store_update.save_block_header(header.clone());
+        self.chain_store_update.save_block_merkle_tree(header)?;
         self.update_header_head(header)?;
         Ok(())
     }
 
+    /// Saves approvals of the previous block included in the header, resolving their positions in
+    /// the approval mask to the accounts of the block producers. Approvals which signature doesn't
+    /// match the key of the block producer in the epoch of the block are dropped. If several
+    /// children approve the block, the one with the most approvals is kept.
+    fn save_block_approvals(&mut self, header: &BlockHeader) -> Result<(), Error> {
+        if header.inner.approval_sigs.is_empty() {
+            return Ok(());
+        }
+        let prev_header = self.get_previous_header(header)?.clone();
+        let validators = self
+            .runtime_adapter
+            .get_epoch_block_proposers(&prev_header.inner.epoch_hash, &prev_header.hash())
+            .map_err(|err| ErrorKind::Other(err.to_string()))?;
+        let prev_hash = prev_header.hash();
+        let approvals: Vec<_> = header
+            .inner
+            .approval_mask
+            .iter()
+            .enumerate()
+            .filter(|(_, approved)| **approved)
+            .zip(header.inner.approval_sigs.iter())
+            .filter_map(|((position, _), signature)| {
+                validators.get(position).map(|(account_id, _)| ApprovalSignature {
+                    account_id: account_id.clone(),
+                    signature: signature.clone(),
+                })
+            })
+            .filter(|approval| {
+                let valid = self.runtime_adapter.check_validator_signature(
+                    &prev_header.inner.epoch_hash,
+                    &approval.account_id,
+                    prev_hash.as_ref(),
+                    &approval.signature,
+                );
+                if !valid {
+                    warn!(target: "chain", "Invalid approval of {} by {} in block {}", prev_hash, approval.account_id, header.hash());
+                }
+                valid
+            })
+            .collect();
+        if approvals.is_empty() {
+            return Ok(());
+        }
+        if let Ok(existing) = self.chain_store_update.get_block_approvals(&prev_hash) {
+            if existing.approvals.len() >= approvals.len() {
+                return Ok(());
+            }
+        }
+        self.chain_store_update.save_block_approvals(
+            &prev_hash,
+            BlockApprovals { child_hash: header.hash(), approvals },
+        );
+        Ok(())
+    }
+
     /// Process incoming block headers for syncing.
     fn sync_block_headers(&mut self, mut headers: Vec<BlockHeader>) -> Result<Option<Tip>, Error> {
         // Sort headers by heights if they are out of order.
@@ -796,10 +1496,29 @@ impl<'a> ChainUpdate<'a> {
         };
 
         if !all_known {
+            // Signature checks are pure CPU, verify them in parallel. Headers which block producer
+            // is only known once the preceding headers are added are checked during validation.
+            let runtime_adapter = &*self.runtime_adapter;
+            let signatures_checked = headers
+                .par_iter()
+                .map(|header| {
+                    match runtime_adapter
+                        .get_block_proposer(&header.inner.epoch_hash, header.inner.height)
+                    {
+                        Ok(validator) => {
+                            verify_header_signature(runtime_adapter, &validator, header)?;
+                            Ok(true)
+                        }
+                        Err(_) => Ok(false),
+                    }
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+
             // Validate header and then add to the chain. If validation of subsequent fails, headers won't be committed to the database.
-            for header in headers.iter() {
-                self.validate_header(header, &Provenance::SYNC)?;
+            for (header, signature_checked) in headers.iter().zip(signatures_checked) {
+                self.validate_header(header, &Provenance::SYNC, !signature_checked)?;
                 self.chain_store_update.save_block_header(header.clone());
+                self.chain_store_update.save_block_merkle_tree(header)?;
 
                 // Add validator proposals for given header.
                 self.runtime_adapter
@@ -830,30 +1549,26 @@ impl<'a> ChainUpdate<'a> {
             .runtime_adapter
             .get_block_proposer(&header.inner.epoch_hash, header.inner.height)
             .map_err(|e| Error::from(ErrorKind::Other(e.to_string())))?;
-        if self.runtime_adapter.check_validator_signature(
-            &header.inner.epoch_hash,
-            &validator,
-            header.hash().as_ref(),
-            &header.signature,
-        ) {
-            Ok(())
-        } else {
-            Err(ErrorKind::InvalidSignature.into())
-        }
+        verify_header_signature(&*self.runtime_adapter, &validator, header)
     }
 
+    /// Validates the header against its previous header, `check_signature` can be unset if the
+    /// signature was already verified.
     fn validate_header(
         &mut self,
         header: &BlockHeader,
         provenance: &Provenance,
+        check_signature: bool,
     ) -> Result<(), Error> {
         // Refuse blocks from the too distant future.
-        if header.timestamp() > Utc::now() + Duration::seconds(ACCEPTABLE_TIME_DIFFERENCE) {
+        if header.timestamp() > Utc::now() + self.max_future_time {
             return Err(ErrorKind::InvalidBlockFutureTime(header.timestamp()).into());
         }
 
         // First I/O cost, delay as much as possible.
-        self.check_header_signature(header)?;
+        if check_signature {
+            self.check_header_signature(header)?;
+        }
 
         let prev_header = self.get_previous_header(header)?;
 
@@ -901,6 +1616,11 @@ impl<'a> ChainUpdate<'a> {
         // when extending the head), update it
         let head = self.chain_store_update.head()?;
         if block.header.inner.total_weight > head.total_weight {
+            if block.header.inner.prev_hash != head.last_block_hash {
+                self.check_reorg_depth(block, &head)?;
+                let event = self.reorg_event(block, &head)?;
+                self.events.push(event);
+            }
             let tip = Tip::from_header(&block.header);
 
             self.chain_store_update.save_body_head(&tip);
@@ -911,6 +1631,68 @@ impl<'a> ChainUpdate<'a> {
         }
     }
 
+    /// Checks that switching the head to given block doesn't revert more than `max_reorg_depth`
+    /// blocks of the current chain.
+    fn check_reorg_depth(&mut self, block: &Block, head: &Tip) -> Result<(), Error> {
+        let max_reorg_depth = match self.max_reorg_depth {
+            Some(max_reorg_depth) => max_reorg_depth,
+            None => return Ok(()),
+        };
+        let mut hash = block.header.inner.prev_hash;
+        loop {
+            let header = self.chain_store_update.get_block_header(&hash)?;
+            let (height, prev_hash) = (header.inner.height, header.inner.prev_hash);
+            let is_on_chain = match self.chain_store_update.get_block_hash_by_height(height) {
+                Ok(chain_hash) => chain_hash == hash,
+                Err(_) => false,
+            };
+            let depth = head.height.saturating_sub(height);
+            if depth > max_reorg_depth {
+                error!(
+                    target: "chain",
+                    "Refusing reorg to block {} at {}: reverts {} blocks, maximum is {}. \
+                     Restart with --allow-deep-reorgs to follow this chain.",
+                    block.hash(),
+                    block.header.inner.height,
+                    depth,
+                    max_reorg_depth
+                );
+                DEEP_REORGS.fetch_add(1, Ordering::Relaxed);
+                return Err(ErrorKind::DeepReorg(depth, max_reorg_depth).into());
+            }
+            if is_on_chain {
+                return Ok(());
+            }
+            hash = prev_hash;
+        }
+    }
+
+    /// Describes switching the head from `head` to given block, must be called before the block
+    /// index is updated for the new head.
+    fn reorg_event(&mut self, block: &Block, head: &Tip) -> Result<ChainEvent, Error> {
+        let mut canonical = vec![block.hash()];
+        let mut hash = block.header.inner.prev_hash;
+        let common_ancestor_height = loop {
+            let header = self.chain_store_update.get_block_header(&hash)?;
+            let (height, prev_hash) = (header.inner.height, header.inner.prev_hash);
+            match self.chain_store_update.get_block_hash_by_height(height) {
+                Ok(chain_hash) if chain_hash == hash => break height,
+                _ => {}
+            }
+            canonical.push(hash);
+            hash = prev_hash;
+        };
+        canonical.reverse();
+        let mut abandoned = vec![];
+        for height in common_ancestor_height + 1..=head.height {
+            // Skipped heights have no block.
+            if let Ok(abandoned_hash) = self.chain_store_update.get_block_hash_by_height(height) {
+                abandoned.push(abandoned_hash);
+            }
+        }
+        Ok(ChainEvent::Reorg { common_ancestor: hash, abandoned, canonical })
+    }
+
     /// Updates "sync" head with given block header.
     fn update_sync_head(&mut self, header: &BlockHeader) -> Result<(), Error> {
         let tip = Tip::from_header(header);
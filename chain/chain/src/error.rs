@@ -4,6 +4,9 @@ use std::io;
 use chrono::{DateTime, Utc};
 use failure::{Backtrace, Context, Fail};
 
+use near_primitives::types::BlockIndex;
+use near_store::is_storage_corruption;
+
 #[derive(Debug)]
 pub struct Error {
     inner: Context<ErrorKind>,
@@ -17,6 +20,9 @@ pub enum ErrorKind {
     /// Orphan block.
     #[fail(display = "Orphan")]
     Orphan,
+    /// Block can't be applied until the state sync is finished.
+    #[fail(display = "Block Pending State")]
+    BlockPendingState,
     /// Peer abusively sending us an old block we already have
     #[fail(display = "Old Block")]
     OldBlock,
@@ -41,6 +47,9 @@ pub enum ErrorKind {
     /// Invalid state root hash.
     #[fail(display = "Invalid State Root Hash")]
     InvalidStateRoot,
+    /// Invalid transactions root hash.
+    #[fail(display = "Invalid Transactions Root Hash")]
+    InvalidTxRoot,
     /// Invalid state payload on state sync.
     #[fail(display = "Invalid State Payload")]
     InvalidStatePayload(String),
@@ -50,12 +59,21 @@ pub enum ErrorKind {
     /// Invalid Signature
     #[fail(display = "Invalid Signature")]
     InvalidSignature,
+    /// Block failed validation before and is refused without validating it again.
+    #[fail(display = "Block is known to be invalid")]
+    KnownBadBlock,
+    /// Reorg would revert more blocks than allowed.
+    #[fail(display = "Reorg depth {} exceeds maximum allowed depth {}", _0, _1)]
+    DeepReorg(BlockIndex, BlockIndex),
     /// IO Error.
     #[fail(display = "IO Error: {}", _0)]
     IOErr(String),
     /// Not found record in the DB.
     #[fail(display = "DB Not Found Error: {}", _0)]
     DBNotFoundErr(String),
+    /// Record in the DB is corrupted.
+    #[fail(display = "{}", _0)]
+    StorageCorruption(String),
     /// Anything else
     #[fail(display = "Other Error: {}", _0)]
     Other(String),
@@ -93,9 +111,12 @@ impl Error {
         match self.kind() {
             ErrorKind::Unfit(_)
             | ErrorKind::Orphan
+            | ErrorKind::BlockPendingState
             | ErrorKind::IOErr(_)
             | ErrorKind::Other(_)
-            | ErrorKind::DBNotFoundErr(_) => false,
+            | ErrorKind::DeepReorg(_, _)
+            | ErrorKind::DBNotFoundErr(_)
+            | ErrorKind::StorageCorruption(_) => false,
             ErrorKind::InvalidBlockPastTime(_, _)
             | ErrorKind::InvalidBlockFutureTime(_)
             | ErrorKind::InvalidBlockHeight
@@ -104,15 +125,32 @@ impl Error {
             | ErrorKind::InvalidBlockConfirmation
             | ErrorKind::InvalidBlockWeight
             | ErrorKind::InvalidStateRoot
+            | ErrorKind::InvalidTxRoot
             | ErrorKind::InvalidStatePayload(_)
             | ErrorKind::InvalidEpochHash
-            | ErrorKind::InvalidSignature => true,
+            | ErrorKind::InvalidSignature
+            | ErrorKind::KnownBadBlock => true,
+        }
+    }
+
+    /// Returns true if the error is caused by the contents covered by the block hash, so every
+    /// block with the same hash is invalid. Signatures and transactions are not covered by the
+    /// hash, so blocks failing on them may still have a valid counterpart.
+    pub fn is_invalid_block(&self) -> bool {
+        match self.kind() {
+            ErrorKind::InvalidBlockPastTime(_, _)
+            | ErrorKind::InvalidBlockWeight
+            | ErrorKind::InvalidStateRoot => true,
+            _ => false,
         }
     }
 
     pub fn is_error(&self) -> bool {
         match self.kind() {
-            ErrorKind::IOErr(_) | ErrorKind::Other(_) | ErrorKind::DBNotFoundErr(_) => true,
+            ErrorKind::IOErr(_)
+            | ErrorKind::Other(_)
+            | ErrorKind::DBNotFoundErr(_)
+            | ErrorKind::StorageCorruption(_) => true,
             _ => false,
         }
     }
@@ -126,7 +164,12 @@ impl From<ErrorKind> for Error {
 
 impl From<io::Error> for Error {
     fn from(error: io::Error) -> Error {
-        Error { inner: Context::new(ErrorKind::IOErr(error.to_string())) }
+        let kind = if is_storage_corruption(&error) {
+            ErrorKind::StorageCorruption(error.to_string())
+        } else {
+            ErrorKind::IOErr(error.to_string())
+        };
+        Error { inner: Context::new(kind) }
     }
 }
 
@@ -1,13 +1,20 @@
+pub use archive::{export_blocks, BlockArchive};
 pub use chain::{Chain, MAX_ORPHAN_SIZE};
 pub use error::{Error, ErrorKind};
-pub use store::{ChainStore, ChainStoreAccess};
+pub use store::{
+    reset_chain_data, verify_store, CacheStats, ChainStore, ChainStoreAccess, ChainStoreCacheStats,
+    VerifyStoreStats,
+};
 pub use types::{
-    Block, BlockApproval, BlockHeader, BlockStatus, Provenance, ReceiptResult, RuntimeAdapter, Tip,
+    ApprovalSignature, Block, BlockApproval, BlockApprovals, BlockHeader, BlockProfile,
+    BlockStatus, ChainEvent, GenesisIdentity, Provenance, ReceiptResult, RuntimeAdapter, Tip,
     ValidTransaction, Weight,
 };
 
+mod archive;
 mod chain;
 mod error;
+pub mod metrics;
 mod store;
 pub mod test_utils;
 mod types;
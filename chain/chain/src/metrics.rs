@@ -0,0 +1,18 @@
+//! Counters of the chain events, exported in the Prometheus text format next to the store
+//! metrics.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Number of the reorgs refused because they revert more than `max_reorg_depth` blocks.
+pub static DEEP_REORGS: AtomicU64 = AtomicU64::new(0);
+
+/// Writes the counters of the chain in the Prometheus text format.
+pub fn export_prometheus() -> String {
+    let mut out = String::new();
+    let name = "near_chain_deep_reorgs_total";
+    let _ =
+        writeln!(out, "# HELP {} Number of reorgs refused for reverting too many blocks.", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    let _ = writeln!(out, "{} {}", name, DEEP_REORGS.load(Ordering::Relaxed));
+    out
+}
@@ -1,31 +1,242 @@
 use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
 use std::io;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use cached::SizedCache;
-use log::debug;
+use borsh::BorshDeserialize;
+use cached::{Cached, SizedCache};
+use log::{debug, info, warn};
 
 use near_primitives::hash::CryptoHash;
+use near_primitives::merkle::PartialMerkleTree;
 use near_primitives::receipt::Receipt;
-use near_primitives::transaction::TransactionResult;
-use near_primitives::types::{BlockIndex, MerkleHash, ValidatorStake};
+use near_primitives::serialize::to_base;
+use near_primitives::transaction::{ReceiptOrigin, TransactionResult};
+use near_primitives::types::{BlockIndex, MerkleHash, ShardId, StatePart, ValidatorStake};
 use near_primitives::utils::index_to_bytes;
+use near_store::flat_state::{
+    get_flat_state_head, rebuild_flat_state, FlatStateDelta, FlatStateHead, FLAT_STATE_HEAD_KEY,
+};
 use near_store::{
-    read_with_cache, Store, StoreUpdate, WrappedTrieChanges, COL_BLOCK, COL_BLOCK_HEADER,
-    COL_BLOCK_INDEX, COL_BLOCK_MISC, COL_RECEIPTS, COL_STATE_REF, COL_TRANSACTION_RESULT,
-    COL_VALIDATOR_PROPOSALS,
+    read_with_cache, Store, StoreUpdate, Trie, TrieChanges, WrappedTrieChanges, COL_BAD_BLOCKS,
+    COL_BLOCK, COL_BLOCK_APPROVALS, COL_BLOCK_HEADER, COL_BLOCK_INDEX, COL_BLOCK_MERKLE_TREE,
+    COL_BLOCK_MISC, COL_BLOCK_ORDINAL, COL_FLAT_STATE, COL_NAMES, COL_ORPHANS, COL_RECEIPTS,
+    COL_RECEIPT_ORIGIN, COL_STATE_CHANGES, COL_STATE_PARTS, COL_STATE_REF, COL_TRANSACTION_RESULT,
+    COL_TRIE_CHANGES, COL_VALIDATOR_PROPOSALS, NUM_COLS,
 };
 
 use crate::error::{Error, ErrorKind};
-use crate::types::{Block, BlockHeader, Tip};
+use crate::types::{Block, BlockApprovals, BlockHeader, GenesisIdentity, Tip};
 
 const HEAD_KEY: &[u8; 4] = b"HEAD";
 const TAIL_KEY: &[u8; 4] = b"TAIL";
 const SYNC_HEAD_KEY: &[u8; 9] = b"SYNC_HEAD";
 const HEADER_HEAD_KEY: &[u8; 11] = b"HEADER_HEAD";
+/// Network the store was initialized for.
+const GENESIS_IDENTITY_KEY: &[u8; 16] = b"GENESIS_IDENTITY";
+/// First height which data wasn't garbage collected yet.
+const GC_TAIL_KEY: &[u8; 7] = b"GC_TAIL";
+/// Block the state is being synced for.
+const STATE_SYNC_KEY: &[u8; 10] = b"STATE_SYNC";
+const SYNCED_SHARDS_KEY: &[u8; 13] = b"SYNCED_SHARDS";
 
 /// lru cache size
 const CACHE_SIZE: usize = 20;
+/// Size of the header cache, enough to keep a batch of synced headers together with their parents.
+const HEADER_CACHE_SIZE: usize = 1024;
+
+/// Maximum number of heights with blocks garbage collected in a single update, so that catching
+/// up on old data doesn't stall block processing.
+const GC_MAX_HEIGHTS_PER_UPDATE: u64 = 2;
+/// Maximum number of heights garbage collection looks at in a single update, including empty ones.
+const GC_MAX_HEIGHTS_SCANNED: u64 = 1000;
+
+/// Columns with the header chain, in addition to the header head.
+const HEADER_COLUMNS: [Option<u32>; 4] =
+    [COL_BLOCK_HEADER, COL_BLOCK_INDEX, COL_BLOCK_MERKLE_TREE, COL_BLOCK_ORDINAL];
+
+/// Key of the trie changes of the block, ordered by height so that all the blocks at the height
+/// can be found, including forks.
+fn trie_changes_key(height: BlockIndex, hash: &CryptoHash) -> Vec<u8> {
+    let mut key = index_to_bytes(height);
+    key.extend_from_slice(hash.as_ref());
+    key
+}
+
+/// Key of the receipts the block sends to the shard, prefixed by the block hash so that receipts
+/// for all the shards can be found.
+fn receipts_key(hash: &CryptoHash, shard_id: ShardId) -> Vec<u8> {
+    let mut key = hash.as_ref().to_vec();
+    key.extend_from_slice(&shard_id.to_le_bytes());
+    key
+}
+
+/// Downloaded state parts are stored by the sync hash, shard and part id, while the receipts of
+/// the shard are stored by the sync hash and shard only.
+fn state_part_key(hash: &CryptoHash, shard_id: ShardId, part_id: u64) -> Vec<u8> {
+    let mut key = receipts_key(hash, shard_id);
+    key.extend_from_slice(&part_id.to_le_bytes());
+    key
+}
+
+/// Removes all data from the store except for `keep_columns` and, if `keep_headers` is set, the header chain.
+/// Returns number of removed records for each column, nothing is removed if `dry_run` is set.
+pub fn reset_chain_data(
+    store: &Store,
+    keep_headers: bool,
+    keep_columns: &[Option<u32>],
+    dry_run: bool,
+) -> Result<Vec<(u32, usize)>, Error> {
+    let mut store_update = store.store_update();
+    let mut removed = vec![];
+    for col in 0..NUM_COLS {
+        let column = Some(col);
+        if keep_columns.contains(&column) || (keep_headers && HEADER_COLUMNS.contains(&column)) {
+            continue;
+        }
+        let mut num_removed = 0;
+        for (key, _) in store.iter(column) {
+            if keep_headers
+                && column == COL_BLOCK_MISC
+                && (&key[..] == &HEADER_HEAD_KEY[..] || &key[..] == &GENESIS_IDENTITY_KEY[..])
+            {
+                continue;
+            }
+            store_update.delete(column, &key);
+            num_removed += 1;
+        }
+        removed.push((col, num_removed));
+    }
+    if !dry_run {
+        store_update.commit()?;
+    }
+    Ok(removed)
+}
+
+/// Number of records checked by `verify_store` and descriptions of the corrupted ones.
+#[derive(Debug, Default)]
+pub struct VerifyStoreStats {
+    pub num_records: u64,
+    pub corrupted: Vec<String>,
+}
+
+/// Checks every record of the column deserializes and passes `check` given the key.
+fn verify_column<T: BorshDeserialize, F: Fn(&[u8], &T) -> Result<(), String>>(
+    store: &Store,
+    column: Option<u32>,
+    stats: &mut VerifyStoreStats,
+    check: F,
+) {
+    for (key, value) in store.iter(column) {
+        stats.num_records += 1;
+        let result = T::try_from_slice(&value).map_err(|err| err.to_string());
+        if let Err(err) = result.and_then(|record| check(&key, &record)) {
+            let column_name = COL_NAMES[column.unwrap_or_default() as usize];
+            stats.corrupted.push(format!("{} at key {}: {}", column_name, to_base(&key), err));
+        }
+    }
+}
+
+/// Reads all the records of the columns the chain can't work without: headers and blocks must
+/// match the hashes they are stored under, block index entries and heads must deserialize.
+/// RocksDB checks the block checksums of every read record.
+pub fn verify_store(store: &Store) -> VerifyStoreStats {
+    let mut stats = VerifyStoreStats::default();
+    verify_column(store, COL_BLOCK_HEADER, &mut stats, |key, header: &BlockHeader| {
+        if header.hash().as_ref() != key {
+            return Err(format!("header hash is {}", header.hash()));
+        }
+        Ok(())
+    });
+    verify_column(store, COL_BLOCK, &mut stats, |key, block: &Block| {
+        if block.hash().as_ref() != key {
+            return Err(format!("block hash is {}", block.hash()));
+        }
+        if Block::compute_tx_root(&block.transactions) != block.header.inner.tx_root {
+            return Err("transactions don't match the tx root".to_string());
+        }
+        Ok(())
+    });
+    verify_column(store, COL_BLOCK_INDEX, &mut stats, |key, _: &CryptoHash| {
+        if key.len() != 8 {
+            return Err(format!("key length is {}", key.len()));
+        }
+        Ok(())
+    });
+    for key in [&HEAD_KEY[..], &HEADER_HEAD_KEY[..], &SYNC_HEAD_KEY[..]].iter() {
+        stats.num_records += 1;
+        if let Err(err) = store.get_ser::<Tip>(COL_BLOCK_MISC, key) {
+            stats.corrupted.push(err.to_string());
+        }
+    }
+    stats
+}
+
+/// Number of reads served by a cache and the ones that went to the database.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of the reads served by the cache, 0 if there were none.
+    pub fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+/// Statistics of the caches of the chain store.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChainStoreCacheStats {
+    pub headers: CacheStats,
+    pub blocks: CacheStats,
+    pub post_state_roots: CacheStats,
+}
+
+/// Same as `read_with_cache`, counting whether the read was served by the cache.
+fn read_with_cache_stats<'a, T: BorshDeserialize + 'a>(
+    storage: &Store,
+    col: Option<u32>,
+    cache: &'a mut SizedCache<Vec<u8>, T>,
+    stats: &mut CacheStats,
+    key: &[u8],
+) -> io::Result<Option<&'a T>> {
+    if cache.cache_get(&key.to_vec()).is_some() {
+        stats.hits += 1;
+    } else {
+        stats.misses += 1;
+    }
+    read_with_cache(storage, col, cache, key)
+}
+
+/// Returns the store to read a record of a column the garbage collection moves to the cold store
+/// from: the cold store if the record is neither cached nor in the hot store.
+fn read_source<'a, T>(
+    storage: &'a Store,
+    col: Option<u32>,
+    cache: &mut SizedCache<Vec<u8>, T>,
+    key: &[u8],
+) -> io::Result<&'a Store> {
+    match storage.cold_store() {
+        Some(cold) if cache.cache_get(&key.to_vec()).is_none() && !storage.exists(col, key)? => {
+            Ok(cold)
+        }
+        _ => Ok(storage),
+    }
+}
+
+/// Whether the record is in the hot store or in the cold one.
+fn exists_with_cold(storage: &Store, col: Option<u32>, key: &[u8]) -> io::Result<bool> {
+    Ok(storage.exists(col, key)?
+        || storage.cold_store().map_or(Ok(false), |cold| cold.exists(col, key))?)
+}
 
 /// Accesses the chain store. Used to create atomic editable views that can be reverted.
 pub trait ChainStoreAccess {
@@ -58,10 +269,22 @@ pub trait ChainStoreAccess {
     fn get_block_header(&mut self, h: &CryptoHash) -> Result<&BlockHeader, Error>;
     /// Returns hash of the block on the main chain for given height.
     fn get_block_hash_by_height(&mut self, height: BlockIndex) -> Result<CryptoHash, Error>;
-    /// Returns resulting receipt for given block.
-    fn get_receipts(&mut self, hash: &CryptoHash) -> Result<&Vec<Receipt>, Error>;
+    /// Returns receipts the given block sends to the shard.
+    fn get_receipts(
+        &mut self,
+        hash: &CryptoHash,
+        shard_id: ShardId,
+    ) -> Result<&Vec<Receipt>, Error>;
     /// Returns transaction result for given tx hash.
     fn get_transaction_result(&mut self, hash: &CryptoHash) -> Result<&TransactionResult, Error>;
+    /// Returns transaction that created receipt with given id.
+    fn get_receipt_origin(&mut self, receipt_id: &CryptoHash) -> Result<&ReceiptOrigin, Error>;
+    /// Returns merkle accumulator of all blocks up to and including block with given hash.
+    fn get_block_merkle_tree(&mut self, hash: &CryptoHash) -> Result<&PartialMerkleTree, Error>;
+    /// Returns hash of the block on the main chain with given ordinal (number of blocks before it).
+    fn get_block_hash_from_ordinal(&mut self, ordinal: u64) -> Result<CryptoHash, Error>;
+    /// Returns signatures of the block producers that approved the block with given hash.
+    fn get_block_approvals(&mut self, hash: &CryptoHash) -> Result<&BlockApprovals, Error>;
 }
 
 /// All chain-related database operations.
@@ -81,6 +304,16 @@ pub struct ChainStore {
     receipts: SizedCache<Vec<u8>, Vec<Receipt>>,
     /// Cache transaction statuses.
     transaction_results: SizedCache<Vec<u8>, TransactionResult>,
+    /// Cache with originating transactions of receipts.
+    receipt_origins: SizedCache<Vec<u8>, ReceiptOrigin>,
+    /// Cache with block merkle accumulators.
+    block_merkle_tree: SizedCache<Vec<u8>, PartialMerkleTree>,
+    /// Cache with approvals of the blocks.
+    block_approvals: SizedCache<Vec<u8>, BlockApprovals>,
+    /// Hits and misses of the header, block and state root caches.
+    cache_stats: ChainStoreCacheStats,
+    /// Whether updates are buffered in memory instead of being written to the disk on commit.
+    buffered_writes: bool,
 }
 
 pub fn option_to_not_found<T>(res: io::Result<Option<T>>, field_name: &str) -> Result<T, Error> {
@@ -96,18 +329,251 @@ impl ChainStore {
         ChainStore {
             store,
             blocks: SizedCache::with_size(CACHE_SIZE),
-            headers: SizedCache::with_size(CACHE_SIZE),
+            headers: SizedCache::with_size(HEADER_CACHE_SIZE),
             post_state_roots: SizedCache::with_size(CACHE_SIZE),
             post_validator_proposals: SizedCache::with_size(CACHE_SIZE),
             // block_index: SizedCache::with_size(CACHE_SIZE),
             receipts: SizedCache::with_size(CACHE_SIZE),
             transaction_results: SizedCache::with_size(CACHE_SIZE),
+            receipt_origins: SizedCache::with_size(CACHE_SIZE),
+            block_merkle_tree: SizedCache::with_size(CACHE_SIZE),
+            block_approvals: SizedCache::with_size(CACHE_SIZE),
+            cache_stats: ChainStoreCacheStats::default(),
+            buffered_writes: false,
+        }
+    }
+
+    /// Shared reference to the store, for the work done outside of the chain.
+    pub fn owned_store(&self) -> Arc<Store> {
+        self.store.clone()
+    }
+
+    /// Switches buffering of the committed updates in memory, so that many small updates are
+    /// written to the disk at once. Buffered updates are flushed when buffering is switched off,
+    /// unless the store writes them in the background.
+    pub fn set_buffered_writes(&mut self, buffered_writes: bool) -> Result<(), Error> {
+        if self.buffered_writes && !buffered_writes && !self.store.async_commit() {
+            self.store.flush()?;
         }
+        self.buffered_writes = buffered_writes;
+        Ok(())
+    }
+
+    /// Returns hits and misses of the caches since the start.
+    pub fn cache_stats(&self) -> &ChainStoreCacheStats {
+        &self.cache_stats
+    }
+
+    /// Removes deleted record from the caches, so that it isn't served after the deletion.
+    fn invalidate_cache(&mut self, col: Option<u32>, key: &[u8]) {
+        let key = key.to_vec();
+        if col == COL_BLOCK {
+            self.blocks.cache_remove(&key);
+        } else if col == COL_BLOCK_HEADER {
+            self.headers.cache_remove(&key);
+        } else if col == COL_STATE_REF {
+            self.post_state_roots.cache_remove(&key);
+        } else if col == COL_RECEIPTS {
+            self.receipts.cache_remove(&key);
+        } else if col == COL_TRANSACTION_RESULT {
+            self.transaction_results.cache_remove(&key);
+        } else if col == COL_RECEIPT_ORIGIN {
+            self.receipt_origins.cache_remove(&key);
+        } else if col == COL_BLOCK_APPROVALS {
+            self.block_approvals.cache_remove(&key);
+        }
+    }
+
+    /// Rebuilds the flat state from the state at the head, unless it's already at the head.
+    pub fn init_flat_state(&mut self) -> Result<(), Error> {
+        let head = self.head()?;
+        let state_root = *self.get_post_state_root(&head.last_block_hash)?;
+        let flat_head = FlatStateHead { block_hash: head.last_block_hash, state_root };
+        if get_flat_state_head(&self.store)? == Some(flat_head) {
+            return Ok(());
+        }
+        info!(target: "chain", "Building flat state at #{} {}", head.height, head.last_block_hash);
+        let num_values = rebuild_flat_state(self.store.clone(), flat_head)
+            .map_err(|err| ErrorKind::Other(err.to_string()))?;
+        info!(target: "chain", "Built flat state with {} values", num_values);
+        Ok(())
+    }
+
+    /// Returns the network the store was initialized for, `None` if it's not recorded yet.
+    pub fn get_genesis_identity(&self) -> Result<Option<GenesisIdentity>, Error> {
+        Ok(self.store.get_ser(COL_BLOCK_MISC, GENESIS_IDENTITY_KEY)?)
+    }
+
+    /// Returns hashes of the blocks known to be invalid with the order they were added in.
+    pub fn get_bad_blocks(&self) -> Result<Vec<(u64, CryptoHash)>, Error> {
+        let mut bad_blocks = self
+            .store
+            .iter(COL_BAD_BLOCKS)
+            .map(|(key, value)| {
+                let index = u64::try_from_slice(&value)?;
+                let hash = CryptoHash::try_from(key.as_ref())
+                    .map_err(|err| ErrorKind::Other(err.to_string()))?;
+                Ok((index, hash))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        bad_blocks.sort();
+        Ok(bad_blocks)
+    }
+
+    /// Returns orphan blocks saved by the previous run of the node.
+    pub fn get_orphans(&self) -> Result<Vec<Block>, Error> {
+        self.store
+            .iter(COL_ORPHANS)
+            .map(|(_, value)| Block::try_from_slice(&value).map_err(Error::from))
+            .collect()
     }
 
     pub fn store_update(&mut self) -> ChainStoreUpdate<Self> {
         ChainStoreUpdate::new(self)
     }
+
+    /// Returns the block the state was being synced for when the node stopped, if any.
+    pub fn get_state_sync_hash(&self) -> Result<Option<CryptoHash>, Error> {
+        self.store.get_ser(COL_BLOCK_MISC, STATE_SYNC_KEY).map_err(|err| err.into())
+    }
+
+    /// Returns the shards which state was downloaded by the last state sync. `None` if the state
+    /// was never synced, then the state of all the shards is computed from genesis.
+    pub fn get_synced_shards(&self) -> Result<Option<Vec<ShardId>>, Error> {
+        self.store.get_ser(COL_BLOCK_MISC, SYNCED_SHARDS_KEY).map_err(|err| err.into())
+    }
+
+    /// Returns state parts of the shard downloaded for the state sync at given block, ordered by
+    /// part id, and the receipts of the shard.
+    pub fn get_state_parts(
+        &self,
+        sync_hash: &CryptoHash,
+        shard_id: ShardId,
+    ) -> Result<(Vec<StatePart>, Vec<Receipt>), Error> {
+        let prefix = receipts_key(sync_hash, shard_id);
+        let mut parts = vec![];
+        let mut receipts = vec![];
+        for (key, value) in self.store.iter_prefix(COL_STATE_PARTS, &prefix) {
+            if key.len() == prefix.len() {
+                receipts = Vec::<Receipt>::try_from_slice(&value)?;
+            } else if let Ok(part) = StatePart::try_from_slice(&value) {
+                // Parts saved in an older format are downloaded again.
+                parts.push(part);
+            }
+        }
+        parts.sort_by_key(|part| part.part_id);
+        Ok((parts, receipts))
+    }
+
+    /// Returns tips of the known forks at most `num_heights` below the head: blocks without known
+    /// children, the head included, starting from the heaviest one. Blocks are found by their trie
+    /// changes, so only the applied ones are considered.
+    pub fn get_fork_tips(&mut self, num_heights: BlockIndex) -> Result<Vec<Tip>, Error> {
+        let head = self.head()?;
+        let header_head = self.header_head()?;
+        let mut headers = vec![];
+        for height in head.height.saturating_sub(num_heights)..=head.height.max(header_head.height)
+        {
+            let prefix = index_to_bytes(height);
+            for (key, _) in self.store.iter_prefix(COL_TRIE_CHANGES, &prefix) {
+                let hash = CryptoHash::try_from(&key[prefix.len()..])
+                    .map_err(|err| ErrorKind::Other(err.to_string()))?;
+                headers.push(self.get_block_header(&hash)?.clone());
+            }
+        }
+        let parents: HashSet<CryptoHash> =
+            headers.iter().map(|header| header.inner.prev_hash).collect();
+        let mut tips: Vec<Tip> = headers
+            .iter()
+            .filter(|header| !parents.contains(&header.hash()))
+            .map(Tip::from_header)
+            .collect();
+        tips.sort_by(|a, b| b.total_weight.cmp(&a.total_weight).then(b.height.cmp(&a.height)));
+        Ok(tips)
+    }
+
+    /// Rolls the chain back to the block of the main chain at given height: removes all the blocks
+    /// and headers above it, including forks, reverts the state they created and sets head, header
+    /// head and sync head to the block, along with the results of their transactions and receipts
+    /// and the orphans above it. State of the block must not be garbage collected yet.
+    /// Returns the new head and hashes of the removed blocks.
+    pub fn reset_to_height(
+        &mut self,
+        height: BlockIndex,
+    ) -> Result<(Tip, Vec<CryptoHash>), Error> {
+        let head = self.head()?;
+        let header_head = self.header_head()?;
+        if height > head.height {
+            return Err(ErrorKind::Other(format!(
+                "Height {} is above the head {}",
+                height, head.height
+            ))
+            .into());
+        }
+        let hash = self.get_block_hash_by_height(height)?;
+        let state_root = *self.get_post_state_root(&hash)?;
+        let tip = Tip::from_header(self.get_block_header(&hash)?);
+
+        // Applied blocks are found by their trie changes, the rest by walking back the heads.
+        let mut removed = vec![];
+        let mut discarded = vec![];
+        let mut store_update = self.store.store_update();
+        for height in (height + 1)..=head.height.max(header_head.height) {
+            let prefix = index_to_bytes(height);
+            for (key, value) in self.store.iter_prefix(COL_TRIE_CHANGES, &prefix) {
+                let hash = CryptoHash::try_from(&key[prefix.len()..])
+                    .map_err(|err| ErrorKind::Other(err.to_string()))?;
+                discarded.push(TrieChanges::try_from_slice(&value)?);
+                store_update.delete(COL_TRIE_CHANGES, &key);
+                removed.push(hash);
+            }
+        }
+        for tip_hash in [head.last_block_hash, header_head.last_block_hash].iter() {
+            let mut header = self.get_block_header(tip_hash)?.clone();
+            while header.inner.height > height {
+                if !removed.contains(&header.hash()) {
+                    removed.push(header.hash());
+                }
+                header = self.get_previous_header(&header)?.clone();
+            }
+        }
+
+        let trie = Arc::new(Trie::new(self.store.clone()));
+        WrappedTrieChanges::new(trie, TrieChanges::empty(state_root))
+            .insertions_with_gc_into(&[], &discarded, &mut store_update)
+            .map_err(|err| ErrorKind::Other(err.to_string()))?;
+        let orphans = self.get_orphans()?;
+        let mut chain_store_update = self.store_update();
+        for hash in removed.iter() {
+            // Results which origin is unknown are only reachable through the removed block.
+            for (result_hash, is_receipt, origin) in chain_store_update.block_results(hash)? {
+                if origin.map_or(true, |origin| origin.block_index > height) {
+                    chain_store_update.delete_result(&mut store_update, &result_hash, is_receipt);
+                }
+            }
+            for col in [COL_BLOCK, COL_BLOCK_HEADER, COL_STATE_REF, COL_BLOCK_APPROVALS].iter() {
+                chain_store_update.delete_cached(&mut store_update, *col, hash.as_ref());
+            }
+            chain_store_update.delete_receipts(hash, &mut store_update, &mut None)?;
+            store_update.delete(COL_VALIDATOR_PROPOSALS, hash.as_ref());
+            store_update.delete(COL_BLOCK_MERKLE_TREE, hash.as_ref());
+            store_update.delete(COL_STATE_CHANGES, hash.as_ref());
+        }
+        for orphan in orphans.iter().filter(|orphan| orphan.header.inner.height > height) {
+            chain_store_update.delete_orphan(&orphan.hash());
+        }
+        // Approvals of the new head were included by the removed blocks.
+        chain_store_update.delete_cached(&mut store_update, COL_BLOCK_APPROVALS, hash.as_ref());
+        // Ordinals past the new head are cleaned up when the header head is saved.
+        chain_store_update.save_head(&tip)?;
+        chain_store_update.save_sync_head(&tip);
+        for height in (height + 1)..=head.height.max(header_head.height) {
+            chain_store_update.block_index.insert(height, None);
+        }
+        chain_store_update.merge(store_update);
+        chain_store_update.commit()?;
+        Ok((tip, removed))
+    }
 }
 
 impl ChainStoreAccess for ChainStore {
@@ -141,15 +607,22 @@ impl ChainStoreAccess for ChainStore {
 
     /// Get full block.
     fn get_block(&mut self, h: &CryptoHash) -> Result<&Block, Error> {
+        let storage = read_source(&*self.store, COL_BLOCK, &mut self.blocks, h.as_ref())?;
         option_to_not_found(
-            read_with_cache(&*self.store, COL_BLOCK, &mut self.blocks, h.as_ref()),
+            read_with_cache_stats(
+                storage,
+                COL_BLOCK,
+                &mut self.blocks,
+                &mut self.cache_stats.blocks,
+                h.as_ref(),
+            ),
             &format!("BLOCK: {}", h),
         )
     }
 
     /// Does this full block exist?
     fn block_exists(&self, h: &CryptoHash) -> Result<bool, Error> {
-        self.store.exists(COL_BLOCK, h.as_ref()).map_err(|e| e.into())
+        exists_with_cold(&*self.store, COL_BLOCK, h.as_ref()).map_err(|e| e.into())
     }
 
     /// Get previous header.
@@ -160,7 +633,13 @@ impl ChainStoreAccess for ChainStore {
     /// Get state root hash after applying header with given hash.
     fn get_post_state_root(&mut self, h: &CryptoHash) -> Result<&MerkleHash, Error> {
         option_to_not_found(
-            read_with_cache(&*self.store, COL_STATE_REF, &mut self.post_state_roots, h.as_ref()),
+            read_with_cache_stats(
+                &*self.store,
+                COL_STATE_REF,
+                &mut self.post_state_roots,
+                &mut self.cache_stats.post_state_roots,
+                h.as_ref(),
+            ),
             &format!("STATE ROOT: {}", h),
         )
     }
@@ -183,7 +662,13 @@ impl ChainStoreAccess for ChainStore {
     /// Get block header.
     fn get_block_header(&mut self, h: &CryptoHash) -> Result<&BlockHeader, Error> {
         option_to_not_found(
-            read_with_cache(&*self.store, COL_BLOCK_HEADER, &mut self.headers, h.as_ref()),
+            read_with_cache_stats(
+                &*self.store,
+                COL_BLOCK_HEADER,
+                &mut self.headers,
+                &mut self.cache_stats.headers,
+                h.as_ref(),
+            ),
             &format!("BLOCK HEADER: {}", h),
         )
     }
@@ -206,17 +691,36 @@ impl ChainStoreAccess for ChainStore {
         //        )
     }
 
-    fn get_receipts(&mut self, hash: &CryptoHash) -> Result<&Vec<Receipt>, Error> {
+    fn get_receipts(
+        &mut self,
+        hash: &CryptoHash,
+        shard_id: ShardId,
+    ) -> Result<&Vec<Receipt>, Error> {
+        let mut key = receipts_key(hash, shard_id);
+        // Receipts saved before they were split by shard are stored under the block hash.
+        if shard_id == 0
+            && self.receipts.cache_get(&key).is_none()
+            && !exists_with_cold(&*self.store, COL_RECEIPTS, &key)?
+        {
+            key = hash.as_ref().to_vec();
+        }
+        let storage = read_source(&*self.store, COL_RECEIPTS, &mut self.receipts, &key)?;
         option_to_not_found(
-            read_with_cache(&*self.store, COL_RECEIPTS, &mut self.receipts, hash.as_ref()),
-            &format!("RECEIPT: {}", hash),
+            read_with_cache(storage, COL_RECEIPTS, &mut self.receipts, &key),
+            &format!("RECEIPT: {} shard {}", hash, shard_id),
         )
     }
 
     fn get_transaction_result(&mut self, hash: &CryptoHash) -> Result<&TransactionResult, Error> {
+        let storage = read_source(
+            &*self.store,
+            COL_TRANSACTION_RESULT,
+            &mut self.transaction_results,
+            hash.as_ref(),
+        )?;
         option_to_not_found(
             read_with_cache(
-                &*self.store,
+                storage,
                 COL_TRANSACTION_RESULT,
                 &mut self.transaction_results,
                 hash.as_ref(),
@@ -224,6 +728,56 @@ impl ChainStoreAccess for ChainStore {
             &format!("TRANSACTION: {}", hash),
         )
     }
+
+    fn get_receipt_origin(&mut self, receipt_id: &CryptoHash) -> Result<&ReceiptOrigin, Error> {
+        let storage = read_source(
+            &*self.store,
+            COL_RECEIPT_ORIGIN,
+            &mut self.receipt_origins,
+            receipt_id.as_ref(),
+        )?;
+        option_to_not_found(
+            read_with_cache(
+                storage,
+                COL_RECEIPT_ORIGIN,
+                &mut self.receipt_origins,
+                receipt_id.as_ref(),
+            ),
+            &format!("RECEIPT ORIGIN: {}", receipt_id),
+        )
+    }
+
+    fn get_block_merkle_tree(&mut self, hash: &CryptoHash) -> Result<&PartialMerkleTree, Error> {
+        option_to_not_found(
+            read_with_cache(
+                &*self.store,
+                COL_BLOCK_MERKLE_TREE,
+                &mut self.block_merkle_tree,
+                hash.as_ref(),
+            ),
+            &format!("BLOCK MERKLE TREE: {}", hash),
+        )
+    }
+
+    fn get_block_hash_from_ordinal(&mut self, ordinal: u64) -> Result<CryptoHash, Error> {
+        option_to_not_found(
+            self.store.get_ser(COL_BLOCK_ORDINAL, &index_to_bytes(ordinal)),
+            &format!("BLOCK ORDINAL: {}", ordinal),
+        )
+    }
+
+    fn get_block_approvals(&mut self, hash: &CryptoHash) -> Result<&BlockApprovals, Error> {
+        let storage = read_source(
+            &*self.store,
+            COL_BLOCK_APPROVALS,
+            &mut self.block_approvals,
+            hash.as_ref(),
+        )?;
+        option_to_not_found(
+            read_with_cache(storage, COL_BLOCK_APPROVALS, &mut self.block_approvals, hash.as_ref()),
+            &format!("BLOCK APPROVALS: {}", hash),
+        )
+    }
 }
 
 /// Provides layer to update chain without touching underlaying database.
@@ -238,13 +792,23 @@ pub struct ChainStoreUpdate<'a, T> {
     post_state_roots: HashMap<CryptoHash, MerkleHash>,
     post_validator_proposals: HashMap<CryptoHash, Vec<ValidatorStake>>,
     block_index: HashMap<BlockIndex, Option<CryptoHash>>,
-    receipts: HashMap<CryptoHash, Vec<Receipt>>,
+    block_merkle_tree: HashMap<CryptoHash, PartialMerkleTree>,
+    block_ordinal: HashMap<u64, Option<CryptoHash>>,
+    receipts: HashMap<(CryptoHash, ShardId), Vec<Receipt>>,
     transaction_results: HashMap<CryptoHash, TransactionResult>,
+    receipt_origins: HashMap<CryptoHash, ReceiptOrigin>,
+    block_approvals: HashMap<CryptoHash, BlockApprovals>,
+    orphans: HashMap<CryptoHash, Option<Block>>,
+    bad_blocks: HashMap<CryptoHash, Option<u64>>,
     head: Option<Tip>,
     tail: Option<Tip>,
     header_head: Option<Tip>,
     sync_head: Option<Tip>,
-    trie_changes: Option<WrappedTrieChanges>,
+    genesis_identity: Option<GenesisIdentity>,
+    trie_changes: Option<(BlockIndex, CryptoHash, WrappedTrieChanges)>,
+    gc_stop_height: Option<BlockIndex>,
+    /// Records deleted by the update, to be removed from the caches of the chain store.
+    deleted_keys: Vec<(Option<u32>, Vec<u8>)>,
 }
 
 impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
@@ -256,15 +820,24 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
             deleted_blocks: HashSet::default(),
             headers: HashMap::default(),
             block_index: HashMap::default(),
+            block_merkle_tree: HashMap::default(),
+            block_ordinal: HashMap::default(),
             post_state_roots: HashMap::default(),
             post_validator_proposals: HashMap::default(),
             receipts: HashMap::default(),
             transaction_results: HashMap::default(),
+            receipt_origins: HashMap::default(),
+            block_approvals: HashMap::default(),
+            orphans: HashMap::default(),
+            bad_blocks: HashMap::default(),
             head: None,
             tail: None,
             header_head: None,
             sync_head: None,
+            genesis_identity: None,
             trie_changes: None,
+            gc_stop_height: None,
+            deleted_keys: vec![],
         }
     }
 }
@@ -368,17 +941,55 @@ impl<'a, T: ChainStoreAccess> ChainStoreAccess for ChainStoreUpdate<'a, T> {
     }
 
     /// Get receipts produced for block with givien hash.
-    fn get_receipts(&mut self, hash: &CryptoHash) -> Result<&Vec<Receipt>, Error> {
-        if let Some(receipts) = self.receipts.get(hash) {
+    fn get_receipts(
+        &mut self,
+        hash: &CryptoHash,
+        shard_id: ShardId,
+    ) -> Result<&Vec<Receipt>, Error> {
+        if let Some(receipts) = self.receipts.get(&(*hash, shard_id)) {
             Ok(receipts)
         } else {
-            self.chain_store.get_receipts(hash)
+            self.chain_store.get_receipts(hash, shard_id)
         }
     }
 
     fn get_transaction_result(&mut self, hash: &CryptoHash) -> Result<&TransactionResult, Error> {
         self.chain_store.get_transaction_result(hash)
     }
+
+    fn get_receipt_origin(&mut self, receipt_id: &CryptoHash) -> Result<&ReceiptOrigin, Error> {
+        if let Some(origin) = self.receipt_origins.get(receipt_id) {
+            Ok(origin)
+        } else {
+            self.chain_store.get_receipt_origin(receipt_id)
+        }
+    }
+
+    fn get_block_merkle_tree(&mut self, hash: &CryptoHash) -> Result<&PartialMerkleTree, Error> {
+        if let Some(merkle_tree) = self.block_merkle_tree.get(hash) {
+            Ok(merkle_tree)
+        } else {
+            self.chain_store.get_block_merkle_tree(hash)
+        }
+    }
+
+    fn get_block_hash_from_ordinal(&mut self, ordinal: u64) -> Result<CryptoHash, Error> {
+        match self.block_ordinal.get(&ordinal) {
+            Some(Some(hash)) => Ok(*hash),
+            Some(None) => {
+                Err(ErrorKind::DBNotFoundErr(format!("BLOCK ORDINAL: {}", ordinal)).into())
+            }
+            None => self.chain_store.get_block_hash_from_ordinal(ordinal),
+        }
+    }
+
+    fn get_block_approvals(&mut self, hash: &CryptoHash) -> Result<&BlockApprovals, Error> {
+        if let Some(approvals) = self.block_approvals.get(hash) {
+            Ok(approvals)
+        } else {
+            self.chain_store.get_block_approvals(hash)
+        }
+    }
 }
 
 impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
@@ -416,6 +1027,7 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
                 }
                 _ => {
                     self.block_index.insert(header_height, Some(header_hash));
+                    self.update_block_ordinal(&header_hash)?;
                     prev_hash = header_prev_hash;
                     prev_height = header_height;
                 }
@@ -423,12 +1035,31 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         }
     }
 
+    /// Sets ordinal to hash index on the main chain for given block.
+    fn update_block_ordinal(&mut self, hash: &CryptoHash) -> Result<(), Error> {
+        let ordinal = self.get_block_merkle_tree(hash)?.size() - 1;
+        self.block_ordinal.insert(ordinal, Some(*hash));
+        Ok(())
+    }
+
     /// Update header head and height to hash index for this branch.
     pub fn save_header_head(&mut self, t: &Tip) -> Result<(), Error> {
+        // New branch may have less blocks than the previous one, clean up ordinals that are past it.
+        let prev_num_blocks = match self.header_head() {
+            Ok(prev_header_head) => {
+                self.get_block_merkle_tree(&prev_header_head.last_block_hash)?.size()
+            }
+            Err(_) => 0,
+        };
         if t.height > 0 {
             self.update_block_index(t.height, t.prev_block_hash)?;
         }
         self.block_index.insert(t.height, Some(t.last_block_hash));
+        self.update_block_ordinal(&t.last_block_hash)?;
+        let num_blocks = self.get_block_merkle_tree(&t.last_block_hash)?.size();
+        for ordinal in num_blocks..prev_num_blocks {
+            self.block_ordinal.insert(ordinal, None);
+        }
         self.header_head = Some(t.clone());
         Ok(())
     }
@@ -438,6 +1069,10 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         self.sync_head = Some(t.clone());
     }
 
+    pub fn save_genesis_identity(&mut self, identity: GenesisIdentity) {
+        self.genesis_identity = Some(identity);
+    }
+
     /// Save block.
     pub fn save_block(&mut self, block: Block) {
         self.blocks.insert(block.hash(), block);
@@ -456,6 +1091,23 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         self.post_validator_proposals.insert(*hash, validator_proposals);
     }
 
+    /// Save orphan block, so that it survives restarts.
+    pub fn save_orphan(&mut self, block: Block) {
+        self.orphans.insert(block.hash(), Some(block));
+    }
+
+    pub fn delete_orphan(&mut self, hash: &CryptoHash) {
+        self.orphans.insert(*hash, None);
+    }
+
+    pub fn save_bad_block(&mut self, hash: &CryptoHash, index: u64) {
+        self.bad_blocks.insert(*hash, Some(index));
+    }
+
+    pub fn delete_bad_block(&mut self, hash: &CryptoHash) {
+        self.bad_blocks.insert(*hash, None);
+    }
+
     pub fn delete_block(&mut self, hash: &CryptoHash) {
         self.deleted_blocks.insert(*hash);
     }
@@ -464,14 +1116,36 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         self.headers.insert(header.hash(), header);
     }
 
-    pub fn save_receipt(&mut self, hash: &CryptoHash, receipt: Vec<Receipt>) {
-        self.receipts.insert(*hash, receipt);
+    /// Appends block to the merkle accumulator of its previous block and saves it for this block.
+    pub fn save_block_merkle_tree(&mut self, header: &BlockHeader) -> Result<(), Error> {
+        let mut merkle_tree = if header.inner.height == 0 {
+            PartialMerkleTree::default()
+        } else {
+            self.get_block_merkle_tree(&header.inner.prev_hash)?.clone()
+        };
+        merkle_tree.insert(header.hash());
+        self.block_merkle_tree.insert(header.hash(), merkle_tree);
+        Ok(())
+    }
+
+    /// Save receipts the block sends to the shard.
+    pub fn save_receipt(&mut self, hash: &CryptoHash, shard_id: ShardId, receipt: Vec<Receipt>) {
+        self.receipts.insert((*hash, shard_id), receipt);
     }
 
     pub fn save_transaction_result(&mut self, hash: &CryptoHash, result: TransactionResult) {
         self.transaction_results.insert(*hash, result);
     }
 
+    pub fn save_receipt_origin(&mut self, receipt_id: &CryptoHash, origin: ReceiptOrigin) {
+        self.receipt_origins.insert(*receipt_id, origin);
+    }
+
+    /// Save approvals of the block with given hash.
+    pub fn save_block_approvals(&mut self, hash: &CryptoHash, approvals: BlockApprovals) {
+        self.block_approvals.insert(*hash, approvals);
+    }
+
     /// Starts a sub-ChainUpdate with atomic commit/rollback of all operations done
     /// within this scope.
     /// If the closure returns and error, all changes are canceled.
@@ -485,8 +1159,10 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         match res {
             // Committing changes.
             Ok(true) => {
-                let store_update = child_store_update.finalize()?;
+                let store_update = child_store_update.finalize_update()?;
+                let deleted_keys = child_store_update.deleted_keys;
                 self.store_updates.push(store_update);
+                self.deleted_keys.extend(deleted_keys);
                 Ok(true)
             }
             // Rolling back changes.
@@ -498,8 +1174,319 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         }
     }
 
-    pub fn save_trie_changes(&mut self, trie_changes: WrappedTrieChanges) {
-        self.trie_changes = Some(trie_changes);
+    /// Saves trie changes of the block, they are kept until the block is garbage collected.
+    pub fn save_trie_changes(
+        &mut self,
+        height: BlockIndex,
+        hash: &CryptoHash,
+        trie_changes: WrappedTrieChanges,
+    ) {
+        self.trie_changes = Some((height, *hash, trie_changes));
+    }
+
+    /// Garbage collects blocks, their results and state below the given height.
+    /// Done incrementally along with the trie changes of the update, a few heights at a time.
+    pub fn clear_old_data(&mut self, stop_height: BlockIndex) {
+        self.gc_stop_height = Some(stop_height);
+    }
+
+    /// Deletes data of the blocks from the GC tail up to the stop height. Returns trie changes of
+    /// the blocks on the main chain, which old state is to be discarded, and of the fork blocks,
+    /// which new state is to be discarded.
+    /// With a cold store, data of the main chain blocks, including their trie changes with the
+    /// discarded state, is moved there. It's written before the deletion is committed, so that
+    /// the history is never lost.
+    fn gc_old_data(
+        &mut self,
+        stop_height: BlockIndex,
+        store_update: &mut StoreUpdate,
+    ) -> Result<(Vec<TrieChanges>, Vec<TrieChanges>), Error> {
+        // Genesis block is always kept.
+        let mut height = self.store().get_ser(COL_BLOCK_MISC, GC_TAIL_KEY)?.unwrap_or(1);
+        let (mut finalized, mut discarded) = (vec![], vec![]);
+        let (mut num_collected, mut num_scanned) = (0, 0);
+        let mut cold_update = self.store().cold_store().map(|cold| cold.store_update());
+        while height < stop_height
+            && num_collected < GC_MAX_HEIGHTS_PER_UPDATE
+            && num_scanned < GC_MAX_HEIGHTS_SCANNED
+        {
+            let main_hash = self.get_block_hash_by_height(height).ok();
+            let prefix = index_to_bytes(height);
+            let entries: Vec<_> = self
+                .store()
+                .iter_prefix(COL_TRIE_CHANGES, &prefix)
+                .map(|(key, value)| (key.to_vec(), value.to_vec()))
+                .collect();
+            for (key, value) in entries.iter() {
+                let hash = CryptoHash::try_from(&key[prefix.len()..])
+                    .map_err(|err| ErrorKind::Other(err.to_string()))?;
+                let trie_changes = TrieChanges::try_from_slice(value)?;
+                // Fork blocks are not a part of the history, they are not moved to the cold store.
+                let mut no_cold = None;
+                let cold = if main_hash == Some(hash) { &mut cold_update } else { &mut no_cold };
+                if main_hash == Some(hash) {
+                    self.gc_block_results(&hash, store_update, cold)?;
+                    // State before this block is discarded, state after it is kept.
+                    let prev_hash = self.get_block_header(&hash)?.inner.prev_hash;
+                    self.delete_cached(store_update, COL_STATE_REF, prev_hash.as_ref());
+                    finalized.push(trie_changes);
+                } else {
+                    self.gc_fork_results(&hash, store_update)?;
+                    self.delete_cached(store_update, COL_STATE_REF, hash.as_ref());
+                    discarded.push(trie_changes);
+                }
+                self.copy_to_cold(cold, COL_BLOCK, hash.as_ref())?;
+                self.delete_cached(store_update, COL_BLOCK, hash.as_ref());
+                self.delete_receipts(&hash, store_update, cold)?;
+                self.copy_to_cold(cold, COL_BLOCK_APPROVALS, hash.as_ref())?;
+                self.delete_cached(store_update, COL_BLOCK_APPROVALS, hash.as_ref());
+                self.copy_to_cold(cold, COL_TRIE_CHANGES, key)?;
+                store_update.delete(COL_TRIE_CHANGES, key);
+                store_update.delete(COL_STATE_CHANGES, hash.as_ref());
+            }
+            if !entries.is_empty() {
+                num_collected += 1;
+            }
+            num_scanned += 1;
+            height += 1;
+        }
+        store_update.set_ser(COL_BLOCK_MISC, GC_TAIL_KEY, &height)?;
+        if let Some(cold_update) = cold_update {
+            cold_update.commit()?;
+        }
+        Ok((finalized, discarded))
+    }
+
+    /// Moves the flat state to the new head: reverts the blocks past the common ancestor of the
+    /// head and the flat state head, and applies the blocks from the ancestor up to the new head,
+    /// recording the replaced values to revert them later. Updated changes of the blocks are put
+    /// into `deltas`. If changes of some block are unknown, e.g. it was applied before the flat
+    /// state existed or the state was synced, the flat state is dropped until it's rebuilt.
+    fn update_flat_state(
+        &mut self,
+        head: &Tip,
+        deltas: &mut HashMap<CryptoHash, FlatStateDelta>,
+        store_update: &mut StoreUpdate,
+    ) -> Result<(), Error> {
+        let flat_head = match get_flat_state_head(self.store())? {
+            Some(flat_head) if flat_head.block_hash != head.last_block_hash => flat_head,
+            _ => return Ok(()),
+        };
+        let mut reverted = vec![];
+        let mut applied = vec![];
+        let mut from = self.get_block_header(&flat_head.block_hash)?.clone();
+        let mut to = self.get_block_header(&head.last_block_hash)?.clone();
+        while from.hash() != to.hash() {
+            if from.inner.height >= to.inner.height {
+                match self.get_flat_state_delta(&from.hash())?.and_then(|delta| delta.undo) {
+                    Some(undo) => reverted.push(undo),
+                    None => return self.drop_flat_state(&from.hash(), store_update),
+                }
+                from = self.get_previous_header(&from)?.clone();
+            } else {
+                match self.get_flat_state_delta(&to.hash())? {
+                    Some(delta) => applied.push((to.hash(), delta)),
+                    None => return self.drop_flat_state(&to.hash(), store_update),
+                }
+                to = self.get_previous_header(&to)?.clone();
+            }
+        }
+
+        let mut values: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new();
+        for undo in reverted {
+            values.extend(undo);
+        }
+        for (hash, mut delta) in applied.into_iter().rev() {
+            let mut undo = Vec::with_capacity(delta.changes.len());
+            for (key, value) in delta.changes.iter() {
+                let old_value = match values.get(key) {
+                    Some(old_value) => old_value.clone(),
+                    None => self.store().get(COL_FLAT_STATE, key)?,
+                };
+                undo.push((key.clone(), old_value));
+                values.insert(key.clone(), value.clone());
+            }
+            delta.undo = Some(undo);
+            deltas.insert(hash, delta);
+        }
+        for (key, value) in values.iter() {
+            match value {
+                Some(value) => store_update.set(COL_FLAT_STATE, key, value),
+                None => store_update.delete(COL_FLAT_STATE, key),
+            }
+        }
+        let state_root = *self.get_post_state_root(&head.last_block_hash)?;
+        let flat_head = FlatStateHead { block_hash: head.last_block_hash, state_root };
+        store_update.set_ser(COL_BLOCK_MISC, FLAT_STATE_HEAD_KEY, &flat_head)?;
+        Ok(())
+    }
+
+    /// Returns changes of the state made by the block, including the block of this update.
+    fn get_flat_state_delta(&self, hash: &CryptoHash) -> Result<Option<FlatStateDelta>, Error> {
+        match &self.trie_changes {
+            Some((_, trie_changes_hash, trie_changes)) if trie_changes_hash == hash => {
+                Ok(Some(FlatStateDelta::new(trie_changes.state_changes().clone())))
+            }
+            _ => Ok(self.store().get_ser(COL_STATE_CHANGES, hash.as_ref())?),
+        }
+    }
+
+    /// Removes the flat state head, so that the flat state is not used until it's rebuilt.
+    fn drop_flat_state(
+        &self,
+        hash: &CryptoHash,
+        store_update: &mut StoreUpdate,
+    ) -> Result<(), Error> {
+        warn!(target: "chain", "Dropping flat state, changes of block {} are unknown", hash);
+        store_update.delete(COL_BLOCK_MISC, FLAT_STATE_HEAD_KEY);
+        Ok(())
+    }
+
+    /// Copies the record to the cold store update, if the record is to be moved there.
+    fn copy_to_cold(
+        &self,
+        cold_update: &mut Option<StoreUpdate>,
+        col: Option<u32>,
+        key: &[u8],
+    ) -> Result<(), Error> {
+        if let Some(cold_update) = cold_update {
+            if let Some(value) = self.store().get(col, key)? {
+                cold_update.set(col, key, &value);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes results of the transactions included in the block and of the receipts it produced.
+    fn gc_block_results(
+        &mut self,
+        hash: &CryptoHash,
+        store_update: &mut StoreUpdate,
+        cold_update: &mut Option<StoreUpdate>,
+    ) -> Result<(), Error> {
+        // Read past the caches, so that the collected data doesn't stay there.
+        if let Some(block) = self.store().get_ser::<Block>(COL_BLOCK, hash.as_ref())? {
+            for transaction in block.transactions.iter() {
+                let tx_hash = transaction.get_hash();
+                self.copy_to_cold(cold_update, COL_TRANSACTION_RESULT, tx_hash.as_ref())?;
+                self.delete_cached(store_update, COL_TRANSACTION_RESULT, tx_hash.as_ref());
+            }
+        }
+        let receipts = self
+            .store()
+            .iter_prefix(COL_RECEIPTS, hash.as_ref())
+            .map(|(_, value)| Vec::<Receipt>::try_from_slice(&value))
+            .collect::<Result<Vec<_>, _>>()?;
+        for receipt in receipts.iter().flatten() {
+            let receipt_id = receipt.receipt_id.as_ref();
+            self.copy_to_cold(cold_update, COL_TRANSACTION_RESULT, receipt_id)?;
+            self.delete_cached(store_update, COL_TRANSACTION_RESULT, receipt_id);
+            self.copy_to_cold(cold_update, COL_RECEIPT_ORIGIN, receipt_id)?;
+            self.delete_cached(store_update, COL_RECEIPT_ORIGIN, receipt_id);
+        }
+        Ok(())
+    }
+
+    /// Deletes results of the transactions and receipts of the fork block, unless they belong to
+    /// the main chain. The same transaction may be included on the main chain, in which case its
+    /// receipts have the origin on the main chain, as the receipt ids only depend on the
+    /// transaction. Results of the transactions that failed without producing receipts can't be
+    /// told apart and are kept.
+    fn gc_fork_results(
+        &mut self,
+        hash: &CryptoHash,
+        store_update: &mut StoreUpdate,
+    ) -> Result<(), Error> {
+        for (result_hash, is_receipt, origin) in self.block_results(hash)? {
+            let on_main_chain = match origin {
+                Some(origin) => {
+                    self.get_block_hash_by_height(origin.block_index).ok()
+                        == Some(origin.block_hash)
+                }
+                // Receipts stored before the origin index was introduced have no origin.
+                None => true,
+            };
+            if !on_main_chain {
+                self.delete_result(store_update, &result_hash, is_receipt);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stored results of the transactions and receipts of the block, whether each one is a result
+    /// of a receipt and the origin of the receipt which tells where the result is from, if known.
+    /// Origin of a transaction is the origin of its first receipt.
+    fn block_results(
+        &self,
+        hash: &CryptoHash,
+    ) -> Result<Vec<(CryptoHash, bool, Option<ReceiptOrigin>)>, Error> {
+        let mut results = vec![];
+        if let Some(block) = self.store().get_ser::<Block>(COL_BLOCK, hash.as_ref())? {
+            for transaction in block.transactions.iter() {
+                let tx_hash = transaction.get_hash();
+                let result = self
+                    .store()
+                    .get_ser::<TransactionResult>(COL_TRANSACTION_RESULT, tx_hash.as_ref())?;
+                if let Some(result) = result {
+                    results.push((tx_hash, false, result.receipts.first().cloned()));
+                }
+            }
+        }
+        let receipts = self
+            .store()
+            .iter_prefix(COL_RECEIPTS, hash.as_ref())
+            .map(|(_, value)| Vec::<Receipt>::try_from_slice(&value))
+            .collect::<Result<Vec<_>, _>>()?;
+        for receipt in receipts.iter().flatten() {
+            results.push((receipt.receipt_id, true, Some(receipt.receipt_id)));
+        }
+        results
+            .into_iter()
+            .map(|(result_hash, is_receipt, receipt_id)| {
+                let origin = match receipt_id {
+                    Some(receipt_id) => self
+                        .store()
+                        .get_ser::<ReceiptOrigin>(COL_RECEIPT_ORIGIN, receipt_id.as_ref())?,
+                    None => None,
+                };
+                Ok((result_hash, is_receipt, origin))
+            })
+            .collect()
+    }
+
+    /// Deletes the result, and the origin of the receipt if it's a result of the receipt.
+    fn delete_result(
+        &mut self,
+        store_update: &mut StoreUpdate,
+        result_hash: &CryptoHash,
+        is_receipt: bool,
+    ) {
+        self.delete_cached(store_update, COL_TRANSACTION_RESULT, result_hash.as_ref());
+        if is_receipt {
+            self.delete_cached(store_update, COL_RECEIPT_ORIGIN, result_hash.as_ref());
+        }
+    }
+
+    /// Deletes receipts the block sends to all the shards.
+    fn delete_receipts(
+        &mut self,
+        hash: &CryptoHash,
+        store_update: &mut StoreUpdate,
+        cold_update: &mut Option<StoreUpdate>,
+    ) -> Result<(), Error> {
+        let keys: Vec<_> =
+            self.store().iter_prefix(COL_RECEIPTS, hash.as_ref()).map(|(key, _)| key).collect();
+        for key in keys {
+            self.copy_to_cold(cold_update, COL_RECEIPTS, &key)?;
+            self.delete_cached(store_update, COL_RECEIPTS, &key);
+        }
+        Ok(())
+    }
+
+    /// Deletes record of a cached column, it's removed from the cache once the update is committed.
+    fn delete_cached(&mut self, store_update: &mut StoreUpdate, col: Option<u32>, key: &[u8]) {
+        store_update.delete(col, key);
+        self.deleted_keys.push((col, key.to_vec()));
     }
 
     /// Merge another StoreUpdate into this one
@@ -507,24 +1494,110 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         self.store_updates.push(store_update);
     }
 
+    /// Saves the block the state is being synced for, so that the sync resumes after a restart.
+    pub fn save_state_sync_hash(&mut self, sync_hash: &CryptoHash) -> Result<(), Error> {
+        let mut store_update = self.store().store_update();
+        store_update.set_ser(COL_BLOCK_MISC, STATE_SYNC_KEY, sync_hash)?;
+        self.merge(store_update);
+        Ok(())
+    }
+
+    /// Saves the shards which state was downloaded by the state sync.
+    pub fn save_synced_shards(&mut self, shard_ids: &Vec<ShardId>) -> Result<(), Error> {
+        let mut store_update = self.store().store_update();
+        store_update.set_ser(COL_BLOCK_MISC, SYNCED_SHARDS_KEY, shard_ids)?;
+        self.merge(store_update);
+        Ok(())
+    }
+
+    /// Saves downloaded and validated state part together with the receipts of the shard.
+    pub fn save_state_part(
+        &mut self,
+        sync_hash: &CryptoHash,
+        shard_id: ShardId,
+        part: &StatePart,
+        receipts: &Vec<Receipt>,
+    ) -> Result<(), Error> {
+        let mut store_update = self.store().store_update();
+        store_update.set_ser(
+            COL_STATE_PARTS,
+            &state_part_key(sync_hash, shard_id, part.part_id),
+            part,
+        )?;
+        store_update.set_ser(COL_STATE_PARTS, &receipts_key(sync_hash, shard_id), receipts)?;
+        self.merge(store_update);
+        Ok(())
+    }
+
+    /// Removes given state parts of the shard, so that they are downloaded again.
+    pub fn delete_state_parts(
+        &mut self,
+        sync_hash: &CryptoHash,
+        shard_id: ShardId,
+        part_ids: &[u64],
+    ) {
+        let mut store_update = self.store().store_update();
+        for part_id in part_ids {
+            store_update.delete(COL_STATE_PARTS, &state_part_key(sync_hash, shard_id, *part_id));
+        }
+        self.merge(store_update);
+    }
+
+    /// Removes all the downloaded state parts of the shard and its receipts.
+    pub fn clear_shard_state_parts(&mut self, sync_hash: &CryptoHash, shard_id: ShardId) {
+        let mut store_update = self.store().store_update();
+        let prefix = receipts_key(sync_hash, shard_id);
+        for (key, _) in self.store().iter_prefix(COL_STATE_PARTS, &prefix) {
+            store_update.delete(COL_STATE_PARTS, &key);
+        }
+        self.merge(store_update);
+    }
+
+    /// Removes the state sync block and all the downloaded state parts.
+    pub fn clear_state_sync(&mut self) {
+        let mut store_update = self.store().store_update();
+        store_update.delete(COL_BLOCK_MISC, STATE_SYNC_KEY);
+        for (key, _) in self.store().iter(COL_STATE_PARTS) {
+            store_update.delete(COL_STATE_PARTS, &key);
+        }
+        self.merge(store_update);
+    }
+
     pub fn finalize(mut self) -> Result<StoreUpdate, Error> {
+        self.finalize_update()
+    }
+
+    fn finalize_update(&mut self) -> Result<StoreUpdate, Error> {
         let mut store_update = self.store().store_update();
-        if let Some(t) = self.head {
+        let (finalized, discarded) = match self.gc_stop_height {
+            Some(stop_height) if self.trie_changes.is_some() => {
+                self.gc_old_data(stop_height, &mut store_update)?
+            }
+            _ => (vec![], vec![]),
+        };
+        let mut flat_state_deltas = HashMap::new();
+        if let Some(head) = self.head.clone() {
+            self.update_flat_state(&head, &mut flat_state_deltas, &mut store_update)?;
+        }
+        if let Some(t) = self.head.take() {
             store_update.set_ser(COL_BLOCK_MISC, HEAD_KEY, &t).map_err::<Error, _>(|e| e.into())?;
         }
-        if let Some(t) = self.tail {
+        if let Some(t) = self.tail.take() {
             store_update.set_ser(COL_BLOCK_MISC, TAIL_KEY, &t).map_err::<Error, _>(|e| e.into())?;
         }
-        if let Some(t) = self.header_head {
+        if let Some(t) = self.header_head.take() {
             store_update
                 .set_ser(COL_BLOCK_MISC, HEADER_HEAD_KEY, &t)
                 .map_err::<Error, _>(|e| e.into())?;
         }
-        if let Some(t) = self.sync_head {
+        if let Some(t) = self.sync_head.take() {
             store_update
                 .set_ser(COL_BLOCK_MISC, SYNC_HEAD_KEY, &t)
                 .map_err::<Error, _>(|e| e.into())?;
         }
+        if let Some(identity) = self.genesis_identity.take() {
+            store_update.set_ser(COL_BLOCK_MISC, GENESIS_IDENTITY_KEY, &identity)?;
+        }
         for (hash, block) in self.blocks.drain() {
             store_update
                 .set_ser(COL_BLOCK, hash.as_ref(), &block)
@@ -532,6 +1605,7 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
         }
         for hash in self.deleted_blocks.drain() {
             store_update.delete(COL_BLOCK, hash.as_ref());
+            self.deleted_keys.push((COL_BLOCK, hash.as_ref().to_vec()));
         }
         for (hash, header) in self.headers.drain() {
             store_update
@@ -557,26 +1631,83 @@ impl<'a, T: ChainStoreAccess> ChainStoreUpdate<'a, T> {
                 store_update.delete(COL_BLOCK_INDEX, &index_to_bytes(height));
             }
         }
-        for (hash, receipt) in self.receipts.drain() {
-            store_update.set_ser(COL_RECEIPTS, hash.as_ref(), &receipt)?;
+        for (hash, merkle_tree) in self.block_merkle_tree.drain() {
+            store_update.set_ser(COL_BLOCK_MERKLE_TREE, hash.as_ref(), &merkle_tree)?;
+        }
+        for (ordinal, hash) in self.block_ordinal.drain() {
+            if let Some(hash) = hash {
+                store_update.set_ser(COL_BLOCK_ORDINAL, &index_to_bytes(ordinal), &hash)?;
+            } else {
+                store_update.delete(COL_BLOCK_ORDINAL, &index_to_bytes(ordinal));
+            }
+        }
+        for ((hash, shard_id), receipt) in self.receipts.drain() {
+            store_update.set_ser(COL_RECEIPTS, &receipts_key(&hash, shard_id), &receipt)?;
         }
         for (hash, tx_result) in self.transaction_results.drain() {
             store_update.set_ser(COL_TRANSACTION_RESULT, hash.as_ref(), &tx_result)?;
         }
-        if let Some(trie_changes) = self.trie_changes {
+        for (receipt_id, origin) in self.receipt_origins.drain() {
+            store_update.set_ser(COL_RECEIPT_ORIGIN, receipt_id.as_ref(), &origin)?;
+        }
+        for (hash, approvals) in self.block_approvals.drain() {
+            store_update.set_ser(COL_BLOCK_APPROVALS, hash.as_ref(), &approvals)?;
+        }
+        for (hash, block) in self.orphans.drain() {
+            if let Some(block) = block {
+                store_update.set_ser(COL_ORPHANS, hash.as_ref(), &block)?;
+            } else {
+                store_update.delete(COL_ORPHANS, hash.as_ref());
+            }
+        }
+        for (hash, index) in self.bad_blocks.drain() {
+            if let Some(index) = index {
+                store_update.set_ser(COL_BAD_BLOCKS, hash.as_ref(), &index)?;
+            } else {
+                store_update.delete(COL_BAD_BLOCKS, hash.as_ref());
+            }
+        }
+        if let Some((height, hash, trie_changes)) = self.trie_changes.take() {
             trie_changes
-                .insertions_into(&mut store_update)
+                .insertions_with_gc_into(&finalized, &discarded, &mut store_update)
                 .map_err(|err| ErrorKind::Other(err.to_string()))?;
-            // TODO: save deletions separately for garbage collection.
+            let key = trie_changes_key(height, &hash);
+            store_update.set_ser(COL_TRIE_CHANGES, &key, trie_changes.trie_changes())?;
+            flat_state_deltas
+                .entry(hash)
+                .or_insert_with(|| FlatStateDelta::new(trie_changes.state_changes().clone()));
+        }
+        for (hash, delta) in flat_state_deltas.iter() {
+            store_update.set_ser(COL_STATE_CHANGES, hash.as_ref(), delta)?;
         }
-        for other in self.store_updates {
+        for other in self.store_updates.drain(..) {
             store_update.merge(other);
         }
         Ok(store_update)
     }
+}
 
+impl<'a> ChainStoreUpdate<'a, ChainStore> {
+    /// Writes the changes into the store and removes deleted records from the caches.
     pub fn commit(self) -> Result<(), Error> {
-        let store_update = self.finalize()?;
-        store_update.commit().map_err(|e| e.into())
+        self.commit_timed().map(|_| ())
+    }
+
+    /// Same as `commit`, returns time spent preparing the update, including the trie changes and
+    /// garbage collection, and time spent writing it into the database.
+    pub fn commit_timed(mut self) -> Result<(Duration, Duration), Error> {
+        let start = Instant::now();
+        let store_update = self.finalize_update()?;
+        let finalized = Instant::now();
+        if self.chain_store.buffered_writes {
+            store_update.commit_buffered()?;
+        } else {
+            store_update.commit()?;
+        }
+        let committed = Instant::now();
+        for (col, key) in self.deleted_keys.iter() {
+            self.chain_store.invalidate_cache(*col, key);
+        }
+        Ok((finalized - start, committed - finalized))
     }
 }
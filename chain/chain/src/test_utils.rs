@@ -8,10 +8,12 @@ use near_primitives::account::Account;
 use near_primitives::hash::CryptoHash;
 use near_primitives::receipt::Receipt;
 use near_primitives::transaction::{
-    SignedTransaction, TransactionLog, TransactionResult, TransactionStatus,
+    InvalidTxError, SignedTransaction, TransactionLog, TransactionResult, TransactionStatus,
 };
-use near_primitives::types::{AccountId, BlockIndex, MerkleHash, ShardId, ValidatorStake};
-use near_primitives::views::QueryResponse;
+use near_primitives::types::{
+    AccountId, BlockIndex, MerkleHash, ShardId, StatePart, ValidatorStake,
+};
+use near_primitives::views::{ProtocolConfigView, QueryResponse, UnstakeInfoView};
 use near_store::test_utils::create_test_store;
 use near_store::{PartialStorage, Store, StoreUpdate, Trie, TrieChanges, WrappedTrieChanges};
 
@@ -25,14 +27,19 @@ pub struct KeyValueRuntime {
     trie: Arc<Trie>,
     root: MerkleHash,
     validators: Vec<ValidatorStake>,
+    tx_validity_period: BlockIndex,
 }
 
 impl KeyValueRuntime {
     pub fn new(store: Arc<Store>) -> Self {
-        Self::new_with_validators(store, vec!["test".to_string()])
+        Self::new_with_validators(store, vec!["test".to_string()], 100)
     }
 
-    pub fn new_with_validators(store: Arc<Store>, validators: Vec<AccountId>) -> Self {
+    pub fn new_with_validators(
+        store: Arc<Store>,
+        validators: Vec<AccountId>,
+        tx_validity_period: BlockIndex,
+    ) -> Self {
         let trie = Arc::new(Trie::new(store.clone()));
         KeyValueRuntime {
             store,
@@ -46,6 +53,7 @@ impl KeyValueRuntime {
                     amount: 1_000_000,
                 })
                 .collect(),
+            tx_validity_period,
         }
     }
 
@@ -127,7 +135,7 @@ impl RuntimeAdapter for KeyValueRuntime {
         _shard_id: ShardId,
         _state_root: MerkleHash,
         transaction: SignedTransaction,
-    ) -> Result<ValidTransaction, String> {
+    ) -> Result<ValidTransaction, InvalidTxError> {
         Ok(ValidTransaction { transaction })
     }
 
@@ -182,6 +190,7 @@ impl RuntimeAdapter for KeyValueRuntime {
                     logs: vec![],
                     receipts: vec![],
                     result: None,
+                    gas_burnt: Some(0),
                 },
             });
         }
@@ -195,6 +204,28 @@ impl RuntimeAdapter for KeyValueRuntime {
         ))
     }
 
+    fn simulate_transaction(
+        &self,
+        shard_id: ShardId,
+        state_root: &MerkleHash,
+        block_index: BlockIndex,
+        prev_block_hash: &CryptoHash,
+        transaction: &SignedTransaction,
+    ) -> Result<(Vec<TransactionLog>, ReceiptResult), Box<dyn std::error::Error>> {
+        let (_, _, tx_results, receipt_result, _, _) = self
+            .apply_transactions_with_optional_storage_proof(
+                shard_id,
+                state_root,
+                block_index,
+                prev_block_hash,
+                &CryptoHash::default(),
+                &vec![],
+                &vec![transaction.clone()],
+                false,
+            )?;
+        Ok((tx_results, receipt_result))
+    }
+
     fn query(
         &self,
         _state_root: MerkleHash,
@@ -205,12 +236,57 @@ impl RuntimeAdapter for KeyValueRuntime {
         Ok(QueryResponse::ViewAccount(Account::new(1000, CryptoHash::default(), 0).into()))
     }
 
-    fn dump_state(
+    fn get_protocol_config(&self) -> ProtocolConfigView {
+        ProtocolConfigView {
+            transaction_validity_period: self.tx_validity_period,
+            ..Default::default()
+        }
+    }
+
+    fn get_unstake_info(
+        &self,
+        _last_hash: &CryptoHash,
+        account_id: &AccountId,
+    ) -> Result<UnstakeInfoView, Box<dyn std::error::Error>> {
+        // Validators never change.
+        let stake = self
+            .validators
+            .iter()
+            .find(|validator| &validator.account_id == account_id)
+            .map(|validator| validator.amount)
+            .unwrap_or(0);
+        Ok(UnstakeInfoView {
+            account_id: account_id.clone(),
+            locked: stake,
+            final_stake: stake,
+            validation_end_height: None,
+            unlock_height: None,
+        })
+    }
+
+    fn obtain_state_part(
+        &self,
+        _shard_id: ShardId,
+        _state_root: MerkleHash,
+        part_id: u64,
+    ) -> Result<StatePart, Box<dyn std::error::Error>> {
+        Ok(StatePart {
+            part_id,
+            num_parts: 1,
+            start_key: vec![],
+            end_key: None,
+            data: vec![],
+            proof: vec![],
+        })
+    }
+
+    fn validate_state_part(
         &self,
         _shard_id: ShardId,
         _state_root: MerkleHash,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        Ok(vec![])
+        _part: &StatePart,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
     }
 
     fn set_state(
@@ -231,8 +307,12 @@ pub fn setup_with_tx_validity_period(
     validity: BlockIndex,
 ) -> (Chain, Arc<KeyValueRuntime>, Arc<InMemorySigner>) {
     let store = create_test_store();
-    let runtime = Arc::new(KeyValueRuntime::new(store.clone()));
-    let chain = Chain::new(store, runtime.clone(), Utc::now(), validity).unwrap();
+    let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+        store.clone(),
+        vec!["test".to_string()],
+        validity,
+    ));
+    let chain = Chain::new(store, runtime.clone(), Utc::now()).unwrap();
     let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
     (chain, runtime, signer)
 }
@@ -1,4 +1,5 @@
 use std::collections::HashMap;
+use std::time::Duration;
 
 use borsh::{BorshDeserialize, BorshSerialize};
 
@@ -6,12 +7,16 @@ use near_crypto::{Signature, Signer};
 pub use near_primitives::block::{Block, BlockHeader, Weight};
 use near_primitives::hash::CryptoHash;
 use near_primitives::receipt::Receipt;
-use near_primitives::transaction::{SignedTransaction, TransactionLog};
-use near_primitives::types::{AccountId, BlockIndex, MerkleHash, ShardId, ValidatorStake};
-use near_primitives::views::QueryResponse;
+use near_primitives::transaction::{InvalidTxError, SignedTransaction, TransactionLog};
+use near_primitives::types::{
+    AccountId, BlockIndex, MerkleHash, ShardId, StatePart, ValidatorStake,
+};
+use near_primitives::views::{
+    BlockProfileView, ProtocolConfigView, QueryResponse, UnstakeInfoView,
+};
 use near_store::{PartialStorage, StoreUpdate, WrappedTrieChanges};
 
-use crate::error::Error;
+use crate::error::{Error, ErrorKind};
 
 #[derive(Eq, PartialEq, Debug)]
 pub enum BlockStatus {
@@ -24,6 +29,51 @@ pub enum BlockStatus {
     Reorg,
 }
 
+/// Time spent on the stages of processing a block.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockProfile {
+    pub hash: CryptoHash,
+    pub height: BlockIndex,
+    /// Verification of the block producer's signature.
+    pub signature_check: Duration,
+    /// Applying the transactions and receipts in the runtime.
+    pub apply_transactions: Duration,
+    /// Preparing the store update with the trie changes, indices and garbage collection.
+    pub trie_commit: Duration,
+    /// Writing the update into the database.
+    pub store_commit: Duration,
+    /// Whole processing of the block, including the stages above.
+    pub total: Duration,
+}
+
+impl From<BlockProfile> for BlockProfileView {
+    fn from(profile: BlockProfile) -> Self {
+        BlockProfileView {
+            hash: profile.hash.into(),
+            height: profile.height,
+            signature_check: profile.signature_check.as_micros() as u64,
+            apply_transactions: profile.apply_transactions.as_micros() as u64,
+            trie_commit: profile.trie_commit.as_micros() as u64,
+            store_commit: profile.store_commit.as_micros() as u64,
+            total: profile.total.as_micros() as u64,
+        }
+    }
+}
+
+/// Changes of the chain reported to the subscribers of the chain.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChainEvent {
+    /// Head switched to another fork.
+    Reorg {
+        /// Last block shared by the old and the new chain.
+        common_ancestor: CryptoHash,
+        /// Blocks of the old chain above the common ancestor, in ascending order of height.
+        abandoned: Vec<CryptoHash>,
+        /// Blocks of the new chain above the common ancestor, in ascending order of height.
+        canonical: Vec<CryptoHash>,
+    },
+}
+
 /// Options for block origin.
 #[derive(Eq, PartialEq)]
 pub enum Provenance {
@@ -102,7 +152,7 @@ pub trait RuntimeAdapter: Send + Sync {
         shard_id: ShardId,
         state_root: MerkleHash,
         transaction: SignedTransaction,
-    ) -> Result<ValidTransaction, String>;
+    ) -> Result<ValidTransaction, InvalidTxError>;
 
     /// Add proposals for validators.
     fn add_validator_proposals(
@@ -181,6 +231,18 @@ pub trait RuntimeAdapter: Send + Sync {
         Box<dyn std::error::Error>,
     >;
 
+    /// Executes transaction on top of given state root without committing any changes.
+    /// Returns results of the transaction and of the receipts executed locally,
+    /// plus the receipts that would be sent to other shards or accounts.
+    fn simulate_transaction(
+        &self,
+        shard_id: ShardId,
+        state_root: &MerkleHash,
+        block_index: BlockIndex,
+        prev_block_hash: &CryptoHash,
+        transaction: &SignedTransaction,
+    ) -> Result<(Vec<TransactionLog>, ReceiptResult), Box<dyn std::error::Error>>;
+
     /// Query runtime with given `path` and `data`.
     fn query(
         &self,
@@ -190,12 +252,32 @@ pub trait RuntimeAdapter: Send + Sync {
         data: &[u8],
     ) -> Result<QueryResponse, Box<dyn std::error::Error>>;
 
-    /// Read state as byte array from given state root.
-    fn dump_state(
+    /// Protocol parameters this runtime was created with.
+    fn get_protocol_config(&self) -> ProtocolConfigView;
+
+    /// When pending stake changes of the account take effect and its stake is unlocked,
+    /// as seen from the block with given hash.
+    fn get_unstake_info(
+        &self,
+        last_hash: &CryptoHash,
+        account_id: &AccountId,
+    ) -> Result<UnstakeInfoView, Box<dyn std::error::Error>>;
+
+    /// Read given part of the state at given state root, with the proof of its items.
+    fn obtain_state_part(
         &self,
         shard_id: ShardId,
         state_root: MerkleHash,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+        part_id: u64,
+    ) -> Result<StatePart, Box<dyn std::error::Error>>;
+
+    /// Check that all the items of the state part are proven to be in the state with given root.
+    fn validate_state_part(
+        &self,
+        shard_id: ShardId,
+        state_root: MerkleHash,
+        part: &StatePart,
+    ) -> Result<(), Box<dyn std::error::Error>>;
 
     /// Set state that expected to be given state root with provided payload.
     /// Returns error if failed to parse or if the resulting tree doesn't match the expected root.
@@ -237,18 +319,78 @@ impl Tip {
     }
 }
 
+/// Signature of a block producer approving a block.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApprovalSignature {
+    pub account_id: AccountId,
+    pub signature: Signature,
+}
+
+/// Network the store was initialized for, saved on the first start and checked on the next ones.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct GenesisIdentity {
+    pub chain_id: String,
+    pub genesis_config_hash: CryptoHash,
+}
+
+impl GenesisIdentity {
+    /// Checks that the store initialized for this network can be used with the given one.
+    pub fn check(&self, other: &GenesisIdentity) -> Result<(), Error> {
+        if self.chain_id != other.chain_id {
+            return Err(ErrorKind::Other(format!(
+                "Store was initialized for chain {:?}, but genesis config is for chain {:?}. Use another home directory or unsafe_reset_all to clear the data.",
+                self.chain_id, other.chain_id
+            ))
+            .into());
+        }
+        if self.genesis_config_hash != other.genesis_config_hash {
+            return Err(ErrorKind::Other(format!(
+                "Store of chain {:?} was initialized with genesis config {}, but genesis config {} is given",
+                self.chain_id, self.genesis_config_hash, other.genesis_config_hash
+            ))
+            .into());
+        }
+        Ok(())
+    }
+}
+
+impl From<&ProtocolConfigView> for GenesisIdentity {
+    fn from(config: &ProtocolConfigView) -> Self {
+        GenesisIdentity {
+            chain_id: config.chain_id.clone(),
+            genesis_config_hash: config.genesis_config_hash,
+        }
+    }
+}
+
+/// Approvals of a block, as included in the header of its child.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockApprovals {
+    /// Hash of the block which header includes the approvals.
+    pub child_hash: CryptoHash,
+    pub approvals: Vec<ApprovalSignature>,
+}
+
 /// Block approval by other block producers.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct BlockApproval {
     pub hash: CryptoHash,
     pub signature: Signature,
+    /// Account that approves the block.
+    pub account_id: AccountId,
+    /// Block producer of the next block, who collects the approvals.
     pub target: AccountId,
 }
 
 impl BlockApproval {
-    pub fn new(hash: CryptoHash, signer: &dyn Signer, target: AccountId) -> Self {
+    pub fn new(
+        hash: CryptoHash,
+        signer: &dyn Signer,
+        account_id: AccountId,
+        target: AccountId,
+    ) -> Self {
         let signature = signer.sign(hash.as_ref());
-        BlockApproval { hash, signature, target }
+        BlockApproval { hash, signature, account_id, target }
     }
 }
 
@@ -1,12 +1,28 @@
 use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
 
-use near_chain::test_utils::{setup, setup_with_tx_validity_period};
-use near_chain::{Block, ErrorKind, Provenance};
-use near_crypto::{KeyType, Signature, Signer};
+use chrono::Utc;
+
+use near_chain::metrics::DEEP_REORGS;
+use near_chain::test_utils::{setup, setup_with_tx_validity_period, KeyValueRuntime};
+use near_chain::{
+    verify_store, ApprovalSignature, Block, Chain, ChainEvent, ChainStore, ChainStoreAccess,
+    ErrorKind, Provenance, Tip,
+};
+use near_crypto::{InMemorySigner, KeyType, Signature, Signer};
 use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::merkle::verify_hash;
+use near_primitives::receipt::Receipt;
 use near_primitives::test_utils::init_test_logger;
 use near_primitives::transaction::{SignedTransaction, Transaction};
 use near_primitives::types::MerkleHash;
+use near_store::flat_state::{get_flat_state_head, StateChanges};
+use near_store::test_utils::{create_test_store, create_test_store_with_cold_store};
+use near_store::{
+    Trie, TrieChanges, WrappedTrieChanges, COL_BLOCK, COL_BLOCK_HEADER, COL_FLAT_STATE,
+    COL_RECEIPTS, COL_TRIE_CHANGES,
+};
 
 #[test]
 fn empty_chain() {
@@ -28,6 +44,30 @@ fn build_chain() {
     assert_eq!(chain.head().unwrap().height, 4);
 }
 
+#[test]
+fn block_ancestry_proofs() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let mut hashes = vec![chain.genesis().hash()];
+    for _ in 0..12 {
+        let prev = chain.head_header().unwrap();
+        let block = Block::empty(&prev, signer.clone());
+        hashes.push(block.hash());
+        chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    }
+    for (i, head_hash) in hashes.iter().enumerate() {
+        let root = chain.get_block_merkle_root(head_hash).unwrap();
+        for (j, hash) in hashes.iter().enumerate() {
+            let proof = chain.get_block_proof(hash, head_hash);
+            if j <= i {
+                assert!(verify_hash(root, &proof.unwrap(), *hash));
+            } else {
+                assert!(proof.is_err());
+            }
+        }
+    }
+}
+
 #[test]
 fn build_chain_with_orhpans() {
     init_test_logger();
@@ -76,6 +116,85 @@ fn build_chain_with_orhpans() {
     );
 }
 
+#[test]
+fn orphans_survive_restart() {
+    init_test_logger();
+    let store = create_test_store();
+    let genesis_time = Utc::now();
+    let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+        store.clone(),
+        vec!["test".to_string()],
+        100,
+    ));
+    let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+    let mut chain = Chain::new(store.clone(), runtime.clone(), genesis_time).unwrap();
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let b2 = Block::empty(&b1.header, signer.clone());
+    let b3 = Block::empty(&b2.header, signer.clone());
+    for block in vec![b2, b3] {
+        assert_eq!(
+            chain.process_block(block, Provenance::NONE, |_, _, _| {}).unwrap_err().kind(),
+            ErrorKind::Orphan
+        );
+    }
+    assert_eq!(chain.orphans_len(), 2);
+
+    // Orphans are reloaded after the restart and get processed once their parent arrives.
+    let mut chain = Chain::new(store.clone(), runtime.clone(), genesis_time).unwrap();
+    assert_eq!(chain.orphans_len(), 2);
+    let tip = chain.process_block(b1, Provenance::NONE, |_, _, _| {}).unwrap();
+    assert_eq!(tip.unwrap().height, 3);
+    assert_eq!(chain.orphans_len(), 0);
+    let chain = Chain::new(store, runtime, genesis_time).unwrap();
+    assert_eq!(chain.orphans_len(), 0);
+}
+
+#[test]
+fn bad_blocks_survive_restart() {
+    init_test_logger();
+    let store = create_test_store();
+    let genesis_time = Utc::now();
+    let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+        store.clone(),
+        vec!["test".to_string()],
+        100,
+    ));
+    let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+    let mut chain = Chain::new(store.clone(), runtime.clone(), genesis_time).unwrap();
+    let bad_block = Block::produce(
+        chain.genesis(),
+        1,
+        hash(&[1]),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    let bad_hash = bad_block.hash();
+    assert_eq!(
+        chain.process_block(bad_block.clone(), Provenance::NONE, |_, _, _| {}).unwrap_err().kind(),
+        ErrorKind::InvalidStateRoot
+    );
+    assert!(chain.is_bad_block(&bad_hash));
+    assert!(!chain.is_bad_block(&chain.genesis().hash()));
+
+    // Block is refused without validation, also after the restart.
+    let mut chain = Chain::new(store, runtime, genesis_time).unwrap();
+    assert!(chain.is_bad_block(&bad_hash));
+    assert_eq!(
+        chain.process_block(bad_block.clone(), Provenance::NONE, |_, _, _| {}).unwrap_err().kind(),
+        ErrorKind::KnownBadBlock
+    );
+    assert_eq!(
+        chain.process_block_header(&bad_block.header).unwrap_err().kind(),
+        ErrorKind::KnownBadBlock
+    );
+    // Valid blocks are still accepted.
+    let block = Block::empty(chain.genesis(), signer);
+    assert!(chain.process_block(block, Provenance::NONE, |_, _, _| {}).unwrap().is_some());
+}
+
 #[test]
 fn build_chain_with_skips_and_forks() {
     init_test_logger();
@@ -112,6 +231,487 @@ fn build_chain_with_skips_and_forks() {
     assert_eq!(chain.get_header_by_height(5).unwrap().inner.height, 5);
 }
 
+#[test]
+fn fork_tree() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let b2 = Block::empty(&b1.header, signer.clone());
+    let b3 = Block::empty(&b1.header, signer.clone());
+    let b4 = Block::empty(&b3.header, signer.clone());
+    let b5 = Block::empty(&b4.header, signer);
+    let (fork_hash, head_hash) = (b2.hash(), b5.hash());
+    for block in vec![b1, b2, b3, b4, b5] {
+        chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    }
+    let tips = chain.fork_tree(10).unwrap();
+    assert_eq!(
+        tips.iter().map(|tip| tip.last_block_hash).collect::<Vec<_>>(),
+        vec![head_hash, fork_hash]
+    );
+    assert_eq!(tips[0], chain.head().unwrap());
+    assert!(tips[0].total_weight > tips[1].total_weight);
+    // Fork further below the head than requested is not reported.
+    assert_eq!(chain.fork_tree(1).unwrap().len(), 1);
+}
+
+#[test]
+fn receipts_per_shard() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let b1 = Block::empty(chain.genesis(), signer);
+    let block_hash = b1.hash();
+    chain.process_block(b1, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    assert!(chain.get_receipts(&block_hash, 0).unwrap().is_empty());
+    assert!(chain.get_receipts(&block_hash, 1).is_err());
+
+    // Receipts saved before they were split by shard are read for shard 0.
+    let legacy_hash = hash(&[1, 2, 3]);
+    let mut store_update = chain.store().store().store_update();
+    store_update.set_ser(COL_RECEIPTS, legacy_hash.as_ref(), &Vec::<Receipt>::new()).unwrap();
+    store_update.commit().unwrap();
+    assert!(chain.get_receipts(&legacy_hash, 0).unwrap().is_empty());
+    assert!(chain.get_receipts(&legacy_hash, 1).is_err());
+}
+
+#[test]
+fn untracked_shards() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    assert_eq!(chain.tracked_shard_ids(), vec![0]);
+    chain.set_tracked_shards(vec![1]).unwrap();
+    assert!(!chain.cares_about_shard(0));
+    assert!(chain.tracked_shard_ids().is_empty());
+    // Blocks are accepted without being applied, so no state is saved for them.
+    for i in 0..2 {
+        let prev = chain.head_header().unwrap();
+        let block = Block::empty(&prev, signer.clone());
+        let block_hash = block.hash();
+        let tip = chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+        assert_eq!(tip.unwrap().height, i + 1);
+        assert!(chain.get_post_state_root(&block_hash).is_err());
+        assert!(chain.get_receipts(&block_hash, 0).is_err());
+    }
+}
+
+#[test]
+fn tracked_shards_without_synced_state() {
+    init_test_logger();
+    let (mut chain, _, _) = setup();
+    // State sync downloaded none of the shards.
+    let mut store_update = chain.mut_store().store_update();
+    store_update.save_synced_shards(&vec![]).unwrap();
+    store_update.commit().unwrap();
+    assert!(chain.set_tracked_shards(vec![0]).is_err());
+    assert!(chain.set_tracked_shards(vec![]).is_err());
+    assert_eq!(chain.tracked_shard_ids(), vec![0]);
+    assert!(chain.set_tracked_shards(vec![1]).is_ok());
+}
+
+#[test]
+fn block_approvals() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let produce = |height, signature| {
+        let mut approvals = HashMap::default();
+        approvals.insert(0, signature);
+        Block::produce(
+            &b1.header,
+            height,
+            MerkleHash::default(),
+            CryptoHash::default(),
+            vec![],
+            approvals,
+            vec![],
+            signer.clone(),
+        )
+    };
+    // Signature of another block doesn't approve the block.
+    let forged = produce(2, signer.sign(chain.genesis().hash().as_ref()));
+    let signature = signer.sign(b1.hash().as_ref());
+    let b2 = produce(3, signature.clone());
+    let (b1_hash, b2_hash) = (b1.hash(), b2.hash());
+    for block in vec![b1.clone(), forged] {
+        chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    }
+    assert!(chain.get_block_approvals(&b1_hash).is_err());
+    chain.process_block(b2, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    let genesis_hash = chain.genesis().hash();
+    assert!(chain.get_block_approvals(&genesis_hash).is_err());
+    let approvals = chain.get_block_approvals(&b1_hash).unwrap();
+    assert_eq!(approvals.child_hash, b2_hash);
+    assert_eq!(
+        approvals.approvals,
+        vec![ApprovalSignature { account_id: "test".to_string(), signature }]
+    );
+}
+
+#[test]
+fn block_profiles() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let b2 = Block::empty(&b1.header, signer.clone());
+    let b1_hash = b1.hash();
+    assert!(chain.process_block(b2.clone(), Provenance::NONE, |_, _, _| {}).is_err());
+    assert!(chain.block_profiles().is_empty());
+    // Orphan processed along with its parent gets its own profile.
+    chain.process_block(b1, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    let profiles: Vec<_> = chain.block_profiles().iter().cloned().collect();
+    assert_eq!(profiles.len(), 2);
+    assert_eq!((profiles[0].hash, profiles[0].height), (b1_hash, 1));
+    assert_eq!((profiles[1].hash, profiles[1].height), (b2.hash(), 2));
+    for profile in profiles {
+        assert!(
+            profile.total
+                >= profile.signature_check
+                    + profile.apply_transactions
+                    + profile.trie_commit
+                    + profile.store_commit
+        );
+    }
+}
+
+#[test]
+fn reset_to_height() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let tx = SignedTransaction::new(
+        Signature::empty(KeyType::ED25519),
+        Transaction {
+            signer_id: "".to_string(),
+            public_key: signer.public_key(),
+            nonce: 0,
+            receiver_id: "".to_string(),
+            block_hash: chain.genesis().hash(),
+            actions: vec![],
+        },
+    );
+    let tx_hash = tx.get_hash();
+    let b2 = Block::produce(
+        &b1.header,
+        2,
+        b1.header.inner.prev_state_root,
+        b1.header.inner.epoch_hash,
+        vec![tx],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    let b3 = Block::empty(&b2.header, signer.clone());
+    let orphan = Block::empty(&Block::empty(&b3.header, signer.clone()).header, signer.clone());
+    let fork = Block::produce(
+        &b1.header,
+        3,
+        MerkleHash::default(),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    let (b2_hash, b3_hash, fork_hash) = (b2.hash(), b3.hash(), fork.hash());
+    for block in vec![b1.clone(), b2.clone(), b3, fork] {
+        assert!(chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    assert!(chain.process_block(orphan, Provenance::NONE, |_, _, _| {}).is_err());
+    assert_eq!(chain.store().get_orphans().unwrap().len(), 1);
+    assert!(chain.get_transaction_result(&tx_hash).is_ok());
+    assert_eq!(chain.head().unwrap().last_block_hash, b3_hash);
+    assert!(chain.reset_to_height(4).is_err());
+
+    let tip = chain.reset_to_height(1).unwrap();
+    assert_eq!(tip.last_block_hash, b1.hash());
+    assert_eq!(chain.head().unwrap(), tip);
+    assert_eq!(chain.header_head().unwrap(), tip);
+    assert_eq!(chain.sync_head().unwrap(), tip);
+    for hash in vec![b2_hash, b3_hash, fork_hash] {
+        assert!(chain.get_block(&hash).is_err());
+        assert!(chain.get_block_header(&hash).is_err());
+    }
+    assert!(chain.get_header_by_height(2).is_err());
+    assert!(chain.get_transaction_result(&tx_hash).is_err());
+    assert!(chain.store().get_orphans().unwrap().is_empty());
+    // Chain continues from the new head.
+    let tip = chain.process_block(b2, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+    assert_eq!(tip.unwrap().last_block_hash, b2_hash);
+}
+
+#[test]
+fn refuse_deep_reorg() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    chain.set_max_reorg_depth(Some(2));
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let b2 = Block::empty(&b1.header, signer.clone());
+    let b3 = Block::empty(&b2.header, signer.clone());
+    let f4 = Block::produce(
+        chain.genesis(),
+        4,
+        MerkleHash::default(),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    let f5 = Block::empty(&f4.header, signer.clone());
+    let f6 = Block::empty(&f5.header, signer.clone());
+    let f7 = Block::empty(&f6.header, signer);
+    for block in vec![b1, b2, b3, f4, f5, f6] {
+        assert!(chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    assert_eq!(chain.head().unwrap().height, 3);
+    // Switching to the fork reverts all three blocks of the current chain.
+    assert_eq!(
+        chain.process_block(f7.clone(), Provenance::NONE, |_, _, _| {}).unwrap_err().kind(),
+        ErrorKind::DeepReorg(3, 2)
+    );
+    assert_eq!(chain.head().unwrap().height, 3);
+    assert!(DEEP_REORGS.load(Ordering::Relaxed) > 0);
+    chain.set_max_reorg_depth(None);
+    assert!(chain.process_block(f7, Provenance::NONE, |_, _, _| {}).is_ok());
+    assert_eq!(chain.head().unwrap().height, 7);
+}
+
+#[test]
+fn reorg_events() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let events = Arc::new(Mutex::new(vec![]));
+    let events1 = events.clone();
+    chain.subscribe_events(move |event| events1.lock().unwrap().push(event.clone()));
+    let genesis_hash = chain.genesis().hash();
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let b2 = Block::empty(&b1.header, signer.clone());
+    let f2 = Block::produce(
+        chain.genesis(),
+        2,
+        MerkleHash::default(),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    let f3 = Block::empty(&f2.header, signer.clone());
+    let f4 = Block::empty(&f3.header, signer);
+    let abandoned = vec![b1.hash(), b2.hash()];
+    let canonical = vec![f2.hash(), f3.hash(), f4.hash()];
+    for block in vec![b1, b2, f2, f3] {
+        assert!(chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    assert!(events.lock().unwrap().is_empty());
+    assert!(chain.process_block(f4, Provenance::NONE, |_, _, _| {}).is_ok());
+    assert_eq!(
+        *events.lock().unwrap(),
+        vec![ChainEvent::Reorg { common_ancestor: genesis_hash, abandoned, canonical }]
+    );
+}
+
+#[test]
+fn gc_old_blocks() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    chain.set_max_reorg_depth(Some(2));
+    chain.set_gc_num_epochs_to_keep(Some(0));
+    let mut blocks = vec![Block::empty(chain.genesis(), signer.clone())];
+    for _ in 1..6 {
+        let block = Block::empty(&blocks.last().unwrap().header, signer.clone());
+        blocks.push(block);
+    }
+    let fork = Block::produce(
+        chain.genesis(),
+        2,
+        MerkleHash::default(),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    assert!(chain.process_block(fork.clone(), Provenance::NONE, |_, _, _| {}).is_ok());
+    for block in blocks.iter() {
+        assert!(chain.process_block(block.clone(), Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    // Blocks more than the reorg depth behind the head of height 6 are collected, headers stay.
+    assert_eq!(chain.head().unwrap().height, 6);
+    assert!(chain.get_block(&fork.hash()).is_err());
+    for block in blocks[..3].iter() {
+        assert!(chain.get_block(&block.hash()).is_err());
+        assert!(chain.get_block_header(&block.hash()).is_ok());
+    }
+    for block in blocks[3..].iter() {
+        assert!(chain.get_block(&block.hash()).is_ok());
+    }
+}
+
+#[test]
+fn gc_with_unlimited_reorgs() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    chain.set_max_reorg_depth(None);
+    chain.set_gc_num_epochs_to_keep(Some(0));
+    let mut blocks = vec![Block::empty(chain.genesis(), signer.clone())];
+    for _ in 1..6 {
+        let block = Block::empty(&blocks.last().unwrap().header, signer.clone());
+        blocks.push(block);
+    }
+    for block in blocks.iter() {
+        assert!(chain.process_block(block.clone(), Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    // Without the reorg depth the kept epochs are counted from the head.
+    for block in blocks[..5].iter() {
+        assert!(chain.get_block(&block.hash()).is_err());
+    }
+    assert!(chain.get_block(&blocks[5].hash()).is_ok());
+}
+
+#[test]
+fn gc_moves_blocks_to_cold_store() {
+    init_test_logger();
+    let cold = create_test_store();
+    let store = create_test_store_with_cold_store(cold.clone());
+    let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+        store.clone(),
+        vec!["test".to_string()],
+        100,
+    ));
+    let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+    let mut chain = Chain::new(store.clone(), runtime, Utc::now()).unwrap();
+    chain.set_max_reorg_depth(Some(2));
+    chain.set_gc_num_epochs_to_keep(Some(0));
+    let mut blocks = vec![Block::empty(chain.genesis(), signer.clone())];
+    for _ in 1..6 {
+        let block = Block::empty(&blocks.last().unwrap().header, signer.clone());
+        blocks.push(block);
+    }
+    let fork = Block::produce(
+        chain.genesis(),
+        2,
+        MerkleHash::default(),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    assert!(chain.process_block(fork.clone(), Provenance::NONE, |_, _, _| {}).is_ok());
+    for block in blocks.iter() {
+        assert!(chain.process_block(block.clone(), Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    // Collected blocks of the main chain are moved to the cold store and still served.
+    assert_eq!(chain.head().unwrap().height, 6);
+    for block in blocks[..3].iter() {
+        assert!(!store.exists(COL_BLOCK, block.hash().as_ref()).unwrap());
+        assert!(cold.exists(COL_BLOCK, block.hash().as_ref()).unwrap());
+        assert_eq!(chain.get_block(&block.hash()).unwrap(), block);
+    }
+    // Trie changes with the discarded state are kept as well.
+    assert_eq!(cold.iter(COL_TRIE_CHANGES).count(), 3);
+    // Fork blocks are discarded.
+    assert!(chain.get_block(&fork.hash()).is_err());
+    assert!(!cold.exists(COL_BLOCK, fork.hash().as_ref()).unwrap());
+}
+
+/// Saves the block with given state changes, moving the head to it if `is_head`.
+fn save_block_with_state_changes(
+    chain_store: &mut ChainStore,
+    trie: Arc<Trie>,
+    block: &Block,
+    state_changes: StateChanges,
+    is_head: bool,
+) {
+    let trie_changes = WrappedTrieChanges::new(trie, TrieChanges::empty(MerkleHash::default()))
+        .with_state_changes(state_changes);
+    let mut store_update = chain_store.store_update();
+    store_update.save_block_header(block.header.clone());
+    store_update.save_post_state_root(&block.hash(), &MerkleHash::default());
+    store_update.save_trie_changes(block.header.inner.height, &block.hash(), trie_changes);
+    if is_head {
+        store_update.save_body_head(&Tip::from_header(&block.header));
+    }
+    store_update.commit().unwrap();
+}
+
+#[test]
+fn flat_state_follows_head() {
+    init_test_logger();
+    let store = create_test_store();
+    let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+        store.clone(),
+        vec!["test".to_string()],
+        100,
+    ));
+    let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+    let trie = Arc::new(Trie::new(store.clone()));
+    let mut chain = Chain::new(store.clone(), runtime, Utc::now()).unwrap();
+    let genesis = chain.genesis().clone();
+    let flat_value = |key: &[u8]| store.get(COL_FLAT_STATE, key).unwrap();
+    assert_eq!(get_flat_state_head(&store).unwrap().unwrap().block_hash, genesis.hash());
+
+    let b1 = Block::empty(&genesis, signer.clone());
+    let changes = vec![(b"a".to_vec(), Some(b"1".to_vec()))];
+    save_block_with_state_changes(chain.mut_store(), trie.clone(), &b1, changes, true);
+    assert_eq!(flat_value(b"a"), Some(b"1".to_vec()));
+
+    // Fork overtaking the block: the block is reverted and the fork blocks are applied.
+    let f1 = Block::produce(
+        &genesis,
+        2,
+        MerkleHash::default(),
+        CryptoHash::default(),
+        vec![],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    let f2 = Block::empty(&f1.header, signer.clone());
+    let changes = vec![(b"b".to_vec(), Some(b"2".to_vec()))];
+    save_block_with_state_changes(chain.mut_store(), trie.clone(), &f1, changes, false);
+    assert_eq!(flat_value(b"b"), None);
+    let changes = vec![(b"b".to_vec(), None), (b"c".to_vec(), Some(b"3".to_vec()))];
+    save_block_with_state_changes(chain.mut_store(), trie, &f2, changes, true);
+    assert_eq!(get_flat_state_head(&store).unwrap().unwrap().block_hash, f2.hash());
+    assert_eq!(flat_value(b"a"), None);
+    assert_eq!(flat_value(b"b"), None);
+    assert_eq!(flat_value(b"c"), Some(b"3".to_vec()));
+
+    // Moving back to the first block reverts the fork.
+    let mut store_update = chain.mut_store().store_update();
+    store_update.save_body_head(&Tip::from_header(&b1.header));
+    store_update.commit().unwrap();
+    assert_eq!(get_flat_state_head(&store).unwrap().unwrap().block_hash, b1.hash());
+    assert_eq!(flat_value(b"a"), Some(b"1".to_vec()));
+    assert_eq!(flat_value(b"c"), None);
+}
+
+#[test]
+fn gc_invalidates_caches() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    chain.set_max_reorg_depth(Some(2));
+    chain.set_gc_num_epochs_to_keep(Some(0));
+    let b1 = Block::empty(chain.genesis(), signer.clone());
+    let b1_hash = b1.hash();
+    assert!(chain.process_block(b1, Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    // Reading the block twice puts it into the cache and serves the second read from there.
+    let blocks_stats = chain.store().cache_stats().blocks;
+    assert!(chain.get_block(&b1_hash).is_ok());
+    assert!(chain.get_block(&b1_hash).is_ok());
+    assert_eq!(chain.store().cache_stats().blocks.hits, blocks_stats.hits + 1);
+    for _ in 0..4 {
+        let prev = chain.head_header().unwrap().clone();
+        let block = Block::empty(&prev, signer.clone());
+        assert!(chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    }
+    // Collected block is not served from the cache.
+    assert!(chain.get_block(&b1_hash).is_err());
+    assert!(chain.get_block_header(&b1_hash).is_ok());
+}
+
 #[test]
 fn test_apply_expired_tx() {
     init_test_logger();
@@ -171,3 +771,68 @@ fn test_tx_wrong_fork() {
     assert!(chain.process_block(b1, Provenance::PRODUCED, |_, _, _| {}).is_ok());
     assert!(chain.process_block(b2, Provenance::PRODUCED, |_, _, _| {}).is_err());
 }
+
+#[test]
+fn test_invalid_tx_root() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let tx = SignedTransaction::new(
+        Signature::empty(KeyType::ED25519),
+        Transaction {
+            signer_id: "".to_string(),
+            public_key: signer.public_key(),
+            nonce: 0,
+            receiver_id: "".to_string(),
+            block_hash: chain.genesis().hash(),
+            actions: vec![],
+        },
+    );
+    let mut b1 = Block::produce(
+        chain.genesis(),
+        1,
+        chain.genesis().inner.prev_state_root,
+        CryptoHash::default(),
+        vec![tx],
+        HashMap::default(),
+        vec![],
+        signer.clone(),
+    );
+    assert_eq!(b1.header.inner.tx_root, Block::compute_tx_root(&b1.transactions));
+    assert_ne!(b1.header.inner.tx_root, MerkleHash::default());
+    // Body that doesn't match the header is rejected.
+    b1.transactions.clear();
+    match chain.process_block(b1, Provenance::PRODUCED, |_, _, _| {}) {
+        Err(e) => assert_eq!(e.kind(), ErrorKind::InvalidTxRoot),
+        Ok(_) => panic!("Block with invalid transactions root was accepted"),
+    }
+}
+
+#[test]
+fn detect_store_corruption() {
+    init_test_logger();
+    let store = create_test_store();
+    let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+        store.clone(),
+        vec!["test".to_string()],
+        100,
+    ));
+    let mut chain = Chain::new(store.clone(), runtime, Utc::now()).unwrap();
+    let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+    let b1 = Block::empty(chain.genesis(), signer);
+    let b1_hash = b1.hash();
+    assert!(chain.process_block(b1, Provenance::PRODUCED, |_, _, _| {}).is_ok());
+    assert!(verify_store(&store).corrupted.is_empty());
+
+    let genesis_header = store.get(COL_BLOCK_HEADER, chain.genesis().hash().as_ref()).unwrap();
+    let mut store_update = store.store_update();
+    store_update.set(COL_BLOCK, b1_hash.as_ref(), &[1, 2, 3]);
+    store_update.set(COL_BLOCK_HEADER, b1_hash.as_ref(), &genesis_header.unwrap());
+    store_update.commit().unwrap();
+    assert_eq!(verify_store(&store).corrupted.len(), 2);
+    // Reads of the corrupted records fail with the corruption error, not as missing data.
+    let mut chain_store = ChainStore::new(store);
+    match chain_store.get_block(&b1_hash).unwrap_err().kind() {
+        ErrorKind::StorageCorruption(_) => {}
+        kind => panic!("Unexpected error {:?}", kind),
+    }
+}
@@ -1,6 +1,10 @@
+use std::sync::Arc;
+
 use near_chain::test_utils::setup;
-use near_chain::Block;
+use near_chain::{Block, ErrorKind, Provenance};
+use near_crypto::{InMemorySigner, KeyType};
 use near_primitives::test_utils::init_test_logger;
+use near_primitives::types::StatePart;
 
 #[test]
 fn chain_sync_headers() {
@@ -14,3 +18,95 @@ fn chain_sync_headers() {
     chain.sync_block_headers(headers.drain(1..).collect()).unwrap();
     assert_eq!(chain.sync_head().unwrap().height, 4);
 }
+
+#[test]
+fn chain_sync_headers_invalid_signature() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let other_signer = Arc::new(InMemorySigner::from_seed("other", KeyType::ED25519, "other"));
+    let mut headers = vec![chain.genesis().clone()];
+    for i in 0..8 {
+        let signer = if i == 5 { other_signer.clone() } else { signer.clone() };
+        headers.push(Block::empty(&headers[i], signer).header);
+    }
+    assert_eq!(
+        chain.sync_block_headers(headers.drain(1..).collect()).unwrap_err().kind(),
+        ErrorKind::InvalidSignature
+    );
+    // None of the headers is saved.
+    assert_eq!(chain.sync_head().unwrap().height, 0);
+    assert_eq!(chain.header_head().unwrap().height, 0);
+}
+
+#[test]
+fn blocks_pending_state_sync() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let mut blocks = vec![chain.get_block(&chain.genesis().hash()).unwrap().clone()];
+    for i in 0..4 {
+        blocks.push(Block::empty(&blocks[i].header, signer.clone()));
+    }
+    let headers = blocks[1..].iter().map(|block| block.header.clone()).collect();
+    chain.sync_block_headers(headers).unwrap();
+    // State is synced for the block at height 3, blocks on top of the synced headers wait for it.
+    chain.start_state_sync(blocks[3].hash()).unwrap();
+    for block in vec![blocks[4].clone(), blocks[3].clone()] {
+        assert_eq!(
+            chain.process_block(block, Provenance::SYNC, |_, _, _| {}).unwrap_err().kind(),
+            ErrorKind::BlockPendingState
+        );
+    }
+    assert_eq!(chain.blocks_pending_state_len(), 2);
+    assert_eq!(chain.orphans_len(), 0);
+
+    chain.set_shard_state(0, blocks[3].hash(), vec![], vec![]).unwrap();
+    let tip = chain.finish_state_sync(|_, _, _| {}).unwrap();
+    assert_eq!(tip.unwrap().height, 4);
+    assert_eq!(chain.head().unwrap().height, 4);
+    assert_eq!(chain.blocks_pending_state_len(), 0);
+    assert_eq!(chain.orphans_len(), 0);
+}
+
+#[test]
+fn state_sync_progress_saved() {
+    init_test_logger();
+    let (mut chain, _, signer) = setup();
+    let genesis = chain.genesis().clone();
+    let block = Block::empty(&genesis, signer);
+    chain.sync_block_headers(vec![block.header.clone()]).unwrap();
+    assert_eq!(chain.get_state_sync_hash().unwrap(), None);
+    chain.start_state_sync(block.hash()).unwrap();
+    assert_eq!(chain.get_state_sync_hash().unwrap(), Some(block.hash()));
+
+    let parts: Vec<_> = (0..3)
+        .rev()
+        .map(|part_id| StatePart {
+            part_id,
+            num_parts: 3,
+            start_key: if part_id == 0 { vec![] } else { vec![part_id as u8] },
+            end_key: if part_id == 2 { None } else { Some(vec![part_id as u8 + 1]) },
+            data: vec![part_id as u8],
+            proof: vec![],
+        })
+        .collect();
+    for part in parts.iter() {
+        chain.save_state_part(&block.hash(), 0, part, &vec![]).unwrap();
+    }
+    let (saved_parts, receipts) = chain.get_state_parts(&block.hash(), 0).unwrap();
+    assert_eq!(saved_parts.iter().map(|part| part.part_id).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(receipts.is_empty());
+    assert!(chain.get_state_parts(&block.hash(), 1).unwrap().0.is_empty());
+
+    // Wrong parts are removed to be downloaded again.
+    chain.delete_state_parts(&block.hash(), 0, Some(&[1][..])).unwrap();
+    let (saved_parts, _) = chain.get_state_parts(&block.hash(), 0).unwrap();
+    assert_eq!(saved_parts.iter().map(|part| part.part_id).collect::<Vec<_>>(), vec![0, 2]);
+    chain.delete_state_parts(&block.hash(), 0, None).unwrap();
+    assert!(chain.get_state_parts(&block.hash(), 0).unwrap().0.is_empty());
+
+    // Progress is removed once the state sync is finished.
+    chain.set_shard_state(0, block.hash(), vec![], vec![]).unwrap();
+    chain.finish_state_sync(|_, _, _| {}).unwrap();
+    assert_eq!(chain.get_state_sync_hash().unwrap(), None);
+    assert!(chain.get_state_parts(&block.hash(), 0).unwrap().0.is_empty());
+}
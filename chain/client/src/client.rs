@@ -1,46 +1,57 @@
 //! Client is responsible for tracking the chain and related pieces of infrastructure.
 //! Block production is done in done in this actor as well (at the moment).
 
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::time::{Duration, Instant};
 
+use actix::dev::MessageResponse;
 use actix::{
-    Actor, ActorFuture, Addr, AsyncContext, Context, ContextFutureSpawner, Handler, Recipient,
-    WrapFuture,
+    Actor, ActorFuture, Addr, AsyncContext, Context, ContextFutureSpawner, Handler, Message,
+    Recipient, ResponseFuture, System, WrapFuture,
 };
 use borsh::BorshSerialize;
 use chrono::{DateTime, Utc};
-use futures::Future;
+use futures::sync::oneshot;
+use futures::{future, Future};
 use log::{debug, error, info, warn};
 
 use near_chain::{
-    Block, BlockApproval, BlockHeader, BlockStatus, Chain, ErrorKind, Provenance, RuntimeAdapter,
-    ValidTransaction,
+    Block, BlockApproval, BlockArchive, BlockHeader, BlockStatus, Chain, ChainEvent,
+    ChainStoreAccess, ErrorKind, Provenance, RuntimeAdapter, ValidTransaction,
 };
-use near_crypto::Signature;
+use near_crypto::{rotated_key_paths, InMemorySigner, KeyFile, Signature};
 use near_network::types::{
-    AnnounceAccount, AnnounceAccountRoute, NetworkInfo, PeerId, ReasonForBan,
+    AnnounceAccount, AnnounceAccountRoute, CorrelationId, NetworkInfo, PeerId, ReasonForBan,
 };
 use near_network::{
     NetworkClientMessages, NetworkClientResponses, NetworkRequests, NetworkResponses,
 };
-use near_pool::TransactionPool;
+use near_pool::{Error as PoolError, TransactionPool};
 use near_primitives::hash::{hash, CryptoHash};
 use near_primitives::receipt::Receipt;
-use near_primitives::transaction::{check_tx_history, SignedTransaction};
-use near_primitives::types::{AccountId, BlockIndex, ShardId};
+use near_primitives::transaction::{check_tx_history, InvalidTxError, SignedTransaction};
+use near_primitives::types::{AccountId, BlockIndex, ShardId, StatePart};
 use near_primitives::unwrap_or_return;
 use near_primitives::utils::from_timestamp;
-use near_primitives::views::ValidatorInfo;
+use near_primitives::views::{
+    BlockProfileView, NetworkInfoView, PeerBanView, PeerInfoView, ReorgView, SignedTransactionView,
+    SyncStatusView, ValidatorInfo,
+};
+use near_store::backup::write_backup;
 use near_store::Store;
 use near_telemetry::TelemetryActor;
 
 use crate::info::InfoHelper;
+use crate::rebroadcast::RebroadcastTracker;
 use crate::sync::{most_weight_peer, BlockSync, HeaderSync, StateSync, SyncNetworkRecipient};
 use crate::types::{
-    BlockProducer, ClientConfig, Error, ShardSyncStatus, Status, StatusSyncInfo, SyncStatus,
+    BackupDatabase, BlockProducer, ClientConfig, Correlated, Error, GetBans, GetChainProfile,
+    GetNetworkInfo, GetRecentReorgs, GetSyncStatus, GetTxPool, Shutdown, Status, StatusSyncInfo,
+    SwapValidatorKey, SyncStatus, UnbanPeer, UpdateConfig,
 };
 use crate::{sync, StatusResponse};
 use std::cmp::min;
@@ -51,8 +62,13 @@ pub struct ClientActor {
     sync_status: SyncStatus,
     chain: Chain,
     runtime_adapter: Arc<dyn RuntimeAdapter>,
-    block_producer: Option<BlockProducer>,
+    /// Validator accounts this node produces and approves blocks for, each with its own signer.
+    block_producers: Vec<BlockProducer>,
+    /// New signers of the validator accounts, used once their keys become validator keys.
+    pending_block_producers: Vec<BlockProducer>,
     tx_pool: TransactionPool,
+    /// Transactions submitted to this node, broadcast again until they are included.
+    rebroadcast: RebroadcastTracker,
     network_actor: Recipient<NetworkRequests>,
     network_info: NetworkInfo,
     /// Identity that represents this Client at the network level.
@@ -68,12 +84,47 @@ pub struct ClientActor {
     block_sync: BlockSync,
     /// Keeps track of syncing state.
     state_sync: StateSync,
+    /// Blocks received during body sync, waiting to be processed by the catch up loop.
+    catchup_blocks: VecDeque<(Block, PeerId)>,
+    /// Catch up loop is scheduled to process the queued blocks.
+    catchup_scheduled: bool,
+    /// Local archive of blocks not processed yet, network sync starts once it is exhausted.
+    archive: Option<BlockArchive>,
+    /// Ancestors of orphans requested recently, with the time of the request.
+    orphan_ancestor_requests: HashMap<CryptoHash, Instant>,
+    /// Block production waiting for transactions: hash and height of the head and the height of
+    /// the block to produce.
+    waiting_production: Option<(CryptoHash, BlockIndex, BlockIndex)>,
     /// Last time we announced our accounts as validators.
     last_val_announce_height: Option<BlockIndex>,
     /// Info helper.
     info_helper: InfoHelper,
+    /// Node is shutting down, no blocks are produced or synced anymore.
+    shutting_down: bool,
+    /// Correlation id of the request currently being handled, if any.
+    correlation_id: Option<CorrelationId>,
+    /// Events of the chain not handled yet, filled by the subscription to the chain.
+    chain_events: Arc<Mutex<Vec<ChainEvent>>>,
+    /// Last switches of the head to another fork, oldest first.
+    recent_reorgs: VecDeque<ReorgView>,
 }
 
+/// Time given to the network to deliver disconnect messages before stopping the system.
+const SHUTDOWN_DELAY: Duration = Duration::from_secs(1);
+
+/// Maximum number of missing ancestors requested for a received orphan.
+const MAX_ORPHAN_ANCESTORS_REQUEST: usize = 16;
+
+/// Time after which the ancestor of an orphan can be requested again.
+const ORPHAN_ANCESTOR_REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Number of upcoming heights whose block producers get the produced block directly. The producer
+/// after the next one builds on this block if the next one misses its slot.
+const NUM_BLOCK_PUSH_HEIGHTS: BlockIndex = 2;
+
+/// Number of the last reorgs kept to be reported over RPC.
+const MAX_RECENT_REORGS: usize = 100;
+
 fn wait_until_genesis(genesis_time: &DateTime<Utc>) {
     let now = Utc::now();
     //get chrono::Duration::num_seconds() by deducting genesis_time from now
@@ -86,6 +137,32 @@ fn wait_until_genesis(genesis_time: &DateTime<Utc>) {
     }
 }
 
+/// Shards which state the node maintains: the configured ones and the shards of the accounts it
+/// produces blocks for. Empty, meaning all the shards, if none are configured.
+fn tracked_shards(
+    config: &ClientConfig,
+    block_producers: &[BlockProducer],
+    runtime_adapter: &dyn RuntimeAdapter,
+) -> Result<Vec<ShardId>, Error> {
+    if config.tracked_shards.is_empty() {
+        return Ok(vec![]);
+    }
+    let num_shards = runtime_adapter.num_shards();
+    if let Some(shard_id) = config.tracked_shards.iter().find(|shard_id| **shard_id >= num_shards) {
+        return Err(Error::Other(format!(
+            "Tracked shard {} doesn't exist, there are {} shards",
+            shard_id, num_shards
+        )));
+    }
+    let mut shards = config.tracked_shards.clone();
+    shards.extend(
+        block_producers.iter().map(|bp| runtime_adapter.account_id_to_shard_id(&bp.account_id)),
+    );
+    shards.sort();
+    shards.dedup();
+    Ok(shards)
+}
+
 impl ClientActor {
     pub fn new(
         config: ClientConfig,
@@ -94,46 +171,64 @@ impl ClientActor {
         runtime_adapter: Arc<dyn RuntimeAdapter>,
         node_id: PeerId,
         network_actor: Recipient<NetworkRequests>,
-        block_producer: Option<BlockProducer>,
+        block_producers: Vec<BlockProducer>,
         telemetry_actor: Addr<TelemetryActor>,
     ) -> Result<Self, Error> {
         wait_until_genesis(&genesis_time);
-        let chain = Chain::new(
-            store,
-            runtime_adapter.clone(),
-            genesis_time,
-            config.transaction_validity_period,
-        )?;
-        let tx_pool = TransactionPool::new();
+        let mut chain = Chain::new(store, runtime_adapter.clone(), genesis_time)?;
+        chain.set_max_future_time(config.max_block_future_time());
+        chain.set_max_reorg_depth(config.max_reorg_depth);
+        chain.set_gc_num_epochs_to_keep(config.gc_num_epochs_to_keep);
+        chain.set_tracked_shards(tracked_shards(&config, &block_producers, &*runtime_adapter)?)?;
+        info!(target: "client", "Tracking shards {:?}", chain.tracked_shard_ids());
+        let chain_events = Arc::new(Mutex::new(vec![]));
+        let events = chain_events.clone();
+        chain.subscribe_events(move |event| events.lock().unwrap().push(event.clone()));
+        let tx_pool = TransactionPool::new(config.tx_pool.clone());
+        let rebroadcast = RebroadcastTracker::new(config.tx_rebroadcast_delay);
         let sync_status = SyncStatus::AwaitingPeers;
-        let header_sync = HeaderSync::new(SyncNetworkRecipient::new(network_actor.clone()));
+        let sync_params = config.sync_params();
+        info!(target: "client", "Sync parameters {:?}, blocks are accepted up to {:?} in the future", sync_params, config.max_block_future_time());
+        let header_sync =
+            HeaderSync::new(SyncNetworkRecipient::new(network_actor.clone()), &sync_params);
         let block_sync = BlockSync::new(
             SyncNetworkRecipient::new(network_actor.clone()),
             config.block_fetch_horizon,
+            &sync_params,
         );
         let state_sync = StateSync::new(
             SyncNetworkRecipient::new(network_actor.clone()),
             config.state_fetch_horizon,
+            &sync_params,
         );
-        if let Some(bp) = &block_producer {
+        let archive = match config.archive_dir {
+            Some(ref dir) => Some(BlockArchive::open(dir)?),
+            None => None,
+        };
+        for bp in block_producers.iter() {
             info!(target: "client", "Starting validator node: {}", bp.account_id);
         }
-        let info_helper = InfoHelper::new(telemetry_actor, block_producer.clone());
-        Ok(ClientActor {
+        let info_helper = InfoHelper::new(telemetry_actor, block_producers.first().cloned());
+        let mut client = ClientActor {
             config,
             sync_status,
             chain,
             runtime_adapter,
             tx_pool,
+            rebroadcast,
             network_actor,
             node_id,
-            block_producer,
+            block_producers,
+            pending_block_producers: vec![],
             network_info: NetworkInfo {
                 num_active_peers: 0,
                 peer_max_count: 0,
                 most_weight_peers: vec![],
+                active_peers: vec![],
                 received_bytes_per_sec: 0,
                 sent_bytes_per_sec: 0,
+                dropped_messages: Default::default(),
+                external_address: None,
                 routes: None,
             },
             approvals: HashMap::default(),
@@ -141,9 +236,20 @@ impl ClientActor {
             header_sync,
             block_sync,
             state_sync,
+            catchup_blocks: VecDeque::default(),
+            catchup_scheduled: false,
+            archive,
+            orphan_ancestor_requests: HashMap::default(),
+            waiting_production: None,
             last_val_announce_height: None,
             info_helper,
-        })
+            shutting_down: false,
+            correlation_id: None,
+            chain_events,
+            recent_reorgs: VecDeque::with_capacity(MAX_RECENT_REORGS),
+        };
+        client.use_previous_validator_keys()?;
+        Ok(client)
     }
 
     fn check_signature_account_announce(
@@ -211,8 +317,12 @@ impl Actor for ClientActor {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
-        // Start syncing job.
-        self.start_sync(ctx);
+        // Start syncing job, after processing the local archive if there is one.
+        if self.archive.is_some() {
+            self.catchup_from_archive(ctx);
+        } else {
+            self.start_sync(ctx);
+        }
 
         // Start fetching information from network.
         self.fetch_network_info(ctx);
@@ -227,18 +337,56 @@ impl Handler<NetworkClientMessages> for ClientActor {
 
     fn handle(&mut self, msg: NetworkClientMessages, ctx: &mut Context<Self>) -> Self::Result {
         match msg {
-            NetworkClientMessages::Transaction(tx) => match self.validate_tx(tx) {
-                Ok(valid_transaction) => {
-                    self.tx_pool.insert_transaction(valid_transaction);
-                    NetworkClientResponses::ValidTx
+            NetworkClientMessages::Correlated(correlation_id, msg) => {
+                self.correlation_id = Some(correlation_id);
+                let response = <Self as Handler<NetworkClientMessages>>::handle(self, *msg, ctx);
+                self.correlation_id = None;
+                match &response {
+                    NetworkClientResponses::ValidTx => {
+                        debug!(target: "client", "correlation_id={} Transaction accepted", correlation_id)
+                    }
+                    NetworkClientResponses::InvalidTx(err) => {
+                        debug!(target: "client", "correlation_id={} Transaction refused: {}", correlation_id, err)
+                    }
+                    _ => {}
                 }
-                Err(err) => NetworkClientResponses::InvalidTx(err),
-            },
+                response
+            }
+            // Light client can't validate transactions, they are handled by the full nodes.
+            NetworkClientMessages::Transaction(_) if self.config.light_client => {
+                NetworkClientResponses::NoResponse
+            }
+            NetworkClientMessages::Transaction(tx) => self.process_tx(ctx, tx),
+            NetworkClientMessages::LocalTransaction(tx) if self.config.light_client => {
+                debug!(target: "client", "Forwarding transaction {} to the peers", tx.get_hash());
+                let _ =
+                    self.network_actor.do_send(NetworkRequests::Transaction { transaction: tx });
+                NetworkClientResponses::ValidTx
+            }
+            NetworkClientMessages::LocalTransaction(tx) => {
+                let response = self.process_tx(ctx, tx.clone());
+                if let NetworkClientResponses::ValidTx = response {
+                    let height = self.chain.head().map(|head| head.height).unwrap_or_default();
+                    self.rebroadcast.track(tx.clone(), height);
+                    let _ = self
+                        .network_actor
+                        .do_send(NetworkRequests::Transaction { transaction: tx });
+                }
+                response
+            }
             NetworkClientMessages::BlockHeader(header, peer_id) => {
-                self.receive_header(header, peer_id)
+                if self.config.light_client {
+                    self.receive_light_header(header, peer_id)
+                } else {
+                    self.receive_header(header, peer_id)
+                }
             }
             NetworkClientMessages::Block(block, peer_id, was_requested) => {
-                self.receive_block(ctx, block, peer_id, was_requested)
+                if self.config.light_client {
+                    self.receive_light_header(block.header, peer_id)
+                } else {
+                    self.receive_block(ctx, block, peer_id, was_requested)
+                }
             }
             NetworkClientMessages::BlockRequest(hash) => {
                 if let Ok(block) = self.chain.get_block(&hash) {
@@ -247,15 +395,17 @@ impl Handler<NetworkClientMessages> for ClientActor {
                     NetworkClientResponses::NoResponse
                 }
             }
-            NetworkClientMessages::BlockHeadersRequest(hashes) => {
-                if let Ok(headers) = self.retrieve_headers(hashes) {
-                    NetworkClientResponses::BlockHeaders(headers)
+            NetworkClientMessages::BlockHeadersRequest(hashes, skip, max_size) => {
+                if let Ok((headers, more_available)) = self.retrieve_headers(hashes, skip, max_size)
+                {
+                    NetworkClientResponses::BlockHeaders(headers, more_available)
                 } else {
                     NetworkClientResponses::NoResponse
                 }
             }
             NetworkClientMessages::GetChainInfo => match self.chain.head() {
                 Ok(head) => NetworkClientResponses::ChainInfo {
+                    chain_id: self.config.chain_id.clone(),
                     genesis: self.chain.genesis().hash(),
                     height: head.height,
                     total_weight: head.total_weight,
@@ -265,59 +415,44 @@ impl Handler<NetworkClientMessages> for ClientActor {
                     NetworkClientResponses::NoResponse
                 }
             },
-            NetworkClientMessages::BlockHeaders(headers, peer_id) => {
-                if self.receive_headers(headers, peer_id) {
-                    NetworkClientResponses::NoResponse
-                } else {
-                    warn!(target: "client", "Banning node for sending invalid block headers");
-                    NetworkClientResponses::Ban { ban_reason: ReasonForBan::BadBlockHeader }
+            NetworkClientMessages::BlockHeaders(headers, peer_id, more_available) => {
+                if !self.receive_headers(headers, peer_id, more_available) {
+                    warn!(target: "client", "{}Banning node for sending invalid block headers", self.log_correlation());
+                    self.ban_sync_peer(peer_id);
                 }
+                NetworkClientResponses::NoResponse
             }
             NetworkClientMessages::BlockApproval(account_id, hash, signature) => {
                 if self.collect_block_approval(&account_id, &hash, &signature) {
                     NetworkClientResponses::NoResponse
                 } else {
-                    warn!(target: "client", "Banning node for sending invalid block approval: {} {} {}", account_id, hash, signature);
-                    NetworkClientResponses::Ban { ban_reason: ReasonForBan::BadBlockApproval }
+                    warn!(target: "client", "{}Banning node for sending invalid block approval: {} {} {}", self.log_correlation(), account_id, hash, signature);
+                    NetworkClientResponses::ReportPeer { reason: ReasonForBan::BadBlockApproval }
                 }
             }
-            NetworkClientMessages::StateRequest(shard_id, hash) => {
-                if let Ok((payload, receipts)) = self.state_request(shard_id, hash) {
+            NetworkClientMessages::StateRequest(shard_id, hash, part_id) => {
+                if let Ok((part, receipts)) = self.state_request(shard_id, hash, part_id) {
                     return NetworkClientResponses::StateResponse {
                         shard_id,
                         hash,
-                        payload,
+                        part,
                         receipts,
                     };
                 }
                 NetworkClientResponses::NoResponse
             }
-            NetworkClientMessages::StateResponse(shard_id, hash, payload, receipts) => {
-                if let SyncStatus::StateSync(sync_hash, sharded_statuses) = &mut self.sync_status {
-                    if hash != *sync_hash {
-                        sharded_statuses.insert(
-                            shard_id,
-                            ShardSyncStatus::Error(format!(
-                                "Incorrect hash of the state response, expected: {}, got: {}",
-                                sync_hash, hash
-                            )),
-                        );
-                    } else {
-                        match self.chain.set_shard_state(shard_id, hash, payload, receipts) {
-                            Ok(()) => {
-                                sharded_statuses.insert(shard_id, ShardSyncStatus::StateDone);
-                            }
-                            Err(err) => {
-                                sharded_statuses.insert(
-                                    shard_id,
-                                    ShardSyncStatus::Error(format!(
-                                        "Failed to set state for {} @ {}: {}",
-                                        shard_id, hash, err
-                                    )),
-                                );
-                            }
-                        }
-                    }
+            NetworkClientMessages::StateResponse(shard_id, hash, part, receipts, peer_id) => {
+                let part_id = part.part_id;
+                if !self.state_sync.receive_state_part(
+                    &mut self.sync_status,
+                    &mut self.chain,
+                    shard_id,
+                    hash,
+                    part,
+                    receipts,
+                ) {
+                    warn!(target: "client", "{}Banning node for sending invalid state part {} for {} @ {}", self.log_correlation(), part_id, shard_id, hash);
+                    self.ban_sync_peer(peer_id);
                 }
                 NetworkClientResponses::NoResponse
             }
@@ -332,23 +467,51 @@ impl Handler<NetworkClientMessages> for ClientActor {
                         );
                         NetworkClientResponses::NoResponse
                     }
-                    Err(ban_reason) => NetworkClientResponses::Ban { ban_reason },
+                    Err(reason) => NetworkClientResponses::ReportPeer { reason },
                 }
             }
         }
     }
 }
 
+/// Handles the request with the correlation id of the RPC call set, so it's logged with it.
+impl<M> Handler<Correlated<M>> for ClientActor
+where
+    M: Message,
+    ClientActor: Handler<M>,
+    <ClientActor as Handler<M>>::Result: MessageResponse<ClientActor, Correlated<M>>,
+{
+    type Result = <ClientActor as Handler<M>>::Result;
+
+    fn handle(&mut self, msg: Correlated<M>, ctx: &mut Context<Self>) -> Self::Result {
+        let Correlated(correlation_id, msg) = msg;
+        self.correlation_id = Some(correlation_id);
+        let result = <Self as Handler<M>>::handle(self, msg, ctx);
+        self.correlation_id = None;
+        result
+    }
+}
+
 impl Handler<Status> for ClientActor {
     type Result = Result<StatusResponse, String>;
 
     fn handle(&mut self, _: Status, _: &mut Context<Self>) -> Self::Result {
-        let head = self.chain.head().map_err(|err| err.to_string())?;
+        // Light client only has the headers, so its latest block is the header head and the
+        // latest known state root is the one committed by the header.
+        let head = if self.config.light_client {
+            self.chain.header_head()
+        } else {
+            self.chain.head()
+        }
+        .map_err(|err| err.to_string())?;
         let prev_header =
             self.chain.get_block_header(&head.last_block_hash).map_err(|err| err.to_string())?;
         let latest_block_time = prev_header.inner.timestamp.clone();
-        let state_root =
-            self.chain.get_post_state_root(&head.last_block_hash).map_err(|err| err.to_string())?;
+        let state_root = if self.config.light_client {
+            prev_header.inner.prev_state_root
+        } else {
+            *self.chain.get_post_state_root(&head.last_block_hash).map_err(|err| err.to_string())?
+        };
         let validators = self
             .runtime_adapter
             .get_epoch_block_proposers(&head.epoch_hash, &head.last_block_hash)
@@ -372,7 +535,275 @@ impl Handler<Status> for ClientActor {
     }
 }
 
+impl Handler<GetChainProfile> for ClientActor {
+    type Result = Result<Vec<BlockProfileView>, String>;
+
+    fn handle(&mut self, _: GetChainProfile, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.chain.block_profiles().iter().cloned().map(BlockProfileView::from).collect())
+    }
+}
+
+impl Handler<GetRecentReorgs> for ClientActor {
+    type Result = Result<Vec<ReorgView>, String>;
+
+    fn handle(&mut self, _: GetRecentReorgs, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.recent_reorgs.iter().cloned().collect())
+    }
+}
+
+impl Handler<GetTxPool> for ClientActor {
+    type Result = Result<Vec<SignedTransactionView>, String>;
+
+    fn handle(&mut self, _: GetTxPool, _: &mut Context<Self>) -> Self::Result {
+        Ok(self.tx_pool.transactions().cloned().map(SignedTransactionView::from).collect())
+    }
+}
+
+impl Handler<GetBans> for ClientActor {
+    type Result = ResponseFuture<Vec<PeerBanView>, String>;
+
+    fn handle(&mut self, _: GetBans, _: &mut Context<Self>) -> Self::Result {
+        Box::new(self.network_actor.send(NetworkRequests::FetchBans).then(|res| match res {
+            Ok(NetworkResponses::Bans(bans)) => Ok(bans),
+            Ok(_) => Err("Unexpected response to bans request".to_string()),
+            Err(err) => Err(err.to_string()),
+        }))
+    }
+}
+
+impl Handler<UnbanPeer> for ClientActor {
+    type Result = ResponseFuture<bool, String>;
+
+    fn handle(&mut self, msg: UnbanPeer, _: &mut Context<Self>) -> Self::Result {
+        info!(target: "client", "{}Unbanning peer {}", self.log_correlation(), msg.peer_id);
+        let request = NetworkRequests::Unban { peer_id: msg.peer_id };
+        Box::new(self.network_actor.send(request).then(|res| match res {
+            Ok(NetworkResponses::Unbanned(unbanned)) => Ok(unbanned),
+            Ok(_) => Err("Unexpected response to unban request".to_string()),
+            Err(err) => Err(err.to_string()),
+        }))
+    }
+}
+
+impl Handler<GetNetworkInfo> for ClientActor {
+    type Result = Result<NetworkInfoView, String>;
+
+    fn handle(&mut self, _: GetNetworkInfo, _: &mut Context<Self>) -> Self::Result {
+        Ok(NetworkInfoView {
+            num_active_peers: self.network_info.num_active_peers,
+            peer_max_count: self.network_info.peer_max_count,
+            sent_bytes_per_sec: self.network_info.sent_bytes_per_sec,
+            received_bytes_per_sec: self.network_info.received_bytes_per_sec,
+            active_peers: self
+                .network_info
+                .active_peers
+                .iter()
+                .map(|peer| PeerInfoView {
+                    peer_id: peer.peer_info.id.to_string(),
+                    addr: peer.peer_info.addr.map(|addr| addr.to_string()),
+                    account_id: peer.peer_info.account_id.clone(),
+                    height: peer.chain_info.height,
+                    latency_ms: peer.latency.map(|latency| latency.as_millis() as u64),
+                    last_seen: peer.last_seen,
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Handler<GetSyncStatus> for ClientActor {
+    type Result = Result<SyncStatusView, String>;
+
+    fn handle(&mut self, _: GetSyncStatus, _: &mut Context<Self>) -> Self::Result {
+        Ok((&self.sync_status).into())
+    }
+}
+
+impl Handler<Shutdown> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, _: Shutdown, ctx: &mut Context<Self>) {
+        if self.shutting_down {
+            return;
+        }
+        info!(target: "client", "{}Shutting down: stopping block production and sync", self.log_correlation());
+        self.shutting_down = true;
+        let _ = self.network_actor.do_send(NetworkRequests::Shutdown);
+        // Flush after the delay, when nothing is written anymore.
+        ctx.run_later(SHUTDOWN_DELAY, |act, _| {
+            if let Err(err) = act.chain.store().store().flush() {
+                error!(target: "client", "Failed to flush the store: {}", err);
+            }
+            System::current().stop();
+        });
+    }
+}
+
+impl Handler<UpdateConfig> for ClientActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateConfig, _: &mut Context<Self>) {
+        info!(target: "client", "Updating config: min peers {}, tracked shards {:?}, max peers {}", msg.min_num_peers, msg.tracked_shards, msg.peer_max_count);
+        self.config.min_num_peers = msg.min_num_peers;
+        // Chain keeps tracking the shards it was started with, since state of a newly tracked
+        // shard is only obtained by the state sync. New value takes effect after restart.
+        self.config.tracked_shards = msg.tracked_shards;
+        let _ = self
+            .network_actor
+            .do_send(NetworkRequests::UpdateConfig { peer_max_count: msg.peer_max_count });
+    }
+}
+
+impl Handler<BackupDatabase> for ClientActor {
+    type Result = ResponseFuture<u64, String>;
+
+    fn handle(&mut self, msg: BackupDatabase, _: &mut Context<Self>) -> Self::Result {
+        let log_correlation = self.log_correlation();
+        info!(target: "client", "{}Writing database backup to {}", log_correlation, msg.path.display());
+        let store = self.chain.store().owned_store();
+        let (sender, receiver) = oneshot::channel();
+        // Blocks keep being processed while the files of the checkpoint are checksummed.
+        let spawned = thread::Builder::new().name("database backup".to_string()).spawn(move || {
+            let result = match write_backup(&store, &msg.path) {
+                Ok(stats) => {
+                    info!(target: "client", "{}Database backup of {} files with {} bytes written to {}", log_correlation, stats.num_files, stats.size, msg.path.display());
+                    Ok(stats.size)
+                }
+                Err(err) => Err(format!("Failed to write database backup: {}", err)),
+            };
+            let _ = sender.send(result);
+        });
+        if let Err(err) = spawned {
+            return Box::new(future::err(format!("Failed to start database backup: {}", err)));
+        }
+        Box::new(receiver.then(|res| match res {
+            Ok(result) => result,
+            Err(_) => Err("Database backup was interrupted".to_string()),
+        }))
+    }
+}
+
+impl Handler<SwapValidatorKey> for ClientActor {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: SwapValidatorKey, _: &mut Context<Self>) -> Self::Result {
+        let signer = read_signer(&msg.key_file)?;
+        if self.get_block_producer(&signer.account_id).is_none() {
+            return Err(format!("{} is not a validator account of this node", signer.account_id));
+        }
+        let key_path =
+            self.config.validator_key_files.get(&signer.account_id).ok_or_else(|| {
+                format!("{} has no validator key file to write the key to", signer.account_id)
+            })?;
+        info!(target: "client", "{}Switching {} to key {} once it's the validator key", self.log_correlation(), signer.account_id, signer.public_key);
+        // Until the new key is the validator key, the previous one is used after restart.
+        KeyFile::from(&signer).rotate_file(key_path).map_err(|err| {
+            format!("Failed to write validator key file {}: {}", key_path.display(), err)
+        })?;
+
+        self.pending_block_producers.retain(|bp| bp.account_id != signer.account_id);
+        self.pending_block_producers.push(BlockProducer::from(signer));
+        let head = self.chain.head().map_err(|err| err.to_string())?;
+        self.swap_block_producers(&head.last_block_hash, head.height + 1);
+        Ok(())
+    }
+}
+
+/// Whether the key of the block producer is its validator key in the epoch.
+fn is_validator_key(
+    runtime_adapter: &dyn RuntimeAdapter,
+    epoch_hash: &CryptoHash,
+    bp: &BlockProducer,
+) -> bool {
+    let signature = bp.signer.sign(epoch_hash.as_ref());
+    runtime_adapter.check_validator_signature(
+        epoch_hash,
+        &bp.account_id,
+        epoch_hash.as_ref(),
+        &signature,
+    )
+}
+
+/// Reads signer from the key file, unlike `InMemorySigner::from_file` doesn't panic on a missing
+/// or malformed file.
+fn read_signer(path: &Path) -> Result<InMemorySigner, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read key file {}: {}", path.display(), err))?;
+    let key_file: KeyFile = serde_json::from_str(&content)
+        .map_err(|err| format!("Failed to parse key file {}: {}", path.display(), err))?;
+    Ok(InMemorySigner::from(key_file))
+}
+
 impl ClientActor {
+    /// Validator accounts whose key file has a key that is not their validator key yet, after a
+    /// swap or a rotation, sign with the latest previous key that is, the new key stays pending.
+    fn use_previous_validator_keys(&mut self) -> Result<(), Error> {
+        let head = self.chain.head()?;
+        let epoch_hash =
+            match self.runtime_adapter.get_epoch_offset(head.last_block_hash, head.height + 1) {
+                Ok((epoch_hash, _)) => epoch_hash,
+                Err(_) => return Ok(()),
+            };
+        for (account_id, key_path) in self.config.validator_key_files.iter() {
+            let bp = match self.get_block_producer(account_id) {
+                Some(bp) => bp.clone(),
+                None => continue,
+            };
+            if is_validator_key(&*self.runtime_adapter, &epoch_hash, &bp) {
+                continue;
+            }
+            let previous_keys: Vec<_> =
+                rotated_key_paths(key_path).take_while(|path| path.exists()).collect();
+            let previous_bp = previous_keys.iter().rev().find_map(|path| {
+                let signer = read_signer(path).ok()?;
+                let previous_bp = BlockProducer::from(signer);
+                if &previous_bp.account_id == account_id
+                    && is_validator_key(&*self.runtime_adapter, &epoch_hash, &previous_bp)
+                {
+                    Some(previous_bp)
+                } else {
+                    None
+                }
+            });
+            if let Some(previous_bp) = previous_bp {
+                info!(target: "client", "Using previous key {} of {} until key {} is the validator key", previous_bp.signer.public_key(), account_id, bp.signer.public_key());
+                for current_bp in self.block_producers.iter_mut() {
+                    if &current_bp.account_id == account_id {
+                        current_bp.signer = previous_bp.signer.clone();
+                    }
+                }
+                self.pending_block_producers.push(bp);
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces signers of the validator accounts with the pending ones, if their keys are the
+    /// validator keys of the epoch of the block at given height.
+    fn swap_block_producers(&mut self, prev_hash: &CryptoHash, height: BlockIndex) {
+        if self.pending_block_producers.is_empty() {
+            return;
+        }
+        let epoch_hash = match self.runtime_adapter.get_epoch_offset(*prev_hash, height) {
+            Ok((epoch_hash, _)) => epoch_hash,
+            Err(_) => return,
+        };
+        let runtime_adapter = &*self.runtime_adapter;
+        let (ready, pending): (Vec<_>, Vec<_>) = self
+            .pending_block_producers
+            .drain(..)
+            .partition(|bp| is_validator_key(runtime_adapter, &epoch_hash, bp));
+        self.pending_block_producers = pending;
+        for new_bp in ready {
+            info!(target: "client", "Switched {} to key {} at #{}", new_bp.account_id, new_bp.signer.public_key(), height);
+            for bp in self.block_producers.iter_mut() {
+                if bp.account_id == new_bp.account_id {
+                    bp.signer = new_bp.signer.clone();
+                }
+            }
+        }
+    }
+
     /// Gets called when block got accepted.
     /// Send updates over network, update tx pool and notify ourselves if it's time to produce next block.
     fn on_block_accepted(
@@ -400,12 +831,21 @@ impl ClientActor {
             // If we produced the block, then we want to broadcast it.
             // If received the block from another node then broadcast "header first" to minimise network traffic.
             if provenance == Provenance::PRODUCED {
-                let _ = self.network_actor.do_send(NetworkRequests::Block { block: block.clone() });
+                let targets = self.get_next_block_producers(&block);
+                let _ = self
+                    .network_actor
+                    .do_send(NetworkRequests::Block { block: block.clone(), targets });
+                // Our other validator accounts approve the block as any other validator.
+                let approvals = self.collect_own_block_approvals(&block);
+                if !approvals.is_empty() {
+                    let _ =
+                        self.network_actor.do_send(NetworkRequests::BlockApprovals { approvals });
+                }
             } else {
-                let approval = self.get_block_approval(&block);
+                let approvals = self.collect_own_block_approvals(&block);
                 let _ = self.network_actor.do_send(NetworkRequests::BlockHeaderAnnounce {
                     header: block.header.clone(),
-                    approval,
+                    approvals,
                 });
             }
 
@@ -423,24 +863,97 @@ impl ClientActor {
         // We only want to reconcile the txpool against the new block *if* total weight has increased.
         if status == BlockStatus::Next || status == BlockStatus::Reorg {
             self.tx_pool.reconcile_block(&block);
+            self.rebroadcast.block_included(&block);
+            self.rebroadcast_transactions(block.header.inner.height);
+            self.swap_block_producers(&block.hash(), block.header.inner.height + 1);
         }
 
         self.check_send_announce_account(&block.hash(), block.header.inner.height);
     }
 
-    /// Check if client Account Id should be sent and send it.
+    /// Validates transaction and adds it to the pool. Produces the block waiting for transactions.
+    /// Transaction already in the pool is accepted again, so that retries of a pending
+    /// transaction wait for its result instead of failing.
+    fn process_tx(
+        &mut self,
+        ctx: &mut Context<ClientActor>,
+        tx: SignedTransaction,
+    ) -> NetworkClientResponses {
+        match self.validate_tx(tx) {
+            Ok(valid_transaction) => match self.tx_pool.insert_transaction(valid_transaction) {
+                Ok(()) => {
+                    if let Some((block_hash, last_height, next_height)) =
+                        self.waiting_production.take()
+                    {
+                        self.produce_block(ctx, block_hash, last_height, next_height);
+                    }
+                    NetworkClientResponses::ValidTx
+                }
+                Err(PoolError::DuplicateTx(hash)) => {
+                    debug!(target: "client", "Transaction {} is already in the pool", hash);
+                    NetworkClientResponses::ValidTx
+                }
+                Err(err) => {
+                    NetworkClientResponses::InvalidTx(InvalidTxError::Refused(err.to_string()))
+                }
+            },
+            Err(err) => NetworkClientResponses::InvalidTx(err),
+        }
+    }
+
+    /// Broadcasts again the local transactions that are not included after the delay, and stops
+    /// tracking the ones that can't be included anymore.
+    fn rebroadcast_transactions(&mut self, height: BlockIndex) {
+        let chain = &mut self.chain;
+        let transaction_validity_period = chain.transaction_validity_period();
+        let transactions = self.rebroadcast.due(height, |tx| {
+            check_tx_history(
+                chain.get_block_header(&tx.transaction.block_hash).ok(),
+                height,
+                transaction_validity_period,
+            )
+        });
+        for transaction in transactions {
+            debug!(target: "client", "Broadcasting transaction {} again at #{}", transaction.get_hash(), height);
+            let _ = self.network_actor.do_send(NetworkRequests::Transaction { transaction });
+        }
+    }
+
+    /// Prefix for log lines about the request currently being handled.
+    fn log_correlation(&self) -> String {
+        self.correlation_id.map(|id| format!("correlation_id={} ", id)).unwrap_or_default()
+    }
+
+    /// Creates approvals for given block from our validator accounts and collects the ones for our
+    /// own next block producer. Returns the approvals that need to be sent to other nodes.
+    fn collect_own_block_approvals(&mut self, block: &Block) -> Vec<BlockApproval> {
+        let approvals = self.get_block_approvals(block).unwrap_or_default();
+        // Approvals for our own accounts don't need to go through the network.
+        let (own_approvals, other_approvals): (Vec<_>, Vec<_>) = approvals
+            .into_iter()
+            .partition(|approval| self.get_block_producer(&approval.target).is_some());
+        for approval in own_approvals {
+            self.collect_block_approval(&approval.account_id, &approval.hash, &approval.signature);
+        }
+        other_approvals
+    }
+
+    /// Returns block producer for given account if this node operates it.
+    fn get_block_producer(&self, account_id: &AccountId) -> Option<&BlockProducer> {
+        self.block_producers.iter().find(|bp| &bp.account_id == account_id)
+    }
+
+    /// Check if client Account Ids should be sent and send them.
     /// Account Id is sent when is not current a validator but are becoming a validator soon.
     fn check_send_announce_account(&mut self, block_hash: &CryptoHash, block_height: BlockIndex) {
         // Announce AccountId if client is becoming a validator soon.
 
         // First check that we currently have an AccountId
-        if self.block_producer.is_none() {
+        if self.block_producers.is_empty() {
             // There is no account id associated with this client
             return;
         }
 
-        let block_producer = self.block_producer.as_ref().unwrap();
-
         let epoch_hash = match self
             .runtime_adapter
             .get_epoch_offset(*block_hash, block_height + self.config.announce_account_horizon)
@@ -460,17 +973,20 @@ impl ClientActor {
                 }
             }
 
-            // Check client is part of the futures validators
+            // Check which of client accounts are part of the futures validators
             if let Ok(validators) =
                 self.runtime_adapter.get_epoch_block_proposers(&epoch_hash, &block_hash)
             {
-                // TODO(MarX): Use HashSet in validator manager to do fast searching.
-                if validators
-                    .iter()
-                    .any(|account_id| (&(account_id.0) == &block_producer.account_id))
-                {
+                for block_producer in self.block_producers.iter() {
+                    // TODO(MarX): Use HashSet in validator manager to do fast searching.
+                    if !validators
+                        .iter()
+                        .any(|account_id| (&(account_id.0) == &block_producer.account_id))
+                    {
+                        continue;
+                    }
                     self.last_val_announce_height = Some(epoch_height);
-                    let (hash, signature) = self.sign_announce_account(epoch_hash).unwrap();
+                    let (hash, signature) = self.sign_announce_account(block_producer, epoch_hash);
 
                     actix::spawn(
                         self.network_actor
@@ -489,18 +1005,18 @@ impl ClientActor {
         }
     }
 
-    fn sign_announce_account(&self, epoch: CryptoHash) -> Result<(CryptoHash, Signature), ()> {
-        if let Some(block_producer) = self.block_producer.as_ref() {
-            let hash = AnnounceAccount::build_header_hash(
-                block_producer.account_id.clone(),
-                self.node_id,
-                epoch,
-            );
-            let signature = block_producer.signer.sign(hash.as_ref());
-            Ok((hash, signature))
-        } else {
-            Err(())
-        }
+    fn sign_announce_account(
+        &self,
+        block_producer: &BlockProducer,
+        epoch: CryptoHash,
+    ) -> (CryptoHash, Signature) {
+        let hash = AnnounceAccount::build_header_hash(
+            block_producer.account_id.clone(),
+            self.node_id,
+            epoch,
+        );
+        let signature = block_producer.signer.sign(hash.as_ref());
+        (hash, signature)
     }
 
     fn get_block_proposer(
@@ -523,44 +1039,77 @@ impl ClientActor {
             .map_err(|err| Error::Other(err.to_string()))
     }
 
-    /// Create approval for given block or return none if not a block producer.
-    fn get_block_approval(&mut self, block: &Block) -> Option<BlockApproval> {
+    /// Create approvals for given block from each of our validator accounts, except the producers
+    /// of the block and of the next block. Returns none if the epoch information is not available.
+    /// Block producers of the next heights that the block should be pushed to before it's
+    /// broadcast, excluding accounts of this node.
+    fn get_next_block_producers(&self, block: &Block) -> Vec<AccountId> {
+        let mut targets = vec![];
+        let block_height = block.header.inner.height;
+        for height in block_height + 1..=block_height + NUM_BLOCK_PUSH_HEIGHTS {
+            let account_id = match self
+                .runtime_adapter
+                .get_epoch_offset(block.header.inner.epoch_hash, height)
+                .map_err(|err| Error::Other(err.to_string()))
+                .and_then(|(epoch_hash, _)| self.get_block_proposer(&epoch_hash, height))
+            {
+                Ok(account_id) => account_id,
+                Err(err) => {
+                    debug!(target: "client", "Failed to get block producer at {}: {}", height, err);
+                    continue;
+                }
+            };
+            if self.get_block_producer(&account_id).is_none() && !targets.contains(&account_id) {
+                targets.push(account_id);
+            }
+        }
+        targets
+    }
+
+    fn get_block_approvals(&mut self, block: &Block) -> Option<Vec<BlockApproval>> {
+        if self.block_producers.is_empty() {
+            return None;
+        }
         let (mut epoch_hash, offset) = self
             .runtime_adapter
             .get_epoch_offset(block.header.inner.epoch_hash, block.header.inner.height + 1)
             .ok()?;
         let next_block_producer_account =
-            self.get_block_proposer(&epoch_hash, block.header.inner.height + 1);
-        if let (Some(block_producer), Ok(next_block_producer_account)) =
-            (&self.block_producer, &next_block_producer_account)
-        {
-            if &block_producer.account_id != next_block_producer_account {
-                // TODO: fix this suboptimal code
-                if offset == 0 {
-                    epoch_hash = self
-                        .runtime_adapter
-                        .get_epoch_offset(block.header.inner.prev_hash, block.header.inner.height)
-                        .ok()?
-                        .0;
-                }
-                if let Ok(validators) =
-                    self.runtime_adapter.get_epoch_block_proposers(&epoch_hash, &block.hash())
-                {
-                    if let Some((_, is_slashed)) =
-                        validators.into_iter().find(|v| v.0 == block_producer.account_id)
-                    {
-                        if !is_slashed {
-                            return Some(BlockApproval::new(
-                                block.hash(),
-                                &*block_producer.signer,
-                                next_block_producer_account.clone(),
-                            ));
-                        }
-                    }
-                }
-            }
+            self.get_block_proposer(&epoch_hash, block.header.inner.height + 1).ok()?;
+        // TODO: fix this suboptimal code
+        if offset == 0 {
+            epoch_hash = self
+                .runtime_adapter
+                .get_epoch_offset(block.header.inner.prev_hash, block.header.inner.height)
+                .ok()?
+                .0;
         }
-        None
+        let validators =
+            self.runtime_adapter.get_epoch_block_proposers(&epoch_hash, &block.hash()).ok()?;
+        let block_producer_account =
+            self.get_block_proposer(&block.header.inner.epoch_hash, block.header.inner.height).ok();
+        let approvals = self
+            .block_producers
+            .iter()
+            .filter(|block_producer| block_producer.account_id != next_block_producer_account)
+            .filter(|block_producer| {
+                block_producer_account.as_ref() != Some(&block_producer.account_id)
+            })
+            .filter(|block_producer| {
+                validators.iter().any(|(account_id, is_slashed)| {
+                    account_id == &block_producer.account_id && !is_slashed
+                })
+            })
+            .map(|block_producer| {
+                BlockApproval::new(
+                    block.hash(),
+                    &*block_producer.signer,
+                    block_producer.account_id.clone(),
+                    next_block_producer_account.clone(),
+                )
+            })
+            .collect();
+        Some(approvals)
     }
 
     /// Checks if we are block producer and if we are next block producer schedules calling `produce_block`.
@@ -572,14 +1121,17 @@ impl ClientActor {
         last_height: BlockIndex,
         check_height: BlockIndex,
     ) {
+        if self.shutting_down {
+            return;
+        }
         let (epoch_hash, _) = unwrap_or_return!(
             self.runtime_adapter.get_epoch_offset(block_hash, check_height + 1),
             ()
         );
         let next_block_producer_account =
             unwrap_or_return!(self.get_block_proposer(&epoch_hash, check_height + 1), ());
-        if let Some(block_producer) = &self.block_producer {
-            if block_producer.account_id.clone() == next_block_producer_account {
+        if !self.block_producers.is_empty() {
+            if self.get_block_producer(&next_block_producer_account).is_some() {
                 ctx.run_later(self.config.min_block_production_delay, move |act, ctx| {
                     act.produce_block(ctx, block_hash, last_height, check_height + 1);
                 });
@@ -626,6 +1178,10 @@ impl ClientActor {
         last_height: BlockIndex,
         next_height: BlockIndex,
     ) {
+        if self.shutting_down {
+            return;
+        }
+        self.waiting_production = None;
         if let Err(err) = self.produce_block_err(ctx, last_height, next_height) {
             error!(target: "client", "Block production failed: {:?}", err);
             self.handle_scheduling_block_production(ctx, block_hash, last_height, next_height - 1);
@@ -640,11 +1196,9 @@ impl ClientActor {
         last_height: BlockIndex,
         next_height: BlockIndex,
     ) -> Result<(), Error> {
-        let block_producer = self
-            .block_producer
-            .as_ref()
-            .ok_or_else(|| Error::BlockProducer("Called without block producer info.".to_string()))?
-            .clone();
+        if self.block_producers.is_empty() {
+            return Err(Error::BlockProducer("Called without block producer info.".to_string()));
+        }
         let head = self.chain.head()?;
         // If last height changed, this process should stop as we spun up another one.
         if head.height != last_height {
@@ -657,13 +1211,20 @@ impl ClientActor {
             .map_err(|e| Error::from(ErrorKind::Other(e.to_string())))?;
 
         let next_block_proposer = self.get_block_proposer(&epoch_hash, next_height)?;
-        if block_producer.account_id != next_block_proposer {
-            info!(target: "client", "Produce block: chain at {}, not block producer for next block.", next_height);
-            return Ok(());
-        }
+        let block_producer = match self.get_block_producer(&next_block_proposer) {
+            Some(block_producer) => block_producer.clone(),
+            None => {
+                info!(target: "client", "Produce block: chain at {}, not block producer for next block.", next_height);
+                return Ok(());
+            }
+        };
         let state_root = self.chain.get_post_state_root(&head.last_block_hash)?.clone();
-        let has_receipts =
-            self.chain.get_receipts(&head.last_block_hash).map(|r| r.len() > 0).unwrap_or(false);
+        let has_receipts = (0..self.runtime_adapter.num_shards()).any(|shard_id| {
+            self.chain
+                .get_receipts(&head.last_block_hash, shard_id)
+                .map(|r| r.len() > 0)
+                .unwrap_or(false)
+        });
 
         // Wait until we have all approvals or timeouts per max block production delay.
         let validators = self
@@ -694,8 +1255,31 @@ impl ClientActor {
             return Ok(());
         }
 
+        // Without transactions, wait for them. The wait is counted from the same moment as the
+        // block timeout of the other validators, so the block reaches them before they skip it.
+        let elapsed = self.last_block_processed.elapsed();
+        let max_wait = self.config.max_transactions_wait();
+        if self.config.adaptive_block_production
+            && self.tx_pool.len() == 0
+            && !has_receipts
+            && elapsed < max_wait
+        {
+            let waiting = (head.last_block_hash, last_height, next_height);
+            self.waiting_production = Some(waiting);
+            ctx.run_later(max_wait - elapsed, move |act, ctx| {
+                // Block may be already produced for a transaction or after a timeout.
+                if act.waiting_production == Some(waiting) {
+                    act.produce_block(ctx, waiting.0, last_height, next_height);
+                }
+            });
+            return Ok(());
+        }
+
         // If we are not producing empty blocks, skip this and call handle scheduling for the next block.
-        if !self.config.produce_empty_blocks && self.tx_pool.len() == 0 && !has_receipts {
+        if self.tx_pool.len() == 0
+            && !has_receipts
+            && self.config.skip_empty_block(next_height - head.height)
+        {
             self.handle_scheduling_block_production(
                 ctx,
                 head.last_block_hash,
@@ -710,15 +1294,16 @@ impl ClientActor {
             self.chain.get_post_validator_proposals(&head.last_block_hash)?.clone();
 
         // Take transactions from the pool.
+        let transaction_validity_period = self.chain.transaction_validity_period();
         let transactions = self
             .tx_pool
-            .prepare_transactions(self.config.block_expected_weight)?
+            .prepare_transactions(&self.config.block_budget())?
             .into_iter()
             .filter(|t| {
                 check_tx_history(
                     self.chain.get_block_header(&t.transaction.block_hash).ok(),
                     head.height,
-                    self.config.transaction_validity_period,
+                    transaction_validity_period,
                 )
             })
             .collect();
@@ -764,9 +1349,68 @@ impl ClientActor {
         for (hash, status, provenance) in accepted_blocks.write().unwrap().drain(..) {
             self.on_block_accepted(ctx, hash, status, provenance);
         }
+        let events: Vec<_> = self.chain_events.lock().unwrap().drain(..).collect();
+        for event in events {
+            self.on_chain_event(event);
+        }
         result
     }
 
+    /// Handles the events of the chain. On reorg the pool is reconciled against all the blocks of
+    /// the new chain and the transactions of the abandoned blocks that are still valid go back
+    /// into the pool.
+    fn on_chain_event(&mut self, event: ChainEvent) {
+        let ChainEvent::Reorg { common_ancestor, abandoned, canonical } = event;
+        let mut included = HashSet::new();
+        for hash in canonical.iter() {
+            match self.chain.get_block(hash) {
+                Ok(block) => {
+                    let block = block.clone();
+                    included.extend(block.transactions.iter().map(|tx| tx.get_hash()));
+                    self.tx_pool.reconcile_block(&block);
+                    self.rebroadcast.block_included(&block);
+                }
+                Err(err) => {
+                    error!(target: "client", "Failed to find canonical block {} after reorg: {}", hash, err);
+                }
+            }
+        }
+        for hash in abandoned.iter() {
+            let transactions = match self.chain.get_block(hash) {
+                Ok(block) => block.transactions.clone(),
+                Err(err) => {
+                    error!(target: "client", "Failed to find abandoned block {} after reorg: {}", hash, err);
+                    continue;
+                }
+            };
+            for tx in transactions {
+                let tx_hash = tx.get_hash();
+                if included.contains(&tx_hash) {
+                    continue;
+                }
+                match self.validate_tx(tx).map(|valid| self.tx_pool.insert_transaction(valid)) {
+                    Ok(Ok(())) => {
+                        debug!(target: "client", "Returned transaction {} of abandoned block {} to the pool", tx_hash, hash);
+                    }
+                    Ok(Err(err)) => {
+                        debug!(target: "client", "Transaction {} of abandoned block {} is not returned to the pool: {}", tx_hash, hash, err);
+                    }
+                    Err(err) => {
+                        debug!(target: "client", "Transaction {} of abandoned block {} is not valid anymore: {:?}", tx_hash, hash, err);
+                    }
+                }
+            }
+        }
+        if self.recent_reorgs.len() == MAX_RECENT_REORGS {
+            self.recent_reorgs.pop_front();
+        }
+        self.recent_reorgs.push_back(ReorgView {
+            common_ancestor: common_ancestor.into(),
+            abandoned: abandoned.into_iter().map(Into::into).collect(),
+            canonical: canonical.into_iter().map(Into::into).collect(),
+        });
+    }
+
     /// Processes received block, returns boolean if block was reasonable or malicious.
     fn receive_block(
         &mut self,
@@ -775,15 +1419,27 @@ impl ClientActor {
         peer_id: PeerId,
         was_requested: bool,
     ) -> NetworkClientResponses {
+        if was_requested {
+            if let SyncStatus::BodySync { .. } = self.sync_status {
+                self.catchup_blocks.push_back((block, peer_id));
+                self.schedule_catchup(ctx);
+                return NetworkClientResponses::NoResponse;
+            }
+        }
         let hash = block.hash();
-        debug!(target: "client", "Received block {} at {} from {}", hash, block.header.inner.height, peer_id);
+        debug!(target: "client", "{}Received block {} at {} from {}", self.log_correlation(), hash, block.header.inner.height, peer_id);
         let prev_hash = block.header.inner.prev_hash;
         let provenance =
             if was_requested { near_chain::Provenance::SYNC } else { near_chain::Provenance::NONE };
         match self.process_block(ctx, block, provenance) {
             Ok(_) => NetworkClientResponses::NoResponse,
+            Err(ref err) if err.is_bad_data() && was_requested => {
+                warn!(target: "client", "{}Banning node for sending invalid block {}: {}", self.log_correlation(), hash, err);
+                self.ban_sync_peer(peer_id);
+                NetworkClientResponses::NoResponse
+            }
             Err(ref err) if err.is_bad_data() => {
-                NetworkClientResponses::Ban { ban_reason: ReasonForBan::BadBlock }
+                NetworkClientResponses::ReportPeer { reason: ReasonForBan::BadBlock }
             }
             Err(ref err) if err.is_error() => {
                 if self.sync_status.is_syncing() {
@@ -797,11 +1453,15 @@ impl ClientActor {
             }
             Err(e) => match e.kind() {
                 near_chain::ErrorKind::Orphan => {
-                    if !self.chain.is_orphan(&prev_hash) && !self.sync_status.is_syncing() {
-                        self.request_block_by_hash(prev_hash, peer_id)
+                    if !self.sync_status.is_syncing() {
+                        self.request_orphan_ancestors(prev_hash, peer_id);
                     }
                     NetworkClientResponses::NoResponse
                 }
+                near_chain::ErrorKind::DeepReorg(_, _) => {
+                    self.info_helper.reorg_refused();
+                    NetworkClientResponses::NoResponse
+                }
                 _ => {
                     debug!("Process block: block {} refused by chain: {}", hash, e.kind());
                     NetworkClientResponses::NoResponse
@@ -810,16 +1470,104 @@ impl ClientActor {
         }
     }
 
+    fn schedule_catchup(&mut self, ctx: &mut Context<ClientActor>) {
+        if !self.catchup_scheduled {
+            self.catchup_scheduled = true;
+            ctx.run_later(Duration::from_millis(0), |act, ctx| act.catchup(ctx));
+        }
+    }
+
+    /// Processes a batch of the blocks received during body sync. Store updates of the batch are
+    /// written to the disk at once and blocks are not logged one by one, progress is reported
+    /// through the sync status instead.
+    fn catchup(&mut self, ctx: &mut Context<ClientActor>) {
+        self.catchup_scheduled = false;
+        if self.shutting_down || self.catchup_blocks.is_empty() {
+            return;
+        }
+        if let Err(err) = self.chain.mut_store().set_buffered_writes(true) {
+            error!(target: "client", "Catch up: failed to buffer store updates: {}", err);
+        }
+        let mut num_processed = 0;
+        while num_processed < self.config.catchup_batch_size {
+            let (block, peer_id) = match self.catchup_blocks.pop_front() {
+                Some(entry) => entry,
+                None => break,
+            };
+            num_processed += 1;
+            match self.process_block(ctx, block, Provenance::SYNC) {
+                Err(ref err) if err.is_bad_data() => {
+                    warn!(target: "client", "Catch up: banning node for sending invalid block: {}", err);
+                    self.ban_sync_peer(peer_id);
+                }
+                // Old and orphan blocks are expected during sync, missing ones are requested again.
+                _ => {}
+            }
+        }
+        if let Err(err) = self.chain.mut_store().set_buffered_writes(false) {
+            error!(target: "client", "Catch up: failed to write store updates: {}", err);
+        }
+        if let Ok(head) = self.chain.head() {
+            if let SyncStatus::BodySync { ref mut current_height, .. } = self.sync_status {
+                *current_height = head.height;
+            }
+            debug!(target: "client", "Catch up: processed {} blocks, head #{}, {} blocks queued", num_processed, head.height, self.catchup_blocks.len());
+        }
+        if !self.catchup_blocks.is_empty() {
+            self.schedule_catchup(ctx);
+        }
+    }
+
+    /// Processes a batch of the blocks of the local archive, with store updates written to the disk
+    /// at once like in `catchup`. Starts syncing with the network when the archive is exhausted or
+    /// one of its blocks can't be read or processed.
+    fn catchup_from_archive(&mut self, ctx: &mut Context<ClientActor>) {
+        if self.shutting_down {
+            return;
+        }
+        let mut archive = match self.archive.take() {
+            Some(archive) => archive,
+            None => return,
+        };
+        if let Err(err) = self.chain.mut_store().set_buffered_writes(true) {
+            error!(target: "client", "Archive: failed to buffer store updates: {}", err);
+        }
+        let mut failed = false;
+        for block in archive.by_ref().take(self.config.catchup_batch_size) {
+            match block.and_then(|block| self.process_block(ctx, block, Provenance::SYNC)) {
+                Err(ref err) if err.is_bad_data() || err.is_error() => {
+                    error!(target: "client", "Archive: stopping at invalid block: {}", err);
+                    failed = true;
+                    break;
+                }
+                // Blocks already known from the previous run are skipped.
+                _ => {}
+            }
+        }
+        if let Err(err) = self.chain.mut_store().set_buffered_writes(false) {
+            error!(target: "client", "Archive: failed to write store updates: {}", err);
+        }
+        let head_height = self.chain.head().map(|head| head.height).unwrap_or_default();
+        if failed || archive.is_empty() {
+            info!(target: "client", "Archive: processed blocks from {} up to #{}, starting sync", archive.dir().display(), head_height);
+            self.start_sync(ctx);
+        } else {
+            debug!(target: "client", "Archive: head #{}, {} blocks left", head_height, archive.len());
+            self.archive = Some(archive);
+            ctx.run_later(Duration::from_millis(0), |act, ctx| act.catchup_from_archive(ctx));
+        }
+    }
+
     fn receive_header(&mut self, header: BlockHeader, peer_info: PeerId) -> NetworkClientResponses {
         let hash = header.hash();
-        debug!(target: "client", "Received block header {} at {} from {}", hash, header.inner.height, peer_info);
+        debug!(target: "client", "{}Received block header {} at {} from {}", self.log_correlation(), hash, header.inner.height, peer_info);
 
         // Process block by chain, if it's valid header ask for the block.
         let result = self.chain.process_block_header(&header);
 
         match result {
             Err(ref e) if e.is_bad_data() => {
-                return NetworkClientResponses::Ban { ban_reason: ReasonForBan::BadBlockHeader }
+                return NetworkClientResponses::ReportPeer { reason: ReasonForBan::BadBlockHeader }
             }
             // Some error that worth surfacing.
             Err(ref e) if e.is_error() => {
@@ -837,13 +1585,49 @@ impl ClientActor {
         NetworkClientResponses::NoResponse
     }
 
-    fn receive_headers(&mut self, headers: Vec<BlockHeader>, peer_id: PeerId) -> bool {
+    /// Light client keeps only the header chain, so the headers of the new blocks are added to it
+    /// directly. Headers that don't extend a known header are ignored, the header sync gets them.
+    fn receive_light_header(
+        &mut self,
+        header: BlockHeader,
+        peer_id: PeerId,
+    ) -> NetworkClientResponses {
+        let hash = header.hash();
+        debug!(target: "client", "{}Received light client header {} at {} from {}", self.log_correlation(), hash, header.inner.height, peer_id);
+        if self.chain.get_block_header(&hash).is_ok()
+            || self.chain.get_block_header(&header.inner.prev_hash).is_err()
+        {
+            return NetworkClientResponses::NoResponse;
+        }
+        match self.chain.sync_block_headers(vec![header]) {
+            Err(ref err) if err.is_bad_data() => {
+                NetworkClientResponses::ReportPeer { reason: ReasonForBan::BadBlockHeader }
+            }
+            Err(err) => {
+                debug!(target: "client", "Light client: header {} refused by chain: {}", hash, err);
+                NetworkClientResponses::NoResponse
+            }
+            Ok(_) => NetworkClientResponses::NoResponse,
+        }
+    }
+
+    fn receive_headers(
+        &mut self,
+        headers: Vec<BlockHeader>,
+        peer_id: PeerId,
+        more_available: bool,
+    ) -> bool {
         info!(target: "client", "Received {} block headers from {}", headers.len(), peer_id);
         if headers.len() == 0 {
             return true;
         }
-        match self.chain.sync_block_headers(headers) {
-            Ok(_) => true,
+        match self.header_sync.process_headers(&mut self.chain, headers, &peer_id) {
+            Ok(_) => {
+                if more_available {
+                    self.header_sync.more_headers_available();
+                }
+                true
+            }
             Err(err) => {
                 if err.is_bad_data() {
                     error!(target: "client", "Error processing sync blocks: {}", err);
@@ -856,6 +1640,20 @@ impl ClientActor {
         }
     }
 
+    /// Bans the peer that served invalid sync data and requests the data pending from it again
+    /// from other peers, instead of waiting for the requests to time out.
+    fn ban_sync_peer(&mut self, peer_id: PeerId) {
+        let _ = self
+            .network_actor
+            .do_send(NetworkRequests::BanPeer { peer_id, reason: ReasonForBan::BadSyncData });
+        // Banned peer is disconnected, so it's not asked again until the network info is updated.
+        self.network_info.most_weight_peers.retain(|peer| peer.peer_info.id != peer_id);
+        self.network_info.active_peers.retain(|peer| peer.peer_info.id != peer_id);
+        self.header_sync.peer_banned(&peer_id);
+        self.block_sync.peer_banned();
+        self.state_sync.peer_banned(&peer_id);
+    }
+
     fn request_block_by_hash(&mut self, hash: CryptoHash, peer_id: PeerId) {
         match self.chain.block_exists(&hash) {
             Ok(false) => {
@@ -871,50 +1669,105 @@ impl ClientActor {
         }
     }
 
+    /// Requests missing ancestors of the orphan with given previous block from the peer that sent
+    /// it. Walks back through the orphan pool and the known headers until a known block, a block
+    /// with unknown header or `MAX_ORPHAN_ANCESTORS_REQUEST` missing blocks. Blocks requested
+    /// recently for other orphans are not requested again.
+    fn request_orphan_ancestors(&mut self, prev_hash: CryptoHash, peer_id: PeerId) {
+        self.orphan_ancestor_requests
+            .retain(|_, requested_at| requested_at.elapsed() < ORPHAN_ANCESTOR_REQUEST_TIMEOUT);
+        let mut hash = prev_hash;
+        let mut num_missing = 0;
+        let mut requested = vec![];
+        while num_missing < MAX_ORPHAN_ANCESTORS_REQUEST {
+            if let Some(prev_hash) = self.chain.orphan_prev_hash(&hash) {
+                hash = prev_hash;
+                continue;
+            }
+            match self.chain.block_exists(&hash) {
+                Ok(false) => {}
+                Ok(true) => break,
+                Err(err) => {
+                    error!(target: "client", "Failed to check block {} exists: {}", hash, err);
+                    break;
+                }
+            }
+            num_missing += 1;
+            if !self.orphan_ancestor_requests.contains_key(&hash) {
+                self.orphan_ancestor_requests.insert(hash, Instant::now());
+                let _ = self.network_actor.do_send(NetworkRequests::BlockRequest { hash, peer_id });
+                requested.push(hash);
+            }
+            match self.chain.get_block_header(&hash) {
+                Ok(header) => hash = header.inner.prev_hash,
+                // Earlier ancestors are requested once this block arrives as an orphan.
+                Err(_) => break,
+            }
+        }
+        if !requested.is_empty() {
+            debug!(target: "client", "{}Requested {} orphan ancestors from {}: {:?}", self.log_correlation(), requested.len(), peer_id, requested);
+        }
+    }
+
+    /// Retrieves headers after the common header with given locator, up to `max_size` bytes in total.
+    /// Returns headers and if there are more headers available after them.
     fn retrieve_headers(
         &mut self,
         hashes: Vec<CryptoHash>,
-    ) -> Result<Vec<BlockHeader>, near_chain::Error> {
+        skip: BlockIndex,
+        max_size: u64,
+    ) -> Result<(Vec<BlockHeader>, bool), near_chain::Error> {
         let header = match self.chain.find_common_header(&hashes) {
             Some(header) => header,
-            None => return Ok(vec![]),
+            None => return Ok((vec![], false)),
         };
 
         let mut headers = vec![];
+        let mut total_size = 0;
+        let mut more_available = false;
         let max_height = self.chain.header_head()?.height;
         // TODO: this may be inefficient if there are a lot of skipped blocks.
-        for h in header.inner.height + 1..=max_height {
+        for h in header.inner.height + 1 + skip..=max_height {
             if let Ok(header) = self.chain.get_header_by_height(h) {
-                headers.push(header.clone());
-                if headers.len() >= sync::MAX_BLOCK_HEADERS as usize {
+                let size = header.try_to_vec()?.len() as u64;
+                // Always send at least one header, even if it's above the size limit.
+                if headers.len() >= sync::MAX_BLOCK_HEADERS as usize
+                    || (!headers.is_empty() && total_size + size > max_size)
+                {
+                    more_available = true;
                     break;
                 }
+                total_size += size;
+                headers.push(header.clone());
             }
         }
-        Ok(headers)
+        Ok((headers, more_available))
     }
 
     /// Validate transaction and return transaction information relevant to ordering it in the mempool.
-    fn validate_tx(&mut self, tx: SignedTransaction) -> Result<ValidTransaction, String> {
-        let head = self.chain.head().map_err(|err| err.to_string())?;
+    fn validate_tx(&mut self, tx: SignedTransaction) -> Result<ValidTransaction, InvalidTxError> {
+        let head = self.chain.head().map_err(|err| InvalidTxError::Refused(err.to_string()))?;
+        let transaction_validity_period = self.chain.transaction_validity_period();
         let state_root = self
             .chain
             .get_post_state_root(&head.last_block_hash)
-            .map_err(|err| err.to_string())?
+            .map_err(|err| InvalidTxError::Refused(err.to_string()))?
             .clone();
         if !check_tx_history(
             self.chain.get_block_header(&tx.transaction.block_hash).ok(),
             head.height,
-            self.config.transaction_validity_period,
+            transaction_validity_period,
         ) {
-            return Err("Transaction has either expired or is from a different fork".to_string());
+            return Err(InvalidTxError::Expired);
         }
         self.runtime_adapter.validate_tx(0, state_root, tx)
     }
 
     /// Check whether need to (continue) sync.
     fn needs_syncing(&self) -> Result<(bool, u64), near_chain::Error> {
-        let head = self.chain.head()?;
+        // Light client doesn't have the blocks, it is synced once it has all the headers.
+        let head =
+            if self.config.light_client { self.chain.header_head()? } else { self.chain.head()? };
         let mut is_syncing = self.sync_status.is_syncing();
 
         let full_peer_info =
@@ -969,6 +1822,9 @@ impl ClientActor {
 
     /// Main syncing job responsible for syncing client with other peers.
     fn sync(&mut self, ctx: &mut Context<ClientActor>) {
+        if self.shutting_down {
+            return;
+        }
         // Macro to schedule to call this function later if error occurred.
         macro_rules! unwrap_or_run_later(($obj: expr) => (match $obj {
             Ok(v) => v,
@@ -993,14 +1849,16 @@ impl ClientActor {
 
                 // Initial transition out of "syncing" state.
                 // Start by handling scheduling block production if needed.
-                let head = unwrap_or_run_later!(self.chain.head());
-                self.check_send_announce_account(&head.last_block_hash, head.height);
-                self.handle_scheduling_block_production(
-                    ctx,
-                    head.last_block_hash,
-                    head.height,
-                    head.height,
-                );
+                if !self.config.light_client {
+                    let head = unwrap_or_run_later!(self.chain.head());
+                    self.check_send_announce_account(&head.last_block_hash, head.height);
+                    self.handle_scheduling_block_production(
+                        ctx,
+                        head.last_block_hash,
+                        head.height,
+                        head.height,
+                    );
+                }
             }
             wait_period = self.config.sync_check_period;
         } else {
@@ -1011,10 +1869,13 @@ impl ClientActor {
                 highest_height,
                 &self.network_info.most_weight_peers
             ));
-            // Only body / state sync if header height is close to the latest.
+            // Only body / state sync if header height is close to the latest. Light client
+            // syncs neither blocks nor state.
             let header_head = unwrap_or_run_later!(self.chain.header_head());
-            if highest_height <= self.config.block_header_fetch_horizon
-                || header_head.height >= highest_height - self.config.block_header_fetch_horizon
+            if !self.config.light_client
+                && (highest_height <= self.config.block_header_fetch_horizon
+                    || header_head.height
+                        >= highest_height - self.config.block_header_fetch_horizon)
             {
                 // Sync state if already running sync state or if block sync is too far.
                 let sync_state = match self.sync_status {
@@ -1032,8 +1893,7 @@ impl ClientActor {
                         &mut self.chain,
                         highest_height,
                         &self.network_info.most_weight_peers,
-                        // TODO: add tracking shards here.
-                        vec![0],
+                        self.chain.tracked_shard_ids(),
                     ));
                 }
             }
@@ -1055,7 +1915,7 @@ impl ClientActor {
                     act.network_info = network_info;
                     actix::fut::ok(())
                 }
-                Ok(NetworkResponses::NoResponse) => actix::fut::ok(()),
+                Ok(_) => actix::fut::ok(()),
                 Err(e) => {
                     error!(target: "client", "Sync: recieved error or incorrect result: {}", e);
                     actix::fut::err(())
@@ -1077,17 +1937,9 @@ impl ClientActor {
                 ()
             );
             let num_validators = validators.len();
-            let is_validator = if let Some(block_producer) = &act.block_producer {
-                if let Some((_, is_slashed)) =
-                    validators.into_iter().find(|x| x.0 == block_producer.account_id)
-                {
-                    !is_slashed
-                } else {
-                    false
-                }
-            } else {
-                false
-            };
+            let is_validator = validators.into_iter().any(|(account_id, is_slashed)| {
+                !is_slashed && act.get_block_producer(&account_id).is_some()
+            });
 
             act.info_helper.info(
                 &head,
@@ -1096,6 +1948,8 @@ impl ClientActor {
                 &act.network_info,
                 is_validator,
                 num_validators,
+                act.chain.store().cache_stats(),
+                &act.tx_pool,
             );
 
             act.log_summary(ctx);
@@ -1149,18 +2003,24 @@ impl ClientActor {
         true
     }
 
+    /// Returns requested part of the state with its proof. Receipts are sent with the first part.
     fn state_request(
         &mut self,
         shard_id: ShardId,
         hash: CryptoHash,
-    ) -> Result<(Vec<u8>, Vec<Receipt>), near_chain::Error> {
+        part_id: u64,
+    ) -> Result<(StatePart, Vec<Receipt>), near_chain::Error> {
         let header = self.chain.get_block_header(&hash)?;
         let prev_hash = header.inner.prev_hash;
-        let payload = self
+        let part = self
             .runtime_adapter
-            .dump_state(shard_id, header.inner.prev_state_root)
+            .obtain_state_part(shard_id, header.inner.prev_state_root, part_id)
             .map_err(|err| ErrorKind::Other(err.to_string()))?;
-        let receipts = self.chain.get_receipts(&prev_hash)?.clone();
-        Ok((payload, receipts))
+        let receipts = if part_id == 0 {
+            self.chain.get_receipts(&prev_hash, shard_id)?.clone()
+        } else {
+            vec![]
+        };
+        Ok((part, receipts))
     }
 }
@@ -2,12 +2,14 @@ use std::time::Instant;
 
 use actix::Addr;
 use ansi_term::Color::{Blue, Cyan, Green, White, Yellow};
+use chrono::Utc;
 use log::info;
 use serde_json::json;
 use sysinfo::{get_current_pid, Pid, ProcessExt, System, SystemExt};
 
-use near_chain::Tip;
+use near_chain::{ChainStoreCacheStats, Tip};
 use near_network::types::{NetworkInfo, PeerId};
+use near_pool::TransactionPool;
 use near_primitives::serialize::to_base;
 use near_telemetry::{telemetry, TelemetryActor};
 
@@ -22,6 +24,8 @@ pub struct InfoHelper {
     num_blocks_processed: u64,
     /// Total number of transactions processed.
     num_tx_processed: u64,
+    /// Total number of reorgs refused for exceeding the maximum depth.
+    num_refused_reorgs: u64,
     /// Process id to query resources.
     pid: Option<Pid>,
     /// System reference.
@@ -41,6 +45,7 @@ impl InfoHelper {
             started: Instant::now(),
             num_blocks_processed: 0,
             num_tx_processed: 0,
+            num_refused_reorgs: 0,
             pid: get_current_pid().ok(),
             sys: System::new(),
             telemetry_actor,
@@ -53,6 +58,10 @@ impl InfoHelper {
         self.num_tx_processed += num_transactions;
     }
 
+    pub fn reorg_refused(&mut self) {
+        self.num_refused_reorgs += 1;
+    }
+
     pub fn info(
         &mut self,
         head: &Tip,
@@ -61,6 +70,8 @@ impl InfoHelper {
         network_info: &NetworkInfo,
         is_validator: bool,
         num_validators: usize,
+        cache_stats: &ChainStoreCacheStats,
+        tx_pool: &TransactionPool,
     ) {
         let (cpu_usage, memory) = if let Some(pid) = self.pid {
             if self.sys.refresh_process(pid) {
@@ -109,6 +120,13 @@ impl InfoHelper {
                     "bandwidth_upload": network_info.sent_bytes_per_sec,
                     "cpu": cpu_usage,
                     "memory": memory,
+                    "refused_reorgs": self.num_refused_reorgs,
+                    "header_cache_hit_rate": cache_stats.headers.hit_rate(),
+                    "block_cache_hit_rate": cache_stats.blocks.hit_rate(),
+                    "state_root_cache_hit_rate": cache_stats.post_state_roots.hit_rate(),
+                    "tx_pool_size": tx_pool.len(),
+                    "tx_pool_bytes": tx_pool.num_bytes(),
+                    "tx_pool_evicted": tx_pool.num_evicted(),
                 }),
                 &self.block_producer,
             ),
@@ -157,12 +175,27 @@ fn display_sync_status(sync_status: &SyncStatus, head: &Tip) -> String {
                         shard_id,
                         match shard_status {
                             ShardSyncStatus::StateDownload {
-                                start_time: _,
-                                prev_update_time: _,
-                                prev_downloaded_size: _,
-                                downloaded_size: _,
-                                total_size: _,
-                            } => format!("download"),
+                                downloaded_size,
+                                total_size,
+                                downloaded_parts,
+                                num_parts,
+                                ..
+                            } => {
+                                let now = Utc::now();
+                                let eta = match shard_status.download_eta(now) {
+                                    Some(eta) => format!("{}s", eta.as_secs()),
+                                    None => "-".to_string(),
+                                };
+                                format!(
+                                    "download {}/{} parts, {} of {}, {}, eta {}",
+                                    downloaded_parts,
+                                    num_parts,
+                                    pretty_bytes(*downloaded_size),
+                                    pretty_bytes(*total_size),
+                                    pretty_bytes_per_sec(shard_status.download_rate(now)),
+                                    eta
+                                )
+                            }
                             ShardSyncStatus::StateValidation => format!("validation"),
                             ShardSyncStatus::StateDone => format!("done"),
                             ShardSyncStatus::Error(error) => format!("error {}", error),
@@ -1,12 +1,16 @@
 pub use crate::client::ClientActor;
 pub use crate::types::{
-    BlockProducer, ClientConfig, Error, GetBlock, Query, Status, StatusResponse,
-    SyncStatus, TxDetails, TxStatus,
+    BackupDatabase, BlockProducer, ClientConfig, Correlated, Error, GetBans, GetBlock,
+    GetBlockApprovals, GetBlockHeader, GetBlockProof, GetChainProfile, GetNetworkInfo,
+    GetProtocolConfig, GetRecentReorgs, GetStoreMetrics, GetSyncStatus, GetTxPool,
+    GetUnstakeInfo, Query, ReloadConfig, Shutdown, SimulateTx, Status, StatusResponse,
+    SwapValidatorKey, SyncConfig, SyncStatus, TxDetails, TxStatus, UnbanPeer, UpdateConfig,
 };
 pub use crate::view_client::ViewClientActor;
 
 mod client;
 mod info;
+mod rebroadcast;
 mod sync;
 pub mod test_utils;
 mod types;
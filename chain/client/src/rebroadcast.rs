@@ -0,0 +1,114 @@
+//! Keeps track of the transactions submitted to this node directly and decides when to broadcast
+//! them again, in case the first broadcast didn't reach the block producers.
+use std::collections::HashMap;
+
+use near_primitives::block::Block;
+use near_primitives::hash::CryptoHash;
+use near_primitives::transaction::SignedTransaction;
+use near_primitives::types::BlockIndex;
+
+struct TrackedTransaction {
+    transaction: SignedTransaction,
+    /// Height after which the transaction is broadcast again.
+    next_height: BlockIndex,
+    /// Number of heights to wait before the next broadcast, doubled after each one.
+    delay: BlockIndex,
+}
+
+/// Local transactions that were not yet included into a block.
+pub struct RebroadcastTracker {
+    /// Number of heights to wait before the first rebroadcast.
+    initial_delay: BlockIndex,
+    transactions: HashMap<CryptoHash, TrackedTransaction>,
+}
+
+impl RebroadcastTracker {
+    pub fn new(initial_delay: BlockIndex) -> Self {
+        RebroadcastTracker { initial_delay, transactions: HashMap::default() }
+    }
+
+    /// Starts tracking transaction that was broadcast at given height.
+    pub fn track(&mut self, transaction: SignedTransaction, height: BlockIndex) {
+        let delay = self.initial_delay.max(1);
+        self.transactions.insert(
+            transaction.get_hash(),
+            TrackedTransaction { transaction, next_height: height + delay, delay },
+        );
+    }
+
+    /// Stops tracking transactions included into the block.
+    pub fn block_included(&mut self, block: &Block) {
+        for transaction in block.transactions.iter() {
+            self.transactions.remove(&transaction.get_hash());
+        }
+    }
+
+    /// Returns transactions that must be broadcast again at given height and schedules the next
+    /// broadcast with doubled delay. Transactions for which `is_valid` returns false (e.g. expired)
+    /// are dropped.
+    pub fn due<F>(&mut self, height: BlockIndex, mut is_valid: F) -> Vec<SignedTransaction>
+    where
+        F: FnMut(&SignedTransaction) -> bool,
+    {
+        self.transactions.retain(|_, tracked| is_valid(&tracked.transaction));
+        let mut result = vec![];
+        for tracked in self.transactions.values_mut() {
+            if tracked.next_height <= height {
+                tracked.delay *= 2;
+                tracked.next_height = height + tracked.delay;
+                result.push(tracked.transaction.clone());
+            }
+        }
+        result
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use near_crypto::{InMemorySigner, KeyType};
+
+    use super::*;
+
+    fn transaction(nonce: u64) -> SignedTransaction {
+        let signer =
+            Arc::new(InMemorySigner::from_seed("alice.near", KeyType::ED25519, "alice.near"));
+        SignedTransaction::send_money(
+            nonce,
+            "alice.near".to_string(),
+            "bob.near".to_string(),
+            signer,
+            1,
+            CryptoHash::default(),
+        )
+    }
+
+    #[test]
+    fn test_rebroadcast_backoff() {
+        let mut tracker = RebroadcastTracker::new(2);
+        tracker.track(transaction(1), 10);
+        tracker.track(transaction(2), 10);
+        assert!(tracker.due(11, |_| true).is_empty());
+        assert_eq!(tracker.due(12, |_| true).len(), 2);
+        // Next broadcast after 4 heights, then after 8.
+        assert!(tracker.due(15, |_| true).is_empty());
+        assert_eq!(tracker.due(16, |_| true).len(), 2);
+        assert!(tracker.due(23, |_| true).is_empty());
+
+        // Included and expired transactions are not broadcast anymore.
+        let mut block = Block::genesis(CryptoHash::default(), Utc::now());
+        block.transactions = vec![transaction(1)];
+        tracker.block_included(&block);
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(tracker.due(24, |_| true).len(), 1);
+        assert!(tracker.due(100, |_| false).is_empty());
+        assert_eq!(tracker.len(), 0);
+    }
+}
@@ -1,39 +1,32 @@
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 
 use actix::Recipient;
 use chrono::{DateTime, Duration, Utc};
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use rand::seq::SliceRandom;
 use rand::{thread_rng, Rng};
 
-use near_chain::{Chain, Tip};
-use near_network::types::ReasonForBan;
+use near_chain::{BlockHeader, Chain, Tip};
+use near_network::types::{PeerId, ReasonForBan};
 use near_network::{FullPeerInfo, NetworkRequests};
 use near_primitives::hash::CryptoHash;
-use near_primitives::types::{BlockIndex, ShardId};
+use near_primitives::receipt::Receipt;
+use near_primitives::types::{BlockIndex, ShardId, StatePart};
 
-use crate::types::{ShardSyncStatus, SyncStatus};
+use crate::types::{ShardSyncStatus, SyncParams, SyncStatus};
 
 /// Maximum number of block headers send over the network.
 pub const MAX_BLOCK_HEADERS: u64 = 512;
 
-const BLOCK_HEADER_PROGRESS_TIMEOUT: i64 = 2;
-
 /// Maximum number of block header hashes to send as part of a locator.
 pub const MAX_BLOCK_HEADER_HASHES: usize = 20;
 
-/// Maximum number of blocks to request in one step.
-const MAX_BLOCK_REQUEST: usize = 100;
-
-/// Maximum number of blocks to ask from single peer.
-const MAX_PEER_BLOCK_REQUEST: usize = 10;
-
-const BLOCK_REQUEST_TIMEOUT: i64 = 6;
-const BLOCK_SOME_RECEIVED_TIMEOUT: i64 = 1;
 const BLOCK_REQUEST_BROADCAST_OFFSET: u64 = 2;
 
-/// Sync state download timeout in minutes.
-const STATE_SYNC_TIMEOUT: i64 = 10;
+/// Maximum number of parts the state of a shard is split into. The number of parts comes from the
+/// peers, parts claiming more are refused.
+pub const MAX_STATE_PARTS: u64 = 100_000;
 
 /// Adapter to allow to test Header/Body/State sync without actix.
 pub trait SyncNetworkAdapter: Sync + Send {
@@ -58,13 +51,36 @@ impl SyncNetworkAdapter for SyncNetworkRecipient {
     }
 }
 
-/// Get random peer from the most weighted peers.
+fn to_chrono_duration(duration: std::time::Duration) -> Duration {
+    Duration::from_std(duration).expect("Sync timeout is out of range")
+}
+
+/// Weight of the peer when choosing peers for sync requests.
+fn peer_weight(peer: &FullPeerInfo) -> u64 {
+    peer.score.max(1)
+}
+
+/// Get random peer from the most weighted peers, with the probability proportional to its score.
 pub fn most_weight_peer(most_weight_peers: &Vec<FullPeerInfo>) -> Option<FullPeerInfo> {
-    if most_weight_peers.len() == 0 {
-        return None;
+    most_weight_peers.choose_weighted(&mut thread_rng(), peer_weight).ok().cloned()
+}
+
+/// Orders peers randomly, peers with higher score being more likely to come first.
+fn order_by_score(peers: &[FullPeerInfo]) -> Vec<FullPeerInfo> {
+    let mut rng = thread_rng();
+    let mut remaining = peers.to_vec();
+    let mut result = Vec::with_capacity(peers.len());
+    while !remaining.is_empty() {
+        let total_weight: u64 = remaining.iter().map(peer_weight).sum();
+        let mut choice = rng.gen_range(0, total_weight);
+        let mut index = 0;
+        while choice >= peer_weight(&remaining[index]) {
+            choice -= peer_weight(&remaining[index]);
+            index += 1;
+        }
+        result.push(remaining.swap_remove(index));
     }
-    let index = thread_rng().gen_range(0, most_weight_peers.len());
-    Some(most_weight_peers[index].clone())
+    result
 }
 
 /// Helper to keep track of sync headers.
@@ -73,21 +89,59 @@ pub struct HeaderSync {
     network_adapter: Box<dyn SyncNetworkAdapter>,
     history_locator: Vec<(BlockIndex, CryptoHash)>,
     prev_header_sync: (DateTime<Utc>, BlockIndex, BlockIndex),
+    /// Peer asked for the headers right after the sync head.
     syncing_peer: Option<FullPeerInfo>,
+    /// Number of header ranges requested from different peers in the last request.
+    num_requested_ranges: u64,
+    /// Headers received before the ones preceding them, by the hash of the preceding header,
+    /// together with the peer that sent them.
+    pending_headers: HashMap<CryptoHash, (PeerId, Vec<BlockHeader>)>,
     stalling_ts: Option<DateTime<Utc>>,
+    /// Syncing peer indicated that it has more headers after the last received batch.
+    more_headers_available: bool,
+    /// Maximum number of peers asked for consecutive ranges of headers at once.
+    max_peers: usize,
+    /// A peer was banned for invalid headers, the headers are requested again from other peers.
+    request_again: bool,
+    /// Time to wait for more headers while they keep coming.
+    progress_timeout: Duration,
+    /// Time to wait for the requested headers.
+    request_timeout: Duration,
+    /// Time of stalled sync after which syncing peer is banned.
+    stall_ban_timeout: Duration,
 }
 
 impl HeaderSync {
-    pub fn new(network_adapter: Box<dyn SyncNetworkAdapter>) -> Self {
+    pub fn new(network_adapter: Box<dyn SyncNetworkAdapter>, params: &SyncParams) -> Self {
         HeaderSync {
             network_adapter,
             history_locator: vec![],
             prev_header_sync: (Utc::now(), 0, 0),
             syncing_peer: None,
+            num_requested_ranges: 1,
+            pending_headers: HashMap::default(),
             stalling_ts: None,
+            more_headers_available: false,
+            request_again: false,
+            max_peers: params.max_header_sync_peers,
+            progress_timeout: to_chrono_duration(params.header_progress),
+            request_timeout: to_chrono_duration(params.header_request),
+            stall_ban_timeout: to_chrono_duration(params.header_stall_ban),
         }
     }
 
+    /// Mark that the last received batch of headers was partial and more can be requested right away.
+    pub fn more_headers_available(&mut self) {
+        self.more_headers_available = true;
+    }
+
+    /// Drops the headers received from the banned peer and requests headers again from other
+    /// peers without waiting for the request timeout.
+    pub fn peer_banned(&mut self, peer_id: &PeerId) {
+        self.pending_headers.retain(|_, (pending_peer_id, _)| pending_peer_id != peer_id);
+        self.request_again = true;
+    }
+
     pub fn run(
         &mut self,
         sync_status: &mut SyncStatus,
@@ -123,10 +177,61 @@ impl HeaderSync {
                 SyncStatus::HeaderSync { current_height: header_head.height, highest_height };
             let header_head = chain.header_head()?;
             self.syncing_peer = None;
-            if let Some(peer) = most_weight_peer(&most_weight_peers) {
-                if peer.chain_info.total_weight > header_head.total_weight {
-                    self.syncing_peer = self.request_headers(chain, peer);
+            let peers: Vec<_> = most_weight_peers
+                .iter()
+                .filter(|peer| peer.chain_info.total_weight > header_head.total_weight)
+                .cloned()
+                .collect();
+            let mut peers = order_by_score(&peers);
+            peers.truncate(self.max_peers);
+            self.request_headers(chain, &header_head, peers);
+        }
+        Ok(())
+    }
+
+    /// Adds received headers to the chain. Headers already known, e.g. from another peer, are
+    /// ignored and headers received before the ones preceding them are kept until those arrive.
+    pub fn process_headers(
+        &mut self,
+        chain: &mut Chain,
+        headers: Vec<BlockHeader>,
+        peer_id: &PeerId,
+    ) -> Result<(), near_chain::Error> {
+        let headers = unknown_headers(chain, headers);
+        let prev_hash = match headers.first() {
+            Some(header) => header.inner.prev_hash,
+            None => return Ok(()),
+        };
+        if chain.get_block_header(&prev_hash).is_err() {
+            if self.pending_headers.len() < self.max_peers {
+                self.pending_headers.insert(prev_hash, (*peer_id, headers));
+            }
+            return Ok(());
+        }
+        chain.sync_block_headers(headers)?;
+
+        // Continue with the pending headers which preceding header was added.
+        loop {
+            let prev_hash = match self
+                .pending_headers
+                .keys()
+                .find(|prev_hash| chain.get_block_header(prev_hash).is_ok())
+            {
+                Some(prev_hash) => *prev_hash,
+                None => break,
+            };
+            let (peer_id, pending) = self.pending_headers.remove(&prev_hash).unwrap();
+            let pending = unknown_headers(chain, pending);
+            if let Err(err) = chain.sync_block_headers(pending) {
+                if err.is_bad_data() {
+                    warn!(target: "sync", "Sync: banning peer {} for invalid headers: {}", peer_id, err);
+                    self.network_adapter.send(NetworkRequests::BanPeer {
+                        peer_id,
+                        reason: ReasonForBan::BadSyncData,
+                    });
+                    self.peer_banned(&peer_id);
                 }
+                break;
             }
         }
         Ok(())
@@ -137,21 +242,30 @@ impl HeaderSync {
         let (timeout, latest_height, prev_height) = self.prev_header_sync;
 
         // Received all necessary header, can request more.
-        let all_headers_received = header_head.height >= prev_height + MAX_BLOCK_HEADERS - 4;
+        // Peer may send smaller batches depending on bandwidth, and indicate that it has more headers.
+        let all_headers_received = header_head.height
+            >= prev_height + MAX_BLOCK_HEADERS * self.num_requested_ranges - 4
+            || (self.more_headers_available && header_head.height > prev_height);
         // No headers processed and it's past timeout, request more.
         let stalling = header_head.height <= latest_height && now > timeout;
 
-        // Always enable header sync on initial state transition from NoSync / AwaitingPeers.
-        let force_sync = match sync_status {
-            SyncStatus::NoSync | SyncStatus::AwaitingPeers => true,
-            _ => false,
-        };
+        // Always enable header sync on initial state transition from NoSync / AwaitingPeers, or
+        // when a peer was banned for invalid headers.
+        let force_sync = self.request_again
+            || match sync_status {
+                SyncStatus::NoSync | SyncStatus::AwaitingPeers => true,
+                _ => false,
+            };
 
         if force_sync || all_headers_received || stalling {
+            self.more_headers_available = false;
+            self.request_again = false;
             self.prev_header_sync =
-                (now + Duration::seconds(10), header_head.height, header_head.height);
+                (now + self.request_timeout, header_head.height, header_head.height);
 
             if stalling {
+                // Headers that didn't connect to the chain until now are requested again.
+                self.pending_headers.clear();
                 if self.stalling_ts.is_none() {
                     self.stalling_ts = Some(now);
                 } else {
@@ -166,14 +280,14 @@ impl HeaderSync {
                     if let Some(ref peer) = self.syncing_peer {
                         match sync_status {
                             SyncStatus::HeaderSync { highest_height, .. } => {
-                                if now > *stalling_ts + Duration::seconds(120)
+                                if now > *stalling_ts + self.stall_ban_timeout
                                     && *highest_height == peer.chain_info.height
                                 {
-                                    info!(target: "sync", "Sync: ban a fraudulent peer: {}, claimed height: {}, total weight: {}",
+                                    info!(target: "sync", "Sync: report a fraudulent peer: {}, claimed height: {}, total weight: {}",
                                         peer.peer_info, peer.chain_info.height, peer.chain_info.total_weight);
-                                    self.network_adapter.send(NetworkRequests::BanPeer {
+                                    self.network_adapter.send(NetworkRequests::ReportPeer {
                                         peer_id: peer.peer_info.id.clone(),
-                                        ban_reason: ReasonForBan::HeightFraud,
+                                        reason: ReasonForBan::HeightFraud,
                                     });
                                 }
                             }
@@ -188,7 +302,7 @@ impl HeaderSync {
             // Resetting the timeout as long as we make progress.
             if header_head.height > latest_height {
                 self.prev_header_sync = (
-                    now + Duration::seconds(BLOCK_HEADER_PROGRESS_TIMEOUT),
+                    now + self.progress_timeout,
                     header_head.height,
                     prev_height,
                 );
@@ -197,17 +311,32 @@ impl HeaderSync {
         }
     }
 
-    /// Request headers from a given peer to advance the chain.
-    fn request_headers(&mut self, chain: &mut Chain, peer: FullPeerInfo) -> Option<FullPeerInfo> {
-        if let Ok(locator) = self.get_locator(chain) {
-            debug!(target: "sync", "Sync: request headers: asking {} for headers, {:?}", peer.peer_info.id, locator);
+    /// Request consecutive ranges of headers following the sync head from given peers, one range
+    /// from each peer, to advance the chain.
+    fn request_headers(&mut self, chain: &mut Chain, header_head: &Tip, peers: Vec<FullPeerInfo>) {
+        let locator = match self.get_locator(chain) {
+            Ok(locator) => locator,
+            Err(_) => return,
+        };
+        self.num_requested_ranges = 0;
+        for peer in peers {
+            let skip = self.num_requested_ranges * MAX_BLOCK_HEADERS;
+            // Peer doesn't have headers of the range.
+            if skip > 0 && peer.chain_info.height <= header_head.height + skip {
+                continue;
+            }
+            debug!(target: "sync", "Sync: request headers: asking {} for headers after {} heights, {:?}", peer.peer_info.id, skip, locator);
             self.network_adapter.send(NetworkRequests::BlockHeadersRequest {
-                hashes: locator,
+                hashes: locator.clone(),
+                skip,
                 peer_id: peer.peer_info.id.clone(),
             });
-            return Some(peer);
+            if skip == 0 {
+                self.syncing_peer = Some(peer);
+            }
+            self.num_requested_ranges += 1;
         }
-        None
+        self.num_requested_ranges = cmp::max(self.num_requested_ranges, 1);
     }
 
     fn get_locator(&mut self, chain: &mut Chain) -> Result<Vec<CryptoHash>, near_chain::Error> {
@@ -243,6 +372,13 @@ impl HeaderSync {
     }
 }
 
+/// Returns the headers that are not in the chain yet, sorted by height.
+fn unknown_headers(chain: &mut Chain, mut headers: Vec<BlockHeader>) -> Vec<BlockHeader> {
+    headers.retain(|header| chain.get_block_header(&header.hash()).is_err());
+    headers.sort_by_key(|header| header.inner.height);
+    headers
+}
+
 /// Check if there is a close enough value to provided height in the locator.
 fn close_enough(locator: &Vec<(u64, CryptoHash)>, height: u64) -> Option<(u64, CryptoHash)> {
     if locator.len() == 0 {
@@ -292,12 +428,21 @@ pub struct BlockSync {
     prev_blocks_received: BlockIndex,
     /// How far to fetch blocks vs fetch state.
     block_fetch_horizon: BlockIndex,
+    /// Maximum number of blocks to request in one step.
+    max_requests: usize,
+    /// Maximum number of blocks to ask from single peer in one step.
+    max_peer_requests: usize,
+    /// Time to wait for the requested blocks.
+    request_timeout: Duration,
+    /// Time to wait for the next block after some were received.
+    some_received_timeout: Duration,
 }
 
 impl BlockSync {
     pub fn new(
         network_adapter: Box<dyn SyncNetworkAdapter>,
         block_fetch_horizon: BlockIndex,
+        params: &SyncParams,
     ) -> Self {
         BlockSync {
             network_adapter,
@@ -305,6 +450,10 @@ impl BlockSync {
             receive_timeout: Utc::now(),
             prev_blocks_received: 0,
             block_fetch_horizon,
+            max_requests: params.max_block_requests,
+            max_peer_requests: params.max_peer_block_requests,
+            request_timeout: to_chrono_duration(params.block_request),
+            some_received_timeout: to_chrono_duration(params.block_some_received),
         }
     }
 
@@ -341,9 +490,10 @@ impl BlockSync {
             return Ok(true);
         }
         hashes.reverse();
-        // Ask for `num_peers * MAX_PEER_BLOCK_REQUEST` blocks up to 100, throttle if there is too many orphans in the chain.
+        // Ask for `num_peers * max_peer_requests` blocks up to `max_requests`, throttle if there
+        // is too many orphans in the chain.
         let block_count = cmp::min(
-            cmp::min(MAX_BLOCK_REQUEST, MAX_PEER_BLOCK_REQUEST * most_weight_peers.len()),
+            cmp::min(self.max_requests, self.max_peer_requests * most_weight_peers.len()),
             near_chain::MAX_ORPHAN_SIZE.saturating_sub(chain.orphans_len()) + 1,
         );
 
@@ -359,9 +509,11 @@ impl BlockSync {
             debug!(target: "sync", "Block sync: {}/{} requesting blocks {:?} from {} peers", head.height, header_head.height, hashes_to_request, most_weight_peers.len());
 
             self.blocks_requested = 0;
-            self.receive_timeout = Utc::now() + Duration::seconds(BLOCK_REQUEST_TIMEOUT);
+            self.receive_timeout = Utc::now() + self.request_timeout;
 
-            let mut peers_iter = most_weight_peers.iter().cycle();
+            // Peers with higher score are asked first and get more blocks if they don't divide.
+            let peers = order_by_score(most_weight_peers);
+            let mut peers_iter = peers.iter().cycle();
             for hash in hashes_to_request.into_iter() {
                 if let Some(peer) = peers_iter.next() {
                     self.network_adapter.send(NetworkRequests::BlockRequest {
@@ -375,6 +527,12 @@ impl BlockSync {
         Ok(false)
     }
 
+    /// Missing blocks are requested again from other peers without waiting for the request
+    /// timeout, as the blocks requested from the banned peer won't arrive.
+    pub fn peer_banned(&mut self) {
+        self.blocks_requested = 0;
+    }
+
     /// Check if we should run block body sync and ask for more full blocks.
     fn block_sync_due(&mut self, chain: &Chain) -> Result<bool, near_chain::Error> {
         let blocks_received = self.blocks_received(chain)?;
@@ -390,7 +548,7 @@ impl BlockSync {
 
         if blocks_received > self.prev_blocks_received {
             // Some blocks received, update for next check.
-            self.receive_timeout = Utc::now() + Duration::seconds(BLOCK_SOME_RECEIVED_TIMEOUT);
+            self.receive_timeout = Utc::now() + self.some_received_timeout;
             self.blocks_requested =
                 self.blocks_requested.saturating_sub(blocks_received - self.prev_blocks_received);
             self.prev_blocks_received = blocks_received;
@@ -411,25 +569,124 @@ impl BlockSync {
     }
 }
 
+/// State parts of a shard downloaded so far.
+struct ShardStateDownload {
+    /// Total number of parts, known once the first part is received.
+    num_parts: Option<u64>,
+    /// Validated parts by part id.
+    parts: BTreeMap<u64, StatePart>,
+    receipts: Vec<Receipt>,
+    /// Parts requested and not received yet, with the peer asked and the time of the request.
+    requested: HashMap<u64, (PeerId, DateTime<Utc>)>,
+    /// Size of the data of the downloaded parts, including the ones saved before the restart.
+    downloaded_size: u64,
+    /// Time the last part was received at.
+    last_progress: DateTime<Utc>,
+}
+
+impl ShardStateDownload {
+    fn new(parts: Vec<StatePart>, receipts: Vec<Receipt>) -> Self {
+        ShardStateDownload {
+            num_parts: parts.first().map(|part| part.num_parts),
+            downloaded_size: parts.iter().map(|part| part.data.len() as u64).sum(),
+            parts: parts.into_iter().map(|part| (part.part_id, part)).collect(),
+            receipts,
+            requested: HashMap::default(),
+            last_progress: Utc::now(),
+        }
+    }
+
+    /// Total size of the state, estimated by the average size of the downloaded parts.
+    fn estimated_total_size(&self) -> u64 {
+        match self.num_parts {
+            Some(num_parts) if !self.parts.is_empty() => {
+                self.downloaded_size * num_parts / self.parts.len() as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// Downloaded parts next to the given part whose key ranges don't adjoin its range.
+    fn conflicting_parts(&self, part: &StatePart) -> Vec<u64> {
+        let mut conflicting = vec![];
+        if let Some(prev) = part.part_id.checked_sub(1).and_then(|id| self.parts.get(&id)) {
+            if prev.end_key.as_ref() != Some(&part.start_key) {
+                conflicting.push(prev.part_id);
+            }
+        }
+        if let Some(next) = self.parts.get(&(part.part_id + 1)) {
+            if part.end_key.as_ref() != Some(&next.start_key) {
+                conflicting.push(next.part_id);
+            }
+        }
+        conflicting
+    }
+
+    /// At most `limit` parts that are neither downloaded nor requested.
+    fn missing_parts(&self, limit: usize) -> Vec<u64> {
+        match self.num_parts {
+            Some(num_parts) => (0..num_parts)
+                .filter(|part_id| {
+                    !self.parts.contains_key(part_id) && !self.requested.contains_key(part_id)
+                })
+                .take(limit)
+                .collect(),
+            // First part tells how many parts there are.
+            None if self.requested.is_empty() && limit > 0 => vec![0],
+            None => vec![],
+        }
+    }
+
+    /// Status of the download, keeping the start of the download from the previous status.
+    fn status(&self, prev_status: Option<&ShardSyncStatus>) -> ShardSyncStatus {
+        let (start_time, prev_downloaded_size) = match prev_status {
+            Some(ShardSyncStatus::StateDownload { start_time, prev_downloaded_size, .. }) => {
+                (*start_time, *prev_downloaded_size)
+            }
+            _ => (Utc::now(), self.downloaded_size),
+        };
+        ShardSyncStatus::StateDownload {
+            start_time,
+            prev_update_time: self.last_progress,
+            prev_downloaded_size,
+            downloaded_size: self.downloaded_size,
+            total_size: self.estimated_total_size(),
+            downloaded_parts: self.parts.len() as u64,
+            num_parts: self.num_parts.unwrap_or(0),
+        }
+    }
+}
+
 /// Helper to track state sync.
+/// State of each shard is downloaded in parts, requested in parallel from different peers. Parts
+/// are validated against the state root and saved, so that the sync resumes after a restart.
 pub struct StateSync {
     network_adapter: Box<dyn SyncNetworkAdapter>,
     state_fetch_horizon: BlockIndex,
-
-    syncing_peers: HashMap<ShardId, FullPeerInfo>,
-    prev_state_sync: HashMap<ShardId, DateTime<Utc>>,
+    /// Maximum number of state parts of a shard requested at once.
+    max_pending_parts: usize,
+    /// Time without any state part of a shard received after which the pending parts are
+    /// requested from other peers.
+    progress_timeout: Duration,
+
+    /// Peers state parts are requested from, chosen for each part by their score.
+    peers: Vec<FullPeerInfo>,
+    downloads: HashMap<ShardId, ShardStateDownload>,
 }
 
 impl StateSync {
     pub fn new(
         network_adapter: Box<dyn SyncNetworkAdapter>,
         state_fetch_horizon: BlockIndex,
+        params: &SyncParams,
     ) -> Self {
         StateSync {
             network_adapter,
             state_fetch_horizon,
-            syncing_peers: Default::default(),
-            prev_state_sync: Default::default(),
+            max_pending_parts: params.max_pending_state_parts,
+            progress_timeout: to_chrono_duration(params.state_progress),
+            peers: vec![],
+            downloads: Default::default(),
         }
     }
 
@@ -451,102 +708,104 @@ impl StateSync {
         tracking_shards: Vec<ShardId>,
     ) -> Result<(), near_chain::Error> {
         let header_head = chain.header_head()?;
-        let mut sync_need_restart = HashSet::new();
 
         let (sync_hash, mut new_shard_sync) = match &sync_status {
             SyncStatus::StateSync(sync_hash, shard_sync) => (sync_hash.clone(), shard_sync.clone()),
-            _ => (self.find_sync_hash(chain)?, HashMap::default()),
-        };
-
-        // Check syncing peer connection status.
-        let mut all_done = false;
-        if let SyncStatus::StateSync(_, shard_statuses) = sync_status {
-            all_done = true;
-            for (shard_id, shard_status) in shard_statuses.iter() {
-                all_done = all_done && ShardSyncStatus::StateDone == *shard_status;
-                if let ShardSyncStatus::Error(error) = shard_status {
-                    error!(target: "sync", "State sync: shard {} sync failed: {}", shard_id, error);
-                    sync_need_restart.insert(shard_id);
-                } else if let Some(ref peer) = self.syncing_peers.get(shard_id) {
-                    if let ShardSyncStatus::StateDownload { .. } = shard_status {
-                        if !most_weight_peers.contains(peer) {
-                            sync_need_restart.insert(shard_id);
-                            info!(target: "sync", "State sync: peer connection lost: {:?}, restart shard {}", peer.peer_info.id, shard_id);
-                        }
+            _ => {
+                let sync_hash = match chain.get_state_sync_hash()? {
+                    Some(sync_hash) if chain.get_block_header(&sync_hash).is_ok() => {
+                        info!(target: "sync", "State sync: resuming state sync for {}", sync_hash);
+                        sync_hash
                     }
-                }
+                    _ => self.find_sync_hash(chain)?,
+                };
+                chain.start_state_sync(sync_hash)?;
+                self.downloads.clear();
+                (sync_hash, HashMap::default())
             }
-        }
-
-        if all_done {
-            info!(target: "sync", "State sync: all shards are done");
-
-            // TODO(1046): this code belongs in chain, but waiting to see where chunks will fit.
+        };
+        self.peers = most_weight_peers.clone();
 
-            // Get header we were syncing into.
-            let header = chain.get_block_header(&sync_hash)?;
-            let hash = header.inner.prev_hash;
-            let prev_header = chain.get_block_header(&hash)?;
-            let tip = Tip::from_header(prev_header);
-            // Update related heads now.
-            let mut chain_store_update = chain.mut_store().store_update();
-            chain_store_update.save_body_head(&tip);
-            chain_store_update.save_body_tail(&tip);
-            chain_store_update.commit()?;
+        if let SyncStatus::StateSync(_, shard_statuses) = sync_status {
+            if shard_statuses.values().all(|status| *status == ShardSyncStatus::StateDone) {
+                info!(target: "sync", "State sync: all shards are done");
 
-            // Check if thare are any orphans unlocked by this state sync.
-            chain.check_orphans(hash, |_, _, _| {});
+                // Update heads and process the blocks unlocked by this state sync.
+                chain.finish_state_sync(|_, _, _| {})?;
 
-            *sync_status = SyncStatus::BodySync { current_height: 0, highest_height: 0 };
-            self.prev_state_sync.clear();
-            self.syncing_peers.clear();
-            return Ok(());
+                *sync_status = SyncStatus::BodySync { current_height: 0, highest_height: 0 };
+                self.downloads.clear();
+                return Ok(());
+            }
         }
 
         let now = Utc::now();
+        let progress_timeout = self.progress_timeout;
         let mut update_sync_status = false;
         for shard_id in tracking_shards {
-            if sync_need_restart.contains(&shard_id) || header_head.height == highest_height {
-                let (go, download_timeout) = match self.prev_state_sync.get(&shard_id) {
-                    None => {
-                        self.prev_state_sync.insert(shard_id, now);
-                        (true, false)
+            match new_shard_sync.get(&shard_id) {
+                Some(ShardSyncStatus::StateDone) => continue,
+                Some(ShardSyncStatus::Error(error)) => {
+                    error!(target: "sync", "State sync: shard {} sync failed: {}", shard_id, error);
+                    // Validated parts are kept, the rest is requested again.
+                    if let Some(download) = self.downloads.get_mut(&shard_id) {
+                        download.requested.clear();
                     }
-                    Some(prev) => (false, now - *prev > Duration::minutes(STATE_SYNC_TIMEOUT)),
-                };
-
-                if download_timeout {
-                    error!(target: "sync", "State sync: state download for shard {} timed out in {} minutes", shard_id, STATE_SYNC_TIMEOUT);
                 }
+                Some(_) => {}
+                None if header_head.height != highest_height => continue,
+                None => {}
+            }
+            if !self.downloads.contains_key(&shard_id) {
+                let (mut parts, receipts) = chain.get_state_parts(&sync_hash, shard_id)?;
+                let num_parts = parts.first().map_or(0, |part| part.num_parts);
+                if num_parts > MAX_STATE_PARTS
+                    || parts.iter().any(|part| part.num_parts != num_parts)
+                {
+                    warn!(target: "sync", "State sync: saved parts of shard {} don't match, downloading them again", shard_id);
+                    chain.delete_state_parts(&sync_hash, shard_id, None)?;
+                    parts.clear();
+                }
+                if !parts.is_empty() {
+                    info!(target: "sync", "State sync: resuming shard {} with {} downloaded parts", shard_id, parts.len());
+                }
+                self.downloads.insert(shard_id, ShardStateDownload::new(parts, receipts));
+            }
 
-                if go || download_timeout {
-                    match self.request_state(shard_id, chain, sync_hash, most_weight_peers) {
-                        Some(peer) => {
-                            self.syncing_peers.insert(shard_id, peer);
-                            new_shard_sync.insert(
-                                shard_id,
-                                ShardSyncStatus::StateDownload {
-                                    start_time: now,
-                                    prev_update_time: now,
-                                    prev_downloaded_size: 0,
-                                    downloaded_size: 0,
-                                    total_size: 0,
-                                },
-                            );
-                        }
-                        None => {
-                            new_shard_sync.insert(
-                                shard_id,
-                                ShardSyncStatus::Error(format!(
-                                    "Failed to find peer with state for shard {}",
-                                    shard_id
-                                )),
-                            );
-                        }
-                    }
-                    update_sync_status = true;
+            // Parts requested from disconnected peers are requested from other peers, as well as
+            // all pending parts if none of them was received for a while.
+            let peers = &self.peers;
+            let download = self.downloads.get_mut(&shard_id).unwrap();
+            if !download.requested.is_empty() && now - download.last_progress > progress_timeout {
+                warn!(target: "sync", "State sync: no progress for shard {} in {}s, requesting {} parts again", shard_id, progress_timeout.num_seconds(), download.requested.len());
+                download.requested.clear();
+                download.last_progress = now;
+            }
+            download.requested.retain(|part_id, (peer_id, _)| {
+                let connected = peers.iter().any(|peer| peer.peer_info.id == *peer_id);
+                if !connected {
+                    debug!(target: "sync", "State sync: peer {} with part {} of shard {} disconnected, requesting again", peer_id, part_id, shard_id);
                 }
+                connected
+            });
+
+            let num_downloaded = download.parts.len() as u64;
+            if download.num_parts.map_or(false, |num_parts| num_downloaded == num_parts) {
+                // All parts were downloaded before the restart.
+                self.set_shard_state(&mut new_shard_sync, chain, shard_id, sync_hash);
+            } else if self.request_parts(shard_id, sync_hash) {
+                let status = self.downloads[&shard_id].status(new_shard_sync.get(&shard_id));
+                new_shard_sync.insert(shard_id, status);
+            } else {
+                new_shard_sync.insert(
+                    shard_id,
+                    ShardSyncStatus::Error(format!(
+                        "Failed to find peer with state for shard {}",
+                        shard_id
+                    )),
+                );
             }
+            update_sync_status = true;
         }
         if update_sync_status {
             *sync_status = SyncStatus::StateSync(sync_hash, new_shard_sync);
@@ -554,22 +813,181 @@ impl StateSync {
         Ok(())
     }
 
-    fn request_state(
-        &mut self,
-        shard_id: ShardId,
-        _chain: &Chain,
-        hash: CryptoHash,
-        most_weight_peers: &Vec<FullPeerInfo>,
-    ) -> Option<FullPeerInfo> {
-        if let Some(peer) = most_weight_peer(most_weight_peers) {
+    /// Requests missing parts of the shard state from the peers, keeping at most
+    /// `max_pending_parts` requests in flight. Returns false if there are no peers.
+    fn request_parts(&mut self, shard_id: ShardId, hash: CryptoHash) -> bool {
+        if self.peers.is_empty() {
+            return false;
+        }
+        let now = Utc::now();
+        let download = match self.downloads.get_mut(&shard_id) {
+            Some(download) => download,
+            None => return true,
+        };
+        let num_requests = self.max_pending_parts.saturating_sub(download.requested.len());
+        for part_id in download.missing_parts(num_requests) {
+            let peer_id = match most_weight_peer(&self.peers) {
+                Some(peer) => peer.peer_info.id,
+                None => return false,
+            };
             self.network_adapter.send(NetworkRequests::StateRequest {
                 shard_id,
                 hash,
-                peer_id: peer.peer_info.id,
+                part_id,
+                peer_id,
             });
-            return Some(peer);
+            download.requested.insert(part_id, (peer_id, now));
+        }
+        true
+    }
+
+    /// Parts requested from the banned peer are requested again from other peers on the next
+    /// step of the sync.
+    pub fn peer_banned(&mut self, peer_id: &PeerId) {
+        self.peers.retain(|peer| peer.peer_info.id != *peer_id);
+        for download in self.downloads.values_mut() {
+            download.requested.retain(|_, (requested_peer_id, _)| requested_peer_id != peer_id);
+        }
+    }
+
+    /// Assembles the downloaded parts and sets the state of the shard.
+    fn set_shard_state(
+        &mut self,
+        shard_statuses: &mut HashMap<ShardId, ShardSyncStatus>,
+        chain: &mut Chain,
+        shard_id: ShardId,
+        hash: CryptoHash,
+    ) {
+        let download = match self.downloads.remove(&shard_id) {
+            Some(download) => download,
+            None => return,
+        };
+        let payload = download.parts.into_iter().flat_map(|(_, part)| part.data).collect();
+        match chain.set_shard_state(shard_id, hash, payload, download.receipts) {
+            Ok(()) => {
+                shard_statuses.insert(shard_id, ShardSyncStatus::StateDone);
+            }
+            Err(err) => {
+                // Saved parts are downloaded again, otherwise every retry would fail the same way.
+                if let Err(err) = chain.delete_state_parts(&hash, shard_id, None) {
+                    error!(target: "sync", "State sync: failed to remove parts of shard {}: {}", shard_id, err);
+                }
+                shard_statuses.insert(
+                    shard_id,
+                    ShardSyncStatus::Error(format!(
+                        "Failed to set state for {} @ {}: {}",
+                        shard_id, hash, err
+                    )),
+                );
+            }
+        }
+    }
+
+    /// Validates the state part received from a peer against the state root and saves it, then
+    /// requests more parts or sets the state of the shard once all parts are downloaded.
+    /// Returns false if the part is invalid and the peer should be banned, the part is requested
+    /// again then.
+    pub fn receive_state_part(
+        &mut self,
+        sync_status: &mut SyncStatus,
+        chain: &mut Chain,
+        shard_id: ShardId,
+        hash: CryptoHash,
+        part: StatePart,
+        receipts: Vec<Receipt>,
+    ) -> bool {
+        let (sync_hash, shard_statuses) = match sync_status {
+            SyncStatus::StateSync(sync_hash, shard_statuses) => (sync_hash, shard_statuses),
+            _ => return true,
+        };
+        if hash != *sync_hash {
+            shard_statuses.insert(
+                shard_id,
+                ShardSyncStatus::Error(format!(
+                    "Incorrect hash of the state response, expected: {}, got: {}",
+                    sync_hash, hash
+                )),
+            );
+            return true;
+        }
+        match shard_statuses.get(&shard_id) {
+            Some(ShardSyncStatus::StateDownload { .. }) => {}
+            _ => return true,
+        }
+        let download = match self.downloads.get_mut(&shard_id) {
+            Some(download) => download,
+            None => return true,
+        };
+        if download.parts.contains_key(&part.part_id) {
+            debug!(target: "sync", "State sync: ignoring already downloaded part {} for shard {}", part.part_id, shard_id);
+            return true;
+        }
+        let num_parts = download.num_parts.unwrap_or(part.num_parts);
+        if part.num_parts != num_parts || part.part_id >= num_parts || num_parts > MAX_STATE_PARTS {
+            warn!(target: "sync", "State sync: state part {} of {} for {} @ {} doesn't match {} parts", part.part_id, part.num_parts, shard_id, hash, num_parts);
+            download.requested.remove(&part.part_id);
+            return false;
+        }
+        if let Err(err) = chain.validate_state_part(shard_id, hash, &part) {
+            warn!(target: "sync", "State sync: invalid state part {} for {} @ {}: {}", part.part_id, shard_id, hash, err);
+            download.requested.remove(&part.part_id);
+            return false;
+        }
+        // Honest peers split the state the same way. Key ranges of the neighbouring parts that
+        // don't meet mean one of the peers lied about the range, which one is unknown, so both
+        // parts are downloaded again.
+        // Receipts of the shard come with each part, so the parts with different receipts are
+        // in conflict with all the downloaded parts.
+        let mut conflicting_parts = download.conflicting_parts(&part);
+        if !conflicting_parts.is_empty() {
+            warn!(target: "sync", "State sync: key range of part {} for {} @ {} doesn't match parts {:?}", part.part_id, shard_id, hash, conflicting_parts);
+        }
+        if !download.parts.is_empty() && download.receipts != receipts {
+            warn!(target: "sync", "State sync: receipts of part {} for {} @ {} don't match the downloaded parts", part.part_id, shard_id, hash);
+            conflicting_parts = download.parts.keys().cloned().collect();
+        }
+        if !conflicting_parts.is_empty() {
+            download.requested.remove(&part.part_id);
+            for part_id in conflicting_parts.iter() {
+                if let Some(part) = download.parts.remove(part_id) {
+                    download.downloaded_size -= part.data.len() as u64;
+                }
+            }
+            if let Err(err) =
+                chain.delete_state_parts(&hash, shard_id, Some(&conflicting_parts[..]))
+            {
+                error!(target: "sync", "State sync: failed to remove parts {:?} of shard {}: {}", conflicting_parts, shard_id, err);
+            }
+            if download.parts.is_empty() {
+                download.num_parts = None;
+            }
+            let hash = *sync_hash;
+            self.request_parts(shard_id, hash);
+            return true;
+        }
+        if let Err(err) = chain.save_state_part(&hash, shard_id, &part, &receipts) {
+            error!(target: "sync", "State sync: failed to save part {} for shard {}: {}", part.part_id, shard_id, err);
         }
-        None
+
+        download.requested.remove(&part.part_id);
+        download.num_parts = Some(num_parts);
+        download.downloaded_size += part.data.len() as u64;
+        download.last_progress = Utc::now();
+        if download.parts.is_empty() {
+            download.receipts = receipts;
+        }
+        download.parts.insert(part.part_id, part);
+        if (download.parts.len() as u64) < num_parts {
+            let status = download.status(shard_statuses.get(&shard_id));
+            shard_statuses.insert(shard_id, status);
+            let hash = *sync_hash;
+            self.request_parts(shard_id, hash);
+            return true;
+        }
+
+        let hash = *sync_hash;
+        self.set_shard_state(shard_statuses, chain, shard_id, hash);
+        true
     }
 }
 
@@ -578,11 +996,13 @@ mod test {
     use super::*;
     use near_chain::test_utils::setup;
     use near_chain::Provenance;
-    use near_network::types::PeerChainInfo;
+    use near_network::types::{PeerChainInfo, PROTOCOL_VERSION};
     use near_network::PeerInfo;
     use near_primitives::block::Block;
     use std::sync::{Arc, RwLock};
 
+    use crate::types::ClientConfig;
+
     #[derive(Default)]
     struct MockNetworkAdapter {
         pub requests: Arc<RwLock<Vec<NetworkRequests>>>,
@@ -613,12 +1033,39 @@ mod test {
         );
     }
 
+    /// With the default block production delays of 1 and 6 seconds, the derived timeouts are the
+    /// same as the constants they replaced, faster chains don't refuse blocks because of the clock
+    /// skew sooner.
+    #[test]
+    fn test_sync_params_defaults() {
+        use std::time::Duration;
+
+        let mut config = ClientConfig::test(false);
+        config.min_block_production_delay = Duration::from_secs(1);
+        config.max_block_production_delay = Duration::from_secs(6);
+        let params = config.sync_params();
+        assert_eq!(params.header_progress, Duration::from_secs(2));
+        assert_eq!(params.header_request, Duration::from_secs(10));
+        assert_eq!(params.header_stall_ban, Duration::from_secs(120));
+        assert_eq!(params.block_request, Duration::from_secs(6));
+        assert_eq!(params.block_some_received, Duration::from_secs(1));
+        assert_eq!(config.max_block_future_time(), Duration::from_secs(120));
+
+        config.min_block_production_delay = Duration::from_millis(100);
+        config.max_block_production_delay = Duration::from_millis(500);
+        assert_eq!(config.sync_params().block_request, Duration::from_millis(500));
+        assert_eq!(config.max_block_future_time(), Duration::from_secs(120));
+        config.sync.block_request_timeout = Some(Duration::from_secs(3));
+        assert_eq!(config.sync_params().block_request, Duration::from_secs(3));
+    }
+
     /// Starts two chains that fork of genesis and checks that they can sync heaaders to the longest.
     #[test]
     fn test_sync_headers_fork() {
         let requests = Arc::new(RwLock::new(vec![]));
         let mock_adapter = Box::new(MockNetworkAdapter { requests: requests.clone() });
-        let mut header_sync = HeaderSync::new(mock_adapter);
+        let mut header_sync =
+            HeaderSync::new(mock_adapter, &ClientConfig::test(false).sync_params());
         let (mut chain, _, signer) = setup();
         for _ in 0..5 {
             let prev = chain.head_header().unwrap();
@@ -639,6 +1086,10 @@ mod test {
                 height: chain2.head().unwrap().height,
                 total_weight: chain2.head().unwrap().total_weight,
             },
+            protocol_version: PROTOCOL_VERSION,
+            score: 0,
+            latency: None,
+            last_seen: Utc::now(),
         };
         let head = chain.head().unwrap();
         assert!(header_sync
@@ -653,8 +1104,134 @@ mod test {
                     .iter()
                     .map(|i| chain.get_block_by_height(*i).unwrap().hash())
                     .collect(),
+                skip: 0,
                 peer_id: peer1.peer_info.id
             }
         );
     }
+
+    /// Headers are requested again from another peer right after the syncing peer is banned.
+    #[test]
+    fn test_request_headers_after_ban() {
+        let requests = Arc::new(RwLock::new(vec![]));
+        let mock_adapter = Box::new(MockNetworkAdapter { requests: requests.clone() });
+        let mut header_sync =
+            HeaderSync::new(mock_adapter, &ClientConfig::test(false).sync_params());
+        let (mut chain, _, _) = setup();
+        let peer = |height| FullPeerInfo {
+            peer_info: PeerInfo::random(),
+            chain_info: PeerChainInfo {
+                genesis: chain.genesis().hash(),
+                height,
+                total_weight: height.into(),
+            },
+            protocol_version: PROTOCOL_VERSION,
+            score: 0,
+            latency: None,
+            last_seen: Utc::now(),
+        };
+        let (peer1, peer2) = (peer(10), peer(10));
+        let mut sync_status = SyncStatus::NoSync;
+        header_sync.run(&mut sync_status, &mut chain, 10, &vec![peer1.clone()]).unwrap();
+        assert_eq!(requests.read().unwrap().len(), 1);
+        // Request is not due yet.
+        header_sync.run(&mut sync_status, &mut chain, 10, &vec![peer2.clone()]).unwrap();
+        assert_eq!(requests.read().unwrap().len(), 1);
+
+        header_sync.peer_banned(&peer1.peer_info.id);
+        header_sync.run(&mut sync_status, &mut chain, 10, &vec![peer2.clone()]).unwrap();
+        match requests.read().unwrap().last() {
+            Some(NetworkRequests::BlockHeadersRequest { skip: 0, peer_id, .. }) => {
+                assert_eq!(*peer_id, peer2.peer_info.id)
+            }
+            request => panic!("Unexpected request {:?}", request),
+        }
+    }
+
+    /// Headers received from different peers out of order are added once the preceding ones arrive.
+    #[test]
+    fn test_process_headers_out_of_order() {
+        let requests = Arc::new(RwLock::new(vec![]));
+        let mock_adapter = Box::new(MockNetworkAdapter { requests: requests.clone() });
+        let mut header_sync =
+            HeaderSync::new(mock_adapter, &ClientConfig::test(false).sync_params());
+        let (mut chain, _, _) = setup();
+        let (mut chain2, _, signer2) = setup();
+        for _ in 0..10 {
+            let prev = chain2.head_header().unwrap();
+            let block = Block::empty(&prev, signer2.clone());
+            chain2.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+        }
+        let headers: Vec<BlockHeader> =
+            (1..=10).map(|h| chain2.get_header_by_height(h).unwrap().clone()).collect();
+        let (peer1, peer2) = (PeerInfo::random().id, PeerInfo::random().id);
+        header_sync.process_headers(&mut chain, headers[5..].to_vec(), &peer2).unwrap();
+        assert_eq!(chain.header_head().unwrap().height, 0);
+        // Overlapping ranges are deduplicated.
+        header_sync.process_headers(&mut chain, headers[..6].to_vec(), &peer1).unwrap();
+        assert_eq!(chain.header_head().unwrap().height, 10);
+        header_sync.process_headers(&mut chain, headers, &peer1).unwrap();
+        assert_eq!(chain.header_head().unwrap().height, 10);
+        assert!(requests.read().unwrap().is_empty());
+    }
+
+    /// Total size and time left are extrapolated from the parts downloaded since the start.
+    #[test]
+    fn test_state_download_progress() {
+        let part = |part_id| StatePart {
+            part_id,
+            num_parts: 10,
+            start_key: vec![],
+            end_key: None,
+            data: vec![0; 100],
+            proof: vec![],
+        };
+        // Part downloaded before the restart doesn't count into the rate.
+        let mut download = ShardStateDownload::new(vec![part(0)], vec![]);
+        let status = download.status(None);
+        download.parts.insert(1, StatePart { data: vec![0; 300], ..part(1) });
+        download.downloaded_size += 300;
+        let mut status = download.status(Some(&status));
+        if let ShardSyncStatus::StateDownload { start_time, .. } = &mut status {
+            *start_time = *start_time - Duration::seconds(3);
+        }
+        match &status {
+            ShardSyncStatus::StateDownload {
+                prev_downloaded_size,
+                downloaded_size,
+                total_size,
+                ..
+            } => {
+                assert_eq!(*prev_downloaded_size, 100);
+                assert_eq!(*downloaded_size, 400);
+                assert_eq!(*total_size, 2000);
+            }
+            _ => panic!("Unexpected status {:?}", status),
+        }
+        let now = Utc::now();
+        let rate = status.download_rate(now);
+        assert!(rate > 90 && rate <= 100);
+        let eta = status.download_eta(now).unwrap().as_secs();
+        assert!(eta >= 16 && eta <= 17);
+    }
+
+    /// Missing parts are found only up to the number of parts to request.
+    #[test]
+    fn test_missing_parts_limit() {
+        let part = StatePart {
+            part_id: 0,
+            num_parts: MAX_STATE_PARTS,
+            start_key: vec![],
+            end_key: Some(vec![1]),
+            data: vec![],
+            proof: vec![],
+        };
+        let mut download = ShardStateDownload::new(vec![], vec![]);
+        assert_eq!(download.missing_parts(0), Vec::<u64>::new());
+        assert_eq!(download.missing_parts(3), vec![0]);
+
+        download = ShardStateDownload::new(vec![part], vec![]);
+        download.requested.insert(1, (PeerInfo::random().id, Utc::now()));
+        assert_eq!(download.missing_parts(3), vec![2, 3, 4]);
+    }
 }
@@ -1,14 +1,16 @@
+use std::path::PathBuf;
 use std::sync::{Arc, RwLock};
 
 use actix::actors::mocker::Mocker;
 use actix::{Actor, Addr, AsyncContext, Context, Recipient};
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 
 use near_chain::test_utils::KeyValueRuntime;
 use near_crypto::{InMemorySigner, KeyType, PublicKey};
 use near_network::types::NetworkInfo;
 use near_network::{NetworkRequests, NetworkResponses, PeerManagerActor};
 use near_store::test_utils::create_test_store;
+use near_store::Store;
 use near_telemetry::TelemetryActor;
 
 use crate::{BlockProducer, ClientActor, ClientConfig, ViewClientActor};
@@ -17,9 +19,10 @@ use near_primitives::types::BlockIndex;
 pub type NetworkMock = Mocker<PeerManagerActor>;
 
 /// Sets up ClientActor and ViewClientActor viewing the same store/runtime.
+/// Client produces blocks for all the given accounts.
 pub fn setup(
     validators: Vec<&str>,
-    account_id: &str,
+    account_ids: Vec<&str>,
     skip_sync_wait: bool,
     recipient: Recipient<NetworkRequests>,
     tx_validity_period: BlockIndex,
@@ -28,27 +31,26 @@ pub fn setup(
     let runtime = Arc::new(KeyValueRuntime::new_with_validators(
         store.clone(),
         validators.into_iter().map(Into::into).collect(),
+        tx_validity_period,
     ));
-    let signer = Arc::new(InMemorySigner::from_seed(account_id, KeyType::ED25519, account_id));
+    let block_producers = account_ids
+        .into_iter()
+        .map(|account_id| {
+            Arc::new(InMemorySigner::from_seed(account_id, KeyType::ED25519, account_id)).into()
+        })
+        .collect();
     let genesis_time = Utc::now();
     let telemetry = TelemetryActor::default().start();
-    let view_client = ViewClientActor::new(
-        store.clone(),
-        genesis_time.clone(),
-        runtime.clone(),
-        tx_validity_period,
-    )
-    .unwrap();
-    let mut config = ClientConfig::test(skip_sync_wait);
-    config.transaction_validity_period = tx_validity_period;
+    let view_client =
+        ViewClientActor::new(store.clone(), genesis_time.clone(), runtime.clone()).unwrap();
     let client = ClientActor::new(
-        config,
+        ClientConfig::test(skip_sync_wait),
         store,
         genesis_time,
         runtime,
         PublicKey::empty(KeyType::ED25519).into(),
         recipient,
-        Some(signer.into()),
+        block_producers,
         telemetry,
     )
     .unwrap();
@@ -75,6 +77,29 @@ pub fn setup_mock_with_validity_period(
     validators: Vec<&'static str>,
     account_id: &'static str,
     skip_sync_wait: bool,
+    network_mock: Box<
+        dyn FnMut(
+            &NetworkRequests,
+            &mut Context<NetworkMock>,
+            Addr<ClientActor>,
+        ) -> NetworkResponses,
+    >,
+    validity_period: BlockIndex,
+) -> (Addr<ClientActor>, Addr<ViewClientActor>) {
+    setup_mock_with_block_producers(
+        validators,
+        vec![account_id],
+        skip_sync_wait,
+        network_mock,
+        validity_period,
+    )
+}
+
+/// Sets up ClientActor with mock PeerManager that produces blocks for several accounts.
+pub fn setup_mock_with_block_producers(
+    validators: Vec<&'static str>,
+    account_ids: Vec<&'static str>,
+    skip_sync_wait: bool,
     mut network_mock: Box<
         dyn FnMut(
             &NetworkRequests,
@@ -95,13 +120,63 @@ pub fn setup_mock_with_validity_period(
         }))
         .start();
         let (client, view_client) =
-            setup(validators, account_id, skip_sync_wait, pm.recipient(), validity_period);
+            setup(validators, account_ids, skip_sync_wait, pm.recipient(), validity_period);
         *view_client_addr1.write().unwrap() = Some(view_client.start());
         client
     });
     (client_addr, view_client_addr.clone().read().unwrap().clone().unwrap())
 }
 
+/// Sets up ClientActor with mock PeerManager over given store, so that a client can be started
+/// again over the data of the previous one. Client produces blocks with the keys from given key
+/// files.
+pub fn setup_mock_with_store(
+    store: Arc<Store>,
+    genesis_time: DateTime<Utc>,
+    validators: Vec<&'static str>,
+    key_files: Vec<PathBuf>,
+    mut network_mock: Box<
+        dyn FnMut(
+            &NetworkRequests,
+            &mut Context<NetworkMock>,
+            Addr<ClientActor>,
+        ) -> NetworkResponses,
+    >,
+) -> Addr<ClientActor> {
+    ClientActor::create(move |ctx| {
+        let client_addr = ctx.address();
+        let pm = NetworkMock::mock(Box::new(move |msg, ctx| {
+            let msg = msg.downcast_ref::<NetworkRequests>().unwrap();
+            let resp = network_mock(msg, ctx, client_addr.clone());
+            Box::new(Some(resp))
+        }))
+        .start();
+        let runtime = Arc::new(KeyValueRuntime::new_with_validators(
+            store.clone(),
+            validators.into_iter().map(Into::into).collect(),
+            100,
+        ));
+        let mut config = ClientConfig::test(true);
+        let mut block_producers = vec![];
+        for key_file in key_files {
+            let signer = InMemorySigner::from_file(&key_file);
+            config.validator_key_files.insert(signer.account_id.clone(), key_file);
+            block_producers.push(BlockProducer::from(signer));
+        }
+        ClientActor::new(
+            config,
+            store,
+            genesis_time,
+            runtime,
+            PublicKey::empty(KeyType::ED25519).into(),
+            pm.recipient(),
+            block_producers,
+            TelemetryActor::default().start(),
+        )
+        .unwrap()
+    })
+}
+
 /// Sets up ClientActor and ViewClientActor without network.
 pub fn setup_no_network(
     validators: Vec<&'static str>,
@@ -126,10 +201,15 @@ pub fn setup_no_network_with_validity_period(
                 num_active_peers: 0,
                 peer_max_count: 0,
                 most_weight_peers: vec![],
+                active_peers: vec![],
                 received_bytes_per_sec: 0,
                 sent_bytes_per_sec: 0,
+                dropped_messages: Default::default(),
+                external_address: None,
                 routes: None,
             }),
+            NetworkRequests::FetchBans => NetworkResponses::Bans(vec![]),
+            NetworkRequests::Unban { .. } => NetworkResponses::Unbanned(false),
             _ => NetworkResponses::NoResponse,
         }),
         validity_period,
@@ -1,15 +1,23 @@
 use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
 use actix::Message;
 use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
 
 use near_crypto::{InMemorySigner, Signer};
+use near_network::types::{CorrelationId, PeerId};
+use near_pool::{BlockBudget, PoolConfig};
 use near_primitives::hash::CryptoHash;
-use near_primitives::types::{AccountId, BlockIndex, ShardId, Version};
+use near_primitives::types::{AccountId, BlockIndex, Gas, ShardId, Version};
+use near_primitives::transaction::SignedTransaction;
 use near_primitives::views::{
-    BlockView, FinalTransactionResult, QueryResponse, TransactionResultView,
+    BlockApprovalsView, BlockHeaderView, BlockProfileView, BlockProofView, BlockView,
+    FinalTransactionResult, NetworkInfoView, PeerBanView, ProtocolConfigView, QueryResponse,
+    ReorgView, ShardSyncStatusView, SignedTransactionView, SimulatedTransactionResult,
+    SyncStatusView, TransactionResultView, UnstakeInfoView,
 };
 pub use near_primitives::views::{StatusResponse, StatusSyncInfo};
 
@@ -72,8 +80,17 @@ pub struct ClientConfig {
     pub min_block_production_delay: Duration,
     /// Maximum duration before producing block or skipping height.
     pub max_block_production_delay: Duration,
+    /// Produce block as soon as the pool has transactions after the minimum delay, otherwise wait
+    /// for them up to the maximum delay.
+    pub adaptive_block_production: bool,
     /// Expected block weight (num of tx, gas, etc).
     pub block_expected_weight: u32,
+    /// Maximum gas prepaid by the transactions of a produced block.
+    pub block_max_gas: Gas,
+    /// Maximum total size of the transactions of a produced block.
+    pub block_max_size: u64,
+    /// Maximum time spent on taking transactions from the pool for a produced block.
+    pub block_assembly_time: Duration,
     /// Skip waiting for sync (for testing or single node testnet).
     pub skip_sync_wait: bool,
     /// How often to check that we are not out of sync.
@@ -92,6 +109,9 @@ pub struct ClientConfig {
     pub log_summary_period: Duration,
     /// Produce empty blocks, use `false` for testing.
     pub produce_empty_blocks: bool,
+    /// When empty blocks are not produced, still produce one if there was no block for this
+    /// many heights, to keep the chain and epochs progressing. `None` waits for transactions.
+    pub max_idle_blocks: Option<BlockIndex>,
     /// Epoch length.
     pub epoch_length: BlockIndex,
     /// Maximum blocks ahead of us before becoming validators to announce account.
@@ -102,8 +122,165 @@ pub struct ClientConfig {
     pub state_fetch_horizon: BlockIndex,
     /// Behind this horizon header fetch kicks in.
     pub block_header_fetch_horizon: BlockIndex,
-    /// Number of blocks for which a transaction is valid
-    pub transaction_validity_period: BlockIndex,
+    /// Shards to track in addition to the ones this node validates, all the shards if empty.
+    /// Blocks are not applied and state is not downloaded for the shards that aren't tracked.
+    pub tracked_shards: Vec<ShardId>,
+    /// Maximum number of blocks a reorg can revert, `None` means unlimited.
+    pub max_reorg_depth: Option<BlockIndex>,
+    /// Number of epochs of blocks and state to keep behind the final head, `None` keeps all.
+    pub gc_num_epochs_to_keep: Option<u64>,
+    /// Limits of the transaction pool.
+    pub tx_pool: PoolConfig,
+    /// Concurrency limits and timeouts of the sync.
+    pub sync: SyncConfig,
+    /// Number of heights after which transactions submitted to this node and not yet included
+    /// are broadcast again, the delay doubles after each broadcast.
+    pub tx_rebroadcast_delay: BlockIndex,
+    /// Maximum number of blocks received during body sync that are processed at once, with their
+    /// store updates written to the disk together.
+    pub catchup_batch_size: usize,
+    /// Light client mode: only headers are synced and validated, blocks are neither downloaded
+    /// nor executed and transactions are forwarded to the peers.
+    pub light_client: bool,
+    /// Directory with exported blocks to process before syncing with the network.
+    pub archive_dir: Option<PathBuf>,
+    /// Key files of the validator accounts. Swapped validator keys are written to them, previous
+    /// keys are kept next to them with `.old` suffix.
+    pub validator_key_files: HashMap<AccountId, PathBuf>,
+}
+
+/// Concurrency limits and timeouts of the sync, so that it can be tuned for slow or very fast
+/// links. Timeouts that are not set are derived from the block production delays.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct SyncConfig {
+    /// Maximum number of peers asked for consecutive ranges of headers at once.
+    pub max_header_sync_peers: usize,
+    /// Maximum number of blocks requested in one step of the block sync.
+    pub max_block_requests: usize,
+    /// Maximum number of blocks requested from a single peer in one step of the block sync.
+    pub max_peer_block_requests: usize,
+    /// Maximum number of state parts of a shard requested at once.
+    pub max_pending_state_parts: usize,
+    /// Time to wait for more headers while they keep coming.
+    pub header_progress_timeout: Option<Duration>,
+    /// Time to wait for the requested headers before requesting again.
+    pub header_request_timeout: Option<Duration>,
+    /// Time of stalled header sync after which syncing peer is reported for claiming fake height.
+    pub header_stall_ban_timeout: Option<Duration>,
+    /// Time to wait for the requested blocks before requesting again.
+    pub block_request_timeout: Option<Duration>,
+    /// Time to wait for the next block after some of the requested blocks were received.
+    pub block_some_received_timeout: Option<Duration>,
+    /// Time without any state part of a shard received after which the pending parts are
+    /// requested from other peers.
+    pub state_progress_timeout: Duration,
+}
+
+impl Default for SyncConfig {
+    fn default() -> Self {
+        SyncConfig {
+            max_header_sync_peers: 4,
+            max_block_requests: 100,
+            max_peer_block_requests: 10,
+            max_pending_state_parts: 8,
+            header_progress_timeout: None,
+            header_request_timeout: None,
+            header_stall_ban_timeout: None,
+            block_request_timeout: None,
+            block_some_received_timeout: None,
+            state_progress_timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Blocks at most this far in the future are always accepted, as in bitcoin.
+pub const MIN_BLOCK_FUTURE_TIME: Duration = Duration::from_secs(120);
+
+/// Limits and timeouts of the sync steps, with the defaults of the unset timeouts resolved.
+#[derive(Clone, Debug)]
+pub struct SyncParams {
+    /// Maximum number of peers asked for consecutive ranges of headers at once.
+    pub max_header_sync_peers: usize,
+    /// Maximum number of blocks requested in one step of the block sync.
+    pub max_block_requests: usize,
+    /// Maximum number of blocks requested from a single peer in one step of the block sync.
+    pub max_peer_block_requests: usize,
+    /// Maximum number of state parts of a shard requested at once.
+    pub max_pending_state_parts: usize,
+    /// Time to wait for more headers while they keep coming.
+    pub header_progress: Duration,
+    /// Time to wait for the requested headers before requesting again.
+    pub header_request: Duration,
+    /// Time of stalled header sync after which syncing peer is banned for claiming fake height.
+    pub header_stall_ban: Duration,
+    /// Time to wait for the requested blocks before requesting again.
+    pub block_request: Duration,
+    /// Time to wait for the next block after some of the requested blocks were received.
+    pub block_some_received: Duration,
+    /// Time without any state part of a shard received after which the pending parts are
+    /// requested from other peers.
+    pub state_progress: Duration,
+}
+
+impl ClientConfig {
+    /// Sync parameters, timeouts that are not configured are derived from the expected block
+    /// production time.
+    pub fn sync_params(&self) -> SyncParams {
+        let sync = &self.sync;
+        SyncParams {
+            max_header_sync_peers: sync.max_header_sync_peers,
+            max_block_requests: sync.max_block_requests,
+            max_peer_block_requests: sync.max_peer_block_requests,
+            max_pending_state_parts: sync.max_pending_state_parts,
+            header_progress: sync
+                .header_progress_timeout
+                .unwrap_or(self.min_block_production_delay * 2),
+            header_request: sync
+                .header_request_timeout
+                .unwrap_or(self.min_block_production_delay * 10),
+            header_stall_ban: sync
+                .header_stall_ban_timeout
+                .unwrap_or(self.max_block_production_delay * 20),
+            block_request: sync.block_request_timeout.unwrap_or(self.max_block_production_delay),
+            block_some_received: sync
+                .block_some_received_timeout
+                .unwrap_or(self.min_block_production_delay),
+            state_progress: sync.state_progress_timeout,
+        }
+    }
+
+    /// Budget of the produced block, the transactions that don't fit are left for the next one.
+    pub fn block_budget(&self) -> BlockBudget {
+        BlockBudget {
+            max_transactions: self.block_expected_weight,
+            max_gas: self.block_max_gas,
+            max_bytes: self.block_max_size,
+            max_time: self.block_assembly_time,
+        }
+    }
+
+    /// Whether block without transactions and receipts is skipped, given the number of heights
+    /// since the last block.
+    pub fn skip_empty_block(&self, idle_heights: BlockIndex) -> bool {
+        !self.produce_empty_blocks && self.max_idle_blocks.map_or(true, |max| idle_heights < max)
+    }
+
+    /// How long since the previous block the adaptive block production waits for transactions.
+    /// Other validators skip the height once the maximum delay passes since the same block, so
+    /// the wait ends the minimum delay earlier, leaving time for the block to reach them.
+    pub fn max_transactions_wait(&self) -> Duration {
+        self.max_block_production_delay
+            .checked_sub(self.min_block_production_delay)
+            .unwrap_or_default()
+    }
+
+    /// How far in the future block timestamp can be, derived from the expected block production
+    /// time. It's never below `MIN_BLOCK_FUTURE_TIME`, so that fast chains tolerate the same clock
+    /// skew as before.
+    pub fn max_block_future_time(&self) -> Duration {
+        std::cmp::max(self.max_block_production_delay * 20, MIN_BLOCK_FUTURE_TIME)
+    }
 }
 
 impl ClientConfig {
@@ -114,7 +291,11 @@ impl ClientConfig {
             rpc_addr: "0.0.0.0:3030".to_string(),
             min_block_production_delay: Duration::from_millis(100),
             max_block_production_delay: Duration::from_millis(300),
+            adaptive_block_production: false,
             block_expected_weight: 1000,
+            block_max_gas: 10u64.pow(15),
+            block_max_size: 4 * 1024 * 1024,
+            block_assembly_time: Duration::from_millis(50),
             skip_sync_wait,
             sync_check_period: Duration::from_millis(100),
             sync_step_period: Duration::from_millis(10),
@@ -124,12 +305,22 @@ impl ClientConfig {
             fetch_info_period: Duration::from_millis(100),
             log_summary_period: Duration::from_secs(10),
             produce_empty_blocks: true,
+            max_idle_blocks: None,
             epoch_length: 10,
             announce_account_horizon: 5,
             block_fetch_horizon: 50,
             state_fetch_horizon: 5,
             block_header_fetch_horizon: 50,
-            transaction_validity_period: 100,
+            tracked_shards: vec![],
+            max_reorg_depth: None,
+            gc_num_epochs_to_keep: None,
+            tx_pool: PoolConfig::default(),
+            sync: SyncConfig::default(),
+            tx_rebroadcast_delay: 5,
+            catchup_batch_size: 50,
+            light_client: false,
+            archive_dir: None,
+            validator_key_files: HashMap::new(),
         }
     }
 }
@@ -159,10 +350,15 @@ pub enum ShardSyncStatus {
     /// Downloading state for fast sync.
     StateDownload {
         start_time: DateTime<Utc>,
+        /// Time the last part was received at, or the download started if none was yet.
         prev_update_time: DateTime<Utc>,
+        /// Size downloaded before the start time, e.g. before the node was restarted.
         prev_downloaded_size: u64,
         downloaded_size: u64,
+        /// Estimated size of the state, extrapolated from the size of the downloaded parts.
         total_size: u64,
+        downloaded_parts: u64,
+        num_parts: u64,
     },
     /// Validating the full state.
     StateValidation,
@@ -172,6 +368,41 @@ pub enum ShardSyncStatus {
     Error(String),
 }
 
+impl ShardSyncStatus {
+    /// Average rate of the state download since its start, in bytes per second.
+    pub fn download_rate(&self, now: DateTime<Utc>) -> u64 {
+        match self {
+            ShardSyncStatus::StateDownload {
+                start_time,
+                prev_downloaded_size,
+                downloaded_size,
+                ..
+            } => {
+                let elapsed_ms = (now - *start_time).num_milliseconds();
+                if elapsed_ms <= 0 {
+                    return 0;
+                }
+                downloaded_size.saturating_sub(*prev_downloaded_size) * 1000 / elapsed_ms as u64
+            }
+            _ => 0,
+        }
+    }
+
+    /// Estimated time left until the state is downloaded at the current rate, if known.
+    pub fn download_eta(&self, now: DateTime<Utc>) -> Option<Duration> {
+        match self {
+            ShardSyncStatus::StateDownload { downloaded_size, total_size, .. } => {
+                let rate = self.download_rate(now);
+                if rate == 0 || *total_size == 0 {
+                    return None;
+                }
+                Some(Duration::from_secs(total_size.saturating_sub(*downloaded_size) / rate))
+            }
+            _ => None,
+        }
+    }
+}
+
 /// Various status sync can be in, whether it's fast sync or archival.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum SyncStatus {
@@ -196,6 +427,62 @@ impl SyncStatus {
     }
 }
 
+impl From<&ShardSyncStatus> for ShardSyncStatusView {
+    fn from(status: &ShardSyncStatus) -> Self {
+        match status {
+            ShardSyncStatus::StateDownload {
+                downloaded_size,
+                total_size,
+                downloaded_parts,
+                num_parts,
+                ..
+            } => ShardSyncStatusView::StateDownload {
+                downloaded_parts: *downloaded_parts,
+                num_parts: *num_parts,
+                downloaded_size: *downloaded_size,
+                total_size: *total_size,
+                percent: if *total_size == 0 {
+                    0.0
+                } else {
+                    *downloaded_size as f64 * 100.0 / *total_size as f64
+                },
+                bytes_per_sec: status.download_rate(Utc::now()),
+                eta_sec: status.download_eta(Utc::now()).map(|eta| eta.as_secs()),
+            },
+            ShardSyncStatus::StateValidation => ShardSyncStatusView::StateValidation,
+            ShardSyncStatus::StateDone => ShardSyncStatusView::StateDone,
+            ShardSyncStatus::Error(error) => ShardSyncStatusView::Error(error.clone()),
+        }
+    }
+}
+
+impl From<&SyncStatus> for SyncStatusView {
+    fn from(status: &SyncStatus) -> Self {
+        match status {
+            SyncStatus::AwaitingPeers => SyncStatusView::AwaitingPeers,
+            SyncStatus::NoSync => SyncStatusView::NoSync,
+            SyncStatus::HeaderSync { current_height, highest_height } => {
+                SyncStatusView::HeaderSync {
+                    current_height: *current_height,
+                    highest_height: *highest_height,
+                }
+            }
+            SyncStatus::StateSync(sync_hash, shard_statuses) => SyncStatusView::StateSync {
+                sync_hash: (*sync_hash).into(),
+                shards: shard_statuses
+                    .iter()
+                    .map(|(shard_id, status)| (*shard_id, status.into()))
+                    .collect(),
+            },
+            SyncStatus::StateSyncDone => SyncStatusView::StateSyncDone,
+            SyncStatus::BodySync { current_height, highest_height } => SyncStatusView::BodySync {
+                current_height: *current_height,
+                highest_height: *highest_height,
+            },
+        }
+    }
+}
+
 /// Actor message requesting block by id or hash.
 pub enum GetBlock {
     Best,
@@ -223,6 +510,72 @@ impl Message for Status {
     type Result = Result<StatusResponse, String>;
 }
 
+/// Processing time breakdown of the last blocks accepted by the client, oldest first.
+pub struct GetChainProfile {}
+
+impl Message for GetChainProfile {
+    type Result = Result<Vec<BlockProfileView>, String>;
+}
+
+/// Last switches of the head to another fork seen by the client, oldest first.
+pub struct GetRecentReorgs {}
+
+impl Message for GetRecentReorgs {
+    type Result = Result<Vec<ReorgView>, String>;
+}
+
+/// Current stage of syncing and its progress.
+pub struct GetSyncStatus {}
+
+impl Message for GetSyncStatus {
+    type Result = Result<SyncStatusView, String>;
+}
+
+/// Transactions in the pool of the node, grouped by the access key and ordered by nonce.
+pub struct GetTxPool {}
+
+impl Message for GetTxPool {
+    type Result = Result<Vec<SignedTransactionView>, String>;
+}
+
+/// Banned peers of the node with the reason and the expiration of each ban.
+pub struct GetBans {}
+
+impl Message for GetBans {
+    type Result = Result<Vec<PeerBanView>, String>;
+}
+
+/// Active peers of the node with their latency and the network traffic.
+pub struct GetNetworkInfo {}
+
+impl Message for GetNetworkInfo {
+    type Result = Result<NetworkInfoView, String>;
+}
+
+/// Lifts the ban of the peer, e.g. of a partner node banned by mistake. Returns whether the peer
+/// was banned.
+pub struct UnbanPeer {
+    pub peer_id: PeerId,
+}
+
+impl Message for UnbanPeer {
+    type Result = Result<bool, String>;
+}
+
+/// Request caused by the RPC call with given correlation id, the actor handling it logs the id.
+pub struct Correlated<M>(pub CorrelationId, pub M);
+
+impl<M: Message> Message for Correlated<M> {
+    type Result = M::Result;
+}
+
+/// Gracefully stops the node: block production, syncing and connections to peers.
+pub struct Shutdown {}
+
+impl Message for Shutdown {
+    type Result = ();
+}
+
 /// Status of given transaction including all the subsequent receipts.
 pub struct TxStatus {
     pub tx_hash: CryptoHash,
@@ -240,3 +593,106 @@ pub struct TxDetails {
 impl Message for TxDetails {
     type Result = Result<TransactionResultView, String>;
 }
+
+/// Re-reads config file and applies values that can be changed without restart.
+pub struct ReloadConfig {}
+
+impl Message for ReloadConfig {
+    type Result = Result<(), String>;
+}
+
+/// Config values of the running client that can be changed without restart.
+pub struct UpdateConfig {
+    pub min_num_peers: usize,
+    pub tracked_shards: Vec<ShardId>,
+    pub peer_max_count: u32,
+}
+
+impl Message for UpdateConfig {
+    type Result = ();
+}
+
+/// Writes backup of the whole database to the directory, which must not exist, returns size of
+/// the backup in bytes. The backup is a checkpoint of the database, so it's consistent while
+/// blocks keep being processed.
+pub struct BackupDatabase {
+    pub path: PathBuf,
+}
+
+impl Message for BackupDatabase {
+    type Result = Result<u64, String>;
+}
+
+/// Replaces the signer of the validator account with the key from given file once the new key
+/// becomes the validator key of the epoch, so that the key can be rotated without restart. The key
+/// is written to the validator key file of the account, so that it's used after restart too.
+pub struct SwapValidatorKey {
+    pub key_file: PathBuf,
+}
+
+impl Message for SwapValidatorKey {
+    type Result = Result<(), String>;
+}
+
+/// Executes transaction on top of the current head without committing or broadcasting it.
+pub struct SimulateTx {
+    pub transaction: SignedTransaction,
+}
+
+impl Message for SimulateTx {
+    type Result = Result<SimulatedTransactionResult, String>;
+}
+
+/// Protocol parameters in effect at the given block.
+pub struct GetProtocolConfig {
+    pub block: GetBlock,
+}
+
+impl Message for GetProtocolConfig {
+    type Result = Result<ProtocolConfigView, String>;
+}
+
+/// Signatures of the block producers that approved the given block.
+pub struct GetBlockApprovals {
+    pub block: GetBlock,
+}
+
+impl Message for GetBlockApprovals {
+    type Result = Result<BlockApprovalsView, String>;
+}
+
+/// Header of the block, `Best` is the head of the header chain, which light clients follow.
+pub struct GetBlockHeader {
+    pub block: GetBlock,
+}
+
+impl Message for GetBlockHeader {
+    type Result = Result<BlockHeaderView, String>;
+}
+
+/// Merkle proof that the block is an ancestor of the head block.
+pub struct GetBlockProof {
+    pub block_hash: CryptoHash,
+    pub head_hash: CryptoHash,
+}
+
+impl Message for GetBlockProof {
+    type Result = Result<BlockProofView, String>;
+}
+
+/// When pending stake changes of the account take effect and its stake is unlocked.
+pub struct GetUnstakeInfo {
+    pub account_id: AccountId,
+}
+
+impl Message for GetUnstakeInfo {
+    type Result = Result<UnstakeInfoView, String>;
+}
+
+/// Counters and latency histograms of the store operations and counters of the chain in the
+/// Prometheus text format.
+pub struct GetStoreMetrics {}
+
+impl Message for GetStoreMetrics {
+    type Result = Result<String, String>;
+}
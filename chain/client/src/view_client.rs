@@ -3,26 +3,34 @@
 
 use std::sync::Arc;
 
-use actix::{Actor, Context, Handler};
+use actix::dev::MessageResponse;
+use actix::{Actor, Context, Handler, Message};
 use chrono::{DateTime, Utc};
+use log::debug;
 
-use near_chain::{Chain, ErrorKind, RuntimeAdapter};
+use near_chain::{Chain, ChainStoreAccess, ErrorKind, RuntimeAdapter};
+use near_network::types::CorrelationId;
 use near_primitives::hash::CryptoHash;
 use near_primitives::transaction::{TransactionResult, TransactionStatus};
 use near_primitives::views::{
-    BlockView, FinalTransactionResult, FinalTransactionStatus, QueryResponse, TransactionLogView,
-    TransactionResultView,
+    ApprovalSignatureView, BlockApprovalsView, BlockHeaderView, BlockProofView, BlockView,
+    FinalTransactionResult, FinalTransactionStatus, ProtocolConfigView, QueryResponse,
+    SimulatedTransactionResult, TransactionLogView, TransactionResultView, UnstakeInfoView,
 };
 use near_store::Store;
 
-use crate::types::{Error, GetBlock, Query, TxStatus};
+use crate::types::{
+    Correlated, Error, GetBlock, GetBlockApprovals, GetBlockHeader, GetBlockProof,
+    GetProtocolConfig, GetStoreMetrics, GetUnstakeInfo, Query, SimulateTx, TxStatus,
+};
 use crate::TxDetails;
-use near_primitives::types::BlockIndex;
 
 /// View client provides currently committed (to the storage) view of the current chain and state.
 pub struct ViewClientActor {
     chain: Chain,
     runtime_adapter: Arc<dyn RuntimeAdapter>,
+    /// Correlation id of the request currently being handled, if any.
+    correlation_id: Option<CorrelationId>,
 }
 
 impl ViewClientActor {
@@ -30,12 +38,15 @@ impl ViewClientActor {
         store: Arc<Store>,
         genesis_time: DateTime<Utc>,
         runtime_adapter: Arc<dyn RuntimeAdapter>,
-        transaction_validity_period: BlockIndex,
     ) -> Result<Self, Error> {
         // TODO: should we create shared ChainStore that is passed to both Client and ViewClient?
-        let chain =
-            Chain::new(store, runtime_adapter.clone(), genesis_time, transaction_validity_period)?;
-        Ok(ViewClientActor { chain, runtime_adapter })
+        let chain = Chain::new(store, runtime_adapter.clone(), genesis_time)?;
+        Ok(ViewClientActor { chain, runtime_adapter, correlation_id: None })
+    }
+
+    /// Prefix for log lines about the request currently being handled.
+    fn log_correlation(&self) -> String {
+        self.correlation_id.map(|id| format!("correlation_id={} ", id)).unwrap_or_default()
     }
 
     pub fn get_transaction_result(
@@ -43,7 +54,17 @@ impl ViewClientActor {
         hash: &CryptoHash,
     ) -> Result<TransactionResultView, String> {
         match self.chain.get_transaction_result(hash) {
-            Ok(result) => Ok(result.clone().into()),
+            Ok(result) => {
+                let mut result: TransactionResultView = result.clone().into();
+                result.origin = match self.chain.get_receipt_origin(hash) {
+                    Ok(origin) => Some(origin.clone().into()),
+                    Err(err) => match err.kind() {
+                        ErrorKind::DBNotFoundErr(_) => None,
+                        _ => return Err(err.to_string()),
+                    },
+                };
+                Ok(result)
+            }
             Err(err) => match err.kind() {
                 ErrorKind::DBNotFoundErr(_) => Ok(TransactionResult {
                     status: TransactionStatus::Unknown,
@@ -100,12 +121,31 @@ impl Actor for ViewClientActor {
     type Context = Context<Self>;
 }
 
+/// Handles the request with the correlation id of the RPC call set, so it's logged with it.
+impl<M> Handler<Correlated<M>> for ViewClientActor
+where
+    M: Message,
+    ViewClientActor: Handler<M>,
+    <ViewClientActor as Handler<M>>::Result: MessageResponse<ViewClientActor, Correlated<M>>,
+{
+    type Result = <ViewClientActor as Handler<M>>::Result;
+
+    fn handle(&mut self, msg: Correlated<M>, ctx: &mut Context<Self>) -> Self::Result {
+        let Correlated(correlation_id, msg) = msg;
+        self.correlation_id = Some(correlation_id);
+        let result = <Self as Handler<M>>::handle(self, msg, ctx);
+        self.correlation_id = None;
+        result
+    }
+}
+
 /// Handles runtime query.
 impl Handler<Query> for ViewClientActor {
     type Result = Result<QueryResponse, String>;
 
     fn handle(&mut self, msg: Query, _: &mut Context<Self>) -> Self::Result {
         let head = self.chain.head().map_err(|err| err.to_string())?;
+        debug!(target: "client", "{}Query {} at #{}", self.log_correlation(), msg.path, head.height);
         let state_root =
             self.chain.get_post_state_root(&head.last_block_hash).map_err(|err| err.to_string())?;
         self.runtime_adapter
@@ -140,6 +180,46 @@ impl Handler<TxStatus> for ViewClientActor {
     }
 }
 
+/// Handles dry-run of the transaction on top of the current head state.
+impl Handler<SimulateTx> for ViewClientActor {
+    type Result = Result<SimulatedTransactionResult, String>;
+
+    fn handle(&mut self, msg: SimulateTx, _: &mut Context<Self>) -> Self::Result {
+        let head = self.chain.head().map_err(|err| err.to_string())?;
+        debug!(target: "client", "{}Simulating transaction {} at #{}", self.log_correlation(), msg.transaction.get_hash(), head.height + 1);
+        let state_root =
+            *self.chain.get_post_state_root(&head.last_block_hash).map_err(|err| err.to_string())?;
+        let shard_id =
+            self.runtime_adapter.account_id_to_shard_id(&msg.transaction.transaction.signer_id);
+        let (tx_results, receipt_result) = self
+            .runtime_adapter
+            .simulate_transaction(
+                shard_id,
+                &state_root,
+                head.height + 1,
+                &head.last_block_hash,
+                &msg.transaction,
+            )
+            .map_err(|err| err.to_string())?;
+        let receipts: Vec<_> = receipt_result.into_iter().flat_map(|(_, r)| r).collect();
+        let gas_burnt = tx_results.iter().filter_map(|log| log.result.gas_burnt).sum();
+        let status = if tx_results.iter().any(|log| log.result.status == TransactionStatus::Failed)
+        {
+            FinalTransactionStatus::Failed
+        } else if !receipts.is_empty() {
+            FinalTransactionStatus::Started
+        } else {
+            FinalTransactionStatus::Completed
+        };
+        Ok(SimulatedTransactionResult {
+            status,
+            transactions: tx_results.into_iter().map(Into::into).collect(),
+            receipts: receipts.into_iter().map(Into::into).collect(),
+            gas_burnt,
+        })
+    }
+}
+
 impl Handler<TxDetails> for ViewClientActor {
     type Result = Result<TransactionResultView, String>;
 
@@ -147,3 +227,98 @@ impl Handler<TxDetails> for ViewClientActor {
         self.get_transaction_result(&msg.tx_hash)
     }
 }
+
+impl Handler<GetProtocolConfig> for ViewClientActor {
+    type Result = Result<ProtocolConfigView, String>;
+
+    fn handle(&mut self, msg: GetProtocolConfig, _: &mut Context<Self>) -> Self::Result {
+        let height = match msg.block {
+            GetBlock::Best => self.chain.head().map(|head| head.height),
+            GetBlock::Height(height) => self.chain.get_header_by_height(height).map(|_| height),
+            GetBlock::Hash(hash) => self.chain.get_block_header(&hash).map(|h| h.inner.height),
+        }
+        .map_err(|err| err.to_string())?;
+        // All blocks are produced with the genesis protocol version, so there are no overrides
+        // of the genesis parameters yet.
+        let config = self.runtime_adapter.get_protocol_config();
+        Ok(ProtocolConfigView { block_height: height, ..config })
+    }
+}
+
+impl Handler<GetBlockApprovals> for ViewClientActor {
+    type Result = Result<BlockApprovalsView, String>;
+
+    fn handle(&mut self, msg: GetBlockApprovals, _: &mut Context<Self>) -> Self::Result {
+        let block_hash = match msg.block {
+            GetBlock::Best => self.chain.head().map(|head| head.last_block_hash),
+            GetBlock::Height(height) => self.chain.get_header_by_height(height).map(|h| h.hash()),
+            GetBlock::Hash(hash) => Ok(hash),
+        }
+        .map_err(|err| err.to_string())?;
+        let approvals = self.chain.get_block_approvals(&block_hash).map_err(|err| err.to_string())?;
+        Ok(BlockApprovalsView {
+            block_hash: block_hash.into(),
+            child_hash: approvals.child_hash.into(),
+            approvals: approvals
+                .approvals
+                .iter()
+                .map(|approval| ApprovalSignatureView {
+                    account_id: approval.account_id.clone(),
+                    signature: approval.signature.clone(),
+                })
+                .collect(),
+        })
+    }
+}
+
+impl Handler<GetBlockHeader> for ViewClientActor {
+    type Result = Result<BlockHeaderView, String>;
+
+    fn handle(&mut self, msg: GetBlockHeader, _: &mut Context<Self>) -> Self::Result {
+        match msg.block {
+            GetBlock::Best => match self.chain.header_head() {
+                Ok(head) => self.chain.get_block_header(&head.last_block_hash).map(Clone::clone),
+                Err(err) => Err(err),
+            },
+            GetBlock::Height(height) => self.chain.get_header_by_height(height).map(Clone::clone),
+            GetBlock::Hash(hash) => self.chain.get_block_header(&hash).map(Clone::clone),
+        }
+        .map(|header| header.into())
+        .map_err(|err| err.to_string())
+    }
+}
+
+impl Handler<GetBlockProof> for ViewClientActor {
+    type Result = Result<BlockProofView, String>;
+
+    fn handle(&mut self, msg: GetBlockProof, _: &mut Context<Self>) -> Self::Result {
+        let proof = self
+            .chain
+            .get_block_proof(&msg.block_hash, &msg.head_hash)
+            .map_err(|err| err.to_string())?;
+        let head_merkle_root =
+            self.chain.get_block_merkle_root(&msg.head_hash).map_err(|err| err.to_string())?;
+        Ok(BlockProofView::new(msg.block_hash, msg.head_hash, head_merkle_root, proof))
+    }
+}
+
+impl Handler<GetUnstakeInfo> for ViewClientActor {
+    type Result = Result<UnstakeInfoView, String>;
+
+    fn handle(&mut self, msg: GetUnstakeInfo, _: &mut Context<Self>) -> Self::Result {
+        let head = self.chain.head().map_err(|err| err.to_string())?;
+        self.runtime_adapter
+            .get_unstake_info(&head.last_block_hash, &msg.account_id)
+            .map_err(|err| err.to_string())
+    }
+}
+
+impl Handler<GetStoreMetrics> for ViewClientActor {
+    type Result = Result<String, String>;
+
+    fn handle(&mut self, _: GetStoreMetrics, _: &mut Context<Self>) -> Self::Result {
+        let mut metrics = self.chain.store().store().prometheus_metrics();
+        metrics.push_str(&near_chain::metrics::export_prometheus());
+        Ok(metrics)
+    }
+}
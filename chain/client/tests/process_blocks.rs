@@ -1,22 +1,30 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, RwLock};
+use std::{env, fs, process};
 
-use actix::System;
+use actix::{Addr, System};
+use chrono::{DateTime, Utc};
 use futures::{future, Future};
 
 use near_chain::{Block, BlockApproval};
-use near_client::test_utils::setup_mock;
-use near_client::GetBlock;
-use near_crypto::{InMemorySigner, KeyType, PublicKey, Signature};
+use near_client::test_utils::{setup_mock, setup_mock_with_block_producers, setup_mock_with_store};
+use near_client::{ClientActor, GetBlock, GetRecentReorgs, GetTxPool, SwapValidatorKey};
+use near_crypto::{InMemorySigner, KeyFile, KeyType, PublicKey, Signature, Signer};
 use near_network::test_utils::wait_or_panic;
-use near_network::types::{FullPeerInfo, NetworkInfo, PeerChainInfo};
-use near_network::{NetworkClientMessages, NetworkRequests, NetworkResponses, PeerInfo};
+use near_network::types::{FullPeerInfo, NetworkInfo, PeerChainInfo, PROTOCOL_VERSION};
+use near_network::{
+    NetworkClientMessages, NetworkClientResponses, NetworkRequests, NetworkResponses, PeerInfo,
+};
 use near_primitives::block::BlockHeader;
 use near_primitives::hash::{hash, CryptoHash};
 use near_primitives::test_utils::{init_integration_logger, init_test_logger};
 use near_primitives::transaction::{SignedTransaction, Transaction};
 use near_primitives::types::MerkleHash;
+use near_primitives::views::ReorgView;
+use near_store::test_utils::create_test_store;
+use near_store::Store;
 
 /// Runs block producing client and stops after network mock received two blocks.
 #[test]
@@ -42,6 +50,87 @@ fn produce_two_blocks() {
     .unwrap();
 }
 
+/// Produced block is pushed to the next block producer, own accounts are not targeted.
+#[test]
+fn produce_block_push_to_next_producer() {
+    init_test_logger();
+    System::run(|| {
+        setup_mock(
+            vec!["test1", "test2"],
+            "test2",
+            true,
+            Box::new(move |msg, _ctx, _| {
+                if let NetworkRequests::Block { targets, .. } = msg {
+                    assert_eq!(targets, &vec!["test1".to_string()]);
+                    System::current().stop();
+                }
+                NetworkResponses::NoResponse
+            }),
+        );
+    })
+    .unwrap();
+}
+
+/// Runs client with keys of both validators and checks it produces blocks for each of them.
+#[test]
+fn produce_blocks_multiple_keys() {
+    init_test_logger();
+    System::run(|| {
+        let producers = Arc::new(RwLock::new(HashSet::new()));
+        setup_mock_with_block_producers(
+            vec!["test1", "test2"],
+            vec!["test1", "test2"],
+            true,
+            Box::new(move |msg, _ctx, _| {
+                if let NetworkRequests::Block { block, .. } = msg {
+                    let signer1 = InMemorySigner::from_seed("test1", KeyType::ED25519, "test1");
+                    let signer2 = InMemorySigner::from_seed("test2", KeyType::ED25519, "test2");
+                    let producer = if block.header.verify_block_producer(&signer1.public_key) {
+                        "test1"
+                    } else {
+                        assert!(block.header.verify_block_producer(&signer2.public_key));
+                        "test2"
+                    };
+                    let mut producers = producers.write().unwrap();
+                    producers.insert(producer);
+                    if producers.len() == 2 {
+                        System::current().stop();
+                    }
+                }
+                NetworkResponses::NoResponse
+            }),
+            100,
+        );
+        near_network::test_utils::wait_or_panic(5000);
+    })
+    .unwrap();
+}
+
+/// Runs client with keys of all the validators and checks that blocks produced by one of its
+/// accounts are approved by the other ones.
+#[test]
+fn produce_blocks_multiple_keys_approvals() {
+    init_test_logger();
+    System::run(|| {
+        setup_mock_with_block_producers(
+            vec!["test1", "test2", "test3"],
+            vec!["test1", "test2", "test3"],
+            true,
+            Box::new(move |msg, _ctx, _| {
+                if let NetworkRequests::Block { block, .. } = msg {
+                    if !block.header.inner.approval_sigs.is_empty() {
+                        System::current().stop();
+                    }
+                }
+                NetworkResponses::NoResponse
+            }),
+            100,
+        );
+        near_network::test_utils::wait_or_panic(5000);
+    })
+    .unwrap();
+}
+
 /// Runs block producing client and sends it a transaction.
 #[test]
 fn produce_blocks_with_tx() {
@@ -53,7 +142,7 @@ fn produce_blocks_with_tx() {
             "test",
             true,
             Box::new(move |msg, _ctx, _| {
-                if let NetworkRequests::Block { block } = msg {
+                if let NetworkRequests::Block { block, .. } = msg {
                     count.fetch_add(block.transactions.len(), Ordering::Relaxed);
                     if count.load(Ordering::Relaxed) >= 1 {
                         System::current().stop();
@@ -82,6 +171,96 @@ fn produce_blocks_with_tx() {
     .unwrap();
 }
 
+/// Transaction submitted to the client directly is broadcast to the peers.
+#[test]
+fn broadcast_local_tx() {
+    init_test_logger();
+    System::run(|| {
+        let (client, view_client) = setup_mock(
+            vec!["test"],
+            "test",
+            true,
+            Box::new(move |msg, _ctx, _| {
+                if let NetworkRequests::Transaction { transaction } = msg {
+                    assert_eq!(transaction.transaction.nonce, 1);
+                    System::current().stop();
+                }
+                NetworkResponses::NoResponse
+            }),
+        );
+        actix::spawn(view_client.send(GetBlock::Best).then(move |res| {
+            let header: BlockHeader = res.unwrap().unwrap().header.into();
+            let block_hash = header.hash;
+            client.do_send(NetworkClientMessages::LocalTransaction(SignedTransaction::new(
+                Signature::empty(KeyType::ED25519),
+                Transaction {
+                    signer_id: "".to_string(),
+                    public_key: PublicKey::empty(KeyType::ED25519),
+                    nonce: 1,
+                    receiver_id: "".to_string(),
+                    block_hash,
+                    actions: vec![],
+                },
+            )));
+            future::ok(())
+        }));
+        wait_or_panic(5000);
+    })
+    .unwrap();
+}
+
+/// Transaction submitted again while it's still in the pool is accepted, so that the retries
+/// wait for its result.
+#[test]
+fn resubmit_pending_tx() {
+    init_test_logger();
+    System::run(|| {
+        let (client, view_client) = setup_mock(
+            vec!["test"],
+            "other",
+            true,
+            Box::new(|_, _, _| NetworkResponses::NoResponse),
+        );
+        actix::spawn(view_client.send(GetBlock::Best).then(move |res| {
+            let header: BlockHeader = res.unwrap().unwrap().header.into();
+            let tx = SignedTransaction::new(
+                Signature::empty(KeyType::ED25519),
+                Transaction {
+                    signer_id: "".to_string(),
+                    public_key: PublicKey::empty(KeyType::ED25519),
+                    nonce: 1,
+                    receiver_id: "".to_string(),
+                    block_hash: header.hash,
+                    actions: vec![],
+                },
+            );
+            let (client1, client2) = (client.clone(), client.clone());
+            client
+                .send(NetworkClientMessages::LocalTransaction(tx.clone()))
+                .and_then(move |res| {
+                    match res {
+                        NetworkClientResponses::ValidTx => {}
+                        _ => panic!("Pending transaction is refused"),
+                    }
+                    client1.send(NetworkClientMessages::LocalTransaction(tx))
+                })
+                .and_then(move |res| {
+                    match res {
+                        NetworkClientResponses::ValidTx => {}
+                        _ => panic!("Pending transaction is refused"),
+                    }
+                    client2.send(GetTxPool {})
+                })
+                .then(|res| {
+                    assert_eq!(res.unwrap().unwrap().len(), 1);
+                    System::current().stop();
+                    future::ok(())
+                })
+        }));
+    })
+    .unwrap();
+}
+
 /// Runs client that receives a block from network and announces header to the network with approval.
 /// Need 3 block producers, to receive approval.
 #[test]
@@ -93,8 +272,8 @@ fn receive_network_block() {
             "test2",
             true,
             Box::new(move |msg, _ctx, _| {
-                if let NetworkRequests::BlockHeaderAnnounce { approval, .. } = msg {
-                    assert!(approval.is_some());
+                if let NetworkRequests::BlockHeaderAnnounce { approvals, .. } = msg {
+                    assert_eq!(approvals.len(), 1);
                     System::current().stop();
                 }
                 NetworkResponses::NoResponse
@@ -173,6 +352,72 @@ fn receive_network_block_header() {
     .unwrap();
 }
 
+/// Runs client that receives a block with missing ancestors, requests them from the sending peer
+/// and connects the chain once they arrive.
+#[test]
+fn receive_orphan_requests_ancestors() {
+    let blocks: Arc<RwLock<Vec<Block>>> = Arc::new(RwLock::new(vec![]));
+    let requested: Arc<RwLock<Vec<CryptoHash>>> = Arc::new(RwLock::new(vec![]));
+    init_test_logger();
+    System::run(|| {
+        let blocks1 = blocks.clone();
+        let requested1 = requested.clone();
+        let (client, view_client) = setup_mock(
+            vec!["test"],
+            "other",
+            true,
+            Box::new(move |msg, _ctx, client_addr| match msg {
+                NetworkRequests::BlockRequest { hash, peer_id } => {
+                    requested1.write().unwrap().push(*hash);
+                    let block =
+                        blocks1.read().unwrap().iter().find(|b| b.hash() == *hash).unwrap().clone();
+                    actix::spawn(
+                        client_addr
+                            .send(NetworkClientMessages::Block(block, *peer_id, true))
+                            .then(|_| futures::future::ok(())),
+                    );
+                    NetworkResponses::NoResponse
+                }
+                NetworkRequests::BlockHeaderAnnounce { header, .. } => {
+                    if header.inner.height == 3 {
+                        let blocks = blocks1.read().unwrap();
+                        assert_eq!(
+                            *requested1.read().unwrap(),
+                            vec![blocks[1].hash(), blocks[0].hash()]
+                        );
+                        System::current().stop();
+                    }
+                    NetworkResponses::NoResponse
+                }
+                _ => NetworkResponses::NoResponse,
+            }),
+        );
+        actix::spawn(view_client.send(GetBlock::Best).then(move |res| {
+            let last_block = res.unwrap().unwrap();
+            let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+            let mut prev: BlockHeader = last_block.header.clone().into();
+            for height in 1..=3 {
+                let block = Block::produce(
+                    &prev,
+                    height,
+                    MerkleHash::default(),
+                    CryptoHash::default(),
+                    vec![],
+                    HashMap::default(),
+                    vec![],
+                    signer.clone(),
+                );
+                prev = block.header.clone();
+                blocks.write().unwrap().push(block);
+            }
+            let last = blocks.read().unwrap()[2].clone();
+            client.do_send(NetworkClientMessages::Block(last, PeerInfo::random().id, false));
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
 /// Include approvals to the next block in newly produced block.
 #[test]
 fn produce_block_with_approvals() {
@@ -183,7 +428,7 @@ fn produce_block_with_approvals() {
             "test2",
             true,
             Box::new(move |msg, _ctx, _| {
-                if let NetworkRequests::Block { block } = msg {
+                if let NetworkRequests::Block { block, .. } = msg {
                     assert!(block.header.inner.approval_sigs.len() > 0);
                     System::current().stop();
                 }
@@ -204,7 +449,12 @@ fn produce_block_with_approvals() {
                 vec![],
                 signer1,
             );
-            let block_approval = BlockApproval::new(block.hash(), &*signer3, "test2".to_string());
+            let block_approval = BlockApproval::new(
+                block.hash(),
+                &*signer3,
+                "test3".to_string(),
+                "test2".to_string(),
+            );
             client.do_send(NetworkClientMessages::Block(block, PeerInfo::random().id, false));
             client.do_send(NetworkClientMessages::BlockApproval(
                 "test3".to_string(),
@@ -228,10 +478,10 @@ fn invalid_blocks() {
             false,
             Box::new(move |msg, _ctx, _client_actor| {
                 match msg {
-                    NetworkRequests::BlockHeaderAnnounce { header, approval } => {
+                    NetworkRequests::BlockHeaderAnnounce { header, approvals } => {
                         assert_eq!(header.inner.height, 1);
                         assert_eq!(header.inner.prev_state_root, MerkleHash::default());
-                        assert_eq!(*approval, None);
+                        assert!(approvals.is_empty());
                         System::current().stop();
                     }
                     _ => {}
@@ -301,7 +551,7 @@ fn skip_block_production() {
             true,
             Box::new(move |msg, _ctx, _client_actor| {
                 match msg {
-                    NetworkRequests::Block { block } => {
+                    NetworkRequests::Block { block, .. } => {
                         if block.header.inner.height > 3 {
                             System::current().stop();
                         }
@@ -316,12 +566,79 @@ fn skip_block_production() {
     .unwrap();
 }
 
+/// Headers response is limited by the requested size in bytes, but always has at least one header.
+#[test]
+fn serve_headers_within_size_limit() {
+    init_test_logger();
+    System::run(|| {
+        let genesis_hash = Arc::new(RwLock::new(CryptoHash::default()));
+        setup_mock(
+            vec!["test"],
+            "test",
+            true,
+            Box::new(move |msg, _ctx, client_addr| {
+                if let NetworkRequests::Block { block, .. } = msg {
+                    if block.header.inner.height == 1 {
+                        *genesis_hash.write().unwrap() = block.header.inner.prev_hash;
+                    }
+                    if block.header.inner.height == 3 {
+                        let hashes = vec![*genesis_hash.read().unwrap()];
+                        let client_addr1 = client_addr.clone();
+                        let request =
+                            NetworkClientMessages::BlockHeadersRequest(hashes.clone(), 0, 1);
+                        actix::spawn(client_addr.send(request).then(move |res| {
+                            match res.unwrap() {
+                                NetworkClientResponses::BlockHeaders(headers, more_available) => {
+                                    assert_eq!(headers.len(), 1);
+                                    assert!(more_available);
+                                }
+                                _ => panic!("Unexpected response to headers request"),
+                            }
+                            let request =
+                                NetworkClientMessages::BlockHeadersRequest(hashes, 0, 1024 * 1024);
+                            client_addr1.send(request).then(|res| {
+                                match res.unwrap() {
+                                    NetworkClientResponses::BlockHeaders(
+                                        headers,
+                                        more_available,
+                                    ) => {
+                                        assert_eq!(headers.len(), 3);
+                                        assert!(!more_available);
+                                    }
+                                    _ => panic!("Unexpected response to headers request"),
+                                }
+                                System::current().stop();
+                                future::result(Ok(()))
+                            })
+                        }));
+                    }
+                }
+                NetworkResponses::NoResponse
+            }),
+        );
+        wait_or_panic(5000);
+    })
+    .unwrap();
+}
+
 /// Runs client that requests syncing headers from peers.
 #[test]
 fn client_sync_headers() {
     init_test_logger();
     System::run(|| {
         let peer_info1 = PeerInfo::random();
+        let full_peer_info1 = FullPeerInfo {
+            peer_info: peer_info1.clone(),
+            chain_info: PeerChainInfo {
+                genesis: Default::default(),
+                height: 5,
+                total_weight: 100.into(),
+            },
+            protocol_version: PROTOCOL_VERSION,
+            score: 0,
+            latency: None,
+            last_seen: Utc::now(),
+        };
         let _ = setup_mock(
             vec!["test"],
             "other",
@@ -330,19 +647,15 @@ fn client_sync_headers() {
                 NetworkRequests::FetchInfo { level: _ } => NetworkResponses::Info(NetworkInfo {
                     num_active_peers: 1,
                     peer_max_count: 1,
-                    most_weight_peers: vec![FullPeerInfo {
-                        peer_info: peer_info1.clone(),
-                        chain_info: PeerChainInfo {
-                            genesis: Default::default(),
-                            height: 5,
-                            total_weight: 100.into(),
-                        },
-                    }],
+                    most_weight_peers: vec![full_peer_info1.clone()],
+                    active_peers: vec![full_peer_info1.clone()],
                     sent_bytes_per_sec: 0,
                     received_bytes_per_sec: 0,
+                    dropped_messages: Default::default(),
+                    external_address: None,
                     routes: None,
                 }),
-                NetworkRequests::BlockHeadersRequest { hashes, peer_id } => {
+                NetworkRequests::BlockHeadersRequest { hashes, peer_id, .. } => {
                     assert_eq!(*peer_id, peer_info1.id);
                     assert_eq!(hashes.len(), 1);
                     // TODO: check it requests correct hashes.
@@ -356,3 +669,150 @@ fn client_sync_headers() {
     })
     .unwrap();
 }
+
+/// Switching to a heavier fork is reported and the transactions of the abandoned block go back
+/// into the pool.
+#[test]
+fn reorg_returns_abandoned_transactions() {
+    init_test_logger();
+    System::run(|| {
+        let (client, view_client) = setup_mock(
+            vec!["test"],
+            "other",
+            true,
+            Box::new(|_, _, _| NetworkResponses::NoResponse),
+        );
+        actix::spawn(view_client.send(GetBlock::Best).then(move |res| {
+            let genesis: BlockHeader = res.unwrap().unwrap().header.into();
+            let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+            let tx = SignedTransaction::new(
+                Signature::empty(KeyType::ED25519),
+                Transaction {
+                    signer_id: "".to_string(),
+                    public_key: PublicKey::empty(KeyType::ED25519),
+                    nonce: 0,
+                    receiver_id: "".to_string(),
+                    block_hash: genesis.hash,
+                    actions: vec![],
+                },
+            );
+            let produce = |prev: &BlockHeader, height, transactions| {
+                Block::produce(
+                    prev,
+                    height,
+                    MerkleHash::default(),
+                    CryptoHash::default(),
+                    transactions,
+                    HashMap::default(),
+                    vec![],
+                    signer.clone(),
+                )
+            };
+            let a1 = produce(&genesis, 1, vec![tx]);
+            let b1 = produce(&genesis, 2, vec![]);
+            let b2 = produce(&b1.header, 3, vec![]);
+            let (a1_hash, b1_hash, b2_hash) = (a1.hash(), b1.hash(), b2.hash());
+            let peer_id = PeerInfo::random().id;
+            let (client1, client2, client3, client4) =
+                (client.clone(), client.clone(), client.clone(), client.clone());
+            client
+                .send(NetworkClientMessages::Block(a1, peer_id, false))
+                .and_then(move |_| client1.send(NetworkClientMessages::Block(b1, peer_id, false)))
+                .and_then(move |_| client2.send(NetworkClientMessages::Block(b2, peer_id, false)))
+                .and_then(move |_| client3.send(GetRecentReorgs {}))
+                .and_then(move |res| {
+                    let reorg = ReorgView {
+                        common_ancestor: genesis.hash.into(),
+                        abandoned: vec![a1_hash.into()],
+                        canonical: vec![b1_hash.into(), b2_hash.into()],
+                    };
+                    assert_eq!(res.unwrap(), vec![reorg]);
+                    client4.send(GetTxPool {})
+                })
+                .then(|res| {
+                    assert_eq!(res.unwrap().unwrap().len(), 1);
+                    System::current().stop();
+                    future::ok(())
+                })
+        }));
+    })
+    .unwrap();
+}
+
+/// Starts validator "test" with the key from given key file, stops once a block is produced.
+fn start_validator(
+    store: Arc<Store>,
+    genesis_time: DateTime<Utc>,
+    key_file: PathBuf,
+) -> Addr<ClientActor> {
+    setup_mock_with_store(
+        store,
+        genesis_time,
+        vec!["test"],
+        vec![key_file],
+        Box::new(|msg, _ctx, _| {
+            if let NetworkRequests::Block { .. } = msg {
+                System::current().stop();
+            }
+            NetworkResponses::NoResponse
+        }),
+    )
+}
+
+/// Validator started with a key that is not its validator key produces blocks once switched to
+/// the validator key, which is written to its key file. After restart with a key in the key file
+/// that is not the validator key yet, the validator produces blocks with the previous key. Missing
+/// and malformed key files are refused.
+#[test]
+fn swap_validator_key() {
+    init_test_logger();
+    let dir = env::temp_dir().join(format!("swap_validator_key_{}", process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let key_file = dir.join("validator_key.json");
+    let previous_key = InMemorySigner::from_random("test".to_string(), KeyType::ED25519);
+    previous_key.write_to_file(&key_file);
+    let new_key_file = dir.join("new_key.json");
+    let validator_key = InMemorySigner::from_seed("test", KeyType::ED25519, "test");
+    validator_key.write_to_file(&new_key_file);
+    let malformed_key_file = dir.join("malformed_key.json");
+    fs::write(&malformed_key_file, "{}").unwrap();
+    let missing_key_file = dir.join("missing_key.json");
+    let store = create_test_store();
+    let genesis_time = Utc::now();
+
+    let (store1, key_file1) = (store.clone(), key_file.clone());
+    System::run(move || {
+        let client = start_validator(store1, genesis_time, key_file1);
+        let (client1, client2) = (client.clone(), client.clone());
+        actix::spawn(
+            client
+                .send(SwapValidatorKey { key_file: missing_key_file })
+                .then(move |res| {
+                    assert!(res.unwrap().is_err());
+                    client1.send(SwapValidatorKey { key_file: malformed_key_file })
+                })
+                .then(move |res| {
+                    assert!(res.unwrap().is_err());
+                    client2.send(SwapValidatorKey { key_file: new_key_file })
+                })
+                .then(|res| {
+                    res.unwrap().unwrap();
+                    future::ok(())
+                }),
+        );
+        wait_or_panic(5000);
+    })
+    .unwrap();
+    assert_eq!(InMemorySigner::from_file(&key_file).public_key, validator_key.public_key);
+    let old_key = InMemorySigner::from_file(&dir.join("validator_key.json.old"));
+    assert_eq!(old_key.public_key, previous_key.public_key);
+
+    let unstaked_key = InMemorySigner::from_random("test".to_string(), KeyType::ED25519);
+    KeyFile::from(&unstaked_key).rotate_file(&key_file).unwrap();
+    System::run(move || {
+        start_validator(store, genesis_time, key_file);
+        wait_or_panic(5000);
+    })
+    .unwrap();
+    let _ = fs::remove_dir_all(&dir);
+}
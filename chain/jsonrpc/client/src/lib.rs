@@ -7,8 +7,10 @@ use serde::Serialize;
 
 use near_primitives::types::BlockIndex;
 use near_primitives::views::{
-    BlockView, CryptoHashView, FinalTransactionResult, QueryResponse, StatusResponse,
-    TransactionResultView,
+    BlockApprovalsView, BlockHeaderView, BlockProfileView, BlockProofView, BlockView,
+    CryptoHashView, FinalTransactionResult, NetworkInfoView, PeerBanView, ProtocolConfigView,
+    QueryResponse, ReorgView, SignedTransactionView, SimulatedTransactionResult, StatusResponse,
+    SyncStatusView, TransactionResultView, UnstakeInfoView,
 };
 
 pub mod message;
@@ -179,6 +181,23 @@ jsonrpc_client!(pub struct JsonRpcClient {
     pub fn tx(&mut self, hash: String) -> RpcRequest<FinalTransactionResult>;
     pub fn tx_details(&mut self, hash: String) -> RpcRequest<TransactionResultView>;
     pub fn block(&mut self, id: BlockId) -> RpcRequest<BlockView>;
+    pub fn block_approvals(&mut self, id: BlockId) -> RpcRequest<BlockApprovalsView>;
+    pub fn header(&mut self, id: BlockId) -> RpcRequest<BlockHeaderView>;
+    pub fn block_proof(&mut self, block_hash: String, head_hash: String) -> RpcRequest<BlockProofView>;
+    pub fn simulate_tx(&mut self, tx: String) -> RpcRequest<SimulatedTransactionResult>;
+    pub fn stop(&mut self) -> RpcRequest<()>;
+    pub fn reload_config(&mut self) -> RpcRequest<()>;
+    pub fn swap_validator_key(&mut self, key_file: String) -> RpcRequest<()>;
+    pub fn protocol_config(&mut self, id: Option<BlockId>) -> RpcRequest<ProtocolConfigView>;
+    pub fn unstake_info(&mut self, account_id: String) -> RpcRequest<UnstakeInfoView>;
+    pub fn chain_profile(&mut self) -> RpcRequest<Vec<BlockProfileView>>;
+    pub fn reorgs(&mut self) -> RpcRequest<Vec<ReorgView>>;
+    pub fn tx_pool(&mut self) -> RpcRequest<Vec<SignedTransactionView>>;
+    pub fn sync_status(&mut self) -> RpcRequest<SyncStatusView>;
+    pub fn bans(&mut self) -> RpcRequest<Vec<PeerBanView>>;
+    pub fn network_info(&mut self) -> RpcRequest<NetworkInfoView>;
+    pub fn unban_peer(&mut self, peer_id: String) -> RpcRequest<bool>;
+    pub fn db_backup(&mut self, path: String) -> RpcRequest<u64>;
 });
 
 /// Create new JSON RPC client that connects to the given address.
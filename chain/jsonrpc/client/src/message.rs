@@ -66,11 +66,21 @@ impl Request {
     ///
     /// The ID is taken from the request.
     pub fn reply(&self, reply: Value) -> Message {
-        Message::Response(Response { jsonrpc: Version, result: Ok(reply), id: self.id.clone() })
+        Message::Response(Response {
+            jsonrpc: Version,
+            result: Ok(reply),
+            id: self.id.clone(),
+            correlation_id: None,
+        })
     }
     /// Answer the request with an error.
     pub fn error(&self, error: RpcError) -> Message {
-        Message::Response(Response { jsonrpc: Version, result: Err(error), id: self.id.clone() })
+        Message::Response(Response {
+            jsonrpc: Version,
+            result: Err(error),
+            id: self.id.clone(),
+            correlation_id: None,
+        })
     }
 }
 
@@ -125,6 +135,8 @@ pub struct Response {
     jsonrpc: Version,
     pub result: Result<Value, RpcError>,
     pub id: Value,
+    /// Id the server assigned to the request to find it in the node logs.
+    pub correlation_id: Option<String>,
 }
 
 impl Serialize for Response {
@@ -136,6 +148,9 @@ impl Serialize for Response {
             Err(ref err) => sub.serialize_field("error", err),
         }?;
         sub.serialize_field("id", &self.id)?;
+        if let Some(ref correlation_id) = self.correlation_id {
+            sub.serialize_field("correlation_id", correlation_id)?;
+        }
         sub.end()
     }
 }
@@ -160,6 +175,8 @@ struct WireResponse {
     result: Option<Value>,
     error: Option<RpcError>,
     id: Value,
+    #[serde(default)]
+    correlation_id: Option<String>,
 }
 
 // Implementing deserialize is hard. We sidestep the difficulty by deserializing a similar
@@ -177,7 +194,7 @@ impl<'de> Deserialize<'de> for Response {
                 return Err(err);
             }
         };
-        Ok(Response { jsonrpc: Version, result, id: wr.id })
+        Ok(Response { jsonrpc: Version, result, id: wr.id, correlation_id: wr.correlation_id })
     }
 }
 
@@ -243,7 +260,12 @@ impl Message {
     }
     /// Create a top-level error (without an ID).
     pub fn error(error: RpcError) -> Self {
-        Message::Response(Response { jsonrpc: Version, result: Err(error), id: Value::Null })
+        Message::Response(Response {
+            jsonrpc: Version,
+            result: Err(error),
+            id: Value::Null,
+            correlation_id: None,
+        })
     }
     /// A constructor for a notification.
     pub fn notification(method: String, params: Option<Value>) -> Self {
@@ -251,7 +273,16 @@ impl Message {
     }
     /// A constructor for a response.
     pub fn response(id: Value, result: Result<Value, RpcError>) -> Self {
-        Message::Response(Response { jsonrpc: Version, result, id })
+        Message::Response(Response { jsonrpc: Version, result, id, correlation_id: None })
+    }
+    /// Attaches correlation id to the response, other messages are returned as is.
+    pub fn with_correlation_id(self, correlation_id: String) -> Self {
+        match self {
+            Message::Response(response) => {
+                Message::Response(Response { correlation_id: Some(correlation_id), ..response })
+            }
+            message => message,
+        }
     }
     /// Returns id or Null if there is no id.
     pub fn id(&self) -> Value {
@@ -390,7 +421,12 @@ mod tests {
         // A successful response
         one(
             r#"{"jsonrpc": "2.0", "result": 42, "id": 3}"#,
-            &Message::Response(Response { jsonrpc: Version, result: Ok(json!(42)), id: json!(3) }),
+            &Message::Response(Response {
+                jsonrpc: Version,
+                result: Ok(json!(42)),
+                id: json!(3),
+                correlation_id: None,
+            }),
         );
         // A successful response
         one(
@@ -399,6 +435,7 @@ mod tests {
                 jsonrpc: Version,
                 result: Ok(Value::Null),
                 id: json!(3),
+                correlation_id: None,
             }),
         );
         // An error
@@ -408,6 +445,17 @@ mod tests {
                 jsonrpc: Version,
                 result: Err(RpcError::new(42, "Wrong!".to_owned(), None)),
                 id: Value::Null,
+                correlation_id: None,
+            }),
+        );
+        // An error with the correlation id of the request
+        one(
+            r#"{"jsonrpc": "2.0", "error": {"code": 42, "message": "Wrong!"}, "id": 4, "correlation_id": "00000000000000ff"}"#,
+            &Message::Response(Response {
+                jsonrpc: Version,
+                result: Err(RpcError::new(42, "Wrong!".to_owned(), None)),
+                id: json!(4),
+                correlation_id: Some("00000000000000ff".to_owned()),
             }),
         );
         // A batch
@@ -518,7 +566,15 @@ mod tests {
         let id1 = req1.id.clone();
         // When we answer a message, we get the same ID
         if let Message::Response(ref resp) = req1.reply(json!([1, 2, 3])) {
-            assert_eq!(*resp, Response { jsonrpc: Version, result: Ok(json!([1, 2, 3])), id: id1 });
+            assert_eq!(
+                *resp,
+                Response {
+                    jsonrpc: Version,
+                    result: Ok(json!([1, 2, 3])),
+                    id: id1,
+                    correlation_id: None,
+                }
+            );
         } else {
             panic!("Not a response");
         }
@@ -533,6 +589,7 @@ mod tests {
                     jsonrpc: Version,
                     result: Err(RpcError::new(42, "Wrong!".to_owned(), None)),
                     id: id2,
+                    correlation_id: None,
                 }
             );
         } else {
@@ -548,6 +605,7 @@ mod tests {
                     jsonrpc: Version,
                     result: Err(RpcError::new(43, "Also wrong!".to_owned(), None)),
                     id: Value::Null,
+                    correlation_id: None,
                 }
             );
         } else {
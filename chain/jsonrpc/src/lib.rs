@@ -1,14 +1,19 @@
 #![feature(await_macro, async_await)]
 
 use std::convert::TryFrom;
+use std::net::IpAddr;
+use std::path::PathBuf;
 use std::time::Duration;
 
-use actix::{Addr, MailboxError};
+use actix::{Addr, MailboxError, Recipient};
 use actix_cors::Cors;
-use actix_web::{App, Error as HttpError, http, HttpResponse, HttpServer, middleware, web};
+use actix_web::{
+    App, Error as HttpError, http, HttpRequest, HttpResponse, HttpServer, middleware, web,
+};
 use borsh::BorshDeserialize;
 use futures03::{compat::Future01CompatExt as _, FutureExt as _, TryFutureExt as _};
 use futures::future::Future;
+use log::{debug, info};
 use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
@@ -16,12 +21,19 @@ use serde_json::Value;
 use async_utils::{delay, timeout};
 use message::{Request, RpcError};
 use message::Message;
-use near_client::{ClientActor, GetBlock, Query, Status, TxDetails, TxStatus, ViewClientActor};
+use near_client::{
+    BackupDatabase, ClientActor, Correlated, GetBans, GetBlock, GetBlockApprovals,
+    GetBlockHeader, GetBlockProof, GetChainProfile, GetNetworkInfo, GetProtocolConfig,
+    GetRecentReorgs, GetStoreMetrics, GetSyncStatus, GetTxPool, GetUnstakeInfo, Query,
+    ReloadConfig, Shutdown, SimulateTx, Status, SwapValidatorKey, TxDetails, TxStatus, UnbanPeer,
+    ViewClientActor,
+};
 pub use near_jsonrpc_client as client;
 use near_jsonrpc_client::{message as message, BlockId};
-use near_network::{NetworkClientMessages, NetworkClientResponses};
+use near_network::types::PeerId;
+use near_network::{CorrelationId, NetworkClientMessages, NetworkClientResponses};
 use near_primitives::hash::CryptoHash;
-use near_primitives::views::{FinalTransactionStatus};
+use near_primitives::views::{CryptoHashView, FinalTransactionStatus};
 use near_primitives::serialize::{BaseEncode, from_base, from_base64};
 use near_primitives::transaction::SignedTransaction;
 
@@ -45,9 +57,21 @@ impl Default for RpcPollingConfig {
     }
 }
 
+/// Methods that manage the node, they are served only on the admin address.
+const ADMIN_METHODS: [&str; 5] =
+    ["stop", "reload_config", "swap_validator_key", "unban_peer", "db_backup"];
+
+fn default_admin_addr() -> String {
+    "127.0.0.1:3031".to_owned()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct RpcConfig {
     pub addr: String,
+    /// Address of the listener that also serves the methods managing the node, should be only
+    /// reachable locally.
+    #[serde(default = "default_admin_addr")]
+    pub admin_addr: String,
     pub cors_allowed_origins: Vec<String>,
     pub polling_config: RpcPollingConfig,
 }
@@ -56,6 +80,7 @@ impl Default for RpcConfig {
     fn default() -> Self {
         RpcConfig {
             addr: "0.0.0.0:3030".to_owned(),
+            admin_addr: default_admin_addr(),
             cors_allowed_origins: vec!["*".to_owned()],
             polling_config: Default::default(),
         }
@@ -109,10 +134,14 @@ fn parse_hash(params: Option<Value>) -> Result<CryptoHash, RpcError> {
     })
 }
 
+#[derive(Clone)]
 struct JsonRpcHandler {
     client_addr: Addr<ClientActor>,
     view_client_addr: Addr<ViewClientActor>,
+    config_reloader: Option<Recipient<Correlated<ReloadConfig>>>,
     polling_config: RpcPollingConfig,
+    /// Whether the handler serves the admin listener.
+    admin: bool,
 }
 
 impl JsonRpcHandler {
@@ -120,43 +149,89 @@ impl JsonRpcHandler {
         let id = message.id();
         match message {
             Message::Request(request) => {
-                Ok(Message::response(id, self.process_request(request).await))
+                let correlation_id = CorrelationId::random();
+                let method = request.method.clone();
+                debug!(target: "jsonrpc", "correlation_id={} Received {} request", correlation_id, method);
+                let result = self.process_request(request, correlation_id).await;
+                match result {
+                    Ok(_) => Ok(Message::response(id, result)),
+                    Err(ref err) => {
+                        info!(target: "jsonrpc", "correlation_id={} Request {} failed: {:?}", correlation_id, method, err);
+                        Ok(Message::response(id, result).with_correlation_id(correlation_id.to_string()))
+                    }
+                }
             }
             _ => Ok(Message::error(RpcError::invalid_request())),
         }
     }
 
-    async fn process_request(&self, request: Request) -> Result<Value, RpcError> {
+    async fn process_request(
+        &self,
+        request: Request,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        if !self.admin && ADMIN_METHODS.contains(&request.method.as_str()) {
+            return Err(RpcError::method_not_found(request.method));
+        }
         match request.method.as_ref() {
-            "broadcast_tx_async" => self.send_tx_async(request.params).await,
-            "broadcast_tx_commit" => self.send_tx_commit(request.params).await,
-            "query" => self.query(request.params).await,
+            "broadcast_tx_async" => self.send_tx_async(request.params, correlation_id).await,
+            "broadcast_tx_commit" => self.send_tx_commit(request.params, correlation_id).await,
+            "query" => self.query(request.params, correlation_id).await,
             "health" => self.health().await,
-            "status" => self.status().await,
-            "tx" => self.tx_status(request.params).await,
-            "tx_details" => self.tx_details(request.params).await,
-            "block" => self.block(request.params).await,
+            "status" => self.status(correlation_id).await,
+            "tx" => self.tx_status(request.params, correlation_id).await,
+            "tx_details" => self.tx_details(request.params, correlation_id).await,
+            "simulate_tx" => self.simulate_tx(request.params, correlation_id).await,
+            "block" => self.block(request.params, correlation_id).await,
+            "block_approvals" => self.block_approvals(request.params, correlation_id).await,
+            "header" => self.header(request.params, correlation_id).await,
+            "block_proof" => self.block_proof(request.params, correlation_id).await,
+            "protocol_config" => self.protocol_config(request.params, correlation_id).await,
+            "unstake_info" => self.unstake_info(request.params, correlation_id).await,
+            "stop" => self.stop(correlation_id).await,
+            "reload_config" => self.reload_config(correlation_id).await,
+            "chain_profile" => self.chain_profile(correlation_id).await,
+            "reorgs" => self.reorgs(correlation_id).await,
+            "tx_pool" => self.tx_pool(correlation_id).await,
+            "sync_status" => self.sync_status(correlation_id).await,
+            "swap_validator_key" => self.swap_validator_key(request.params, correlation_id).await,
+            "bans" => self.bans(correlation_id).await,
+            "network_info" => self.network_info(correlation_id).await,
+            "unban_peer" => self.unban_peer(request.params, correlation_id).await,
+            "db_backup" => self.db_backup(request.params, correlation_id).await,
             _ => Err(RpcError::method_not_found(request.method)),
         }
     }
 
-    async fn send_tx_async(&self, params: Option<Value>) -> Result<Value, RpcError> {
+    async fn send_tx_async(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
         let tx = parse_tx(params)?;
         let hash = (&tx.get_hash()).to_base();
-        actix::spawn(
-            self.client_addr
-                .send(NetworkClientMessages::Transaction(tx))
-                .map(|_| ())
-                .map_err(|_| ()),
-        );
-        Ok(Value::String(hash))
+        // Validation is quick, so the caller learns right away if the transaction is refused.
+        let result = self.client_addr
+            .send(NetworkClientMessages::Correlated(correlation_id, Box::new(NetworkClientMessages::LocalTransaction(tx))))
+            .map_err(|err| RpcError::server_error(Some(err.to_string())))
+            .compat()
+            .await?;
+        match result {
+            NetworkClientResponses::ValidTx => Ok(Value::String(hash)),
+            NetworkClientResponses::InvalidTx(err) => Err(RpcError::server_error(Some(err))),
+            _ => Err(RpcError::server_error(Some("Unexpected client response".to_owned()))),
+        }
     }
 
-    async fn send_tx_commit(&self, params: Option<Value>) -> Result<Value, RpcError> {
+    async fn send_tx_commit(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
         let tx = parse_tx(params)?;
         let tx_hash = tx.get_hash();
         let result = self.client_addr
-            .send(NetworkClientMessages::Transaction(tx))
+            .send(NetworkClientMessages::Correlated(correlation_id, Box::new(NetworkClientMessages::LocalTransaction(tx))))
             .map_err(|err| RpcError::server_error(Some(err.to_string())))
             .compat()
             .await?;
@@ -164,7 +239,8 @@ impl JsonRpcHandler {
             NetworkClientResponses::ValidTx => {
                 timeout(self.polling_config.polling_timeout, async {
                     loop {
-                        let final_tx = self.view_client_addr.send(TxStatus { tx_hash }).compat().await;
+                        let request = Correlated(correlation_id, TxStatus { tx_hash });
+                        let final_tx = self.view_client_addr.send(request).compat().await;
                         if let Ok(Ok(ref tx)) = final_tx {
                             match tx.status {
                                 FinalTransactionStatus::Started | FinalTransactionStatus::Unknown => {}
@@ -182,48 +258,257 @@ impl JsonRpcHandler {
             NetworkClientResponses::InvalidTx(err) => {
                 Err(RpcError::server_error(Some(err)))
             }
-            _ => unreachable!(),
+            _ => Err(RpcError::server_error(Some("Unexpected client response".to_owned()))),
+        }
+    }
+
+    /// Gracefully stops the node.
+    async fn stop(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        self.client_addr.do_send(Correlated(correlation_id, Shutdown {}));
+        Ok(Value::Null)
+    }
+
+    /// Re-reads config file and applies values that can be changed without restart.
+    async fn reload_config(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        match &self.config_reloader {
+            Some(config_reloader) => {
+                let request = Correlated(correlation_id, ReloadConfig {});
+                jsonify(config_reloader.send(request).compat().await)
+            }
+            None => Err(RpcError::server_error(Some("Config reload is not supported".to_owned()))),
         }
     }
 
+    /// Processing time breakdown of the last accepted blocks, to diagnose slow block processing.
+    async fn chain_profile(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        let request = Correlated(correlation_id, GetChainProfile {});
+        jsonify(self.client_addr.send(request).compat().await)
+    }
+
+    /// Last switches of the head to another fork, to diagnose forks in the network.
+    async fn reorgs(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        let request = Correlated(correlation_id, GetRecentReorgs {});
+        jsonify(self.client_addr.send(request).compat().await)
+    }
+
+    /// Current stage of syncing, including state download progress of each shard.
+    async fn sync_status(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        jsonify(self.client_addr.send(Correlated(correlation_id, GetSyncStatus {})).compat().await)
+    }
+
+    /// Transactions waiting in the pool to be included into a block.
+    async fn tx_pool(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        jsonify(self.client_addr.send(Correlated(correlation_id, GetTxPool {})).compat().await)
+    }
+
+    /// Switches the validator account to the key from given key file once it becomes the
+    /// validator key of the epoch.
+    async fn swap_validator_key(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (key_file,) = parse_params::<(String,)>(params)?;
+        let key_file = PathBuf::from(key_file);
+        let request = Correlated(correlation_id, SwapValidatorKey { key_file });
+        jsonify(self.client_addr.send(request).compat().await)
+    }
+
+    /// Writes backup of the database to the directory at given path on the node, returns size of
+    /// the backup in bytes.
+    async fn db_backup(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (path,) = parse_params::<(String,)>(params)?;
+        let path = PathBuf::from(path);
+        let request = Correlated(correlation_id, BackupDatabase { path });
+        jsonify(self.client_addr.send(request).compat().await)
+    }
+
+    /// Banned peers with the reason and the expiration of each ban.
+    async fn bans(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        jsonify(self.client_addr.send(Correlated(correlation_id, GetBans {})).compat().await)
+    }
+
+    /// Active peers with their latency and the network traffic of the node.
+    async fn network_info(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        jsonify(self.client_addr.send(Correlated(correlation_id, GetNetworkInfo {})).compat().await)
+    }
+
+    /// Lifts the ban of the peer with given id, returns whether the peer was banned.
+    async fn unban_peer(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (peer_id,) = parse_params::<(String,)>(params)?;
+        let peer_id = PeerId::try_from(peer_id.as_str())
+            .map_err(|err| RpcError::invalid_params(Some(format!("Invalid peer id: {}", err))))?;
+        let request = Correlated(correlation_id, UnbanPeer { peer_id });
+        jsonify(self.client_addr.send(request).compat().await)
+    }
+
     async fn health(&self) -> Result<Value, RpcError> {
         Ok(Value::Null)
     }
 
-    pub async fn status(&self) -> Result<Value, RpcError> {
-        jsonify(self.client_addr.send(Status {}).compat().await)
+    pub async fn status(&self, correlation_id: CorrelationId) -> Result<Value, RpcError> {
+        jsonify(self.client_addr.send(Correlated(correlation_id, Status {})).compat().await)
     }
 
-    async fn query(&self, params: Option<Value>) -> Result<Value, RpcError> {
+    async fn query(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
         let (path, data) = parse_params::<(String, String)>(params)?;
         let data = from_base_or_parse_err(data)?;
-        jsonify(self.view_client_addr.send(Query { path, data }).compat().await)
+        let request = Correlated(correlation_id, Query { path, data });
+        jsonify(self.view_client_addr.send(request).compat().await)
     }
 
-    async fn tx_status(&self, params: Option<Value>) -> Result<Value, RpcError> {
+    async fn tx_status(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
         let tx_hash = parse_hash(params)?;
-        jsonify(self.view_client_addr.send(TxStatus { tx_hash }).compat().await)
+        let request = Correlated(correlation_id, TxStatus { tx_hash });
+        jsonify(self.view_client_addr.send(request).compat().await)
     }
 
-    async fn tx_details(&self, params: Option<Value>) -> Result<Value, RpcError> {
+    async fn tx_details(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
         let tx_hash = parse_hash(params)?;
-        jsonify(self.view_client_addr.send(TxDetails { tx_hash }).compat().await)
+        let request = Correlated(correlation_id, TxDetails { tx_hash });
+        jsonify(self.view_client_addr.send(request).compat().await)
+    }
+
+    async fn simulate_tx(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let transaction = parse_tx(params)?;
+        let request = Correlated(correlation_id, SimulateTx { transaction });
+        jsonify(self.view_client_addr.send(request).compat().await)
+    }
+
+    async fn protocol_config(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let block_id = match params {
+            None => None,
+            Some(Value::Array(ref args)) if args.is_empty() => None,
+            params => parse_params::<(Option<BlockId>,)>(params)?.0,
+        };
+        let block = match block_id {
+            None => GetBlock::Best,
+            Some(BlockId::Height(height)) => GetBlock::Height(height),
+            Some(BlockId::Hash(hash)) => GetBlock::Hash(hash.into()),
+        };
+        let request = Correlated(correlation_id, GetProtocolConfig { block });
+        jsonify(self.view_client_addr.send(request).compat().await)
     }
 
-    async fn block(&self, params: Option<Value>) -> Result<Value, RpcError> {
+    async fn block_approvals(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
         let (block_id,) = parse_params::<(BlockId,)>(params)?;
-        jsonify(self.view_client_addr.send(match block_id {
+        let block = match block_id {
             BlockId::Height(height) => GetBlock::Height(height),
             BlockId::Hash(hash) => GetBlock::Hash(hash.into()),
-        }).compat().await)
+        };
+        let request = Correlated(correlation_id, GetBlockApprovals { block });
+        jsonify(self.view_client_addr.send(request).compat().await)
     }
+
+    async fn header(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (block_id,) = parse_params::<(BlockId,)>(params)?;
+        let block = match block_id {
+            BlockId::Height(height) => GetBlock::Height(height),
+            BlockId::Hash(hash) => GetBlock::Hash(hash.into()),
+        };
+        let request = Correlated(correlation_id, GetBlockHeader { block });
+        jsonify(self.view_client_addr.send(request).compat().await)
+    }
+
+    async fn block_proof(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (block_hash, head_hash) = parse_params::<(CryptoHashView, CryptoHashView)>(params)?;
+        let request = GetBlockProof { block_hash: block_hash.into(), head_hash: head_hash.into() };
+        jsonify(self.view_client_addr.send(Correlated(correlation_id, request)).compat().await)
+    }
+
+    async fn unstake_info(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (account_id,) = parse_params::<(String,)>(params)?;
+        let request = Correlated(correlation_id, GetUnstakeInfo { account_id });
+        jsonify(self.view_client_addr.send(request).compat().await)
+    }
+
+    async fn block(
+        &self,
+        params: Option<Value>,
+        correlation_id: CorrelationId,
+    ) -> Result<Value, RpcError> {
+        let (block_id,) = parse_params::<(BlockId,)>(params)?;
+        let request = match block_id {
+            BlockId::Height(height) => GetBlock::Height(height),
+            BlockId::Hash(hash) => GetBlock::Hash(hash.into()),
+        };
+        jsonify(self.view_client_addr.send(Correlated(correlation_id, request)).compat().await)
+    }
+}
+
+/// Whether the request to the admin listener is sent by a local client and not by a web page.
+/// Browsers send `Origin` with cross-origin requests, and a `Host` other than a loopback address
+/// means the request was sent to a DNS name rebound to the loopback address.
+fn is_local_request(req: &HttpRequest) -> bool {
+    if req.headers().contains_key(http::header::ORIGIN) {
+        return false;
+    }
+    let host = match req.headers().get(http::header::HOST).and_then(|host| host.to_str().ok()) {
+        Some(host) => host,
+        None => return false,
+    };
+    let hostname = if host.starts_with('[') {
+        host[1..].split(']').next().unwrap_or_default()
+    } else {
+        host.split(':').next().unwrap_or_default()
+    };
+    hostname == "localhost" || hostname.parse::<IpAddr>().map_or(false, |ip| ip.is_loopback())
 }
 
 fn rpc_handler(
+    req: HttpRequest,
     message: web::Json<Message>,
     handler: web::Data<JsonRpcHandler>,
 ) -> impl Future<Item = HttpResponse, Error = HttpError> {
+    let allowed = !handler.admin || is_local_request(&req);
     let response = async move {
+        if !allowed {
+            return Ok(HttpResponse::Forbidden().finish());
+        }
         let message = handler.process(message.0).await?;
         Ok(HttpResponse::Ok().json(message))
     };
@@ -232,7 +517,7 @@ fn rpc_handler(
 
 fn status_handler(handler: web::Data<JsonRpcHandler>) -> impl Future<Item = HttpResponse, Error = HttpError> {
     let response = async move {
-        match handler.status().await {
+        match handler.status(CorrelationId::random()).await {
             Ok(value) => Ok(HttpResponse::Ok().json(value)),
             Err(_) => Ok(HttpResponse::ServiceUnavailable().finish()),
         }
@@ -240,6 +525,19 @@ fn status_handler(handler: web::Data<JsonRpcHandler>) -> impl Future<Item = Http
     response.boxed().compat()
 }
 
+/// Metrics of the node in the Prometheus text format.
+fn metrics_handler(handler: web::Data<JsonRpcHandler>) -> impl Future<Item = HttpResponse, Error = HttpError> {
+    let response = async move {
+        match handler.view_client_addr.send(GetStoreMetrics {}).compat().await {
+            Ok(Ok(metrics)) => {
+                Ok(HttpResponse::Ok().content_type("text/plain; version=0.0.4").body(metrics))
+            }
+            _ => Ok(HttpResponse::ServiceUnavailable().finish()),
+        }
+    };
+    response.boxed().compat()
+}
+
 fn get_cors(cors_allowed_origins: &[String]) -> Cors {
     let mut cors = Cors::new();
     if cors_allowed_origins != ["*".to_string()] {
@@ -253,28 +551,52 @@ fn get_cors(cors_allowed_origins: &[String]) -> Cors {
         .max_age(3600)
 }
 
+fn routes(config: &mut web::ServiceConfig) {
+    config
+        .service(web::resource("/").route(web::post().to_async(rpc_handler)))
+        .service(web::resource("/status").route(web::get().to_async(status_handler)))
+        .service(web::resource("/metrics").route(web::get().to_async(metrics_handler)));
+}
+
 pub fn start_http(
     config: RpcConfig,
     client_addr: Addr<ClientActor>,
     view_client_addr: Addr<ViewClientActor>,
+    config_reloader: Option<Recipient<Correlated<ReloadConfig>>>,
 ) {
-    let RpcConfig { addr, polling_config, cors_allowed_origins } = config;
+    let RpcConfig { addr, admin_addr, polling_config, cors_allowed_origins } = config;
+    let handler = JsonRpcHandler {
+        client_addr,
+        view_client_addr,
+        config_reloader,
+        polling_config,
+        admin: false,
+    };
+    let admin_handler = JsonRpcHandler { admin: true, ..handler.clone() };
     HttpServer::new(move || {
         App::new()
             .wrap(get_cors(&cors_allowed_origins))
-            .data(JsonRpcHandler {
-                client_addr: client_addr.clone(),
-                view_client_addr: view_client_addr.clone(),
-                polling_config,
-            })
+            .data(handler.clone())
             .data(web::JsonConfig::default().limit(JSON_PAYLOAD_MAX_SIZE))
             .wrap(middleware::Logger::default())
-            .service(web::resource("/").route(web::post().to_async(rpc_handler)))
-            .service(web::resource("/status").route(web::get().to_async(status_handler)))
+            .configure(routes)
     })
     .bind(addr)
     .unwrap()
     .workers(4)
     .shutdown_timeout(5)
     .start();
+    // Admin listener allows no other origins, so that web pages can't manage the node.
+    HttpServer::new(move || {
+        App::new()
+            .data(admin_handler.clone())
+            .data(web::JsonConfig::default().limit(JSON_PAYLOAD_MAX_SIZE))
+            .wrap(middleware::Logger::default())
+            .configure(routes)
+    })
+    .bind(admin_addr)
+    .unwrap()
+    .workers(1)
+    .shutdown_timeout(5)
+    .start();
 }
@@ -15,6 +15,15 @@ pub fn start_all_with_validity_period(
     validator: bool,
     validity_period: BlockIndex,
 ) -> (Addr<ViewClientActor>, String) {
+    let (view_client_addr, addr, _) = start_all_with_admin(validator, validity_period);
+    (view_client_addr, addr)
+}
+
+/// Starts the node and the RPC, returns the RPC address and the admin RPC address.
+pub fn start_all_with_admin(
+    validator: bool,
+    validity_period: BlockIndex,
+) -> (Addr<ViewClientActor>, String, String) {
     let (client_addr, view_client_addr) = setup_no_network_with_validity_period(
         vec!["test1", "test2"],
         if validator { "test1" } else { "other" },
@@ -23,6 +32,8 @@ pub fn start_all_with_validity_period(
     );
 
     let addr = format!("127.0.0.1:{}", open_port());
-    start_http(RpcConfig::new(&addr), client_addr.clone(), view_client_addr.clone());
-    (view_client_addr, addr)
+    let admin_addr = format!("127.0.0.1:{}", open_port());
+    let config = RpcConfig { admin_addr: admin_addr.clone(), ..RpcConfig::new(&addr) };
+    start_http(config, client_addr.clone(), view_client_addr.clone(), None);
+    (view_client_addr, addr, admin_addr)
 }
@@ -1,9 +1,10 @@
 use actix::System;
+use actix_web::client::Client;
 use futures::future;
 use futures::future::Future;
 
 use near_jsonrpc::client::new_http_client;
-use near_jsonrpc::test_utils::start_all;
+use near_jsonrpc::test_utils::{start_all, start_all_with_admin};
 use near_primitives::test_utils::init_test_logger;
 
 /// Retrieve client status via HTTP GET.
@@ -26,3 +27,67 @@ fn test_status() {
     })
     .unwrap();
 }
+
+/// Retrieve metrics of the store in the Prometheus text format via HTTP GET.
+#[test]
+fn test_metrics() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let client = Client::new();
+        actix::spawn(
+            client
+                .get(format!("http://{}/metrics", addr))
+                .send()
+                .and_then(|mut response| {
+                    assert!(response.status().is_success());
+                    response.body().map_err(|err| panic!("Payload error: {:?}", err))
+                })
+                .then(|body| {
+                    let text = String::from_utf8(body.unwrap().to_vec()).unwrap();
+                    assert!(text.contains("# TYPE near_store_reads_total counter\n"));
+                    assert!(text.contains("near_store_reads_total{store=\"hot\",column=\"block\"}"));
+                    assert!(text.contains("# TYPE near_chain_deep_reorgs_total counter\n"));
+                    System::current().stop();
+                    future::result(Ok(()))
+                }),
+        );
+    })
+    .unwrap();
+}
+
+/// Admin listener refuses requests sent by web pages and to other host names.
+#[test]
+fn test_admin_refuses_web_pages() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, _addr, admin_addr) = start_all_with_admin(false, 100);
+
+        let client = Client::new();
+        let url = format!("http://{}/", admin_addr);
+        let body = r#"{"jsonrpc": "2.0", "id": "dontcare", "method": "bans", "params": []}"#;
+        let post = move |header: Option<(&'static str, &'static str)>| {
+            let mut request = client.post(&url).header("Content-Type", "application/json");
+            if let Some((name, value)) = header {
+                request = request.header(name, value);
+            }
+            request.send_body(body).map(|response| response.status().as_u16())
+        };
+        actix::spawn(
+            post(None)
+                .join3(
+                    post(Some(("Origin", "http://example.com"))),
+                    post(Some(("Host", "example.com"))),
+                )
+                .then(|res| {
+                    assert_eq!(res.unwrap(), (200, 403, 403));
+                    System::current().stop();
+                    future::result(Ok(()))
+                }),
+        );
+    })
+    .unwrap();
+}
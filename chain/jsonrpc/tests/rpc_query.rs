@@ -3,9 +3,12 @@ use futures::future;
 use futures::future::Future;
 
 use near_jsonrpc::client::new_client;
-use near_jsonrpc::test_utils::start_all;
+use near_jsonrpc::test_utils::{start_all, start_all_with_admin};
+use near_crypto::{KeyType, SecretKey};
 use near_jsonrpc_client::BlockId;
+use near_primitives::serialize::to_base;
 use near_primitives::test_utils::init_test_logger;
+use near_primitives::views::{ProtocolConfigView, SyncStatusView, UnstakeInfoView};
 
 /// Retrieve blocks via json rpc
 #[test]
@@ -60,6 +63,34 @@ fn test_block_by_hash() {
     .unwrap();
 }
 
+/// Retrieve header and proof that genesis is in the chain of itself.
+#[test]
+fn test_header_and_block_proof() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr.clone()));
+        actix::spawn(client.header(BlockId::Height(0)).then(move |res| {
+            let header = res.unwrap();
+            assert_eq!(header.height, 0);
+            assert_eq!(header.prev_hash.0, &[0; 32]);
+            let hash = to_base(&header.hash.0);
+            let mut client = new_client(&format!("http://{}", addr));
+            client.block_proof(hash.clone(), hash).then(move |res| {
+                let res = res.unwrap();
+                assert_eq!(res.block_hash, header.hash);
+                assert_eq!(res.head_hash, header.hash);
+                assert!(res.proof.is_empty());
+                System::current().stop();
+                future::ok(())
+            })
+        }));
+    })
+    .unwrap();
+}
+
 /// Connect to json rpc and query the client.
 #[test]
 fn test_query() {
@@ -99,6 +130,82 @@ fn test_status() {
     .unwrap();
 }
 
+/// Retrieve protocol config via JSON RPC.
+#[test]
+fn test_protocol_config() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.protocol_config(Some(BlockId::Height(0))).then(|res| {
+            let res = res.unwrap();
+            assert_eq!(res, ProtocolConfigView::default());
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
+/// Retrieve protocol config for a block that is not known.
+#[test]
+fn test_protocol_config_unknown_block() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.protocol_config(Some(BlockId::Height(1_000_000))).then(|res| {
+            assert!(res.is_err());
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
+/// Retrieve unstake schedule of the validator via JSON RPC.
+#[test]
+fn test_unstake_info() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.unstake_info("test1".to_string()).then(move |res| {
+            assert_eq!(
+                res.unwrap(),
+                UnstakeInfoView {
+                    account_id: "test1".to_string(),
+                    locked: 1_000_000,
+                    final_stake: 1_000_000,
+                    validation_end_height: None,
+                    unlock_height: None,
+                }
+            );
+            client.unstake_info("other".to_string()).then(|res| {
+                assert_eq!(
+                    res.unwrap(),
+                    UnstakeInfoView {
+                        account_id: "other".to_string(),
+                        locked: 0,
+                        final_stake: 0,
+                        validation_end_height: None,
+                        unlock_height: None,
+                    }
+                );
+                System::current().stop();
+                future::result(Ok(()))
+            })
+        }));
+    })
+    .unwrap();
+}
+
 /// Check health fails when node is absent.
 #[test]
 fn test_health_fail() {
@@ -132,3 +239,128 @@ fn test_health_ok() {
     })
     .unwrap();
 }
+
+/// Retrieve processing time breakdown of the last blocks.
+#[test]
+fn test_chain_profile() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.chain_profile().then(|res| {
+            let profiles = res.unwrap();
+            for profile in profiles.iter() {
+                assert!(profile.total >= profile.apply_transactions + profile.store_commit);
+            }
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
+/// Node without forks reports no reorgs.
+#[test]
+fn test_reorgs() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.reorgs().then(|res| {
+            assert!(res.unwrap().is_empty());
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
+/// Retrieve transactions waiting in the pool of an idle node.
+#[test]
+fn test_tx_pool() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.tx_pool().then(|res| {
+            assert!(res.unwrap().is_empty());
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
+/// Connect to json rpc and query the sync status.
+#[test]
+fn test_sync_status() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.sync_status().then(|res| {
+            let status = res.unwrap();
+            assert!(status == SyncStatusView::NoSync || status == SyncStatusView::AwaitingPeers);
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
+
+/// List bans of a node without peers and try to unban peers that are not banned.
+#[test]
+fn test_bans() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr, admin_addr) = start_all_with_admin(false, 100);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        let mut admin_client = new_client(&format!("http://{}", admin_addr));
+        let peer_id = SecretKey::from_random(KeyType::ED25519).public_key().to_string();
+        // Managing the node is only possible through the admin address.
+        let public_unban = client.unban_peer(peer_id.clone());
+        let admin_unban = admin_client.unban_peer(peer_id);
+        actix::spawn(client.bans().join3(public_unban.then(Ok), admin_unban).then(move |res| {
+            let (bans, public_unban, unbanned) = res.unwrap();
+            assert!(bans.is_empty());
+            assert!(public_unban.is_err());
+            assert!(!unbanned);
+            admin_client.unban_peer("invalid".to_string()).then(|res| {
+                assert!(res.is_err());
+                System::current().stop();
+                future::result(Ok(()))
+            })
+        }));
+    })
+    .unwrap();
+}
+
+/// Retrieve network info, test node has no peers.
+#[test]
+fn test_network_info() {
+    init_test_logger();
+
+    System::run(|| {
+        let (_view_client_addr, addr) = start_all(false);
+
+        let mut client = new_client(&format!("http://{}", addr));
+        actix::spawn(client.network_info().then(|res| {
+            let res = res.unwrap();
+            assert_eq!(res.num_active_peers, 0);
+            assert!(res.active_peers.is_empty());
+            System::current().stop();
+            future::result(Ok(()))
+        }));
+    })
+    .unwrap();
+}
@@ -114,6 +114,48 @@ fn test_send_tx_commit() {
     .unwrap();
 }
 
+/// Test dry-running transaction via json rpc.
+#[test]
+fn test_simulate_tx() {
+    init_test_logger();
+
+    System::run(|| {
+        let (view_client, addr) = start_all(true);
+
+        let mut client = new_client(&format!("http://{}", addr));
+
+        actix::spawn(view_client.send(GetBlock::Best).then(move |res| {
+            let header: BlockHeader = res.unwrap().unwrap().header.into();
+            let block_hash = header.hash;
+            let signer = InMemorySigner::from_seed("test1", KeyType::ED25519, "test1");
+            let tx = SignedTransaction::send_money(
+                1,
+                "test1".to_string(),
+                "test2".to_string(),
+                Arc::new(signer),
+                100,
+                block_hash,
+            );
+            let tx_hash = tx.get_hash();
+            let bytes = tx.try_to_vec().unwrap();
+            client
+                .simulate_tx(to_base64(&bytes))
+                .map_err(|why| {
+                    System::current().stop();
+                    panic!(why);
+                })
+                .map(move |result| {
+                    assert_eq!(result.status, FinalTransactionStatus::Completed);
+                    assert_eq!(result.transactions.len(), 1);
+                    assert_eq!(result.transactions[0].hash, tx_hash.into());
+                    System::current().stop();
+                })
+        }));
+        wait_or_panic(10000);
+    })
+    .unwrap();
+}
+
 /// Test that expired transaction should be rejected
 #[test]
 fn test_expired_tx() {
@@ -0,0 +1,193 @@
+//! Converts misbehavior reports about peers into graded responses.
+//!
+//! Every report lowers the score of the reported peer by the penalty of the reason. Once the peer
+//! has lost enough score it is temporarily banned and its score is restored. A peer that keeps
+//! misbehaving after a number of temporary bans is banned permanently.
+use std::collections::HashMap;
+use std::time::Duration;
+
+use cached::{Cached, SizedCache};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::types::{PeerId, ReasonForBan};
+
+/// Response to a misbehavior report.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BanResponse {
+    /// Peer's score was decreased, keep the connection.
+    Decrease,
+    /// Peer must be disconnected and banned for the configured ban window.
+    TempBan,
+    /// Peer must be disconnected and never accepted again.
+    PermanentBan,
+}
+
+/// Thresholds used to grade misbehavior reports.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default)]
+pub struct BanPolicyConfig {
+    /// Score lost for minor offences, that can happen to honest peers (e.g. during forks).
+    pub minor_penalty: u32,
+    /// Score lost for offences that only malicious or broken peers commit.
+    pub major_penalty: u32,
+    /// Score a peer can lose before being temporarily banned.
+    pub temp_ban_threshold: u32,
+    /// Number of temporary bans after which the next ban becomes permanent.
+    pub permanent_ban_after: u32,
+    /// Duration of the temporary ban for the reason, `NetworkConfig::ban_window` for the reasons
+    /// not listed.
+    pub ban_durations: HashMap<ReasonForBan, Duration>,
+    /// Maximum number of peers which misbehavior is remembered, the peers reported least
+    /// recently are forgotten first.
+    pub max_tracked_peers: usize,
+}
+
+impl Default for BanPolicyConfig {
+    fn default() -> Self {
+        BanPolicyConfig {
+            minor_penalty: 20,
+            major_penalty: 100,
+            temp_ban_threshold: 100,
+            permanent_ban_after: 3,
+            ban_durations: HashMap::default(),
+            max_tracked_peers: 10_000,
+        }
+    }
+}
+
+#[derive(Default)]
+struct PeerRecord {
+    /// Score lost since the last temporary ban.
+    score_lost: u32,
+    /// Number of temporary bans given to the peer.
+    num_temp_bans: u32,
+}
+
+/// Tracks misbehavior of peers and decides how to respond to it.
+pub struct BanPolicy {
+    config: BanPolicyConfig,
+    records: SizedCache<PeerId, PeerRecord>,
+}
+
+impl BanPolicy {
+    pub fn new(config: BanPolicyConfig) -> Self {
+        let records = SizedCache::with_size(config.max_tracked_peers.max(1));
+        BanPolicy { config, records }
+    }
+
+    fn penalty(&self, reason: ReasonForBan) -> u32 {
+        match reason {
+            ReasonForBan::None | ReasonForBan::WrongNetwork => 0,
+            ReasonForBan::BadBlockApproval
+            | ReasonForBan::HeightFraud
+            | ReasonForBan::BadHandshake
+            | ReasonForBan::InvalidPeerId
+            | ReasonForBan::MalformedMessage
+            | ReasonForBan::UnsolicitedMessage => self.config.minor_penalty,
+            ReasonForBan::BadBlock
+            | ReasonForBan::BadBlockHeader
+            | ReasonForBan::InvalidSignature
+            | ReasonForBan::InvalidHash
+            | ReasonForBan::BadStatePart
+            | ReasonForBan::BadSyncData
+            | ReasonForBan::OversizedMessage
+            | ReasonForBan::Abusive => self.config.major_penalty,
+        }
+    }
+
+    /// Records misbehavior of given peer and returns how it should be dealt with.
+    pub fn report(&mut self, peer_id: &PeerId, reason: ReasonForBan) -> BanResponse {
+        // Peer on another network is not malicious, it's banned only to stop reconnecting to it.
+        if reason == ReasonForBan::WrongNetwork {
+            return BanResponse::TempBan;
+        }
+        let penalty = self.penalty(reason);
+        if self.records.cache_get(peer_id).is_none() {
+            self.records.cache_set(*peer_id, PeerRecord::default());
+        }
+        let record = self.records.cache_get_mut(peer_id).unwrap();
+        record.score_lost = record.score_lost.saturating_add(penalty);
+        if record.score_lost < self.config.temp_ban_threshold {
+            return BanResponse::Decrease;
+        }
+        record.score_lost = 0;
+        if record.num_temp_bans >= self.config.permanent_ban_after {
+            BanResponse::PermanentBan
+        } else {
+            record.num_temp_bans += 1;
+            BanResponse::TempBan
+        }
+    }
+
+    /// Forgets everything known about the peer.
+    pub fn forget(&mut self, peer_id: &PeerId) {
+        self.records.cache_remove(peer_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_crypto::{KeyType, SecretKey};
+
+    use super::*;
+
+    fn peer_id() -> PeerId {
+        PeerId::from(SecretKey::from_random(KeyType::ED25519).public_key())
+    }
+
+    #[test]
+    fn test_minor_offences_accumulate() {
+        let mut policy = BanPolicy::new(BanPolicyConfig::default());
+        let peer = peer_id();
+        for _ in 0..4 {
+            assert_eq!(policy.report(&peer, ReasonForBan::HeightFraud), BanResponse::Decrease);
+        }
+        assert_eq!(policy.report(&peer, ReasonForBan::HeightFraud), BanResponse::TempBan);
+        // Score is restored after the temporary ban.
+        assert_eq!(policy.report(&peer, ReasonForBan::HeightFraud), BanResponse::Decrease);
+        // Other peers are not affected.
+        assert_eq!(policy.report(&peer_id(), ReasonForBan::HeightFraud), BanResponse::Decrease);
+        assert_eq!(policy.report(&peer, ReasonForBan::None), BanResponse::Decrease);
+    }
+
+    #[test]
+    fn test_repeated_bans_become_permanent() {
+        let mut policy = BanPolicy::new(BanPolicyConfig::default());
+        let peer = peer_id();
+        for _ in 0..3 {
+            assert_eq!(policy.report(&peer, ReasonForBan::BadBlock), BanResponse::TempBan);
+        }
+        assert_eq!(policy.report(&peer, ReasonForBan::BadStatePart), BanResponse::PermanentBan);
+        policy.forget(&peer);
+        assert_eq!(policy.report(&peer, ReasonForBan::BadBlock), BanResponse::TempBan);
+    }
+
+    #[test]
+    fn test_wrong_network_never_banned_permanently() {
+        let mut policy = BanPolicy::new(BanPolicyConfig::default());
+        let peer = peer_id();
+        for _ in 0..5 {
+            assert_eq!(policy.report(&peer, ReasonForBan::WrongNetwork), BanResponse::TempBan);
+        }
+        // Bans for being on another network don't count towards a permanent ban.
+        assert_eq!(policy.report(&peer, ReasonForBan::BadBlock), BanResponse::TempBan);
+    }
+
+    #[test]
+    fn test_records_are_bounded() {
+        let config = BanPolicyConfig { max_tracked_peers: 2, ..BanPolicyConfig::default() };
+        let mut policy = BanPolicy::new(config);
+        let peers = [peer_id(), peer_id(), peer_id()];
+        for peer in peers.iter() {
+            assert_eq!(policy.report(peer, ReasonForBan::HeightFraud), BanResponse::Decrease);
+        }
+        assert_eq!(policy.records.cache_size(), 2);
+        // Score of the least recently reported peer is forgotten.
+        for _ in 0..3 {
+            assert_eq!(policy.report(&peers[2], ReasonForBan::HeightFraud), BanResponse::Decrease);
+            assert_eq!(policy.report(&peers[0], ReasonForBan::HeightFraud), BanResponse::Decrease);
+        }
+        assert_eq!(policy.report(&peers[2], ReasonForBan::HeightFraud), BanResponse::TempBan);
+        assert_eq!(policy.report(&peers[0], ReasonForBan::HeightFraud), BanResponse::Decrease);
+    }
+}
@@ -0,0 +1,137 @@
+//! Limits of the upload and download rate, for all the peers together and for each peer. Messages
+//! to a peer over the limit are queued, and a peer over the download limit is not read from until
+//! enough time has passed.
+use std::cmp::{max, min};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+/// Bandwidth limits in bytes per second, `None` means unlimited.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct BandwidthConfig {
+    /// Maximum upload rate to all the peers together.
+    pub max_upload_bytes_per_sec: Option<u64>,
+    /// Maximum download rate from all the peers together.
+    pub max_download_bytes_per_sec: Option<u64>,
+    /// Maximum upload rate to a single peer.
+    pub max_peer_upload_bytes_per_sec: Option<u64>,
+    /// Maximum download rate from a single peer.
+    pub max_peer_download_bytes_per_sec: Option<u64>,
+}
+
+/// Token bucket that allows bursts of one second worth of traffic. A message larger than what is
+/// available is let through as long as the bucket is not in debt, and the debt is paid off before
+/// the next one.
+pub struct RateLimiter {
+    bytes_per_sec: u64,
+    /// Bytes that can be transferred right away, negative when in debt.
+    available: i64,
+    last_update: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = max(bytes_per_sec, 1);
+        RateLimiter { bytes_per_sec, available: bytes_per_sec as i64, last_update: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if now <= self.last_update {
+            return;
+        }
+        let elapsed = now.duration_since(self.last_update);
+        let refill = elapsed.as_micros() * self.bytes_per_sec as u128 / 1_000_000;
+        self.available = min(self.available + refill as i64, self.bytes_per_sec as i64);
+        self.last_update = now;
+    }
+
+    /// Time to wait at given moment before the next transfer can start.
+    pub fn delay(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        if self.available >= 0 {
+            Duration::from_millis(0)
+        } else {
+            let debt = (-self.available) as u64;
+            Duration::from_micros(debt * 1_000_000 / self.bytes_per_sec + 1)
+        }
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        self.available -= bytes as i64;
+    }
+}
+
+/// Limits transfers in one direction of the connection with a peer, by the limit of the peer and
+/// the limit shared by all the peers.
+pub struct Throttle {
+    global: Option<Arc<Mutex<RateLimiter>>>,
+    peer: Option<RateLimiter>,
+}
+
+impl Throttle {
+    pub fn new(global: Option<Arc<Mutex<RateLimiter>>>, peer_bytes_per_sec: Option<u64>) -> Self {
+        Throttle { global, peer: peer_bytes_per_sec.map(RateLimiter::new) }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.global.is_some() || self.peer.is_some()
+    }
+
+    /// Time to wait before the next transfer can start, zero if it can start right away.
+    pub fn delay(&mut self) -> Duration {
+        let now = Instant::now();
+        let peer_delay = self.peer.as_mut().map_or(Duration::from_millis(0), |peer| peer.delay(now));
+        let global_delay = self
+            .global
+            .as_ref()
+            .map_or(Duration::from_millis(0), |global| global.lock().unwrap().delay(now));
+        max(peer_delay, global_delay)
+    }
+
+    pub fn consume(&mut self, bytes: u64) {
+        if let Some(peer) = self.peer.as_mut() {
+            peer.consume(bytes);
+        }
+        if let Some(global) = self.global.as_ref() {
+            global.lock().unwrap().consume(bytes);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(1000);
+        let start = limiter.last_update;
+        assert_eq!(limiter.delay(start), Duration::from_millis(0));
+        // Burst of one second worth of traffic goes through, larger message puts limiter in debt.
+        limiter.consume(3000);
+        assert!(limiter.delay(start) > Duration::from_millis(1999));
+        assert!(limiter.delay(start + Duration::from_millis(1500)) > Duration::from_millis(0));
+        assert_eq!(limiter.delay(start + Duration::from_millis(2000)), Duration::from_millis(0));
+        // Unused bandwidth accumulates up to one second worth of traffic.
+        let later = start + Duration::from_secs(100);
+        limiter.delay(later);
+        limiter.consume(1000);
+        assert_eq!(limiter.delay(later), Duration::from_millis(0));
+        limiter.consume(1);
+        assert!(limiter.delay(later) > Duration::from_millis(0));
+    }
+
+    #[test]
+    fn test_throttle_shared_limit() {
+        let global = Arc::new(Mutex::new(RateLimiter::new(1000)));
+        let mut first = Throttle::new(Some(global.clone()), None);
+        let mut second = Throttle::new(Some(global), Some(1_000_000));
+        assert!(!Throttle::new(None, None).is_limited());
+        assert_eq!(second.delay(), Duration::from_millis(0));
+        first.consume(2000);
+        // Traffic of one peer counts against the limit of all the others.
+        assert!(second.delay() > Duration::from_millis(500));
+    }
+}
@@ -1,19 +1,129 @@
-use std::io::{Error, ErrorKind};
+use std::fmt;
+use std::io::{Error, ErrorKind, Read, Write};
 
 use bytes::{BufMut, BytesMut};
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
 use tokio::codec::{Decoder, Encoder};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 
-use crate::types::PeerMessage;
+use crate::encryption::SEAL_OVERHEAD;
+use crate::types::{PeerMessage, RoutedMessageBody};
 
+/// Serialized messages smaller than this are sent uncompressed.
+const MIN_COMPRESSED_MESSAGE_SIZE: usize = 1024;
+
+/// Maximum size of control messages, requests and other messages without a large payload.
+const MAX_SMALL_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// Maximum size of peer lists, routes, transactions and single headers.
+const MAX_MEDIUM_MESSAGE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Borsh enum tags of the variants of `PeerMessage`, in the order of declaration.
+mod tag {
+    pub const HANDSHAKE: u8 = 0;
+    pub const HANDSHAKE_FAILURE: u8 = 1;
+    pub const PEERS_REQUEST: u8 = 2;
+    pub const PEERS_RESPONSE: u8 = 3;
+    pub const BLOCK_HEADERS_REQUEST: u8 = 4;
+    pub const BLOCK_HEADERS: u8 = 5;
+    pub const BLOCK_HEADER_ANNOUNCE: u8 = 6;
+    pub const BLOCK_REQUEST: u8 = 7;
+    pub const BLOCK: u8 = 8;
+    pub const BLOCK_APPROVAL: u8 = 9;
+    pub const TRANSACTION: u8 = 10;
+    pub const STATE_REQUEST: u8 = 11;
+    pub const STATE_RESPONSE: u8 = 12;
+    pub const ANNOUNCE_ACCOUNT: u8 = 13;
+    pub const DISCONNECT: u8 = 14;
+    pub const BLOCK_HEADERS_RANGE_REQUEST: u8 = 15;
+    pub const ROUTES: u8 = 16;
+    pub const ROUTED: u8 = 17;
+    pub const COMPRESSED: u8 = 18;
+    pub const PING: u8 = 19;
+    pub const PONG: u8 = 20;
+}
+
+/// Maximum size of the serialized message with the tag, capped by the maximum size of any
+/// message, `None` for unknown tags.
+fn max_message_size(message_tag: u8, limit: usize) -> Option<usize> {
+    let max_size = match message_tag {
+        tag::HANDSHAKE
+        | tag::HANDSHAKE_FAILURE
+        | tag::PEERS_REQUEST
+        | tag::BLOCK_HEADERS_REQUEST
+        | tag::BLOCK_REQUEST
+        | tag::BLOCK_APPROVAL
+        | tag::STATE_REQUEST
+        | tag::ANNOUNCE_ACCOUNT
+        | tag::DISCONNECT
+        | tag::BLOCK_HEADERS_RANGE_REQUEST
+        | tag::PING
+        | tag::PONG => Some(MAX_SMALL_MESSAGE_SIZE),
+        tag::PEERS_RESPONSE | tag::BLOCK_HEADER_ANNOUNCE | tag::TRANSACTION | tag::ROUTES => {
+            Some(MAX_MEDIUM_MESSAGE_SIZE)
+        }
+        tag::BLOCK_HEADERS | tag::BLOCK | tag::STATE_RESPONSE | tag::ROUTED | tag::COMPRESSED => {
+            Some(limit)
+        }
+        _ => None,
+    };
+    max_size.map(|max_size| max_size.min(limit))
+}
+
+/// Reason the received bytes were not decoded into a message.
+#[derive(Debug)]
+pub enum DecodeError {
+    /// Message is larger than allowed for its type.
+    TooLarge { message_tag: u8, len: usize },
+    /// Message is of unknown type, fails to deserialize or to decompress.
+    Malformed(Error),
+}
+
+impl From<Error> for DecodeError {
+    fn from(err: Error) -> Self {
+        DecodeError::Malformed(err)
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::TooLarge { message_tag, len } => {
+                write!(f, "Message with tag {} is too large: {} bytes", message_tag, len)
+            }
+            DecodeError::Malformed(err) => write!(f, "Malformed message: {}", err),
+        }
+    }
+}
+
+/// Checks the type and the size of the serialized message before it is deserialized, so that
+/// a peer can't make the node allocate memory for a huge message of a type that is always small.
+fn check_message(bytes: &[u8], limit: usize) -> Result<(), DecodeError> {
+    let message_tag =
+        *bytes.first().ok_or_else(|| Error::new(ErrorKind::InvalidData, "Empty message"))?;
+    let max_size = max_message_size(message_tag, limit).ok_or_else(|| {
+        Error::new(ErrorKind::InvalidData, format!("Unknown message tag {}", message_tag))
+    })?;
+    if bytes.len() > max_size {
+        return Err(DecodeError::TooLarge { message_tag, len: bytes.len() });
+    }
+    Ok(())
+}
+
+/// Length-prefixed frames, frames announcing a length larger than the maximum size of a message
+/// are refused before they are buffered.
 pub struct Codec {
     max_length: u32,
 }
 
 impl Codec {
-    pub fn new() -> Self {
-        Codec { max_length: std::u32::MAX }
+    pub fn new(max_message_size: usize) -> Self {
+        // Encrypted frames carry the authentication tag in addition to the message.
+        let max_length = max_message_size.saturating_add(SEAL_OVERHEAD);
+        Codec { max_length: max_length.min(u32::max_value() as usize) as u32 }
     }
 }
 
@@ -46,6 +156,12 @@ impl Decoder for Codec {
         let mut len_bytes: [u8; 4] = [0; 4];
         len_bytes.copy_from_slice(&buf[0..4]);
         let len = unsafe { std::mem::transmute::<[u8; 4], u32>(len_bytes) }.to_le();
+        if len > self.max_length {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Frame of {} bytes is larger than the maximum {}", len, self.max_length),
+            ));
+        }
         if buf.len() < 4 + len as usize {
             // not enough bytes, keep waiting
             Ok(None)
@@ -61,38 +177,115 @@ pub fn peer_message_to_bytes(peer_message: PeerMessage) -> Result<Vec<u8>, std::
     peer_message.try_to_vec()
 }
 
-pub fn bytes_to_peer_message(bytes: &[u8]) -> Result<PeerMessage, std::io::Error> {
-    PeerMessage::try_from_slice(bytes)
+/// Whether the message carries large payload worth compressing: blocks, headers and state.
+pub fn is_compressible(peer_message: &PeerMessage) -> bool {
+    match peer_message {
+        PeerMessage::Block(_)
+        | PeerMessage::BlockHeaders(_, _)
+        | PeerMessage::StateResponse(_, _, _, _) => true,
+        PeerMessage::Routed(routed) => match routed.body {
+            RoutedMessageBody::Block(_) | RoutedMessageBody::StateResponse(_, _, _, _) => true,
+            _ => false,
+        },
+        _ => false,
+    }
+}
+
+/// Serializes the message and wraps it into `PeerMessage::Compressed` if it's large enough and
+/// compression makes it smaller.
+pub fn peer_message_to_compressed_bytes(
+    peer_message: PeerMessage,
+) -> Result<Vec<u8>, std::io::Error> {
+    let bytes = peer_message.try_to_vec()?;
+    if bytes.len() < MIN_COMPRESSED_MESSAGE_SIZE {
+        return Ok(bytes);
+    }
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(&bytes)?;
+    let compressed = encoder.finish()?;
+    if compressed.len() >= bytes.len() {
+        return Ok(bytes);
+    }
+    PeerMessage::Compressed(compressed).try_to_vec()
+}
+
+/// Validates and deserializes the message, decompressing it if it was sent compressed. Messages,
+/// including the decompressed ones, larger than `max_message_size` are refused.
+pub fn bytes_to_peer_message(
+    bytes: &[u8],
+    max_message_size: usize,
+) -> Result<PeerMessage, DecodeError> {
+    check_message(bytes, max_message_size)?;
+    match PeerMessage::try_from_slice(bytes)? {
+        PeerMessage::Compressed(compressed) => {
+            let mut bytes = vec![];
+            // One byte over the limit is enough to tell that the message is too large.
+            DeflateDecoder::new(&compressed[..])
+                .take(max_message_size as u64 + 1)
+                .read_to_end(&mut bytes)?;
+            check_message(&bytes, max_message_size)?;
+            match PeerMessage::try_from_slice(&bytes)? {
+                PeerMessage::Compressed(_) => Err(DecodeError::Malformed(Error::new(
+                    ErrorKind::InvalidData,
+                    "Nested compressed message",
+                ))),
+                peer_message => Ok(peer_message),
+            }
+        }
+        peer_message => Ok(peer_message),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::types::{Handshake, PeerChainInfo, PeerInfo};
+    use std::sync::Arc;
+
+    use chrono::Utc;
+
+    use near_chain::Block;
+    use near_crypto::{InMemorySigner, KeyType, Signature};
+    use near_primitives::hash::CryptoHash;
+    use near_primitives::transaction::SignedTransaction;
+    use near_primitives::types::StatePart;
+
+    use crate::types::{
+        AnnounceAccount, Handshake, HandshakeFailureReason, PeerChainInfo, PeerInfo, RoutedMessage,
+    };
 
     use super::*;
 
+    const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
     fn test_codec(msg: PeerMessage) {
-        let mut codec = Codec::new();
+        let mut codec = Codec::new(MAX_MESSAGE_SIZE);
         let mut buffer = BytesMut::new();
         codec.encode(peer_message_to_bytes(msg.clone()).unwrap(), &mut buffer).unwrap();
         let decoded = codec.decode(&mut buffer).unwrap().unwrap();
-        assert_eq!(bytes_to_peer_message(&decoded).unwrap(), msg);
+        assert_eq!(bytes_to_peer_message(&decoded, MAX_MESSAGE_SIZE).unwrap(), msg);
     }
 
-    #[test]
-    fn test_peer_message_handshake() {
+    fn fake_handshake() -> Handshake {
         let peer_info = PeerInfo::random();
-        let fake_handshake = Handshake {
-            version: 1,
+        Handshake {
+            protocol_version: 1,
             peer_id: peer_info.id,
             listen_port: None,
+            chain_id: "unittest".to_string(),
             chain_info: PeerChainInfo {
                 genesis: Default::default(),
                 height: 0,
                 total_weight: 0.into(),
             },
-        };
-        let msg = PeerMessage::Handshake(fake_handshake);
+            private: false,
+            compression: true,
+            observed_addr: Some("127.0.0.1:24567".parse().unwrap()),
+            encryption: None,
+        }
+    }
+
+    #[test]
+    fn test_peer_message_handshake() {
+        let msg = PeerMessage::Handshake(fake_handshake());
         test_codec(msg);
     }
 
@@ -103,4 +296,150 @@ mod test {
         let msg = PeerMessage::PeersResponse(vec![peer_info1, peer_info2]);
         test_codec(msg);
     }
+
+    #[test]
+    fn test_peer_message_compression() {
+        let small = PeerMessage::PeersResponse(vec![PeerInfo::random()]);
+        assert_eq!(
+            peer_message_to_compressed_bytes(small.clone()).unwrap(),
+            peer_message_to_bytes(small).unwrap()
+        );
+
+        let large = PeerMessage::PeersResponse(vec![PeerInfo::random(); 100]);
+        let compressed = peer_message_to_compressed_bytes(large.clone()).unwrap();
+        assert!(compressed.len() < peer_message_to_bytes(large.clone()).unwrap().len());
+        assert_eq!(bytes_to_peer_message(&compressed, MAX_MESSAGE_SIZE).unwrap(), large);
+
+        // Compressed message inside of a compressed message is refused.
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&PeerMessage::Compressed(vec![]).try_to_vec().unwrap()).unwrap();
+        let nested = PeerMessage::Compressed(encoder.finish().unwrap()).try_to_vec().unwrap();
+        assert!(bytes_to_peer_message(&nested, MAX_MESSAGE_SIZE).is_err());
+    }
+
+    /// Every tag matches the Borsh tag of its variant and has a size limit.
+    #[test]
+    fn test_message_tags() {
+        let hash = CryptoHash::default();
+        let signature = Signature::empty(KeyType::ED25519);
+        let block = Block::genesis(hash, Utc::now());
+        let signer =
+            Arc::new(InMemorySigner::from_seed("alice.near", KeyType::ED25519, "alice.near"));
+        let transaction = SignedTransaction::send_money(
+            1,
+            "alice.near".to_string(),
+            "bob.near".to_string(),
+            signer,
+            1,
+            hash,
+        );
+        let part = StatePart {
+            part_id: 0,
+            num_parts: 1,
+            start_key: vec![],
+            end_key: None,
+            data: vec![],
+            proof: vec![],
+        };
+        let announce_account =
+            AnnounceAccount { account_id: "alice.near".to_string(), epoch: hash, route: vec![] };
+        let routed = RoutedMessage {
+            target: PeerInfo::random().id,
+            author: PeerInfo::random().id,
+            signature: signature.clone(),
+            ttl: 0,
+            body: RoutedMessageBody::StateRequest(0, hash, 0),
+        };
+        let messages = vec![
+            (PeerMessage::Handshake(fake_handshake()), tag::HANDSHAKE),
+            (
+                PeerMessage::HandshakeFailure(
+                    PeerInfo::random(),
+                    HandshakeFailureReason::ProtocolVersionMismatch(0),
+                ),
+                tag::HANDSHAKE_FAILURE,
+            ),
+            (PeerMessage::PeersRequest, tag::PEERS_REQUEST),
+            (PeerMessage::PeersResponse(vec![]), tag::PEERS_RESPONSE),
+            (PeerMessage::BlockHeadersRequest(vec![]), tag::BLOCK_HEADERS_REQUEST),
+            (PeerMessage::BlockHeaders(vec![], false), tag::BLOCK_HEADERS),
+            (PeerMessage::BlockHeaderAnnounce(block.header.clone()), tag::BLOCK_HEADER_ANNOUNCE),
+            (PeerMessage::BlockRequest(hash), tag::BLOCK_REQUEST),
+            (PeerMessage::Block(block), tag::BLOCK),
+            (
+                PeerMessage::BlockApproval("alice.near".to_string(), hash, signature),
+                tag::BLOCK_APPROVAL,
+            ),
+            (PeerMessage::Transaction(transaction), tag::TRANSACTION),
+            (PeerMessage::StateRequest(0, hash, 0), tag::STATE_REQUEST),
+            (PeerMessage::StateResponse(0, hash, part, vec![]), tag::STATE_RESPONSE),
+            (PeerMessage::AnnounceAccount(announce_account), tag::ANNOUNCE_ACCOUNT),
+            (PeerMessage::Disconnect, tag::DISCONNECT),
+            (PeerMessage::BlockHeadersRangeRequest(vec![], 0), tag::BLOCK_HEADERS_RANGE_REQUEST),
+            (PeerMessage::Routes(vec![]), tag::ROUTES),
+            (PeerMessage::Routed(routed), tag::ROUTED),
+            (PeerMessage::Compressed(vec![]), tag::COMPRESSED),
+            (PeerMessage::Ping(0), tag::PING),
+            (PeerMessage::Pong(0), tag::PONG),
+        ];
+        assert_eq!(messages.len(), tag::PONG as usize + 1);
+        for (msg, message_tag) in messages {
+            assert_eq!(peer_message_to_bytes(msg).unwrap()[0], message_tag);
+            assert!(max_message_size(message_tag, MAX_MESSAGE_SIZE).is_some());
+        }
+    }
+
+    #[test]
+    fn test_malformed_messages() {
+        let valid = peer_message_to_bytes(PeerMessage::PeersResponse(vec![PeerInfo::random()]));
+        let valid = valid.unwrap();
+        // Truncated, with trailing bytes, empty and of unknown type.
+        assert!(bytes_to_peer_message(&valid[..valid.len() - 1], MAX_MESSAGE_SIZE).is_err());
+        let mut trailing = valid.clone();
+        trailing.push(0);
+        assert!(bytes_to_peer_message(&trailing, MAX_MESSAGE_SIZE).is_err());
+        assert!(bytes_to_peer_message(&[], MAX_MESSAGE_SIZE).is_err());
+        assert!(bytes_to_peer_message(&[tag::PONG + 1], MAX_MESSAGE_SIZE).is_err());
+
+        // Small message padded beyond the limit of its type is refused before deserialization.
+        let mut padded = vec![tag::BLOCK_REQUEST];
+        padded.resize(MAX_SMALL_MESSAGE_SIZE + 1, 0);
+        match bytes_to_peer_message(&padded, MAX_MESSAGE_SIZE) {
+            Err(DecodeError::TooLarge { message_tag, .. }) => {
+                assert_eq!(message_tag, tag::BLOCK_REQUEST)
+            }
+            _ => panic!("Expected too large message"),
+        }
+
+        // Large message is refused beyond the configured limit, also when it's compressed.
+        let mut padded = vec![tag::BLOCK];
+        padded.resize(1025, 0);
+        match bytes_to_peer_message(&padded, 1024) {
+            Err(DecodeError::TooLarge { message_tag, .. }) => assert_eq!(message_tag, tag::BLOCK),
+            _ => panic!("Expected too large message"),
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&padded).unwrap();
+        let compressed = PeerMessage::Compressed(encoder.finish().unwrap()).try_to_vec().unwrap();
+        assert!(compressed.len() <= 1024);
+        match bytes_to_peer_message(&compressed, 1024) {
+            Err(DecodeError::TooLarge { message_tag, .. }) => assert_eq!(message_tag, tag::BLOCK),
+            _ => panic!("Expected too large message"),
+        }
+
+        // Garbage instead of the deflate stream.
+        let garbage = PeerMessage::Compressed(vec![0xff; 16]).try_to_vec().unwrap();
+        assert!(bytes_to_peer_message(&garbage, MAX_MESSAGE_SIZE).is_err());
+    }
+
+    #[test]
+    fn test_frame_length_limit() {
+        let mut codec = Codec::new(MAX_MESSAGE_SIZE);
+        let mut buffer = BytesMut::new();
+        buffer.put_u32_le((MAX_MESSAGE_SIZE + SEAL_OVERHEAD) as u32);
+        assert!(codec.decode(&mut buffer).unwrap().is_none());
+        let mut buffer = BytesMut::new();
+        buffer.put_u32_le((MAX_MESSAGE_SIZE + SEAL_OVERHEAD) as u32 + 1);
+        assert!(codec.decode(&mut buffer).is_err());
+    }
 }
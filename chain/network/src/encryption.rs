@@ -0,0 +1,139 @@
+//! Authenticated encryption of the connection between peers. Each side of the connection offers
+//! an ephemeral X25519 key in the handshake, signed with its node key, so the session keys can
+//! only be derived by the holders of the peer ids. Frames after the handshake are sealed with
+//! ChaCha20-Poly1305, using separate keys and counter nonces for each direction.
+use sodiumoxide::crypto::aead::chacha20poly1305_ietf::{self as aead, Key, Nonce};
+use sodiumoxide::crypto::kx;
+
+use near_crypto::SecretKey;
+
+use crate::types::{EncryptionOffer, PeerId};
+
+/// Number of bytes a sealed frame is longer than the message.
+pub const SEAL_OVERHEAD: usize = aead::TAGBYTES;
+
+/// Initializes libsodium, which makes its random number generator and the choice of the cipher
+/// implementations safe to use from several threads. Must be called before any handshake key is
+/// generated, calling it again has no effect.
+pub fn init() -> Result<(), String> {
+    sodiumoxide::init().map_err(|()| "Failed to initialize libsodium".to_string())
+}
+
+/// Ephemeral key pair of this node for a single connection.
+pub struct HandshakeKey {
+    public_key: kx::PublicKey,
+    secret_key: kx::SecretKey,
+    offer: EncryptionOffer,
+}
+
+impl HandshakeKey {
+    pub fn new(node_secret_key: &SecretKey) -> Self {
+        let (public_key, secret_key) = kx::gen_keypair();
+        let signature = node_secret_key.sign(&public_key.0);
+        let offer = EncryptionOffer { public_key: public_key.0.to_vec(), signature };
+        HandshakeKey { public_key, secret_key, offer }
+    }
+
+    /// Offer sent to the peer in the handshake.
+    pub fn offer(&self) -> EncryptionOffer {
+        self.offer.clone()
+    }
+
+    /// Derives the ciphers for sent and received frames from the offer of the peer, after
+    /// checking the offer is signed by the peer. The side that dialed the connection is the
+    /// initiator.
+    pub fn session(
+        &self,
+        peer_id: &PeerId,
+        offer: &EncryptionOffer,
+        initiator: bool,
+    ) -> Result<(Cipher, Cipher), String> {
+        if !offer.signature.verify(&offer.public_key, &peer_id.public_key()) {
+            return Err("Encryption offer is not signed by the peer".to_string());
+        }
+        let peer_key = kx::PublicKey::from_slice(&offer.public_key)
+            .ok_or_else(|| "Invalid encryption key".to_string())?;
+        let (receive_key, send_key) = if initiator {
+            kx::client_session_keys(&self.public_key, &self.secret_key, &peer_key)
+        } else {
+            kx::server_session_keys(&self.public_key, &self.secret_key, &peer_key)
+        }
+        .map_err(|_| "Failed to derive session keys".to_string())?;
+        Ok((Cipher::new(send_key), Cipher::new(receive_key)))
+    }
+}
+
+/// Seals or opens the frames of one direction of the connection, in the order they are sent.
+pub struct Cipher {
+    key: Key,
+    nonce: u64,
+}
+
+impl Cipher {
+    fn new(session_key: kx::SessionKey) -> Self {
+        Cipher { key: Key(session_key.0), nonce: 0 }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let mut nonce = [0u8; aead::NONCEBYTES];
+        nonce[..8].copy_from_slice(&self.nonce.to_le_bytes());
+        self.nonce += 1;
+        Nonce(nonce)
+    }
+
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let nonce = self.next_nonce();
+        aead::seal(data, None, &nonce, &self.key)
+    }
+
+    /// Fails if the frame was tampered with, replayed or sent out of order.
+    pub fn open(&mut self, data: &[u8]) -> Result<Vec<u8>, String> {
+        let nonce = self.next_nonce();
+        aead::open(data, None, &nonce, &self.key)
+            .map_err(|_| "Failed to decrypt the message".to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_crypto::KeyType;
+
+    use super::*;
+
+    fn node_key() -> (PeerId, SecretKey) {
+        let secret_key = SecretKey::from_random(KeyType::ED25519);
+        (PeerId::from(secret_key.public_key()), secret_key)
+    }
+
+    #[test]
+    fn test_session() {
+        let (id1, secret_key1) = node_key();
+        let (id2, secret_key2) = node_key();
+        let key1 = HandshakeKey::new(&secret_key1);
+        let key2 = HandshakeKey::new(&secret_key2);
+        let (mut send1, mut receive1) = key1.session(&id2, &key2.offer(), true).unwrap();
+        let (mut send2, mut receive2) = key2.session(&id1, &key1.offer(), false).unwrap();
+
+        let sealed = send1.seal(b"ping");
+        assert_ne!(&sealed[..4], b"ping");
+        assert_eq!(receive2.open(&sealed).unwrap(), b"ping");
+        assert_eq!(receive1.open(&send2.seal(b"pong")).unwrap(), b"pong");
+
+        // Tampered and replayed frames are rejected.
+        let mut tampered = send1.seal(b"data");
+        tampered[0] ^= 1;
+        assert!(receive2.open(&tampered).is_err());
+        assert!(receive2.open(&sealed).is_err());
+    }
+
+    #[test]
+    fn test_offer_of_other_peer() {
+        let (_, secret_key1) = node_key();
+        let (id2, _) = node_key();
+        let (_, secret_key3) = node_key();
+        let key1 = HandshakeKey::new(&secret_key1);
+        let key3 = HandshakeKey::new(&secret_key3);
+        // Peer claiming id2 with the offer signed by another node.
+        assert!(key1.session(&id2, &key3.offer(), true).is_err());
+    }
+}
@@ -1,14 +1,24 @@
+pub use observer::NetworkEventsObserver;
 pub use peer_manager::PeerManagerActor;
 pub use types::{
-    FullPeerInfo, NetworkClientMessages, NetworkClientResponses, NetworkConfig, NetworkRequests,
-    NetworkResponses, PeerInfo,
+    CorrelationId, FullPeerInfo, NetworkClientMessages, NetworkClientResponses, NetworkConfig,
+    NetworkRequests, NetworkResponses, PeerInfo,
 };
 
+pub mod ban_policy;
+pub mod bandwidth;
 mod codec;
+mod encryption;
+mod observer;
 mod peer;
 mod peer_manager;
+pub mod peer_score;
 pub mod peer_store;
+pub mod send_queue;
+mod socks5;
 pub mod types;
+mod upnp;
 mod rate_counter;
+mod routing;
 
 pub mod test_utils;
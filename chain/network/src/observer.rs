@@ -0,0 +1,23 @@
+//! Hooks to observe network events without changing the peer manager and peer actors.
+
+use crate::types::{FullPeerInfo, PeerId, PeerMessage, ReasonForBan};
+
+/// Observer of the network events, registered on the `PeerManagerActor`.
+/// Hooks are called synchronously from the network actors, so they must be cheap.
+/// All hooks do nothing by default.
+pub trait NetworkEventsObserver: Send + Sync {
+    /// Peer finished handshake and became active.
+    fn peer_connected(&self, _peer_info: &FullPeerInfo) {}
+
+    /// Active peer disconnected or was removed.
+    fn peer_disconnected(&self, _peer_id: &PeerId) {}
+
+    /// Message received from the peer. Peer id is not known until handshake is finished.
+    fn message_received(&self, _peer_id: Option<&PeerId>, _msg: &PeerMessage) {}
+
+    /// Message sent to the peer. Peer id is not known until handshake is finished.
+    fn message_sent(&self, _peer_id: Option<&PeerId>, _msg: &PeerMessage) {}
+
+    /// Peer was banned for given reason.
+    fn peer_banned(&self, _peer_id: &PeerId, _ban_reason: ReasonForBan) {}
+}
@@ -1,26 +1,42 @@
-use std::cmp::max;
+use std::cmp::{max, min};
+use std::collections::HashMap;
 use std::io;
 use std::net::SocketAddr;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use actix::io::{FramedWrite, WriteHandler};
 use actix::{
     Actor, ActorContext, ActorFuture, Addr, AsyncContext, Context, ContextFutureSpawner, Handler,
     Recipient, Running, StreamHandler, WrapFuture,
 };
+use chrono::{DateTime, Utc};
+use futures::future::Future;
 use log::{debug, error, info, warn};
 use tokio::io::WriteHalf;
 use tokio::net::TcpStream;
+use tokio::timer::Delay;
 
 use near_primitives::hash::CryptoHash;
+use near_primitives::types::ShardId;
 use near_primitives::utils::DisplayOption;
 
-use crate::codec::{bytes_to_peer_message, peer_message_to_bytes, Codec};
+use crate::ban_policy::BanResponse;
+use crate::bandwidth::Throttle;
+use crate::codec::{
+    bytes_to_peer_message, is_compressible, peer_message_to_bytes,
+    peer_message_to_compressed_bytes, Codec, DecodeError,
+};
+use crate::encryption::{Cipher, HandshakeKey};
+use crate::observer::NetworkEventsObserver;
+use crate::peer_score::PeerScore;
 use crate::rate_counter::RateCounter;
+use crate::send_queue::{MessagePriority, SendQueue};
 use crate::types::{
-    Ban, Consolidate, Handshake, NetworkClientMessages, PeerChainInfo, PeerInfo, PeerMessage,
-    PeerStatsResult, PeerStatus, PeerType, PeersRequest, PeersResponse, QueryPeerStats,
-    ReasonForBan, SendMessage, Unregister, HandshakeFailureReason, PROTOCOL_VERSION
+    Consolidate, CorrelationId, Handshake, NetworkClientMessages, PeerChainInfo, PeerId, PeerInfo,
+    PeerMessage, PeerStatsResult, PeerStatus, PeerType, PeersRequest, PeersResponse,
+    QueryPeerStats, ReasonForBan, ReportMisbehavior, RoutedMessageFrom, RoutesUpdate, SendMessage,
+    Unregister, HandshakeFailureReason, PROTOCOL_VERSION
 };
 use crate::{NetworkClientResponses, PeerManagerActor};
 
@@ -31,6 +47,32 @@ const MAX_TRACK_SIZE: usize = 30;
 // TODO: current limit is way to high due to us sending lots of messages during sync.
 const MAX_PEER_MSG_PER_MIN: u64 = 50000;
 
+/// Number of seconds of measured peer bandwidth a single headers response is allowed to take.
+const HEADERS_RESPONSE_WINDOW_SECS: u64 = 5;
+
+/// Minimum size in bytes of the headers response, used while peer bandwidth is not known yet.
+const MIN_HEADERS_RESPONSE_SIZE: u64 = 64 * 1024;
+
+/// Time after which the sync request sent to the peer is counted as timed out.
+const SYNC_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often messages held back by the upload limit are sent.
+const SEND_QUEUE_FLUSH_PERIOD: Duration = Duration::from_millis(10);
+
+/// Time given to the handshake failure to reach the peer before the connection is closed.
+const HANDSHAKE_FAILURE_DISCONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// First network protocol version that supports ping and pong messages.
+const MIN_PING_PROTOCOL_VERSION: u32 = 8;
+
+/// Sync request sent to the peer and awaiting the response.
+#[derive(Hash, PartialEq, Eq)]
+enum SyncRequest {
+    Headers,
+    Block(CryptoHash),
+    StatePart(ShardId, CryptoHash, u64),
+}
+
 /// Keeps track of requests and received hashes of transactions and blocks.
 /// Also keeps track of number of bytes sent and received from this peer to prevent abuse.
 pub struct Tracker {
@@ -42,6 +84,10 @@ pub struct Tracker {
     requested: Vec<CryptoHash>,
     /// Received elements.
     received: Vec<CryptoHash>,
+    /// Sync requests awaiting the response, with the time they were sent.
+    sync_requests: HashMap<SyncRequest, Instant>,
+    /// Responses to sync requests since the score was last taken.
+    score: PeerScore,
 }
 
 impl Default for Tracker {
@@ -51,6 +97,8 @@ impl Default for Tracker {
             received_bytes: RateCounter::new(),
             requested: Default::default(),
             received: Default::default(),
+            sync_requests: Default::default(),
+            score: Default::default(),
         }
     }
 }
@@ -89,6 +137,35 @@ impl Tracker {
             self.requested.insert(0, hash);
         }
     }
+
+    fn push_sync_request(&mut self, request: SyncRequest) {
+        self.sync_requests.entry(request).or_insert_with(Instant::now);
+    }
+
+    /// Records latency of the response if it answers a pending sync request.
+    fn sync_response(&mut self, request: SyncRequest) {
+        if let Some(sent_at) = self.sync_requests.remove(&request) {
+            self.score.record_response(sent_at.elapsed().as_millis() as u64);
+        }
+    }
+
+    /// Counts pending sync requests sent longer than the timeout ago as timed out.
+    fn expire_sync_requests(&mut self) {
+        let score = &mut self.score;
+        self.sync_requests.retain(|_, sent_at| {
+            if sent_at.elapsed() > SYNC_REQUEST_TIMEOUT {
+                score.record_timeout();
+                return false;
+            }
+            true
+        });
+    }
+
+    /// Returns responses to sync requests since the previous call.
+    fn take_score(&mut self) -> PeerScore {
+        self.expire_sync_requests();
+        std::mem::replace(&mut self.score, PeerScore::default())
+    }
 }
 
 pub struct Peer {
@@ -112,10 +189,54 @@ pub struct Peer {
     client_addr: Recipient<NetworkClientMessages>,
     /// Tracker for requests and responses.
     tracker: Tracker,
+    /// This node chain id.
+    chain_id: String,
     /// This node genesis hash.
     genesis: CryptoHash,
     /// Latest chain info from the peer.
     chain_info: PeerChainInfo,
+    /// Maximum size of a message in bytes, also caps the block headers response.
+    max_message_size: u64,
+    /// Minimum network protocol version this peer must have to be accepted.
+    min_peer_protocol_version: u32,
+    /// This node is a private validator, see `Handshake::private`.
+    private: bool,
+    /// This node wants large messages compressed, see `Handshake::compression`.
+    enable_compression: bool,
+    /// Both this node and the peer want large messages compressed, known after handshake.
+    compression: bool,
+    /// Ephemeral key offered to the peer, `None` if this node doesn't encrypt connections.
+    handshake_key: Option<HandshakeKey>,
+    /// Peers that don't encrypt the connection are refused.
+    require_encryption: bool,
+    /// This node's handshake was sent, frames after it are encrypted if the session is set up.
+    handshake_sent: bool,
+    /// Encrypts sent frames, set once both peers offered their keys.
+    send_cipher: Option<Cipher>,
+    /// Decrypts received frames, set once both peers offered their keys.
+    receive_cipher: Option<Cipher>,
+    /// Observers of the network events.
+    observers: Vec<Arc<dyn NetworkEventsObserver>>,
+    /// Limits of the upload rate to this peer.
+    upload: Throttle,
+    /// Limits of the download rate from this peer.
+    download: Throttle,
+    /// Serialized messages waiting for the upload limit to allow sending them.
+    send_queue: SendQueue,
+    /// Peers were requested from the peer and the response is awaited.
+    peers_requested: bool,
+    /// How often to ping the peer.
+    ping_period: Duration,
+    /// Peer is disconnected if nothing was received from it for this long.
+    silence_timeout: Duration,
+    /// Network protocol version the peer reported in the handshake.
+    peer_protocol_version: u32,
+    /// Nonce of the ping awaiting the pong and the time it was sent.
+    ping: Option<(u64, Instant)>,
+    /// Round trip time of the last answered ping.
+    latency: Option<Duration>,
+    /// Last time anything was received from the peer.
+    last_seen: DateTime<Utc>,
 }
 
 impl Peer {
@@ -128,6 +249,17 @@ impl Peer {
         handshake_timeout: Duration,
         peer_manager_addr: Addr<PeerManagerActor>,
         client_addr: Recipient<NetworkClientMessages>,
+        max_message_size: u64,
+        min_peer_protocol_version: u32,
+        private: bool,
+        enable_compression: bool,
+        handshake_key: Option<HandshakeKey>,
+        require_encryption: bool,
+        observers: Vec<Arc<dyn NetworkEventsObserver>>,
+        upload: Throttle,
+        download: Throttle,
+        ping_period: Duration,
+        silence_timeout: Duration,
     ) -> Self {
         Peer {
             node_info,
@@ -140,40 +272,123 @@ impl Peer {
             peer_manager_addr,
             client_addr,
             tracker: Default::default(),
+            chain_id: Default::default(),
             genesis: Default::default(),
             chain_info: Default::default(),
+            max_message_size,
+            min_peer_protocol_version,
+            private,
+            enable_compression,
+            compression: false,
+            handshake_key,
+            require_encryption,
+            handshake_sent: false,
+            send_cipher: None,
+            receive_cipher: None,
+            observers,
+            upload,
+            download,
+            send_queue: SendQueue::default(),
+            peers_requested: false,
+            ping_period,
+            silence_timeout,
+            peer_protocol_version: 0,
+            ping: None,
+            latency: None,
+            last_seen: Utc::now(),
         }
     }
 
+    /// Peer id, known after handshake.
+    fn peer_id(&self) -> Option<&PeerId> {
+        self.peer_info.as_ref().as_ref().map(|peer_info| &peer_info.id)
+    }
+
     /// Whether the peer is considered abusive due to sending too many messages.
     fn is_abusive(&self) -> bool {
         self.tracker.received_bytes.count_per_min() > MAX_PEER_MSG_PER_MIN
             || self.tracker.sent_bytes.count_per_min() > MAX_PEER_MSG_PER_MIN
     }
 
+    /// Maximum size in bytes of the headers response for this peer, based on measured bandwidth.
+    fn headers_response_size(&self) -> u64 {
+        let bytes_per_sec = max(
+            self.tracker.sent_bytes.bytes_per_min(),
+            self.tracker.received_bytes.bytes_per_min(),
+        ) / 60;
+        min(
+            self.max_message_size,
+            max(MIN_HEADERS_RESPONSE_SIZE, bytes_per_sec * HEADERS_RESPONSE_WINDOW_SECS),
+        )
+    }
+
     fn send_message(&mut self, msg: PeerMessage) {
         // Skip sending block and headers if we received it or header from this peer.
         // Record block requests in tracker.
         match &msg {
             PeerMessage::Block(b) if self.tracker.has_received(b.hash()) => return,
             PeerMessage::BlockHeaderAnnounce(h) if self.tracker.has_received(h.hash()) => return,
-            PeerMessage::BlockRequest(h) => self.tracker.push_request(*h),
+            PeerMessage::BlockRequest(h) => {
+                self.tracker.push_request(*h);
+                self.tracker.push_sync_request(SyncRequest::Block(*h));
+            }
+            PeerMessage::BlockHeadersRequest(_) | PeerMessage::BlockHeadersRangeRequest(_, _) => {
+                self.tracker.push_sync_request(SyncRequest::Headers)
+            }
+            PeerMessage::StateRequest(shard_id, hash, part_id) => {
+                self.tracker.push_sync_request(SyncRequest::StatePart(*shard_id, *hash, *part_id))
+            }
+            PeerMessage::PeersRequest => self.peers_requested = true,
             _ => (),
         };
         debug!(target: "network", "{:?}: Sending {:?} message to peer {}", self.node_info.id, msg, self.peer_info);
-        match peer_message_to_bytes(msg) {
+        let priority = MessagePriority::of(&msg);
+        for observer in self.observers.iter() {
+            observer.message_sent(self.peer_id(), &msg);
+        }
+        let bytes = if self.compression && is_compressible(&msg) {
+            peer_message_to_compressed_bytes(msg)
+        } else {
+            peer_message_to_bytes(msg)
+        };
+        match bytes {
             Ok(bytes) => {
-                self.tracker.increment_sent(bytes.len() as u64);
-                self.framed.write(bytes);
+                if self.send_queue.push(priority, bytes) {
+                    self.flush_send_queue();
+                } else {
+                    warn!(target: "network", "Dropping {:?} priority message to {}, send queue is full", priority, self.peer_info);
+                }
             }
             Err(err) => error!(target: "network", "Error converting proto to bytes: {}", err),
         };
     }
 
+    fn write_bytes(&mut self, bytes: Vec<u8>) {
+        let bytes = match self.send_cipher.as_mut() {
+            Some(cipher) if self.handshake_sent => cipher.seal(&bytes),
+            _ => bytes,
+        };
+        let len = bytes.len() as u64;
+        self.tracker.increment_sent(len);
+        self.upload.consume(len);
+        self.framed.write(bytes);
+    }
+
+    /// Sends queued messages from the highest priority, as many as the upload limit allows.
+    fn flush_send_queue(&mut self) {
+        while self.upload.delay() == Duration::from_millis(0) {
+            match self.send_queue.pop() {
+                Some(bytes) => self.write_bytes(bytes),
+                None => break,
+            }
+        }
+    }
+
     fn fetch_client_chain_info(&mut self, ctx: &mut Context<Peer>) {
         ctx.wait(self.client_addr.send(NetworkClientMessages::GetChainInfo).into_actor(self).then(
             move |res, act, _ctx| match res {
-                Ok(NetworkClientResponses::ChainInfo { genesis, .. }) => {
+                Ok(NetworkClientResponses::ChainInfo { chain_id, genesis, .. }) => {
+                    act.chain_id = chain_id;
                     act.genesis = genesis;
                     actix::fut::ok(())
                 }
@@ -191,13 +406,23 @@ impl Peer {
             .send(NetworkClientMessages::GetChainInfo)
             .into_actor(self)
             .then(move |res, act, _ctx| match res {
-                Ok(NetworkClientResponses::ChainInfo { genesis, height, total_weight }) => {
+                Ok(NetworkClientResponses::ChainInfo {
+                    chain_id,
+                    genesis,
+                    height,
+                    total_weight,
+                }) => {
                     let handshake = Handshake::new(
                         act.node_info.id,
                         act.node_info.addr_port(),
+                        chain_id,
                         PeerChainInfo { genesis, height, total_weight },
+                        act.private,
+                        act.enable_compression,
+                        Some(act.peer_addr),
+                        act.handshake_key.as_ref().map(|key| key.offer()),
                     );
-                    act.send_message(PeerMessage::Handshake(handshake));
+                    act.write_handshake(handshake);
                     actix::fut::ok(())
                 }
                 Err(err) => {
@@ -209,6 +434,75 @@ impl Peer {
             .spawn(ctx);
     }
 
+    /// Writes the handshake right away, ahead of the queued messages, since the frames after it
+    /// are encrypted.
+    fn write_handshake(&mut self, handshake: Handshake) {
+        let msg = PeerMessage::Handshake(handshake);
+        for observer in self.observers.iter() {
+            observer.message_sent(self.peer_id(), &msg);
+        }
+        match peer_message_to_bytes(msg) {
+            Ok(bytes) => {
+                self.write_bytes(bytes);
+                self.handshake_sent = true;
+            }
+            Err(err) => error!(target: "network", "Error converting proto to bytes: {}", err),
+        }
+    }
+
+    /// Sets up encryption of the connection from the handshake of the peer. Returns whether the
+    /// connection can proceed, which requires it to be encrypted if this node requires that.
+    fn setup_encryption(&mut self, handshake: &Handshake) -> bool {
+        let (key, offer) = match (self.handshake_key.as_ref(), handshake.encryption.as_ref()) {
+            (Some(key), Some(offer)) => (key, offer),
+            _ => {
+                if self.require_encryption {
+                    info!(target: "network", "Peer {} doesn't encrypt the connection, which is required", handshake.peer_id);
+                    return false;
+                }
+                return true;
+            }
+        };
+        let initiator = self.peer_type == PeerType::Outbound;
+        match key.session(&handshake.peer_id, offer, initiator) {
+            Ok((send_cipher, receive_cipher)) => {
+                self.send_cipher = Some(send_cipher);
+                self.receive_cipher = Some(receive_cipher);
+                true
+            }
+            Err(err) => {
+                warn!(target: "network", "Failed to set up encryption with {}: {}", handshake.peer_id, err);
+                false
+            }
+        }
+    }
+
+    /// Reports misbehavior of this peer to the peer manager and disconnects if it was banned.
+    fn report_misbehavior(&mut self, ctx: &mut Context<Peer>, reason: ReasonForBan) {
+        let peer_id = match self.peer_info.as_ref() {
+            Some(peer_info) => peer_info.id,
+            None => return,
+        };
+        self.peer_manager_addr
+            .send(ReportMisbehavior { peer_id, reason })
+            .into_actor(self)
+            .then(move |res, act, ctx| {
+                match res {
+                    Ok(BanResponse::Decrease) => {}
+                    Ok(BanResponse::TempBan) | Ok(BanResponse::PermanentBan) => {
+                        act.peer_status = PeerStatus::Banned(reason);
+                        ctx.stop();
+                    }
+                    Err(err) => {
+                        error!(target: "network", "Failed sending report to peer manager: {}", err);
+                        ctx.stop();
+                    }
+                }
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
     /// Process non handshake/peer related messages.
     fn receive_client_message(&mut self, ctx: &mut Context<Peer>, msg: PeerMessage) {
         let peer_id = match self.peer_info.as_ref() {
@@ -218,11 +512,15 @@ impl Peer {
             }
         };
 
+        let correlation_id = CorrelationId::random();
+        debug!(target: "network", "correlation_id={} Received {} from {}", correlation_id, msg, self.peer_info);
+
         // Wrap peer message into what client expects.
         let network_client_msg = match msg {
             PeerMessage::Block(block) => {
                 let block_hash = block.hash();
                 self.tracker.push_received(block_hash);
+                self.tracker.sync_response(SyncRequest::Block(block_hash));
                 self.chain_info.height = max(self.chain_info.height, block.header.inner.height);
                 self.chain_info.total_weight =
                     max(self.chain_info.total_weight, block.header.inner.total_weight);
@@ -244,21 +542,30 @@ impl Peer {
             }
             PeerMessage::BlockRequest(hash) => NetworkClientMessages::BlockRequest(hash),
             PeerMessage::BlockHeadersRequest(hashes) => {
-                NetworkClientMessages::BlockHeadersRequest(hashes)
+                NetworkClientMessages::BlockHeadersRequest(hashes, 0, self.headers_response_size())
+            }
+            PeerMessage::BlockHeadersRangeRequest(hashes, skip) => {
+                NetworkClientMessages::BlockHeadersRequest(
+                    hashes,
+                    skip,
+                    self.headers_response_size(),
+                )
             }
-            PeerMessage::BlockHeaders(headers) => {
-                NetworkClientMessages::BlockHeaders(headers, peer_id)
+            PeerMessage::BlockHeaders(headers, more_available) => {
+                self.tracker.sync_response(SyncRequest::Headers);
+                NetworkClientMessages::BlockHeaders(headers, peer_id, more_available)
             }
-            PeerMessage::StateRequest(shard_id, hash) => {
-                NetworkClientMessages::StateRequest(shard_id, hash)
+            PeerMessage::StateRequest(shard_id, hash, part_id) => {
+                NetworkClientMessages::StateRequest(shard_id, hash, part_id)
             }
-            PeerMessage::StateResponse(shard_id, hash, payload, receipts) => {
-                NetworkClientMessages::StateResponse(shard_id, hash, payload, receipts)
+            PeerMessage::StateResponse(shard_id, hash, part, receipts) => {
+                self.tracker.sync_response(SyncRequest::StatePart(shard_id, hash, part.part_id));
+                NetworkClientMessages::StateResponse(shard_id, hash, part, receipts, peer_id)
             }
             PeerMessage::AnnounceAccount(announce_account) => {
                 if announce_account.peer_id_sender() != peer_id {
-                    // Ban peer if tries to impersonate another peer.
-                    self.peer_status = PeerStatus::Banned(ReasonForBan::InvalidPeerId);
+                    // Report peer if tries to impersonate another peer.
+                    self.report_misbehavior(ctx, ReasonForBan::InvalidPeerId);
                     return;
                 } else {
                     NetworkClientMessages::AnnounceAccount(announce_account)
@@ -267,39 +574,46 @@ impl Peer {
             PeerMessage::Handshake(_)
             | PeerMessage::HandshakeFailure(_, _)
             | PeerMessage::PeersRequest
-            | PeerMessage::PeersResponse(_) => {
+            | PeerMessage::PeersResponse(_)
+            | PeerMessage::Routes(_)
+            | PeerMessage::Routed(_)
+            | PeerMessage::Compressed(_)
+            | PeerMessage::Ping(_)
+            | PeerMessage::Pong(_)
+            | PeerMessage::Disconnect => {
                 error!(target: "network", "Peer receive_client_message received unexpected type");
+                self.report_misbehavior(ctx, ReasonForBan::UnsolicitedMessage);
                 return;
             }
         };
         self.client_addr
-            .send(network_client_msg)
+            .send(NetworkClientMessages::Correlated(correlation_id, Box::new(network_client_msg)))
             .into_actor(self)
-            .then(|res, act, ctx| {
+            .then(move |res, act, ctx| {
                 // Ban peer if client thinks received data is bad.
                 match res {
                     Ok(NetworkClientResponses::InvalidTx(err)) => {
-                        warn!(target: "network", "Received invalid tx from peer {}: {}", act.peer_info, err);
+                        warn!(target: "network", "correlation_id={} Transaction from peer {} is refused: {}", correlation_id, act.peer_info, err);
                         // TODO: count as malicious behaviour?
                     }
-                    Ok(NetworkClientResponses::Ban { ban_reason }) => {
-                        act.peer_status = PeerStatus::Banned(ban_reason);
-                        ctx.stop();
+                    Ok(NetworkClientResponses::ReportPeer { reason }) => {
+                        warn!(target: "network", "correlation_id={} Reporting peer {} for {:?}", correlation_id, act.peer_info, reason);
+                        act.report_misbehavior(ctx, reason);
                     }
                     Ok(NetworkClientResponses::Block(block)) => {
                         act.send_message(PeerMessage::Block(block))
                     }
-                    Ok(NetworkClientResponses::BlockHeaders(headers)) => {
-                        act.send_message(PeerMessage::BlockHeaders(headers))
+                    Ok(NetworkClientResponses::BlockHeaders(headers, more_available)) => {
+                        act.send_message(PeerMessage::BlockHeaders(headers, more_available))
                     }
-                    Ok(NetworkClientResponses::StateResponse { shard_id, hash, payload, receipts }) => {
-                        act.send_message(PeerMessage::StateResponse(shard_id, hash, payload, receipts))
+                    Ok(NetworkClientResponses::StateResponse { shard_id, hash, part, receipts }) => {
+                        act.send_message(PeerMessage::StateResponse(shard_id, hash, part, receipts))
                     }
                     Err(err) => {
                         error!(
                             target: "network",
-                            "Received error sending message to client: {} for {}",
-                            err, act.peer_info
+                            "correlation_id={} Received error sending message to client: {} for {}",
+                            correlation_id, err, act.peer_info
                         );
                         return actix::fut::err(());
                     }
@@ -309,6 +623,62 @@ impl Peer {
             })
             .spawn(ctx);
     }
+
+    /// Disconnects the peer if nothing was received from it for the silence timeout, otherwise
+    /// pings it to measure the round trip time.
+    fn keep_alive(&mut self, ctx: &mut Context<Peer>) {
+        let silence =
+            Utc::now().signed_duration_since(self.last_seen).to_std().unwrap_or_default();
+        if silence > self.silence_timeout {
+            info!(target: "network", "Nothing received from {} for {:?}, disconnecting", self.peer_info, silence);
+            ctx.stop();
+            return;
+        }
+        if self.peer_status == PeerStatus::Ready
+            && self.peer_protocol_version >= MIN_PING_PROTOCOL_VERSION
+        {
+            let nonce = rand::random();
+            self.ping = Some((nonce, Instant::now()));
+            self.send_message(PeerMessage::Ping(nonce));
+        }
+    }
+
+    /// Reports the peer for the invalid message. Peer that hasn't completed the handshake can't
+    /// be reported and is disconnected instead.
+    fn report_invalid_message(&mut self, ctx: &mut Context<Peer>, reason: ReasonForBan) {
+        if self.peer_status == PeerStatus::Ready {
+            self.report_misbehavior(ctx, reason);
+        } else {
+            ctx.stop();
+        }
+    }
+
+    /// Bans the dialed peer that rejected the handshake because it is on another network. The
+    /// peer id of the failure message is not used, so that a peer can't get others banned.
+    fn report_wrong_network(&mut self) {
+        if let Some(peer_info) = self.peer_info.as_ref() {
+            self.peer_manager_addr.do_send(ReportMisbehavior {
+                peer_id: peer_info.id,
+                reason: ReasonForBan::WrongNetwork,
+            });
+        }
+    }
+
+    /// Bans the peer that is on another network and closes the connection, after telling the
+    /// peer why it was rejected.
+    fn reject_wrong_network(
+        &mut self,
+        ctx: &mut Context<Peer>,
+        peer_id: PeerId,
+        reason: HandshakeFailureReason,
+    ) {
+        self.send_message(PeerMessage::HandshakeFailure(self.node_info.clone(), reason));
+        self.peer_manager_addr.do_send(ReportMisbehavior {
+            peer_id,
+            reason: ReasonForBan::WrongNetwork,
+        });
+        ctx.run_later(HANDSHAKE_FAILURE_DISCONNECT_DELAY, |_, ctx| ctx.stop());
+    }
 }
 
 impl Actor for Peer {
@@ -327,6 +697,12 @@ impl Actor for Peer {
             }
         });
 
+        if self.upload.is_limited() {
+            ctx.run_interval(SEND_QUEUE_FLUSH_PERIOD, |act, _ctx| act.flush_send_queue());
+        }
+
+        ctx.run_interval(self.ping_period, |act, ctx| act.keep_alive(ctx));
+
         // If outbound peer, initiate handshake.
         if self.peer_type == PeerType::Outbound {
             self.send_handshake(ctx);
@@ -336,10 +712,9 @@ impl Actor for Peer {
     fn stopping(&mut self, _: &mut Self::Context) -> Running {
         debug!(target: "network", "{:?}: Peer {} disconnected.", self.node_info.id, self.peer_info);
         if let Some(peer_info) = self.peer_info.as_ref() {
+            // Banned peers were already removed by the peer manager.
             if self.peer_status == PeerStatus::Ready {
                 self.peer_manager_addr.do_send(Unregister { peer_id: peer_info.id })
-            } else if let PeerStatus::Banned(ban_reason) = self.peer_status {
-                self.peer_manager_addr.do_send(Ban { peer_id: peer_info.id, ban_reason });
             }
         }
         Running::Stop
@@ -350,19 +725,53 @@ impl WriteHandler<io::Error> for Peer {}
 
 impl StreamHandler<Vec<u8>, io::Error> for Peer {
     fn handle(&mut self, msg: Vec<u8>, ctx: &mut Self::Context) {
-        self.tracker.increment_received(msg.len() as u64);
-        let peer_msg = match bytes_to_peer_message(&msg) {
+        let msg_len = msg.len() as u64;
+        self.tracker.increment_received(msg_len);
+        self.last_seen = Utc::now();
+        if self.download.is_limited() {
+            self.download.consume(msg_len);
+            // Stop reading from the connection until the download limit allows it again.
+            let delay = self.download.delay();
+            if delay > Duration::from_millis(0) {
+                ctx.wait(Delay::new(Instant::now() + delay).map_err(|_| ()).into_actor(self));
+            }
+        }
+        let msg = match self.receive_cipher.as_mut() {
+            Some(cipher) => match cipher.open(&msg) {
+                Ok(msg) => msg,
+                Err(err) => {
+                    warn!(target: "network", "Received invalid message of {} bytes from {}: {}", msg_len, self.peer_info, err);
+                    self.report_invalid_message(ctx, ReasonForBan::MalformedMessage);
+                    return;
+                }
+            },
+            None => msg,
+        };
+        let peer_msg = match bytes_to_peer_message(&msg, self.max_message_size as usize) {
             Ok(peer_msg) => peer_msg,
             Err(err) => {
-                error!(target: "network", "Received invalid data {:?} from {}: {}", msg, self.peer_info, err);
+                warn!(target: "network", "Received invalid message of {} bytes from {}: {}", msg_len, self.peer_info, err);
+                let reason = match err {
+                    DecodeError::TooLarge { .. } => ReasonForBan::OversizedMessage,
+                    DecodeError::Malformed(_) => ReasonForBan::MalformedMessage,
+                };
+                self.report_invalid_message(ctx, reason);
                 return;
             }
         };
+        for observer in self.observers.iter() {
+            observer.message_received(self.peer_id(), &peer_msg);
+        }
         match (self.peer_type, self.peer_status, peer_msg) {
             (_, PeerStatus::Connecting, PeerMessage::HandshakeFailure(peer_info, reason)) => {
                 match reason {
                     HandshakeFailureReason::GenesisMismatch(genesis) => {
                         error!(target: "network", "Attempting to connect to a node ({}) with a different genesis block. Our genesis: {}, their genesis: {}", peer_info, self.genesis, genesis);
+                        self.report_wrong_network();
+                    },
+                    HandshakeFailureReason::ChainIdMismatch(chain_id) => {
+                        error!(target: "network", "Attempting to connect to a node ({}) on a different chain. Our chain id: {}, their chain id: {}", peer_info, self.chain_id, chain_id);
+                        self.report_wrong_network();
                     },
                     HandshakeFailureReason::ProtocolVersionMismatch(version) => {
                         error!(target: "network", "Unable to connect to a node ({}) due to a network protocol version mismatch. Our version: {}, their: {}", peer_info, PROTOCOL_VERSION, version);
@@ -373,32 +782,36 @@ impl StreamHandler<Vec<u8>, io::Error> for Peer {
             (_, PeerStatus::Connecting, PeerMessage::Handshake(handshake)) => {
                 debug!(target: "network", "{:?}: Received handshake {:?}", self.node_info.id, handshake);
 
+                if handshake.chain_id != self.chain_id {
+                    info!(target: "network", "Received connection from node {} on a different chain {}.", handshake.peer_id, handshake.chain_id);
+                    let reason = HandshakeFailureReason::ChainIdMismatch(self.chain_id.clone());
+                    self.reject_wrong_network(ctx, handshake.peer_id, reason);
+                    return;
+                }
                 if handshake.chain_info.genesis != self.genesis {
-                    info!(target: "network", "Received connection from node with different genesis.");
-                    ctx.address().do_send(SendMessage {
-                        message: PeerMessage::HandshakeFailure(
-                            self.node_info.clone(),
-                            HandshakeFailureReason::GenesisMismatch(self.genesis))
-                    });
+                    info!(target: "network", "Received connection from node {} with different genesis.", handshake.peer_id);
+                    let reason = HandshakeFailureReason::GenesisMismatch(self.genesis);
+                    self.reject_wrong_network(ctx, handshake.peer_id, reason);
                     return;
-                    // Connection will be closed by a handshake timeout
                 }
-                if handshake.version != PROTOCOL_VERSION {
-                    info!(target: "network", "Received connection from node with different network protocol version.");
+                if handshake.protocol_version < self.min_peer_protocol_version {
+                    info!(target: "network", "Received connection from node with network protocol version {}, minimum supported is {}.", handshake.protocol_version, self.min_peer_protocol_version);
                     ctx.address().do_send(SendMessage {
                         message: PeerMessage::HandshakeFailure(
                             self.node_info.clone(),
                             HandshakeFailureReason::ProtocolVersionMismatch(PROTOCOL_VERSION))
                     });
-                }
-                if handshake.chain_info.genesis != self.genesis {
-                    info!(target: "network", "Received connection from node with different genesis.");
-                    ctx.stop();
+                    return;
+                    // Connection will be closed by a handshake timeout
                 }
                 if handshake.peer_id == self.node_info.id {
                     warn!(target: "network", "Received info about itself. Disconnecting this peer.");
                     ctx.stop();
                 }
+                if !self.setup_encryption(&handshake) {
+                    ctx.stop();
+                    return;
+                }
                 let peer_info = PeerInfo {
                     id: handshake.peer_id,
                     addr: handshake
@@ -407,12 +820,17 @@ impl StreamHandler<Vec<u8>, io::Error> for Peer {
                     account_id: None,
                 };
                 self.chain_info = handshake.chain_info;
+                self.peer_protocol_version = handshake.protocol_version;
+                self.compression = self.enable_compression && handshake.compression;
                 self.peer_manager_addr
                     .send(Consolidate {
                         actor: ctx.address(),
                         peer_info: peer_info.clone(),
                         peer_type: self.peer_type,
                         chain_info: handshake.chain_info,
+                        protocol_version: handshake.protocol_version,
+                        private: handshake.private,
+                        observed_addr: handshake.observed_addr,
                     })
                     .into_actor(self)
                     .then(move |res, act, ctx| {
@@ -436,6 +854,10 @@ impl StreamHandler<Vec<u8>, io::Error> for Peer {
                     })
                     .wait(ctx);
             }
+            (_, _, PeerMessage::Disconnect) => {
+                info!(target: "network", "Peer {} is disconnecting", self.peer_info);
+                ctx.stop();
+            }
             (_, PeerStatus::Ready, PeerMessage::Handshake(_)) => {
                 // Received handshake after already have seen handshake from this peer.
                 debug!(target: "network", "Duplicate handshake from {}", self.peer_info);
@@ -451,15 +873,57 @@ impl StreamHandler<Vec<u8>, io::Error> for Peer {
             }
             (_, PeerStatus::Ready, PeerMessage::PeersResponse(peers)) => {
                 debug!(target: "network", "Received peers from {}: {} peers.", self.peer_info, peers.len());
+                // Unrequested peers are not stored, so that a peer can't flood the peer store.
+                if !self.peers_requested {
+                    self.report_invalid_message(ctx, ReasonForBan::UnsolicitedMessage);
+                    return;
+                }
+                self.peers_requested = false;
                 self.peer_manager_addr.do_send(PeersResponse { peers });
             }
+            (_, PeerStatus::Ready, PeerMessage::Ping(nonce)) => {
+                self.send_message(PeerMessage::Pong(nonce));
+            }
+            (_, PeerStatus::Ready, PeerMessage::Pong(nonce)) => match self.ping {
+                Some((ping_nonce, sent_at)) if ping_nonce == nonce => {
+                    self.latency = Some(sent_at.elapsed());
+                    self.ping = None;
+                }
+                // Pong to an older ping, the newer one is awaited.
+                _ => {}
+            },
+            (_, PeerStatus::Ready, PeerMessage::Routes(distances)) => {
+                if let Some(peer_id) = self.peer_id() {
+                    self.peer_manager_addr.do_send(RoutesUpdate { peer_id: *peer_id, distances });
+                }
+            }
+            (_, PeerStatus::Ready, PeerMessage::Routed(msg)) => {
+                if let Some(peer_id) = self.peer_id() {
+                    self.peer_manager_addr.do_send(RoutedMessageFrom { msg, from: *peer_id });
+                }
+            }
             (_, PeerStatus::Ready, msg) => {
                 self.receive_client_message(ctx, msg);
             }
             (_, _, msg) => {
                 warn!(target: "network", "Received {} while {:?} from {:?} connection.", msg, self.peer_status, self.peer_type);
+                self.report_invalid_message(ctx, ReasonForBan::UnsolicitedMessage);
+            }
+        }
+    }
+
+    /// Frame longer than the maximum message size fails to read, the peer is reported for it.
+    fn error(&mut self, err: io::Error, _ctx: &mut Self::Context) -> Running {
+        warn!(target: "network", "Failed to read from {}: {}", self.peer_info, err);
+        if err.kind() == io::ErrorKind::InvalidData && self.peer_status == PeerStatus::Ready {
+            if let Some(peer_id) = self.peer_id() {
+                self.peer_manager_addr.do_send(ReportMisbehavior {
+                    peer_id: *peer_id,
+                    reason: ReasonForBan::OversizedMessage,
+                });
             }
         }
+        Running::Stop
     }
 }
 
@@ -484,6 +948,10 @@ impl Handler<QueryPeerStats> for Peer {
                 self.tracker.sent_bytes.count_per_min(),
                 self.tracker.received_bytes.count_per_min(),
             ),
+            score: self.tracker.take_score(),
+            dropped_messages: self.send_queue.take_dropped(),
+            latency: self.latency,
+            last_seen: self.last_seen,
         }
     }
 }
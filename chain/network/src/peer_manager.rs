@@ -1,6 +1,8 @@
 use std::cmp;
 use std::collections::{HashMap, HashSet};
-use std::sync::Arc;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
 use actix::actors::resolver::{ConnectAddr, Resolver};
@@ -12,31 +14,48 @@ use actix::{
 };
 use chrono::offset::TimeZone;
 use chrono::{DateTime, Utc};
-use futures::future;
+use futures::future::{self, Future};
 use log::{debug, error, info, warn};
 use rand::{thread_rng, Rng};
 use tokio::codec::FramedRead;
 use tokio::io::AsyncRead;
 use tokio::net::{TcpListener, TcpStream};
 
+use near_chain::BlockApproval;
 use near_primitives::types::AccountId;
-use near_primitives::utils::from_timestamp;
 use near_store::Store;
 
+use crate::ban_policy::{BanPolicy, BanResponse};
+use crate::bandwidth::{RateLimiter, Throttle};
 use crate::codec::Codec;
+use crate::encryption::{self, HandshakeKey};
+use crate::observer::NetworkEventsObserver;
 use crate::peer::Peer;
+use crate::peer_score::PeerScore;
 use crate::peer_store::PeerStore;
+use crate::routing::{PeerRoutingTable, MAX_ROUTE_DISTANCE};
+use crate::send_queue::DroppedMessages;
 use crate::types::{
-    AnnounceAccount, Ban, Consolidate, FullPeerInfo, InboundTcpConnect, KnownPeerStatus,
-    NetworkInfo, OutboundTcpConnect, PeerId, PeerList, PeerMessage, PeerType, PeersRequest,
-    PeersResponse, QueryPeerStats, ReasonForBan, SendMessage, Unregister,
+    AnnounceAccount, Consolidate, FullPeerInfo, InboundTcpConnect, NetworkInfo,
+    OutboundTcpConnect, PeerId, PeerList, PeerMessage, PeerType, PeersRequest, PeersResponse,
+    QueryPeerStats, ReasonForBan, ReportMisbehavior, RoutedMessage, RoutedMessageBody,
+    RoutedMessageFrom, RoutesUpdate, SendMessage, Unregister, UpnpMapped,
 };
 use crate::types::{
-    NetworkClientMessages, NetworkConfig, NetworkRequests, NetworkResponses, PeerInfo,
+    NetworkClientMessages, NetworkClientResponses, NetworkConfig, NetworkRequests,
+    NetworkResponses, PeerInfo,
 };
+use crate::{socks5, upnp};
 
-/// How often to request peers from active peers.
-const REQUEST_PEERS_SECS: i64 = 60;
+/// How often to send the distances to the reachable peers to the active peers.
+const ROUTES_BROADCAST_PERIOD: Duration = Duration::from_secs(1);
+
+/// Duration of the port mapping on the router, the mapping is renewed in half of that time.
+const UPNP_LEASE: Duration = Duration::from_secs(60 * 60);
+
+/// Number of peers that must see this node at the same IP address for it to be considered the
+/// external address.
+const MIN_OBSERVED_ADDRESS_VOTES: usize = 2;
 
 macro_rules! unwrap_or_error(($obj: expr, $error: expr) => (match $obj {
     Ok(result) => result,
@@ -56,6 +75,9 @@ struct ActivePeer {
     sent_bytes_per_sec: u64,
     /// Last time requested peers.
     last_time_peer_requested: DateTime<Utc>,
+    /// Peer is a private validator, it's not saved into the peer store so that it's never
+    /// advertised to other peers.
+    private: bool,
 }
 
 enum RoutingTableUpdate {
@@ -74,6 +96,7 @@ impl RoutingTableUpdate {
 }
 
 // TODO: Clear routing table periodically
+/// Peers of the validator accounts, with the number of hops the announcement took to reach us.
 struct RoutingTable {
     account_peers: HashMap<AccountId, (PeerId, usize)>,
 }
@@ -84,15 +107,17 @@ impl RoutingTable {
     }
 
     fn update(&mut self, data: &AnnounceAccount) -> RoutingTableUpdate {
+        let peer_id = data.header().peer_id;
         match self.account_peers.get(&data.account_id) {
             // If this account id is already tracked in the routing table ...
-            Some((_, num_hops)) => {
-                // check if this connection is better than the one we keep track
-                // regarding number of intermediate hops.
-                if data.num_hops() < *num_hops {
-                    // and add it
-                    self.account_peers
-                        .insert(data.account_id.clone(), (data.peer_id_sender(), data.num_hops()));
+            Some((known_peer_id, num_hops)) => {
+                if *known_peer_id != peer_id {
+                    // the validator moved to another node ...
+                    self.account_peers.insert(data.account_id.clone(), (peer_id, data.num_hops()));
+                    RoutingTableUpdate::NewAccount
+                } else if data.num_hops() < *num_hops {
+                    // or the announcement came through a shorter path.
+                    self.account_peers.insert(data.account_id.clone(), (peer_id, data.num_hops()));
                     RoutingTableUpdate::UpdatedAccount
                 } else {
                     RoutingTableUpdate::Ignore
@@ -100,8 +125,7 @@ impl RoutingTable {
             }
             // If we don't have this account id store it in the routing table.
             None => {
-                self.account_peers
-                    .insert(data.account_id.clone(), (data.peer_id_sender(), data.num_hops()));
+                self.account_peers.insert(data.account_id.clone(), (peer_id, data.num_hops()));
                 RoutingTableUpdate::NewAccount
             }
         }
@@ -122,14 +146,32 @@ pub struct PeerManagerActor {
     client_addr: Recipient<NetworkClientMessages>,
     /// Peer store that provides read/write access to peers.
     peer_store: PeerStore,
+    /// Policy that decides how to respond to misbehavior of the peers.
+    ban_policy: BanPolicy,
     /// Set of outbound connections that were not consolidated yet.
     outgoing_peers: HashSet<PeerId>,
     /// Active peers (inbound and outbound) with their full peer information.
     active_peers: HashMap<PeerId, ActivePeer>,
     /// Routing table to keep track of account id
     routing_table: RoutingTable,
+    /// Routes to the peers that are not connected directly.
+    peer_routes: PeerRoutingTable,
     /// Monitor peers attempts, used for fast checking in the beginning with exponential backoff.
     monitor_peers_attempts: u64,
+    /// Node is shutting down, no new connections are made or accepted.
+    shutting_down: bool,
+    /// Observers of the network events, shared with the peers.
+    observers: Vec<Arc<dyn NetworkEventsObserver>>,
+    /// Upload limit of all the peers together, shared with the peers.
+    upload_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    /// Download limit of all the peers together, shared with the peers.
+    download_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    /// Messages dropped because the send queue of the peer was full.
+    dropped_messages: DroppedMessages,
+    /// IP address of this node as seen by each of the active peers.
+    observed_ips: HashMap<PeerId, IpAddr>,
+    /// External IP address of the router the listening port is mapped on with UPnP.
+    upnp_external_ip: Option<IpAddr>,
 }
 
 impl PeerManagerActor {
@@ -138,34 +180,65 @@ impl PeerManagerActor {
         config: NetworkConfig,
         client_addr: Recipient<NetworkClientMessages>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        let peer_store = PeerStore::new(store, &config.boot_nodes)?;
+        encryption::init()?;
+        let boot_nodes: Vec<_> =
+            config.boot_nodes.iter().chain(config.sentries.iter()).cloned().collect();
+        let peer_store = PeerStore::new(store, &boot_nodes)?;
         debug!(target: "network", "Found known peers: {} (boot nodes={})", peer_store.len(), config.boot_nodes.len());
+        let peer_id: PeerId = config.public_key.into();
+        let ban_policy = BanPolicy::new(config.ban_policy.clone());
+        let new_limiter = |limit: Option<u64>| {
+            limit.map(|bytes_per_sec| Arc::new(Mutex::new(RateLimiter::new(bytes_per_sec))))
+        };
+        let upload_limiter = new_limiter(config.bandwidth.max_upload_bytes_per_sec);
+        let download_limiter = new_limiter(config.bandwidth.max_download_bytes_per_sec);
         Ok(PeerManagerActor {
-            peer_id: config.public_key.into(),
+            peer_id,
             config,
             client_addr,
+            ban_policy,
             peer_store,
             active_peers: HashMap::default(),
             outgoing_peers: HashSet::default(),
             // account_peers: HashMap::default(),
             routing_table: RoutingTable::new(),
+            peer_routes: PeerRoutingTable::new(peer_id),
             monitor_peers_attempts: 0,
+            shutting_down: false,
+            observers: vec![],
+            upload_limiter,
+            download_limiter,
+            dropped_messages: DroppedMessages::default(),
+            observed_ips: HashMap::default(),
+            upnp_external_ip: None,
         })
     }
 
+    /// Registers observer of the network events. Must be called before the actor is started,
+    /// so that all peers report their events to it.
+    pub fn register_observer(&mut self, observer: Arc<dyn NetworkEventsObserver>) {
+        self.observers.push(observer);
+    }
+
     fn num_active_peers(&self) -> usize {
         self.active_peers.len()
     }
 
-    fn register_peer(&mut self, full_peer_info: FullPeerInfo, addr: Addr<Peer>) {
+    fn register_peer(&mut self, full_peer_info: FullPeerInfo, addr: Addr<Peer>, private: bool) {
         if self.outgoing_peers.contains(&full_peer_info.peer_info.id) {
             self.outgoing_peers.remove(&full_peer_info.peer_info.id);
         }
-        unwrap_or_error!(
-            self.peer_store.peer_connected(&full_peer_info),
-            "Failed to save peer data"
-        );
+        if !private {
+            unwrap_or_error!(
+                self.peer_store.peer_connected(&full_peer_info),
+                "Failed to save peer data"
+            );
+        }
 
+        for observer in self.observers.iter() {
+            observer.peer_connected(&full_peer_info);
+        }
+        self.peer_routes.add_peer(full_peer_info.peer_info.id);
         self.active_peers.insert(
             full_peer_info.peer_info.id,
             ActivePeer {
@@ -174,6 +247,7 @@ impl PeerManagerActor {
                 sent_bytes_per_sec: 0,
                 received_bytes_per_sec: 0,
                 last_time_peer_requested: Utc.timestamp(0, 0),
+                private,
             },
         );
     }
@@ -184,14 +258,114 @@ impl PeerManagerActor {
             self.outgoing_peers.remove(&peer_id);
             return;
         }
-        self.active_peers.remove(&peer_id);
+        self.peer_routes.remove_peer(&peer_id);
+        self.observed_ips.remove(&peer_id);
+        if let Some(active_peer) = self.active_peers.remove(&peer_id) {
+            for observer in self.observers.iter() {
+                observer.peer_disconnected(&peer_id);
+            }
+            if active_peer.private {
+                return;
+            }
+        }
         unwrap_or_error!(self.peer_store.peer_disconnected(&peer_id), "Failed to save peer data");
     }
 
-    fn ban_peer(&mut self, peer_id: &PeerId, ban_reason: ReasonForBan) {
-        info!(target: "network", "Banning peer {:?}", peer_id);
-        self.active_peers.remove(&peer_id);
-        unwrap_or_error!(self.peer_store.peer_ban(peer_id, ban_reason), "Failed to save peer data");
+    fn ban_peer(&mut self, peer_id: &PeerId, ban_reason: ReasonForBan, permanently: bool) {
+        info!(
+            target: "network",
+            "Banning peer {:?} for {:?} (permanently: {})", peer_id, ban_reason, permanently
+        );
+        for observer in self.observers.iter() {
+            observer.peer_banned(peer_id, ban_reason);
+        }
+        if let Some(active_peer) = self.active_peers.remove(&peer_id) {
+            active_peer.addr.do_send(SendMessage { message: PeerMessage::Disconnect });
+            for observer in self.observers.iter() {
+                observer.peer_disconnected(peer_id);
+            }
+        }
+        self.peer_routes.remove_peer(peer_id);
+        self.observed_ips.remove(peer_id);
+        let result = if permanently {
+            self.peer_store.peer_ban_permanently(peer_id, ban_reason)
+        } else {
+            let duration = self
+                .config
+                .ban_policy
+                .ban_durations
+                .get(&ban_reason)
+                .cloned()
+                .unwrap_or(self.config.ban_window);
+            self.peer_store.peer_ban(peer_id, ban_reason, duration)
+        };
+        unwrap_or_error!(result, "Failed to save peer data");
+    }
+
+    /// Adds responses of the peer to its history and updates the score of the active peer.
+    fn update_peer_score(&mut self, peer_id: &PeerId, update: &PeerScore) {
+        let score = unwrap_or_error!(
+            self.peer_store.update_peer_score(peer_id, update),
+            "Failed to save peer score"
+        );
+        if let Some(active_peer) = self.active_peers.get_mut(peer_id) {
+            active_peer.full_peer_info.score = score;
+        }
+    }
+
+    /// Grades misbehavior of the peer with the ban policy and bans the peer if required.
+    /// Misbehavior also lowers the score of the peer for sync requests.
+    fn report_misbehavior(&mut self, peer_id: &PeerId, reason: ReasonForBan) -> BanResponse {
+        if reason != ReasonForBan::None {
+            let mut update = PeerScore::default();
+            update.record_bad_data();
+            self.update_peer_score(peer_id, &update);
+        }
+        let response = self.ban_policy.report(peer_id, reason);
+        debug!(target: "network", "Peer {} reported for {:?}: {:?}", peer_id, reason, response);
+        match response {
+            BanResponse::Decrease => {}
+            BanResponse::TempBan => self.ban_peer(peer_id, reason, false),
+            BanResponse::PermanentBan => self.ban_peer(peer_id, reason, true),
+        }
+        response
+    }
+
+    /// Address other nodes can dial this node at: the configured one, the one mapped with UPnP or
+    /// the IP address most of the active peers see this node at, with the listening port.
+    fn external_address(&self) -> Option<SocketAddr> {
+        if self.config.external_address.is_some() {
+            return self.config.external_address;
+        }
+        if self.config.hides_address() {
+            return None;
+        }
+        let port = self.config.addr?.port();
+        if let Some(ip) = self.upnp_external_ip {
+            return Some(SocketAddr::new(ip, port));
+        }
+        let mut votes: HashMap<IpAddr, usize> = HashMap::default();
+        for ip in self.observed_ips.values() {
+            *votes.entry(*ip).or_insert(0) += 1;
+        }
+        votes
+            .into_iter()
+            .filter(|(_, num_votes)| *num_votes >= MIN_OBSERVED_ADDRESS_VOTES)
+            .max_by_key(|(ip, num_votes)| (*num_votes, *ip))
+            .map(|(ip, _)| SocketAddr::new(ip, port))
+    }
+
+    /// Maps the listening port on the router with UPnP in the background, and renews the mapping
+    /// before the lease expires.
+    fn map_upnp_port(&mut self, ctx: &mut Context<Self>, port: u16) {
+        let addr = ctx.address();
+        thread::spawn(move || match upnp::map_port(port, UPNP_LEASE) {
+            Ok(external_ip) => addr.do_send(UpnpMapped { external_ip }),
+            Err(err) => warn!(target: "network", "Failed to map port {} with UPnP: {}", port, err),
+        });
+        ctx.run_later(UPNP_LEASE / 2, move |act, ctx| {
+            act.map_upnp_port(ctx, port);
+        });
     }
 
     /// Connects peer with given TcpStream and optional information if it's outbound.
@@ -206,28 +380,80 @@ impl PeerManagerActor {
         let account_id = self.config.account_id.clone();
         let server_addr = self.config.addr;
         let handshake_timeout = self.config.handshake_timeout;
+        let max_message_size = self.config.max_message_size;
+        let min_peer_protocol_version = self.config.min_peer_protocol_version;
+        let private = self.config.is_private();
+        let hides_address = self.config.hides_address();
+        let enable_compression = self.config.enable_compression;
+        let handshake_key = if self.config.enable_encryption {
+            Some(HandshakeKey::new(&self.config.secret_key))
+        } else {
+            None
+        };
+        let require_encryption = self.config.require_encryption;
+        let ping_period = self.config.ping_period;
+        let silence_timeout = self.config.peer_silence_timeout;
         let client_addr = self.client_addr.clone();
+        let observers = self.observers.clone();
+        let upload = Throttle::new(
+            self.upload_limiter.clone(),
+            self.config.bandwidth.max_peer_upload_bytes_per_sec,
+        );
+        let download = Throttle::new(
+            self.download_limiter.clone(),
+            self.config.bandwidth.max_peer_download_bytes_per_sec,
+        );
         Peer::create(move |ctx| {
             let server_addr = server_addr.unwrap_or_else(|| stream.local_addr().unwrap());
-            let remote_addr = stream.peer_addr().unwrap();
+            // Private validator and node behind a proxy don't advertise their address.
+            let node_addr = if hides_address { None } else { Some(server_addr) };
+            // Connection through a proxy is with the proxy, the peer is at the dialed address.
+            let remote_addr = peer_info
+                .as_ref()
+                .and_then(|peer_info| peer_info.addr)
+                .unwrap_or_else(|| stream.peer_addr().unwrap());
             let (read, write) = stream.split();
 
             // TODO: check if peer is banned or known based on IP address and port.
 
-            Peer::add_stream(FramedRead::new(read, Codec::new()), ctx);
+            Peer::add_stream(FramedRead::new(read, Codec::new(max_message_size as usize)), ctx);
             Peer::new(
-                PeerInfo { id: peer_id, addr: Some(server_addr), account_id },
+                PeerInfo { id: peer_id, addr: node_addr, account_id },
                 remote_addr,
                 peer_info,
                 peer_type,
-                FramedWrite::new(write, Codec::new(), ctx),
+                FramedWrite::new(write, Codec::new(max_message_size as usize), ctx),
                 handshake_timeout,
                 recipient,
                 client_addr,
+                max_message_size,
+                min_peer_protocol_version,
+                private,
+                enable_compression,
+                handshake_key,
+                require_encryption,
+                observers,
+                upload,
+                download,
+                ping_period,
+                silence_timeout,
             )
         });
     }
 
+    /// Opens the connection to the address, through the proxy if it's configured.
+    fn connect(&self, addr: SocketAddr) -> Box<dyn Future<Item = TcpStream, Error = String>> {
+        match self.config.proxy {
+            Some(proxy) => Box::new(socks5::connect(proxy, addr).map_err(|err| err.to_string())),
+            None => Box::new(Resolver::from_registry().send(ConnectAddr(addr)).then(|res| {
+                match res {
+                    Ok(res) => res.map_err(|err| err.to_string()),
+                    Err(err) => Err(err.to_string()),
+                }
+            })),
+        }
+    }
+
     fn is_outbound_bootstrap_needed(&self) -> bool {
         (self.active_peers.len() + self.outgoing_peers.len())
             < (self.config.peer_max_count as usize)
@@ -265,7 +491,12 @@ impl PeerManagerActor {
 
     /// Get a random peer we are not connected to from the known list.
     fn sample_random_peer(&self, ignore_list: &HashSet<PeerId>) -> Option<PeerInfo> {
-        let unconnected_peers = self.peer_store.unconnected_peers(ignore_list);
+        let unconnected_peers: Vec<_> = self
+            .peer_store
+            .unconnected_peers(ignore_list)
+            .into_iter()
+            .filter(|peer_info| self.config.is_peer_allowed(&peer_info.id, peer_info.addr))
+            .collect();
         let index = thread_rng().gen_range(0, std::cmp::max(unconnected_peers.len(), 1));
 
         unconnected_peers
@@ -279,10 +510,10 @@ impl PeerManagerActor {
     fn query_active_peers_for_more_peers(&mut self, ctx: &mut Context<Self>) {
         let mut requests = vec![];
         let msg = SendMessage { message: PeerMessage::PeersRequest };
+        let period = chrono::Duration::from_std(self.config.peers_request_period)
+            .unwrap_or_else(|_| chrono::Duration::max_value());
         for (_, active_peer) in self.active_peers.iter_mut() {
-            if Utc::now().signed_duration_since(active_peer.last_time_peer_requested).num_seconds()
-                > REQUEST_PEERS_SECS
-            {
+            if Utc::now().signed_duration_since(active_peer.last_time_peer_requested) >= period {
                 active_peer.last_time_peer_requested = Utc::now();
                 requests.push(active_peer.addr.send(msg.clone()));
             }
@@ -294,6 +525,19 @@ impl PeerManagerActor {
             .spawn(ctx);
     }
 
+    /// Periodically exchange known peers with the active peers, so that the node learns about the
+    /// peers of its peers and doesn't depend on the boot nodes to find the rest of the network.
+    fn gossip_peers(&mut self, ctx: &mut Context<Self>) {
+        // Private validator connects only to its sentries and has no use for other peers.
+        if !self.shutting_down && !self.config.is_private() {
+            self.query_active_peers_for_more_peers(ctx);
+        }
+
+        ctx.run_later(self.config.peers_request_period, move |act, ctx| {
+            act.gossip_peers(ctx);
+        });
+    }
+
     /// Periodically query peer actors for latest weight and traffic info.
     fn monitor_peer_stats(&mut self, ctx: &mut Context<Self>) {
         for (peer_id, active_peer) in self.active_peers.iter() {
@@ -302,13 +546,17 @@ impl PeerManagerActor {
                 .into_actor(self)
                 .map_err(|err, _, _| error!("Failed sending message: {}", err))
                 .and_then(move |res, act, _| {
+                    act.dropped_messages.add(&res.dropped_messages);
                     if res.is_abusive {
-                        warn!(target: "network", "Banning peer {} for abuse ({} sent, {} recv)", peer_id1, res.message_counts.0, res.message_counts.1);
-                        act.ban_peer(&peer_id1, ReasonForBan::Abusive);
+                        warn!(target: "network", "Reporting peer {} for abuse ({} sent, {} recv)", peer_id1, res.message_counts.0, res.message_counts.1);
+                        act.report_misbehavior(&peer_id1, ReasonForBan::Abusive);
                     } else if let Some(active_peer) = act.active_peers.get_mut(&peer_id1) {
                         active_peer.full_peer_info.chain_info = res.chain_info;
+                        active_peer.full_peer_info.latency = res.latency;
+                        active_peer.full_peer_info.last_seen = res.last_seen;
                         active_peer.sent_bytes_per_sec = res.sent_bytes_per_sec;
                         active_peer.received_bytes_per_sec = res.received_bytes_per_sec;
+                        act.update_peer_score(&peer_id1, &res.score);
                     }
                     actix::fut::ok(())
                 })
@@ -326,27 +574,12 @@ impl PeerManagerActor {
     ///  - unban peers that have been banned for awhile,
     ///  - remove expired peers,
     fn monitor_peers(&mut self, ctx: &mut Context<Self>) {
-        let mut to_unban = vec![];
-        for (peer_id, peer_state) in self.peer_store.iter() {
-            match peer_state.status {
-                KnownPeerStatus::Banned(_, last_banned) => {
-                    let interval = unwrap_or_error!(
-                        (Utc::now() - from_timestamp(last_banned)).to_std(),
-                        "Failed to convert time"
-                    );
-                    if interval > self.config.ban_window {
-                        info!(target: "network", "Monitor peers: unbanned {} after {:?}.", peer_id, interval);
-                        to_unban.push(peer_id.clone());
-                    }
-                }
-                _ => {}
-            }
-        }
-        for peer_id in to_unban {
+        for peer_id in self.peer_store.expired_bans() {
+            info!(target: "network", "Monitor peers: ban of {} expired.", peer_id);
             unwrap_or_error!(self.peer_store.peer_unban(&peer_id), "Failed to unban a peer");
         }
 
-        if self.is_outbound_bootstrap_needed() {
+        if !self.shutting_down && self.is_outbound_bootstrap_needed() {
             if let Some(peer_info) = self.sample_random_peer(&self.outgoing_peers) {
                 self.outgoing_peers.insert(peer_info.id);
                 ctx.notify(OutboundTcpConnect { peer_info });
@@ -382,6 +615,80 @@ impl PeerManagerActor {
             .spawn(ctx);
     }
 
+    /// Periodically send to each active peer the distances to the peers reachable through us.
+    fn broadcast_routes(&mut self, ctx: &mut Context<Self>) {
+        for (peer_id, active_peer) in self.active_peers.iter() {
+            let distances = self.peer_routes.distances_for(peer_id);
+            active_peer.addr.do_send(SendMessage { message: PeerMessage::Routes(distances) });
+        }
+
+        ctx.run_later(ROUTES_BROADCAST_PERIOD, move |act, ctx| {
+            act.broadcast_routes(ctx);
+        });
+    }
+
+    /// Sends message to the peer through the peers on the route to it.
+    fn send_routed_message(&self, target: PeerId, body: RoutedMessageBody) {
+        let msg = RoutedMessage::new(
+            target,
+            self.peer_id,
+            &self.config.secret_key,
+            MAX_ROUTE_DISTANCE as u8,
+            body,
+        );
+        self.route_message(msg);
+    }
+
+    /// Forwards the routed message to the next peer on the route to its target.
+    fn route_message(&self, msg: RoutedMessage) {
+        match self.peer_routes.next_hop(&msg.target) {
+            Some((peer_id, _)) => {
+                if let Some(active_peer) = self.active_peers.get(&peer_id) {
+                    active_peer.addr.do_send(SendMessage { message: PeerMessage::Routed(msg) });
+                }
+            }
+            None => {
+                debug!(target: "network", "No route to {}, dropping message from {}", msg.target, msg.author);
+            }
+        }
+    }
+
+    /// Passes the routed message addressed to this node to the client and routes the response
+    /// back to the author.
+    fn receive_routed_message(&self, ctx: &mut Context<Self>, msg: RoutedMessage) {
+        let author = msg.author;
+        let client_msg = match msg.body {
+            RoutedMessageBody::BlockApproval(account_id, hash, signature) => {
+                NetworkClientMessages::BlockApproval(account_id, hash, signature)
+            }
+            RoutedMessageBody::Block(block) => NetworkClientMessages::Block(block, author, false),
+            RoutedMessageBody::StateRequest(shard_id, hash, part_id) => {
+                NetworkClientMessages::StateRequest(shard_id, hash, part_id)
+            }
+            RoutedMessageBody::StateResponse(shard_id, hash, part, receipts) => {
+                NetworkClientMessages::StateResponse(shard_id, hash, part, receipts, author)
+            }
+        };
+        self.client_addr
+            .send(client_msg)
+            .into_actor(self)
+            .map_err(|e, _, _| error!("Failed sending message to client: {}", e))
+            .and_then(move |res, act, _| {
+                match res {
+                    NetworkClientResponses::StateResponse { shard_id, hash, part, receipts } => {
+                        let body = RoutedMessageBody::StateResponse(shard_id, hash, part, receipts);
+                        act.send_routed_message(author, body);
+                    }
+                    NetworkClientResponses::ReportPeer { reason } => {
+                        act.report_misbehavior(&author, reason);
+                    }
+                    _ => {}
+                }
+                actix::fut::ok(())
+            })
+            .spawn(ctx);
+    }
+
     fn announce_account(&mut self, ctx: &mut Context<Self>, mut announce_account: AnnounceAccount) {
         // If this is a new account send an announcement to random set of peers.
         if self.routing_table.update(&announce_account).is_new() {
@@ -395,29 +702,29 @@ impl PeerManagerActor {
         }
     }
 
-    /// Send message to specific account.
-    fn send_message_to_account(
-        &self,
-        ctx: &mut Context<Self>,
-        account_id: AccountId,
-        msg: SendMessage,
-    ) {
+    /// Send message to the peer of the validator account, through other peers if it's not
+    /// connected directly.
+    fn send_message_to_account(&self, account_id: AccountId, body: RoutedMessageBody) {
         if let Some(peer_id) = self.routing_table.get_route(&account_id) {
-            if let Some(active_peer) = self.active_peers.get(peer_id) {
-                active_peer
-                    .addr
-                    .send(msg)
-                    .into_actor(self)
-                    .map_err(|e, _, _| error!("Failed sending message: {}", e))
-                    .and_then(|_, _, _| actix::fut::ok(()))
-                    .spawn(ctx);
-            } else {
-                error!(target: "network", "Missing peer {:?} that is related to account {}", peer_id, account_id);
-            }
+            self.send_routed_message(*peer_id, body);
         } else {
             warn!(target: "network", "Unknown account {} in routing table.", account_id);
         }
     }
+
+    /// Sends the approvals to the block producers they are for.
+    fn send_block_approvals(&self, approvals: Vec<BlockApproval>) {
+        for approval in approvals {
+            self.send_message_to_account(
+                approval.target,
+                RoutedMessageBody::BlockApproval(
+                    approval.account_id,
+                    approval.hash,
+                    approval.signature,
+                ),
+            );
+        }
+    }
 }
 
 impl Actor for PeerManagerActor {
@@ -437,6 +744,20 @@ impl Actor for PeerManagerActor {
 
         // Start active peer stats querying.
         self.monitor_peer_stats(ctx);
+
+        // Start sending routes to the active peers.
+        self.broadcast_routes(ctx);
+
+        // Start exchanging known peers with the active peers.
+        self.gossip_peers(ctx);
+
+        // Private validator is dialed only by its sentries and node behind a proxy is not dialed
+        // directly, they don't need the port mapped.
+        if self.config.upnp && !self.config.hides_address() {
+            if let Some(server_addr) = self.config.addr {
+                self.map_upnp_port(ctx, server_addr.port());
+            }
+        }
     }
 }
 
@@ -455,37 +776,57 @@ impl Handler<NetworkRequests> for PeerManagerActor {
                     num_active_peers: self.num_active_peers(),
                     peer_max_count: self.config.peer_max_count,
                     most_weight_peers: self.most_weight_peers(),
+                    active_peers: self
+                        .active_peers
+                        .values()
+                        .map(|active_peer| active_peer.full_peer_info.clone())
+                        .collect(),
                     sent_bytes_per_sec,
                     received_bytes_per_sec,
+                    dropped_messages: self.dropped_messages,
+                    external_address: self.external_address(),
                     routes,
                 })
             }
-            NetworkRequests::Block { block } => {
-                self.broadcast_message(ctx, SendMessage { message: PeerMessage::Block(block) });
-                NetworkResponses::NoResponse
-            }
-            NetworkRequests::BlockHeaderAnnounce { header, approval } => {
-                if let Some(approval) = approval {
-                    if let Some(account_id) = &self.config.account_id {
-                        self.send_message_to_account(
-                            ctx,
-                            approval.target,
-                            SendMessage {
-                                message: PeerMessage::BlockApproval(
-                                    account_id.clone(),
-                                    approval.hash,
-                                    approval.signature,
-                                ),
-                            },
-                        );
+            NetworkRequests::Block { block, targets } => {
+                let mut pushed_peers = HashSet::new();
+                for account_id in targets {
+                    if let Some(peer_id) = self.routing_table.get_route(&account_id).cloned() {
+                        if pushed_peers.insert(peer_id) {
+                            let body = RoutedMessageBody::Block(block.clone());
+                            self.send_routed_message(peer_id, body);
+                        }
+                    } else {
+                        debug!(target: "network", "Unknown account {} to push block to", account_id);
                     }
                 }
+                // Peers that got the block directly don't need it once more.
+                let message = PeerMessage::Block(block);
+                let requests: Vec<_> = self
+                    .active_peers
+                    .iter()
+                    .filter(|(peer_id, _)| !pushed_peers.contains(*peer_id))
+                    .map(|(_, peer)| peer.addr.send(SendMessage { message: message.clone() }))
+                    .collect();
+                future::join_all(requests)
+                    .into_actor(self)
+                    .map_err(|e, _, _| error!("Failed sending block: {}", e))
+                    .and_then(|_, _, _| actix::fut::ok(()))
+                    .spawn(ctx);
+                NetworkResponses::NoResponse
+            }
+            NetworkRequests::BlockHeaderAnnounce { header, approvals } => {
+                self.send_block_approvals(approvals);
                 self.broadcast_message(
                     ctx,
                     SendMessage { message: PeerMessage::BlockHeaderAnnounce(header) },
                 );
                 NetworkResponses::NoResponse
             }
+            NetworkRequests::BlockApprovals { approvals } => {
+                self.send_block_approvals(approvals);
+                NetworkResponses::NoResponse
+            }
             NetworkRequests::BlockRequest { hash, peer_id } => {
                 if let Some(active_peer) = self.active_peers.get(&peer_id) {
                     active_peer
@@ -494,33 +835,80 @@ impl Handler<NetworkRequests> for PeerManagerActor {
                 }
                 NetworkResponses::NoResponse
             }
-            NetworkRequests::BlockHeadersRequest { hashes, peer_id } => {
+            NetworkRequests::BlockHeadersRequest { hashes, skip, peer_id } => {
                 if let Some(active_peer) = self.active_peers.get(&peer_id) {
-                    active_peer
-                        .addr
-                        .do_send(SendMessage { message: PeerMessage::BlockHeadersRequest(hashes) });
+                    // Plain request is understood by peers that don't support ranges.
+                    let message = if skip == 0 {
+                        PeerMessage::BlockHeadersRequest(hashes)
+                    } else {
+                        PeerMessage::BlockHeadersRangeRequest(hashes, skip)
+                    };
+                    active_peer.addr.do_send(SendMessage { message });
                 }
                 NetworkResponses::NoResponse
             }
-            NetworkRequests::StateRequest { shard_id, hash, peer_id } => {
+            NetworkRequests::StateRequest { shard_id, hash, part_id, peer_id } => {
                 if let Some(active_peer) = self.active_peers.get(&peer_id) {
                     active_peer.addr.do_send(SendMessage {
-                        message: PeerMessage::StateRequest(shard_id, hash),
+                        message: PeerMessage::StateRequest(shard_id, hash, part_id),
                     });
+                } else {
+                    self.send_routed_message(
+                        peer_id,
+                        RoutedMessageBody::StateRequest(shard_id, hash, part_id),
+                    );
                 }
                 NetworkResponses::NoResponse
             }
-            NetworkRequests::BanPeer { peer_id, ban_reason } => {
-                if let Some(_) = self.active_peers.get(&peer_id) {
-                    // TODO: send stop signal to the addr.
-                }
-                self.ban_peer(&peer_id, ban_reason);
+            NetworkRequests::Transaction { transaction } => {
+                self.broadcast_message(
+                    ctx,
+                    SendMessage { message: PeerMessage::Transaction(transaction) },
+                );
+                NetworkResponses::NoResponse
+            }
+            NetworkRequests::ReportPeer { peer_id, reason } => {
+                self.report_misbehavior(&peer_id, reason);
+                NetworkResponses::NoResponse
+            }
+            NetworkRequests::BanPeer { peer_id, reason } => {
+                let mut update = PeerScore::default();
+                update.record_bad_data();
+                self.update_peer_score(&peer_id, &update);
+                self.ban_peer(&peer_id, reason, false);
                 NetworkResponses::NoResponse
             }
             NetworkRequests::AnnounceAccount(announce_account) => {
                 self.announce_account(ctx, announce_account);
                 NetworkResponses::NoResponse
             }
+            NetworkRequests::Shutdown => {
+                info!(target: "network", "Disconnecting from {} peers", self.active_peers.len());
+                self.shutting_down = true;
+                self.broadcast_message(ctx, SendMessage { message: PeerMessage::Disconnect });
+                NetworkResponses::NoResponse
+            }
+            NetworkRequests::UpdateConfig { peer_max_count } => {
+                info!(target: "network", "Updating max peer count {} -> {}", self.config.peer_max_count, peer_max_count);
+                self.config.peer_max_count = peer_max_count;
+                NetworkResponses::NoResponse
+            }
+            NetworkRequests::FetchBans => NetworkResponses::Bans(self.peer_store.bans()),
+            NetworkRequests::Unban { peer_id } => {
+                if !self.peer_store.is_banned(&peer_id) {
+                    return NetworkResponses::Unbanned(false);
+                }
+                info!(target: "network", "Unbanning peer {} on request", peer_id);
+                // Misbehavior that led to the ban is forgotten, so the next ban is not permanent.
+                self.ban_policy.forget(&peer_id);
+                match self.peer_store.peer_unban(&peer_id) {
+                    Ok(()) => NetworkResponses::Unbanned(true),
+                    Err(err) => {
+                        error!(target: "network", "Failed to unban peer {}: {}", peer_id, err);
+                        NetworkResponses::Unbanned(false)
+                    }
+                }
+            }
         }
     }
 }
@@ -529,6 +917,9 @@ impl Handler<InboundTcpConnect> for PeerManagerActor {
     type Result = ();
 
     fn handle(&mut self, msg: InboundTcpConnect, ctx: &mut Self::Context) {
+        if self.shutting_down {
+            return;
+        }
         self.connect_peer(ctx.address(), msg.stream, PeerType::Inbound, None);
     }
 }
@@ -538,27 +929,19 @@ impl Handler<OutboundTcpConnect> for PeerManagerActor {
 
     fn handle(&mut self, msg: OutboundTcpConnect, ctx: &mut Self::Context) {
         if let Some(addr) = msg.peer_info.addr {
-            Resolver::from_registry()
-                .send(ConnectAddr(addr))
+            self.connect(addr)
                 .into_actor(self)
                 .then(move |res, act, ctx| match res {
-                    Ok(res) => match res {
-                        Ok(stream) => {
-                            debug!(target: "network", "Connected to {}", msg.peer_info);
-                            act.connect_peer(
-                                ctx.address(),
-                                stream,
-                                PeerType::Outbound,
-                                Some(msg.peer_info),
-                            );
-                            actix::fut::ok(())
-                        }
-                        Err(err) => {
-                            info!(target: "network", "Error connecting to {}: {}", addr, err);
-                            act.outgoing_peers.remove(&msg.peer_info.id);
-                            actix::fut::err(())
-                        }
-                    },
+                    Ok(stream) => {
+                        debug!(target: "network", "Connected to {}", msg.peer_info);
+                        act.connect_peer(
+                            ctx.address(),
+                            stream,
+                            PeerType::Outbound,
+                            Some(msg.peer_info),
+                        );
+                        actix::fut::ok(())
+                    }
                     Err(err) => {
                         info!(target: "network", "Error connecting to {}: {}", addr, err);
                         act.outgoing_peers.remove(&msg.peer_info.id);
@@ -580,6 +963,14 @@ impl Handler<Consolidate> for PeerManagerActor {
         if self.active_peers.contains_key(&msg.peer_info.id) {
             return false;
         }
+        if self.peer_store.is_banned(&msg.peer_info.id) {
+            debug!(target: "network", "Refusing connection from banned peer {}", msg.peer_info.id);
+            return false;
+        }
+        if !self.config.is_peer_allowed(&msg.peer_info.id, msg.peer_info.addr) {
+            debug!(target: "network", "Refusing connection with peer {} not allowed by the blacklist or whitelist", msg.peer_info);
+            return false;
+        }
         // This is incoming connection but we have this peer already in outgoing.
         // This only happens when both of us connect at the same time, break tie using higher peer id.
         if msg.peer_type == PeerType::Inbound && self.outgoing_peers.contains(&msg.peer_info.id) {
@@ -589,9 +980,21 @@ impl Handler<Consolidate> for PeerManagerActor {
             }
         }
         // TODO: double check that address is connectable and add account id.
+        if let Some(observed_addr) = msg.observed_addr {
+            self.observed_ips.insert(msg.peer_info.id, observed_addr.ip());
+        }
+        let score = self.peer_store.peer_score(&msg.peer_info.id);
         self.register_peer(
-            FullPeerInfo { peer_info: msg.peer_info, chain_info: msg.chain_info },
+            FullPeerInfo {
+                peer_info: msg.peer_info,
+                chain_info: msg.chain_info,
+                protocol_version: msg.protocol_version,
+                score,
+                latency: None,
+                last_seen: Utc::now(),
+            },
             msg.actor,
+            msg.private,
         );
         true
     }
@@ -605,11 +1008,11 @@ impl Handler<Unregister> for PeerManagerActor {
     }
 }
 
-impl Handler<Ban> for PeerManagerActor {
-    type Result = ();
+impl Handler<ReportMisbehavior> for PeerManagerActor {
+    type Result = BanResponse;
 
-    fn handle(&mut self, msg: Ban, _ctx: &mut Self::Context) {
-        self.ban_peer(&msg.peer_id, msg.ban_reason);
+    fn handle(&mut self, msg: ReportMisbehavior, _ctx: &mut Self::Context) -> Self::Result {
+        self.report_misbehavior(&msg.peer_id, msg.reason)
     }
 }
 
@@ -617,7 +1020,7 @@ impl Handler<PeersRequest> for PeerManagerActor {
     type Result = PeerList;
 
     fn handle(&mut self, _msg: PeersRequest, _ctx: &mut Self::Context) -> Self::Result {
-        PeerList { peers: self.peer_store.healthy_peers(self.config.max_send_peers) }
+        PeerList { peers: self.peer_store.peers_to_share(self.config.max_send_peers) }
     }
 }
 
@@ -625,8 +1028,60 @@ impl Handler<PeersResponse> for PeerManagerActor {
     type Result = ();
 
     fn handle(&mut self, msg: PeersResponse, _ctx: &mut Self::Context) {
-        self.peer_store.add_peers(
-            msg.peers.into_iter().filter(|peer_info| peer_info.id != self.peer_id).collect(),
-        );
+        let peers: Vec<_> = msg
+            .peers
+            .into_iter()
+            .take(self.config.max_send_peers as usize)
+            .filter(|peer_info| {
+                peer_info.id != self.peer_id
+                    && self.config.is_peer_allowed(&peer_info.id, peer_info.addr)
+            })
+            .collect();
+        let num_new_peers =
+            unwrap_or_error!(self.peer_store.add_peers(peers), "Failed to add peers");
+        if num_new_peers > 0 {
+            debug!(target: "network", "Learned about {} new peers, {} known peers", num_new_peers, self.peer_store.len());
+        }
+    }
+}
+
+impl Handler<UpnpMapped> for PeerManagerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpnpMapped, _ctx: &mut Self::Context) {
+        if self.upnp_external_ip != Some(msg.external_ip) {
+            info!(target: "network", "Mapped listening port with UPnP, external IP {}", msg.external_ip);
+            self.upnp_external_ip = Some(msg.external_ip);
+        }
+    }
+}
+
+impl Handler<RoutesUpdate> for PeerManagerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoutesUpdate, _ctx: &mut Self::Context) {
+        if !self.peer_routes.update(&msg.peer_id, msg.distances) {
+            debug!(target: "network", "Ignoring routes from peer {} that is not active", msg.peer_id);
+        }
+    }
+}
+
+impl Handler<RoutedMessageFrom> for PeerManagerActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: RoutedMessageFrom, ctx: &mut Self::Context) {
+        let RoutedMessageFrom { mut msg, from } = msg;
+        if msg.target != self.peer_id {
+            if msg.ttl == 0 {
+                debug!(target: "network", "Dropping routed message from {} to {} received from {}: ttl expired", msg.author, msg.target, from);
+                return;
+            }
+            msg.ttl -= 1;
+            self.route_message(msg);
+        } else if msg.verify() {
+            self.receive_routed_message(ctx, msg);
+        } else {
+            warn!(target: "network", "Dropping routed message with invalid signature of {} received from {}", msg.author, from);
+        }
     }
 }
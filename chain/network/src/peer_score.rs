@@ -0,0 +1,132 @@
+//! Tracks how well a peer serves sync requests: latency of its responses, requests it left
+//! unanswered and invalid data it sent. The score derived from this history is used to prefer fast
+//! and reliable peers when requesting headers, blocks and state parts.
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Latency assumed for peers that haven't responded to any request yet, in milliseconds.
+const DEFAULT_LATENCY_MS: u64 = 1000;
+
+/// Number of requests after which the history is halved, so that recent behavior dominates.
+const MAX_REQUESTS_HISTORY: u64 = 1000;
+
+/// Score of a peer without any issues that responds instantly.
+const MAX_SCORE: f64 = 1_000_000.0;
+
+/// Response history of a peer.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct PeerScore {
+    /// Number of requests the peer responded to.
+    pub num_responses: u64,
+    /// Sum of the latencies of the responses, in milliseconds.
+    pub total_latency_ms: u64,
+    /// Number of requests the peer didn't respond to in time.
+    pub num_timeouts: u64,
+    /// Number of times the peer was reported for sending invalid data.
+    pub num_bad_data: u64,
+}
+
+impl PeerScore {
+    pub fn record_response(&mut self, latency_ms: u64) {
+        self.num_responses += 1;
+        self.total_latency_ms += latency_ms;
+        self.decay();
+    }
+
+    pub fn record_timeout(&mut self) {
+        self.num_timeouts += 1;
+        self.decay();
+    }
+
+    pub fn record_bad_data(&mut self) {
+        self.num_bad_data += 1;
+    }
+
+    /// Adds the history collected elsewhere, e.g. by the peer actor since the last stats query.
+    pub fn merge(&mut self, other: &PeerScore) {
+        self.num_responses += other.num_responses;
+        self.total_latency_ms += other.total_latency_ms;
+        self.num_timeouts += other.num_timeouts;
+        self.num_bad_data += other.num_bad_data;
+        self.decay();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == PeerScore::default()
+    }
+
+    fn decay(&mut self) {
+        if self.num_responses + self.num_timeouts > MAX_REQUESTS_HISTORY {
+            self.num_responses /= 2;
+            self.total_latency_ms /= 2;
+            self.num_timeouts /= 2;
+            self.num_bad_data /= 2;
+        }
+    }
+
+    pub fn average_latency_ms(&self) -> u64 {
+        if self.num_responses == 0 {
+            DEFAULT_LATENCY_MS
+        } else {
+            self.total_latency_ms / self.num_responses
+        }
+    }
+
+    /// Score of the peer, higher is better and never zero. Proportional to the share of answered
+    /// requests, decreases with the latency and with every incident of invalid data.
+    pub fn score(&self) -> u64 {
+        // New peers are assumed to have answered one request, so that they are not dismissed.
+        let success = (self.num_responses + 1) as f64
+            / (self.num_responses + self.num_timeouts + 1) as f64;
+        let latency = DEFAULT_LATENCY_MS as f64
+            / (DEFAULT_LATENCY_MS + self.average_latency_ms()) as f64;
+        let bad_data = 1.0 / (self.num_bad_data + 1) as f64;
+        ((MAX_SCORE * success * latency * bad_data) as u64).max(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_score_ordering() {
+        let new_peer = PeerScore::default();
+        let mut fast_peer = PeerScore::default();
+        let mut slow_peer = PeerScore::default();
+        for _ in 0..10 {
+            fast_peer.record_response(100);
+            slow_peer.record_response(5000);
+        }
+        assert!(fast_peer.score() > new_peer.score());
+        assert!(new_peer.score() > slow_peer.score());
+
+        let mut unreliable_peer = fast_peer.clone();
+        for _ in 0..10 {
+            unreliable_peer.record_timeout();
+        }
+        assert!(unreliable_peer.score() < fast_peer.score());
+        let mut bad_peer = fast_peer.clone();
+        bad_peer.record_bad_data();
+        assert!(bad_peer.score() < fast_peer.score());
+        for _ in 0..100 {
+            bad_peer.record_bad_data();
+        }
+        assert!(bad_peer.score() >= 1);
+    }
+
+    #[test]
+    fn test_peer_score_decay() {
+        let mut score = PeerScore::default();
+        for _ in 0..MAX_REQUESTS_HISTORY {
+            score.record_timeout();
+        }
+        let unreliable = score.score();
+        // Recent responses outweigh the old timeouts.
+        for _ in 0..2 * MAX_REQUESTS_HISTORY {
+            score.record_response(100);
+        }
+        assert!(score.num_responses + score.num_timeouts <= MAX_REQUESTS_HISTORY);
+        assert!(score.num_timeouts < 100);
+        assert!(score.score() > unreliable * 10);
+    }
+}
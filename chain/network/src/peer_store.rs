@@ -1,16 +1,19 @@
 use std::collections::{hash_map::Iter, HashMap, HashSet};
 use std::convert::TryInto;
 use std::sync::Arc;
+use std::time::Duration;
 
-use borsh::BorshSerialize;
+use borsh::{BorshDeserialize, BorshSerialize};
 use chrono::Utc;
 use log::debug;
 use rand::seq::SliceRandom;
 use rand::thread_rng;
 
-use near_primitives::utils::to_timestamp;
-use near_store::{Store, COL_PEERS};
+use near_primitives::utils::{from_timestamp, to_timestamp};
+use near_primitives::views::PeerBanView;
+use near_store::{Store, COL_PEERS, COL_PEER_SCORES};
 
+use crate::peer_score::PeerScore;
 use crate::types::{
     FullPeerInfo, KnownPeerState, KnownPeerStatus, NetworkConfig, PeerId, PeerInfo, ReasonForBan,
 };
@@ -19,6 +22,8 @@ use crate::types::{
 pub struct PeerStore {
     store: Arc<Store>,
     peer_states: HashMap<PeerId, KnownPeerState>,
+    /// Response history of the known peers, kept separately from their state.
+    peer_scores: HashMap<PeerId, PeerScore>,
 }
 
 impl PeerStore {
@@ -32,10 +37,9 @@ impl PeerStore {
             let value: Vec<u8> = value.into();
             let peer_id: PeerId = key.try_into()?;
             let mut peer_state: KnownPeerState = value.try_into()?;
-            match peer_state.status {
-                KnownPeerStatus::Banned(_, _) => {}
-                _ => peer_state.status = KnownPeerStatus::NotConnected,
-            };
+            if !peer_state.status.is_banned() {
+                peer_state.status = KnownPeerStatus::NotConnected;
+            }
             peer_states.insert(peer_id, peer_state);
         }
         for peer_info in boot_nodes.iter() {
@@ -43,7 +47,12 @@ impl PeerStore {
                 peer_states.insert(peer_info.id, KnownPeerState::new(peer_info.clone()));
             }
         }
-        Ok(PeerStore { store, peer_states })
+        let mut peer_scores = HashMap::default();
+        for (key, value) in store.iter(COL_PEER_SCORES) {
+            let peer_id: PeerId = key.to_vec().try_into()?;
+            peer_scores.insert(peer_id, PeerScore::try_from_slice(&value)?);
+        }
+        Ok(PeerStore { store, peer_states, peer_scores })
     }
 
     pub fn len(&self) -> usize {
@@ -71,7 +80,10 @@ impl PeerStore {
     ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(peer_state) = self.peer_states.get_mut(peer_id) {
             peer_state.last_seen = to_timestamp(Utc::now());
-            peer_state.status = KnownPeerStatus::NotConnected;
+            // Peer that was banned while connected stays banned.
+            if !peer_state.status.is_banned() {
+                peer_state.status = KnownPeerStatus::NotConnected;
+            }
             let mut store_update = self.store.store_update();
             store_update.set_ser(COL_PEERS, &peer_id.try_to_vec()?, peer_state)?;
             store_update.commit().map_err(|err| err.into())
@@ -80,14 +92,33 @@ impl PeerStore {
         }
     }
 
+    /// Bans the peer for given duration, the ban is kept across restarts until it expires.
+    /// Unknown peers are added, so that peers rejected during the handshake can be banned too.
     pub fn peer_ban(
         &mut self,
         peer_id: &PeerId,
         ban_reason: ReasonForBan,
+        duration: Duration,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let until = Utc::now() + chrono::Duration::from_std(duration)?;
+        let peer_state = self.peer_states.entry(*peer_id).or_insert_with(|| {
+            KnownPeerState::new(PeerInfo { id: *peer_id, addr: None, account_id: None })
+        });
+        peer_state.last_seen = to_timestamp(Utc::now());
+        peer_state.status = KnownPeerStatus::Banned(ban_reason, to_timestamp(until));
+        let mut store_update = self.store.store_update();
+        store_update.set_ser(COL_PEERS, &peer_id.try_to_vec()?, peer_state)?;
+        store_update.commit().map_err(|err| err.into())
+    }
+
+    pub fn peer_ban_permanently(
+        &mut self,
+        peer_id: &PeerId,
+        ban_reason: ReasonForBan,
     ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(peer_state) = self.peer_states.get_mut(peer_id) {
             peer_state.last_seen = to_timestamp(Utc::now());
-            peer_state.status = KnownPeerStatus::Banned(ban_reason, to_timestamp(Utc::now()));
+            peer_state.status = KnownPeerStatus::BannedPermanently(ban_reason);
             let mut store_update = self.store.store_update();
             store_update.set_ser(COL_PEERS, &peer_id.try_to_vec()?, peer_state)?;
             store_update.commit().map_err(|err| err.into())
@@ -107,6 +138,27 @@ impl PeerStore {
         }
     }
 
+    /// Score of the peer based on its response history.
+    pub fn peer_score(&self, peer_id: &PeerId) -> u64 {
+        self.peer_scores.get(peer_id).cloned().unwrap_or_default().score()
+    }
+
+    /// Adds responses of the peer to its history and returns the new score.
+    pub fn update_peer_score(
+        &mut self,
+        peer_id: &PeerId,
+        update: &PeerScore,
+    ) -> Result<u64, Box<dyn std::error::Error>> {
+        let peer_score = self.peer_scores.entry(*peer_id).or_insert_with(PeerScore::default);
+        if !update.is_empty() {
+            peer_score.merge(update);
+            let mut store_update = self.store.store_update();
+            store_update.set_ser(COL_PEER_SCORES, &peer_id.try_to_vec()?, peer_score)?;
+            store_update.commit()?;
+        }
+        Ok(peer_score.score())
+    }
+
     fn find_peers<F>(&self, mut filter: F, count: u32) -> Vec<PeerInfo>
     where
         F: FnMut(&KnownPeerState) -> bool,
@@ -123,6 +175,39 @@ impl PeerStore {
         peers.iter().take(count as usize).cloned().collect::<Vec<_>>()
     }
 
+    /// Whether given peer is currently banned.
+    pub fn is_banned(&self, peer_id: &PeerId) -> bool {
+        self.peer_states.get(peer_id).map_or(false, |p| p.status.is_banned())
+    }
+
+    /// Temporarily banned peers whose ban has expired.
+    pub fn expired_bans(&self) -> Vec<PeerId> {
+        let now = to_timestamp(Utc::now());
+        self.peer_states
+            .iter()
+            .filter_map(|(peer_id, peer_state)| match peer_state.status {
+                KnownPeerStatus::Banned(_, until) if until <= now => Some(*peer_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// All the banned peers with the reason and the expiration of the ban.
+    pub fn bans(&self) -> Vec<PeerBanView> {
+        self.peer_states
+            .iter()
+            .filter_map(|(peer_id, peer_state)| {
+                let (reason, until) = match peer_state.status {
+                    KnownPeerStatus::Banned(reason, until) => (reason, Some(from_timestamp(until))),
+                    KnownPeerStatus::BannedPermanently(reason) => (reason, None),
+                    _ => return None,
+                };
+                let reason = format!("{:?}", reason);
+                Some(PeerBanView { peer_id: peer_id.to_string(), reason, until })
+            })
+            .collect()
+    }
+
     /// Return unconnected or peers with unknown status that we can try to connect to.
     pub fn unconnected_peers(&self, ignore_list: &HashSet<PeerId>) -> Vec<PeerInfo> {
         self.find_peers(
@@ -138,14 +223,16 @@ impl PeerStore {
     pub fn healthy_peers(&self, max_count: u32) -> Vec<PeerInfo> {
         // TODO: better healthy peer definition here.
         self.find_peers(
-            |p| match p.status {
-                KnownPeerStatus::Banned(_, _) => false,
-                _ => true,
-            },
+            |p| !p.status.is_banned(),
             max_count,
         )
     }
 
+    /// Return up to given amount of known peers with an address to share with other peers.
+    pub fn peers_to_share(&self, max_count: u32) -> Vec<PeerInfo> {
+        self.find_peers(|p| !p.status.is_banned() && p.peer_info.addr.is_some(), max_count)
+    }
+
     /// Return iterator over all known peers.
     pub fn iter(&self) -> Iter<PeerId, KnownPeerState> {
         self.peer_states.iter()
@@ -161,6 +248,7 @@ impl PeerStore {
         for (peer_id, peer_status) in self.peer_states.iter() {
             let diff = (now - peer_status.last_seen()).to_std()?;
             if peer_status.status != KnownPeerStatus::Connected
+                && !peer_status.status.is_banned()
                 && diff > config.peer_expiration_duration
             {
                 debug!(target: "network", "Removing peer: last seen {:?}", diff);
@@ -170,17 +258,29 @@ impl PeerStore {
         let mut store_update = self.store.store_update();
         for peer_id in to_remove {
             self.peer_states.remove(&peer_id);
+            self.peer_scores.remove(&peer_id);
             store_update.delete(COL_PEERS, &peer_id.try_to_vec()?);
+            store_update.delete(COL_PEER_SCORES, &peer_id.try_to_vec()?);
         }
         store_update.commit().map_err(|err| err.into())
     }
 
-    pub fn add_peers(&mut self, peers: Vec<PeerInfo>) {
+    /// Adds peers learned from other peers, so that they are known after a restart. Peers without
+    /// an address can't be dialed and are skipped. Returns number of new peers.
+    pub fn add_peers(&mut self, peers: Vec<PeerInfo>) -> Result<usize, Box<dyn std::error::Error>> {
+        let mut store_update = self.store.store_update();
+        let mut num_new_peers = 0;
         for peer_info in peers.into_iter() {
-            if !self.peer_states.contains_key(&peer_info.id) {
-                self.peer_states.insert(peer_info.id, KnownPeerState::new(peer_info));
+            if peer_info.addr.is_none() || self.peer_states.contains_key(&peer_info.id) {
+                continue;
             }
+            let peer_state = KnownPeerState::new(peer_info);
+            store_update.set_ser(COL_PEERS, &peer_state.peer_info.id.try_to_vec()?, &peer_state)?;
+            self.peer_states.insert(peer_state.peer_info.id, peer_state);
+            num_new_peers += 1;
         }
+        store_update.commit()?;
+        Ok(num_new_peers)
     }
 }
 
@@ -211,13 +311,111 @@ mod test {
             let store = create_store(tmp_dir.path().to_str().unwrap());
             let mut peer_store = PeerStore::new(store, &boot_nodes).unwrap();
             assert_eq!(peer_store.healthy_peers(3).iter().count(), 2);
-            peer_store.peer_ban(&peer_info_to_ban.id, ReasonForBan::Abusive).unwrap();
+            peer_store
+                .peer_ban(&peer_info_to_ban.id, ReasonForBan::Abusive, Duration::from_secs(3600))
+                .unwrap();
             assert_eq!(peer_store.healthy_peers(3).iter().count(), 1);
         }
+        {
+            let store_new = create_store(tmp_dir.path().to_str().unwrap());
+            let mut peer_store_new = PeerStore::new(store_new, &boot_nodes).unwrap();
+            assert_eq!(peer_store_new.healthy_peers(3).iter().count(), 1);
+            assert!(peer_store_new.expired_bans().is_empty());
+            let bans = peer_store_new.bans();
+            assert_eq!(bans.len(), 1);
+            assert_eq!(bans[0].peer_id, peer_info_to_ban.id.to_string());
+            assert_eq!(bans[0].reason, "Abusive");
+            assert!(bans[0].until.unwrap() > Utc::now());
+            peer_store_new.peer_unban(&peer_info_to_ban.id).unwrap();
+        }
+        {
+            let store_new = create_store(tmp_dir.path().to_str().unwrap());
+            let mut peer_store_new = PeerStore::new(store_new, &boot_nodes).unwrap();
+            assert!(peer_store_new.bans().is_empty());
+            // Bans of zero duration expire right away.
+            peer_store_new
+                .peer_ban(&peer_info_a.id, ReasonForBan::BadBlock, Duration::from_secs(0))
+                .unwrap();
+            assert_eq!(peer_store_new.expired_bans(), vec![peer_info_a.id]);
+            // Peers not known yet can be banned as well.
+            let unknown_peer = gen_peer_info();
+            peer_store_new
+                .peer_ban(&unknown_peer.id, ReasonForBan::WrongNetwork, Duration::from_secs(3600))
+                .unwrap();
+            assert!(peer_store_new.is_banned(&unknown_peer.id));
+        }
+    }
+
+    #[test]
+    fn ban_permanently_survives_disconnect() {
+        let tmp_dir = tempdir::TempDir::new("_test_store_ban_permanently").unwrap();
+        let peer_info_a = gen_peer_info();
+        let peer_info_to_ban = gen_peer_info();
+        let boot_nodes = vec![peer_info_a.clone(), peer_info_to_ban.clone()];
+        {
+            let store = create_store(tmp_dir.path().to_str().unwrap());
+            let mut peer_store = PeerStore::new(store, &boot_nodes).unwrap();
+            peer_store
+                .peer_ban_permanently(&peer_info_to_ban.id, ReasonForBan::BadBlock)
+                .unwrap();
+            peer_store.peer_disconnected(&peer_info_to_ban.id).unwrap();
+            assert!(peer_store.is_banned(&peer_info_to_ban.id));
+            assert!(!peer_store.is_banned(&peer_info_a.id));
+        }
         {
             let store_new = create_store(tmp_dir.path().to_str().unwrap());
             let peer_store_new = PeerStore::new(store_new, &boot_nodes).unwrap();
+            assert!(peer_store_new.is_banned(&peer_info_to_ban.id));
             assert_eq!(peer_store_new.healthy_peers(3).iter().count(), 1);
         }
     }
+
+    #[test]
+    fn added_peers_persisted() {
+        let tmp_dir = tempdir::TempDir::new("_test_store_add_peers").unwrap();
+        let boot_node = gen_peer_info();
+        let mut peer_info_a = gen_peer_info();
+        peer_info_a.addr = Some("127.0.0.1:24567".parse().unwrap());
+        let peer_info_without_addr = gen_peer_info();
+        let boot_nodes = vec![boot_node.clone()];
+        {
+            let store = create_store(tmp_dir.path().to_str().unwrap());
+            let mut peer_store = PeerStore::new(store, &boot_nodes).unwrap();
+            let peers = vec![peer_info_a.clone(), peer_info_without_addr, boot_node];
+            assert_eq!(peer_store.add_peers(peers).unwrap(), 1);
+            assert_eq!(peer_store.add_peers(vec![peer_info_a.clone()]).unwrap(), 0);
+            assert_eq!(peer_store.len(), 2);
+        }
+        {
+            let store_new = create_store(tmp_dir.path().to_str().unwrap());
+            let peer_store_new = PeerStore::new(store_new, &boot_nodes).unwrap();
+            assert_eq!(peer_store_new.len(), 2);
+            // Only peers that can be dialed are shared.
+            assert_eq!(peer_store_new.peers_to_share(10), vec![peer_info_a]);
+        }
+    }
+
+    #[test]
+    fn peer_score_persisted() {
+        let tmp_dir = tempdir::TempDir::new("_test_store_peer_score").unwrap();
+        let peer_info_a = gen_peer_info();
+        let peer_info_b = gen_peer_info();
+        let boot_nodes = vec![peer_info_a.clone(), peer_info_b.clone()];
+        let score = {
+            let store = create_store(tmp_dir.path().to_str().unwrap());
+            let mut peer_store = PeerStore::new(store, &boot_nodes).unwrap();
+            let mut update = PeerScore::default();
+            update.record_response(100);
+            update.record_timeout();
+            let score = peer_store.update_peer_score(&peer_info_a.id, &update).unwrap();
+            assert_eq!(peer_store.peer_score(&peer_info_a.id), score);
+            assert_ne!(peer_store.peer_score(&peer_info_b.id), score);
+            score
+        };
+        {
+            let store_new = create_store(tmp_dir.path().to_str().unwrap());
+            let peer_store_new = PeerStore::new(store_new, &boot_nodes).unwrap();
+            assert_eq!(peer_store_new.peer_score(&peer_info_a.id), score);
+        }
+    }
 }
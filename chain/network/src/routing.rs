@@ -0,0 +1,143 @@
+//! Distance-vector routing between peers. Every node periodically sends to its direct peers the
+//! number of hops to all the peers it can reach, and routes messages for the peers it isn't
+//! connected to through the direct peer with the shortest distance to them.
+use std::collections::{HashMap, HashSet};
+
+use crate::types::PeerId;
+
+/// Routes longer than this are dropped, which bounds counting to infinity after a disconnect.
+pub const MAX_ROUTE_DISTANCE: u32 = 16;
+
+/// Distances to the peers reported by each of the direct peers.
+pub struct PeerRoutingTable {
+    /// Peer id of this node.
+    peer_id: PeerId,
+    /// Direct peer -> peer it can reach -> number of hops from the direct peer to it.
+    peer_distances: HashMap<PeerId, HashMap<PeerId, u32>>,
+}
+
+impl PeerRoutingTable {
+    pub fn new(peer_id: PeerId) -> Self {
+        PeerRoutingTable { peer_id, peer_distances: HashMap::default() }
+    }
+
+    pub fn add_peer(&mut self, peer_id: PeerId) {
+        self.peer_distances.entry(peer_id).or_insert_with(HashMap::default);
+    }
+
+    /// Removes the direct peer together with all the routes through it.
+    pub fn remove_peer(&mut self, peer_id: &PeerId) {
+        self.peer_distances.remove(peer_id);
+    }
+
+    /// Replaces the distances reported by the direct peer. Returns false if the peer is not
+    /// connected directly.
+    pub fn update(&mut self, peer_id: &PeerId, distances: Vec<(PeerId, u32)>) -> bool {
+        let own_peer_id = self.peer_id;
+        match self.peer_distances.get_mut(peer_id) {
+            Some(peer_distances) => {
+                *peer_distances = distances
+                    .into_iter()
+                    .filter(|(target, distance)| {
+                        target != peer_id
+                            && *target != own_peer_id
+                            && *distance < MAX_ROUTE_DISTANCE
+                    })
+                    .collect();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the direct peer to send the message for the target to and the number of hops
+    /// to the target. Ties are broken by the peer id, so that routes are stable.
+    pub fn next_hop(&self, target: &PeerId) -> Option<(PeerId, u32)> {
+        if self.peer_distances.contains_key(target) {
+            return Some((*target, 1));
+        }
+        self.peer_distances
+            .iter()
+            .filter_map(|(peer_id, distances)| {
+                distances.get(target).map(|distance| (*peer_id, distance + 1))
+            })
+            .min_by_key(|(peer_id, distance)| (*distance, *peer_id))
+    }
+
+    /// All the reachable peers with the next hop and the number of hops to them.
+    pub fn routes(&self) -> HashMap<PeerId, (PeerId, u32)> {
+        let mut targets: HashSet<PeerId> = self.peer_distances.keys().cloned().collect();
+        for distances in self.peer_distances.values() {
+            targets.extend(distances.keys().cloned());
+        }
+        targets
+            .into_iter()
+            .filter_map(|target| self.next_hop(&target).map(|route| (target, route)))
+            .collect()
+    }
+
+    /// Distances to send to the direct peer, without the routes that go through it (split
+    /// horizon), so that the peer doesn't route back through this node when its own route breaks.
+    pub fn distances_for(&self, peer_id: &PeerId) -> Vec<(PeerId, u32)> {
+        self.routes()
+            .into_iter()
+            .filter(|(target, (next_hop, distance))| {
+                target != peer_id && next_hop != peer_id && *distance < MAX_ROUTE_DISTANCE
+            })
+            .map(|(target, (_, distance))| (target, distance))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use near_crypto::{KeyType, SecretKey};
+
+    use super::*;
+
+    fn random_peer_id() -> PeerId {
+        SecretKey::from_random(KeyType::ED25519).public_key().into()
+    }
+
+    #[test]
+    fn test_routes_through_closest_peer() {
+        let me = random_peer_id();
+        let (a, b, c, d) = (random_peer_id(), random_peer_id(), random_peer_id(), random_peer_id());
+        let mut table = PeerRoutingTable::new(me);
+        table.add_peer(a);
+        table.add_peer(b);
+        assert!(table.update(&a, vec![(c, 1), (d, 3), (me, 1)]));
+        assert!(table.update(&b, vec![(d, 1)]));
+        // Distances from peers that are not connected directly are ignored.
+        assert!(!table.update(&c, vec![(d, 1)]));
+
+        assert_eq!(table.next_hop(&a), Some((a, 1)));
+        assert_eq!(table.next_hop(&c), Some((a, 2)));
+        assert_eq!(table.next_hop(&d), Some((b, 2)));
+        assert_eq!(table.routes().len(), 4);
+
+        // Peer `a` doesn't get back the routes that go through it.
+        let mut distances = table.distances_for(&a);
+        distances.sort_by_key(|(_, distance)| *distance);
+        assert_eq!(distances, vec![(b, 1), (d, 2)]);
+
+        // Once `b` disconnects, the longer route through `a` is used.
+        table.remove_peer(&b);
+        assert_eq!(table.next_hop(&d), Some((a, 4)));
+        assert!(table.update(&a, vec![(c, 1)]));
+        assert_eq!(table.next_hop(&d), None);
+    }
+
+    #[test]
+    fn test_long_routes_dropped() {
+        let me = random_peer_id();
+        let (a, b) = (random_peer_id(), random_peer_id());
+        let mut table = PeerRoutingTable::new(me);
+        table.add_peer(a);
+        table.update(&a, vec![(b, MAX_ROUTE_DISTANCE)]);
+        assert_eq!(table.next_hop(&b), None);
+        table.update(&a, vec![(b, MAX_ROUTE_DISTANCE - 1)]);
+        assert_eq!(table.next_hop(&b), Some((a, MAX_ROUTE_DISTANCE)));
+        assert!(table.distances_for(&random_peer_id()).iter().all(|(target, _)| *target != b));
+    }
+}
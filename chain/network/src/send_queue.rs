@@ -0,0 +1,151 @@
+//! Messages waiting to be sent to a peer, in a bounded queue per priority class. Queued block
+//! approvals and announcements are sent before header responses and other traffic, which is sent
+//! before state sync payloads, so that consensus is not held back by a large state transfer.
+use std::collections::VecDeque;
+
+use crate::types::{PeerMessage, RoutedMessageBody};
+
+/// Maximum number of queued messages of each priority class, further messages are dropped.
+const MAX_CRITICAL_QUEUE_LEN: usize = 1024;
+const MAX_NORMAL_QUEUE_LEN: usize = 1024;
+const MAX_BULK_QUEUE_LEN: usize = 64;
+
+/// Priority class of the message, from the highest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessagePriority {
+    /// Connection control, keep-alive, block approvals and block announcements.
+    Critical = 0,
+    /// Header responses, blocks, transactions and requests.
+    Normal = 1,
+    /// State sync payloads.
+    Bulk = 2,
+}
+
+impl MessagePriority {
+    pub fn of(msg: &PeerMessage) -> Self {
+        match msg {
+            PeerMessage::Handshake(_)
+            | PeerMessage::HandshakeFailure(_, _)
+            | PeerMessage::Disconnect
+            | PeerMessage::Ping(_)
+            | PeerMessage::Pong(_)
+            | PeerMessage::BlockApproval(_, _, _)
+            | PeerMessage::BlockHeaderAnnounce(_) => MessagePriority::Critical,
+            PeerMessage::StateResponse(_, _, _, _) => MessagePriority::Bulk,
+            PeerMessage::Routed(routed) => match routed.body {
+                RoutedMessageBody::BlockApproval(_, _, _) | RoutedMessageBody::Block(_) => {
+                    MessagePriority::Critical
+                }
+                RoutedMessageBody::StateRequest(_, _, _) => MessagePriority::Normal,
+                RoutedMessageBody::StateResponse(_, _, _, _) => MessagePriority::Bulk,
+            },
+            _ => MessagePriority::Normal,
+        }
+    }
+
+    fn max_queue_len(self) -> usize {
+        match self {
+            MessagePriority::Critical => MAX_CRITICAL_QUEUE_LEN,
+            MessagePriority::Normal => MAX_NORMAL_QUEUE_LEN,
+            MessagePriority::Bulk => MAX_BULK_QUEUE_LEN,
+        }
+    }
+}
+
+/// Number of messages of each priority class dropped because their queue was full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DroppedMessages {
+    pub critical: u64,
+    pub normal: u64,
+    pub bulk: u64,
+}
+
+impl DroppedMessages {
+    fn increment(&mut self, priority: MessagePriority) {
+        match priority {
+            MessagePriority::Critical => self.critical += 1,
+            MessagePriority::Normal => self.normal += 1,
+            MessagePriority::Bulk => self.bulk += 1,
+        }
+    }
+
+    pub fn add(&mut self, other: &DroppedMessages) {
+        self.critical += other.critical;
+        self.normal += other.normal;
+        self.bulk += other.bulk;
+    }
+
+    pub fn total(&self) -> u64 {
+        self.critical + self.normal + self.bulk
+    }
+}
+
+/// Serialized messages waiting to be sent, one queue per priority class.
+#[derive(Default)]
+pub struct SendQueue {
+    queues: [VecDeque<Vec<u8>>; 3],
+    /// Messages dropped since the last call to `take_dropped`.
+    dropped: DroppedMessages,
+}
+
+impl SendQueue {
+    /// Queues the message. Returns false if the queue of its class is full and it was dropped.
+    pub fn push(&mut self, priority: MessagePriority, bytes: Vec<u8>) -> bool {
+        let queue = &mut self.queues[priority as usize];
+        if queue.len() >= priority.max_queue_len() {
+            self.dropped.increment(priority);
+            return false;
+        }
+        queue.push_back(bytes);
+        true
+    }
+
+    /// Takes the oldest message of the highest priority class.
+    pub fn pop(&mut self) -> Option<Vec<u8>> {
+        self.queues.iter_mut().filter_map(|queue| queue.pop_front()).next()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queues.iter().all(|queue| queue.is_empty())
+    }
+
+    /// Returns number of messages dropped since the previous call.
+    pub fn take_dropped(&mut self) -> DroppedMessages {
+        std::mem::replace(&mut self.dropped, DroppedMessages::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_queue_priorities() {
+        let mut queue = SendQueue::default();
+        assert!(queue.push(MessagePriority::Bulk, vec![3]));
+        assert!(queue.push(MessagePriority::Normal, vec![2]));
+        assert!(queue.push(MessagePriority::Critical, vec![1]));
+        assert!(queue.push(MessagePriority::Normal, vec![4]));
+        assert_eq!(queue.pop(), Some(vec![1]));
+        assert_eq!(queue.pop(), Some(vec![2]));
+        assert_eq!(queue.pop(), Some(vec![4]));
+        assert_eq!(queue.pop(), Some(vec![3]));
+        assert_eq!(queue.pop(), None);
+        assert!(queue.is_empty());
+        assert_eq!(MessagePriority::of(&PeerMessage::Disconnect), MessagePriority::Critical);
+        assert_eq!(MessagePriority::of(&PeerMessage::PeersRequest), MessagePriority::Normal);
+    }
+
+    #[test]
+    fn test_send_queue_drops_when_full() {
+        let mut queue = SendQueue::default();
+        for _ in 0..MAX_BULK_QUEUE_LEN {
+            assert!(queue.push(MessagePriority::Bulk, vec![0]));
+        }
+        assert!(!queue.push(MessagePriority::Bulk, vec![0]));
+        // Full queue of one class doesn't affect the other classes.
+        assert!(queue.push(MessagePriority::Critical, vec![0]));
+        assert_eq!(queue.take_dropped(), DroppedMessages { critical: 0, normal: 0, bulk: 1 });
+        assert_eq!(queue.take_dropped().total(), 0);
+    }
+}
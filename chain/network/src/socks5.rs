@@ -0,0 +1,136 @@
+//! Client side of the SOCKS5 protocol (RFC 1928), used to connect to the peers through a proxy
+//! such as Tor. Only the connect command without authentication is supported.
+use std::io;
+use std::net::SocketAddr;
+
+use futures::future::{self, Future};
+use tokio::io::{read_exact, write_all};
+use tokio::net::TcpStream;
+
+const SOCKS_VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const CONNECT_COMMAND: u8 = 1;
+const SUCCEEDED: u8 = 0;
+
+/// Types of the address in the request and the reply.
+const ADDRESS_IPV4: u8 = 1;
+const ADDRESS_DOMAIN: u8 = 3;
+const ADDRESS_IPV6: u8 = 4;
+
+/// Length of the connect reply up to and including the first byte of the bound address.
+const REPLY_HEADER_LEN: usize = 5;
+
+/// Connects to the target through the proxy. Once connected, the stream is tunneled to the
+/// target and can be used as a direct connection.
+pub fn connect(
+    proxy: SocketAddr,
+    target: SocketAddr,
+) -> impl Future<Item = TcpStream, Error = io::Error> {
+    TcpStream::connect(&proxy)
+        .and_then(|stream| write_all(stream, [SOCKS_VERSION, 1, NO_AUTHENTICATION]))
+        .and_then(|(stream, _)| read_exact(stream, [0u8; 2]))
+        .and_then(move |(stream, reply)| {
+            future::result(check_method_reply(&reply))
+                .and_then(move |_| write_all(stream, connect_request(target)))
+        })
+        .and_then(|(stream, _)| read_exact(stream, [0u8; REPLY_HEADER_LEN]))
+        .and_then(|(stream, reply)| {
+            future::result(bound_address_len(&reply))
+                .and_then(move |len| read_exact(stream, vec![0u8; len]))
+                .map(|(stream, _)| stream)
+        })
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn check_method_reply(reply: &[u8; 2]) -> io::Result<()> {
+    if reply[0] != SOCKS_VERSION {
+        return Err(invalid_data(format!("Unsupported proxy version {}", reply[0])));
+    }
+    if reply[1] != NO_AUTHENTICATION {
+        return Err(invalid_data("Proxy requires authentication".to_string()));
+    }
+    Ok(())
+}
+
+fn connect_request(target: SocketAddr) -> Vec<u8> {
+    let mut request = vec![SOCKS_VERSION, CONNECT_COMMAND, 0];
+    match target {
+        SocketAddr::V4(addr) => {
+            request.push(ADDRESS_IPV4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(ADDRESS_IPV6);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    request
+}
+
+/// Checks the header of the connect reply, returns number of the remaining bytes of the bound
+/// address and port to read.
+fn bound_address_len(reply: &[u8; REPLY_HEADER_LEN]) -> io::Result<usize> {
+    if reply[0] != SOCKS_VERSION {
+        return Err(invalid_data(format!("Unsupported proxy version {}", reply[0])));
+    }
+    if reply[1] != SUCCEEDED {
+        return Err(io::Error::new(
+            io::ErrorKind::ConnectionRefused,
+            format!("Proxy failed to connect, reply code {}", reply[1]),
+        ));
+    }
+    match reply[3] {
+        ADDRESS_IPV4 => Ok(4 - 1 + 2),
+        ADDRESS_IPV6 => Ok(16 - 1 + 2),
+        ADDRESS_DOMAIN => Ok(reply[4] as usize + 2),
+        address_type => Err(invalid_data(format!("Unknown address type {}", address_type))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_connect_through_proxy() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let target: SocketAddr = "10.0.0.1:24567".parse().unwrap();
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            assert_eq!(greeting, [SOCKS_VERSION, 1, NO_AUTHENTICATION]);
+            stream.write_all(&[SOCKS_VERSION, NO_AUTHENTICATION]).unwrap();
+            let mut request = [0u8; 10];
+            stream.read_exact(&mut request).unwrap();
+            assert_eq!(request.to_vec(), connect_request(target));
+            stream.write_all(&[SOCKS_VERSION, SUCCEEDED, 0, ADDRESS_DOMAIN, 4]).unwrap();
+            stream.write_all(b"node\x5f\xe7").unwrap();
+            stream.write_all(b"hello").unwrap();
+        });
+        let (_, data) = tokio::runtime::current_thread::block_on_all(
+            connect(proxy, target).and_then(|stream| read_exact(stream, [0u8; 5])),
+        )
+        .unwrap();
+        assert_eq!(&data, b"hello");
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_proxy_errors() {
+        assert!(check_method_reply(&[SOCKS_VERSION, 2]).is_err());
+        assert!(check_method_reply(&[4, NO_AUTHENTICATION]).is_err());
+        // Host unreachable.
+        assert!(bound_address_len(&[SOCKS_VERSION, 4, 0, ADDRESS_IPV4, 0]).is_err());
+        assert_eq!(bound_address_len(&[SOCKS_VERSION, SUCCEEDED, 0, ADDRESS_IPV6, 0]).unwrap(), 17);
+    }
+}
@@ -5,7 +5,9 @@ use actix::{Actor, AsyncContext, Context, System};
 use futures::future::Future;
 use tokio::timer::Delay;
 
-use crate::types::{NetworkConfig, PeerInfo};
+use crate::ban_policy::BanPolicyConfig;
+use crate::bandwidth::BandwidthConfig;
+use crate::types::{NetworkConfig, PeerInfo, PROTOCOL_VERSION};
 use futures::future;
 use near_crypto::{KeyType, SecretKey};
 
@@ -36,7 +38,23 @@ impl NetworkConfig {
             ban_window: Duration::from_secs(1),
             peer_expiration_duration: Duration::from_secs(60 * 60),
             max_send_peers: 512,
+            peers_request_period: Duration::from_secs(1),
+            ping_period: Duration::from_secs(1),
+            peer_silence_timeout: Duration::from_secs(60),
             peer_stats_period: Duration::from_secs(5),
+            max_message_size: 10 * 1024 * 1024,
+            min_peer_protocol_version: PROTOCOL_VERSION,
+            ban_policy: BanPolicyConfig::default(),
+            blacklist: vec![],
+            whitelist: vec![],
+            sentries: vec![],
+            enable_compression: true,
+            enable_encryption: true,
+            require_encryption: false,
+            external_address: None,
+            upnp: false,
+            bandwidth: BandwidthConfig::default(),
+            proxy: None,
         }
     }
 }
@@ -3,27 +3,33 @@ use std::convert::{From, TryInto};
 use std::convert::{Into, TryFrom};
 use std::fmt;
 use std::hash::{Hash, Hasher};
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
 use actix::dev::{MessageResponse, ResponseChannel};
 use actix::{Actor, Addr, Message};
 use borsh::{BorshDeserialize, BorshSerialize};
 use chrono::{DateTime, Utc};
+use serde_derive::{Deserialize, Serialize};
 use tokio::net::TcpStream;
 
 use near_chain::{Block, BlockApproval, BlockHeader, Weight};
 use near_crypto::{PublicKey, ReadablePublicKey, SecretKey, Signature};
 use near_primitives::hash::{hash, CryptoHash};
 use near_primitives::receipt::Receipt;
-use near_primitives::transaction::SignedTransaction;
-use near_primitives::types::{AccountId, BlockIndex, ShardId};
+use near_primitives::transaction::{InvalidTxError, SignedTransaction};
+use near_primitives::types::{AccountId, BlockIndex, ShardId, StatePart};
 use near_primitives::utils::{from_timestamp, to_timestamp};
+use near_primitives::views::PeerBanView;
 
+use crate::ban_policy::{BanPolicyConfig, BanResponse};
+use crate::bandwidth::BandwidthConfig;
 use crate::peer::Peer;
+use crate::peer_score::PeerScore;
+use crate::send_queue::DroppedMessages;
 
 /// Current latest version of the protocol
-pub const PROTOCOL_VERSION: u32 = 3;
+pub const PROTOCOL_VERSION: u32 = 16;
 
 /// Peer id is the public key.
 #[derive(BorshSerialize, BorshDeserialize, Copy, Clone, Eq, PartialOrd, Ord, PartialEq)]
@@ -55,6 +61,14 @@ impl TryFrom<Vec<u8>> for PeerId {
     }
 }
 
+impl TryFrom<&str> for PeerId {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(s: &str) -> Result<PeerId, Self::Error> {
+        Ok(PeerId(ReadablePublicKey::new(s).try_into()?))
+    }
+}
+
 impl Hash for PeerId {
     fn hash<H: Hasher>(&self, state: &mut H) {
         state.write(&self.0.try_to_vec().unwrap());
@@ -123,6 +137,42 @@ impl TryFrom<&str> for PeerInfo {
     }
 }
 
+/// Entry of the peer blacklist or whitelist: peer id, IP address or IP address with a port.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PeerFilterEntry {
+    PeerId(PeerId),
+    Ip(IpAddr),
+    Addr(SocketAddr),
+}
+
+impl PeerFilterEntry {
+    /// Whether the peer with given id and address matches the entry.
+    pub fn matches(&self, peer_id: &PeerId, addr: Option<SocketAddr>) -> bool {
+        match self {
+            PeerFilterEntry::PeerId(id) => id == peer_id,
+            PeerFilterEntry::Ip(ip) => addr.map_or(false, |addr| addr.ip() == *ip),
+            PeerFilterEntry::Addr(entry_addr) => addr == Some(*entry_addr),
+        }
+    }
+}
+
+impl TryFrom<&str> for PeerFilterEntry {
+    type Error = Box<dyn std::error::Error>;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        if let Ok(addr) = s.parse() {
+            return Ok(PeerFilterEntry::Addr(addr));
+        }
+        if let Ok(ip) = s.parse() {
+            return Ok(PeerFilterEntry::Ip(ip));
+        }
+        let public_key: PublicKey = ReadablePublicKey::new(s)
+            .try_into()
+            .map_err(|err| format!("Expected peer id, ip or ip:port, got {}: {}", s, err))?;
+        Ok(PeerFilterEntry::PeerId(PeerId(public_key)))
+    }
+}
+
 /// Peer chain information.
 #[derive(BorshSerialize, BorshDeserialize, Copy, Clone, Debug, Eq, PartialEq, Default)]
 pub struct PeerChainInfo {
@@ -154,21 +204,59 @@ pub enum PeerStatus {
     Banned(ReasonForBan),
 }
 
+/// Ephemeral key for encrypting the connection, signed by the key of the sender's peer id.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone, Debug)]
+pub struct EncryptionOffer {
+    pub public_key: Vec<u8>,
+    pub signature: Signature,
+}
+
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone, Debug)]
 pub struct Handshake {
-    /// Protocol version.
-    pub version: u32,
+    /// Network protocol version of the sender.
+    pub protocol_version: u32,
     /// Sender's peer id.
     pub peer_id: PeerId,
     /// Sender's listening addr.
     pub listen_port: Option<u16>,
+    /// Id of the chain the sender is on.
+    pub chain_id: String,
     /// Peer's chain information.
     pub chain_info: PeerChainInfo,
+    /// Sender is a private validator behind sentry nodes, its peer id must not be advertised.
+    pub private: bool,
+    /// Sender wants large messages compressed. Compression is used only if both peers want it.
+    pub compression: bool,
+    /// Address of the receiver as seen by the sender, helps nodes behind NAT to discover their
+    /// external address.
+    pub observed_addr: Option<SocketAddr>,
+    /// Sender wants the connection encrypted. Messages after the handshakes are encrypted if
+    /// both peers offer a key.
+    pub encryption: Option<EncryptionOffer>,
 }
 
 impl Handshake {
-    pub fn new(peer_id: PeerId, listen_port: Option<u16>, chain_info: PeerChainInfo) -> Self {
-        Handshake { version: PROTOCOL_VERSION, peer_id, listen_port, chain_info }
+    pub fn new(
+        peer_id: PeerId,
+        listen_port: Option<u16>,
+        chain_id: String,
+        chain_info: PeerChainInfo,
+        private: bool,
+        compression: bool,
+        observed_addr: Option<SocketAddr>,
+        encryption: Option<EncryptionOffer>,
+    ) -> Self {
+        Handshake {
+            protocol_version: PROTOCOL_VERSION,
+            peer_id,
+            listen_port,
+            chain_id,
+            chain_info,
+            private,
+            compression,
+            observed_addr,
+            encryption,
+        }
     }
 }
 
@@ -256,10 +344,64 @@ impl AnnounceAccount {
     }
 }
 
+/// Content of the message routed to the peer that may not be connected directly.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone, Debug)]
+pub enum RoutedMessageBody {
+    BlockApproval(AccountId, CryptoHash, Signature),
+    Block(Block),
+    StateRequest(ShardId, CryptoHash, u64),
+    StateResponse(ShardId, CryptoHash, StatePart, Vec<Receipt>),
+}
+
+/// Message forwarded through the peers on the route to the target peer.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone, Debug)]
+pub struct RoutedMessage {
+    /// Peer the message is delivered to.
+    pub target: PeerId,
+    /// Peer that created the message, responses are routed back to it.
+    pub author: PeerId,
+    /// Signature of the author, covering everything but the ttl.
+    pub signature: Signature,
+    /// Number of hops the message can still make before it's dropped.
+    pub ttl: u8,
+    pub body: RoutedMessageBody,
+}
+
+impl RoutedMessage {
+    pub fn new(
+        target: PeerId,
+        author: PeerId,
+        secret_key: &SecretKey,
+        ttl: u8,
+        body: RoutedMessageBody,
+    ) -> Self {
+        let hash = RoutedMessage::build_hash(&target, &author, &body);
+        let signature = secret_key.sign(hash.as_ref());
+        RoutedMessage { target, author, signature, ttl, body }
+    }
+
+    fn build_hash(target: &PeerId, author: &PeerId, body: &RoutedMessageBody) -> CryptoHash {
+        let data = [
+            target.try_to_vec().unwrap(),
+            author.try_to_vec().unwrap(),
+            body.try_to_vec().unwrap(),
+        ]
+        .concat();
+        hash(&data)
+    }
+
+    /// Checks that the message was signed by its author.
+    pub fn verify(&self) -> bool {
+        let hash = RoutedMessage::build_hash(&self.target, &self.author, &self.body);
+        self.signature.verify(hash.as_ref(), &self.author.public_key())
+    }
+}
+
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone, Debug)]
 pub enum HandshakeFailureReason {
     ProtocolVersionMismatch(u32),
     GenesisMismatch(CryptoHash),
+    ChainIdMismatch(String),
 }
 
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Clone, Debug)]
@@ -271,7 +413,8 @@ pub enum PeerMessage {
     PeersResponse(Vec<PeerInfo>),
 
     BlockHeadersRequest(Vec<CryptoHash>),
-    BlockHeaders(Vec<BlockHeader>),
+    /// Batch of headers and whether the sender has more headers after this batch.
+    BlockHeaders(Vec<BlockHeader>, bool),
     BlockHeaderAnnounce(BlockHeader),
 
     BlockRequest(CryptoHash),
@@ -280,10 +423,28 @@ pub enum PeerMessage {
 
     Transaction(SignedTransaction),
 
-    StateRequest(ShardId, CryptoHash),
-    StateResponse(ShardId, CryptoHash, Vec<u8>, Vec<Receipt>),
+    StateRequest(ShardId, CryptoHash, u64),
+    StateResponse(ShardId, CryptoHash, StatePart, Vec<Receipt>),
 
     AnnounceAccount(AnnounceAccount),
+
+    /// Peer is going to close the connection, e.g. because it is shutting down.
+    Disconnect,
+
+    /// Request headers that follow the common header of the locator, after skipping given number
+    /// of heights, so that different ranges can be requested from different peers.
+    BlockHeadersRangeRequest(Vec<CryptoHash>, BlockIndex),
+
+    /// Number of hops from the sender to the peers it can reach, for distance-vector routing.
+    Routes(Vec<(PeerId, u32)>),
+    /// Message for the peer that may not be connected to the sender directly.
+    Routed(RoutedMessage),
+    /// Deflate compressed serialized message, see `Handshake::compression`.
+    Compressed(Vec<u8>),
+
+    /// Keep-alive request with a nonce, answered with a pong with the same nonce.
+    Ping(u64),
+    Pong(u64),
 }
 
 impl fmt::Display for PeerMessage {
@@ -294,15 +455,22 @@ impl fmt::Display for PeerMessage {
             PeerMessage::PeersRequest => f.write_str("PeersRequest"),
             PeerMessage::PeersResponse(_) => f.write_str("PeersResponse"),
             PeerMessage::BlockHeadersRequest(_) => f.write_str("BlockHeaderRequest"),
-            PeerMessage::BlockHeaders(_) => f.write_str("BlockHeaders"),
+            PeerMessage::BlockHeaders(_, _) => f.write_str("BlockHeaders"),
             PeerMessage::BlockHeaderAnnounce(_) => f.write_str("BlockHeaderAnnounce"),
             PeerMessage::BlockRequest(_) => f.write_str("BlockRequest"),
             PeerMessage::Block(_) => f.write_str("Block"),
             PeerMessage::BlockApproval(_, _, _) => f.write_str("BlockApproval"),
             PeerMessage::Transaction(_) => f.write_str("Transaction"),
-            PeerMessage::StateRequest(_, _) => f.write_str("StateRequest"),
+            PeerMessage::StateRequest(_, _, _) => f.write_str("StateRequest"),
             PeerMessage::StateResponse(_, _, _, _) => f.write_str("StateResponse"),
             PeerMessage::AnnounceAccount(_) => f.write_str("AnnounceAccount"),
+            PeerMessage::Disconnect => f.write_str("Disconnect"),
+            PeerMessage::BlockHeadersRangeRequest(_, _) => f.write_str("BlockHeadersRangeRequest"),
+            PeerMessage::Routes(_) => f.write_str("Routes"),
+            PeerMessage::Routed(_) => f.write_str("Routed"),
+            PeerMessage::Compressed(_) => f.write_str("Compressed"),
+            PeerMessage::Ping(_) => f.write_str("Ping"),
+            PeerMessage::Pong(_) => f.write_str("Pong"),
         }
     }
 }
@@ -325,8 +493,68 @@ pub struct NetworkConfig {
     pub peer_expiration_duration: Duration,
     /// Maximum number of peer addresses we should ever send.
     pub max_send_peers: u32,
+    /// How often to request known peers from each active peer.
+    pub peers_request_period: Duration,
+    /// How often to ping each peer to measure the round trip time.
+    pub ping_period: Duration,
+    /// Peers that didn't send anything for this long are disconnected.
+    pub peer_silence_timeout: Duration,
     /// Duration for checking on stats from the peers.
     pub peer_stats_period: Duration,
+    /// Maximum size of a message in bytes, also the maximum size of the block headers response
+    /// sent to a peer.
+    pub max_message_size: u64,
+    /// Minimum network protocol version a peer must have to be accepted.
+    pub min_peer_protocol_version: u32,
+    /// Thresholds for turning peer misbehavior reports into bans.
+    pub ban_policy: BanPolicyConfig,
+    /// Peers that are never connected to nor accepted.
+    pub blacklist: Vec<PeerFilterEntry>,
+    /// If not empty, only these peers are connected to and accepted. Address entries only match
+    /// inbound peers that report their listening port.
+    pub whitelist: Vec<PeerFilterEntry>,
+    /// Trusted sentry nodes of the private validator. If not empty, the node connects only to
+    /// them and never advertises its address, the sentries relay its blocks and approvals.
+    pub sentries: Vec<PeerInfo>,
+    /// Compress large messages to the peers that support it.
+    pub enable_compression: bool,
+    /// Encrypt connections with the peers that support it.
+    pub enable_encryption: bool,
+    /// Refuse connections with the peers that don't encrypt them.
+    pub require_encryption: bool,
+    /// Address other nodes can dial this node at, discovered automatically if not set.
+    pub external_address: Option<SocketAddr>,
+    /// Map the listening port on the router of the local network with UPnP.
+    pub upnp: bool,
+    /// Upload and download rate limits, for all the peers together and for each peer.
+    pub bandwidth: BandwidthConfig,
+    /// SOCKS5 proxy, such as Tor, to connect to the peers through. The node doesn't advertise
+    /// its address when it's set.
+    pub proxy: Option<SocketAddr>,
+}
+
+impl NetworkConfig {
+    /// Whether the node is a private validator behind the sentry nodes.
+    pub fn is_private(&self) -> bool {
+        !self.sentries.is_empty()
+    }
+
+    /// Whether the node keeps its address from the peers: private validators and nodes that
+    /// connect through a proxy.
+    pub fn hides_address(&self) -> bool {
+        self.is_private() || self.proxy.is_some()
+    }
+
+    /// Whether connections with the peer are allowed by the blacklist and the whitelist. Private
+    /// validator only connects to its sentries.
+    pub fn is_peer_allowed(&self, peer_id: &PeerId, addr: Option<SocketAddr>) -> bool {
+        if self.is_private() {
+            return self.sentries.iter().any(|sentry| sentry.id == *peer_id);
+        }
+        !self.blacklist.iter().any(|entry| entry.matches(peer_id, addr))
+            && (self.whitelist.is_empty()
+                || self.whitelist.iter().any(|entry| entry.matches(peer_id, addr)))
+    }
 }
 
 /// Status of the known peers.
@@ -335,7 +563,18 @@ pub enum KnownPeerStatus {
     Unknown,
     NotConnected,
     Connected,
+    /// Banned for the reason until the timestamp.
     Banned(ReasonForBan, u64),
+    BannedPermanently(ReasonForBan),
+}
+
+impl KnownPeerStatus {
+    pub fn is_banned(&self) -> bool {
+        match self {
+            KnownPeerStatus::Banned(_, _) | KnownPeerStatus::BannedPermanently(_) => true,
+            _ => false,
+        }
+    }
 }
 
 /// Information node stores about known peers.
@@ -407,6 +646,11 @@ pub struct Consolidate {
     pub peer_info: PeerInfo,
     pub peer_type: PeerType,
     pub chain_info: PeerChainInfo,
+    pub protocol_version: u32,
+    /// Peer is a private validator, see `Handshake::private`.
+    pub private: bool,
+    /// Address of this node as seen by the peer, see `Handshake::observed_addr`.
+    pub observed_addr: Option<SocketAddr>,
 }
 
 impl Message for Consolidate {
@@ -419,6 +663,26 @@ pub struct Unregister {
     pub peer_id: PeerId,
 }
 
+/// External IP address of the router the listening port was mapped on with UPnP.
+#[derive(Message)]
+pub struct UpnpMapped {
+    pub external_ip: IpAddr,
+}
+
+/// Distances to the peers reported by the direct peer.
+#[derive(Message)]
+pub struct RoutesUpdate {
+    pub peer_id: PeerId,
+    pub distances: Vec<(PeerId, u32)>,
+}
+
+/// Routed message received from the direct peer.
+#[derive(Message)]
+pub struct RoutedMessageFrom {
+    pub msg: RoutedMessage,
+    pub from: PeerId,
+}
+
 pub struct PeerList {
     pub peers: Vec<PeerInfo>,
 }
@@ -449,7 +713,18 @@ where
 }
 
 /// Ban reason.
-#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, Copy)]
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    Copy,
+    Hash,
+)]
 pub enum ReasonForBan {
     None = 0,
     BadBlock = 1,
@@ -461,12 +736,64 @@ pub enum ReasonForBan {
     InvalidSignature = 7,
     InvalidPeerId = 8,
     InvalidHash = 9,
+    BadStatePart = 10,
+    BadSyncData = 11,
+    /// Peer is on a different chain or has a different genesis.
+    WrongNetwork = 12,
+    /// Message failed to decode.
+    MalformedMessage = 13,
+    /// Message is larger than allowed for its type.
+    OversizedMessage = 14,
+    /// Message the peer had no reason to send, e.g. a response that wasn't requested.
+    UnsolicitedMessage = 15,
 }
 
-#[derive(Message)]
-pub struct Ban {
+impl TryFrom<&str> for ReasonForBan {
+    type Error = String;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        Ok(match s {
+            "None" => ReasonForBan::None,
+            "BadBlock" => ReasonForBan::BadBlock,
+            "BadBlockHeader" => ReasonForBan::BadBlockHeader,
+            "HeightFraud" => ReasonForBan::HeightFraud,
+            "BadHandshake" => ReasonForBan::BadHandshake,
+            "BadBlockApproval" => ReasonForBan::BadBlockApproval,
+            "Abusive" => ReasonForBan::Abusive,
+            "InvalidSignature" => ReasonForBan::InvalidSignature,
+            "InvalidPeerId" => ReasonForBan::InvalidPeerId,
+            "InvalidHash" => ReasonForBan::InvalidHash,
+            "BadStatePart" => ReasonForBan::BadStatePart,
+            "BadSyncData" => ReasonForBan::BadSyncData,
+            "WrongNetwork" => ReasonForBan::WrongNetwork,
+            "MalformedMessage" => ReasonForBan::MalformedMessage,
+            "OversizedMessage" => ReasonForBan::OversizedMessage,
+            "UnsolicitedMessage" => ReasonForBan::UnsolicitedMessage,
+            _ => return Err(format!("Unknown ban reason {}", s)),
+        })
+    }
+}
+
+/// Report misbehavior of the peer, responded with the action taken by the ban policy.
+pub struct ReportMisbehavior {
     pub peer_id: PeerId,
-    pub ban_reason: ReasonForBan,
+    pub reason: ReasonForBan,
+}
+
+impl Message for ReportMisbehavior {
+    type Result = BanResponse;
+}
+
+impl<A, M> MessageResponse<A, M> for BanResponse
+where
+    A: Actor,
+    M: Message<Result = BanResponse>,
+{
+    fn handle<R: ResponseChannel<M>>(self, _: &mut A::Context, tx: Option<R>) {
+        if let Some(tx) = tx {
+            tx.send(self)
+        }
+    }
 }
 
 #[derive(Debug, PartialEq)]
@@ -475,28 +802,52 @@ pub enum NetworkRequests {
     /// Level denote how much information is going to be delivered.
     /// Higher level implies more information. (This is useful for testing)
     FetchInfo { level: usize },
-    /// Sends block, either when block was just produced or when requested.
-    Block { block: Block },
-    /// Sends block header announcement, with possibly attaching approval for this block if
-    /// participating in this epoch.
-    BlockHeaderAnnounce { header: BlockHeader, approval: Option<BlockApproval> },
+    /// Sends block, either when block was just produced or when requested. The block is pushed to
+    /// the peers of the target accounts, e.g. the next block producers, before it's broadcast.
+    Block { block: Block, targets: Vec<AccountId> },
+    /// Sends block header announcement, attaching approvals for this block from our validator
+    /// accounts participating in this epoch.
+    BlockHeaderAnnounce { header: BlockHeader, approvals: Vec<BlockApproval> },
+    /// Sends approvals of a block from our validator accounts to the next block producers.
+    BlockApprovals { approvals: Vec<BlockApproval> },
     /// Request block with given hash from given peer.
     BlockRequest { hash: CryptoHash, peer_id: PeerId },
-    /// Request given block headers.
-    BlockHeadersRequest { hashes: Vec<CryptoHash>, peer_id: PeerId },
-    /// Request state for given shard at given state root.
-    StateRequest { shard_id: ShardId, hash: CryptoHash, peer_id: PeerId },
-    /// Ban given peer.
-    BanPeer { peer_id: PeerId, ban_reason: ReasonForBan },
+    /// Request block headers following the locator, skipping given number of heights.
+    BlockHeadersRequest { hashes: Vec<CryptoHash>, skip: BlockIndex, peer_id: PeerId },
+    /// Request given part of the state for given shard at given state root.
+    StateRequest { shard_id: ShardId, hash: CryptoHash, part_id: u64, peer_id: PeerId },
+    /// Broadcasts transaction to all the active peers.
+    Transaction { transaction: SignedTransaction },
+    /// Report misbehavior of given peer, which is banned if the ban policy decides so.
+    ReportPeer { peer_id: PeerId, reason: ReasonForBan },
+    /// Temporarily ban given peer right away, bypassing the ban policy.
+    BanPeer { peer_id: PeerId, reason: ReasonForBan },
     /// Announce account
     AnnounceAccount(AnnounceAccount),
+    /// Node is shutting down: disconnect from all peers and stop accepting new connections.
+    Shutdown,
+    /// Update config values that can be changed without restart.
+    UpdateConfig { peer_max_count: u32 },
+    /// List the banned peers.
+    FetchBans,
+    /// Lift the ban of the peer.
+    Unban { peer_id: PeerId },
 }
 
+
 /// Combines peer address info and chain information.
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub struct FullPeerInfo {
     pub peer_info: PeerInfo,
     pub chain_info: PeerChainInfo,
+    /// Network protocol version the peer reported in the handshake.
+    pub protocol_version: u32,
+    /// Score of the peer based on its responses to sync requests, higher is better.
+    pub score: u64,
+    /// Round trip time of the last answered ping.
+    pub latency: Option<Duration>,
+    /// Last time anything was received from the peer.
+    pub last_seen: DateTime<Utc>,
 }
 
 #[derive(Debug)]
@@ -504,8 +855,13 @@ pub struct NetworkInfo {
     pub num_active_peers: usize,
     pub peer_max_count: u32,
     pub most_weight_peers: Vec<FullPeerInfo>,
+    pub active_peers: Vec<FullPeerInfo>,
     pub sent_bytes_per_sec: u64,
     pub received_bytes_per_sec: u64,
+    /// Messages dropped because the send queue of the peer was full, since the node started.
+    pub dropped_messages: DroppedMessages,
+    /// Address other nodes can dial this node at, configured or discovered.
+    pub external_address: Option<SocketAddr>,
     // Only send full routes to accounts on demand
     pub routes: Option<HashMap<AccountId, (PeerId, usize)>>,
 }
@@ -514,6 +870,9 @@ pub struct NetworkInfo {
 pub enum NetworkResponses {
     NoResponse,
     Info(NetworkInfo),
+    Bans(Vec<PeerBanView>),
+    /// Whether the peer was banned before the request.
+    Unbanned(bool),
 }
 
 impl<A, M> MessageResponse<A, M> for NetworkResponses
@@ -532,28 +891,51 @@ impl Message for NetworkRequests {
     type Result = NetworkResponses;
 }
 
+/// Random id assigned to an inbound RPC or network request to correlate log lines about it
+/// across actors.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct CorrelationId(u64);
+
+impl CorrelationId {
+    pub fn random() -> Self {
+        CorrelationId(rand::random())
+    }
+}
+
+impl fmt::Display for CorrelationId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:016x}", self.0)
+    }
+}
+
 #[derive(Debug)]
 pub enum NetworkClientMessages {
+    /// Message caused by the request with given correlation id.
+    Correlated(CorrelationId, Box<NetworkClientMessages>),
     /// Received transaction.
     Transaction(SignedTransaction),
+    /// Transaction submitted to this node directly, e.g. through RPC. It's broadcast to the peers
+    /// and broadcast again until it's included into a block.
+    LocalTransaction(SignedTransaction),
     /// Received block header.
     BlockHeader(BlockHeader, PeerId),
     /// Received block, possibly requested.
     Block(Block, PeerId, bool),
-    /// Received list of headers for syncing.
-    BlockHeaders(Vec<BlockHeader>, PeerId),
+    /// Received list of headers for syncing, with a flag if peer has more headers available.
+    BlockHeaders(Vec<BlockHeader>, PeerId, bool),
     /// Get Chain information from Client.
     GetChainInfo,
     /// Block approval.
     BlockApproval(AccountId, CryptoHash, Signature),
-    /// Request headers.
-    BlockHeadersRequest(Vec<CryptoHash>),
+    /// Request headers following the locator after skipping given number of heights, with the
+    /// maximum size in bytes of the response.
+    BlockHeadersRequest(Vec<CryptoHash>, BlockIndex, u64),
     /// Request a block.
     BlockRequest(CryptoHash),
-    /// State request.
-    StateRequest(ShardId, CryptoHash),
-    /// State response.
-    StateResponse(ShardId, CryptoHash, Vec<u8>, Vec<Receipt>),
+    /// State part request.
+    StateRequest(ShardId, CryptoHash, u64),
+    /// State part response from given peer.
+    StateResponse(ShardId, CryptoHash, StatePart, Vec<Receipt>, PeerId),
     /// Account announcement that needs to be validated before being processed
     AnnounceAccount(AnnounceAccount),
 }
@@ -563,18 +945,18 @@ pub enum NetworkClientResponses {
     NoResponse,
     /// Valid transaction inserted into mempool as response to Transaction.
     ValidTx,
-    /// Invalid transaction inserted into mempool as response to Transaction.
-    InvalidTx(String),
-    /// Ban peer for malicious behaviour.
-    Ban { ban_reason: ReasonForBan },
+    /// Transaction refused by the node as response to Transaction.
+    InvalidTx(InvalidTxError),
+    /// Report peer for malicious behaviour, which is banned if the ban policy decides so.
+    ReportPeer { reason: ReasonForBan },
     /// Chain information.
-    ChainInfo { genesis: CryptoHash, height: BlockIndex, total_weight: Weight },
+    ChainInfo { chain_id: String, genesis: CryptoHash, height: BlockIndex, total_weight: Weight },
     /// Block response.
     Block(Block),
-    /// Headers response.
-    BlockHeaders(Vec<BlockHeader>),
-    /// Response to state request.
-    StateResponse { shard_id: ShardId, hash: CryptoHash, payload: Vec<u8>, receipts: Vec<Receipt> },
+    /// Headers response and if there are more headers available after them.
+    BlockHeaders(Vec<BlockHeader>, bool),
+    /// Response to state part request.
+    StateResponse { shard_id: ShardId, hash: CryptoHash, part: StatePart, receipts: Vec<Receipt> },
 }
 
 impl<A, M> MessageResponse<A, M> for NetworkClientResponses
@@ -609,6 +991,14 @@ pub struct PeerStatsResult {
     pub is_abusive: bool,
     /// Counts of incoming/outgoing messages from given peer.
     pub message_counts: (u64, u64),
+    /// Responses to sync requests since the previous stats query.
+    pub score: PeerScore,
+    /// Messages to the peer dropped because the send queue was full since the previous query.
+    pub dropped_messages: DroppedMessages,
+    /// Round trip time of the last answered ping.
+    pub latency: Option<Duration>,
+    /// Last time anything was received from the peer.
+    pub last_seen: DateTime<Utc>,
 }
 
 impl<A, M> MessageResponse<A, M> for PeerStatsResult
@@ -0,0 +1,228 @@
+//! Minimal UPnP Internet Gateway Device client. Maps the listening port of the node on the router
+//! of the local network, so that nodes behind NAT can be dialed without manual configuration, and
+//! queries the external IP address of the router. All calls are blocking.
+use std::io::{Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket};
+use std::time::Duration;
+
+/// Multicast address of the UPnP discovery.
+const SSDP_ADDR: &str = "239.255.255.250:1900";
+
+/// Timeout of the discovery and of every request to the gateway.
+const TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Services of the gateway that support port mapping.
+const SERVICE_TYPES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:1",
+    "urn:schemas-upnp-org:service:WANPPPConnection:1",
+];
+
+const PORT_MAPPING_DESCRIPTION: &str = "near";
+
+/// Maps the TCP port of this node to the same external port of the gateway for the duration of
+/// the lease. Returns the external IP address of the gateway.
+pub fn map_port(port: u16, lease: Duration) -> Result<IpAddr, String> {
+    let gateway = Gateway::discover()?;
+    gateway.add_port_mapping(port, lease)?;
+    gateway.external_ip()
+}
+
+/// Port mapping service of the gateway.
+struct Gateway {
+    addr: SocketAddr,
+    control_path: String,
+    service_type: &'static str,
+    /// Address of this node in the local network.
+    local_ip: IpAddr,
+}
+
+impl Gateway {
+    fn discover() -> Result<Self, String> {
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|err| err.to_string())?;
+        socket.set_read_timeout(Some(TIMEOUT)).map_err(|err| err.to_string())?;
+        let request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\
+             ST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\r\n",
+            SSDP_ADDR
+        );
+        socket.send_to(request.as_bytes(), SSDP_ADDR).map_err(|err| err.to_string())?;
+        let mut buf = [0u8; 2048];
+        let (len, _) =
+            socket.recv_from(&mut buf).map_err(|err| format!("No gateway found: {}", err))?;
+        let response = String::from_utf8_lossy(&buf[..len]);
+        let location = header(&response, "location").ok_or("Gateway response without location")?;
+        let (addr, path) = parse_url(location)?;
+        let (description, local_ip) = http_request(addr, "GET", &path, &[], "")?;
+        for service_type in SERVICE_TYPES.iter().cloned() {
+            if let Some(control_path) = control_path(&description, service_type) {
+                return Ok(Gateway { addr, control_path, service_type, local_ip });
+            }
+        }
+        Err("Gateway doesn't support port mapping".to_string())
+    }
+
+    fn add_port_mapping(&self, port: u16, lease: Duration) -> Result<(), String> {
+        self.call(
+            "AddPortMapping",
+            &[
+                ("NewRemoteHost", "".to_string()),
+                ("NewExternalPort", port.to_string()),
+                ("NewProtocol", "TCP".to_string()),
+                ("NewInternalPort", port.to_string()),
+                ("NewInternalClient", self.local_ip.to_string()),
+                ("NewEnabled", "1".to_string()),
+                ("NewPortMappingDescription", PORT_MAPPING_DESCRIPTION.to_string()),
+                ("NewLeaseDuration", lease.as_secs().to_string()),
+            ],
+        )
+        .map(|_| ())
+    }
+
+    fn external_ip(&self) -> Result<IpAddr, String> {
+        let response = self.call("GetExternalIPAddress", &[])?;
+        xml_value(&response, "NewExternalIPAddress")
+            .ok_or("Gateway response without external IP address")?
+            .parse()
+            .map_err(|err| format!("Invalid external IP address: {}", err))
+    }
+
+    /// Calls the SOAP action of the port mapping service, returns the response body.
+    fn call(&self, action: &str, args: &[(&str, String)]) -> Result<String, String> {
+        let args: String =
+            args.iter().map(|(name, value)| format!("<{0}>{1}</{0}>", name, value)).collect();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+            action, self.service_type, args
+        );
+        let soap_action = format!("\"{}#{}\"", self.service_type, action);
+        let headers =
+            [("Content-Type", "text/xml; charset=\"utf-8\""), ("SOAPAction", soap_action.as_str())];
+        http_request(self.addr, "POST", &self.control_path, &headers, &body)
+            .map_err(|err| format!("{} failed: {}", action, err))
+            .map(|(response, _)| response)
+    }
+}
+
+/// Sends HTTP request, returns the body of the successful response and the local IP address of
+/// the connection.
+fn http_request(
+    addr: SocketAddr,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(String, IpAddr), String> {
+    let mut stream = TcpStream::connect_timeout(&addr, TIMEOUT).map_err(|err| err.to_string())?;
+    stream.set_read_timeout(Some(TIMEOUT)).map_err(|err| err.to_string())?;
+    stream.set_write_timeout(Some(TIMEOUT)).map_err(|err| err.to_string())?;
+    let local_ip = stream.local_addr().map_err(|err| err.to_string())?.ip();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        addr,
+        body.len()
+    );
+    for (name, value) in headers.iter() {
+        request.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    request.push_str("\r\n");
+    request.push_str(body);
+    stream.write_all(request.as_bytes()).map_err(|err| err.to_string())?;
+    let mut response = vec![];
+    stream.read_to_end(&mut response).map_err(|err| err.to_string())?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.split_whitespace().nth(1).unwrap_or_default();
+    if status != "200" {
+        return Err(format!("HTTP status {}", status));
+    }
+    let body = response.splitn(2, "\r\n\r\n").nth(1).unwrap_or_default();
+    Ok((body.to_string(), local_ip))
+}
+
+/// Value of the header of HTTP response, header names are case insensitive.
+fn header<'a>(response: &'a str, name: &str) -> Option<&'a str> {
+    response.lines().find_map(|line| {
+        let mut parts = line.splitn(2, ':');
+        let key = parts.next()?;
+        let value = parts.next()?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Splits `http://host[:port]/path` into the address of the host and the path.
+fn parse_url(url: &str) -> Result<(SocketAddr, String), String> {
+    if !url.starts_with("http://") {
+        return Err(format!("Unsupported gateway url {}", url));
+    }
+    let rest = &url["http://".len()..];
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    let host = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+    let addr = host
+        .to_socket_addrs()
+        .map_err(|err| format!("Invalid gateway url {}: {}", url, err))?
+        .next()
+        .ok_or_else(|| format!("Invalid gateway url {}", url))?;
+    Ok((addr, path.to_string()))
+}
+
+/// Path of the control endpoint of the service from the device description.
+fn control_path(description: &str, service_type: &str) -> Option<String> {
+    let service = &description[description.find(service_type)?..];
+    let control_url = xml_value(service, "controlURL")?;
+    if control_url.starts_with("http://") {
+        parse_url(control_url).ok().map(|(_, path)| path)
+    } else if control_url.starts_with('/') {
+        Some(control_url.to_string())
+    } else {
+        Some(format!("/{}", control_url))
+    }
+}
+
+/// Text of the first element with the tag, namespace prefixes are not supported.
+fn xml_value<'a>(xml: &'a str, tag: &str) -> Option<&'a str> {
+    let start = xml.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + xml[start..].find(&format!("</{}>", tag))?;
+    Some(xml[start..end].trim())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_gateway_responses() {
+        let response = "HTTP/1.1 200 OK\r\nCACHE-CONTROL: max-age=120\r\n\
+                        Location: http://192.168.1.1:5000/rootDesc.xml\r\n\r\n";
+        let location = header(response, "LOCATION").unwrap();
+        assert_eq!(
+            parse_url(location).unwrap(),
+            ("192.168.1.1:5000".parse().unwrap(), "/rootDesc.xml".to_string())
+        );
+        assert!(parse_url("https://192.168.1.1/rootDesc.xml").is_err());
+
+        let description = format!(
+            "<root><service><serviceType>{}</serviceType><controlURL>/ctl/L3F</controlURL>\
+             </service><service><serviceType>{}</serviceType><controlURL>ctl/IPConn</controlURL>\
+             </service></root>",
+            "urn:schemas-upnp-org:service:Layer3Forwarding:1", SERVICE_TYPES[0]
+        );
+        assert_eq!(control_path(&description, SERVICE_TYPES[0]), Some("/ctl/IPConn".to_string()));
+        assert_eq!(control_path(&description, SERVICE_TYPES[1]), None);
+
+        let response = "<u:GetExternalIPAddressResponse>\
+                        <NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>\
+                        </u:GetExternalIPAddressResponse>";
+        assert_eq!(xml_value(response, "NewExternalIPAddress"), Some("1.2.3.4"));
+    }
+}
@@ -3,9 +3,9 @@ use chrono::{DateTime, Utc};
 use futures::{future, Future};
 use near_chain::test_utils::KeyValueRuntime;
 use near_client::{BlockProducer, ClientActor, ClientConfig};
-use near_crypto::{InMemorySigner, KeyType};
+use near_crypto::{InMemorySigner, KeyType, SecretKey};
 use near_network::test_utils::{convert_boot_nodes, open_port, WaitOrTimeout};
-use near_network::types::NetworkInfo;
+use near_network::types::{NetworkInfo, PeerId};
 use near_network::{NetworkConfig, NetworkRequests, NetworkResponses, PeerManagerActor};
 use near_primitives::test_utils::init_test_logger;
 use near_store::test_utils::create_test_store;
@@ -29,6 +29,7 @@ pub fn setup_network_node(
     let runtime = Arc::new(KeyValueRuntime::new_with_validators(
         store.clone(),
         validators.into_iter().map(Into::into).collect(),
+        100,
     ));
     let signer = Arc::new(InMemorySigner::from_seed(account_id, KeyType::ED25519, account_id));
     let block_producer = BlockProducer::from(signer.clone());
@@ -42,7 +43,7 @@ pub fn setup_network_node(
             runtime,
             config.public_key.clone().into(),
             ctx.address().recipient(),
-            Some(block_producer),
+            vec![block_producer],
             telemetry_actor,
         )
         .unwrap()
@@ -109,7 +110,15 @@ fn check_account_id_propagation(
                                     res.unwrap()
                                 {
                                     println!("Routes of {}: {:?}", account_ids_copy[i], routes);
-                                    if routes.unwrap().len() == total_nodes {
+                                    let routes = routes.unwrap();
+                                    // Accounts are routed to the peers of their validators.
+                                    let to_validators =
+                                        routes.iter().all(|(account_id, (peer_id, _))| {
+                                            let secret_key =
+                                                SecretKey::from_seed(KeyType::ED25519, account_id);
+                                            *peer_id == PeerId::from(secret_key.public_key())
+                                        });
+                                    if routes.len() == total_nodes && to_validators {
                                         count.fetch_add(1, Ordering::Relaxed);
 
                                         if counters
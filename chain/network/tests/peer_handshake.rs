@@ -9,10 +9,10 @@ use futures::future::Future;
 
 use near_client::ClientActor;
 use near_network::test_utils::{convert_boot_nodes, open_port, WaitOrTimeout};
-use near_network::types::NetworkInfo;
+use near_network::types::{NetworkInfo, PeerId, PeerMessage, PROTOCOL_VERSION};
 use near_network::{
-    NetworkClientMessages, NetworkClientResponses, NetworkConfig, NetworkRequests,
-    NetworkResponses, PeerManagerActor,
+    FullPeerInfo, NetworkClientMessages, NetworkClientResponses, NetworkConfig,
+    NetworkEventsObserver, NetworkRequests, NetworkResponses, PeerManagerActor,
 };
 use near_primitives::test_utils::init_test_logger;
 use near_store::test_utils::create_test_store;
@@ -20,14 +20,29 @@ use near_store::test_utils::create_test_store;
 type ClientMock = Mocker<ClientActor>;
 
 fn make_peer_manager(seed: &str, port: u16, boot_nodes: Vec<(&str, u16)>) -> PeerManagerActor {
-    let store = create_test_store();
+    make_peer_manager_on_chain(seed, port, boot_nodes, "unittest")
+}
+
+fn make_peer_manager_on_chain(
+    seed: &str,
+    port: u16,
+    boot_nodes: Vec<(&str, u16)>,
+    chain_id: &str,
+) -> PeerManagerActor {
     let mut config = NetworkConfig::from_seed(seed, port);
     config.boot_nodes = convert_boot_nodes(boot_nodes);
+    make_peer_manager_with_config(config, chain_id)
+}
+
+fn make_peer_manager_with_config(config: NetworkConfig, chain_id: &str) -> PeerManagerActor {
+    let chain_id = chain_id.to_string();
+    let store = create_test_store();
     let client_addr = ClientMock::mock(Box::new(move |msg, _ctx| {
         let msg = msg.downcast_ref::<NetworkClientMessages>().unwrap();
         match msg {
             NetworkClientMessages::GetChainInfo => {
                 Box::new(Some(NetworkClientResponses::ChainInfo {
+                    chain_id: chain_id.clone(),
                     genesis: Default::default(),
                     height: 1,
                     total_weight: 1.into(),
@@ -69,6 +84,143 @@ fn peer_handshake() {
     .unwrap();
 }
 
+/// Network info exposes the protocol version peers reported in the handshake.
+#[test]
+fn peer_handshake_protocol_version() {
+    init_test_logger();
+
+    System::run(|| {
+        let (port1, port2) = (open_port(), open_port());
+        let pm1 = make_peer_manager("test1", port1, vec![("test2", port2)]).start();
+        let _pm2 = make_peer_manager("test2", port2, vec![("test1", port1)]).start();
+        WaitOrTimeout::new(
+            Box::new(move |_| {
+                actix::spawn(pm1.send(NetworkRequests::FetchInfo { level: 0 }).then(move |res| {
+                    if let NetworkResponses::Info(NetworkInfo { active_peers, .. }) = res.unwrap()
+                    {
+                        if active_peers.len() == 1 {
+                            assert_eq!(active_peers[0].protocol_version, PROTOCOL_VERSION);
+                            System::current().stop();
+                        }
+                    }
+                    future::result(Ok(()))
+                }));
+            }),
+            100,
+            2000,
+        )
+        .start();
+    })
+    .unwrap();
+}
+
+/// Peer that doesn't encrypt connections connects to the peer that does, without encryption.
+#[test]
+fn peer_handshake_without_encryption() {
+    init_test_logger();
+
+    System::run(|| {
+        let (port1, port2) = (open_port(), open_port());
+        let mut config = NetworkConfig::from_seed("test1", port1);
+        config.boot_nodes = convert_boot_nodes(vec![("test2", port2)]);
+        config.enable_encryption = false;
+        let pm1 = make_peer_manager_with_config(config, "unittest").start();
+        let _pm2 = make_peer_manager("test2", port2, vec![("test1", port1)]).start();
+        WaitOrTimeout::new(
+            Box::new(move |_| {
+                actix::spawn(pm1.send(NetworkRequests::FetchInfo { level: 0 }).then(move |res| {
+                    if let NetworkResponses::Info(NetworkInfo { num_active_peers, .. }) =
+                        res.unwrap()
+                    {
+                        if num_active_peers == 1 {
+                            System::current().stop();
+                        }
+                    }
+                    future::result(Ok(()))
+                }));
+            }),
+            100,
+            2000,
+        )
+        .start();
+    })
+    .unwrap();
+}
+
+/// Peers on different chains don't connect and ban each other during the handshake.
+#[test]
+fn peer_handshake_wrong_chain() {
+    init_test_logger();
+
+    System::run(|| {
+        let (port1, port2) = (open_port(), open_port());
+        let pm1 = make_peer_manager_on_chain("test1", port1, vec![], "testnet").start();
+        let _pm2 = make_peer_manager("test2", port2, vec![("test1", port1)]).start();
+        let peer_id2 = convert_boot_nodes(vec![("test2", port2)])[0].id;
+        WaitOrTimeout::new(
+            Box::new(move |_| {
+                actix::spawn(pm1.send(NetworkRequests::FetchBans).then(move |res| {
+                    if let NetworkResponses::Bans(bans) = res.unwrap() {
+                        if bans.iter().any(|ban| ban.peer_id == peer_id2.to_string()) {
+                            assert_eq!(bans[0].reason, "WrongNetwork");
+                            System::current().stop();
+                        }
+                    }
+                    future::result(Ok(()))
+                }));
+            }),
+            100,
+            2000,
+        )
+        .start();
+    })
+    .unwrap();
+}
+
+/// Counts connected peers and received messages.
+#[derive(Default)]
+struct CountingObserver {
+    connected: AtomicUsize,
+    received: AtomicUsize,
+}
+
+impl NetworkEventsObserver for CountingObserver {
+    fn peer_connected(&self, _peer_info: &FullPeerInfo) {
+        self.connected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn message_received(&self, _peer_id: Option<&PeerId>, _msg: &PeerMessage) {
+        self.received.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn peer_handshake_observer() {
+    init_test_logger();
+
+    System::run(|| {
+        let (port1, port2) = (open_port(), open_port());
+        let observer = Arc::new(CountingObserver::default());
+        let mut pm1 = make_peer_manager("test1", port1, vec![("test2", port2)]);
+        pm1.register_observer(observer.clone());
+        let _pm1 = pm1.start();
+        let _pm2 = make_peer_manager("test2", port2, vec![("test1", port1)]).start();
+        WaitOrTimeout::new(
+            Box::new(move |_| {
+                if observer.connected.load(Ordering::Relaxed) == 1
+                    && observer.received.load(Ordering::Relaxed) > 0
+                {
+                    System::current().stop();
+                }
+            }),
+            100,
+            2000,
+        )
+        .start();
+    })
+    .unwrap();
+}
+
 #[test]
 fn peers_connect_all() {
     init_test_logger();
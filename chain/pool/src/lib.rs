@@ -1,89 +1,265 @@
-use std::collections::btree_map::BTreeMap;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Instant;
+
+use borsh::BorshSerialize;
+use log::debug;
 
 use near_chain::{Block, ValidTransaction};
+use near_crypto::PublicKey;
+use near_primitives::hash::CryptoHash;
 use near_primitives::transaction::SignedTransaction;
-use near_primitives::types::{AccountId, Nonce};
+use near_primitives::types::{AccountId, Gas, Nonce};
 
-pub use crate::types::Error;
+pub use crate::types::{BlockBudget, Error, PoolConfig};
 
 pub mod types;
 
+type AccessKey = (AccountId, PublicKey);
+
+struct PoolEntry {
+    /// Insertion order of the transaction, smaller is older.
+    seq: u64,
+    /// Size of the serialized transaction.
+    size: u64,
+    transaction: SignedTransaction,
+}
+
 /// Transaction pool: keeps track of transactions that were not yet accepted into the block chain.
+/// When the pool is full, the oldest transactions are evicted to make room for the new ones:
+/// transactions don't carry a fee, so age is the only measure of their value.
 pub struct TransactionPool {
+    config: PoolConfig,
     num_transactions: usize,
-    /// Transactions grouped by account and ordered by nonce.
-    pub transactions: HashMap<AccountId, BTreeMap<Nonce, SignedTransaction>>,
+    /// Total size of the serialized transactions in the pool.
+    num_bytes: u64,
+    /// Transactions grouped by the access key of the signer and ordered by nonce.
+    transactions: BTreeMap<AccessKey, BTreeMap<Nonce, PoolEntry>>,
+    /// Hashes of the transactions in the pool.
+    tx_hashes: HashSet<CryptoHash>,
+    /// Number of transactions in the pool per signer account.
+    account_transactions: HashMap<AccountId, usize>,
+    /// Transactions in the order of insertion.
+    by_age: BTreeMap<u64, (AccessKey, Nonce)>,
+    next_seq: u64,
+    /// Total number of transactions evicted to fit into the limits.
+    num_evicted: u64,
 }
 
 impl TransactionPool {
-    pub fn new() -> Self {
-        TransactionPool { num_transactions: 0, transactions: HashMap::default() }
+    pub fn new(config: PoolConfig) -> Self {
+        TransactionPool {
+            config,
+            num_transactions: 0,
+            num_bytes: 0,
+            transactions: BTreeMap::default(),
+            tx_hashes: HashSet::default(),
+            account_transactions: HashMap::default(),
+            by_age: BTreeMap::default(),
+            next_seq: 0,
+            num_evicted: 0,
+        }
     }
 
     /// Insert a valid transaction into the pool that passed validation.
-    pub fn insert_transaction(&mut self, valid_transaction: ValidTransaction) {
-        let signer_id = valid_transaction.transaction.transaction.signer_id.clone();
-        let nonce = valid_transaction.transaction.transaction.nonce;
-        self.num_transactions += 1;
+    /// Refuses transactions already in the pool, the ones reusing a nonce of a pooled
+    /// transaction signed with the same access key and the ones exceeding the limits of the
+    /// signer. Evicts the oldest transactions if the pool is full.
+    pub fn insert_transaction(&mut self, valid_transaction: ValidTransaction) -> Result<(), Error> {
+        let transaction = valid_transaction.transaction;
+        let hash = transaction.get_hash();
+        if self.tx_hashes.contains(&hash) {
+            return Err(Error::DuplicateTx(hash));
+        }
+        let key = (transaction.transaction.signer_id.clone(), transaction.transaction.public_key);
+        let nonce = transaction.transaction.nonce;
+        if self.transactions.get(&key).map_or(false, |map| map.contains_key(&nonce)) {
+            return Err(Error::NonceUsed(nonce));
+        }
+        let num_account_transactions =
+            self.account_transactions.get(&key.0).cloned().unwrap_or_default();
+        if num_account_transactions >= self.config.max_transactions_per_account {
+            return Err(Error::AccountLimit(key.0));
+        }
+        let size = transaction.try_to_vec().map_err(|err| Error::Other(err.to_string()))?.len();
+        let size = size as u64;
+        if size > self.config.max_bytes {
+            return Err(Error::TooLarge(size));
+        }
+        while self.num_transactions >= self.config.max_transactions
+            || self.num_bytes + size > self.config.max_bytes
+        {
+            if !self.evict_oldest() {
+                break;
+            }
+        }
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.by_age.insert(seq, (key.clone(), nonce));
+        *self.account_transactions.entry(key.0.clone()).or_insert(0) += 1;
         self.transactions
-            .entry(signer_id)
+            .entry(key)
             .or_insert_with(BTreeMap::new)
-            .insert(nonce, valid_transaction.transaction);
+            .insert(nonce, PoolEntry { seq, size, transaction });
+        self.tx_hashes.insert(hash);
+        self.num_transactions += 1;
+        self.num_bytes += size;
+        Ok(())
     }
 
-    /// Take transactions from the pool, in the appropriate order to be put in a new block.
-    /// Ensure that on average they will fit into expected weight.
+    /// Removes the oldest transaction from the pool, returns false if the pool is empty.
+    fn evict_oldest(&mut self) -> bool {
+        let (key, nonce) = match self.by_age.values().next() {
+            Some(oldest) => oldest.clone(),
+            None => return false,
+        };
+        if let Some(entry) = self.remove_transaction(&key, nonce) {
+            debug!(target: "pool", "Evicting transaction {} from the full pool", entry.transaction.get_hash());
+            self.num_evicted += 1;
+        }
+        true
+    }
+
+    fn remove_transaction(&mut self, key: &AccessKey, nonce: Nonce) -> Option<PoolEntry> {
+        let map = self.transactions.get_mut(key)?;
+        let entry = map.remove(&nonce)?;
+        if map.is_empty() {
+            self.transactions.remove(key);
+        }
+        let remove_account = match self.account_transactions.get_mut(&key.0) {
+            Some(num) => {
+                *num -= 1;
+                *num == 0
+            }
+            None => false,
+        };
+        if remove_account {
+            self.account_transactions.remove(&key.0);
+        }
+        self.by_age.remove(&entry.seq);
+        self.tx_hashes.remove(&entry.transaction.get_hash());
+        self.num_transactions -= 1;
+        self.num_bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// Take transactions from the pool, in the appropriate order to be put in a new block,
+    /// until the budget of the block is exhausted. Access keys take turns, one transaction each
+    /// in the order of nonces, starting from the key with the oldest transaction, so that no
+    /// signer is starved when the budget runs out. Transactions stay in the pool until they are
+    /// included, so the ones that didn't fit are taken for the next block.
     pub fn prepare_transactions(
         &mut self,
-        expected_weight: u32,
+        budget: &BlockBudget,
     ) -> Result<Vec<SignedTransaction>, Error> {
-        // TODO: pack transactions better.
-        let result = self
-            .transactions
-            .values()
-            .flat_map(BTreeMap::values)
-            .take(expected_weight as usize)
-            .cloned()
-            .collect();
+        let started = Instant::now();
+        let mut result = vec![];
+        let mut total_gas: Gas = 0;
+        let mut total_bytes = 0;
+        let mut queues: Vec<_> =
+            self.transactions.values().map(|transactions| transactions.values()).collect();
+        queues.sort_by_key(|queue| queue.clone().map(|entry| entry.seq).min());
+        'rounds: while !queues.is_empty() {
+            let mut i = 0;
+            while i < queues.len() {
+                if result.len() >= budget.max_transactions as usize
+                    || started.elapsed() >= budget.max_time
+                {
+                    break 'rounds;
+                }
+                let entry = match queues[i].next() {
+                    Some(entry) => entry,
+                    None => {
+                        queues.remove(i);
+                        continue;
+                    }
+                };
+                let gas = entry
+                    .transaction
+                    .transaction
+                    .actions
+                    .iter()
+                    .fold(0, |sum: Gas, action| sum.saturating_add(action.get_prepaid_gas()));
+                // Always take at least one transaction, so that large ones are not stuck.
+                if !result.is_empty()
+                    && (total_gas.saturating_add(gas) > budget.max_gas
+                        || total_bytes + entry.size > budget.max_bytes)
+                {
+                    // Following transactions of the access key depend on this one by nonce.
+                    queues.remove(i);
+                    continue;
+                }
+                total_gas = total_gas.saturating_add(gas);
+                total_bytes += entry.size;
+                result.push(entry.transaction.clone());
+                i += 1;
+            }
+        }
+        if result.len() < self.num_transactions {
+            debug!(target: "pool", "Block budget allows {} of {} transactions, {} gas, {} bytes", result.len(), self.num_transactions, total_gas, total_bytes);
+        }
         Ok(result)
     }
 
     /// Quick reconciliation step - evict all transactions that already in the block
-    /// or became invalid after it.
+    /// or became invalid after it, i.e. the ones with the nonce not larger than the nonce
+    /// of the included transaction signed with the same access key.
     pub fn reconcile_block(&mut self, block: &Block) {
         for signed_transaction in block.transactions.iter() {
-            let signer_id = &signed_transaction.transaction.signer_id;
-            let nonce = signed_transaction.transaction.nonce;
-            let mut remove_map = false;
-            if let Some(map) = self.transactions.get_mut(signer_id) {
-                map.remove(&nonce);
-                remove_map = map.is_empty();
-            }
-            if remove_map {
-                self.num_transactions -= 1;
-                self.transactions.remove(signer_id);
+            let key = (
+                signed_transaction.transaction.signer_id.clone(),
+                signed_transaction.transaction.public_key,
+            );
+            let nonces: Vec<Nonce> = match self.transactions.get(&key) {
+                Some(map) => {
+                    map.range(..=signed_transaction.transaction.nonce).map(|(n, _)| *n).collect()
+                }
+                None => continue,
+            };
+            for nonce in nonces {
+                self.remove_transaction(&key, nonce);
             }
         }
     }
 
+    /// Transactions in the pool, grouped by the access key of the signer and ordered by nonce.
+    pub fn transactions(&self) -> impl Iterator<Item = &SignedTransaction> {
+        self.transactions.values().flat_map(BTreeMap::values).map(|entry| &entry.transaction)
+    }
+
+    pub fn contains(&self, hash: &CryptoHash) -> bool {
+        self.tx_hashes.contains(hash)
+    }
+
     pub fn len(&self) -> usize {
         self.num_transactions
     }
+
+    /// Total size of the serialized transactions in the pool.
+    pub fn num_bytes(&self) -> u64 {
+        self.num_bytes
+    }
+
+    /// Total number of transactions evicted from the pool to fit into the limits.
+    pub fn num_evicted(&self) -> u64 {
+        self.num_evicted
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use std::sync::Arc;
+    use std::time::Duration;
 
     use rand::seq::SliceRandom;
     use rand::thread_rng;
 
-    use near_chain::ValidTransaction;
+    use chrono::Utc;
+
+    use near_chain::{Block, ValidTransaction};
     use near_crypto::{InMemorySigner, KeyType};
     use near_primitives::transaction::SignedTransaction;
 
-    use crate::TransactionPool;
+    use crate::{BlockBudget, Error, PoolConfig, TransactionPool};
     use near_primitives::hash::CryptoHash;
     use near_primitives::types::Balance;
 
@@ -105,15 +281,193 @@ mod tests {
                 )
             })
             .collect();
-        let mut pool = TransactionPool::new();
+        let mut pool = TransactionPool::new(PoolConfig::default());
         let mut rng = thread_rng();
         transactions.shuffle(&mut rng);
         for tx in transactions {
-            pool.insert_transaction(ValidTransaction { transaction: tx });
+            pool.insert_transaction(ValidTransaction { transaction: tx }).unwrap();
         }
-        let transactions = pool.prepare_transactions(10).unwrap();
+        let transactions = pool.prepare_transactions(&budget(10, 1024 * 1024)).unwrap();
         let nonces: Vec<u64> = transactions.iter().map(|tx| tx.transaction.nonce).collect();
         assert_eq!(nonces, (1..10).collect::<Vec<u64>>())
     }
 
+    /// Transactions already in the pool and the ones reusing the nonce of the access key are
+    /// refused, included transactions evict the ones with the same or lower nonce.
+    #[test]
+    fn test_duplicates_and_reconcile() {
+        let signer =
+            Arc::new(InMemorySigner::from_seed("alice.near", KeyType::ED25519, "alice.near"));
+        let send_money = |nonce, amount| {
+            let transaction = SignedTransaction::send_money(
+                nonce,
+                "alice.near".to_string(),
+                "bob.near".to_string(),
+                signer.clone(),
+                amount,
+                CryptoHash::default(),
+            );
+            ValidTransaction { transaction }
+        };
+        let mut pool = TransactionPool::new(PoolConfig::default());
+        for nonce in 1..5 {
+            pool.insert_transaction(send_money(nonce, 1)).unwrap();
+        }
+        match pool.insert_transaction(send_money(2, 1)) {
+            Err(Error::DuplicateTx(_)) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        match pool.insert_transaction(send_money(2, 10)) {
+            Err(Error::NonceUsed(2)) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        assert_eq!(pool.len(), 4);
+
+        let mut block = Block::genesis(CryptoHash::default(), Utc::now());
+        block.transactions = vec![send_money(3, 1).transaction];
+        pool.reconcile_block(&block);
+        assert_eq!(pool.len(), 1);
+        let nonces: Vec<u64> = pool.transactions().map(|tx| tx.transaction.nonce).collect();
+        assert_eq!(nonces, vec![4]);
+        // Evicted transactions can be submitted again, e.g. after a reorg.
+        pool.insert_transaction(send_money(2, 1)).unwrap();
+        assert_eq!(pool.len(), 2);
+    }
+
+    fn budget(max_transactions: u32, max_bytes: u64) -> BlockBudget {
+        BlockBudget {
+            max_transactions,
+            max_gas: 10u64.pow(15),
+            max_bytes,
+            max_time: Duration::from_secs(1),
+        }
+    }
+
+    fn send_money(signer_id: &str, nonce: u64) -> ValidTransaction {
+        let signer = Arc::new(InMemorySigner::from_seed(signer_id, KeyType::ED25519, signer_id));
+        let transaction = SignedTransaction::send_money(
+            nonce,
+            signer_id.to_string(),
+            "bob.near".to_string(),
+            signer,
+            1,
+            CryptoHash::default(),
+        );
+        ValidTransaction { transaction }
+    }
+
+    /// Full pool evicts the oldest transactions, single account can't take over the pool.
+    #[test]
+    fn test_pool_limits() {
+        let config = PoolConfig {
+            max_transactions: 4,
+            max_bytes: 1024 * 1024,
+            max_transactions_per_account: 3,
+        };
+        let mut pool = TransactionPool::new(config);
+        for nonce in 1..4 {
+            pool.insert_transaction(send_money("alice.near", nonce)).unwrap();
+        }
+        match pool.insert_transaction(send_money("alice.near", 4)) {
+            Err(Error::AccountLimit(_)) => {}
+            res => panic!("unexpected {:?}", res),
+        }
+        pool.insert_transaction(send_money("carol.near", 1)).unwrap();
+        pool.insert_transaction(send_money("carol.near", 2)).unwrap();
+        assert_eq!(pool.len(), 4);
+        assert_eq!(pool.num_evicted(), 1);
+        let alice_nonces: Vec<u64> = pool
+            .transactions()
+            .filter(|tx| tx.transaction.signer_id == "alice.near")
+            .map(|tx| tx.transaction.nonce)
+            .collect();
+        assert_eq!(alice_nonces, vec![2, 3]);
+        // Evicted transaction freed the slot of its account.
+        pool.insert_transaction(send_money("alice.near", 4)).unwrap();
+        assert_eq!(pool.num_evicted(), 2);
+
+        // Size limit evicts transactions as well.
+        let size = pool.num_bytes() / pool.len() as u64;
+        let config = PoolConfig {
+            max_transactions: 100,
+            max_bytes: size * 2,
+            max_transactions_per_account: 100,
+        };
+        let mut pool = TransactionPool::new(config);
+        for nonce in 1..4 {
+            pool.insert_transaction(send_money("alice.near", nonce)).unwrap();
+        }
+        assert_eq!(pool.len(), 2);
+        assert_eq!(pool.num_bytes(), size * 2);
+        assert_eq!(pool.num_evicted(), 1);
+        let nonces: Vec<u64> = pool.transactions().map(|tx| tx.transaction.nonce).collect();
+        assert_eq!(nonces, vec![2, 3]);
+    }
+
+    /// Transactions that don't fit into the budget of the block are left for the next one,
+    /// together with the following transactions of the same access key.
+    #[test]
+    fn test_prepare_within_budget() {
+        let mut pool = TransactionPool::new(PoolConfig::default());
+        for nonce in 1..4 {
+            pool.insert_transaction(send_money("alice.near", nonce)).unwrap();
+        }
+        pool.insert_transaction(send_money("carol.near", 1)).unwrap();
+        let size = pool.num_bytes() / pool.len() as u64;
+        let signers_nonces = |transactions: Vec<SignedTransaction>| -> Vec<(String, u64)> {
+            transactions
+                .into_iter()
+                .map(|tx| (tx.transaction.signer_id, tx.transaction.nonce))
+                .collect()
+        };
+
+        let transactions = pool.prepare_transactions(&budget(3, size * 10)).unwrap();
+        assert_eq!(transactions.len(), 3);
+        // Access keys take turns, so the later signer isn't starved by the earlier one.
+        let transactions = pool.prepare_transactions(&budget(10, size * 2)).unwrap();
+        assert_eq!(
+            signers_nonces(transactions),
+            vec![("alice.near".to_string(), 1), ("carol.near".to_string(), 1)]
+        );
+        let transactions = pool.prepare_transactions(&budget(10, size * 3)).unwrap();
+        assert_eq!(
+            signers_nonces(transactions),
+            vec![
+                ("alice.near".to_string(), 1),
+                ("carol.near".to_string(), 1),
+                ("alice.near".to_string(), 2)
+            ]
+        );
+        // Transaction larger than the budget is still taken alone.
+        let transactions = pool.prepare_transactions(&budget(10, 1)).unwrap();
+        assert_eq!(signers_nonces(transactions), vec![("alice.near".to_string(), 1)]);
+        // Nothing is removed from the pool until included.
+        assert_eq!(pool.len(), 4);
+    }
+
+    /// Access key with the oldest transaction goes first in each round, whatever its order.
+    #[test]
+    fn test_prepare_round_robin() {
+        let mut pool = TransactionPool::new(PoolConfig::default());
+        pool.insert_transaction(send_money("zoe.near", 1)).unwrap();
+        for nonce in 1..4 {
+            pool.insert_transaction(send_money("alice.near", nonce)).unwrap();
+        }
+        pool.insert_transaction(send_money("zoe.near", 2)).unwrap();
+        let signers_nonces: Vec<_> = pool
+            .prepare_transactions(&budget(4, 1024 * 1024))
+            .unwrap()
+            .into_iter()
+            .map(|tx| (tx.transaction.signer_id, tx.transaction.nonce))
+            .collect();
+        assert_eq!(
+            signers_nonces,
+            vec![
+                ("zoe.near".to_string(), 1),
+                ("alice.near".to_string(), 1),
+                ("zoe.near".to_string(), 2),
+                ("alice.near".to_string(), 2)
+            ]
+        );
+    }
 }
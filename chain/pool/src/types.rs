@@ -1,6 +1,46 @@
+use std::time::Duration;
+
 use failure::Fail;
+use serde_derive::{Deserialize, Serialize};
 
 use near_chain::ValidTransaction;
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::{AccountId, Gas, Nonce};
+
+/// Budget of the block being produced: transactions are taken from the pool until it's exhausted,
+/// the rest stay in the pool for the next blocks.
+#[derive(Clone, Debug)]
+pub struct BlockBudget {
+    pub max_transactions: u32,
+    /// Maximum total gas prepaid by the transactions.
+    pub max_gas: Gas,
+    /// Maximum total size of the serialized transactions.
+    pub max_bytes: u64,
+    /// Maximum time spent on taking transactions from the pool.
+    pub max_time: Duration,
+}
+
+/// Limits of the transaction pool, so that a burst of transactions can't exhaust the memory.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct PoolConfig {
+    /// Maximum number of transactions in the pool, the oldest ones are evicted when reached.
+    pub max_transactions: usize,
+    /// Maximum total size of the serialized transactions in the pool.
+    pub max_bytes: u64,
+    /// Maximum number of transactions of a single signer account, new ones are refused after.
+    pub max_transactions_per_account: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        PoolConfig {
+            max_transactions: 10_000,
+            max_bytes: 20 * 1024 * 1024,
+            max_transactions_per_account: 100,
+        }
+    }
+}
 
 /// Possible errors whe interacting with transaction pool.
 #[derive(Debug, Fail)]
@@ -8,6 +48,18 @@ pub enum Error {
     /// An invalid pool entry caused by underlying tx validation error
     #[fail(display = "Invalid Tx {}", _0)]
     InvalidTx(String),
+    /// Transaction with the same hash is already in the pool.
+    #[fail(display = "Transaction {} is already in the pool", _0)]
+    DuplicateTx(CryptoHash),
+    /// Pool already has a transaction with the same nonce signed with the same access key.
+    #[fail(display = "Transaction with nonce {} for the access key is already in the pool", _0)]
+    NonceUsed(Nonce),
+    /// Signer account already has the maximum number of transactions in the pool.
+    #[fail(display = "Too many transactions of account {} in the pool", _0)]
+    AccountLimit(AccountId),
+    /// Transaction is larger than the whole pool.
+    #[fail(display = "Transaction of {} bytes doesn't fit into the pool", _0)]
+    TooLarge(u64),
     /// Other kinds of error (not yet pulled out into meaningful errors).
     #[fail(display = "General pool error {}", _0)]
     Other(String),
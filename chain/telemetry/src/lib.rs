@@ -19,6 +19,12 @@ pub struct TelemetryEvent {
     content: serde_json::Value,
 }
 
+/// Replaces telemetry config, e.g. on config reload.
+#[derive(Message)]
+pub struct UpdateTelemetryConfig {
+    pub config: TelemetryConfig,
+}
+
 pub struct TelemetryActor {
     config: TelemetryConfig,
     client: Client,
@@ -58,6 +64,14 @@ impl Handler<TelemetryEvent> for TelemetryActor {
     }
 }
 
+impl Handler<UpdateTelemetryConfig> for TelemetryActor {
+    type Result = ();
+
+    fn handle(&mut self, msg: UpdateTelemetryConfig, _ctx: &mut Context<Self>) {
+        self.config = msg.config;
+    }
+}
+
 /// Send telemetry event to all the endpoints.
 pub fn telemetry(telemetry: &Addr<TelemetryActor>, content: serde_json::Value) {
     telemetry.do_send(TelemetryEvent { content });
@@ -1,6 +1,6 @@
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
 
 use serde_derive::{Deserialize, Serialize};
 
@@ -14,14 +14,31 @@ pub struct KeyFile {
 }
 
 impl KeyFile {
+    /// Writes key file atomically: content goes into a temporary file next to the target,
+    /// which is then renamed, so the key file is never left partially written.
     pub fn write_to_file(&self, path: &Path) {
-        let mut file = File::create(path).expect("Failed to create / write a key file.");
-        let str = serde_json::to_string_pretty(self).expect("Error serializing the key file.");
-        if let Err(err) = file.write_all(str.as_bytes()) {
+        if let Err(err) = self.try_write_to_file(path) {
             panic!("Failed to write a key file {}", err);
         }
     }
 
+    /// Same as `write_to_file`, but returns the error instead of panicking.
+    pub fn try_write_to_file(&self, path: &Path) -> io::Result<()> {
+        let str = serde_json::to_string_pretty(self).expect("Error serializing the key file.");
+        write_atomically(path, str.as_bytes())
+    }
+
+    /// Replaces the key in the key file at the path with this key. Previous key is kept next to
+    /// the key file with `.old` suffix, numbered if the key was already replaced, so that none of
+    /// the previous keys is lost.
+    pub fn rotate_file(&self, path: &Path) -> io::Result<()> {
+        let previous_key = fs::read(path)?;
+        let previous_path =
+            rotated_key_paths(path).find(|path| !path.exists()).expect("Unbounded range");
+        write_atomically(&previous_path, &previous_key)?;
+        self.try_write_to_file(path)
+    }
+
     pub fn from_file(path: &Path) -> Self {
         let mut file = File::open(path).expect("Could not open key file.");
         let mut content = String::new();
@@ -29,3 +46,27 @@ impl KeyFile {
         serde_json::from_str(&content).expect("Failed to deserialize KeyFile")
     }
 }
+
+/// Paths of the previous keys kept on rotations of the key file, in order of the rotations,
+/// including the ones that don't exist yet.
+pub fn rotated_key_paths(key_path: &Path) -> impl Iterator<Item = PathBuf> {
+    let key_path = key_path.as_os_str().to_os_string();
+    (0..).map(move |n| {
+        let mut path = key_path.clone();
+        path.push(".old");
+        if n > 0 {
+            path.push(format!(".{}", n));
+        }
+        PathBuf::from(path)
+    })
+}
+
+fn write_atomically(path: &Path, content: &[u8]) -> io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut file = File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
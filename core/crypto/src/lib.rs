@@ -1,4 +1,4 @@
-pub use key_file::KeyFile;
+pub use key_file::{rotated_key_paths, KeyFile};
 pub use signature::{KeyType, PublicKey, ReadablePublicKey, SecretKey, Signature};
 pub use signer::{InMemorySigner, Signer};
 
@@ -7,6 +7,7 @@ use chrono::prelude::{DateTime, Utc};
 use near_crypto::{KeyType, PublicKey, Signature, Signer};
 
 use crate::hash::{hash, CryptoHash};
+use crate::merkle::merklize;
 use crate::transaction::SignedTransaction;
 use crate::types::{BlockIndex, MerkleHash, ValidatorStake};
 use crate::utils::{from_timestamp, to_timestamp};
@@ -166,8 +167,7 @@ impl Block {
         validator_proposal: Vec<ValidatorStake>,
         signer: Arc<dyn Signer>,
     ) -> Self {
-        // TODO: merkelize transactions.
-        let tx_root = CryptoHash::default();
+        let tx_root = Block::compute_tx_root(&transactions);
         let (approval_mask, approval_sigs) = if let Some(max_approver) = approvals.keys().max() {
             (
                 (0..=*max_approver).map(|i| approvals.contains_key(&i)).collect(),
@@ -196,6 +196,11 @@ impl Block {
         }
     }
 
+    /// Returns merkle root of the transactions, default hash if there are none.
+    pub fn compute_tx_root(transactions: &[SignedTransaction]) -> MerkleHash {
+        merklize(transactions).0
+    }
+
     pub fn hash(&self) -> CryptoHash {
         self.header.hash()
     }
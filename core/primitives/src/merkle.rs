@@ -1,6 +1,6 @@
 use crate::hash::hash;
 use crate::types::MerkleHash;
-use borsh::BorshSerialize;
+use borsh::{BorshDeserialize, BorshSerialize};
 
 pub type MerklePath = Vec<(MerkleHash, Direction)>;
 
@@ -10,7 +10,7 @@ pub enum Direction {
     Right,
 }
 
-fn combine_hash(hash1: MerkleHash, hash2: MerkleHash) -> MerkleHash {
+pub fn combine_hash(hash1: MerkleHash, hash2: MerkleHash) -> MerkleHash {
     let mut combined: Vec<u8> = hash1.into();
     combined.append(&mut hash2.into());
     hash(&combined)
@@ -76,7 +76,12 @@ pub fn merklize<T: BorshSerialize>(arr: &[T]) -> (MerkleHash, Vec<MerklePath>) {
 
 /// Verify merkle path for given item and corresponding path.
 pub fn verify_path<T: BorshSerialize>(root: MerkleHash, path: &MerklePath, item: &T) -> bool {
-    let mut hash = hash(&item.try_to_vec().expect("Failed to serialize"));
+    verify_hash(root, path, hash(&item.try_to_vec().expect("Failed to serialize")))
+}
+
+/// Verify merkle path for given leaf hash and corresponding path.
+pub fn verify_hash(root: MerkleHash, path: &MerklePath, leaf: MerkleHash) -> bool {
+    let mut hash = leaf;
     for (h, d) in path {
         match d {
             Direction::Left => {
@@ -90,6 +95,53 @@ pub fn verify_path<T: BorshSerialize>(root: MerkleHash, path: &MerklePath, item:
     hash == root
 }
 
+/// Merkle accumulator that supports appending leaves, keeping only roots of the complete subtrees.
+/// Subtrees in `path` go from the largest to the smallest, and correspond to set bits of `size`.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PartialMerkleTree {
+    path: Vec<MerkleHash>,
+    size: u64,
+}
+
+impl PartialMerkleTree {
+    /// Root of the accumulator. Complete subtrees are combined from the smallest to the largest.
+    pub fn root(&self) -> MerkleHash {
+        match self.path.last() {
+            None => MerkleHash::default(),
+            Some(last) => {
+                let mut res = *last;
+                for i in (0..self.path.len() - 1).rev() {
+                    res = combine_hash(self.path[i], res);
+                }
+                res
+            }
+        }
+    }
+
+    /// Appends new leaf, merging complete subtrees of the same size.
+    pub fn insert(&mut self, leaf: MerkleHash) {
+        let mut size = self.size;
+        let mut node = leaf;
+        while size % 2 == 1 {
+            let last = self.path.pop().expect("Path has an element for each set bit of size");
+            node = combine_hash(last, node);
+            size /= 2;
+        }
+        self.path.push(node);
+        self.size += 1;
+    }
+
+    /// Number of leaves in the accumulator.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Roots of the complete subtrees, from the largest to the smallest.
+    pub fn path(&self) -> &[MerkleHash] {
+        &self.path
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -117,6 +169,26 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_partial_merkle_tree() {
+        let mut tree = PartialMerkleTree::default();
+        let mut leaves = vec![];
+        for i in 0..20u32 {
+            let leaf = hash(&i.try_to_vec().unwrap());
+            tree.insert(leaf);
+            leaves.push(leaf);
+            assert_eq!(tree.size(), leaves.len() as u64);
+            assert_eq!(tree.path().len() as u32, tree.size().count_ones());
+        }
+        let mut other = PartialMerkleTree::default();
+        for leaf in leaves.iter() {
+            other.insert(*leaf);
+        }
+        assert_eq!(tree.root(), other.root());
+        other.insert(leaves[0]);
+        assert_ne!(tree.root(), other.root());
+    }
+
     #[test]
     fn test_incorrect_path() {
         let items = vec![111, 222, 333];
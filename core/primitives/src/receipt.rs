@@ -52,6 +52,7 @@ impl Receipt {
                 output_data_receivers: vec![],
                 input_data_ids: vec![],
                 actions: vec![Action::Transfer(TransferAction { deposit: refund })],
+                depth: 0,
             }),
         }
     }
@@ -76,6 +77,9 @@ pub struct ActionReceipt {
     pub input_data_ids: Vec<CryptoHash>,
 
     pub actions: Vec<Action>,
+
+    /// Number of function calls that led to this receipt, zero for the receipts of transactions.
+    pub depth: u64,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, Hash, Clone, Debug, PartialEq, Eq)]
@@ -10,6 +10,7 @@ use crate::account::AccessKey;
 use crate::block::BlockHeader;
 use crate::hash::{hash, CryptoHash};
 use crate::logging;
+use crate::serialize::u128_dec_format;
 use crate::types::{AccountId, Balance, BlockIndex, Gas, Nonce};
 
 pub type LogEntry = String;
@@ -220,6 +221,9 @@ pub struct TransactionResult {
     pub receipts: Vec<CryptoHash>,
     /// Execution Result
     pub result: Option<Vec<u8>>,
+    /// Gas burnt while processing this transaction or receipt, unknown for the results stored
+    /// before the burnt gas was recorded.
+    pub gas_burnt: Option<Gas>,
 }
 
 impl fmt::Debug for TransactionResult {
@@ -229,10 +233,22 @@ impl fmt::Debug for TransactionResult {
             .field("logs", &format_args!("{}", logging::pretty_vec(&self.logs)))
             .field("receipts", &format_args!("{}", logging::pretty_vec(&self.receipts)))
             .field("result", &format_args!("{}", logging::pretty_result(&self.result)))
+            .field("gas_burnt", &self.gas_burnt)
             .finish()
     }
 }
 
+/// Transaction that created the receipt, directly or through a chain of other receipts.
+#[derive(BorshSerialize, BorshDeserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ReceiptOrigin {
+    /// Hash of the originating transaction.
+    pub transaction_hash: CryptoHash,
+    /// Hash of the block that included the originating transaction.
+    pub block_hash: CryptoHash,
+    /// Height of the block that included the originating transaction.
+    pub block_index: BlockIndex,
+}
+
 #[derive(PartialEq, Clone, Default, Debug)]
 pub struct TransactionLog {
     /// Hash of a transaction or a receipt that generated this result.
@@ -263,6 +279,67 @@ pub fn check_tx_history(
     }
 }
 
+/// Reason why transaction was refused before being included into a block.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub enum InvalidTxError {
+    InvalidSignerId(AccountId),
+    SignerDoesNotExist(AccountId),
+    /// Access key doesn't exist or doesn't allow the transaction.
+    InvalidAccessKey(String),
+    /// Nonce of the transaction must be larger than the nonce of the access key.
+    InvalidNonce { nonce: Nonce, access_key_nonce: Nonce },
+    InvalidReceiverId(AccountId),
+    InvalidSignature,
+    /// Signer can't pay for the deposits and the gas of the transaction.
+    NotEnoughBalance {
+        signer_id: AccountId,
+        #[serde(with = "u128_dec_format")]
+        balance: Balance,
+        #[serde(with = "u128_dec_format")]
+        cost: Balance,
+    },
+    /// Block hash of the transaction is too old or is from a different fork.
+    Expired,
+    /// Transaction may be valid, but the node can't accept it, e.g. its pool is full.
+    Refused(String),
+}
+
+impl fmt::Display for InvalidTxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidTxError::InvalidSignerId(signer_id) => {
+                write!(f, "Invalid signer account ID {:?} according to requirements", signer_id)
+            }
+            InvalidTxError::SignerDoesNotExist(signer_id) => {
+                write!(f, "Signer {:?} does not exist", signer_id)
+            }
+            InvalidTxError::InvalidAccessKey(err) => f.write_str(err),
+            InvalidTxError::InvalidNonce { nonce, access_key_nonce } => write!(
+                f,
+                "Transaction nonce {} must be larger than nonce of the used access key {}",
+                nonce, access_key_nonce
+            ),
+            InvalidTxError::InvalidReceiverId(receiver_id) => {
+                write!(f, "Invalid receiver account ID {:?} according to requirements", receiver_id)
+            }
+            InvalidTxError::InvalidSignature => {
+                f.write_str("Transaction is not signed with the given public key")
+            }
+            InvalidTxError::NotEnoughBalance { signer_id, balance, cost } => write!(
+                f,
+                "Sender {} does not have enough balance {} for operation costing {}",
+                signer_id, balance, cost
+            ),
+            InvalidTxError::Expired => {
+                f.write_str("Transaction has either expired or is from a different fork")
+            }
+            InvalidTxError::Refused(err) => f.write_str(err),
+        }
+    }
+}
+
+impl std::error::Error for InvalidTxError {}
+
 #[cfg(test)]
 mod tests {
     use std::convert::TryInto;
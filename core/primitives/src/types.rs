@@ -54,6 +54,24 @@ impl PartialEq for ValidatorStake {
 
 impl Eq for ValidatorStake {}
 
+/// Part of the state of a shard, served during state sync.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq)]
+pub struct StatePart {
+    /// Index of this part.
+    pub part_id: u64,
+    /// Total number of parts the state is split into.
+    pub num_parts: u64,
+    /// First key of the key range of this part, empty for the first part.
+    pub start_key: Vec<u8>,
+    /// First key of the next part, the range doesn't include it. `None` for the last part.
+    pub end_key: Option<Vec<u8>>,
+    /// Serialized key-value pairs of this part, all the items in its key range.
+    pub data: Vec<u8>,
+    /// Trie nodes read when walking the key range of this part from the state root, enough to
+    /// check that the part has all the items in the range.
+    pub proof: Vec<Vec<u8>>,
+}
+
 /// Data structure for semver version and github tag or commit.
 #[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct Version {
@@ -6,11 +6,13 @@ use chrono::{DateTime, Utc};
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
 use near_crypto::{PublicKey, Signature};
+use near_vm_logic::{EVENT_LOG_PREFIX, LOG_PREFIX};
 
 use crate::account::{AccessKey, AccessKeyPermission, Account, FunctionCallPermission};
 use crate::block::{Block, BlockHeader, BlockHeaderInner};
 use crate::hash::CryptoHash;
 use crate::logging;
+use crate::merkle::{Direction, MerklePath};
 use crate::receipt::{ActionReceipt, DataReceipt, DataReceiver, Receipt, ReceiptEnum};
 use crate::serialize::{
     from_base, from_base64, option_base64_format, option_u128_dec_format, to_base, to_base64,
@@ -18,11 +20,12 @@ use crate::serialize::{
 };
 use crate::transaction::{
     Action, AddKeyAction, CreateAccountAction, DeleteAccountAction, DeleteKeyAction,
-    DeployContractAction, FunctionCallAction, LogEntry, SignedTransaction, StakeAction,
-    TransactionLog, TransactionResult, TransactionStatus, TransferAction,
+    DeployContractAction, FunctionCallAction, LogEntry, ReceiptOrigin, SignedTransaction,
+    StakeAction, TransactionLog, TransactionResult, TransactionStatus, TransferAction,
 };
 use crate::types::{
-    AccountId, Balance, BlockIndex, Gas, Nonce, StorageUsage, ValidatorStake, Version,
+    AccountId, Balance, BlockIndex, Gas, Nonce, ShardId, StorageUsage, ValidatorId,
+    ValidatorStake, Version,
 };
 
 #[derive(Debug, PartialEq, Eq, Clone)]
@@ -161,6 +164,9 @@ impl From<AccessKeyView> for AccessKey {
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ViewStateResult {
     pub values: HashMap<Vec<u8>, Vec<u8>>,
+    /// Key to continue from if the number of returned values was limited.
+    #[serde(default)]
+    pub next_key: Option<Vec<u8>>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -223,6 +229,67 @@ pub struct StatusResponse {
     pub sync_info: StatusSyncInfo,
 }
 
+/// Progress of syncing a specific shard.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum ShardSyncStatusView {
+    StateDownload {
+        downloaded_parts: u64,
+        num_parts: u64,
+        downloaded_size: u64,
+        total_size: u64,
+        /// Percentage of the downloaded state size.
+        percent: f64,
+        /// Average download rate in bytes per second.
+        bytes_per_sec: u64,
+        /// Estimated number of seconds until the download completes.
+        eta_sec: Option<u64>,
+    },
+    StateValidation,
+    StateDone,
+    Error(String),
+}
+
+/// Stage of syncing the node is in, with its progress.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum SyncStatusView {
+    AwaitingPeers,
+    NoSync,
+    HeaderSync { current_height: BlockIndex, highest_height: BlockIndex },
+    StateSync { sync_hash: CryptoHashView, shards: HashMap<ShardId, ShardSyncStatusView> },
+    StateSyncDone,
+    BodySync { current_height: BlockIndex, highest_height: BlockIndex },
+}
+
+/// Ban of a known peer.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PeerBanView {
+    pub peer_id: String,
+    pub reason: String,
+    /// Time the ban expires at, `None` if the ban is permanent.
+    pub until: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PeerInfoView {
+    pub peer_id: String,
+    pub addr: Option<String>,
+    pub account_id: Option<AccountId>,
+    pub height: BlockIndex,
+    /// Round trip time of the last answered ping in milliseconds.
+    pub latency_ms: Option<u64>,
+    /// Last time anything was received from the peer.
+    pub last_seen: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NetworkInfoView {
+    pub num_active_peers: usize,
+    pub peer_max_count: u32,
+    pub sent_bytes_per_sec: u64,
+    pub received_bytes_per_sec: u64,
+    pub active_peers: Vec<PeerInfoView>,
+}
+
 impl TryFrom<QueryResponse> for AccountView {
     type Error = String;
 
@@ -509,15 +576,28 @@ pub struct TransactionResultView {
     pub logs: Vec<LogEntry>,
     pub receipts: Vec<CryptoHashView>,
     pub result: Option<String>,
+    /// Gas burnt, `null` if the node executed the transaction or receipt before it recorded the
+    /// burnt gas.
+    #[serde(default)]
+    pub gas_burnt: Option<Gas>,
+    /// Transaction that created this receipt, empty for transactions.
+    #[serde(default)]
+    pub origin: Option<ReceiptOriginView>,
+    /// Structured events parsed from the logs.
+    #[serde(default)]
+    pub events: Vec<EventView>,
 }
 
 impl From<TransactionResult> for TransactionResultView {
     fn from(result: TransactionResult) -> Self {
         Self {
             status: result.status,
-            logs: result.logs,
             receipts: result.receipts.into_iter().map(|h| h.into()).collect(),
             result: result.result.map(|v| to_base64(&v)),
+            gas_burnt: result.gas_burnt,
+            origin: None,
+            events: result.logs.iter().filter_map(|log| EventView::from_log(log)).collect(),
+            logs: result.logs,
         }
     }
 }
@@ -529,6 +609,49 @@ impl From<TransactionResultView> for TransactionResult {
             logs: view.logs,
             receipts: view.receipts.into_iter().map(|h| h.into()).collect(),
             result: view.result.map(|v| from_base64(&v).unwrap()),
+            gas_burnt: view.gas_burnt,
+        }
+    }
+}
+
+/// Structured event emitted by a contract as a log with `EVENT_LOG_PREFIX`, see `near_vm_logic`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EventView {
+    /// Name of the standard the event belongs to, e.g. `nep141`.
+    pub standard: String,
+    /// Version of the standard.
+    pub version: String,
+    /// Name of the event, e.g. `ft_transfer`.
+    pub event: String,
+    /// Event specific data.
+    #[serde(default)]
+    pub data: Option<serde_json::Value>,
+}
+
+impl EventView {
+    /// Parses the event from the log, returns `None` if the log is not a well-formed event.
+    pub fn from_log(log: &str) -> Option<Self> {
+        let log = if log.starts_with(LOG_PREFIX) { &log[LOG_PREFIX.len()..] } else { log };
+        if !log.starts_with(EVENT_LOG_PREFIX) {
+            return None;
+        }
+        serde_json::from_str(&log[EVENT_LOG_PREFIX.len()..]).ok()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReceiptOriginView {
+    pub transaction_hash: CryptoHashView,
+    pub block_hash: CryptoHashView,
+    pub block_index: BlockIndex,
+}
+
+impl From<ReceiptOrigin> for ReceiptOriginView {
+    fn from(origin: ReceiptOrigin) -> Self {
+        Self {
+            transaction_hash: origin.transaction_hash.into(),
+            block_hash: origin.block_hash.into(),
+            block_index: origin.block_index,
         }
     }
 }
@@ -584,6 +707,153 @@ impl FinalTransactionResult {
     }
 }
 
+/// Outcome of executing transaction against the current state without committing it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SimulatedTransactionResult {
+    /// Status of the transaction and the receipts executed locally.
+    pub status: FinalTransactionStatus,
+    /// Results of the transaction and the receipts executed locally.
+    pub transactions: Vec<TransactionLogView>,
+    /// Receipts that would be sent to other accounts and were not executed.
+    pub receipts: Vec<ReceiptView>,
+    /// Total gas burnt by the transaction and the receipts executed locally.
+    pub gas_burnt: Gas,
+}
+
+/// Protocol parameters in effect at the given block, as set in the genesis config.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct ProtocolConfigView {
+    /// Height of the block the parameters were requested for.
+    pub block_height: BlockIndex,
+    pub protocol_version: u32,
+    pub chain_id: String,
+    /// Hash of the whole genesis config, identifies the network together with the chain id.
+    pub genesis_config_hash: CryptoHash,
+    pub epoch_length: BlockIndex,
+    pub num_block_producers: ValidatorId,
+    pub block_producers_per_shard: Vec<ValidatorId>,
+    pub validator_kickout_threshold: f64,
+    pub transaction_validity_period: BlockIndex,
+    pub gas_economics: GasEconomicsView,
+    pub runtime_limits: RuntimeLimitsView,
+}
+
+/// Storage rent and other economic constants of the runtime.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct GasEconomicsView {
+    #[serde(with = "u128_dec_format")]
+    pub storage_cost_byte_per_block: Balance,
+    pub poke_threshold: BlockIndex,
+    #[serde(with = "u128_dec_format")]
+    pub account_length_baseline_cost_per_block: Balance,
+}
+
+/// Expected effect of the pending stake changes of the account, e.g. after unstaking.
+/// Heights are the expected starts of the epochs, the actual epoch starts at the first block at
+/// or after them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct UnstakeInfoView {
+    pub account_id: AccountId,
+    /// Stake currently locked by the staking rules.
+    #[serde(with = "u128_dec_format")]
+    pub locked: Balance,
+    /// Stake the account is going to have once the pending proposals take effect.
+    #[serde(with = "u128_dec_format")]
+    pub final_stake: Balance,
+    /// Start of the first epoch in which the account no longer validates, `None` if it keeps
+    /// validating or isn't a validator.
+    pub validation_end_height: Option<BlockIndex>,
+    /// Start of the epoch at which stake above the final one is returned to the account, `None`
+    /// if nothing is to be returned.
+    pub unlock_height: Option<BlockIndex>,
+}
+
+/// Signature of a block producer approving a block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ApprovalSignatureView {
+    pub account_id: AccountId,
+    pub signature: Signature,
+}
+
+/// Signatures of the block producers that approved the block, included in the header of its child.
+/// Lets light clients check that the block was endorsed by the block producers of its epoch.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockApprovalsView {
+    pub block_hash: CryptoHashView,
+    pub child_hash: CryptoHashView,
+    pub approvals: Vec<ApprovalSignatureView>,
+}
+
+/// Sibling hash on the merkle path and the side it is combined from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct MerklePathItemView {
+    pub hash: CryptoHashView,
+    pub direction: Direction,
+}
+
+/// Proof that the block is an ancestor of the head block: merkle path of the block in the
+/// accumulator of all the blocks up to the head. Lets light clients check a block against a
+/// single trusted header.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockProofView {
+    pub block_hash: CryptoHashView,
+    pub head_hash: CryptoHashView,
+    /// Root of the block merkle accumulator of the head block.
+    pub head_merkle_root: CryptoHashView,
+    pub proof: Vec<MerklePathItemView>,
+}
+
+impl BlockProofView {
+    pub fn new(
+        block_hash: CryptoHash,
+        head_hash: CryptoHash,
+        head_merkle_root: CryptoHash,
+        proof: MerklePath,
+    ) -> Self {
+        BlockProofView {
+            block_hash: block_hash.into(),
+            head_hash: head_hash.into(),
+            head_merkle_root: head_merkle_root.into(),
+            proof: proof
+                .into_iter()
+                .map(|(hash, direction)| MerklePathItemView { hash: hash.into(), direction })
+                .collect(),
+        }
+    }
+}
+
+/// Time in microseconds spent on the stages of processing a block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BlockProfileView {
+    pub hash: CryptoHashView,
+    pub height: BlockIndex,
+    pub signature_check: u64,
+    pub apply_transactions: u64,
+    pub trie_commit: u64,
+    pub store_commit: u64,
+    pub total: u64,
+}
+
+/// Switch of the head to another fork, the blocks are in ascending order of height.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ReorgView {
+    pub common_ancestor: CryptoHashView,
+    pub abandoned: Vec<CryptoHashView>,
+    pub canonical: Vec<CryptoHashView>,
+}
+
+/// Limits enforced by the runtime during the execution of transactions and receipts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RuntimeLimitsView {
+    pub max_gas_per_block: Gas,
+    pub max_receipt_depth: u64,
+    pub max_promises_per_function_call: u64,
+    pub max_number_logs: u64,
+    pub max_log_len: u64,
+    pub max_storage_key_len: u64,
+    pub max_storage_value_len: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ValidatorStakeView {
     pub account_id: AccountId,
@@ -637,6 +907,8 @@ pub enum ReceiptEnumView {
         output_data_receivers: Vec<DataReceiverView>,
         input_data_ids: Vec<CryptoHashView>,
         actions: Vec<ActionView>,
+        #[serde(default)]
+        depth: u64,
     },
     Data {
         data_id: CryptoHashView,
@@ -670,6 +942,7 @@ impl From<Receipt> for ReceiptView {
                         .map(Into::into)
                         .collect(),
                     actions: action_receipt.actions.into_iter().map(Into::into).collect(),
+                    depth: action_receipt.depth,
                 },
                 ReceiptEnum::Data(data_receipt) => ReceiptEnumView::Data {
                     data_id: data_receipt.data_id.into(),
@@ -696,6 +969,7 @@ impl TryFrom<ReceiptView> for Receipt {
                     output_data_receivers,
                     input_data_ids,
                     actions,
+                    depth,
                 } => ReceiptEnum::Action(ActionReceipt {
                     signer_id,
                     signer_public_key: signer_public_key.into(),
@@ -712,6 +986,7 @@ impl TryFrom<ReceiptView> for Receipt {
                         .into_iter()
                         .map(TryInto::try_into)
                         .collect::<Result<Vec<_>, _>>()?,
+                    depth,
                 }),
                 ReceiptEnumView::Data { data_id, data } => {
                     ReceiptEnum::Data(DataReceipt { data_id: data_id.into(), data })
@@ -720,3 +995,24 @@ impl TryFrom<ReceiptView> for Receipt {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_from_log() {
+        let event = EventView::from_log(
+            r#"LOG: EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[{"amount":"10"}]}"#,
+        )
+        .unwrap();
+        assert_eq!(event.standard, "nep141");
+        assert_eq!(event.version, "1.0.0");
+        assert_eq!(event.event, "ft_transfer");
+        assert_eq!(event.data, Some(serde_json::json!([{"amount": "10"}])));
+
+        assert_eq!(EventView::from_log("LOG: hello"), None);
+        assert_eq!(EventView::from_log("LOG: EVENT_JSON:{not json}"), None);
+        assert_eq!(EventView::from_log(r#"LOG: EVENT_JSON:{"standard":"nep141"}"#), None);
+    }
+}
@@ -0,0 +1,251 @@
+//! Backup of the store: checkpoint of the RocksDB database together with SHA-256 checksums of
+//! its files, so that a backup damaged after it was written is detected before it replaces the
+//! database.
+//!
+//! Checksums are written to the `CHECKSUMS` file of the backup directory. Format of the file:
+//! magic bytes, version, entries of the files in the name order (each prefixed by a tag with the
+//! name, size and checksum of the file), end tag and the checksum of all the preceding bytes.
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+
+use crate::snapshot::{read_bytes, write_bytes, HashingReader, HashingWriter};
+use crate::Store;
+
+const BACKUP_MAGIC: &[u8; 8] = b"NEARBKUP";
+const BACKUP_VERSION: u32 = 2;
+
+const CHECKSUMS_FILE: &str = "CHECKSUMS";
+
+const TAG_FILE: u8 = 1;
+const TAG_END: u8 = 2;
+
+/// Number and total size of the files of the database in the backup.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BackupStats {
+    pub num_files: u64,
+    pub size: u64,
+}
+
+#[derive(Debug, PartialEq)]
+struct FileChecksum {
+    name: String,
+    size: u64,
+    checksum: Vec<u8>,
+}
+
+fn file_checksum(dir: &Path, name: String) -> io::Result<FileChecksum> {
+    let mut writer = HashingWriter { inner: io::sink(), hasher: Sha256::new() };
+    let size = io::copy(&mut File::open(dir.join(&name))?, &mut writer)?;
+    Ok(FileChecksum { name, size, checksum: writer.hasher.result().to_vec() })
+}
+
+/// Checksums of the files of the database in the directory, in the name order.
+fn dir_checksums(dir: &Path) -> Result<Vec<FileChecksum>, Box<dyn std::error::Error>> {
+    let mut names = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().into_string().map_err(|_| "Non UTF-8 file name")?;
+        if name == CHECKSUMS_FILE {
+            continue;
+        }
+        if !entry.file_type()?.is_file() {
+            return Err(format!("Unexpected {} in {}", name, dir.display()).into());
+        }
+        names.push(name);
+    }
+    names.sort();
+    Ok(names.into_iter().map(|name| file_checksum(dir, name)).collect::<io::Result<_>>()?)
+}
+
+fn write_checksums(dir: &Path, checksums: &[FileChecksum]) -> io::Result<()> {
+    let file = File::create(dir.join(CHECKSUMS_FILE))?;
+    let mut writer = HashingWriter { inner: file, hasher: Sha256::new() };
+    writer.write_all(BACKUP_MAGIC)?;
+    writer.write_u32::<LittleEndian>(BACKUP_VERSION)?;
+    for file_checksum in checksums {
+        writer.write_u8(TAG_FILE)?;
+        write_bytes(&mut writer, file_checksum.name.as_bytes())?;
+        writer.write_u64::<LittleEndian>(file_checksum.size)?;
+        write_bytes(&mut writer, &file_checksum.checksum)?;
+    }
+    writer.write_u8(TAG_END)?;
+    let checksum = writer.hasher.result();
+    let mut file = writer.inner;
+    file.write_all(&checksum)?;
+    file.sync_all()
+}
+
+fn read_checksums(dir: &Path) -> Result<Vec<FileChecksum>, Box<dyn std::error::Error>> {
+    let file = File::open(dir.join(CHECKSUMS_FILE))?;
+    let mut reader = HashingReader { inner: io::BufReader::new(file), hasher: Sha256::new() };
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != BACKUP_MAGIC {
+        return Err("Not a backup".into());
+    }
+    let version = reader.read_u32::<LittleEndian>()?;
+    if version != BACKUP_VERSION {
+        return Err(format!("Unsupported backup version {}", version).into());
+    }
+    let mut checksums = vec![];
+    loop {
+        match reader.read_u8()? {
+            TAG_FILE => {
+                let name = String::from_utf8(read_bytes(&mut reader)?)?;
+                let size = reader.read_u64::<LittleEndian>()?;
+                let checksum = read_bytes(&mut reader)?;
+                checksums.push(FileChecksum { name, size, checksum });
+            }
+            TAG_END => break,
+            tag => return Err(format!("Unknown backup entry tag {}", tag).into()),
+        }
+    }
+    let computed = reader.hasher.result();
+    let mut checksum = [0u8; 32];
+    reader.inner.read_exact(&mut checksum)?;
+    if computed.as_slice() != &checksum[..] {
+        return Err("Checksum mismatch of the backup checksums".into());
+    }
+    Ok(checksums)
+}
+
+/// Verifies the directory holds exactly the files of the database with the given checksums.
+fn verify_files(dir: &Path, expected: &[FileChecksum]) -> Result<(), Box<dyn std::error::Error>> {
+    let checksums = dir_checksums(dir)?;
+    for name in checksums.iter().map(|file| &file.name) {
+        if !expected.iter().any(|file| &file.name == name) {
+            return Err(format!("Unexpected file {} in {}", name, dir.display()).into());
+        }
+    }
+    for file in expected {
+        match checksums.iter().find(|other| other.name == file.name) {
+            None => {
+                return Err(format!("File {} is missing in {}", file.name, dir.display()).into())
+            }
+            Some(other) if other != file => {
+                return Err(
+                    format!("Checksum mismatch of {} in {}", file.name, dir.display()).into()
+                )
+            }
+            Some(_) => {}
+        }
+    }
+    Ok(())
+}
+
+fn stats(checksums: &[FileChecksum]) -> BackupStats {
+    BackupStats {
+        num_files: checksums.len() as u64,
+        size: checksums.iter().map(|file| file.size).sum(),
+    }
+}
+
+/// Writes the backup of the store to the directory at the path, which must not exist. The
+/// checkpoint is consistent, the store keeps being written while its files are checksummed.
+/// The directory is removed if the backup fails.
+pub fn write_backup(store: &Store, path: &Path) -> Result<BackupStats, Box<dyn std::error::Error>> {
+    store.checkpoint(path)?;
+    let result = dir_checksums(path).and_then(|checksums| {
+        write_checksums(path, &checksums)?;
+        Ok(stats(&checksums))
+    });
+    if result.is_err() {
+        let _ = fs::remove_dir_all(path);
+    }
+    result
+}
+
+/// Verifies the files of the backup against its checksums.
+pub fn verify_backup(path: &Path) -> Result<BackupStats, Box<dyn std::error::Error>> {
+    let checksums = read_checksums(path)?;
+    verify_files(path, &checksums)?;
+    Ok(stats(&checksums))
+}
+
+/// Copies the database from the verified backup to the directory at the path, which must not
+/// exist, then verifies the copied files against the checksums of the backup. The directory
+/// must be discarded if the restore fails.
+pub fn restore_backup(
+    input: &Path,
+    path: &Path,
+) -> Result<BackupStats, Box<dyn std::error::Error>> {
+    let checksums = read_checksums(input)?;
+    verify_files(input, &checksums)?;
+    fs::create_dir(path)?;
+    for file in checksums.iter() {
+        fs::copy(input.join(&file.name), path.join(&file.name))?;
+    }
+    verify_files(path, &checksums)?;
+    Ok(stats(&checksums))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+
+    use crate::{create_store, COL_BLOCK, COL_BLOCK_MISC, COL_PEERS};
+
+    use super::*;
+
+    fn create_backup(dir: &Path) -> (Arc<Store>, PathBuf) {
+        let store = create_store(dir.join("data").to_str().unwrap());
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK_MISC, b"HEAD", &[1, 2, 3]);
+        for i in 0..100u32 {
+            store_update.set(COL_BLOCK, &i.to_le_bytes(), &vec![i as u8; (i % 7) as usize + 1]);
+        }
+        store_update.set(COL_PEERS, b"peer", b"info");
+        store_update.commit().unwrap();
+        let backup_path = dir.join("backup");
+        let stats = write_backup(&store, &backup_path).unwrap();
+        assert!(stats.num_files > 0);
+        assert_eq!(verify_backup(&backup_path).unwrap(), stats);
+        (store, backup_path)
+    }
+
+    #[test]
+    fn test_backup_roundtrip() {
+        let dir = TempDir::new("backup_roundtrip").unwrap();
+        let (store, backup_path) = create_backup(dir.path());
+        // Updates after the checkpoint are not in the backup.
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK_MISC, b"HEAD", &[4, 5, 6]);
+        store_update.commit().unwrap();
+        let restore_path = dir.path().join("restored");
+        let stats = restore_backup(&backup_path, &restore_path).unwrap();
+        assert_eq!(verify_backup(&backup_path).unwrap(), stats);
+        let restored = create_store(restore_path.to_str().unwrap());
+        assert_eq!(restored.get(COL_BLOCK_MISC, b"HEAD").unwrap(), Some(vec![1, 2, 3]));
+        assert_eq!(restored.get(COL_BLOCK, &5u32.to_le_bytes()).unwrap(), Some(vec![5; 6]));
+        assert_eq!(restored.get(COL_PEERS, b"peer").unwrap(), Some(b"info".to_vec()));
+    }
+
+    #[test]
+    fn test_backup_corrupted() {
+        let dir = TempDir::new("backup_corrupted").unwrap();
+        let (_, backup_path) = create_backup(dir.path());
+        let name = read_checksums(&backup_path).unwrap()[0].name.clone();
+        let mut data = fs::read(backup_path.join(&name)).unwrap();
+        let middle = data.len() / 2;
+        data[middle] ^= 0xff;
+        fs::write(backup_path.join(&name), &data).unwrap();
+        assert!(verify_backup(&backup_path).is_err());
+        assert!(restore_backup(&backup_path, &dir.path().join("restored")).is_err());
+        fs::remove_file(backup_path.join(&name)).unwrap();
+        assert!(verify_backup(&backup_path).is_err());
+    }
+
+    #[test]
+    fn test_restore_into_existing_dir() {
+        let dir = TempDir::new("backup_existing").unwrap();
+        let (_, backup_path) = create_backup(dir.path());
+        assert!(restore_backup(&backup_path, &dir.path().join("data")).is_err());
+    }
+}
@@ -0,0 +1,294 @@
+//! RocksDB database of the store. Column `i` of the store is the column family `col{i}`, the
+//! same as in the databases written by `kvdb-rocksdb`, so that existing databases are opened as
+//! they are.
+use std::cmp::Ordering;
+use std::collections::{btree_map, BTreeMap};
+use std::fs::File;
+use std::io::{self, Read};
+use std::iter::{self, Peekable};
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, RwLock};
+
+use kvdb::{DBOp, DBTransaction, DBValue, KeyValueDB};
+use log::error;
+use rocksdb::checkpoint::Checkpoint;
+use rocksdb::{
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DBIterator, Direction, IteratorMode,
+    Options, WriteBatch, DB,
+};
+
+use crate::{CompactionStyle, StoreOptions, NUM_COLS};
+
+const MB: usize = 1024 * 1024;
+
+/// Buffered changes of each column, the default column first, `None` for deleted keys.
+type Changes = Vec<BTreeMap<Vec<u8>, Option<Vec<u8>>>>;
+
+fn other_io_err<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+fn column_name(col: u32) -> String {
+    format!("col{}", col)
+}
+
+/// Index of the column in the buffered changes.
+fn change_index(col: Option<u32>) -> usize {
+    col.map_or(0, |col| col as usize + 1)
+}
+
+/// Size of the first level files and of the blocks, tuned for the kind of the disk.
+fn compaction_profile(style: CompactionStyle, path: &str) -> (u64, usize) {
+    let is_rotational = match style {
+        CompactionStyle::Auto => is_rotational(path).unwrap_or(false),
+        CompactionStyle::Ssd => false,
+        CompactionStyle::Hdd => true,
+    };
+    if is_rotational {
+        (256 * MB as u64, 64 * 1024)
+    } else {
+        (64 * MB as u64, 16 * 1024)
+    }
+}
+
+/// Whether the path is on a rotational disk, if it can be found out from the output of `df` and
+/// the block device flags of Linux.
+fn is_rotational(path: &str) -> Option<bool> {
+    // The directory of the database may not exist yet.
+    let dir = Path::new(path).ancestors().find(|dir| dir.exists())?;
+    let output = Command::new("df").arg(dir).output().ok()?;
+    let output = String::from_utf8(output.stdout).ok()?;
+    let device = output.lines().nth(1)?.split_whitespace().next()?;
+    let device = device.trim_start_matches("/dev/");
+    let disk: String = device.chars().take_while(|c| c.is_ascii_alphabetic()).collect();
+    if disk.is_empty() {
+        return None;
+    }
+    let mut flag = [0u8; 1];
+    File::open(PathBuf::from("/sys/block").join(disk).join("queue/rotational"))
+        .and_then(|mut file| file.read_exact(&mut flag))
+        .ok()?;
+    Some(flag[0] == b'1')
+}
+
+pub struct RocksDB {
+    db: DB,
+    /// Changes written by `write_buffered` and not flushed yet.
+    overlay: RwLock<Changes>,
+    /// Changes being written by `flush`, still visible to the reads until they are on the disk.
+    flushing: RwLock<Changes>,
+    flushing_lock: Mutex<()>,
+}
+
+impl RocksDB {
+    pub fn open(path: &str, options: &StoreOptions) -> io::Result<RocksDB> {
+        let mut db_options = Options::default();
+        db_options.create_if_missing(true);
+        db_options.create_missing_column_families(true);
+        db_options.set_max_open_files(options.max_open_files);
+        let (file_size, block_size) = compaction_profile(options.compaction, path);
+        // Options of the blocks are shared by the columns, so is the block cache.
+        let mut block_options = BlockBasedOptions::default();
+        block_options.set_block_size(block_size);
+        block_options.set_lru_cache(options.block_cache_size_mb * MB);
+        let columns = (0..NUM_COLS).map(|col| {
+            let mut column_options = Options::default();
+            column_options.set_write_buffer_size(options.write_buffer_size_mb * MB);
+            column_options.set_target_file_size_base(file_size);
+            column_options.set_block_based_table_factory(&block_options);
+            ColumnFamilyDescriptor::new(column_name(col), column_options)
+        });
+        let db = DB::open_cf_descriptors(&db_options, path, columns).map_err(other_io_err)?;
+        let changes = vec![BTreeMap::new(); NUM_COLS as usize + 1];
+        Ok(RocksDB {
+            db,
+            overlay: RwLock::new(changes.clone()),
+            flushing: RwLock::new(changes),
+            flushing_lock: Mutex::new(()),
+        })
+    }
+
+    /// Flushes the buffered changes and creates a checkpoint of the database at the path, which
+    /// must not exist. Files of the checkpoint are hard links to the files of the database if
+    /// the path is on the same filesystem, copies otherwise.
+    pub fn checkpoint(&self, path: &Path) -> io::Result<()> {
+        self.flush()?;
+        let checkpoint = Checkpoint::new(&self.db).map_err(other_io_err)?;
+        checkpoint.create_checkpoint(path).map_err(other_io_err)
+    }
+
+    fn cf_handle(&self, col: u32) -> io::Result<ColumnFamily> {
+        self.db
+            .cf_handle(&column_name(col))
+            .ok_or_else(|| other_io_err(format!("Column {} is missing in the database", col)))
+    }
+
+    /// Iterates over the records of the column on the disk, starting from the key.
+    fn db_iter(&self, col: Option<u32>, from: &[u8]) -> io::Result<DBIterator> {
+        let mode = IteratorMode::From(from, Direction::Forward);
+        match col {
+            None => Ok(self.db.iterator(mode)),
+            Some(col) => self.db.iterator_cf(self.cf_handle(col)?, mode).map_err(other_io_err),
+        }
+    }
+
+    /// Iterates over the records of the column starting from the key, buffered changes included.
+    fn iter_from<'a>(
+        &'a self,
+        col: Option<u32>,
+        from: &[u8],
+    ) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        // Changes are taken before the records on the disk, so that the changes flushed in
+        // between are still seen.
+        let changes = {
+            let overlay = self.overlay.read().unwrap();
+            let flushing = self.flushing.read().unwrap();
+            let index = change_index(col);
+            let mut changes = BTreeMap::new();
+            for buffered in vec![&flushing[index], &overlay[index]] {
+                changes.extend(
+                    buffered
+                        .range(from.to_vec()..)
+                        .map(|(key, value)| (key.clone(), value.clone())),
+                );
+            }
+            changes
+        };
+        match self.db_iter(col, from) {
+            Ok(db_iter) => Box::new(BufferedIterator {
+                db_iter: db_iter.peekable(),
+                changes: changes.into_iter().peekable(),
+            }),
+            Err(err) => {
+                error!(target: "store", "Failed to iterate over column {:?}: {}", col, err);
+                Box::new(iter::empty())
+            }
+        }
+    }
+
+    fn write_changes(&self, changes: &Changes) -> io::Result<()> {
+        let mut batch = WriteBatch::default();
+        for (index, column_changes) in changes.iter().enumerate() {
+            let cf = if index == 0 { None } else { Some(self.cf_handle(index as u32 - 1)?) };
+            for (key, value) in column_changes.iter() {
+                match (cf, value) {
+                    (None, Some(value)) => batch.put(key, value),
+                    (None, None) => batch.delete(key),
+                    (Some(cf), Some(value)) => batch.put_cf(cf, key, value),
+                    (Some(cf), None) => batch.delete_cf(cf, key),
+                }
+                .map_err(other_io_err)?;
+            }
+        }
+        self.db.write(batch).map_err(other_io_err)
+    }
+}
+
+impl KeyValueDB for RocksDB {
+    fn get(&self, col: Option<u32>, key: &[u8]) -> io::Result<Option<DBValue>> {
+        {
+            let overlay = self.overlay.read().unwrap();
+            let flushing = self.flushing.read().unwrap();
+            let index = change_index(col);
+            if let Some(value) = overlay[index].get(key).or_else(|| flushing[index].get(key)) {
+                return Ok(value.as_ref().map(|value| DBValue::from_slice(value)));
+            }
+        }
+        let value = match col {
+            None => self.db.get(key),
+            Some(col) => self.db.get_cf(self.cf_handle(col)?, key),
+        };
+        value.map(|value| value.map(|value| DBValue::from_slice(&value))).map_err(other_io_err)
+    }
+
+    fn get_by_prefix(&self, col: Option<u32>, prefix: &[u8]) -> Option<Box<[u8]>> {
+        self.iter_from_prefix(col, prefix).next().map(|(_, value)| value)
+    }
+
+    fn write_buffered(&self, transaction: DBTransaction) {
+        let mut overlay = self.overlay.write().unwrap();
+        for op in transaction.ops {
+            match op {
+                DBOp::Insert { col, key, value } => {
+                    overlay[change_index(col)].insert(key.to_vec(), Some(value.to_vec()))
+                }
+                DBOp::Delete { col, key } => overlay[change_index(col)].insert(key.to_vec(), None),
+            };
+        }
+    }
+
+    fn flush(&self) -> io::Result<()> {
+        let _flushing_lock = self.flushing_lock.lock().unwrap();
+        {
+            let mut overlay = self.overlay.write().unwrap();
+            let mut flushing = self.flushing.write().unwrap();
+            mem::swap(&mut *overlay, &mut *flushing);
+        }
+        let result = self.write_changes(&self.flushing.read().unwrap());
+        let mut overlay = self.overlay.write().unwrap();
+        let mut flushing = self.flushing.write().unwrap();
+        for (index, column_changes) in flushing.iter_mut().enumerate() {
+            let column_changes = mem::replace(column_changes, BTreeMap::new());
+            if result.is_err() {
+                // Changes that failed to be written are kept unless they were overwritten since.
+                for (key, value) in column_changes {
+                    overlay[index].entry(key).or_insert(value);
+                }
+            }
+        }
+        result
+    }
+
+    fn iter<'a>(
+        &'a self,
+        col: Option<u32>,
+    ) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        self.iter_from(col, &[])
+    }
+
+    fn iter_from_prefix<'a>(
+        &'a self,
+        col: Option<u32>,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        Box::new(self.iter_from(col, prefix).take_while(move |(key, _)| key.starts_with(prefix)))
+    }
+
+    fn restore(&self, _new_db: &str) -> io::Result<()> {
+        Err(other_io_err("Database is restored from a backup with `near db restore`"))
+    }
+}
+
+/// Records on the disk merged with the buffered changes, which take precedence.
+struct BufferedIterator<'a> {
+    db_iter: Peekable<DBIterator<'a>>,
+    changes: Peekable<btree_map::IntoIter<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+impl<'a> Iterator for BufferedIterator<'a> {
+    type Item = (Box<[u8]>, Box<[u8]>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let ordering = match (self.db_iter.peek(), self.changes.peek()) {
+                (None, None) => return None,
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (Some((db_key, _)), Some((key, _))) => (**db_key).cmp(&key[..]),
+            };
+            match ordering {
+                Ordering::Less => return self.db_iter.next(),
+                Ordering::Equal => {
+                    self.db_iter.next();
+                }
+                Ordering::Greater => {}
+            }
+            // Deleted keys are skipped.
+            if let Some((key, Some(value))) = self.changes.next() {
+                return Some((key.into_boxed_slice(), value.into_boxed_slice()));
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Flat state: values of the state at the head block stored under their raw trie keys, so that
+//! reads of the head state are a single lookup instead of a trie traversal. The chain moves it
+//! along with the head, applying changes of the new blocks and reverting them on reorgs.
+use std::io;
+use std::sync::Arc;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+
+use near_primitives::hash::CryptoHash;
+use near_primitives::types::MerkleHash;
+
+use crate::trie::Trie;
+use crate::{Store, COL_BLOCK_MISC, COL_FLAT_STATE, NUM_COLS};
+
+/// Key of the flat state head in the block misc column.
+pub const FLAT_STATE_HEAD_KEY: &[u8; 15] = b"FLAT_STATE_HEAD";
+
+/// Number of values written at once while rebuilding the flat state.
+const REBUILD_BATCH_SIZE: usize = 10_000;
+
+/// Values of the state keys set by a block, `None` for the removed keys.
+pub type StateChanges = Vec<(Vec<u8>, Option<Vec<u8>>)>;
+
+/// Changes of the state made by a block. Once the flat state moves past the block, it also keeps
+/// the values the changes replaced, to revert the block on a reorg.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct FlatStateDelta {
+    pub changes: StateChanges,
+    pub undo: Option<StateChanges>,
+}
+
+impl FlatStateDelta {
+    pub fn new(changes: StateChanges) -> Self {
+        FlatStateDelta { changes, undo: None }
+    }
+}
+
+/// Block the flat state is at and its state root.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub struct FlatStateHead {
+    pub block_hash: CryptoHash,
+    pub state_root: MerkleHash,
+}
+
+/// Returns the block the flat state is at, `None` if there is no flat state.
+pub fn get_flat_state_head(store: &Store) -> io::Result<Option<FlatStateHead>> {
+    store.get_ser(COL_BLOCK_MISC, FLAT_STATE_HEAD_KEY)
+}
+
+/// Removes the flat state head, so that the flat state is not used until it's rebuilt.
+pub fn drop_flat_state(store: &Store) -> io::Result<()> {
+    let mut store_update = store.store_update();
+    store_update.delete(COL_BLOCK_MISC, FLAT_STATE_HEAD_KEY);
+    store_update.commit()
+}
+
+/// Reads the value from the flat state if it's at the state root, `None` otherwise.
+pub fn get_flat_state_value(
+    store: &Store,
+    state_root: &MerkleHash,
+    key: &[u8],
+) -> io::Result<Option<Option<Vec<u8>>>> {
+    let is_at_root = |store: &Store| -> io::Result<bool> {
+        Ok(get_flat_state_head(store)?.map_or(false, |head| &head.state_root == state_root))
+    };
+    if !is_at_root(store)? {
+        return Ok(None);
+    }
+    let value = store.get(COL_FLAT_STATE, key)?;
+    // The head may move between the reads, the value is used only if it didn't.
+    if !is_at_root(store)? {
+        return Ok(None);
+    }
+    Ok(Some(value))
+}
+
+/// Replaces the flat state with the values of the state at the head root. The head is saved
+/// last, so that the flat state is not used if the rebuild is interrupted.
+pub fn rebuild_flat_state(
+    store: Arc<Store>,
+    head: FlatStateHead,
+) -> Result<u64, Box<dyn std::error::Error>> {
+    let mut store_update = store.store_update();
+    store_update.delete(COL_BLOCK_MISC, FLAT_STATE_HEAD_KEY);
+    for (key, _) in store.iter(COL_FLAT_STATE) {
+        store_update.delete(COL_FLAT_STATE, &key);
+    }
+    store_update.commit()?;
+
+    let trie = Trie::new(store.clone());
+    let mut store_update = store.store_update();
+    let (mut num_values, mut batch_size) = (0, 0);
+    for item in trie.iter(&head.state_root)? {
+        let (key, value) = item?;
+        store_update.set(COL_FLAT_STATE, &key, &value);
+        num_values += 1;
+        batch_size += 1;
+        if batch_size == REBUILD_BATCH_SIZE {
+            store_update.commit()?;
+            store_update = store.store_update();
+            batch_size = 0;
+        }
+    }
+    store_update.set_ser(COL_BLOCK_MISC, FLAT_STATE_HEAD_KEY, &head)?;
+    store_update.commit()?;
+    Ok(num_values)
+}
+
+/// Checks the flat state against the state root of its head: the trie built from its values
+/// must have the same root. Returns number of the values, `None` if there is no flat state.
+pub fn verify_flat_state(store: &Store) -> Result<Option<u64>, String> {
+    let head = match get_flat_state_head(store).map_err(|err| err.to_string())? {
+        Some(head) => head,
+        None => return Ok(None),
+    };
+    let mut num_values = 0;
+    let values = store.iter(COL_FLAT_STATE).map(|(key, value)| {
+        num_values += 1;
+        (key.to_vec(), Some(value.to_vec()))
+    });
+    let scratch = Store::new(Arc::new(kvdb_memorydb::create(NUM_COLS)));
+    let trie = Trie::new(Arc::new(scratch));
+    let changes = trie.update(&Trie::empty_root(), values).map_err(|err| err.to_string())?;
+    if changes.new_root != head.state_root {
+        return Err(format!(
+            "Flat state at block {} has root {}, expected {}",
+            head.block_hash, changes.new_root, head.state_root
+        ));
+    }
+    Ok(Some(num_values))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use near_primitives::hash::CryptoHash;
+
+    use crate::test_utils::create_test_store;
+    use crate::trie::Trie;
+    use crate::COL_FLAT_STATE;
+
+    use super::{
+        get_flat_state_head, get_flat_state_value, rebuild_flat_state, verify_flat_state,
+        FlatStateHead,
+    };
+
+    #[test]
+    fn test_flat_state_rebuild_and_verify() {
+        let store = create_test_store();
+        let trie = Arc::new(Trie::new(store.clone()));
+        let changes = vec![
+            (b"alice".to_vec(), Some(b"1".to_vec())),
+            (b"bob".to_vec(), Some(b"2".to_vec())),
+            (b"carol".to_vec(), Some(b"3".to_vec())),
+        ];
+        let (store_update, root) =
+            trie.update(&Trie::empty_root(), changes.into_iter()).unwrap().into(trie).unwrap();
+        store_update.commit().unwrap();
+        assert_eq!(verify_flat_state(&store), Ok(None));
+
+        let head = FlatStateHead { block_hash: CryptoHash::default(), state_root: root };
+        assert_eq!(rebuild_flat_state(store.clone(), head).unwrap(), 3);
+        assert_eq!(get_flat_state_head(&store).unwrap(), Some(head));
+        assert_eq!(get_flat_state_value(&store, &root, b"bob").unwrap(), Some(Some(b"2".to_vec())));
+        assert_eq!(get_flat_state_value(&store, &root, b"dave").unwrap(), Some(None));
+        assert_eq!(get_flat_state_value(&store, &Trie::empty_root(), b"bob").unwrap(), None);
+        assert_eq!(verify_flat_state(&store), Ok(Some(3)));
+
+        // A value that doesn't match the state root is detected.
+        let mut store_update = store.store_update();
+        store_update.set(COL_FLAT_STATE, b"bob", b"4");
+        store_update.commit().unwrap();
+        assert!(verify_flat_state(&store).is_err());
+    }
+}
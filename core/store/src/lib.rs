@@ -1,11 +1,14 @@
-use std::sync::Arc;
-use std::{fmt, io};
+use std::path::Path;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use std::{fmt, io, thread};
 
 use borsh::{BorshDeserialize, BorshSerialize};
 use cached::{Cached, SizedCache};
 pub use kvdb::DBValue;
 use kvdb::{DBOp, DBTransaction, KeyValueDB};
-use kvdb_rocksdb::{Database, DatabaseConfig};
+use log::error;
+use serde_derive::{Deserialize, Serialize};
 
 use near_crypto::PublicKey;
 use near_primitives::account::{AccessKey, Account};
@@ -17,6 +20,9 @@ use near_primitives::utils::{
     key_for_received_data, prefix_for_access_key, prefix_for_data,
 };
 
+use crate::db::RocksDB;
+use crate::metrics::{export_prometheus, StoreMetrics};
+use crate::migrations::ReceiptV0;
 pub use crate::trie::{
     update::TrieUpdate, update::TrieUpdateIterator, PartialStorage, Trie, TrieChanges,
     TrieIterator, WrappedTrieChanges,
@@ -24,6 +30,12 @@ pub use crate::trie::{
 use near_primitives::hash::CryptoHash;
 use near_primitives::receipt::{Receipt, ReceivedData};
 
+pub mod backup;
+mod db;
+pub mod flat_state;
+pub mod metrics;
+pub mod migrations;
+pub mod snapshot;
 pub mod test_utils;
 mod trie;
 
@@ -40,42 +52,170 @@ pub const COL_PROPOSALS: Option<u32> = Some(9);
 pub const COL_VALIDATORS: Option<u32> = Some(10);
 pub const COL_LAST_EPOCH_PROPOSALS: Option<u32> = Some(11);
 pub const COL_VALIDATOR_PROPOSALS: Option<u32> = Some(12);
-const NUM_COLS: u32 = 13;
+pub const COL_BLOCK_MERKLE_TREE: Option<u32> = Some(13);
+pub const COL_BLOCK_ORDINAL: Option<u32> = Some(14);
+pub const COL_RECEIPT_ORIGIN: Option<u32> = Some(15);
+pub const COL_TRIE_CHANGES: Option<u32> = Some(16);
+pub const COL_ORPHANS: Option<u32> = Some(17);
+pub const COL_BLOCK_APPROVALS: Option<u32> = Some(18);
+pub const COL_BAD_BLOCKS: Option<u32> = Some(19);
+pub const COL_STATE_PARTS: Option<u32> = Some(20);
+pub const COL_PEER_SCORES: Option<u32> = Some(21);
+pub const COL_FLAT_STATE: Option<u32> = Some(22);
+pub const COL_STATE_CHANGES: Option<u32> = Some(23);
+pub const NUM_COLS: u32 = 24;
+
+/// Human readable names of the columns, indexed by the column number.
+pub const COL_NAMES: [&str; NUM_COLS as usize] = [
+    "block misc",
+    "block",
+    "block header",
+    "block index",
+    "state",
+    "state ref",
+    "transaction result",
+    "receipts",
+    "peers",
+    "proposals",
+    "validators",
+    "last epoch proposals",
+    "validator proposals",
+    "block merkle tree",
+    "block ordinal",
+    "receipt origin",
+    "trie changes",
+    "orphans",
+    "block approvals",
+    "bad blocks",
+    "state parts",
+    "peer scores",
+    "flat state",
+    "state changes",
+];
+
+/// Record of the store that can't be read back, either RocksDB found a block checksum mismatch
+/// while reading it or the value doesn't deserialize.
+#[derive(Debug)]
+pub struct StorageCorruption(pub String);
+
+impl fmt::Display for StorageCorruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Storage corruption: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageCorruption {}
+
+/// Whether the error is caused by a corrupted record of the store.
+pub fn is_storage_corruption(err: &io::Error) -> bool {
+    err.get_ref().map_or(false, |inner| inner.is::<StorageCorruption>())
+}
+
+fn storage_corruption(column: Option<u32>, key: &[u8], reason: &str) -> io::Error {
+    let column_name = column.map_or("default", |col| COL_NAMES[col as usize]);
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        StorageCorruption(format!("{} in column {} at key {}", reason, column_name, to_base(key))),
+    )
+}
+
+/// RocksDB verifies block checksums on every read and reports mismatches as corruption.
+fn read_error(column: Option<u32>, key: &[u8], err: io::Error) -> io::Error {
+    let message = err.to_string();
+    if message.starts_with("Corruption") {
+        storage_corruption(column, key, &message)
+    } else {
+        err
+    }
+}
 
 pub struct Store {
     storage: Arc<dyn KeyValueDB>,
+    /// RocksDB database of the storage, none for the database in memory.
+    rocksdb: Option<Arc<RocksDB>>,
+    /// Whether committed updates are buffered and written to the disk by the flusher thread.
+    async_commit: bool,
+    /// Store the garbage collection moves old blocks to instead of discarding them.
+    cold: Option<Arc<Store>>,
+    metrics: Arc<StoreMetrics>,
 }
 
 impl Store {
     pub fn new(storage: Arc<dyn KeyValueDB>) -> Store {
-        Store { storage }
+        Store {
+            storage,
+            rocksdb: None,
+            async_commit: false,
+            cold: None,
+            metrics: Arc::new(StoreMetrics::default()),
+        }
+    }
+
+    /// Whether committed updates are written to the disk in the background.
+    pub fn async_commit(&self) -> bool {
+        self.async_commit
+    }
+
+    /// Cold store of the archived history, if the node keeps one.
+    pub fn cold_store(&self) -> Option<&Store> {
+        self.cold.as_ref().map(|cold| &**cold)
+    }
+
+    /// Counters and latencies of the operations on the store.
+    pub fn metrics(&self) -> &StoreMetrics {
+        &self.metrics
+    }
+
+    /// Metrics of the store and of its cold store in the Prometheus text format.
+    pub fn prometheus_metrics(&self) -> String {
+        let mut stores = vec![("hot", self.metrics())];
+        if let Some(cold) = self.cold_store() {
+            stores.push(("cold", cold.metrics()));
+        }
+        export_prometheus(&stores)
+    }
+
+    /// Reads the value of the key from the database, recording the read in the metrics.
+    fn read(&self, column: Option<u32>, key: &[u8]) -> Result<Option<DBValue>, io::Error> {
+        let started = Instant::now();
+        let result = self.storage.get(column, key);
+        let size = result.as_ref().ok().and_then(|value| value.as_ref().map(|value| value.len()));
+        self.metrics.record_read(column, key, size, started.elapsed());
+        result
     }
 
     pub fn get(&self, column: Option<u32>, key: &[u8]) -> Result<Option<Vec<u8>>, io::Error> {
-        self.storage.get(column, key).map(|a| a.map(|b| b.to_vec()))
+        self.read(column, key)
+            .map(|a| a.map(|b| b.to_vec()))
+            .map_err(|err| read_error(column, key, err))
     }
 
+    /// Reads and deserializes the value, values that don't deserialize are reported as
+    /// `StorageCorruption`.
     pub fn get_ser<T: BorshDeserialize>(
         &self,
         column: Option<u32>,
         key: &[u8],
     ) -> Result<Option<T>, io::Error> {
-        match self.storage.get(column, key) {
+        match self.read(column, key) {
             Ok(Some(bytes)) => match T::try_from_slice(bytes.as_ref()) {
                 Ok(result) => Ok(Some(result)),
-                Err(e) => Err(e),
+                Err(err) => Err(storage_corruption(column, key, &err.to_string())),
             },
             Ok(None) => Ok(None),
-            Err(e) => Err(e),
+            Err(err) => Err(read_error(column, key, err)),
         }
     }
 
     pub fn exists(&self, column: Option<u32>, key: &[u8]) -> Result<bool, io::Error> {
-        self.storage.get(column, key).map(|value| value.is_some())
+        self.read(column, key).map(|value| value.is_some())
     }
 
     pub fn store_update(&self) -> StoreUpdate {
-        StoreUpdate::new(self.storage.clone())
+        let mut store_update = StoreUpdate::new(self.storage.clone());
+        store_update.async_commit = self.async_commit;
+        store_update.metrics = Some(self.metrics.clone());
+        store_update
     }
 
     pub fn iter<'a>(
@@ -84,6 +224,47 @@ impl Store {
     ) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
         self.storage.iter(column)
     }
+
+    /// Iterates over the records of the column with keys starting with `prefix`.
+    pub fn iter_prefix<'a>(
+        &'a self,
+        column: Option<u32>,
+        prefix: &'a [u8],
+    ) -> Box<dyn Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+        self.storage.iter_from_prefix(column, prefix)
+    }
+
+    /// Flushes buffered writes to the underlying database.
+    pub fn flush(&self) -> Result<(), io::Error> {
+        let started = Instant::now();
+        let result = self.storage.flush();
+        self.metrics.record_flush(started.elapsed());
+        result
+    }
+
+    /// Creates a consistent copy of the database at the path, which must not exist, with buffered
+    /// updates flushed first. Files of the copy are hard links to the files of the database when
+    /// the path is on the same filesystem, so it's created at once.
+    pub fn checkpoint(&self, path: &Path) -> Result<(), io::Error> {
+        match &self.rocksdb {
+            Some(rocksdb) => rocksdb.checkpoint(path),
+            None => Err(io::Error::new(
+                io::ErrorKind::Other,
+                "Checkpoints are only supported by the RocksDB backend",
+            )),
+        }
+    }
+}
+
+/// Buffered updates are written to the disk when the store is closed.
+impl Drop for Store {
+    fn drop(&mut self) {
+        if self.async_commit {
+            if let Err(err) = self.flush() {
+                error!(target: "store", "Failed to flush the store: {}", err);
+            }
+        }
+    }
 }
 
 /// Keeps track of current changes to the database and can commit all of them to the database.
@@ -92,17 +273,21 @@ pub struct StoreUpdate {
     transaction: DBTransaction,
     /// Optionally has reference to the trie to clear cache on the commit.
     trie: Option<Arc<Trie>>,
+    /// Commit buffers the update instead of writing it to the disk.
+    async_commit: bool,
+    /// Metrics of the store the commit is recorded in.
+    metrics: Option<Arc<StoreMetrics>>,
 }
 
 impl StoreUpdate {
     pub fn new(storage: Arc<dyn KeyValueDB>) -> Self {
         let transaction = storage.transaction();
-        StoreUpdate { storage, transaction, trie: None }
+        StoreUpdate { storage, transaction, trie: None, async_commit: false, metrics: None }
     }
 
     pub fn new_with_trie(storage: Arc<dyn KeyValueDB>, trie: Arc<Trie>) -> Self {
         let transaction = storage.transaction();
-        StoreUpdate { storage, transaction, trie: Some(trie) }
+        StoreUpdate { storage, transaction, trie: Some(trie), async_commit: false, metrics: None }
     }
 
     pub fn set(&mut self, column: Option<u32>, key: &[u8], value: &[u8]) {
@@ -144,11 +329,53 @@ impl StoreUpdate {
         }
     }
 
+    /// Counts the written and deleted values of the update in the metrics, returns number of
+    /// the operations.
+    fn record_ops(&self) -> usize {
+        if let Some(metrics) = &self.metrics {
+            for op in self.transaction.ops.iter() {
+                match op {
+                    DBOp::Insert { col, value, .. } => metrics.record_write(*col, value.len()),
+                    DBOp::Delete { col, .. } => metrics.record_delete(*col),
+                }
+            }
+        }
+        self.transaction.ops.len()
+    }
+
+    /// Writes all the changes at once. If the store commits asynchronously, the changes are
+    /// buffered like in `commit_buffered` and written by the flusher thread.
     pub fn commit(self) -> Result<(), io::Error> {
+        if self.async_commit {
+            return self.commit_buffered();
+        }
+        let started = Instant::now();
+        let num_ops = self.record_ops();
+        let metrics = self.metrics;
         if let Some(trie) = self.trie {
             trie.update_cache(&self.transaction)?;
         }
-        self.storage.write(self.transaction)
+        let result = self.storage.write(self.transaction);
+        if let Some(metrics) = metrics {
+            metrics.record_commit(num_ops, started.elapsed());
+        }
+        result
+    }
+
+    /// Same as `commit`, but the changes are kept in memory, visible to the reads, until
+    /// `Store::flush` writes them to the disk together with the other buffered changes.
+    pub fn commit_buffered(self) -> Result<(), io::Error> {
+        let started = Instant::now();
+        let num_ops = self.record_ops();
+        let metrics = self.metrics;
+        if let Some(trie) = self.trie {
+            trie.update_cache(&self.transaction)?;
+        }
+        self.storage.write_buffered(self.transaction);
+        if let Some(metrics) = metrics {
+            metrics.record_commit(num_ops, started.elapsed());
+        }
+        Ok(())
     }
 }
 
@@ -184,10 +411,150 @@ pub fn read_with_cache<'a, T: BorshDeserialize + 'a>(
     Ok(None)
 }
 
+/// Compaction profile of the database, tuned for the kind of the disk.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompactionStyle {
+    /// Detected from the disk the database is on.
+    Auto,
+    Ssd,
+    Hdd,
+}
+
+/// How committed updates are written to the disk.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommitPolicy {
+    /// Each update is written to the disk when it's committed.
+    Sync,
+    /// Updates are kept in memory, visible to the reads, and written to the disk by a background
+    /// thread every flush period and when the store is closed. Each flush writes all the buffered
+    /// updates in one batch through the write-ahead log, so after a crash the database is at the
+    /// state of the last flush and no block is partially written. Not allowed on nodes with
+    /// validator keys, which must not sign anything that may be lost in a crash.
+    Async,
+}
+
+/// Database backing the store.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StoreBackend {
+    /// RocksDB database at the store path.
+    RocksDb,
+    /// Database in memory, nothing is written to the disk and the data is lost when the node
+    /// stops. For tests and throwaway nodes.
+    Memory,
+}
+
+/// Database backend of the store and RocksDB tuning options.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct StoreOptions {
+    pub backend: StoreBackend,
+    /// Size of the cache of uncompressed blocks, in MiB.
+    pub block_cache_size_mb: usize,
+    /// Size of the write buffer of each column, in MiB.
+    pub write_buffer_size_mb: usize,
+    /// Maximum number of files the database keeps open, -1 for unlimited.
+    pub max_open_files: i32,
+    pub compaction: CompactionStyle,
+    pub commit: CommitPolicy,
+    /// Period of writing buffered updates to the disk with the async commit policy.
+    pub flush_period_ms: u64,
+    /// Reads, commits and flushes that take longer are logged as slow, 0 to not log them.
+    pub slow_op_threshold_ms: u64,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        StoreOptions {
+            backend: StoreBackend::RocksDb,
+            block_cache_size_mb: 40,
+            write_buffer_size_mb: 1,
+            max_open_files: 512,
+            compaction: CompactionStyle::Ssd,
+            commit: CommitPolicy::Sync,
+            flush_period_ms: 1000,
+            slow_op_threshold_ms: 50,
+        }
+    }
+}
+
+impl StoreOptions {
+    fn slow_op_threshold(&self) -> Option<Duration> {
+        if self.slow_op_threshold_ms == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(self.slow_op_threshold_ms))
+        }
+    }
+}
+
 pub fn create_store(path: &str) -> Arc<Store> {
-    let db_config = DatabaseConfig::with_columns(Some(NUM_COLS));
-    let db = Arc::new(Database::open(&db_config, path).expect("Failed to open the database"));
-    Arc::new(Store::new(db))
+    create_store_with_options(path, &StoreOptions::default())
+}
+
+/// Opens the store with given options, the path is ignored by the memory backend.
+pub fn create_store_with_options(path: &str, options: &StoreOptions) -> Arc<Store> {
+    open_store(path, options, None)
+}
+
+/// Opens the store together with the cold store at `cold_path`, which gets the history the
+/// garbage collection removes from the store. Writes to the cold store are always synchronous,
+/// so that the history is on the disk before it's deleted from the store, and its compaction
+/// profile is detected from the disk, which is usually of a different kind.
+pub fn create_store_with_cold_store(
+    path: &str,
+    cold_path: &str,
+    options: &StoreOptions,
+) -> Arc<Store> {
+    let cold_options = StoreOptions {
+        compaction: CompactionStyle::Auto,
+        commit: CommitPolicy::Sync,
+        ..options.clone()
+    };
+    let cold = create_store_with_options(cold_path, &cold_options);
+    open_store(path, options, Some(cold))
+}
+
+fn open_store(path: &str, options: &StoreOptions, cold: Option<Arc<Store>>) -> Arc<Store> {
+    let mut store = match options.backend {
+        StoreBackend::RocksDb => {
+            let rocksdb =
+                Arc::new(RocksDB::open(path, options).expect("Failed to open the database"));
+            let mut store = Store::new(rocksdb.clone());
+            store.rocksdb = Some(rocksdb);
+            store
+        }
+        StoreBackend::Memory => Store::new(Arc::new(kvdb_memorydb::create(NUM_COLS))),
+    };
+    migrations::migrate(&store).expect("Failed to migrate the database");
+    store.async_commit = options.commit == CommitPolicy::Async;
+    store.cold = cold;
+    store.metrics = Arc::new(StoreMetrics::new(options.slow_op_threshold()));
+    let store = Arc::new(store);
+    if store.async_commit {
+        spawn_flusher(Arc::downgrade(&store), Duration::from_millis(options.flush_period_ms));
+    }
+    store
+}
+
+/// Periodically writes buffered updates of the store to the disk, until the store is dropped.
+fn spawn_flusher(store: Weak<Store>, period: Duration) {
+    thread::Builder::new()
+        .name("store flusher".to_string())
+        .spawn(move || loop {
+            thread::sleep(period);
+            match store.upgrade() {
+                Some(store) => {
+                    if let Err(err) = store.flush() {
+                        error!(target: "store", "Failed to flush the store: {}", err);
+                    }
+                }
+                None => break,
+            }
+        })
+        .expect("Failed to start the store flusher");
 }
 
 /// Reads an object from Trie.
@@ -244,7 +611,17 @@ pub fn get_receipt(
     account_id: &AccountId,
     receipt_id: &CryptoHash,
 ) -> Option<Receipt> {
-    get(state_update, &key_for_postponed_receipt(account_id, receipt_id))
+    state_update
+        .get(&key_for_postponed_receipt(account_id, receipt_id))
+        .and_then(|data| decode_postponed_receipt(&data))
+}
+
+/// Decodes a receipt postponed in the state. The state is not migrated, so the receipts that were
+/// postponed before the receipts had depth are decoded in the old layout, with zero depth.
+pub fn decode_postponed_receipt(data: &[u8]) -> Option<Receipt> {
+    Receipt::try_from_slice(data)
+        .ok()
+        .or_else(|| ReceiptV0::try_from_slice(data).ok().map(Receipt::from))
 }
 
 pub fn set_access_key(
@@ -289,3 +666,113 @@ pub fn remove_account(
     state_update.remove_starts_with(&prefix_for_data(account_id))?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use tempdir::TempDir;
+
+    use crate::test_utils::create_test_store;
+
+    use super::*;
+
+    #[test]
+    fn test_get_ser_corruption() {
+        let store = create_test_store();
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK_MISC, b"HEAD", &[1, 2, 3]);
+        store_update.commit().unwrap();
+        let err = store.get_ser::<u64>(COL_BLOCK_MISC, b"HEAD").unwrap_err();
+        assert!(is_storage_corruption(&err));
+        assert!(err.to_string().contains("block misc"));
+        assert!(!is_storage_corruption(&io::Error::new(io::ErrorKind::Other, "other")));
+    }
+
+    #[test]
+    fn test_memory_backend() {
+        let dir = TempDir::new("memory_backend").unwrap();
+        let path = dir.path().join("data");
+        let options = StoreOptions { backend: StoreBackend::Memory, ..Default::default() };
+        let store = create_store_with_options(path.to_str().unwrap(), &options);
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK, b"block", b"value");
+        store_update.commit().unwrap();
+        assert_eq!(store.get(COL_BLOCK, b"block").unwrap(), Some(b"value".to_vec()));
+        assert!(!path.exists());
+        assert!(store.checkpoint(&dir.path().join("checkpoint")).is_err());
+    }
+
+    #[test]
+    fn test_async_commit() {
+        let dir = TempDir::new("async_commit").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let options =
+            StoreOptions { commit: CommitPolicy::Async, flush_period_ms: 10, ..Default::default() };
+        {
+            let store = create_store_with_options(path, &options);
+            let mut store_update = store.store_update();
+            store_update.set(COL_BLOCK, b"block", b"value");
+            store_update.commit().unwrap();
+            assert_eq!(store.get(COL_BLOCK, b"block").unwrap(), Some(b"value".to_vec()));
+            // Give the flusher time to write the update while the store is open.
+            thread::sleep(Duration::from_millis(200));
+        }
+        // Let the flusher notice the store is dropped.
+        thread::sleep(Duration::from_millis(50));
+        let store = create_store(path);
+        assert_eq!(store.get(COL_BLOCK, b"block").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_async_commit_flushed_on_close() {
+        let dir = TempDir::new("async_commit_close").unwrap();
+        let path = dir.path().to_str().unwrap();
+        let options = StoreOptions {
+            commit: CommitPolicy::Async,
+            flush_period_ms: 60_000,
+            ..Default::default()
+        };
+        {
+            let store = create_store_with_options(path, &options);
+            let mut store_update = store.store_update();
+            store_update.set(COL_BLOCK, b"block", b"value");
+            store_update.commit().unwrap();
+        }
+        let store = create_store(path);
+        assert_eq!(store.get(COL_BLOCK, b"block").unwrap(), Some(b"value".to_vec()));
+    }
+
+    #[test]
+    fn test_iter_buffered_updates() {
+        let dir = TempDir::new("iter_buffered").unwrap();
+        let options = StoreOptions {
+            commit: CommitPolicy::Async,
+            flush_period_ms: 60_000,
+            ..Default::default()
+        };
+        let store = create_store_with_options(dir.path().to_str().unwrap(), &options);
+        let mut store_update = store.store_update();
+        for key in vec![&b"a1"[..], b"a2", b"a4", b"b1"] {
+            store_update.set(COL_BLOCK, key, key);
+        }
+        store_update.commit().unwrap();
+        store.flush().unwrap();
+        let mut store_update = store.store_update();
+        store_update.delete(COL_BLOCK, b"a2");
+        store_update.set(COL_BLOCK, b"a3", b"new");
+        store_update.set(COL_BLOCK, b"a4", b"new");
+        store_update.commit().unwrap();
+        let records: Vec<_> = store
+            .iter_prefix(COL_BLOCK, b"a")
+            .map(|(key, value)| (key.to_vec(), value.to_vec()))
+            .collect();
+        let expected = vec![
+            (b"a1".to_vec(), b"a1".to_vec()),
+            (b"a3".to_vec(), b"new".to_vec()),
+            (b"a4".to_vec(), b"new".to_vec()),
+        ];
+        assert_eq!(records, expected);
+        assert_eq!(store.get(COL_BLOCK, b"a2").unwrap(), None);
+        store.flush().unwrap();
+        assert_eq!(store.iter(COL_BLOCK).count(), 4);
+    }
+}
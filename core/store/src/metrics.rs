@@ -0,0 +1,273 @@
+//! Counters and latency histograms of the store operations, exported in the Prometheus text
+//! format, and logging of the operations slower than the configured threshold.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use log::warn;
+
+use near_primitives::serialize::to_base;
+
+use crate::{COL_NAMES, NUM_COLS};
+
+/// Upper bounds of the latency histogram buckets, in microseconds.
+const LATENCY_BUCKETS_US: [u64; 10] =
+    [100, 250, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000];
+/// Number of the histogram buckets, including the bucket without the upper bound.
+const NUM_BUCKETS: usize = 11;
+
+/// Latency histogram with the counts of the observations in each bucket, the last bucket is for
+/// the observations above the largest bound.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [AtomicU64; NUM_BUCKETS],
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    pub fn observe(&self, latency: Duration) {
+        let latency_us = latency.as_micros() as u64;
+        let bucket = LATENCY_BUCKETS_US
+            .iter()
+            .position(|bound| latency_us <= *bound)
+            .unwrap_or(LATENCY_BUCKETS_US.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(latency_us, Ordering::Relaxed);
+    }
+
+    /// Number of the observations.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Writes the cumulative buckets, the sum and the count of the histogram.
+    fn export(&self, name: &str, labels: &str, out: &mut String) {
+        let mut cumulative = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS_US
+                .get(i)
+                .map_or("+Inf".to_string(), |bound| (*bound as f64 / 1e6).to_string());
+            let _ = writeln!(out, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, bound, cumulative);
+        }
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(out, "{}_count{{{}}} {}", name, labels, cumulative);
+    }
+}
+
+/// Operations on one column of the store.
+#[derive(Default)]
+pub struct ColumnMetrics {
+    pub reads: AtomicU64,
+    pub read_bytes: AtomicU64,
+    pub writes: AtomicU64,
+    pub written_bytes: AtomicU64,
+    pub deletes: AtomicU64,
+    pub slow_reads: AtomicU64,
+    pub read_latency: Histogram,
+}
+
+/// Metrics of a store. Reads are counted for each column, writes are counted for each column
+/// when the update is committed and the latency of the whole commit is measured.
+pub struct StoreMetrics {
+    columns: Vec<ColumnMetrics>,
+    pub commit_latency: Histogram,
+    pub flush_latency: Histogram,
+    pub slow_writes: AtomicU64,
+    /// Operations that take longer are logged, `None` to not log them.
+    slow_op_threshold: Option<Duration>,
+}
+
+impl Default for StoreMetrics {
+    fn default() -> Self {
+        StoreMetrics::new(None)
+    }
+}
+
+impl StoreMetrics {
+    pub fn new(slow_op_threshold: Option<Duration>) -> Self {
+        StoreMetrics {
+            columns: (0..NUM_COLS).map(|_| ColumnMetrics::default()).collect(),
+            commit_latency: Histogram::default(),
+            flush_latency: Histogram::default(),
+            slow_writes: AtomicU64::new(0),
+            slow_op_threshold,
+        }
+    }
+
+    /// Metrics of the column, `None` for the default column, which the store doesn't use.
+    pub fn column(&self, column: Option<u32>) -> Option<&ColumnMetrics> {
+        column.and_then(|col| self.columns.get(col as usize))
+    }
+
+    fn is_slow(&self, latency: Duration) -> bool {
+        self.slow_op_threshold.map_or(false, |threshold| latency > threshold)
+    }
+
+    /// Records a read of the key, `size` is the size of the value if it's found.
+    pub(crate) fn record_read(
+        &self,
+        column: Option<u32>,
+        key: &[u8],
+        size: Option<usize>,
+        latency: Duration,
+    ) {
+        let (col, metrics) = match column {
+            Some(col) => (col, &self.columns[col as usize]),
+            None => return,
+        };
+        metrics.reads.fetch_add(1, Ordering::Relaxed);
+        metrics.read_bytes.fetch_add(size.unwrap_or(0) as u64, Ordering::Relaxed);
+        metrics.read_latency.observe(latency);
+        if self.is_slow(latency) {
+            metrics.slow_reads.fetch_add(1, Ordering::Relaxed);
+            warn!(target: "store", "Slow read of {} in column {}: {:?}", to_base(key), COL_NAMES[col as usize], latency);
+        }
+    }
+
+    pub(crate) fn record_write(&self, column: Option<u32>, size: usize) {
+        if let Some(metrics) = self.column(column) {
+            metrics.writes.fetch_add(1, Ordering::Relaxed);
+            metrics.written_bytes.fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn record_delete(&self, column: Option<u32>) {
+        if let Some(metrics) = self.column(column) {
+            metrics.deletes.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Records a commit of the update with given number of operations.
+    pub(crate) fn record_commit(&self, num_ops: usize, latency: Duration) {
+        self.commit_latency.observe(latency);
+        if self.is_slow(latency) {
+            self.slow_writes.fetch_add(1, Ordering::Relaxed);
+            warn!(target: "store", "Slow commit of {} operations: {:?}", num_ops, latency);
+        }
+    }
+
+    /// Records a write of the buffered updates to the disk.
+    pub(crate) fn record_flush(&self, latency: Duration) {
+        self.flush_latency.observe(latency);
+        if self.is_slow(latency) {
+            self.slow_writes.fetch_add(1, Ordering::Relaxed);
+            warn!(target: "store", "Slow flush of the buffered updates: {:?}", latency);
+        }
+    }
+}
+
+/// Writes the metrics of the stores in the Prometheus text format, each store is labeled with
+/// its name.
+pub fn export_prometheus(stores: &[(&str, &StoreMetrics)]) -> String {
+    let mut out = String::new();
+    let column_counters: [(&str, &str, fn(&ColumnMetrics) -> &AtomicU64); 6] = [
+        ("near_store_reads_total", "Number of reads", |m| &m.reads),
+        ("near_store_read_bytes_total", "Size of the read values", |m| &m.read_bytes),
+        ("near_store_writes_total", "Number of written values", |m| &m.writes),
+        ("near_store_written_bytes_total", "Size of the written values", |m| &m.written_bytes),
+        ("near_store_deletes_total", "Number of deleted keys", |m| &m.deletes),
+        ("near_store_slow_reads_total", "Number of reads above the threshold", |m| &m.slow_reads),
+    ];
+    for (name, help, counter) in column_counters.iter() {
+        let _ = writeln!(out, "# HELP {} {}.", name, help);
+        let _ = writeln!(out, "# TYPE {} counter", name);
+        for (store, metrics) in stores {
+            for (col, column) in metrics.columns.iter().enumerate() {
+                let value = counter(column).load(Ordering::Relaxed);
+                let _ = writeln!(
+                    out,
+                    "{}{{store=\"{}\",column=\"{}\"}} {}",
+                    name, store, COL_NAMES[col], value
+                );
+            }
+        }
+    }
+
+    let name = "near_store_read_latency_seconds";
+    let _ = writeln!(out, "# HELP {} Latency of the reads.", name);
+    let _ = writeln!(out, "# TYPE {} histogram", name);
+    for (store, metrics) in stores {
+        for (col, column) in metrics.columns.iter().enumerate() {
+            let labels = format!("store=\"{}\",column=\"{}\"", store, COL_NAMES[col]);
+            column.read_latency.export(name, &labels, &mut out);
+        }
+    }
+
+    let histograms: [(&str, &str, fn(&StoreMetrics) -> &Histogram); 2] = [
+        ("near_store_commit_latency_seconds", "Latency of the commits", |m| &m.commit_latency),
+        (
+            "near_store_flush_latency_seconds",
+            "Latency of the writes of the buffered updates",
+            |m| &m.flush_latency,
+        ),
+    ];
+    for (name, help, histogram) in histograms.iter() {
+        let _ = writeln!(out, "# HELP {} {}.", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        for (store, metrics) in stores {
+            histogram(metrics).export(name, &format!("store=\"{}\"", store), &mut out);
+        }
+    }
+
+    let name = "near_store_slow_writes_total";
+    let _ = writeln!(out, "# HELP {} Number of commits and flushes above the threshold.", name);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (store, metrics) in stores {
+        let value = metrics.slow_writes.load(Ordering::Relaxed);
+        let _ = writeln!(out, "{}{{store=\"{}\"}} {}", name, store, value);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::Ordering;
+    use std::time::Duration;
+
+    use crate::test_utils::create_test_store;
+    use crate::COL_BLOCK;
+
+    use super::{export_prometheus, Histogram};
+
+    #[test]
+    fn test_histogram_buckets() {
+        let histogram = Histogram::default();
+        histogram.observe(Duration::from_micros(50));
+        histogram.observe(Duration::from_millis(20));
+        histogram.observe(Duration::from_secs(2));
+        assert_eq!(histogram.count(), 3);
+        let mut out = String::new();
+        histogram.export("latency", "store=\"hot\"", &mut out);
+        assert!(out.contains("latency_bucket{store=\"hot\",le=\"0.0001\"} 1\n"));
+        assert!(out.contains("latency_bucket{store=\"hot\",le=\"0.05\"} 2\n"));
+        assert!(out.contains("latency_bucket{store=\"hot\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_count{store=\"hot\"} 3\n"));
+    }
+
+    #[test]
+    fn test_store_metrics() {
+        let store = create_test_store();
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK, b"block", b"value");
+        store_update.delete(COL_BLOCK, b"other");
+        store_update.commit().unwrap();
+        assert_eq!(store.get(COL_BLOCK, b"block").unwrap(), Some(b"value".to_vec()));
+        assert!(!store.exists(COL_BLOCK, b"other").unwrap());
+
+        let metrics = store.metrics();
+        let column = metrics.column(COL_BLOCK).unwrap();
+        assert_eq!(column.reads.load(Ordering::Relaxed), 2);
+        assert_eq!(column.read_bytes.load(Ordering::Relaxed), 5);
+        assert_eq!(column.writes.load(Ordering::Relaxed), 1);
+        assert_eq!(column.written_bytes.load(Ordering::Relaxed), 5);
+        assert_eq!(column.deletes.load(Ordering::Relaxed), 1);
+        assert_eq!(column.read_latency.count(), 2);
+        assert_eq!(metrics.commit_latency.count(), 1);
+
+        let text = export_prometheus(&[("hot", metrics)]);
+        assert!(text.contains("near_store_reads_total{store=\"hot\",column=\"block\"} 2\n"));
+        assert!(text.contains("near_store_commit_latency_seconds_count{store=\"hot\"} 1\n"));
+    }
+}
@@ -0,0 +1,265 @@
+//! Version of the database layout and migrations between the versions. The version is stored in
+//! the database and the migrations from it to the current version are applied when the store is
+//! opened, so that nodes keep their data across layout changes.
+//!
+//! To change the layout, bump `DB_VERSION` and append the migration from the previous version to
+//! `MIGRATIONS`.
+use std::io;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::info;
+
+use near_crypto::PublicKey;
+use near_primitives::hash::CryptoHash;
+use near_primitives::receipt::{ActionReceipt, DataReceipt, DataReceiver, Receipt, ReceiptEnum};
+use near_primitives::transaction::{Action, LogEntry, TransactionResult, TransactionStatus};
+use near_primitives::types::{AccountId, Balance};
+
+use crate::{Store, StoreUpdate, COL_BLOCK_MISC, COL_RECEIPTS, COL_TRANSACTION_RESULT};
+
+pub type DbVersion = u32;
+
+/// Current version of the database layout.
+pub const DB_VERSION: DbVersion = 1;
+
+/// Key of the database version in the misc column.
+const VERSION_KEY: &[u8; 10] = b"DB_VERSION";
+
+/// Changes the layout of the store from one version to the next one, the changes are committed
+/// together with the new version.
+type Migration = fn(&Store) -> Result<StoreUpdate, io::Error>;
+
+/// Migrations indexed by the version they migrate from.
+const MIGRATIONS: [Migration; DB_VERSION as usize] = [migrate_0_to_1];
+
+/// Version of the database, `None` if it's not set yet.
+pub fn get_store_version(store: &Store) -> Result<Option<DbVersion>, io::Error> {
+    store.get_ser(COL_BLOCK_MISC, VERSION_KEY)
+}
+
+fn set_store_version(store_update: &mut StoreUpdate, version: DbVersion) -> Result<(), io::Error> {
+    store_update.set_ser(COL_BLOCK_MISC, VERSION_KEY, &version)
+}
+
+/// Brings the store to the current version. New store gets the current version, store created
+/// before the layout was versioned is at version 0. Fails if the store was written by a newer
+/// version of the node.
+pub fn migrate(store: &Store) -> Result<(), io::Error> {
+    let mut version = match get_store_version(store)? {
+        Some(version) => version,
+        None if store.iter(COL_BLOCK_MISC).next().is_none() => {
+            let mut store_update = store.store_update();
+            set_store_version(&mut store_update, DB_VERSION)?;
+            return store_update.commit();
+        }
+        None => 0,
+    };
+    if version > DB_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "Database version {} is newer than the supported version {}, upgrade the node",
+                version, DB_VERSION
+            ),
+        ));
+    }
+    while version < DB_VERSION {
+        info!(target: "store", "Migrating database from version {} to {}", version, version + 1);
+        let mut store_update = MIGRATIONS[version as usize](store)?;
+        set_store_version(&mut store_update, version + 1)?;
+        store_update.commit()?;
+        version += 1;
+    }
+    Ok(())
+}
+
+/// Transaction result before the burnt gas was recorded.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct TransactionResultV0 {
+    status: TransactionStatus,
+    logs: Vec<LogEntry>,
+    receipts: Vec<CryptoHash>,
+    result: Option<Vec<u8>>,
+}
+
+/// Receipt before the depth of the action receipts was recorded.
+#[derive(BorshSerialize, BorshDeserialize)]
+pub(crate) struct ReceiptV0 {
+    predecessor_id: AccountId,
+    receiver_id: AccountId,
+    receipt_id: CryptoHash,
+    receipt: ReceiptEnumV0,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+enum ReceiptEnumV0 {
+    Action(ActionReceiptV0),
+    Data(DataReceipt),
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct ActionReceiptV0 {
+    signer_id: AccountId,
+    signer_public_key: PublicKey,
+    gas_price: Balance,
+    output_data_receivers: Vec<DataReceiver>,
+    input_data_ids: Vec<CryptoHash>,
+    actions: Vec<Action>,
+}
+
+/// The depth of the old receipts is unknown, so it's zero as for the receipts of transactions.
+impl From<ReceiptV0> for Receipt {
+    fn from(old: ReceiptV0) -> Self {
+        Receipt {
+            predecessor_id: old.predecessor_id,
+            receiver_id: old.receiver_id,
+            receipt_id: old.receipt_id,
+            receipt: match old.receipt {
+                ReceiptEnumV0::Action(action_receipt) => ReceiptEnum::Action(ActionReceipt {
+                    signer_id: action_receipt.signer_id,
+                    signer_public_key: action_receipt.signer_public_key,
+                    gas_price: action_receipt.gas_price,
+                    output_data_receivers: action_receipt.output_data_receivers,
+                    input_data_ids: action_receipt.input_data_ids,
+                    actions: action_receipt.actions,
+                    depth: 0,
+                }),
+                ReceiptEnumV0::Data(data_receipt) => ReceiptEnum::Data(data_receipt),
+            },
+        }
+    }
+}
+
+/// Adds the burnt gas to the transaction results, as unknown for the old results.
+/// Adds the depth to the receipts of the chunks, as zero. Values that are already in the new
+/// layout don't parse as the old one and are kept.
+fn migrate_0_to_1(store: &Store) -> Result<StoreUpdate, io::Error> {
+    let mut store_update = store.store_update();
+    let mut num_results = 0;
+    for (key, value) in store.iter(COL_TRANSACTION_RESULT) {
+        if let Ok(old) = TransactionResultV0::try_from_slice(&value) {
+            let result = TransactionResult {
+                status: old.status,
+                logs: old.logs,
+                receipts: old.receipts,
+                result: old.result,
+                gas_burnt: None,
+            };
+            store_update.set_ser(COL_TRANSACTION_RESULT, &key, &result)?;
+            num_results += 1;
+        }
+    }
+    info!(target: "store", "Added unknown burnt gas to {} transaction results", num_results);
+    let mut num_receipts = 0;
+    for (key, value) in store.iter(COL_RECEIPTS) {
+        if let Ok(old) = Vec::<ReceiptV0>::try_from_slice(&value) {
+            num_receipts += old.len();
+            let receipts: Vec<Receipt> = old.into_iter().map(Receipt::from).collect();
+            store_update.set_ser(COL_RECEIPTS, &key, &receipts)?;
+        }
+    }
+    info!(target: "store", "Added depth to {} receipts", num_receipts);
+    Ok(store_update)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::create_test_store;
+    use crate::COL_BLOCK;
+
+    use super::*;
+
+    #[test]
+    fn test_new_store_version() {
+        let store = create_test_store();
+        migrate(&store).unwrap();
+        assert_eq!(get_store_version(&store).unwrap(), Some(DB_VERSION));
+    }
+
+    #[test]
+    fn test_migrate_unversioned_store() {
+        let store = create_test_store();
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK_MISC, b"HEAD", b"head");
+        store_update.set(COL_BLOCK, b"block", b"block");
+        store_update.commit().unwrap();
+
+        migrate(&store).unwrap();
+        assert_eq!(get_store_version(&store).unwrap(), Some(DB_VERSION));
+        assert_eq!(store.get(COL_BLOCK, b"block").unwrap(), Some(b"block".to_vec()));
+        // Migrated store is not migrated again.
+        migrate(&store).unwrap();
+        assert_eq!(get_store_version(&store).unwrap(), Some(DB_VERSION));
+    }
+
+    #[test]
+    fn test_migrate_transaction_results() {
+        let store = create_test_store();
+        let old = TransactionResultV0 {
+            status: TransactionStatus::Completed,
+            logs: vec!["log".to_string()],
+            receipts: vec![CryptoHash::default()],
+            result: Some(vec![1]),
+        };
+        let new = TransactionResult { gas_burnt: Some(10), ..Default::default() };
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK_MISC, b"HEAD", b"head");
+        store_update.set_ser(COL_TRANSACTION_RESULT, b"old", &old).unwrap();
+        store_update.set_ser(COL_TRANSACTION_RESULT, b"new", &new).unwrap();
+        store_update.commit().unwrap();
+
+        migrate(&store).unwrap();
+        let migrated: TransactionResult =
+            store.get_ser(COL_TRANSACTION_RESULT, b"old").unwrap().unwrap();
+        assert_eq!(migrated.status, TransactionStatus::Completed);
+        assert_eq!(migrated.logs, vec!["log".to_string()]);
+        assert_eq!(migrated.result, Some(vec![1]));
+        assert_eq!(migrated.gas_burnt, None);
+        let kept: TransactionResult =
+            store.get_ser(COL_TRANSACTION_RESULT, b"new").unwrap().unwrap();
+        assert_eq!(kept, new);
+    }
+
+    #[test]
+    fn test_migrate_receipts() {
+        let store = create_test_store();
+        let old = ReceiptV0 {
+            predecessor_id: "alice.near".to_string(),
+            receiver_id: "bob.near".to_string(),
+            receipt_id: CryptoHash::default(),
+            receipt: ReceiptEnumV0::Action(ActionReceiptV0 {
+                signer_id: "alice.near".to_string(),
+                signer_public_key: PublicKey::empty(near_crypto::KeyType::ED25519),
+                gas_price: 1,
+                output_data_receivers: vec![],
+                input_data_ids: vec![],
+                actions: vec![],
+            }),
+        };
+        let old_bytes = old.try_to_vec().unwrap();
+        let mut store_update = store.store_update();
+        store_update.set(COL_BLOCK_MISC, b"HEAD", b"head");
+        store_update.set_ser(COL_RECEIPTS, b"receipts", &vec![old]).unwrap();
+        store_update.commit().unwrap();
+
+        migrate(&store).unwrap();
+        let migrated: Vec<Receipt> = store.get_ser(COL_RECEIPTS, b"receipts").unwrap().unwrap();
+        assert_eq!(migrated.len(), 1);
+        assert_eq!(migrated[0].receiver_id, "bob.near");
+        match &migrated[0].receipt {
+            ReceiptEnum::Action(action_receipt) => assert_eq!(action_receipt.depth, 0),
+            _ => panic!("the receipt should be an action receipt"),
+        }
+        // Postponed receipts are kept in the state in the old layout.
+        assert_eq!(crate::decode_postponed_receipt(&old_bytes), Some(migrated[0].clone()));
+    }
+
+    #[test]
+    fn test_newer_store_version() {
+        let store = create_test_store();
+        let mut store_update = store.store_update();
+        set_store_version(&mut store_update, DB_VERSION + 1).unwrap();
+        store_update.commit().unwrap();
+        assert!(migrate(&store).is_err());
+    }
+}
@@ -0,0 +1,238 @@
+//! Snapshot of the state at a given state root together with arbitrary store records, written as
+//! a gzip compressed stream and protected by a SHA-256 checksum of its uncompressed content.
+//!
+//! Format of the uncompressed stream: magic bytes, version, borsh serialized `SnapshotHeader`,
+//! entries (state items and store records, each prefixed by a tag), end tag and the checksum of
+//! all the preceding bytes.
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use kvdb::DBValue;
+use sha2::{Digest, Sha256};
+
+use near_primitives::hash::CryptoHash;
+
+use crate::trie::update::TrieUpdate;
+use crate::trie::Trie;
+use crate::Store;
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"NEARSNAP";
+const SNAPSHOT_VERSION: u32 = 1;
+
+const TAG_STATE_ITEM: u8 = 0;
+const TAG_RECORD: u8 = 1;
+const TAG_END: u8 = 2;
+
+/// Describes content of the snapshot.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq)]
+pub struct SnapshotHeader {
+    /// Root of the state stored in the snapshot.
+    pub state_root: CryptoHash,
+    /// Data of the snapshot creator, e.g. the block the state belongs to.
+    pub metadata: Vec<u8>,
+}
+
+/// Number of entries written to or read from the snapshot.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SnapshotStats {
+    pub num_state_items: u64,
+    pub num_records: u64,
+}
+
+pub(crate) struct HashingWriter<W: Write> {
+    pub(crate) inner: W,
+    pub(crate) hasher: Sha256,
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.input(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub(crate) struct HashingReader<R: Read> {
+    pub(crate) inner: R,
+    pub(crate) hasher: Sha256,
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.input(&buf[..read]);
+        Ok(read)
+    }
+}
+
+pub(crate) fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> io::Result<()> {
+    writer.write_u32::<LittleEndian>(bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+pub(crate) fn read_bytes<R: Read>(reader: &mut R) -> io::Result<Vec<u8>> {
+    let len = reader.read_u32::<LittleEndian>()? as u64;
+    // Not preallocated, so that a corrupted length fails on reading instead of allocation.
+    let mut bytes = vec![];
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Entry is truncated"));
+    }
+    Ok(bytes)
+}
+
+/// Writes snapshot of the state with given root and the given store records to the writer.
+pub fn write_snapshot<W, I>(
+    trie: &Trie,
+    header: &SnapshotHeader,
+    records: I,
+    writer: W,
+) -> Result<SnapshotStats, Box<dyn std::error::Error>>
+where
+    W: Write,
+    I: IntoIterator<Item = (Option<u32>, Vec<u8>, Vec<u8>)>,
+{
+    let encoder = GzEncoder::new(writer, Compression::default());
+    let mut writer = HashingWriter { inner: encoder, hasher: Sha256::new() };
+    let mut stats = SnapshotStats::default();
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_u32::<LittleEndian>(SNAPSHOT_VERSION)?;
+    write_bytes(&mut writer, &header.try_to_vec()?)?;
+    for item in trie.iter(&header.state_root)? {
+        let (key, value) = item?;
+        writer.write_u8(TAG_STATE_ITEM)?;
+        write_bytes(&mut writer, &key)?;
+        write_bytes(&mut writer, value.as_ref())?;
+        stats.num_state_items += 1;
+    }
+    for (column, key, value) in records {
+        let column = column.ok_or("Records of the default column are not supported")?;
+        writer.write_u8(TAG_RECORD)?;
+        writer.write_u32::<LittleEndian>(column)?;
+        write_bytes(&mut writer, &key)?;
+        write_bytes(&mut writer, &value)?;
+        stats.num_records += 1;
+    }
+    writer.write_u8(TAG_END)?;
+    let checksum = writer.hasher.result();
+    let mut encoder = writer.inner;
+    encoder.write_all(&checksum)?;
+    encoder.finish()?.flush()?;
+    Ok(stats)
+}
+
+/// Reads snapshot from the reader and saves its state and records into the store.
+/// Nothing is saved if the checksum or the state root of the snapshot doesn't match.
+pub fn restore_snapshot<R: Read>(
+    trie: Arc<Trie>,
+    store: &Store,
+    reader: R,
+) -> Result<(SnapshotHeader, SnapshotStats), Box<dyn std::error::Error>> {
+    let mut reader = HashingReader { inner: GzDecoder::new(reader), hasher: Sha256::new() };
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err("Not a snapshot file".into());
+    }
+    let version = reader.read_u32::<LittleEndian>()?;
+    if version != SNAPSHOT_VERSION {
+        return Err(format!("Unsupported snapshot version {}", version).into());
+    }
+    let header = SnapshotHeader::try_from_slice(&read_bytes(&mut reader)?)?;
+    let mut stats = SnapshotStats::default();
+    let mut state_update = TrieUpdate::new(trie.clone(), CryptoHash::default());
+    let mut store_update = store.store_update();
+    loop {
+        match reader.read_u8()? {
+            TAG_STATE_ITEM => {
+                let key = read_bytes(&mut reader)?;
+                let value = read_bytes(&mut reader)?;
+                state_update.set(key, DBValue::from_slice(&value));
+                stats.num_state_items += 1;
+            }
+            TAG_RECORD => {
+                let column = reader.read_u32::<LittleEndian>()?;
+                let key = read_bytes(&mut reader)?;
+                let value = read_bytes(&mut reader)?;
+                store_update.set(Some(column), &key, &value);
+                stats.num_records += 1;
+            }
+            TAG_END => break,
+            tag => return Err(format!("Unknown snapshot entry tag {}", tag).into()),
+        }
+    }
+    let computed = reader.hasher.result();
+    let mut checksum = [0u8; 32];
+    reader.inner.read_exact(&mut checksum)?;
+    if computed.as_slice() != &checksum[..] {
+        return Err("Snapshot checksum mismatch".into());
+    }
+    let (state_store_update, root) = state_update.finalize()?.into(trie)?;
+    if root != header.state_root {
+        return Err(
+            format!("Snapshot state root mismatch: {} != {}", root, header.state_root).into()
+        );
+    }
+    store_update.merge(state_store_update);
+    store_update.commit()?;
+    Ok((header, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::test_utils::{create_test_store, create_trie};
+    use crate::COL_BLOCK_MISC;
+
+    use super::*;
+
+    fn create_snapshot() -> (SnapshotHeader, Vec<u8>) {
+        let trie = create_trie();
+        let changes = (0..100u32)
+            .map(|i| (i.to_le_bytes().to_vec(), Some(vec![i as u8; (i % 7) as usize + 1])));
+        let (store_update, state_root) =
+            trie.update(&Trie::empty_root(), changes).unwrap().into(trie.clone()).unwrap();
+        store_update.commit().unwrap();
+        let header = SnapshotHeader { state_root, metadata: b"block".to_vec() };
+        let records = vec![(COL_BLOCK_MISC, b"HEAD".to_vec(), vec![1, 2, 3])];
+        let mut data = vec![];
+        let stats = write_snapshot(&trie, &header, records, &mut data).unwrap();
+        assert_eq!(stats, SnapshotStats { num_state_items: 100, num_records: 1 });
+        (header, data)
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let (header, data) = create_snapshot();
+        let store = create_test_store();
+        let trie = Arc::new(Trie::new(store.clone()));
+        let (restored, stats) = restore_snapshot(trie.clone(), &store, &data[..]).unwrap();
+        assert_eq!(restored, header);
+        assert_eq!(stats, SnapshotStats { num_state_items: 100, num_records: 1 });
+        assert_eq!(trie.get(&header.state_root, &5u32.to_le_bytes()), Some(vec![5; 6]));
+        assert_eq!(store.get(COL_BLOCK_MISC, b"HEAD").unwrap(), Some(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_snapshot_corrupted() {
+        let (_, data) = create_snapshot();
+        // Corrupting compressed stream or truncating it must fail without saving anything.
+        let mut corrupted = data.clone();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0xff;
+        for bad in vec![corrupted, data[..data.len() - 10].to_vec()] {
+            let store = create_test_store();
+            let trie = Arc::new(Trie::new(store.clone()));
+            assert!(restore_snapshot(trie, &store, &bad[..]).is_err());
+            assert_eq!(store.get(COL_BLOCK_MISC, b"HEAD").unwrap(), None);
+        }
+    }
+}
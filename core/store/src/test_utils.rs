@@ -9,6 +9,14 @@ pub fn create_test_store() -> Arc<Store> {
     Arc::new(Store::new(db))
 }
 
+/// Creates an in-memory database which moves collected history to the given cold store.
+pub fn create_test_store_with_cold_store(cold: Arc<Store>) -> Arc<Store> {
+    let db = Arc::new(kvdb_memorydb::create(NUM_COLS));
+    let mut store = Store::new(db);
+    store.cold = Some(cold);
+    Arc::new(store)
+}
+
 /// Creates a Trie using an in-memory database.
 pub fn create_trie() -> Arc<Trie> {
     let store = create_test_store();
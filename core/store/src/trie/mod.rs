@@ -1,9 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::fmt;
 use std::io::{Cursor, ErrorKind, Read, Write};
 use std::sync::{Arc, Mutex};
 
+use borsh::{BorshDeserialize, BorshSerialize};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use cached::{Cached, SizedCache};
 pub use kvdb::DBValue;
@@ -12,6 +13,7 @@ use log::error;
 
 use near_primitives::hash::{hash, CryptoHash};
 
+use crate::flat_state::StateChanges;
 use crate::{Store, StoreUpdate, COL_STATE};
 
 use self::nibble_slice::NibbleSlice;
@@ -368,6 +370,20 @@ impl TrieCachingStorage {
         // TODO defend from huge values in cache
         TrieCachingStorage { store, cache: Arc::new(Mutex::new(SizedCache::with_size(10000))) }
     }
+
+    /// Reads the node without the reference count past the cache. Unlike `retrieve_raw_bytes`,
+    /// missing and malformed values are reported instead of asserted.
+    fn read_raw_node(&self, hash: &CryptoHash) -> Result<Vec<u8>, String> {
+        let bytes = self
+            .store
+            .get(COL_STATE, hash.as_ref())
+            .map_err(|err| format!("Failed to read node {}: {}", hash, err))?
+            .ok_or_else(|| format!("Node {} not found in storage", hash))?;
+        if bytes.len() < 4 {
+            return Err(format!("Node {} has no reference count", hash));
+        }
+        Ok(bytes[..bytes.len() - 4].to_vec())
+    }
 }
 
 impl TrieStorage for TrieCachingStorage {
@@ -442,6 +458,7 @@ pub struct Trie {
 /// Having old_root and values in deletions allows to apply TrieChanges in reverse
 ///
 /// StoreUpdate are the changes from current state refcount to refcount + delta.
+#[derive(BorshSerialize, BorshDeserialize)]
 pub struct TrieChanges {
     #[allow(dead_code)]
     old_root: CryptoHash,
@@ -491,15 +508,10 @@ impl TrieChanges {
         self,
         trie: Arc<Trie>,
     ) -> Result<(StoreUpdate, CryptoHash), Box<dyn std::error::Error>> {
-        let mut store_update = StoreUpdate::new_with_trie(
-            trie.storage
-                .as_caching_storage()
-                .expect("Storage should be TrieCachingStorage")
-                .store
-                .storage
-                .clone(),
-            trie.clone(),
-        );
+        let store =
+            &trie.storage.as_caching_storage().expect("Storage should be TrieCachingStorage").store;
+        let mut store_update = StoreUpdate::new_with_trie(store.storage.clone(), trie.clone());
+        store_update.metrics = Some(store.metrics.clone());
         self.insertions_into(trie.clone(), &mut store_update)?;
         self.deletions_into(trie.clone(), &mut store_update)?;
         Ok((store_update, self.new_root))
@@ -509,11 +521,18 @@ impl TrieChanges {
 pub struct WrappedTrieChanges {
     trie: Arc<Trie>,
     trie_changes: TrieChanges,
+    state_changes: StateChanges,
 }
 
 impl WrappedTrieChanges {
     pub fn new(trie: Arc<Trie>, trie_changes: TrieChanges) -> Self {
-        WrappedTrieChanges { trie, trie_changes }
+        WrappedTrieChanges { trie, trie_changes, state_changes: vec![] }
+    }
+
+    /// Attaches values of the state keys the changes set, they update the flat state.
+    pub fn with_state_changes(mut self, state_changes: StateChanges) -> Self {
+        self.state_changes = state_changes;
+        self
     }
 
     pub fn insertions_into(
@@ -529,6 +548,55 @@ impl WrappedTrieChanges {
     ) -> Result<(), Box<dyn std::error::Error>> {
         self.trie_changes.deletions_into(self.trie.clone(), store_update)
     }
+
+    pub fn trie_changes(&self) -> &TrieChanges {
+        &self.trie_changes
+    }
+
+    pub fn state_changes(&self) -> &StateChanges {
+        &self.state_changes
+    }
+
+    /// Applies insertions together with garbage collection of older states in one go, so that
+    /// refcounts of the nodes touched by several of them stay consistent:
+    /// deletions of `finalized` changes are applied (their old state is discarded) and
+    /// insertions of `discarded` changes are reverted (their new state is discarded).
+    pub fn insertions_with_gc_into(
+        &self,
+        finalized: &[TrieChanges],
+        discarded: &[TrieChanges],
+        store_update: &mut StoreUpdate,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut deltas: HashMap<CryptoHash, (&[u8], i64)> = HashMap::new();
+        for (key, value, rc) in self.trie_changes.insertions.iter() {
+            deltas.entry(*key).or_insert((value, 0)).1 += i64::from(*rc);
+        }
+        let removed = finalized
+            .iter()
+            .flat_map(|changes| changes.deletions.iter())
+            .chain(discarded.iter().flat_map(|changes| changes.insertions.iter()));
+        for (key, value, rc) in removed {
+            deltas.entry(*key).or_insert((value, 0)).1 -= i64::from(*rc);
+        }
+        store_update.trie = Some(self.trie.clone());
+        for (key, (value, delta)) in deltas.into_iter() {
+            if delta == 0 {
+                continue;
+            }
+            let storage_rc = i64::from(self.trie.storage.retrieve_rc(&key).unwrap_or_default());
+            let rc = storage_rc + delta;
+            if rc < 0 {
+                return Err(format!("Refcount of trie node {} drops below zero", key).into());
+            }
+            if rc > 0 {
+                let bytes = RcTrieNode::encode(value, rc as u32)?;
+                store_update.set(COL_STATE, key.as_ref(), &bytes);
+            } else {
+                store_update.delete(COL_STATE, key.as_ref());
+            }
+        }
+        Ok(())
+    }
 }
 
 enum FlattenNodesCrumb {
@@ -1154,6 +1222,122 @@ impl Trie {
         TrieIterator::new(self, root)
     }
 
+    /// Iterator over the items with keys starting with `prefix`, in the key order.
+    pub fn iter_prefix<'a>(
+        &'a self,
+        root: &CryptoHash,
+        prefix: &[u8],
+    ) -> Result<TrieIterator<'a>, Box<dyn std::error::Error>> {
+        let mut iter = TrieIterator::new(self, root)?;
+        iter.seek_prefix(prefix)?;
+        Ok(iter)
+    }
+
+    /// Walks all the nodes reachable from given root and checks that they are present and match
+    /// their hashes. Nodes in `visited` are skipped and checked nodes are added to it, so nodes
+    /// shared between several roots are checked once. Returns the number of checked nodes.
+    pub fn check_integrity(
+        &self,
+        root: &CryptoHash,
+        visited: &mut HashSet<CryptoHash>,
+    ) -> Result<u64, String> {
+        let mut num_nodes = 0;
+        let mut stack = vec![*root];
+        while let Some(node_hash) = stack.pop() {
+            if node_hash == Trie::empty_root() || !visited.insert(node_hash) {
+                continue;
+            }
+            let bytes = match self.storage.as_caching_storage() {
+                Some(storage) => storage.read_raw_node(&node_hash)?,
+                None => self
+                    .storage
+                    .retrieve_raw_bytes(&node_hash)
+                    .ok_or_else(|| format!("Node {} not found in storage", node_hash))?,
+            };
+            if hash(&bytes) != node_hash {
+                return Err(format!("Node {} has data with hash {}", node_hash, hash(&bytes)));
+            }
+            match RawTrieNode::decode(&bytes)
+                .map_err(|_| format!("Failed to decode node {}", node_hash))?
+            {
+                RawTrieNode::Leaf(_, _) => {}
+                RawTrieNode::Branch(children, _) => {
+                    stack.extend(children.iter().filter_map(|child| *child))
+                }
+                RawTrieNode::Extension(_, child) => stack.push(child),
+            }
+            num_nodes += 1;
+        }
+        Ok(num_nodes)
+    }
+
+    /// Items with keys from `start` inclusive to `end` exclusive, in the key order. `None` end
+    /// means the range goes till the last key.
+    fn get_range(
+        &self,
+        root: &CryptoHash,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>, String> {
+        let mut iter = self.iter(root).map_err(|err| err.to_string())?;
+        iter.seek(start)?;
+        let mut items = vec![];
+        for item in iter {
+            let (key, value) = item?;
+            if key.as_slice() < start {
+                continue;
+            }
+            if end.map_or(false, |end| key.as_slice() >= end) {
+                break;
+            }
+            items.push((key, value.to_vec()));
+        }
+        Ok(items)
+    }
+
+    /// Returns the items in the key range, see `get_range`, with the trie nodes read to find
+    /// them. These nodes are enough to check with `Trie::verify_range_proof` that the range has
+    /// exactly these items.
+    pub fn get_range_proof(
+        &self,
+        root: &CryptoHash,
+        start: &[u8],
+        end: Option<&[u8]>,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, Vec<Vec<u8>>), String> {
+        let trie = self.recording_reads();
+        let items = trie.get_range(root, start, end)?;
+        let partial_storage = trie.recorded_storage().expect("Storage should be recording");
+        Ok((items, partial_storage.nodes.into_iter().map(|(_, node)| node).collect()))
+    }
+
+    /// Checks that the key range of the trie with given root has exactly the given items, using
+    /// only the nodes from the proof. Nodes are addressed by the hash of their data and the walk
+    /// starts from the root, so the proof must contain the root and can't contain nodes that
+    /// don't belong to the trie. Walking the range finds the keys the items would leave out.
+    pub fn verify_range_proof(
+        root: &CryptoHash,
+        start: &[u8],
+        end: Option<&[u8]>,
+        items: &[(Vec<u8>, Vec<u8>)],
+        proof: &[Vec<u8>],
+    ) -> Result<(), String> {
+        let nodes = proof.iter().map(|node| (hash(node), node.clone())).collect();
+        let trie = Trie::from_recorded_storage(PartialStorage { nodes });
+        let proven_items = trie.get_range(root, start, end)?;
+        if proven_items.len() != items.len() {
+            return Err(format!(
+                "Range has {} items under root {}, got {}",
+                proven_items.len(),
+                root,
+                items.len()
+            ));
+        }
+        match proven_items.iter().zip(items.iter()).find(|(proven, item)| proven != item) {
+            Some((_, (key, _))) => Err(format!("Item {:?} doesn't match root {}", key, root)),
+            None => Ok(()),
+        }
+    }
+
     #[inline]
     pub fn update_cache(&self, transaction: &DBTransaction) -> std::io::Result<()> {
         let storage =
@@ -1216,6 +1400,8 @@ pub struct TrieIterator<'a> {
     trail: Vec<Crumb>,
     key_nibbles: Vec<u8>,
     root: CryptoHash,
+    /// Iteration stops at the first key that doesn't start with the prefix.
+    prefix: Vec<u8>,
 }
 
 impl<'a> TrieIterator<'a> {
@@ -1227,6 +1413,7 @@ impl<'a> TrieIterator<'a> {
             trail: Vec::with_capacity(8),
             key_nibbles: Vec::with_capacity(64),
             root: *root,
+            prefix: vec![],
         };
         if let Ok(node) = trie.retrieve_node(root) {
             r.descend_into_node(&node);
@@ -1235,7 +1422,15 @@ impl<'a> TrieIterator<'a> {
         Err(format!("Root hash {} not found", root).into())
     }
 
-    /// Position the iterator on the first element with key => `key`.
+    /// Position the iterator on the first element with key starting with `prefix` and stop the
+    /// iteration after the last such element.
+    pub fn seek_prefix(&mut self, prefix: &[u8]) -> Result<(), String> {
+        self.prefix = prefix.to_vec();
+        self.seek(prefix)
+    }
+
+    /// Position the iterator on the first element with key => `key`. The prefix set by
+    /// `seek_prefix` is kept, so the iteration over the prefix can be resumed from a given key.
     pub fn seek(&mut self, key: &[u8]) -> Result<(), String> {
         self.trail.clear();
         self.key_nibbles.clear();
@@ -1315,12 +1510,8 @@ impl<'a> TrieIterator<'a> {
         }
         result
     }
-}
-
-impl<'a> Iterator for TrieIterator<'a> {
-    type Item = TrieItem<'a>;
 
-    fn next(&mut self) -> Option<Self::Item> {
+    fn next_item(&mut self) -> Option<TrieItem<'a>> {
         enum IterStep {
             Continue,
             PopTrail,
@@ -1402,6 +1593,20 @@ impl<'a> Iterator for TrieIterator<'a> {
     }
 }
 
+impl<'a> Iterator for TrieIterator<'a> {
+    type Item = TrieItem<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_item() {
+            Some(Ok((key, _))) if !key.starts_with(&self.prefix) => {
+                self.trail.clear();
+                None
+            }
+            item => item,
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use rand::seq::SliceRandom;
@@ -1498,6 +1703,38 @@ mod tests {
         assert_eq!(other_iter.next().unwrap().unwrap().0, b"x".to_vec());
     }
 
+    #[test]
+    fn test_trie_iter_prefix() {
+        let trie = create_trie();
+        let pairs = vec![
+            (b"a".to_vec(), Some(b"111".to_vec())),
+            (b"aa".to_vec(), Some(b"222".to_vec())),
+            (b"ab".to_vec(), Some(b"333".to_vec())),
+            (b"abc".to_vec(), Some(b"444".to_vec())),
+            (b"b".to_vec(), Some(b"555".to_vec())),
+            (b"ba".to_vec(), Some(b"666".to_vec())),
+        ];
+        let root = test_populate_trie(trie.clone(), &Trie::empty_root(), pairs.clone());
+        let keys = |iter: TrieIterator| iter.map(|item| item.unwrap().0).collect::<Vec<_>>();
+        for prefix in vec![&b""[..], b"a", b"ab", b"abc", b"b", b"c", b"0"] {
+            let expected = pairs
+                .iter()
+                .filter(|(key, _)| key.starts_with(prefix))
+                .map(|(key, _)| key.clone())
+                .collect::<Vec<_>>();
+            assert_eq!(keys(trie.iter_prefix(&root, prefix).unwrap()), expected);
+        }
+
+        // Pagination: resume iteration over the prefix from the first key of the next page.
+        let mut iter = trie.iter_prefix(&root, b"a").unwrap();
+        let page = iter.by_ref().take(2).map(|item| item.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(page, vec![b"a".to_vec(), b"aa".to_vec()]);
+        let next_key = iter.next().unwrap().unwrap().0;
+        let mut iter = trie.iter_prefix(&root, b"a").unwrap();
+        iter.seek(&next_key).unwrap();
+        assert_eq!(keys(iter), vec![b"ab".to_vec(), b"abc".to_vec()]);
+    }
+
     #[test]
     fn test_trie_leaf_into_branch() {
         let trie = create_trie();
@@ -1775,4 +2012,116 @@ mod tests {
             assert_eq!(trie2.recorded_storage().unwrap().nodes.len(), 2);
         }
     }
+
+    #[test]
+    fn test_check_integrity() {
+        let store = create_test_store();
+        let trie = Arc::new(Trie::new(store.clone()));
+        let changes = vec![
+            (b"doge".to_vec(), Some(b"coin".to_vec())),
+            (b"docu".to_vec(), Some(b"value".to_vec())),
+        ];
+        let root = test_populate_trie(trie.clone(), &Trie::empty_root(), changes);
+        let mut visited = HashSet::new();
+        // Trie: extension -> branch -> 2 leaves
+        assert_eq!(trie.check_integrity(&root, &mut visited), Ok(4));
+        assert_eq!(trie.check_integrity(&root, &mut visited), Ok(0));
+
+        let mut store_update = store.store_update();
+        store_update.delete(COL_STATE, root.as_ref());
+        store_update.commit().unwrap();
+        let trie = Trie::new(store.clone());
+        assert!(trie.check_integrity(&root, &mut HashSet::new()).is_err());
+
+        let mut store_update = store.store_update();
+        store_update.set(COL_STATE, root.as_ref(), b"ab");
+        store_update.commit().unwrap();
+        let trie = Trie::new(store);
+        assert!(trie.check_integrity(&root, &mut HashSet::new()).is_err());
+    }
+
+    #[test]
+    fn test_trie_proof() {
+        let store = create_test_store();
+        let trie = Arc::new(Trie::new(store));
+        let changes = vec![
+            (b"doge".to_vec(), Some(b"coin".to_vec())),
+            (b"docu".to_vec(), Some(b"value".to_vec())),
+            (b"horse".to_vec(), Some(b"stallion".to_vec())),
+        ];
+        let root = test_populate_trie(trie.clone(), &Trie::empty_root(), changes);
+        let (start, end) = (&b"do"[..], Some(&b"h"[..]));
+        let (items, proof) = trie.get_range_proof(&root, start, end).unwrap();
+        assert_eq!(
+            items,
+            vec![(b"docu".to_vec(), b"value".to_vec()), (b"doge".to_vec(), b"coin".to_vec())]
+        );
+        assert_eq!(Trie::verify_range_proof(&root, start, end, &items, &proof), Ok(()));
+
+        let forged_items = vec![items[0].clone(), (b"doge".to_vec(), b"bone".to_vec())];
+        assert!(Trie::verify_range_proof(&root, start, end, &forged_items, &proof).is_err());
+        let partial_items = vec![items[1].clone()];
+        assert!(Trie::verify_range_proof(&root, start, end, &partial_items, &proof).is_err());
+        let mut extra_items = items.clone();
+        extra_items.push((b"horse".to_vec(), b"stallion".to_vec()));
+        assert!(Trie::verify_range_proof(&root, start, end, &extra_items, &proof).is_err());
+        let other_root = hash(b"other root");
+        assert!(Trie::verify_range_proof(&other_root, start, end, &items, &proof).is_err());
+        let mut forged_proof = proof.clone();
+        forged_proof.push(b"garbage".to_vec());
+        forged_proof.remove(0);
+        assert!(Trie::verify_range_proof(&root, start, end, &items, &forged_proof).is_err());
+
+        let (items, proof) = trie.get_range_proof(&root, b"h", None).unwrap();
+        assert_eq!(items, vec![(b"horse".to_vec(), b"stallion".to_vec())]);
+        assert_eq!(Trie::verify_range_proof(&root, b"h", None, &items, &proof), Ok(()));
+    }
+
+    #[test]
+    fn test_trie_changes_gc() {
+        let store = create_test_store();
+        let trie = Arc::new(Trie::new(store.clone()));
+        let changes = vec![
+            (b"doge".to_vec(), Some(b"coin".to_vec())),
+            (b"docu".to_vec(), Some(b"value".to_vec())),
+        ];
+        let root0 = test_populate_trie(trie.clone(), &Trie::empty_root(), changes);
+        let apply_insertions = |root: &CryptoHash, key: &[u8], value: &[u8]| {
+            let changes =
+                trie.update(root, vec![(key.to_vec(), Some(value.to_vec()))].into_iter()).unwrap();
+            let changes = WrappedTrieChanges::new(trie.clone(), changes);
+            let mut store_update = store.store_update();
+            changes.insertions_into(&mut store_update).unwrap();
+            store_update.commit().unwrap();
+            changes
+        };
+        // State 1 and state 2 are forks on top of state 0, state 3 is built on top of state 1.
+        let changes1 = apply_insertions(&root0, b"doge", b"bone");
+        let changes2 = apply_insertions(&root0, b"horse", b"stallion");
+        let root1 = changes1.trie_changes().new_root;
+        let root2 = changes2.trie_changes().new_root;
+        let changes3 = trie
+            .update(&root1, vec![(b"docu".to_vec(), Some(b"draft".to_vec()))].into_iter())
+            .unwrap();
+        let root3 = changes3.new_root;
+        let changes3 = WrappedTrieChanges::new(trie.clone(), changes3);
+        let discarded = [changes2.trie_changes];
+        let mut store_update = store.store_update();
+        changes3
+            .insertions_with_gc_into(&[changes1.trie_changes], &discarded, &mut store_update)
+            .unwrap();
+        store_update.commit().unwrap();
+        // Discarding the fork once more would drop refcounts of its nodes below zero.
+        let no_changes = WrappedTrieChanges::new(trie.clone(), TrieChanges::empty(root3));
+        assert!(no_changes
+            .insertions_with_gc_into(&[], &discarded, &mut store.store_update())
+            .is_err());
+
+        let mut visited = HashSet::new();
+        assert!(trie.check_integrity(&root1, &mut visited).is_ok());
+        assert!(trie.check_integrity(&root3, &mut visited).is_ok());
+        assert_eq!(store.iter(COL_STATE).count(), visited.len());
+        assert!(trie.check_integrity(&root0, &mut HashSet::new()).is_err());
+        assert!(trie.check_integrity(&root2, &mut HashSet::new()).is_err());
+    }
 }
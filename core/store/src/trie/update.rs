@@ -3,10 +3,11 @@ use std::iter::Peekable;
 use std::sync::Arc;
 
 use kvdb::DBValue;
-use log::debug;
+use log::{debug, error};
 
 use near_primitives::types::MerkleHash;
 
+use crate::flat_state::{get_flat_state_value, StateChanges};
 use crate::trie::TrieChanges;
 
 use super::{Trie, TrieIterator};
@@ -17,21 +18,49 @@ pub struct TrieUpdate {
     root: MerkleHash,
     committed: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
     prospective: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Whether values are read from the flat state when it's at the root.
+    flat_state: bool,
 }
 
 impl TrieUpdate {
     pub fn new(trie: Arc<Trie>, root: MerkleHash) -> Self {
-        TrieUpdate { trie, root, committed: BTreeMap::default(), prospective: BTreeMap::default() }
+        TrieUpdate {
+            trie,
+            root,
+            committed: BTreeMap::default(),
+            prospective: BTreeMap::default(),
+            flat_state: false,
+        }
+    }
+    /// Same as `new`, values are read from the flat state if it's at the root, which is a single
+    /// lookup instead of the trie traversal. Iteration still goes through the trie.
+    pub fn new_with_flat_state(trie: Arc<Trie>, root: MerkleHash) -> Self {
+        TrieUpdate { flat_state: true, ..TrieUpdate::new(trie, root) }
     }
     pub fn get(&self, key: &[u8]) -> Option<DBValue> {
         if let Some(value) = self.prospective.get(key) {
             Some(DBValue::from_slice(value.as_ref()?))
         } else if let Some(value) = self.committed.get(key) {
             Some(DBValue::from_slice(value.as_ref()?))
+        } else if let Some(value) = self.get_from_flat_state(key) {
+            value.map(DBValue::from_vec)
         } else {
             self.trie.get(&self.root, key).map(DBValue::from_vec)
         }
     }
+    fn get_from_flat_state(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        if !self.flat_state {
+            return None;
+        }
+        let store = &self.trie.storage.as_caching_storage()?.store;
+        match get_flat_state_value(store, &self.root, key) {
+            Ok(value) => value,
+            Err(err) => {
+                error!(target: "store", "Failed to read flat state: {}", err);
+                None
+            }
+        }
+    }
     pub fn set(&mut self, key: Vec<u8>, value: DBValue) {
         self.prospective.insert(key, Some(value.into_vec()));
     }
@@ -83,12 +112,33 @@ impl TrieUpdate {
         let TrieUpdate { trie, root, committed, .. } = self;
         trie.update(&root, committed.into_iter())
     }
+    /// Same as `finalize`, also returns the values set by the update.
+    pub fn finalize_with_state_changes(
+        mut self,
+    ) -> Result<(TrieChanges, StateChanges), Box<dyn std::error::Error>> {
+        if !self.prospective.is_empty() {
+            self.commit();
+        }
+        let TrieUpdate { trie, root, committed, .. } = self;
+        let state_changes = committed.clone().into_iter().collect();
+        let trie_changes = trie.update(&root, committed.into_iter())?;
+        Ok((trie_changes, state_changes))
+    }
 
     /// Returns Error if the underlying storage fails
     pub fn iter(&self, prefix: &[u8]) -> Result<TrieUpdateIterator, Box<dyn std::error::Error>> {
         TrieUpdateIterator::new(self, prefix, b"", None)
     }
 
+    /// Iterator over the keys starting with `prefix`, from `prefix` + `start`.
+    pub fn iter_from(
+        &self,
+        prefix: &[u8],
+        start: &[u8],
+    ) -> Result<TrieUpdateIterator, Box<dyn std::error::Error>> {
+        TrieUpdateIterator::new(self, prefix, start, None)
+    }
+
     pub fn range(
         &self,
         prefix: &[u8],
@@ -169,6 +219,7 @@ impl<'a> TrieUpdateIterator<'a> {
             }
             None => None,
         };
+        trie_iter.seek_prefix(prefix)?;
         trie_iter.seek(&start_offset)?;
         let committed_iter = state_update.committed.range(start_offset.clone()..);
         let prospective_iter = state_update.prospective.range(start_offset..);
@@ -0,0 +1,34 @@
+//! Restore of the database from the backup written by the running node with the `db_backup` RPC.
+use std::fs;
+use std::path::Path;
+
+use log::info;
+
+use near_store::backup::{restore_backup, BackupStats};
+
+/// Restores the backup into a new database next to the store path and replaces the database at
+/// the store path with it once the restored files are verified. The node must be stopped.
+pub fn restore_database(
+    store_path: &str,
+    input: &Path,
+) -> Result<BackupStats, Box<dyn std::error::Error>> {
+    let restore_path = format!("{}.restore", store_path);
+    if Path::new(&restore_path).exists() {
+        fs::remove_dir_all(&restore_path)?;
+    }
+    let stats = match restore_backup(input, Path::new(&restore_path)) {
+        Ok(stats) => stats,
+        Err(err) => {
+            if Path::new(&restore_path).exists() {
+                fs::remove_dir_all(&restore_path)?;
+            }
+            return Err(err);
+        }
+    };
+    info!(target: "near", "Verified {} restored files, replacing database at {}", stats.num_files, store_path);
+    if Path::new(store_path).exists() {
+        fs::remove_dir_all(store_path)?;
+    }
+    fs::rename(&restore_path, store_path)?;
+    Ok(stats)
+}
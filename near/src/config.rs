@@ -1,34 +1,50 @@
+use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::Arc;
 use std::time::Duration;
-use std::{cmp, fs};
+use std::{cmp, fs, iter};
 
+use actix::System;
+use actix_web::client::Client;
 use chrono::{DateTime, Utc};
-use log::info;
+use futures::future::{lazy, Future};
+use log::{error, info, LevelFilter};
 use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
+use serde::de::DeserializeOwned;
 use serde_derive::{Deserialize, Serialize};
 
 use near_client::BlockProducer;
-use near_client::ClientConfig;
-use near_crypto::{InMemorySigner, KeyFile, KeyType, PublicKey, ReadablePublicKey, Signer};
+use near_client::{ClientConfig, SyncConfig};
+use near_crypto::{
+    rotated_key_paths, InMemorySigner, KeyFile, KeyType, PublicKey, ReadablePublicKey, Signer,
+};
 use near_jsonrpc::RpcConfig;
+use near_network::ban_policy::BanPolicyConfig;
+use near_network::bandwidth::BandwidthConfig;
 use near_network::test_utils::open_port;
 use near_network::types::PROTOCOL_VERSION;
+use near_network::types::{PeerFilterEntry, PeerInfo};
 use near_network::NetworkConfig;
+use near_pool::PoolConfig;
 use near_primitives::account::AccessKey;
 use near_primitives::hash::{hash, CryptoHash};
 use near_primitives::serialize::{to_base64, u128_dec_format};
-use near_primitives::types::{AccountId, Balance, BlockIndex, ValidatorId};
+use near_primitives::transaction::{Action, SignedTransaction, StakeAction};
+use near_primitives::types::{AccountId, Balance, BlockIndex, Gas, Nonce, ShardId, ValidatorId};
 use near_primitives::views::AccountView;
+use near_store::{CommitPolicy, StoreBackend, StoreOptions};
 use near_telemetry::TelemetryConfig;
 use node_runtime::config::RuntimeConfig;
 use node_runtime::StateRecord;
 
+use crate::STORE_PATH;
+
 /// Initial balance used in tests.
 pub const TESTING_INIT_BALANCE: Balance = 1_000_000_000_000_000;
 
@@ -56,6 +72,9 @@ pub const MAX_BLOCK_PRODUCTION_DELAY: u64 = 6;
 /// Expected epoch length.
 pub const EXPECTED_EPOCH_LENGTH: BlockIndex = (5 * 60) / MIN_BLOCK_PRODUCTION_DELAY;
 
+/// Maximum number of blocks a reorg can revert by default.
+pub const MAX_REORG_DEPTH: BlockIndex = 500;
+
 /// Criterion for kicking out validators.
 pub const VALIDATOR_KICKOUT_THRESHOLD: f64 = 0.9;
 
@@ -67,6 +86,22 @@ pub const FAST_EPOCH_LENGTH: u64 = 60;
 /// Number of blocks for which a given transaction is valid
 pub const TRANSACTION_VALIDITY_PERIOD: u64 = 100;
 
+/// Number of heights before the first rebroadcast of a local transaction by default.
+pub const TX_REBROADCAST_DELAY: BlockIndex = 5;
+
+/// Maximum gas prepaid by the transactions of a produced block by default.
+pub const BLOCK_MAX_GAS: Gas = 1_000_000_000_000_000;
+
+/// Maximum total size of the transactions of a produced block by default.
+pub const BLOCK_MAX_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Oldest protocol version of the genesis config the node accepts. Runtime settings added since
+/// then are optional in the genesis config and have their default values when missing.
+pub const MIN_GENESIS_PROTOCOL_VERSION: u32 = 3;
+
+/// Maximum size of a network message in bytes by default.
+pub const MAX_MESSAGE_SIZE: u64 = 10 * 1024 * 1024;
+
 pub const CONFIG_FILENAME: &str = "config.json";
 pub const GENESIS_CONFIG_FILENAME: &str = "genesis.json";
 pub const NODE_KEY_FILE: &str = "node_key.json";
@@ -74,6 +109,16 @@ pub const VALIDATOR_KEY_FILE: &str = "validator_key.json";
 
 const DEFAULT_TELEMETRY_URL: &str = "https://explorer.nearprotocol.com/api/nodes";
 
+/// Location of published configs for named chains, files are under `<chain id>/` path.
+const NETWORK_CONFIG_URL: &str =
+    "https://s3-us-west-1.amazonaws.com/build.nearprotocol.com/nearcore-deploy";
+
+/// Timeout for downloading config files.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Maximum size of the downloaded config file.
+const MAX_DOWNLOAD_SIZE: usize = 512 * 1024 * 1024;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Network {
     /// Address to listen for incoming connections.
@@ -93,6 +138,77 @@ pub struct Network {
     pub skip_sync_wait: bool,
     /// Ban window for peers who misbehave.
     pub ban_window: Duration,
+    /// Peers with network protocol version lower than this are refused during the handshake.
+    #[serde(default = "default_min_peer_protocol_version")]
+    pub min_peer_protocol_version: u32,
+    /// Peers never to connect to nor accept: peer ids, IP addresses or IP addresses with ports.
+    #[serde(default)]
+    pub blacklist: Vec<String>,
+    /// If not empty, only these peers are connected to and accepted, same format as blacklist.
+    #[serde(default)]
+    pub whitelist: Vec<String>,
+    /// Comma separated list of trusted sentry nodes of the private validator. If set, the node
+    /// connects only to them and never advertises its own address.
+    #[serde(default)]
+    pub sentries: String,
+    /// Compress large messages, such as blocks and state parts, to the peers that support it.
+    #[serde(default = "default_enable_compression")]
+    pub enable_compression: bool,
+    /// Encrypt connections with the peers that support it. Connections with the peers that don't
+    /// are not encrypted, unless encryption is required.
+    #[serde(default = "default_enable_encryption")]
+    pub enable_encryption: bool,
+    /// Refuse connections with the peers that don't encrypt them.
+    #[serde(default)]
+    pub require_encryption: bool,
+    /// Map the listening port on the router of the local network with UPnP, so that the node is
+    /// dialable from outside without manual router configuration.
+    #[serde(default)]
+    pub upnp: bool,
+    /// Upload and download rate limits in bytes per second, unlimited if not set.
+    #[serde(default)]
+    pub bandwidth: BandwidthConfig,
+    /// How often to ping each peer to measure the round trip time.
+    #[serde(default = "default_ping_period")]
+    pub ping_period: Duration,
+    /// Peers that didn't send anything for this long, not even a pong, are disconnected.
+    #[serde(default = "default_peer_silence_timeout")]
+    pub peer_silence_timeout: Duration,
+    /// SOCKS5 proxy ip:port, such as a local Tor client, to connect to the peers through. If set,
+    /// the node doesn't advertise its address. If empty, connects to the peers directly.
+    #[serde(default)]
+    pub proxy: String,
+    /// Maximum size of a message in bytes, larger messages from the peers are refused and block
+    /// headers responses are sized to fit it.
+    #[serde(default = "default_max_message_size")]
+    pub max_message_size: u64,
+    /// Penalties and thresholds of the misbehavior reports, and durations of the bans.
+    #[serde(default)]
+    pub ban_policy: BanPolicyConfig,
+}
+
+fn default_min_peer_protocol_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+fn default_enable_compression() -> bool {
+    true
+}
+
+fn default_enable_encryption() -> bool {
+    true
+}
+
+fn default_ping_period() -> Duration {
+    Duration::from_secs(10)
+}
+
+fn default_peer_silence_timeout() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_max_message_size() -> u64 {
+    MAX_MESSAGE_SIZE
 }
 
 impl Default for Network {
@@ -106,6 +222,20 @@ impl Default for Network {
             reconnect_delay: Duration::from_secs(60),
             skip_sync_wait: false,
             ban_window: Duration::from_secs(3 * 60 * 60),
+            min_peer_protocol_version: PROTOCOL_VERSION,
+            blacklist: vec![],
+            whitelist: vec![],
+            sentries: "".to_string(),
+            enable_compression: true,
+            enable_encryption: true,
+            require_encryption: false,
+            upnp: false,
+            bandwidth: BandwidthConfig::default(),
+            ping_period: default_ping_period(),
+            peer_silence_timeout: default_peer_silence_timeout(),
+            proxy: "".to_string(),
+            max_message_size: MAX_MESSAGE_SIZE,
+            ban_policy: BanPolicyConfig::default(),
         }
     }
 }
@@ -118,8 +248,46 @@ pub struct Consensus {
     pub min_block_production_delay: Duration,
     /// Maximum duration before producing block or skipping height.
     pub max_block_production_delay: Duration,
+    /// Produce block as soon as there are transactions, otherwise wait for them up to the
+    /// maximum block production delay.
+    #[serde(default)]
+    pub adaptive_block_production: bool,
     /// Produce empty blocks, use `false` for testing.
     pub produce_empty_blocks: bool,
+    /// When empty blocks are not produced, still produce one if there was no block for this
+    /// many heights. `null` waits for transactions.
+    #[serde(default)]
+    pub max_idle_blocks: Option<BlockIndex>,
+    /// Maximum number of blocks a reorg can revert, deeper reorgs are refused.
+    /// Can be overridden with `--allow-deep-reorgs`.
+    #[serde(default = "default_max_reorg_depth")]
+    pub max_reorg_depth: BlockIndex,
+    /// Number of heights after which transactions submitted to this node and not yet included
+    /// are broadcast again, the delay doubles after each broadcast.
+    #[serde(default = "default_tx_rebroadcast_delay")]
+    pub tx_rebroadcast_delay: BlockIndex,
+    /// Maximum gas prepaid by the transactions of a produced block, the rest stay in the pool.
+    #[serde(default = "default_block_max_gas")]
+    pub block_max_gas: Gas,
+    /// Maximum total size of the transactions of a produced block, the rest stay in the pool.
+    #[serde(default = "default_block_max_size")]
+    pub block_max_size: u64,
+}
+
+fn default_max_reorg_depth() -> BlockIndex {
+    MAX_REORG_DEPTH
+}
+
+fn default_tx_rebroadcast_delay() -> BlockIndex {
+    TX_REBROADCAST_DELAY
+}
+
+fn default_block_max_gas() -> Gas {
+    BLOCK_MAX_GAS
+}
+
+fn default_block_max_size() -> u64 {
+    BLOCK_MAX_SIZE
 }
 
 impl Default for Consensus {
@@ -128,7 +296,43 @@ impl Default for Consensus {
             min_num_peers: 3,
             min_block_production_delay: Duration::from_secs(MIN_BLOCK_PRODUCTION_DELAY),
             max_block_production_delay: Duration::from_secs(MAX_BLOCK_PRODUCTION_DELAY),
+            adaptive_block_production: false,
             produce_empty_blocks: true,
+            max_idle_blocks: None,
+            max_reorg_depth: MAX_REORG_DEPTH,
+            tx_rebroadcast_delay: TX_REBROADCAST_DELAY,
+            block_max_gas: BLOCK_MAX_GAS,
+            block_max_size: BLOCK_MAX_SIZE,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StoreConfig {
+    /// Path to the database directory, relative paths are resolved against the home directory.
+    /// By default database is in `data` directory under home.
+    pub path: Option<PathBuf>,
+    /// Path to the cold store database the garbage collection moves old blocks, their results
+    /// and trie changes to, reads of them fall through to it. Meant for archival nodes keeping
+    /// the history on cheaper disks, relative paths are resolved against the home directory.
+    #[serde(default)]
+    pub cold_path: Option<PathBuf>,
+    /// Number of epochs of blocks and state to keep behind the final head, older ones are
+    /// garbage collected. Garbage collection is opt-in, by default (`null`) everything is kept.
+    #[serde(default)]
+    pub gc_num_epochs_to_keep: Option<u64>,
+    /// Database backend and RocksDB tuning options.
+    #[serde(flatten)]
+    pub options: StoreOptions,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            path: None,
+            cold_path: None,
+            gc_num_epochs_to_keep: None,
+            options: StoreOptions::default(),
         }
     }
 }
@@ -143,6 +347,18 @@ pub struct Config {
     pub telemetry: TelemetryConfig,
     pub network: Network,
     pub consensus: Consensus,
+    pub store: StoreConfig,
+    /// Maximum log level, e.g. "info" or "debug". Can be changed without restart.
+    pub log_level: Option<String>,
+    /// Shards to track in addition to the ones this node validates, all the shards if empty.
+    /// Blocks are not applied and state is not downloaded for the shards that aren't tracked.
+    pub tracked_shards: Vec<ShardId>,
+    /// Key files of additional validator accounts this node produces and approves blocks for.
+    pub additional_validator_key_files: Vec<String>,
+    /// Limits of the transaction pool.
+    pub tx_pool: PoolConfig,
+    /// Concurrency limits and timeouts of the sync.
+    pub sync: SyncConfig,
 }
 
 impl Default for Config {
@@ -155,6 +371,12 @@ impl Default for Config {
             telemetry: TelemetryConfig::default(),
             network: Network::default(),
             consensus: Consensus::default(),
+            store: StoreConfig::default(),
+            log_level: None,
+            tracked_shards: vec![],
+            additional_validator_key_files: vec![],
+            tx_pool: PoolConfig::default(),
+            sync: SyncConfig::default(),
         }
     }
 }
@@ -184,12 +406,15 @@ impl From<&str> for Config {
 
 #[derive(Clone)]
 pub struct NearConfig {
-    config: Config,
+    pub(crate) config: Config,
     pub client_config: ClientConfig,
     pub network_config: NetworkConfig,
     pub rpc_config: RpcConfig,
     pub telemetry_config: TelemetryConfig,
+    pub store_config: StoreConfig,
     pub block_producer: Option<BlockProducer>,
+    /// Block producers for the additional validator accounts operated by this node.
+    pub additional_block_producers: Vec<BlockProducer>,
     pub genesis_config: GenesisConfig,
 }
 
@@ -208,7 +433,11 @@ impl NearConfig {
                 rpc_addr: config.rpc.addr.clone(),
                 min_block_production_delay: config.consensus.min_block_production_delay,
                 max_block_production_delay: config.consensus.max_block_production_delay,
+                adaptive_block_production: config.consensus.adaptive_block_production,
                 block_expected_weight: 1000,
+                block_max_gas: config.consensus.block_max_gas,
+                block_max_size: config.consensus.block_max_size,
+                block_assembly_time: config.consensus.min_block_production_delay / 2,
                 skip_sync_wait: config.network.skip_sync_wait,
                 sync_check_period: Duration::from_secs(10),
                 sync_step_period: Duration::from_millis(10),
@@ -218,13 +447,23 @@ impl NearConfig {
                 fetch_info_period: Duration::from_millis(100),
                 log_summary_period: Duration::from_secs(10),
                 produce_empty_blocks: config.consensus.produce_empty_blocks,
+                max_idle_blocks: config.consensus.max_idle_blocks,
                 epoch_length: genesis_config.epoch_length,
                 announce_account_horizon: genesis_config.epoch_length / 2,
                 // TODO(1047): this should be adjusted depending on the speed of sync of state.
                 block_fetch_horizon: 50,
                 state_fetch_horizon: 5,
                 block_header_fetch_horizon: 50,
-                transaction_validity_period: genesis_config.transaction_validity_period,
+                tracked_shards: config.tracked_shards.clone(),
+                max_reorg_depth: Some(config.consensus.max_reorg_depth),
+                gc_num_epochs_to_keep: config.store.gc_num_epochs_to_keep,
+                tx_pool: config.tx_pool.clone(),
+                sync: config.sync.clone(),
+                tx_rebroadcast_delay: config.consensus.tx_rebroadcast_delay,
+                catchup_batch_size: 50,
+                light_client: false,
+                archive_dir: None,
+                validator_key_files: HashMap::new(),
             },
             network_config: NetworkConfig {
                 public_key: network_key_pair.public_key.into(),
@@ -251,15 +490,69 @@ impl NearConfig {
                 peer_max_count: config.network.max_peers,
                 ban_window: config.network.ban_window,
                 max_send_peers: 512,
+                peers_request_period: Duration::from_secs(60),
+                ping_period: config.network.ping_period,
+                peer_silence_timeout: config.network.peer_silence_timeout,
                 peer_expiration_duration: Duration::from_secs(7 * 24 * 60 * 60),
                 peer_stats_period: Duration::from_secs(5),
+                max_message_size: config.network.max_message_size,
+                min_peer_protocol_version: config.network.min_peer_protocol_version,
+                ban_policy: config.network.ban_policy.clone(),
+                blacklist: config
+                    .network
+                    .blacklist
+                    .iter()
+                    .map(|entry| entry.as_str().try_into().expect("Failed to parse blacklist"))
+                    .collect(),
+                whitelist: config
+                    .network
+                    .whitelist
+                    .iter()
+                    .map(|entry| entry.as_str().try_into().expect("Failed to parse whitelist"))
+                    .collect(),
+                sentries: if config.network.sentries.is_empty() {
+                    vec![]
+                } else {
+                    config
+                        .network
+                        .sentries
+                        .split(",")
+                        .map(|chunk| chunk.try_into().expect("Failed to parse PeerInfo"))
+                        .collect()
+                },
+                enable_compression: config.network.enable_compression,
+                enable_encryption: config.network.enable_encryption,
+                require_encryption: config.network.require_encryption,
+                external_address: if config.network.external_address.is_empty() {
+                    None
+                } else {
+                    Some(config.network.external_address.parse().unwrap())
+                },
+                upnp: config.network.upnp,
+                bandwidth: config.network.bandwidth,
+                proxy: if config.network.proxy.is_empty() {
+                    None
+                } else {
+                    Some(config.network.proxy.parse().unwrap())
+                },
             },
             telemetry_config: config.telemetry,
             rpc_config: config.rpc,
+            store_config: config.store,
             genesis_config: genesis_config.clone(),
             block_producer,
+            additional_block_producers: vec![],
         }
     }
+
+    /// All block producers of this node, the main validator key first.
+    pub fn block_producers(&self) -> Vec<BlockProducer> {
+        self.block_producer
+            .iter()
+            .chain(self.additional_block_producers.iter())
+            .cloned()
+            .collect()
+    }
 }
 
 impl NearConfig {
@@ -273,6 +566,10 @@ impl NearConfig {
         if let Some(block_producer) = &self.block_producer {
             block_producer.signer.write_to_file(&dir.join(self.config.validator_key_file.clone()));
         }
+        let key_files = self.config.additional_validator_key_files.iter();
+        for (block_producer, key_file) in self.additional_block_producers.iter().zip(key_files) {
+            block_producer.signer.write_to_file(&dir.join(key_file));
+        }
 
         let network_signer =
             InMemorySigner::from_secret_key("".to_string(), self.network_config.secret_key.clone());
@@ -318,8 +615,12 @@ pub struct GenesisConfig {
     pub validators: Vec<AccountInfo>,
     /// Records in storage per each shard at genesis.
     pub records: Vec<Vec<StateRecord>>,
-    /// Number of blocks for which a given transaction is valid
-    pub transaction_validity_period: u64,
+    /// Number of blocks for which a given transaction is valid. It's part of the genesis hash,
+    /// so changing it for a running chain means starting a new one, there is no migration.
+    pub transaction_validity_period: BlockIndex,
+    /// Hash of the content the config was parsed from, see `hash`.
+    #[serde(skip)]
+    source_hash: Option<CryptoHash>,
 }
 
 impl GenesisConfig {
@@ -369,6 +670,7 @@ impl GenesisConfig {
             validators,
             records,
             transaction_validity_period: TRANSACTION_VALIDITY_PERIOD,
+            source_hash: None,
         }
     }
 
@@ -415,6 +717,7 @@ impl GenesisConfig {
             validators,
             records: vec![records],
             transaction_validity_period: TRANSACTION_VALIDITY_PERIOD,
+            source_hash: None,
         }
     }
 
@@ -435,16 +738,29 @@ impl GenesisConfig {
             panic!("Failed to write a genesis config file {}", err);
         }
     }
+
+    /// Hash of the genesis file the config was read from, identifies the network together with
+    /// the chain id. Configs built in memory are hashed as the file `write_to_file` creates.
+    pub fn hash(&self) -> CryptoHash {
+        self.source_hash.unwrap_or_else(|| {
+            hash(
+                serde_json::to_string_pretty(self)
+                    .expect("Error serializing the genesis config.")
+                    .as_bytes(),
+            )
+        })
+    }
 }
 
 impl From<&str> for GenesisConfig {
-    fn from(config: &str) -> Self {
-        let config: GenesisConfig =
-            serde_json::from_str(config).expect("Failed to deserialize the genesis config.");
-        if config.protocol_version != PROTOCOL_VERSION {
+    fn from(content: &str) -> Self {
+        let mut config: GenesisConfig =
+            serde_json::from_str(content).expect("Failed to deserialize the genesis config.");
+        config.source_hash = Some(hash(content.as_bytes()));
+        if !is_supported_genesis_version(config.protocol_version) {
             panic!(format!(
-                "Incorrect version of genesis config {} expected {}",
-                config.protocol_version, PROTOCOL_VERSION
+                "Incorrect version of genesis config {} expected {} to {}",
+                config.protocol_version, MIN_GENESIS_PROTOCOL_VERSION, PROTOCOL_VERSION
             ));
         }
         config
@@ -489,6 +805,96 @@ pub fn testnet_genesis() -> GenesisConfig {
     )
 }
 
+/// Downloads file from given url and returns its content.
+pub fn download_file(url: &str) -> Result<String, String> {
+    let url = url.to_string();
+    System::new("download").block_on(lazy(move || {
+        Client::build()
+            .timeout(DOWNLOAD_TIMEOUT)
+            .finish()
+            .get(&url)
+            .send()
+            .map_err(|err| err.to_string())
+            .and_then(move |mut response| {
+                let status = response.status();
+                response.body().limit(MAX_DOWNLOAD_SIZE).map_err(|err| err.to_string()).and_then(
+                    move |body| {
+                        if !status.is_success() {
+                            return Err(format!("Failed to download {}: {}", url, status));
+                        }
+                        String::from_utf8(body.to_vec()).map_err(|err| err.to_string())
+                    },
+                )
+            })
+    }))
+}
+
+/// Reads genesis config from given file path or downloads it if http(s) url is given.
+pub fn load_genesis_from_source(source: &str) -> GenesisConfig {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        info!(target: "near", "Downloading genesis config from {}", source);
+        let content = download_file(source)
+            .unwrap_or_else(|err| panic!("Failed to download genesis config: {}", err));
+        GenesisConfig::from(content.as_str())
+    } else {
+        GenesisConfig::from_file(&PathBuf::from(source))
+    }
+}
+
+/// Initializes configs to join existing network, with genesis from given path or url and
+/// config either downloaded for given chain or the default one.
+fn init_configs_from_network(
+    dir: &Path,
+    chain_id: Option<String>,
+    account_id: Option<&str>,
+    genesis: Option<&str>,
+    download_config: bool,
+) {
+    let network_url = |filename: &str| match &chain_id {
+        Some(chain_id) => format!("{}/{}/{}", NETWORK_CONFIG_URL, chain_id, filename),
+        None => panic!("Chain ID is required to download {}", filename),
+    };
+    let genesis_config = match genesis {
+        Some(source) => load_genesis_from_source(source),
+        None => load_genesis_from_source(&network_url(GENESIS_CONFIG_FILENAME)),
+    };
+    if let Some(chain_id) = &chain_id {
+        if chain_id != &genesis_config.chain_id {
+            panic!(
+                "Chain ID {} does not match chain ID {} from the genesis config",
+                chain_id, genesis_config.chain_id
+            );
+        }
+    }
+    let config = if download_config {
+        let url = network_url(CONFIG_FILENAME);
+        info!(target: "near", "Downloading config from {}", url);
+        let content = download_file(&url)
+            .unwrap_or_else(|err| panic!("Failed to download config: {}", err));
+        Config::from(content.as_str())
+    } else {
+        let mut config = Config::default();
+        config.telemetry.endpoints.push(DEFAULT_TELEMETRY_URL.to_string());
+        config
+    };
+    config.write_to_file(&dir.join(CONFIG_FILENAME));
+
+    // If account id was given, create new key pair for this validator.
+    if let Some(account_id) =
+        account_id.and_then(|x| if x.is_empty() { None } else { Some(x.to_string()) })
+    {
+        let signer = InMemorySigner::from_random(account_id.clone(), KeyType::ED25519);
+        info!(target: "near", "Use key {} for {} to stake.", signer.public_key, account_id);
+        signer.write_to_file(&dir.join(&config.validator_key_file));
+    }
+
+    let network_signer = InMemorySigner::from_random("".to_string(), KeyType::ED25519);
+    network_signer.write_to_file(&dir.join(&config.node_key_file));
+
+    genesis_config.write_to_file(&dir.join(&config.genesis_file));
+    info!(target: "near", "Generated node key and genesis file for {} in {}", genesis_config.chain_id, dir.to_str().unwrap());
+}
+
 /// Initializes genesis and client configs and stores in the given folder
 pub fn init_configs(
     dir: &Path,
@@ -496,6 +902,8 @@ pub fn init_configs(
     account_id: Option<&str>,
     test_seed: Option<&str>,
     fast: bool,
+    genesis: Option<&str>,
+    download_config: bool,
 ) {
     fs::create_dir_all(dir).expect("Failed to create directory");
     // Check if config already exists in home dir.
@@ -504,6 +912,15 @@ pub fn init_configs(
         let genesis_config = GenesisConfig::from_file(&dir.join(config.genesis_file));
         panic!("Found existing config in {} with chain-id = {}. Use unsafe_reset_all to clear the folder.", dir.to_str().unwrap(), genesis_config.chain_id);
     }
+    if genesis.is_some() || download_config {
+        if test_seed.is_some() {
+            panic!("Test seed is not supported when joining existing network");
+        }
+        let chain_id =
+            chain_id.and_then(|c| if c.is_empty() { None } else { Some(c.to_string()) });
+        init_configs_from_network(dir, chain_id, account_id, genesis, download_config);
+        return;
+    }
     let chain_id = chain_id
         .and_then(|c| if c.is_empty() { None } else { Some(c.to_string()) })
         .unwrap_or(random_chain_id());
@@ -586,6 +1003,7 @@ pub fn init_configs(
                     CryptoHash::default(),
                 )],
                 transaction_validity_period: TRANSACTION_VALIDITY_PERIOD,
+                source_hash: None,
             };
             genesis_config.write_to_file(&dir.join(config.genesis_file));
             info!(target: "near", "Generated node key, validator key, genesis file in {}", dir.to_str().unwrap());
@@ -643,6 +1061,7 @@ pub fn create_testnet_configs_from_seeds(
         validators,
         records,
         transaction_validity_period: TRANSACTION_VALIDITY_PERIOD,
+        source_hash: None,
     };
     let mut configs = vec![];
     let first_node_port = open_port();
@@ -652,6 +1071,7 @@ pub fn create_testnet_configs_from_seeds(
             config.network.addr =
                 format!("127.0.0.1:{}", if i == 0 { first_node_port } else { open_port() });
             config.rpc.addr = format!("127.0.0.1:{}", open_port());
+            config.rpc.admin_addr = format!("127.0.0.1:{}", open_port());
             config.network.boot_nodes = if i == 0 {
                 "".to_string()
             } else {
@@ -683,14 +1103,17 @@ pub fn create_testnet_configs(
     )
 }
 
+/// Writes testnet configuration for each node into `<dir>/<prefix><i>`.
+/// If `local_ports` is true, nodes are set up to connect to each other on the local machine.
 pub fn init_testnet_configs(
     dir: &Path,
     num_validators: usize,
     num_non_validators: usize,
     prefix: &str,
+    local_ports: bool,
 ) {
     let (configs, signers, network_signers, genesis_config) =
-        create_testnet_configs(num_validators, num_non_validators, prefix, false);
+        create_testnet_configs(num_validators, num_non_validators, prefix, local_ports);
     for i in 0..(num_validators + num_non_validators) {
         let node_dir = dir.join(format!("{}{}", prefix, i));
         fs::create_dir_all(node_dir.clone()).expect("Failed to create directory");
@@ -704,18 +1127,512 @@ pub fn init_testnet_configs(
     }
 }
 
-pub fn load_config(dir: &Path) -> NearConfig {
+/// Generates new ED25519 key for given account and writes it into the key file at given path.
+/// Panics if the file already exists, to never overwrite existing keys.
+pub fn generate_key_file(path: &Path, account_id: &str) -> InMemorySigner {
+    if path.exists() {
+        panic!("Key file {} already exists", path.to_str().unwrap());
+    }
+    let signer = InMemorySigner::from_random(account_id.to_string(), KeyType::ED25519);
+    signer.write_to_file(path);
+    signer
+}
+
+/// Replaces validator key in the home directory with newly generated key for the same account.
+/// Previous key is kept next to the validator key file with `.old` suffix, numbered if the key
+/// was already rotated.
+/// If `stake` is given as (amount, nonce, block hash), returns staking transaction signed with the
+/// previous key that switches validator to the new key.
+pub fn rotate_validator_key(
+    dir: &Path,
+    stake: Option<(Balance, Nonce, CryptoHash)>,
+) -> (InMemorySigner, Option<SignedTransaction>) {
     let config = Config::from_file(&dir.join(CONFIG_FILENAME));
-    let genesis_config = GenesisConfig::from_file(&dir.join(config.genesis_file.clone()));
-    let block_producer = if dir.join(config.validator_key_file.clone()).exists() {
-        let signer =
-            Arc::new(InMemorySigner::from_file(&dir.join(config.validator_key_file.clone())));
-        Some(BlockProducer::from(signer))
+    let key_path = dir.join(config.validator_key_file);
+    if !key_path.exists() {
+        panic!("Validator key file {} doesn't exist", key_path.to_str().unwrap());
+    }
+    let old_signer = InMemorySigner::from_file(&key_path);
+    let signer = InMemorySigner::from_random(old_signer.account_id.clone(), KeyType::ED25519);
+    if let Err(err) = KeyFile::from(&signer).rotate_file(&key_path) {
+        panic!("Failed to rotate validator key {}: {}", key_path.display(), err);
+    }
+    info!(target: "near", "Rotated validator key for {}: {} -> {}", signer.account_id, old_signer.public_key, signer.public_key);
+
+    let transaction = stake.map(|(amount, nonce, block_hash)| {
+        SignedTransaction::from_actions(
+            nonce,
+            old_signer.account_id.clone(),
+            old_signer.account_id.clone(),
+            Arc::new(old_signer),
+            vec![Action::Stake(StakeAction { stake: amount, public_key: signer.public_key })],
+            block_hash,
+        )
+    });
+    (signer, transaction)
+}
+
+/// Reads and deserializes JSON file, returning readable error on failure.
+fn read_json_file<T: DeserializeOwned>(path: &Path, what: &str) -> Result<T, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read {} from {}: {}", what, path.display(), err))?;
+    serde_json::from_str(&content)
+        .map_err(|err| format!("Failed to parse {} from {}: {}", what, path.display(), err))
+}
+
+/// Reads genesis config file, the config is identified by the hash of the file content.
+fn read_genesis_file(path: &Path) -> Result<GenesisConfig, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|err| format!("Failed to read genesis config from {}: {}", path.display(), err))?;
+    let mut genesis_config: GenesisConfig = serde_json::from_str(&content).map_err(|err| {
+        format!("Failed to parse genesis config from {}: {}", path.display(), err)
+    })?;
+    genesis_config.source_hash = Some(hash(content.as_bytes()));
+    Ok(genesis_config)
+}
+
+/// Reads config file from given directory.
+pub fn read_config(dir: &Path) -> Result<Config, String> {
+    read_json_file(&dir.join(CONFIG_FILENAME), "config")
+}
+
+/// Parses log level from the config, e.g. "info" or "debug".
+pub fn parse_log_level(log_level: &str) -> Result<LevelFilter, String> {
+    log_level.parse().map_err(|_| format!("Invalid log level {}", log_level))
+}
+
+/// Checks that key file has matching public and secret keys.
+fn validate_key_file(path: &Path, what: &str) -> Result<KeyFile, String> {
+    let key_file: KeyFile = read_json_file(path, what)?;
+    if key_file.secret_key.public_key() != key_file.public_key {
+        return Err(format!(
+            "Public key {} in {} doesn't match its secret key, regenerate the key file",
+            key_file.public_key,
+            path.display()
+        ));
+    }
+    Ok(key_file)
+}
+
+/// Whether the node supports genesis config of given protocol version.
+fn is_supported_genesis_version(protocol_version: u32) -> bool {
+    protocol_version >= MIN_GENESIS_PROTOCOL_VERSION && protocol_version <= PROTOCOL_VERSION
+}
+
+/// Validates genesis config consistency.
+fn validate_genesis(genesis_config: &GenesisConfig, errors: &mut Vec<String>) {
+    if !is_supported_genesis_version(genesis_config.protocol_version) {
+        errors.push(format!(
+            "Genesis protocol version {} is not supported, expected {} to {}, use genesis for this version of the node",
+            genesis_config.protocol_version, MIN_GENESIS_PROTOCOL_VERSION, PROTOCOL_VERSION
+        ));
+    }
+    if genesis_config.chain_id.is_empty() {
+        errors.push("Genesis chain_id is empty".to_string());
+    }
+    if genesis_config.epoch_length == 0 {
+        errors.push("Genesis epoch_length must be positive".to_string());
+    }
+    if genesis_config.transaction_validity_period == 0 {
+        errors.push("Genesis transaction_validity_period must be positive".to_string());
+    }
+    if genesis_config.validator_kickout_threshold < 0.0
+        || genesis_config.validator_kickout_threshold > 1.0
+    {
+        errors.push(format!(
+            "Genesis validator_kickout_threshold {} must be between 0 and 1",
+            genesis_config.validator_kickout_threshold
+        ));
+    }
+    if genesis_config.num_block_producers == 0 {
+        errors.push("Genesis num_block_producers must be positive".to_string());
+    }
+    if genesis_config.block_producers_per_shard.is_empty() {
+        errors.push("Genesis block_producers_per_shard must have an entry per shard".to_string());
+    }
+    if genesis_config.block_producers_per_shard.len()
+        != genesis_config.avg_fisherman_per_shard.len()
+    {
+        errors.push(format!(
+            "Genesis block_producers_per_shard has {} shards, while avg_fisherman_per_shard has {}",
+            genesis_config.block_producers_per_shard.len(),
+            genesis_config.avg_fisherman_per_shard.len()
+        ));
+    }
+    for (shard_id, num) in genesis_config.block_producers_per_shard.iter().enumerate() {
+        if *num > genesis_config.num_block_producers {
+            errors.push(format!(
+                "Genesis shard {} has {} block producers, more than num_block_producers {}",
+                shard_id, num, genesis_config.num_block_producers
+            ));
+        }
+    }
+    if genesis_config.validators.is_empty() {
+        errors.push("Genesis must have at least one validator".to_string());
+    }
+    for validator in genesis_config.validators.iter() {
+        if validator.amount == 0 {
+            errors.push(format!("Genesis validator {} has zero stake", validator.account_id));
+        }
+        let has_account = genesis_config.records.iter().flatten().any(|record| match record {
+            StateRecord::Account { account_id, .. } => account_id == &validator.account_id,
+            _ => false,
+        });
+        if !has_account {
+            errors.push(format!(
+                "Genesis validator {} doesn't have an account in genesis records",
+                validator.account_id
+            ));
+        }
+        let public_key: Result<PublicKey, _> = validator.public_key.clone().try_into();
+        if public_key.is_err() {
+            errors.push(format!(
+                "Genesis validator {} has invalid public key {:?}",
+                validator.account_id, validator.public_key
+            ));
+        }
+    }
+}
+
+/// Validates config, genesis and key files in the given directory.
+/// Returns list of found problems, each with the description of what to fix.
+pub fn validate_configs(dir: &Path) -> Result<(), Vec<String>> {
+    let mut errors = vec![];
+    let config = match read_config(dir) {
+        Ok(config) => config,
+        Err(err) => return Err(vec![format!("{}. Use `near init` to create config", err)]),
+    };
+
+    let genesis_config: Option<GenesisConfig> =
+        match read_json_file(&dir.join(&config.genesis_file), "genesis config") {
+            Ok(genesis_config) => {
+                validate_genesis(&genesis_config, &mut errors);
+                Some(genesis_config)
+            }
+            Err(err) => {
+                errors.push(err);
+                None
+            }
+        };
+
+    let network_addr: Option<SocketAddr> = if config.network.addr.is_empty() {
+        None
+    } else {
+        match config.network.addr.parse() {
+            Ok(addr) => Some(addr),
+            Err(err) => {
+                errors.push(format!(
+                    "Invalid network addr {}: {}, expected ip:port",
+                    config.network.addr, err
+                ));
+                None
+            }
+        }
+    };
+    if !config.network.external_address.is_empty() {
+        if let Err(err) = config.network.external_address.parse::<SocketAddr>() {
+            errors.push(format!(
+                "Invalid network external address {}: {}, expected ip:port",
+                config.network.external_address, err
+            ));
+        }
+    }
+    if config.network.max_message_size == 0 {
+        errors.push("Network max_message_size must be positive".to_string());
+    }
+    if config.network.max_message_size > u32::max_value() as u64 {
+        errors.push(format!(
+            "Network max_message_size {} must be at most {}",
+            config.network.max_message_size,
+            u32::max_value()
+        ));
+    }
+    if config.consensus.block_max_size >= config.network.max_message_size {
+        errors.push(format!(
+            "Consensus block_max_size {} must be smaller than network max_message_size {}",
+            config.consensus.block_max_size, config.network.max_message_size
+        ));
+    }
+    if config.network.ping_period.as_millis() == 0 {
+        errors.push("Network ping_period must be positive".to_string());
+    }
+    if config.network.peer_silence_timeout <= config.network.ping_period {
+        errors.push(format!(
+            "Network peer_silence_timeout {:?} must be longer than ping_period {:?}",
+            config.network.peer_silence_timeout, config.network.ping_period
+        ));
+    }
+    if !config.network.proxy.is_empty() {
+        if let Err(err) = config.network.proxy.parse::<SocketAddr>() {
+            errors.push(format!(
+                "Invalid network proxy {}: {}, expected ip:port",
+                config.network.proxy, err
+            ));
+        }
+        if config.network.upnp {
+            errors.push("Network upnp can't be enabled together with the proxy".to_string());
+        }
+    }
+    if config.network.require_encryption && !config.network.enable_encryption {
+        errors.push("Network encryption can't be required when it's disabled".to_string());
+    }
+    let rpc_addr: Option<SocketAddr> = match config.rpc.addr.parse() {
+        Ok(addr) => Some(addr),
+        Err(err) => {
+            errors.push(format!("Invalid rpc addr {}: {}, expected ip:port", config.rpc.addr, err));
+            None
+        }
+    };
+    if let (Some(network_addr), Some(rpc_addr)) = (network_addr, rpc_addr) {
+        if network_addr.port() == rpc_addr.port()
+            && (network_addr.ip() == rpc_addr.ip()
+                || network_addr.ip().is_unspecified()
+                || rpc_addr.ip().is_unspecified())
+        {
+            errors.push(format!(
+                "Network addr {} and rpc addr {} use the same port, change one of them",
+                network_addr, rpc_addr
+            ));
+        }
+    }
+    match config.rpc.admin_addr.parse::<SocketAddr>() {
+        Ok(admin_addr) => {
+            if !admin_addr.ip().is_loopback() {
+                errors.push(format!(
+                    "Rpc admin addr {} must be a loopback address, it allows to stop the node",
+                    admin_addr
+                ));
+            }
+            if rpc_addr.map_or(false, |rpc_addr| rpc_addr.port() == admin_addr.port()) {
+                errors.push(format!(
+                    "Rpc addr {} and rpc admin addr {} use the same port, change one of them",
+                    config.rpc.addr, admin_addr
+                ));
+            }
+        }
+        Err(err) => errors.push(format!(
+            "Invalid rpc admin addr {}: {}, expected ip:port",
+            config.rpc.admin_addr, err
+        )),
+    }
+
+    if config.network.min_peer_protocol_version > PROTOCOL_VERSION {
+        errors.push(format!(
+            "Minimum peer protocol version {} is higher than our protocol version {}",
+            config.network.min_peer_protocol_version, PROTOCOL_VERSION
+        ));
+    }
+
+    if config.store.options.block_cache_size_mb == 0 {
+        errors.push("Store block_cache_size_mb must be positive".to_string());
+    }
+    if config.store.options.write_buffer_size_mb == 0 {
+        errors.push("Store write_buffer_size_mb must be positive".to_string());
+    }
+    if config.store.options.max_open_files == 0 || config.store.options.max_open_files < -1 {
+        errors.push(format!(
+            "Invalid store max_open_files {}, expected positive number or -1 for unlimited",
+            config.store.options.max_open_files
+        ));
+    }
+    if config.store.options.commit == CommitPolicy::Async
+        && config.store.options.flush_period_ms == 0
+    {
+        errors.push("Store flush_period_ms must be positive with the async commit".to_string());
+    }
+    if let Some(store_path) = &config.store.path {
+        let store_path = dir.join(store_path);
+        if store_path.exists() && !store_path.is_dir() {
+            errors.push(format!("Store path {} is not a directory", store_path.display()));
+        }
+    }
+    if let Some(cold_path) = &config.store.cold_path {
+        let cold_path = dir.join(cold_path);
+        if cold_path.exists() && !cold_path.is_dir() {
+            errors.push(format!("Store cold path {} is not a directory", cold_path.display()));
+        }
+        if cold_path == dir.join(config.store.path.as_ref().map_or(Path::new(STORE_PATH), |p| p)) {
+            errors.push("Store cold path must differ from the store path".to_string());
+        }
+        if config.store.gc_num_epochs_to_keep.is_none() {
+            errors.push(
+                "Store cold path requires garbage collection, set gc_num_epochs_to_keep"
+                    .to_string(),
+            );
+        }
+        if config.store.options.backend == StoreBackend::Memory {
+            errors.push("Store cold path is not supported by the memory backend".to_string());
+        }
+    }
+    if let Some(log_level) = &config.log_level {
+        if let Err(err) = parse_log_level(log_level) {
+            errors.push(err);
+        }
+    }
+    if let Some(genesis_config) = &genesis_config {
+        let num_shards = genesis_config.block_producers_per_shard.len() as ShardId;
+        for shard_id in config.tracked_shards.iter() {
+            if *shard_id >= num_shards {
+                errors.push(format!(
+                    "Tracked shard {} doesn't exist, genesis has {} shards",
+                    shard_id, num_shards
+                ));
+            }
+        }
+    }
+
+    if !config.network.boot_nodes.is_empty() {
+        for boot_node in config.network.boot_nodes.split(",") {
+            let peer_info: Result<PeerInfo, _> = boot_node.try_into();
+            if let Err(err) = peer_info {
+                errors.push(format!("Invalid boot node {}: {}", boot_node, err));
+            }
+        }
+    }
+    if !config.network.sentries.is_empty() {
+        for sentry in config.network.sentries.split(",") {
+            let peer_info: Result<PeerInfo, _> = sentry.try_into();
+            if let Err(err) = peer_info {
+                errors.push(format!("Invalid sentry {}: {}", sentry, err));
+            }
+        }
+    }
+
+    for (name, entries) in
+        [("blacklist", &config.network.blacklist), ("whitelist", &config.network.whitelist)].iter()
+    {
+        for entry in entries.iter() {
+            let filter_entry: Result<PeerFilterEntry, _> = entry.as_str().try_into();
+            if let Err(err) = filter_entry {
+                errors.push(format!("Invalid {} entry {}: {}", name, entry, err));
+            }
+        }
+    }
+
+    let bandwidth = &config.network.bandwidth;
+    for (name, limit) in [
+        ("max_upload_bytes_per_sec", bandwidth.max_upload_bytes_per_sec),
+        ("max_download_bytes_per_sec", bandwidth.max_download_bytes_per_sec),
+        ("max_peer_upload_bytes_per_sec", bandwidth.max_peer_upload_bytes_per_sec),
+        ("max_peer_download_bytes_per_sec", bandwidth.max_peer_download_bytes_per_sec),
+    ]
+    .iter()
+    {
+        if *limit == Some(0) {
+            errors.push(format!("Bandwidth limit {} must be positive", name));
+        }
+    }
+
+    if let Err(err) = validate_key_file(&dir.join(&config.node_key_file), "node key") {
+        errors.push(err);
+    }
+    let validator_key_path = dir.join(&config.validator_key_file);
+    if validator_key_path.exists() {
+        match validate_key_file(&validator_key_path, "validator key") {
+            Ok(key_file) => {
+                let validator = genesis_config.as_ref().and_then(|genesis_config| {
+                    genesis_config
+                        .validators
+                        .iter()
+                        .find(|validator| validator.account_id == key_file.account_id)
+                });
+                if let Some(validator) = validator {
+                    let public_key: Result<PublicKey, _> = validator.public_key.clone().try_into();
+                    if let Ok(public_key) = public_key {
+                        // After a rotation the genesis key is one of the previous keys.
+                        let is_rotated = rotated_key_paths(&validator_key_path)
+                            .take_while(|path| path.exists())
+                            .any(|path| {
+                                read_json_file::<KeyFile>(&path, "previous validator key")
+                                    .map_or(false, |old_key| old_key.public_key == public_key)
+                            });
+                        if public_key != key_file.public_key && !is_rotated {
+                            errors.push(format!(
+                                "Validator key {} for {} doesn't match genesis validator key {}, check {}",
+                                key_file.public_key,
+                                key_file.account_id,
+                                public_key,
+                                validator_key_path.display()
+                            ));
+                        }
+                    }
+                }
+            }
+            Err(err) => errors.push(err),
+        }
+    }
+    let mut validator_accounts = HashSet::new();
+    for key_file_name in
+        iter::once(&config.validator_key_file).chain(config.additional_validator_key_files.iter())
+    {
+        let key_file_path = dir.join(key_file_name);
+        if !key_file_path.exists() {
+            if key_file_name != &config.validator_key_file {
+                errors.push(format!(
+                    "Additional validator key file {} doesn't exist",
+                    key_file_path.display()
+                ));
+            }
+            continue;
+        }
+        match validate_key_file(&key_file_path, "validator key") {
+            Ok(key_file) => {
+                if !validator_accounts.insert(key_file.account_id.clone()) {
+                    errors.push(format!(
+                        "Validator account {} is configured more than once, check {}",
+                        key_file.account_id,
+                        key_file_path.display()
+                    ));
+                }
+            }
+            // Errors of the main validator key are already reported above.
+            Err(err) if key_file_name != &config.validator_key_file => errors.push(err),
+            Err(_) => {}
+        }
+    }
+    if config.store.options.commit == CommitPolicy::Async && !validator_accounts.is_empty() {
+        errors.push(
+            "Store async commit is not allowed on a node with validator keys, signed blocks and approvals must be on the disk before they are sent"
+                .to_string(),
+        );
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Loads configs from given directory, returns the found problems if they are not valid.
+pub fn load_config(dir: &Path) -> Result<NearConfig, Vec<String>> {
+    validate_configs(dir)?;
+    let config = read_config(dir).map_err(|err| vec![err])?;
+    let genesis_config =
+        read_genesis_file(&dir.join(&config.genesis_file)).map_err(|err| vec![err])?;
+    let mut validator_key_files = HashMap::new();
+    let mut read_block_producer = |key_file: &str| -> Result<BlockProducer, Vec<String>> {
+        let key_path = dir.join(key_file);
+        let key_file = validate_key_file(&key_path, "validator key").map_err(|err| vec![err])?;
+        validator_key_files.insert(key_file.account_id.clone(), key_path);
+        Ok(BlockProducer::from(Arc::new(InMemorySigner::from(key_file))))
+    };
+    let block_producer = if dir.join(&config.validator_key_file).exists() {
+        Some(read_block_producer(&config.validator_key_file)?)
     } else {
         None
     };
-    let network_signer = InMemorySigner::from_file(&dir.join(config.node_key_file.clone()));
-    NearConfig::new(config, &genesis_config, (&network_signer).into(), block_producer)
+    let additional_block_producers = config
+        .additional_validator_key_files
+        .iter()
+        .map(|key_file| read_block_producer(key_file))
+        .collect::<Result<Vec<_>, _>>()?;
+    let network_key =
+        validate_key_file(&dir.join(&config.node_key_file), "node key").map_err(|err| vec![err])?;
+    let mut near_config = NearConfig::new(config, &genesis_config, network_key, block_producer);
+    near_config.additional_block_producers = additional_block_producers;
+    near_config.client_config.validator_key_files = validator_key_files;
+    Ok(near_config)
 }
 
 pub fn load_test_config(seed: &str, port: u16, genesis_config: &GenesisConfig) -> NearConfig {
@@ -723,13 +1640,19 @@ pub fn load_test_config(seed: &str, port: u16, genesis_config: &GenesisConfig) -
     config.network.skip_sync_wait = true;
     config.network.addr = format!("0.0.0.0:{}", port);
     config.rpc.addr = format!("0.0.0.0:{}", open_port());
+    config.rpc.admin_addr = format!("127.0.0.1:{}", open_port());
     config.consensus.min_block_production_delay =
         Duration::from_millis(FAST_MIN_BLOCK_PRODUCTION_DELAY);
     config.consensus.max_block_production_delay =
         Duration::from_millis(FAST_MAX_BLOCK_PRODUCTION_DELAY);
     let signer = Arc::new(InMemorySigner::from_seed(seed, KeyType::ED25519, seed));
     let block_producer = BlockProducer::from(signer.clone());
-    NearConfig::new(config, &genesis_config, signer.into(), Some(block_producer))
+    let mut near_config =
+        NearConfig::new(config, &genesis_config, signer.into(), Some(block_producer));
+    // Nodes started in the test process keep their data in memory, nodes started as separate
+    // processes from the saved config keep it on the disk.
+    near_config.store_config.options.backend = StoreBackend::Memory;
+    near_config
 }
 
 #[cfg(test)]
@@ -737,6 +1660,10 @@ mod tests {
     use serde_json::json;
 
     use near_crypto::ReadablePublicKey;
+    use near_network::types::{PeerId, ReasonForBan};
+    use near_store::create_store;
+
+    use crate::get_configured_store_path;
 
     use super::*;
 
@@ -766,5 +1693,317 @@ mod tests {
                 amount: 50
             }
         );
+        assert_eq!(spec.transaction_validity_period, 100);
+    }
+
+    #[test]
+    fn test_deserialize_legacy_wasm_limits() {
+        let default_config = RuntimeConfig::default().wasm_config;
+        let mut wasm_config = serde_json::to_value(&default_config).unwrap();
+        let fields = wasm_config.as_object_mut().unwrap();
+        for limit in &["max_gas_per_block", "max_receipt_depth", "max_storage_key_len"] {
+            fields.remove(*limit);
+        }
+        fields.insert("max_log_len".to_string(), json!(10));
+        let data = json!({ "wasm_config": wasm_config });
+        let runtime_config: RuntimeConfig = serde_json::from_value(data).unwrap();
+        let limits = runtime_config.wasm_config.limits;
+        assert_eq!(limits.max_log_len, 10);
+        assert_eq!(limits.max_receipt_depth, default_config.limits.max_receipt_depth);
+        assert_eq!(limits.max_storage_key_len, default_config.limits.max_storage_key_len);
+    }
+
+    #[test]
+    fn test_deserialize_runtime_config_v3() {
+        // Runtime config of the testnet genesis of protocol version 3, before the runtime limits,
+        // storage iterators and batched storage calls were added.
+        let data = include_str!("../tests/res/runtime_config_v3.json");
+        let runtime_config: RuntimeConfig = serde_json::from_str(data).unwrap();
+        let wasm_config = runtime_config.wasm_config;
+        let default_config = RuntimeConfig::default().wasm_config;
+        assert_eq!(wasm_config.max_gas_burnt, 1_000_000_000);
+        assert_eq!(wasm_config.limits.max_log_len, 500);
+        assert_eq!(wasm_config.limits.max_receipt_depth, default_config.limits.max_receipt_depth);
+        assert_eq!(
+            wasm_config.storage_iter_create_base_cost,
+            default_config.storage_iter_create_base_cost
+        );
+        assert_eq!(
+            wasm_config.storage_iter_next_byte_cost,
+            default_config.storage_iter_next_byte_cost
+        );
+        assert_eq!(wasm_config.storage_batch_base_cost, default_config.storage_batch_base_cost);
+        assert_eq!(wasm_config.storage_batch_byte_cost, default_config.storage_batch_byte_cost);
+    }
+
+    #[test]
+    fn test_sync_config() {
+        let config: Config = serde_json::from_value(json!({
+            "sync": {"max_block_requests": 20, "block_request_timeout": {"secs": 5, "nanos": 0}}
+        }))
+        .unwrap();
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let near_config =
+            NearConfig::new(config, &GenesisConfig::test(vec!["test"]), signer.into(), None);
+        let params = near_config.client_config.sync_params();
+        assert_eq!(params.max_block_requests, 20);
+        assert_eq!(params.max_peer_block_requests, SyncConfig::default().max_peer_block_requests);
+        assert_eq!(params.block_request, Duration::from_secs(5));
+        // Timeouts that are not set are derived from the block production delays.
+        assert_eq!(
+            params.block_some_received,
+            near_config.client_config.min_block_production_delay
+        );
+    }
+
+    #[test]
+    fn test_block_limits_config() {
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let genesis_config = GenesisConfig::test(vec!["test"]);
+        let near_config =
+            NearConfig::new(Config::default(), &genesis_config, signer.clone().into(), None);
+        assert_eq!(near_config.client_config.block_max_gas, BLOCK_MAX_GAS);
+        assert_eq!(near_config.client_config.block_max_size, BLOCK_MAX_SIZE);
+
+        let mut consensus = serde_json::to_value(Consensus::default()).unwrap();
+        consensus["block_max_gas"] = json!(1000);
+        consensus["block_max_size"] = json!(2048);
+        let config: Config = serde_json::from_value(json!({ "consensus": consensus })).unwrap();
+        let near_config = NearConfig::new(config, &genesis_config, signer.into(), None);
+        let budget = near_config.client_config.block_budget();
+        assert_eq!((budget.max_gas, budget.max_bytes), (1000, 2048));
+    }
+
+    #[test]
+    fn test_tx_rebroadcast_delay_config() {
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let genesis_config = GenesisConfig::test(vec!["test"]);
+        let mut consensus = serde_json::to_value(Consensus::default()).unwrap();
+        consensus.as_object_mut().unwrap().remove("tx_rebroadcast_delay");
+        let config: Config = serde_json::from_value(json!({ "consensus": consensus })).unwrap();
+        let near_config = NearConfig::new(config, &genesis_config, signer.clone().into(), None);
+        assert_eq!(near_config.client_config.tx_rebroadcast_delay, TX_REBROADCAST_DELAY);
+
+        consensus["tx_rebroadcast_delay"] = json!(20);
+        let config: Config = serde_json::from_value(json!({ "consensus": consensus })).unwrap();
+        let near_config = NearConfig::new(config, &genesis_config, signer.into(), None);
+        assert_eq!(near_config.client_config.tx_rebroadcast_delay, 20);
+    }
+
+    #[test]
+    fn test_ban_policy_config() {
+        let mut network = serde_json::to_value(Network::default()).unwrap();
+        network["ban_policy"] = json!({
+            "temp_ban_threshold": 200,
+            "ban_durations": {"BadBlock": {"secs": 60, "nanos": 0}}
+        });
+        let config: Config = serde_json::from_value(json!({ "network": network })).unwrap();
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let near_config =
+            NearConfig::new(config, &GenesisConfig::test(vec!["test"]), signer.into(), None);
+        let ban_policy = near_config.network_config.ban_policy;
+        assert_eq!(ban_policy.temp_ban_threshold, 200);
+        assert_eq!(ban_policy.major_penalty, BanPolicyConfig::default().major_penalty);
+        assert_eq!(
+            ban_policy.ban_durations.get(&ReasonForBan::BadBlock),
+            Some(&Duration::from_secs(60))
+        );
+    }
+
+    #[test]
+    fn test_peer_blacklist_and_whitelist() {
+        let peer_id: PeerId =
+            InMemorySigner::from_seed("peer", KeyType::ED25519, "peer").public_key.into();
+        let config: Config = serde_json::from_value(json!({
+            "network": {
+                "addr": "0.0.0.0:24567",
+                "external_address": "",
+                "boot_nodes": "",
+                "max_peers": 40,
+                "handshake_timeout": {"secs": 20, "nanos": 0},
+                "reconnect_delay": {"secs": 60, "nanos": 0},
+                "skip_sync_wait": false,
+                "ban_window": {"secs": 10800, "nanos": 0},
+                "blacklist": ["10.0.0.1", "10.0.0.2:24567"],
+                "whitelist": [peer_id.to_string(), "10.0.0.2"]
+            }
+        }))
+        .unwrap();
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let near_config =
+            NearConfig::new(config, &GenesisConfig::test(vec!["test"]), signer.into(), None);
+        let network_config = near_config.network_config;
+        let other_peer_id: PeerId =
+            InMemorySigner::from_seed("other", KeyType::ED25519, "other").public_key.into();
+        let addr = |s: &str| Some(s.parse().unwrap());
+        assert!(network_config.is_peer_allowed(&peer_id, None));
+        assert!(network_config.is_peer_allowed(&other_peer_id, addr("10.0.0.2:24568")));
+        // Blacklist takes precedence over the whitelist.
+        assert!(!network_config.is_peer_allowed(&peer_id, addr("10.0.0.1:24567")));
+        assert!(!network_config.is_peer_allowed(&other_peer_id, addr("10.0.0.2:24567")));
+        assert!(!network_config.is_peer_allowed(&other_peer_id, addr("10.0.0.3:24567")));
+    }
+
+    #[test]
+    fn test_private_validator_sentries() {
+        let sentry_id: PeerId =
+            InMemorySigner::from_seed("sentry", KeyType::ED25519, "sentry").public_key.into();
+        let mut config = Config::default();
+        config.network.sentries = format!("{}@127.0.0.1:24567", sentry_id);
+        let signer = Arc::new(InMemorySigner::from_seed("test", KeyType::ED25519, "test"));
+        let near_config =
+            NearConfig::new(config, &GenesisConfig::test(vec!["test"]), signer.into(), None);
+        let network_config = near_config.network_config;
+        assert!(network_config.is_private());
+        assert!(network_config.is_peer_allowed(&sentry_id, None));
+        let other_peer_id: PeerId =
+            InMemorySigner::from_seed("other", KeyType::ED25519, "other").public_key.into();
+        let addr = Some("127.0.0.1:24568".parse().unwrap());
+        assert!(!network_config.is_peer_allowed(&other_peer_id, addr));
+    }
+
+    #[test]
+    fn test_rotate_validator_key() {
+        let tmp_dir = tempdir::TempDir::new("_test_rotate_validator_key").unwrap();
+        init_configs(
+            tmp_dir.path(),
+            Some("test-chain"),
+            Some("test.near"),
+            None,
+            false,
+            None,
+            false,
+        );
+        let key_path = tmp_dir.path().join(VALIDATOR_KEY_FILE);
+        let old_signer = InMemorySigner::from_file(&key_path);
+        let (signer, transaction) =
+            rotate_validator_key(tmp_dir.path(), Some((100, 1, CryptoHash::default())));
+        assert_eq!(signer.account_id, old_signer.account_id);
+        assert_ne!(signer.public_key, old_signer.public_key);
+        assert_eq!(InMemorySigner::from_file(&key_path).public_key, signer.public_key);
+        let old_key = InMemorySigner::from_file(&tmp_dir.path().join("validator_key.json.old"));
+        assert_eq!(old_key.public_key, old_signer.public_key);
+        let transaction = transaction.unwrap();
+        assert_eq!(transaction.transaction.public_key, old_signer.public_key);
+        assert_eq!(
+            transaction.transaction.actions,
+            vec![Action::Stake(StakeAction { stake: 100, public_key: signer.public_key })]
+        );
+
+        // Rotating again keeps both previous keys.
+        let (new_signer, _) = rotate_validator_key(tmp_dir.path(), None);
+        let old_key = InMemorySigner::from_file(&tmp_dir.path().join("validator_key.json.old"));
+        assert_eq!(old_key.public_key, old_signer.public_key);
+        let old_key = InMemorySigner::from_file(&tmp_dir.path().join("validator_key.json.old.1"));
+        assert_eq!(old_key.public_key, signer.public_key);
+        assert_eq!(InMemorySigner::from_file(&key_path).public_key, new_signer.public_key);
+        // The genesis key is among the previous keys, so the configs are still valid.
+        let near_config = load_config(tmp_dir.path()).unwrap();
+        assert_eq!(near_config.block_producer.unwrap().signer.public_key(), new_signer.public_key);
+    }
+
+    #[test]
+    fn test_validate_configs() {
+        let tmp_dir = tempdir::TempDir::new("_test_validate_configs").unwrap();
+        init_configs(
+            tmp_dir.path(),
+            Some("test-chain"),
+            Some("test.near"),
+            None,
+            false,
+            None,
+            false,
+        );
+        assert!(validate_configs(tmp_dir.path()).is_ok());
+
+        // Genesis configs of the older supported protocol versions are accepted.
+        let mut config = Config::from_file(&tmp_dir.path().join(CONFIG_FILENAME));
+        let genesis_path = tmp_dir.path().join(&config.genesis_file);
+        let mut genesis_config = GenesisConfig::from_file(&genesis_path);
+        genesis_config.protocol_version = MIN_GENESIS_PROTOCOL_VERSION;
+        let mut genesis = serde_json::to_value(&genesis_config).unwrap();
+        genesis["runtime_config"] =
+            serde_json::from_str(include_str!("../tests/res/runtime_config_v3.json")).unwrap();
+        fs::write(&genesis_path, serde_json::to_string_pretty(&genesis).unwrap()).unwrap();
+        assert!(validate_configs(tmp_dir.path()).is_ok());
+        assert!(load_config(tmp_dir.path()).is_ok());
+
+        config.rpc.addr = "127.0.0.1:24567".to_string();
+        config.network.boot_nodes = "invalid".to_string();
+        // Produced blocks wouldn't fit into a message.
+        config.network.max_message_size = config.consensus.block_max_size;
+        // The node has a validator key.
+        config.store.options.commit = CommitPolicy::Async;
+        config.write_to_file(&tmp_dir.path().join(CONFIG_FILENAME));
+        let errors = validate_configs(tmp_dir.path()).unwrap_err();
+        assert_eq!(errors.len(), 4);
+    }
+
+    #[test]
+    fn test_init_configs_from_genesis_file() {
+        let tmp_dir = tempdir::TempDir::new("_test_init_configs_from_genesis_file").unwrap();
+        let genesis_path = tmp_dir.path().join(GENESIS_CONFIG_FILENAME);
+        let mut genesis_config = testnet_genesis();
+        genesis_config.chain_id = "existing-chain".to_string();
+        genesis_config.write_to_file(&genesis_path);
+
+        let node_dir = tmp_dir.path().join("node");
+        init_configs(
+            &node_dir,
+            None,
+            Some("test.near"),
+            None,
+            false,
+            Some(genesis_path.to_str().unwrap()),
+            false,
+        );
+        assert!(validate_configs(&node_dir).is_ok());
+        let config = Config::from_file(&node_dir.join(CONFIG_FILENAME));
+        let genesis_config = GenesisConfig::from_file(&node_dir.join(config.genesis_file));
+        assert_eq!(genesis_config.chain_id, "existing-chain");
+    }
+
+    #[test]
+    fn test_store_genesis_mismatch() {
+        let tmp_dir = tempdir::TempDir::new("_test_store_genesis_mismatch").unwrap();
+        init_configs(
+            tmp_dir.path(),
+            Some("test-chain"),
+            Some("test.near"),
+            None,
+            false,
+            None,
+            false,
+        );
+        let config = Config::from_file(&tmp_dir.path().join(CONFIG_FILENAME));
+        let genesis_path = tmp_dir.path().join(&config.genesis_file);
+        let genesis_config = GenesisConfig::from_file(&genesis_path);
+        assert_eq!(genesis_config.hash(), hash(&fs::read(&genesis_path).unwrap()));
+        let start_chain = |genesis_config: &GenesisConfig| {
+            let store = create_store(&get_configured_store_path(tmp_dir.path(), &config.store));
+            let runtime = Arc::new(crate::NightshadeRuntime::new(
+                tmp_dir.path(),
+                store.clone(),
+                genesis_config.clone(),
+            ));
+            near_chain::Chain::new(store, runtime, genesis_config.genesis_time).map(|_| ())
+        };
+        start_chain(&genesis_config).unwrap();
+        // Config read again from the unchanged file is the same genesis.
+        start_chain(&GenesisConfig::from_file(&genesis_path)).unwrap();
+
+        let mut other_chain = genesis_config.clone();
+        other_chain.chain_id = "other-chain".to_string();
+        let err = start_chain(&other_chain).unwrap_err().kind().to_string();
+        assert!(err.contains("initialized for chain \"test-chain\""), "{}", err);
+
+        let mut other_genesis = genesis_config.clone();
+        other_genesis.epoch_length += 1;
+        let other_path = tmp_dir.path().join("other_genesis.json");
+        other_genesis.write_to_file(&other_path);
+        let other_genesis = GenesisConfig::from_file(&other_path);
+        assert_ne!(other_genesis.hash(), genesis_config.hash());
+        let err = start_chain(&other_genesis).unwrap_err().kind().to_string();
+        assert!(err.contains(&other_genesis.hash().to_string()), "{}", err);
     }
 }
@@ -3,30 +3,49 @@ use std::path::Path;
 use std::sync::Arc;
 
 use actix::{Actor, Addr, AsyncContext};
-use log::info;
+use futures::{Future, Stream};
+use log::{error, info};
 
-use near_client::{ClientActor, ViewClientActor};
+use near_client::{ClientActor, ReloadConfig, Shutdown, ViewClientActor};
 use near_jsonrpc::start_http;
 use near_network::PeerManagerActor;
-use near_store::create_store;
+use near_store::{create_store_with_cold_store, create_store_with_options};
 use near_telemetry::TelemetryActor;
 
 pub use crate::config::{
-    init_configs, load_config, load_test_config, GenesisConfig, NearConfig, NEAR_BASE,
+    init_configs, load_config, load_test_config, GenesisConfig, NearConfig, StoreConfig,
+    NEAR_BASE,
 };
-pub use crate::runtime::NightshadeRuntime;
+use crate::config::parse_log_level;
+use crate::logging::set_log_level;
+use crate::reload::ConfigReloader;
+pub use crate::runtime::{simulate_validator_schedule, NightshadeRuntime};
+pub use crate::validator_manager::EpochSchedule;
 
+pub mod backup;
 pub mod config;
+pub mod logging;
+mod reload;
+pub mod replay;
 mod runtime;
+pub mod snapshot;
 #[cfg(test)]
 mod test_utils;
 mod validator_manager;
 
 const STORE_PATH: &str = "data";
 
+/// Returns path to the database in `data` directory under given home.
 pub fn get_store_path(base_path: &Path) -> String {
-    let mut store_path = base_path.to_owned();
-    store_path.push(STORE_PATH);
+    get_configured_store_path(base_path, &StoreConfig::default())
+}
+
+/// Returns path to the database set in store config, relative paths are resolved against given home.
+pub fn get_configured_store_path(base_path: &Path, store_config: &StoreConfig) -> String {
+    let store_path = match &store_config.path {
+        Some(path) => base_path.join(path),
+        None => base_path.join(STORE_PATH),
+    };
     match fs::canonicalize(store_path.clone()) {
         Ok(path) => info!(target: "near", "Opening store database at {:?}", path),
         _ => info!(target: "near", "Did not find {:?} path, will be creating new store database", store_path),
@@ -34,6 +53,16 @@ pub fn get_store_path(base_path: &Path) -> String {
     store_path.to_str().unwrap().to_owned()
 }
 
+/// Returns path to the cold store set in store config, relative paths are resolved against given
+/// home. `None` if the node doesn't keep the cold store.
+pub fn get_cold_store_path(base_path: &Path, store_config: &StoreConfig) -> Option<String> {
+    store_config.cold_path.as_ref().map(|path| {
+        let cold_path = base_path.join(path);
+        info!(target: "near", "Opening cold store database at {:?}", cold_path);
+        cold_path.to_str().unwrap().to_owned()
+    })
+}
+
 pub fn get_default_home() -> String {
     match std::env::var("NEAR_HOME") {
         Ok(home) => home,
@@ -47,33 +76,109 @@ pub fn get_default_home() -> String {
     }
 }
 
+/// Gracefully stops the nodes on SIGINT or SIGTERM.
+/// Must be called from within running actix system.
+#[cfg(unix)]
+pub fn stop_on_signals(clients: Vec<Addr<ClientActor>>) {
+    use tokio_signal::unix::{Signal, SIGTERM};
+
+    let ctrl_c = tokio_signal::ctrl_c().flatten_stream();
+    let sigterm = Signal::new(SIGTERM).flatten_stream().map(|_| ());
+    actix::spawn(ctrl_c.select(sigterm).into_future().then(move |_| {
+        info!(target: "near", "Received stop signal, shutting down");
+        for client in clients {
+            client.do_send(Shutdown {});
+        }
+        Ok(())
+    }));
+}
+
+#[cfg(not(unix))]
+pub fn stop_on_signals(clients: Vec<Addr<ClientActor>>) {
+    actix::spawn(tokio_signal::ctrl_c().flatten_stream().into_future().then(move |_| {
+        info!(target: "near", "Received stop signal, shutting down");
+        for client in clients {
+            client.do_send(Shutdown {});
+        }
+        Ok(())
+    }));
+}
+
+/// Reloads config on SIGHUP.
+/// Must be called from within running actix system.
+#[cfg(unix)]
+fn reload_on_signals(config_reloader: Addr<ConfigReloader>) {
+    use tokio_signal::unix::{Signal, SIGHUP};
+
+    actix::spawn(
+        Signal::new(SIGHUP)
+            .flatten_stream()
+            .map_err(|err| error!(target: "near", "Failed to listen to SIGHUP: {}", err))
+            .for_each(move |_| {
+                config_reloader.send(ReloadConfig {}).then(|res| {
+                    match res {
+                        Ok(Ok(())) => info!(target: "near", "Config reloaded"),
+                        Ok(Err(err)) => error!(target: "near", "Failed to reload config: {}", err),
+                        Err(err) => error!(target: "near", "Failed to reload config: {}", err),
+                    }
+                    Ok(())
+                })
+            }),
+    );
+}
+
+#[cfg(not(unix))]
+fn reload_on_signals(_config_reloader: Addr<ConfigReloader>) {}
+
 pub fn start_with_config(
     home_dir: &Path,
     config: NearConfig,
 ) -> (Addr<ClientActor>, Addr<ViewClientActor>) {
-    let store = create_store(&get_store_path(home_dir));
+    let store_path = get_configured_store_path(home_dir, &config.store_config);
+    let store = match get_cold_store_path(home_dir, &config.store_config) {
+        Some(cold_path) => {
+            create_store_with_cold_store(&store_path, &cold_path, &config.store_config.options)
+        }
+        None => create_store_with_options(&store_path, &config.store_config.options),
+    };
     let runtime =
         Arc::new(NightshadeRuntime::new(home_dir, store.clone(), config.genesis_config.clone()));
 
+    if let Some(log_level) = &config.config.log_level {
+        set_log_level(parse_log_level(log_level).expect("Failed to parse log level"));
+    }
+
     let telemetry = TelemetryActor::new(config.telemetry_config.clone()).start();
 
     let view_client = ViewClientActor::new(
         store.clone(),
         config.genesis_config.genesis_time.clone(),
         runtime.clone(),
-        config.genesis_config.transaction_validity_period,
     )
     .unwrap()
     .start();
     let view_client1 = view_client.clone();
     let node_id = config.network_config.public_key.clone().into();
+    let home_dir = home_dir.to_path_buf();
+    // Light client doesn't have the state, so it can't produce blocks.
+    let block_producers =
+        if config.client_config.light_client { vec![] } else { config.block_producers() };
     let client = ClientActor::create(move |ctx| {
         let network_actor =
             PeerManagerActor::new(store.clone(), config.network_config, ctx.address().recipient())
                 .unwrap()
                 .start();
 
-        start_http(config.rpc_config, ctx.address(), view_client1);
+        let config_reloader =
+            ConfigReloader::new(home_dir, ctx.address(), telemetry.clone()).start();
+        reload_on_signals(config_reloader.clone());
+
+        start_http(
+            config.rpc_config,
+            ctx.address(),
+            view_client1,
+            Some(config_reloader.recipient()),
+        );
 
         ClientActor::new(
             config.client_config,
@@ -82,7 +187,7 @@ pub fn start_with_config(
             runtime,
             node_id,
             network_actor.recipient(),
-            config.block_producer,
+            block_producers,
             telemetry,
         )
         .unwrap()
@@ -0,0 +1,104 @@
+//! Logger of the node. By default each target has its own level, `log_level` of the config
+//! overrides the level of all targets and can be changed at runtime.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Value of the level override when the levels of the targets are used.
+const NO_OVERRIDE: usize = usize::max_value();
+
+/// Level of all targets, set from the config.
+static LEVEL_OVERRIDE: AtomicUsize = AtomicUsize::new(NO_OVERRIDE);
+
+/// Logs with the default levels of the targets, or with the overridden level for all of them.
+struct NodeLogger {
+    default: env_logger::Logger,
+    all: env_logger::Logger,
+}
+
+impl NodeLogger {
+    fn logger(&self, metadata: &Metadata) -> Option<&env_logger::Logger> {
+        match LEVEL_OVERRIDE.load(Ordering::Relaxed) {
+            NO_OVERRIDE => Some(&self.default),
+            level if metadata.level() as usize <= level => Some(&self.all),
+            _ => None,
+        }
+    }
+}
+
+impl Log for NodeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.logger(metadata).map_or(false, |logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = self.logger(record.metadata()) {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.default.flush();
+    }
+}
+
+/// Builder that only silences the dependencies that are too noisy at any level.
+fn base_builder() -> env_logger::Builder {
+    let mut builder = env_logger::Builder::new();
+    builder
+        .filter_module("tokio_reactor", LevelFilter::Info)
+        .filter_module("cranelift_codegen", LevelFilter::Warn)
+        .filter_module("cranelift_wasm", LevelFilter::Warn);
+    builder
+}
+
+pub fn init_logging(verbose: bool) {
+    let mut default = base_builder();
+    if verbose {
+        default.filter(None, LevelFilter::Debug);
+    } else {
+        default
+            .filter(Some("near"), LevelFilter::Info)
+            .filter(Some("info"), LevelFilter::Info)
+            .filter(None, LevelFilter::Warn);
+    }
+    let default = default.build();
+    let all = base_builder().filter(None, LevelFilter::Trace).build();
+    let max_level = default.filter();
+    log::set_boxed_logger(Box::new(NodeLogger { default, all }))
+        .expect("Logger is already initialized");
+    log::set_max_level(max_level);
+}
+
+/// Logs all targets at given level instead of their default levels.
+pub fn set_log_level(level: LevelFilter) {
+    LEVEL_OVERRIDE.store(level as usize, Ordering::Relaxed);
+    log::set_max_level(level);
+}
+
+#[cfg(test)]
+mod tests {
+    use log::{Level, MetadataBuilder};
+
+    use super::*;
+
+    #[test]
+    fn test_log_level_override() {
+        let logger = NodeLogger {
+            default: base_builder().filter(None, LevelFilter::Warn).build(),
+            all: base_builder().filter(None, LevelFilter::Trace).build(),
+        };
+        let debug = MetadataBuilder::new().level(Level::Debug).target("near").build();
+        let reactor_debug =
+            MetadataBuilder::new().level(Level::Debug).target("tokio_reactor").build();
+        assert!(!logger.enabled(&debug));
+
+        set_log_level(LevelFilter::Debug);
+        assert!(logger.enabled(&debug));
+        assert!(!logger.enabled(&reactor_debug));
+        set_log_level(LevelFilter::Info);
+        assert!(!logger.enabled(&debug));
+        LEVEL_OVERRIDE.store(NO_OVERRIDE, Ordering::Relaxed);
+    }
+}
@@ -1,32 +1,57 @@
 use std::convert::TryInto;
-use std::fs;
-use std::path::Path;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use actix::System;
+use actix_web::client::Client;
+use borsh::BorshSerialize;
 use clap::{crate_version, App, Arg, SubCommand};
-use log::{info, LevelFilter};
+use futures::future::{self, Future};
+use log::{error, info, warn};
 
 use git_version::git_version;
-use near::config::init_testnet_configs;
-use near::{get_default_home, get_store_path, init_configs, load_config, start_with_config};
+use near::config::{
+    generate_key_file, init_testnet_configs, read_config, rotate_validator_key, validate_configs,
+    CONFIG_FILENAME, VALIDATOR_KEY_FILE,
+};
+use near::{
+    get_configured_store_path, get_default_home, init_configs, load_config, start_with_config,
+    stop_on_signals, NearConfig, NightshadeRuntime, StoreConfig,
+};
+use near::backup::restore_database;
+use near::logging::init_logging;
+use near::replay::replay_blocks;
+use near::snapshot::{create_snapshot, load_snapshot};
+use near_chain::{export_blocks, reset_chain_data, verify_store, ChainStore, ChainStoreAccess};
+use near_jsonrpc::client::JsonRpcClient;
+use near_primitives::hash::CryptoHash;
+use near_primitives::serialize::to_base64;
 use near_primitives::types::Version;
+use near_store::flat_state::{drop_flat_state, verify_flat_state};
+use near_store::{create_store, create_store_with_options, StoreBackend, COL_PEERS};
 
-fn init_logging(verbose: bool) {
-    if verbose {
-        env_logger::Builder::new()
-            .filter_module("tokio_reactor", LevelFilter::Info)
-            .filter_module("cranelift_codegen", LevelFilter::Warn)
-            .filter_module("cranelift_wasm", LevelFilter::Warn)
-            .filter(None, LevelFilter::Debug)
-            .init();
-    } else {
-        env_logger::Builder::new()
-            .filter_module("tokio_reactor", LevelFilter::Info)
-            .filter(Some("near"), LevelFilter::Info)
-            .filter(Some("info"), LevelFilter::Info)
-            .filter(None, LevelFilter::Warn)
-            .init();
-    }
+/// Time the running node is given to write the database backup.
+const BACKUP_TIMEOUT: Duration = Duration::from_secs(60 * 60);
+
+/// Loads configs from given directory, reports the problems and exits if they are not valid.
+fn load_config_or_exit(dir: &Path) -> NearConfig {
+    load_config(dir).unwrap_or_else(|errors| {
+        for err in errors.iter() {
+            error!(target: "near", "{}", err);
+        }
+        eprintln!("Found {} problems with config in {}", errors.len(), dir.display());
+        std::process::exit(1);
+    })
+}
+
+/// Asks user to confirm the action on the terminal.
+fn confirm(question: &str) -> bool {
+    print!("{} [y/N] ", question);
+    io::stdout().flush().expect("Failed to flush stdout");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().eq_ignore_ascii_case("y")
 }
 
 fn main() {
@@ -42,17 +67,28 @@ fn main() {
                 .help("Directory for config and data (default \"~/.near\")")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("data-dir")
+                .long("data-dir")
+                .help("Directory for the database, overrides store path from config, relative to the current directory (default \"data\" under home)")
+                .takes_value(true),
+        )
         .subcommand(SubCommand::with_name("init").about("Initializes NEAR configuration")
             .arg(Arg::with_name("chain-id").long("chain-id").takes_value(true).help("Chain ID, by default creates new random"))
             .arg(Arg::with_name("account-id").long("account-id").takes_value(true).help("Account ID for the validator key"))
             .arg(Arg::with_name("test-seed").long("test-seed").takes_value(true).help("Specify private key generated from seed (TESTING ONLY)"))
             .arg(Arg::with_name("fast").long("fast").takes_value(false).help("Makes block production fast (TESTING ONLY)"))
+            .arg(Arg::with_name("genesis").long("genesis").takes_value(true).help("Path or url of the genesis file of existing network to join"))
+            .arg(Arg::with_name("download-config").long("download-config").takes_value(false).help("Downloads default config for the network with given chain ID"))
         )
         .subcommand(SubCommand::with_name("testnet").about("Setups testnet configuration with all necessary files (validator key, node key, genesis and config)")
             .arg(Arg::with_name("v").long("v").takes_value(true).help("Number of validators to initialize the testnet with (default 4)"))
             .arg(Arg::with_name("n").long("n").takes_value(true).help("Number of non-validators to initialize the testnet with (default 0)"))
             .arg(Arg::with_name("prefix").long("prefix").takes_value(true).help("Prefix the directory name for each node with (node results in node0, node1, ...) (default \"node\")"))
         )
+        .subcommand(SubCommand::with_name("localnet").about("Runs local network of validators in a single process, data is kept in localnet directory under home")
+            .arg(Arg::with_name("v").long("v").takes_value(true).help("Number of validators to start the localnet with (default 4, or the number of nodes of the existing localnet)"))
+        )
         .subcommand(SubCommand::with_name("run").about("Runs NEAR node")
             .arg(Arg::with_name("produce-empty-blocks").long("produce-empty-blocks").help("Set this to false to only produce blocks when there are txs or receipts (default true)").takes_value(true))
             .arg(Arg::with_name("boot-nodes").long("boot-nodes").help("Set the boot nodes to bootstrap network from").takes_value(true))
@@ -60,14 +96,72 @@ fn main() {
             .arg(Arg::with_name("network-addr").long("network-addr").help("Customize network listening address (useful for running multiple nodes on the same machine)").takes_value(true))
             .arg(Arg::with_name("rpc-addr").long("rpc-addr").help("Customize RPC listening address (useful for running multiple nodes on the same machine)").takes_value(true))
             .arg(Arg::with_name("telemetry-url").long("telemetry-url").help("Customize telemetry url").takes_value(true))
+            .arg(Arg::with_name("allow-deep-reorgs").long("allow-deep-reorgs").help("Follow reorgs deeper than max_reorg_depth from consensus config (use only after checking the network)").takes_value(false))
+            .arg(Arg::with_name("light").long("light").help("Run light client: only sync and validate headers, forward transactions to full nodes").takes_value(false))
+            .arg(Arg::with_name("archive-dir").long("archive-dir").help("Process blocks exported with export-blocks from this directory before syncing with the network").takes_value(true))
+            .arg(Arg::with_name("verify-store").long("verify-store").help("Check the headers, blocks and heads in the database before starting, refuse to start if any of them is corrupted").takes_value(false))
+        )
+        .subcommand(SubCommand::with_name("check-config").about("Validates config, genesis and key files in home directory"))
+        .subcommand(SubCommand::with_name("keys").about("Manages validator keys")
+            .subcommand(SubCommand::with_name("generate").about("Generates new key file for given account")
+                .arg(Arg::with_name("account-id").long("account-id").takes_value(true).required(true).help("Account ID for the key"))
+                .arg(Arg::with_name("key-file").long("key-file").takes_value(true).help("Path of the key file to write (default validator key file in home)"))
+            )
+            .subcommand(SubCommand::with_name("rotate-validator").about("Replaces validator key with a newly generated one, keeping previous keys with .old suffix")
+                .arg(Arg::with_name("stake").long("stake").takes_value(true).help("If set, prints staking transaction for given amount that switches to the new key"))
+                .arg(Arg::with_name("nonce").long("nonce").takes_value(true).help("Nonce of the previous validator key for the staking transaction"))
+                .arg(Arg::with_name("block-hash").long("block-hash").takes_value(true).help("Recent block hash for the staking transaction"))
+            )
+        )
+        .subcommand(SubCommand::with_name("unsafe_reset_data").about("(unsafe) Remove all the data, effectively resetting node to genesis state (keeps genesis and config)")
+            .arg(Arg::with_name("keep-headers").long("keep-headers").takes_value(false).help("Keep the header chain, blocks are synced again on top of it"))
+            .arg(Arg::with_name("keep-peers").long("keep-peers").takes_value(false).help("Keep the known peers"))
+            .arg(Arg::with_name("dry-run").long("dry-run").takes_value(false).help("Only print what would be removed"))
+            .arg(Arg::with_name("yes").long("yes").takes_value(false).help("Do not ask for confirmation"))
+        )
+        .subcommand(SubCommand::with_name("unsafe_reset_to_height").about("(unsafe) Roll the chain back to the block at given height, removing newer blocks and their state")
+            .arg(Arg::with_name("height").long("height").takes_value(true).required(true).help("Height of the block on the main chain to become the head"))
+            .arg(Arg::with_name("yes").long("yes").takes_value(false).help("Do not ask for confirmation"))
+        )
+        .subcommand(SubCommand::with_name("snapshot").about("Exports or imports state snapshot, that lets new nodes start without syncing all the blocks")
+            .subcommand(SubCommand::with_name("create").about("Writes snapshot of the state at a finalized block with the header chain")
+                .arg(Arg::with_name("output").long("output").takes_value(true).required(true).help("Path of the snapshot file to write"))
+                .arg(Arg::with_name("height").long("height").takes_value(true).help("Height of the block to snapshot (default head minus max_reorg_depth)"))
+            )
+            .subcommand(SubCommand::with_name("restore").about("Loads snapshot into the empty database of the node")
+                .arg(Arg::with_name("input").long("input").takes_value(true).required(true).help("Path of the snapshot file to load"))
+            )
+        )
+        .subcommand(SubCommand::with_name("db").about("Backs up the database of the running node or restores it from the backup")
+            .subcommand(SubCommand::with_name("backup").about("Asks the running node to write backup of its database, a checkpoint of the database with checksums of its files")
+                .arg(Arg::with_name("output").long("output").takes_value(true).required(true).help("Path of the backup directory to write, files of the database are hard linked into it when it is on the same filesystem"))
+            )
+            .subcommand(SubCommand::with_name("restore").about("Replaces the database with the verified content of the backup, the node must be stopped")
+                .arg(Arg::with_name("input").long("input").takes_value(true).required(true).help("Path of the backup directory to load"))
+                .arg(Arg::with_name("yes").long("yes").takes_value(false).help("Do not ask for confirmation"))
+            )
+        )
+        .subcommand(SubCommand::with_name("replay").about("Re-executes blocks of the main chain and writes outcomes, state writes and gas of every block into a JSON lines trace")
+            .arg(Arg::with_name("from").long("from").takes_value(true).required(true).help("Height of the first block to replay"))
+            .arg(Arg::with_name("to").long("to").takes_value(true).required(true).help("Height of the last block to replay"))
+            .arg(Arg::with_name("trace").long("trace").takes_value(true).required(true).help("Path of the trace file to write"))
+        )
+        .subcommand(SubCommand::with_name("export-blocks").about("Writes blocks of the main chain into a directory, one file per block, for other nodes to catch up from with --archive-dir")
+            .arg(Arg::with_name("from").long("from").takes_value(true).help("Height of the first block to export (default 1)"))
+            .arg(Arg::with_name("to").long("to").takes_value(true).help("Height of the last block to export (default head)"))
+            .arg(Arg::with_name("output-dir").long("output-dir").takes_value(true).required(true).help("Directory to write the block files into"))
         )
-        .subcommand(SubCommand::with_name("unsafe_reset_data").about("(unsafe) Remove all the data, effectively resetting node to genesis state (keeps genesis and config)"))
         .subcommand(SubCommand::with_name("unsafe_reset_all").about("(unsafe) Remove all the config, keys, data and effectively removing all information about the network"))
         .get_matches();
 
     init_logging(matches.is_present("verbose"));
 
     let home_dir = matches.value_of("home").map(|dir| Path::new(dir)).unwrap();
+    // Unlike the store path in the config, which is relative to home, relative data dir is
+    // given from the current directory.
+    let data_dir = matches.value_of("data-dir").map(|dir| {
+        std::env::current_dir().expect("Failed to get current directory").join(dir)
+    });
 
     match matches.subcommand() {
         ("init", Some(args)) => {
@@ -76,7 +170,17 @@ fn main() {
             let account_id = args.value_of("account-id");
             let test_seed = args.value_of("test-seed");
             let fast = args.is_present("fast");
-            init_configs(home_dir, chain_id, account_id, test_seed, fast);
+            let genesis = args.value_of("genesis");
+            let download_config = args.is_present("download-config");
+            init_configs(
+                home_dir,
+                chain_id,
+                account_id,
+                test_seed,
+                fast,
+                genesis,
+                download_config,
+            );
         }
         ("testnet", Some(args)) => {
             let num_validators = args
@@ -88,11 +192,55 @@ fn main() {
                 .map(|x| x.parse().expect("Failed to parse number of non-validators"))
                 .unwrap_or(0);
             let prefix = args.value_of("prefix").unwrap_or("node");
-            init_testnet_configs(home_dir, num_validators, num_non_validators, prefix);
+            init_testnet_configs(home_dir, num_validators, num_non_validators, prefix, false);
+        }
+        ("localnet", Some(args)) => {
+            let prefix = "node";
+            let localnet_dir = home_dir.join("localnet");
+            let num_existing = (0..)
+                .take_while(|i| {
+                    localnet_dir.join(format!("{}{}", prefix, i)).join(CONFIG_FILENAME).exists()
+                })
+                .count();
+            let num_validators = match args.value_of("v").map(|x| x.parse::<usize>()) {
+                Some(Ok(num_validators)) if num_validators > 0 => num_validators,
+                Some(_) => {
+                    eprintln!("Number of validators must be a positive integer");
+                    std::process::exit(1);
+                }
+                None if num_existing > 0 => num_existing,
+                None => 4,
+            };
+            if num_existing == 0 {
+                init_testnet_configs(&localnet_dir, num_validators, 0, prefix, true);
+            } else if num_existing != num_validators {
+                eprintln!(
+                    "Localnet in {} has {} validators, remove it to start with {} validators",
+                    localnet_dir.display(),
+                    num_existing,
+                    num_validators
+                );
+                std::process::exit(1);
+            }
+            info!(target: "near", "Accounts {}0..{}{} have keys in validator_key.json of their node directories", prefix, prefix, num_validators - 1);
+
+            let system = System::new("NEAR");
+            let mut clients = vec![];
+            for i in 0..num_validators {
+                let node_dir = localnet_dir.join(format!("{}{}", prefix, i));
+                let mut near_config = load_config_or_exit(&node_dir);
+                near_config.client_config.version = version.clone();
+                info!(target: "near", "Node {}{} RPC is listening on http://{}", prefix, i, near_config.rpc_config.addr);
+                let (client, _) = start_with_config(&node_dir, near_config);
+                clients.push(client);
+            }
+            stop_on_signals(clients);
+            system.run().unwrap();
+            info!(target: "near", "Localnet stopped, data is kept in {}", localnet_dir.display());
         }
         ("run", Some(args)) => {
             // Load configs from home.
-            let mut near_config = load_config(home_dir);
+            let mut near_config = load_config_or_exit(home_dir);
             // Set current version in client config.
             near_config.client_config.version = version;
             // Override some parameters from command line.
@@ -129,20 +277,287 @@ fn main() {
             if let Some(telemetry_url) = args.value_of("telemetry-url") {
                 near_config.telemetry_config.endpoints.push(telemetry_url.to_string());
             }
+            if args.is_present("allow-deep-reorgs") {
+                near_config.client_config.max_reorg_depth = None;
+            }
+            if args.is_present("light") {
+                near_config.client_config.light_client = true;
+            }
+            if let Some(archive_dir) = args.value_of("archive-dir") {
+                near_config.client_config.archive_dir = Some(PathBuf::from(archive_dir));
+            }
+            if data_dir.is_some() {
+                near_config.store_config.path = data_dir;
+            }
+            if args.is_present("verify-store")
+                && near_config.store_config.options.backend == StoreBackend::RocksDb
+            {
+                let store_path = get_configured_store_path(home_dir, &near_config.store_config);
+                let store = create_store(&store_path);
+                let stats = verify_store(&store);
+                for corrupted in stats.corrupted.iter() {
+                    error!(target: "near", "Corrupted record: {}", corrupted);
+                }
+                if !stats.corrupted.is_empty() {
+                    eprintln!(
+                        "Found {} corrupted records in {}, restore the database from a backup or reset it",
+                        stats.corrupted.len(),
+                        store_path
+                    );
+                    std::process::exit(1);
+                }
+                info!(target: "near", "Verified {} records in {}", stats.num_records, store_path);
+                // Flat state is derived from the trie, so it's rebuilt on start if it's off.
+                match verify_flat_state(&store) {
+                    Ok(Some(num_values)) => {
+                        info!(target: "near", "Verified flat state with {} values", num_values)
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        warn!(target: "near", "{}, rebuilding it", err);
+                        drop_flat_state(&store).expect("Failed to drop flat state");
+                    }
+                }
+            }
 
             let system = System::new("NEAR");
-            start_with_config(home_dir, near_config);
+            let (client, _) = start_with_config(home_dir, near_config);
+            stop_on_signals(vec![client]);
             system.run().unwrap();
+            info!(target: "near", "Node stopped");
         }
-        ("unsafe_reset_data", Some(_args)) => {
-            let store_path = get_store_path(home_dir);
-            info!(target: "near", "Removing all data from {}", store_path);
-            fs::remove_dir_all(store_path).expect("Removing data failed");
+        ("check-config", Some(_args)) => match validate_configs(home_dir) {
+            Ok(()) => info!(target: "near", "Config in {} is valid", home_dir.display()),
+            Err(errors) => {
+                for err in errors.iter() {
+                    error!(target: "near", "{}", err);
+                }
+                eprintln!("Found {} problems with config in {}", errors.len(), home_dir.display());
+                std::process::exit(1);
+            }
+        },
+        ("keys", Some(args)) => match args.subcommand() {
+            ("generate", Some(args)) => {
+                let account_id = args.value_of("account-id").unwrap();
+                let key_file = args
+                    .value_of("key-file")
+                    .map(PathBuf::from)
+                    .unwrap_or_else(|| home_dir.join(VALIDATOR_KEY_FILE));
+                let signer = generate_key_file(&key_file, account_id);
+                info!(target: "near", "Generated key {} for {} in {}", signer.public_key, account_id, key_file.to_str().unwrap());
+            }
+            ("rotate-validator", Some(args)) => {
+                let stake = args.value_of("stake").map(|stake| {
+                    let amount = stake.parse().expect("Failed to parse stake amount");
+                    let nonce = args
+                        .value_of("nonce")
+                        .expect("Nonce is required to create staking transaction")
+                        .parse()
+                        .expect("Failed to parse nonce");
+                    let block_hash: CryptoHash = args
+                        .value_of("block-hash")
+                        .expect("Block hash is required to create staking transaction")
+                        .to_string()
+                        .try_into()
+                        .expect("Failed to parse block hash");
+                    (amount, nonce, block_hash)
+                });
+                let (_, transaction) = rotate_validator_key(home_dir, stake);
+                if let Some(transaction) = transaction {
+                    let bytes = transaction.try_to_vec().expect("Failed to serialize transaction");
+                    info!(target: "near", "Staking transaction {} to submit with broadcast_tx_commit:", transaction.get_hash());
+                    println!("{}", to_base64(&bytes));
+                }
+            }
+            (_, _) => {
+                eprintln!("{}", args.usage());
+                std::process::exit(1);
+            }
+        },
+        ("unsafe_reset_data", Some(args)) => {
+            let store_config = match data_dir {
+                Some(path) => StoreConfig { path: Some(path), ..Default::default() },
+                None => read_config(home_dir).map(|config| config.store).unwrap_or_default(),
+            };
+            let store_path = get_configured_store_path(home_dir, &store_config);
+            let keep_headers = args.is_present("keep-headers");
+            let keep_peers = args.is_present("keep-peers");
+            let dry_run = args.is_present("dry-run");
+            if !dry_run
+                && !args.is_present("yes")
+                && !confirm(&format!("Remove data from {}?", store_path))
+            {
+                info!(target: "near", "Reset cancelled");
+                return;
+            }
+            if keep_headers || keep_peers {
+                let store = create_store_with_options(&store_path, &store_config.options);
+                let keep_columns = if keep_peers { vec![COL_PEERS] } else { vec![] };
+                let removed = reset_chain_data(&store, keep_headers, &keep_columns, dry_run)
+                    .expect("Removing data failed");
+                for (column, num_removed) in removed {
+                    info!(target: "near", "{} {} records from column {}", if dry_run { "Would remove" } else { "Removed" }, num_removed, column);
+                }
+            } else if dry_run {
+                info!(target: "near", "Would remove all data from {}", store_path);
+            } else {
+                info!(target: "near", "Removing all data from {}", store_path);
+                fs::remove_dir_all(store_path).expect("Removing data failed");
+            }
+        }
+        ("unsafe_reset_to_height", Some(args)) => {
+            let store_config = match data_dir {
+                Some(path) => StoreConfig { path: Some(path), ..Default::default() },
+                None => read_config(home_dir).map(|config| config.store).unwrap_or_default(),
+            };
+            let store_path = get_configured_store_path(home_dir, &store_config);
+            let height: u64 =
+                args.value_of("height").unwrap().parse().expect("Failed to parse height");
+            if !args.is_present("yes")
+                && !confirm(&format!("Remove blocks above #{} from {}?", height, store_path))
+            {
+                info!(target: "near", "Reset cancelled");
+                return;
+            }
+            let mut chain_store =
+                ChainStore::new(create_store_with_options(&store_path, &store_config.options));
+            let (tip, removed) =
+                chain_store.reset_to_height(height).expect("Resetting chain failed");
+            info!(target: "near", "Reset chain to #{} {}, removed {} blocks", tip.height, tip.last_block_hash, removed.len());
+        }
+        ("snapshot", Some(args)) => {
+            let mut near_config = load_config_or_exit(home_dir);
+            if data_dir.is_some() {
+                near_config.store_config.path = data_dir;
+            }
+            let store = create_store_with_options(
+                &get_configured_store_path(home_dir, &near_config.store_config),
+                &near_config.store_config.options,
+            );
+            match args.subcommand() {
+                ("create", Some(args)) => {
+                    let output = Path::new(args.value_of("output").unwrap());
+                    let height = args
+                        .value_of("height")
+                        .map(|x| x.parse().expect("Failed to parse height"));
+                    let max_reorg_depth = near_config.client_config.max_reorg_depth;
+                    let (tip, stats) = create_snapshot(store, height, max_reorg_depth, output)
+                        .expect("Creating snapshot failed");
+                    info!(target: "near", "Snapshot at #{} {} with {} state items and {} records written to {}", tip.height, tip.last_block_hash, stats.num_state_items, stats.num_records, output.display());
+                }
+                ("restore", Some(args)) => {
+                    let input = Path::new(args.value_of("input").unwrap());
+                    let (tip, stats) =
+                        load_snapshot(store, input).expect("Restoring snapshot failed");
+                    info!(target: "near", "Restored snapshot at #{} {} with {} state items and {} records", tip.height, tip.last_block_hash, stats.num_state_items, stats.num_records);
+                }
+                (_, _) => {
+                    eprintln!("{}", args.usage());
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("db", Some(args)) => {
+            let mut near_config = load_config_or_exit(home_dir);
+            if data_dir.is_some() {
+                near_config.store_config.path = data_dir;
+            }
+            match args.subcommand() {
+                ("backup", Some(args)) => {
+                    // The node resolves relative paths against its own working directory.
+                    let output = std::env::current_dir()
+                        .expect("Failed to get current directory")
+                        .join(args.value_of("output").unwrap());
+                    let server_addr = format!("http://{}", near_config.rpc_config.admin_addr);
+                    System::run(move || {
+                        let client = Client::build().timeout(BACKUP_TIMEOUT).finish();
+                        let mut client = JsonRpcClient::new(&server_addr, client);
+                        let path = output.to_string_lossy().to_string();
+                        actix::spawn(client.db_backup(path).then(move |res| {
+                            match res {
+                                Ok(size) => info!(target: "near", "Backup of {} bytes written to {}", size, output.display()),
+                                Err(err) => error!(target: "near", "Backup failed: {}", err),
+                            }
+                            System::current().stop();
+                            future::ok(())
+                        }));
+                    })
+                    .expect("Failed to run backup");
+                }
+                ("restore", Some(args)) => {
+                    let store_path =
+                        get_configured_store_path(home_dir, &near_config.store_config);
+                    let input = Path::new(args.value_of("input").unwrap());
+                    if !args.is_present("yes")
+                        && !confirm(&format!("Replace data in {} with the backup?", store_path))
+                    {
+                        info!(target: "near", "Restore cancelled");
+                        return;
+                    }
+                    let stats =
+                        restore_database(&store_path, input).expect("Restoring backup failed");
+                    info!(target: "near", "Restored backup of {} files with {} bytes", stats.num_files, stats.size);
+                }
+                (_, _) => {
+                    eprintln!("{}", args.usage());
+                    std::process::exit(1);
+                }
+            }
+        }
+        ("replay", Some(args)) => {
+            let mut near_config = load_config_or_exit(home_dir);
+            if data_dir.is_some() {
+                near_config.store_config.path = data_dir;
+            }
+            let from = args.value_of("from").unwrap().parse().expect("Failed to parse from height");
+            let to = args.value_of("to").unwrap().parse().expect("Failed to parse to height");
+            let store = create_store_with_options(
+                &get_configured_store_path(home_dir, &near_config.store_config),
+                &near_config.store_config.options,
+            );
+            let runtime =
+                NightshadeRuntime::new(home_dir, store.clone(), near_config.genesis_config.clone());
+            let trace_path = Path::new(args.value_of("trace").unwrap());
+            let mut trace =
+                BufWriter::new(File::create(trace_path).expect("Failed to create trace file"));
+            let stats =
+                replay_blocks(store, &runtime, from, to, &mut trace).expect("Replay failed");
+            trace.flush().expect("Failed to write trace file");
+            info!(target: "near", "Trace of {} blocks written to {}", stats.num_blocks, trace_path.display());
+            if !stats.diverged_heights.is_empty() {
+                error!(target: "near", "State diverged at heights {:?}", stats.diverged_heights);
+                std::process::exit(1);
+            }
+        }
+        ("export-blocks", Some(args)) => {
+            let mut near_config = load_config_or_exit(home_dir);
+            if data_dir.is_some() {
+                near_config.store_config.path = data_dir;
+            }
+            let store = create_store_with_options(
+                &get_configured_store_path(home_dir, &near_config.store_config),
+                &near_config.store_config.options,
+            );
+            let mut chain_store = ChainStore::new(store);
+            let head = chain_store.head().expect("Failed to read the head of the chain");
+            let from = args
+                .value_of("from")
+                .map_or(1, |x| x.parse().expect("Failed to parse from height"));
+            let to = args
+                .value_of("to")
+                .map_or(head.height, |x| x.parse().expect("Failed to parse to height"));
+            let output_dir = Path::new(args.value_of("output-dir").unwrap());
+            let num_blocks = export_blocks(&mut chain_store, from, to, output_dir)
+                .expect("Exporting blocks failed");
+            info!(target: "near", "Exported {} blocks to {}", num_blocks, output_dir.display());
         }
         ("unsafe_reset_all", Some(_args)) => {
             info!(target: "near", "Removing all data and config from {}", home_dir.to_str().unwrap());
             fs::remove_dir_all(home_dir).expect("Removing data and config failed.");
         }
-        (_, _) => unreachable!(),
+        (_, _) => {
+            eprintln!("{}", matches.usage());
+            std::process::exit(1);
+        }
     }
 }
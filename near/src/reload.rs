@@ -0,0 +1,64 @@
+//! Reloading of the config values that can be changed without restarting the node.
+
+use std::path::PathBuf;
+
+use actix::{Actor, Addr, Context, Handler};
+use log::{debug, info};
+
+use near_client::{ClientActor, Correlated, ReloadConfig, UpdateConfig};
+use near_telemetry::{TelemetryActor, UpdateTelemetryConfig};
+
+use crate::config::{parse_log_level, read_config, validate_configs};
+use crate::logging::set_log_level;
+
+/// Re-reads config file on request and pushes updated values to the running actors.
+pub struct ConfigReloader {
+    home_dir: PathBuf,
+    client: Addr<ClientActor>,
+    telemetry: Addr<TelemetryActor>,
+}
+
+impl ConfigReloader {
+    pub fn new(
+        home_dir: PathBuf,
+        client: Addr<ClientActor>,
+        telemetry: Addr<TelemetryActor>,
+    ) -> Self {
+        ConfigReloader { home_dir, client, telemetry }
+    }
+}
+
+impl Actor for ConfigReloader {
+    type Context = Context<Self>;
+}
+
+impl Handler<ReloadConfig> for ConfigReloader {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, _: ReloadConfig, _: &mut Context<Self>) -> Self::Result {
+        validate_configs(&self.home_dir).map_err(|errors| errors.join("; "))?;
+        let config = read_config(&self.home_dir)?;
+        info!(target: "near", "Reloading config from {}", self.home_dir.display());
+
+        if let Some(log_level) = &config.log_level {
+            set_log_level(parse_log_level(log_level)?);
+        }
+        self.telemetry.do_send(UpdateTelemetryConfig { config: config.telemetry });
+        self.client.do_send(UpdateConfig {
+            min_num_peers: config.consensus.min_num_peers,
+            tracked_shards: config.tracked_shards,
+            peer_max_count: config.network.max_peers,
+        });
+        Ok(())
+    }
+}
+
+impl Handler<Correlated<ReloadConfig>> for ConfigReloader {
+    type Result = Result<(), String>;
+
+    fn handle(&mut self, msg: Correlated<ReloadConfig>, ctx: &mut Context<Self>) -> Self::Result {
+        let Correlated(correlation_id, msg) = msg;
+        debug!(target: "near", "correlation_id={} Reloading config on request", correlation_id);
+        self.handle(msg, ctx)
+    }
+}
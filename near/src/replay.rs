@@ -0,0 +1,286 @@
+//! Re-execution of the blocks of the main chain, recording outcomes of transactions and receipts
+//! and the resulting state changes into a JSON lines trace. Traces of the same blocks produced by
+//! different node versions must be identical.
+use std::io::Write;
+use std::sync::Arc;
+
+use log::{info, warn};
+use serde_derive::Serialize;
+
+use near_chain::{ChainStore, ChainStoreAccess, RuntimeAdapter};
+use near_primitives::serialize::to_base64;
+use near_primitives::types::{BlockIndex, Gas};
+use near_primitives::views::TransactionLogView;
+use near_store::{Store, Trie};
+
+/// Entry of the trace, written as a single JSON line.
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum TraceEntry {
+    /// Outcome of the transaction or the receipt, including the gas it burnt.
+    Outcome { height: BlockIndex, outcome: TransactionLogView },
+    /// Value written to the state by the block, `None` if the key was removed.
+    StateWrite { height: BlockIndex, key: String, value: Option<String> },
+    /// Summary of the block, written after its outcomes and state writes.
+    Block {
+        height: BlockIndex,
+        hash: String,
+        gas_burnt: Gas,
+        state_root: String,
+        expected_state_root: String,
+    },
+}
+
+/// Summary of the replay.
+#[derive(Debug, Default)]
+pub struct ReplayStats {
+    pub num_blocks: u64,
+    pub num_outcomes: u64,
+    pub num_state_writes: u64,
+    /// Heights of the blocks which state root differs from the one stored by the node.
+    pub diverged_heights: Vec<BlockIndex>,
+}
+
+fn write_entry(trace: &mut dyn Write, entry: &TraceEntry) -> Result<(), Box<dyn std::error::Error>> {
+    serde_json::to_writer(&mut *trace, entry)?;
+    trace.write_all(b"\n")?;
+    Ok(())
+}
+
+/// Re-executes blocks of the main chain at heights `from..=to` on top of the stored state and
+/// writes the trace. Nothing is saved into the store, so the node must keep the state of the
+/// blocks preceding the range, i.e. they must not be garbage collected.
+pub fn replay_blocks(
+    store: Arc<Store>,
+    runtime_adapter: &dyn RuntimeAdapter,
+    from: BlockIndex,
+    to: BlockIndex,
+    trace: &mut dyn Write,
+) -> Result<ReplayStats, Box<dyn std::error::Error>> {
+    let mut chain_store = ChainStore::new(store.clone());
+    let trie = Trie::new(store);
+    let mut stats = ReplayStats::default();
+    // Genesis block has no transactions to replay.
+    for height in from.max(1)..=to {
+        let hash = match chain_store.get_block_hash_by_height(height) {
+            Ok(hash) => hash,
+            // Heights without a block on the main chain are skipped.
+            Err(_) => continue,
+        };
+        let block = chain_store.get_block(&hash)?.clone();
+        let prev_hash = block.header.inner.prev_hash;
+        let prev_state_root = block.header.inner.prev_state_root;
+        let mut receipts = vec![];
+        for shard_id in 0..runtime_adapter.num_shards() {
+            receipts.push(chain_store.get_receipts(&prev_hash, shard_id)?.clone());
+        }
+        let expected_state_root = *chain_store.get_post_state_root(&hash)?;
+        let (trie_changes, state_root, tx_results, _, _) = runtime_adapter.apply_transactions(
+            0,
+            &prev_state_root,
+            height,
+            &prev_hash,
+            &hash,
+            &receipts,
+            &block.transactions,
+        )?;
+        let mut gas_burnt = 0;
+        for outcome in tx_results.into_iter() {
+            gas_burnt += outcome.result.gas_burnt.unwrap_or_default();
+            write_entry(trace, &TraceEntry::Outcome { height, outcome: outcome.into() })?;
+            stats.num_outcomes += 1;
+        }
+        // Values set by the replayed block, sorted by key, except the ones it left unchanged.
+        for (key, value) in trie_changes.state_changes().iter() {
+            if trie.get(&prev_state_root, key) == *value {
+                continue;
+            }
+            let entry = TraceEntry::StateWrite {
+                height,
+                key: to_base64(key),
+                value: value.as_ref().map(to_base64),
+            };
+            write_entry(trace, &entry)?;
+            stats.num_state_writes += 1;
+        }
+        write_entry(
+            trace,
+            &TraceEntry::Block {
+                height,
+                hash: hash.to_string(),
+                gas_burnt,
+                state_root: state_root.to_string(),
+                expected_state_root: expected_state_root.to_string(),
+            },
+        )?;
+        if state_root != expected_state_root {
+            warn!(target: "near", "Replay of #{} {} diverged: state root {}, expected {}", height, hash, state_root, expected_state_root);
+            stats.diverged_heights.push(height);
+        }
+        stats.num_blocks += 1;
+    }
+    info!(target: "near", "Replayed {} blocks, {} outcomes, {} state writes", stats.num_blocks, stats.num_outcomes, stats.num_state_writes);
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use serde_json::json;
+    use tempdir::TempDir;
+
+    use near_chain::{Block, Chain, Provenance};
+    use near_crypto::{InMemorySigner, KeyType};
+    use near_primitives::transaction::SignedTransaction;
+    use near_primitives::test_utils::init_test_logger;
+    use near_primitives::types::MerkleHash;
+    use near_store::test_utils::create_test_store;
+
+    use crate::{GenesisConfig, NightshadeRuntime};
+
+    use super::*;
+
+    /// Changes of the values between two stored states, sorted by key.
+    fn state_changes(
+        trie: &Trie,
+        old_root: &MerkleHash,
+        new_root: &MerkleHash,
+    ) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>, Box<dyn std::error::Error>> {
+        let mut changes = vec![];
+        if old_root == new_root {
+            return Ok(changes);
+        }
+        let mut old_iter = trie.iter(old_root)?;
+        let mut new_iter = trie.iter(new_root)?;
+        let mut old_item = old_iter.next().transpose()?;
+        let mut new_item = new_iter.next().transpose()?;
+        loop {
+            match (old_item.take(), new_item.take()) {
+                (None, None) => break,
+                (Some((key, _)), None) => {
+                    changes.push((key, None));
+                    old_item = old_iter.next().transpose()?;
+                }
+                (None, Some((key, value))) => {
+                    changes.push((key, Some(value.to_vec())));
+                    new_item = new_iter.next().transpose()?;
+                }
+                (Some((old_key, old_value)), Some((new_key, new_value))) => {
+                    match old_key.cmp(&new_key) {
+                        Ordering::Less => {
+                            changes.push((old_key, None));
+                            new_item = Some((new_key, new_value));
+                            old_item = old_iter.next().transpose()?;
+                        }
+                        Ordering::Greater => {
+                            changes.push((new_key, Some(new_value.to_vec())));
+                            old_item = Some((old_key, old_value));
+                            new_item = new_iter.next().transpose()?;
+                        }
+                        Ordering::Equal => {
+                            if old_value != new_value {
+                                changes.push((new_key, Some(new_value.to_vec())));
+                            }
+                            old_item = old_iter.next().transpose()?;
+                            new_item = new_iter.next().transpose()?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(changes)
+    }
+
+    #[test]
+    fn test_replay_trace() {
+        init_test_logger();
+        let dir = TempDir::new("replay").unwrap();
+        let genesis_config = GenesisConfig::test(vec!["test0", "test1"]);
+        let signer = Arc::new(InMemorySigner::from_seed("test0", KeyType::ED25519, "test0"));
+        let store = create_test_store();
+        let runtime =
+            Arc::new(NightshadeRuntime::new(dir.path(), store.clone(), genesis_config.clone()));
+        let mut chain =
+            Chain::new(store.clone(), runtime.clone(), genesis_config.genesis_time).unwrap();
+        let genesis_hash = chain.genesis().hash();
+        for i in 0..3 {
+            let prev = chain.head_header().unwrap().clone();
+            let state_root = *chain.get_post_state_root(&prev.hash()).unwrap();
+            // Transaction in the first block produces a receipt executed in the second one.
+            let transactions = if i == 0 {
+                vec![SignedTransaction::send_money(
+                    1,
+                    "test0".to_string(),
+                    "test1".to_string(),
+                    signer.clone(),
+                    100,
+                    genesis_hash,
+                )]
+            } else {
+                vec![]
+            };
+            let block = Block::produce(
+                &prev,
+                prev.inner.height + 1,
+                state_root,
+                prev.inner.epoch_hash,
+                transactions,
+                HashMap::default(),
+                vec![],
+                signer.clone(),
+            );
+            chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+        }
+
+        let mut trace = vec![];
+        let stats = replay_blocks(store.clone(), runtime.as_ref(), 0, 3, &mut trace).unwrap();
+        assert_eq!(stats.num_blocks, 3);
+        assert!(stats.num_outcomes >= 2);
+        assert!(stats.num_state_writes > 0);
+        assert!(stats.diverged_heights.is_empty());
+        let entries: Vec<serde_json::Value> = String::from_utf8(trace.clone())
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(entries.iter().filter(|entry| entry["type"] == "block").count(), 3);
+        let outcomes: Vec<_> = entries.iter().filter(|entry| entry["type"] == "outcome").collect();
+        assert_eq!(outcomes[0]["height"], 1);
+        assert!(outcomes[0]["outcome"]["result"]["gas_burnt"].as_u64().unwrap() > 0);
+        assert_eq!(outcomes[1]["height"], 2);
+        // State writes of the replayed blocks are the same as the changes of the stored state.
+        let trie = Trie::new(store.clone());
+        let mut chain_store = ChainStore::new(store.clone());
+        for height in 1..=3 {
+            let hash = chain_store.get_block_hash_by_height(height).unwrap();
+            let block = chain_store.get_block(&hash).unwrap();
+            let prev_state_root = block.header.inner.prev_state_root;
+            let state_root = *chain_store.get_post_state_root(&hash).unwrap();
+            let expected: Vec<_> = state_changes(&trie, &prev_state_root, &state_root)
+                .unwrap()
+                .into_iter()
+                .map(|(key, value)| {
+                    json!({
+                        "type": "state_write",
+                        "height": height,
+                        "key": to_base64(&key),
+                        "value": value.map(|value| to_base64(&value)),
+                    })
+                })
+                .collect();
+            let writes: Vec<_> = entries
+                .iter()
+                .filter(|entry| entry["type"] == "state_write" && entry["height"] == height)
+                .cloned()
+                .collect();
+            assert_eq!(writes, expected);
+        }
+        // Replay is deterministic.
+        let mut second_trace = vec![];
+        replay_blocks(store, runtime.as_ref(), 0, 3, &mut second_trace).unwrap();
+        assert_eq!(second_trace, trace);
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::{BTreeSet, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 use std::convert::TryInto;
 use std::io::{Cursor, Read, Write};
 use std::path::Path;
@@ -16,10 +16,15 @@ use near_crypto::{PublicKey, Signature};
 use near_primitives::account::{AccessKey, Account};
 use near_primitives::hash::{hash, CryptoHash};
 use near_primitives::receipt::Receipt;
-use near_primitives::transaction::{SignedTransaction, TransactionLog};
-use near_primitives::types::{AccountId, BlockIndex, MerkleHash, ShardId, ValidatorStake};
+use near_primitives::transaction::{InvalidTxError, SignedTransaction, TransactionLog};
+use near_primitives::types::{
+    AccountId, BlockIndex, MerkleHash, ShardId, StatePart, ValidatorStake,
+};
 use near_primitives::utils::prefix_for_access_key;
-use near_primitives::views::{QueryResponse, ViewStateResult};
+use near_primitives::views::{
+    GasEconomicsView, ProtocolConfigView, QueryResponse, RuntimeLimitsView, UnstakeInfoView,
+    ViewStateResult,
+};
 use near_store::{
     get_access_key_raw, get_account, set_account, PartialStorage, Store, StoreUpdate, Trie,
     TrieUpdate, WrappedTrieChanges,
@@ -31,20 +36,64 @@ use node_runtime::state_viewer::TrieViewer;
 use node_runtime::{ApplyState, Runtime, ETHASH_CACHE_PATH};
 
 use crate::config::GenesisConfig;
-use crate::validator_manager::{ValidatorEpochConfig, ValidatorManager};
+use crate::validator_manager::{
+    simulate_schedule, EpochSchedule, ValidatorEpochConfig, ValidatorManager,
+};
 
 const POISONED_LOCK_ERR: &str = "The lock was poisoned.";
+/// Approximate size of a state part served to the syncing nodes, in bytes.
+const STATE_PART_SIZE: usize = 1024 * 1024;
+
+/// Parses key-value pairs of the state, serialized as length-prefixed keys and values.
+fn decode_state_items(data: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>, Box<dyn std::error::Error>> {
+    let mut items = vec![];
+    let mut cursor = Cursor::new(data);
+    while cursor.position() < data.len() as u64 {
+        let key = read_state_bytes(&mut cursor)?;
+        let value = read_state_bytes(&mut cursor)?;
+        items.push((key, value));
+    }
+    Ok(items)
+}
+
+/// Reads length-prefixed bytes. The length is checked against the data left, so a malformed
+/// state part can't cause a huge allocation.
+fn read_state_bytes(cursor: &mut Cursor<&[u8]>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let len = cursor.read_u32::<LittleEndian>()? as u64;
+    if len > cursor.get_ref().len() as u64 - cursor.position() {
+        return Err(format!("Item length {} exceeds the state data", len).into());
+    }
+    let mut bytes = vec![0; len as usize];
+    cursor.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+/// Serializes key-value pairs of the state as length-prefixed keys and values.
+fn encode_state_items(items: &[(Vec<u8>, Vec<u8>)]) -> Result<Vec<u8>, std::io::Error> {
+    let mut data = vec![];
+    for (key, value) in items {
+        data.write_u32::<LittleEndian>(key.len() as u32)?;
+        data.write_all(&key)?;
+        data.write_u32::<LittleEndian>(value.len() as u32)?;
+        data.write_all(&value)?;
+    }
+    Ok(data)
+}
 
 /// Defines Nightshade state transition, validator rotation and block weight for fork choice rule.
 /// TODO: this possibly should be merged with the runtime cargo or at least reconsiled on the interfaces.
 pub struct NightshadeRuntime {
     genesis_config: GenesisConfig,
+    genesis_config_hash: CryptoHash,
 
     store: Arc<Store>,
     pub trie: Arc<Trie>,
     trie_viewer: TrieViewer,
     runtime: Runtime,
     validator_manager: RwLock<ValidatorManager>,
+    /// First keys of the state parts of the last state root each shard was requested at, so the
+    /// state is split once and each part is read from its first key.
+    state_part_boundaries: Mutex<HashMap<ShardId, (MerkleHash, Arc<Vec<Vec<u8>>>)>>,
 }
 
 impl NightshadeRuntime {
@@ -55,37 +104,94 @@ impl NightshadeRuntime {
         let ethash_provider = Arc::new(Mutex::new(EthashProvider::new(ethash_dir.as_path())));
         let runtime = Runtime::new(genesis_config.runtime_config.clone(), ethash_provider.clone());
         let trie_viewer = TrieViewer::new(ethash_provider);
-        let initial_epoch_config = ValidatorEpochConfig {
-            epoch_length: genesis_config.epoch_length,
-            rng_seed: [0; 32],
-            num_shards: genesis_config.block_producers_per_shard.len() as ShardId,
-            num_block_producers: genesis_config.num_block_producers,
-            block_producers_per_shard: genesis_config.block_producers_per_shard.clone(),
-            avg_fisherman_per_shard: genesis_config.avg_fisherman_per_shard.clone(),
-            validator_kickout_threshold: genesis_config.validator_kickout_threshold,
-        };
         let validator_manager = RwLock::new(
             ValidatorManager::new(
-                initial_epoch_config,
-                genesis_config
-                    .validators
-                    .iter()
-                    .map(|account_info| ValidatorStake {
-                        account_id: account_info.account_id.clone(),
-                        public_key: account_info
-                            .public_key
-                            .clone()
-                            .try_into()
-                            .expect("Failed to deserialize"),
-                        amount: account_info.amount,
-                    })
-                    .collect(),
+                genesis_epoch_config(&genesis_config),
+                genesis_validators(&genesis_config),
                 store.clone(),
             )
             .expect("Failed to start Validator Manager"),
         );
-        NightshadeRuntime { genesis_config, store, trie, runtime, trie_viewer, validator_manager }
+        let genesis_config_hash = genesis_config.hash();
+        NightshadeRuntime {
+            genesis_config,
+            genesis_config_hash,
+            store,
+            trie,
+            runtime,
+            trie_viewer,
+            validator_manager,
+            state_part_boundaries: Mutex::new(HashMap::new()),
+        }
     }
+
+    /// First keys of the parts the state with given root is split into, the first part starts
+    /// with the empty key. Parts are about `STATE_PART_SIZE` bytes, except the last one.
+    fn get_state_part_boundaries(
+        &self,
+        shard_id: ShardId,
+        state_root: &MerkleHash,
+    ) -> Result<Arc<Vec<Vec<u8>>>, Box<dyn std::error::Error>> {
+        let mut cache = self.state_part_boundaries.lock().expect(POISONED_LOCK_ERR);
+        if let Some((root, boundaries)) = cache.get(&shard_id) {
+            if root == state_root {
+                return Ok(boundaries.clone());
+            }
+        }
+        let mut boundaries = vec![vec![]];
+        let mut current_size = 0;
+        for item in self.trie.iter(state_root)? {
+            let (key, value) = item?;
+            if current_size >= STATE_PART_SIZE {
+                boundaries.push(key.clone());
+                current_size = 0;
+            }
+            current_size += key.len() + value.len() + 8;
+        }
+        let boundaries = Arc::new(boundaries);
+        cache.insert(shard_id, (*state_root, boundaries.clone()));
+        Ok(boundaries)
+    }
+}
+
+fn genesis_epoch_config(genesis_config: &GenesisConfig) -> ValidatorEpochConfig {
+    ValidatorEpochConfig {
+        epoch_length: genesis_config.epoch_length,
+        rng_seed: [0; 32],
+        num_shards: genesis_config.block_producers_per_shard.len() as ShardId,
+        num_block_producers: genesis_config.num_block_producers,
+        block_producers_per_shard: genesis_config.block_producers_per_shard.clone(),
+        avg_fisherman_per_shard: genesis_config.avg_fisherman_per_shard.clone(),
+        validator_kickout_threshold: genesis_config.validator_kickout_threshold,
+    }
+}
+
+fn genesis_validators(genesis_config: &GenesisConfig) -> Vec<ValidatorStake> {
+    genesis_config
+        .validators
+        .iter()
+        .map(|account_info| ValidatorStake {
+            account_id: account_info.account_id.clone(),
+            public_key: account_info.public_key.clone().try_into().expect("Failed to deserialize"),
+            amount: account_info.amount,
+        })
+        .collect()
+}
+
+/// Simulates block producer schedule for `num_epochs` epochs starting from the genesis validators,
+/// with given `proposals` staked in the first epoch.
+pub fn simulate_validator_schedule(
+    genesis_config: &GenesisConfig,
+    proposals: Vec<ValidatorStake>,
+    num_epochs: u64,
+) -> Result<Vec<EpochSchedule>, String> {
+    simulate_schedule(
+        genesis_epoch_config(genesis_config),
+        genesis_validators(genesis_config),
+        proposals,
+        num_epochs,
+    )
+    .map_err(|err| err.to_string())
 }
 
 impl RuntimeAdapter for NightshadeRuntime {
@@ -126,7 +232,7 @@ impl RuntimeAdapter for NightshadeRuntime {
         prev_header: &BlockHeader,
         header: &BlockHeader,
     ) -> Result<Weight, Error> {
-        let mut vm = self.validator_manager.write().expect(POISONED_LOCK_ERR);
+        let vm = self.validator_manager.read().expect(POISONED_LOCK_ERR);
         let validator = vm
             .get_block_proposer_info(header.inner.epoch_hash, header.inner.height)
             .map_err(|err| ErrorKind::Other(err.to_string()))?;
@@ -162,7 +268,7 @@ impl RuntimeAdapter for NightshadeRuntime {
         epoch_hash: &CryptoHash,
         height: BlockIndex,
     ) -> Result<AccountId, Box<dyn std::error::Error>> {
-        let mut vm = self.validator_manager.write().expect(POISONED_LOCK_ERR);
+        let vm = self.validator_manager.read().expect(POISONED_LOCK_ERR);
         Ok(vm.get_block_proposer_info(*epoch_hash, height)?.account_id)
     }
 
@@ -188,8 +294,8 @@ impl RuntimeAdapter for NightshadeRuntime {
         data: &[u8],
         signature: &Signature,
     ) -> bool {
-        let mut vm = self.validator_manager.write().expect(POISONED_LOCK_ERR);
-        if let Ok(validators) = vm.get_validators(*epoch_hash) {
+        let vm = self.validator_manager.read().expect(POISONED_LOCK_ERR);
+        if let Ok(validators) = vm.read_validators(epoch_hash) {
             if let Some(idx) = validators.validator_to_index.get(account_id) {
                 let staking_key = &validators.validators[*idx].public_key;
                 return signature.verify(data, staking_key);
@@ -214,12 +320,27 @@ impl RuntimeAdapter for NightshadeRuntime {
         _shard_id: ShardId,
         state_root: MerkleHash,
         transaction: SignedTransaction,
-    ) -> Result<ValidTransaction, String> {
+    ) -> Result<ValidTransaction, InvalidTxError> {
         let state_update = TrieUpdate::new(self.trie.clone(), state_root);
         let verifier = TransactionVerifier::new(&state_update);
-        if let Err(err) = verifier.verify_transaction(&transaction) {
-            debug!(target: "runtime", "Tx {:?} validation failed: {:?}", transaction, err);
-            return Err(err);
+        let data = match verifier.verify_transaction(&transaction) {
+            Ok(data) => data,
+            Err(err) => {
+                debug!(target: "runtime", "Tx {:?} validation failed: {:?}", transaction, err);
+                return Err(err);
+            }
+        };
+        // Rent is not charged here, so the transaction can still fail for the lack of balance.
+        let (_, cost) = self
+            .runtime
+            .transaction_cost(&transaction.transaction)
+            .map_err(|err| InvalidTxError::Refused(err.to_string()))?;
+        if data.signer.amount < cost {
+            return Err(InvalidTxError::NotEnoughBalance {
+                signer_id: data.signer_id,
+                balance: data.signer.amount,
+                cost,
+            });
         }
         Ok(ValidTransaction { transaction })
     }
@@ -329,7 +450,8 @@ impl RuntimeAdapter for NightshadeRuntime {
             self.runtime.apply(state_update, &apply_state, &receipts, &transactions)?;
 
         Ok((
-            WrappedTrieChanges::new(self.trie.clone(), apply_result.trie_changes),
+            WrappedTrieChanges::new(self.trie.clone(), apply_result.trie_changes)
+                .with_state_changes(apply_result.state_changes),
             apply_result.root,
             apply_result.tx_result,
             apply_result.new_receipts,
@@ -338,6 +460,28 @@ impl RuntimeAdapter for NightshadeRuntime {
         ))
     }
 
+    fn simulate_transaction(
+        &self,
+        shard_id: ShardId,
+        state_root: &MerkleHash,
+        block_index: BlockIndex,
+        prev_block_hash: &CryptoHash,
+        transaction: &SignedTransaction,
+    ) -> Result<(Vec<TransactionLog>, ReceiptResult), Box<dyn std::error::Error>> {
+        // Trie changes of the throwaway update are dropped and never committed to the store.
+        let state_update = TrieUpdate::new(self.trie.clone(), *state_root);
+        let apply_state = ApplyState {
+            root: *state_root,
+            shard_id,
+            block_index,
+            parent_block_hash: *prev_block_hash,
+            epoch_length: self.genesis_config.epoch_length,
+        };
+        let apply_result =
+            self.runtime.apply(state_update, &apply_state, &[], &[transaction.clone()])?;
+        Ok((apply_result.tx_result, apply_result.new_receipts))
+    }
+
     fn query(
         &self,
         state_root: MerkleHash,
@@ -348,25 +492,93 @@ impl RuntimeAdapter for NightshadeRuntime {
         query_client(self, state_root, height, path, data)
     }
 
-    fn dump_state(
+    fn get_protocol_config(&self) -> ProtocolConfigView {
+        let runtime_config = &self.genesis_config.runtime_config;
+        let limits = &runtime_config.wasm_config.limits;
+        ProtocolConfigView {
+            block_height: 0,
+            protocol_version: self.genesis_config.protocol_version,
+            chain_id: self.genesis_config.chain_id.clone(),
+            genesis_config_hash: self.genesis_config_hash,
+            epoch_length: self.genesis_config.epoch_length,
+            num_block_producers: self.genesis_config.num_block_producers,
+            block_producers_per_shard: self.genesis_config.block_producers_per_shard.clone(),
+            validator_kickout_threshold: self.genesis_config.validator_kickout_threshold,
+            transaction_validity_period: self.genesis_config.transaction_validity_period,
+            gas_economics: GasEconomicsView {
+                storage_cost_byte_per_block: runtime_config.storage_cost_byte_per_block,
+                poke_threshold: runtime_config.poke_threshold,
+                account_length_baseline_cost_per_block: runtime_config
+                    .account_length_baseline_cost_per_block,
+            },
+            runtime_limits: RuntimeLimitsView {
+                max_gas_per_block: limits.max_gas_per_block,
+                max_receipt_depth: limits.max_receipt_depth,
+                max_promises_per_function_call: limits.max_promises_per_function_call,
+                max_number_logs: limits.max_number_logs,
+                max_log_len: limits.max_log_len,
+                max_storage_key_len: limits.max_storage_key_len,
+                max_storage_value_len: limits.max_storage_value_len,
+            },
+        }
+    }
+
+    fn get_unstake_info(
+        &self,
+        last_hash: &CryptoHash,
+        account_id: &AccountId,
+    ) -> Result<UnstakeInfoView, Box<dyn std::error::Error>> {
+        let vm = self.validator_manager.read().expect(POISONED_LOCK_ERR);
+        Ok(vm.get_unstake_info(last_hash, account_id)?)
+    }
+
+    fn obtain_state_part(
         &self,
         shard_id: ShardId,
         state_root: MerkleHash,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        part_id: u64,
+    ) -> Result<StatePart, Box<dyn std::error::Error>> {
         // TODO(1052): make sure state_root is present in the trie.
-        // create snapshot.
-        let mut result = vec![];
-        let mut cursor = Cursor::new(&mut result);
-        for item in self.trie.iter(&state_root)? {
-            let (key, value) = item?;
-            cursor.write_u32::<LittleEndian>(key.len() as u32)?;
-            cursor.write_all(&key)?;
-            cursor.write_u32::<LittleEndian>(value.len() as u32)?;
-            cursor.write_all(value.as_ref())?;
+        // TODO(1048): Save on disk a snapshot split into parts instead of iterating whole state.
+        let boundaries = self.get_state_part_boundaries(shard_id, &state_root)?;
+        let num_parts = boundaries.len() as u64;
+        if part_id >= num_parts {
+            return Err(format!("State has {} parts, requested part {}", num_parts, part_id).into());
         }
-        // TODO(1048): Save on disk an snapshot, split into chunks and compressed. Send chunks instead of single blob.
-        info!(target: "runtime", "Dumped state for shard #{} @ {}, size = {}", shard_id, state_root, result.len());
-        Ok(result)
+        let start_key = boundaries[part_id as usize].clone();
+        let end_key = boundaries.get(part_id as usize + 1).cloned();
+        let range_end = end_key.as_ref().map(Vec::as_slice);
+        let (items, proof) = self.trie.get_range_proof(&state_root, &start_key, range_end)?;
+        let data = encode_state_items(&items)?;
+        info!(target: "runtime", "Obtained state part {}/{} for shard #{} @ {}, size = {}", part_id, num_parts, shard_id, state_root, data.len());
+        Ok(StatePart { part_id, num_parts, start_key, end_key, data, proof })
+    }
+
+    fn validate_state_part(
+        &self,
+        _shard_id: ShardId,
+        state_root: MerkleHash,
+        part: &StatePart,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if part.part_id >= part.num_parts {
+            let err = format!("Part {} of {} is out of range", part.part_id, part.num_parts);
+            return Err(err.into());
+        }
+        // Ranges of the parts must cover all the keys: the first part starts with the empty key,
+        // only the last part goes till the last key.
+        if (part.part_id == 0) != part.start_key.is_empty()
+            || (part.part_id + 1 == part.num_parts) != part.end_key.is_none()
+        {
+            let err = format!("Part {} of {} has wrong key range", part.part_id, part.num_parts);
+            return Err(err.into());
+        }
+        if part.end_key.as_ref().map_or(false, |end_key| *end_key <= part.start_key) {
+            return Err(format!("Part {} has empty key range", part.part_id).into());
+        }
+        let items = decode_state_items(&part.data)?;
+        let end_key = part.end_key.as_ref().map(Vec::as_slice);
+        Trie::verify_range_proof(&state_root, &part.start_key, end_key, &items, &part.proof)?;
+        Ok(())
     }
 
     fn set_state(
@@ -377,15 +589,7 @@ impl RuntimeAdapter for NightshadeRuntime {
     ) -> Result<(), Box<dyn std::error::Error>> {
         info!(target: "runtime", "Setting state for shard #{} @ {}, size = {}", shard_id, state_root, payload.len());
         let mut state_update = TrieUpdate::new(self.trie.clone(), CryptoHash::default());
-        let payload_len = payload.len();
-        let mut cursor = Cursor::new(payload);
-        while cursor.position() < payload_len as u64 {
-            let key_len = cursor.read_u32::<LittleEndian>()? as usize;
-            let mut key = vec![0; key_len];
-            cursor.read_exact(&mut key)?;
-            let value_len = cursor.read_u32::<LittleEndian>()? as usize;
-            let mut value = vec![0; value_len];
-            cursor.read_exact(&mut value)?;
+        for (key, value) in decode_state_items(&payload)? {
             state_update.set(key, DBValue::from_slice(&value));
         }
         let (store_update, root) = state_update.finalize()?.into(self.trie.clone())?;
@@ -403,7 +607,7 @@ impl node_runtime::adapter::ViewRuntimeAdapter for NightshadeRuntime {
         state_root: MerkleHash,
         account_id: &AccountId,
     ) -> Result<Account, Box<dyn std::error::Error>> {
-        let state_update = TrieUpdate::new(self.trie.clone(), state_root);
+        let state_update = TrieUpdate::new_with_flat_state(self.trie.clone(), state_root);
         self.trie_viewer.view_account(&state_update, account_id)
     }
 
@@ -416,7 +620,7 @@ impl node_runtime::adapter::ViewRuntimeAdapter for NightshadeRuntime {
         args: &[u8],
         logs: &mut Vec<String>,
     ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let state_update = TrieUpdate::new(self.trie.clone(), state_root);
+        let state_update = TrieUpdate::new_with_flat_state(self.trie.clone(), state_root);
         self.trie_viewer.call_function(state_update, height, contract_id, method_name, args, logs)
     }
 
@@ -426,7 +630,7 @@ impl node_runtime::adapter::ViewRuntimeAdapter for NightshadeRuntime {
         account_id: &AccountId,
         public_key: &PublicKey,
     ) -> Result<Option<AccessKey>, Box<dyn std::error::Error>> {
-        let state_update = TrieUpdate::new(self.trie.clone(), state_root);
+        let state_update = TrieUpdate::new_with_flat_state(self.trie.clone(), state_root);
         self.trie_viewer.view_access_key(&state_update, account_id, public_key)
     }
 
@@ -435,7 +639,7 @@ impl node_runtime::adapter::ViewRuntimeAdapter for NightshadeRuntime {
         state_root: MerkleHash,
         account_id: &AccountId,
     ) -> Result<Vec<(PublicKey, AccessKey)>, Box<dyn std::error::Error>> {
-        let state_update = TrieUpdate::new(self.trie.clone(), state_root);
+        let state_update = TrieUpdate::new_with_flat_state(self.trie.clone(), state_root);
         let prefix = prefix_for_access_key(account_id);
         match state_update.iter(&prefix) {
             Ok(iter) => iter
@@ -457,9 +661,11 @@ impl node_runtime::adapter::ViewRuntimeAdapter for NightshadeRuntime {
         state_root: MerkleHash,
         account_id: &AccountId,
         prefix: &[u8],
+        start: &[u8],
+        limit: Option<usize>,
     ) -> Result<ViewStateResult, Box<dyn std::error::Error>> {
-        let state_update = TrieUpdate::new(self.trie.clone(), state_root);
-        self.trie_viewer.view_state(&state_update, account_id, prefix)
+        let state_update = TrieUpdate::new_with_flat_state(self.trie.clone(), state_root);
+        self.trie_viewer.view_state(&state_update, account_id, prefix, start, limit)
     }
 }
 
@@ -0,0 +1,155 @@
+//! Export of the chain state at a finalized block into a snapshot file and its import into a fresh
+//! store, so that new nodes can start from that block instead of syncing block by block.
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+use std::sync::Arc;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use log::info;
+
+use near_chain::{ChainStore, ChainStoreAccess, Tip};
+use near_primitives::types::BlockIndex;
+use near_store::snapshot::{restore_snapshot, write_snapshot, SnapshotHeader, SnapshotStats};
+use near_store::{
+    Store, Trie, COL_BLOCK, COL_BLOCK_HEADER, COL_BLOCK_INDEX, COL_BLOCK_MERKLE_TREE,
+    COL_BLOCK_ORDINAL, COL_LAST_EPOCH_PROPOSALS, COL_PROPOSALS, COL_RECEIPTS, COL_STATE_REF,
+    COL_VALIDATORS, COL_VALIDATOR_PROPOSALS,
+};
+
+/// Columns copied into the snapshot as a whole: header chain and validator assignments.
+const SNAPSHOT_COLUMNS: [Option<u32>; 8] = [
+    COL_BLOCK_HEADER,
+    COL_BLOCK_INDEX,
+    COL_BLOCK_MERKLE_TREE,
+    COL_BLOCK_ORDINAL,
+    COL_PROPOSALS,
+    COL_VALIDATORS,
+    COL_LAST_EPOCH_PROPOSALS,
+    COL_VALIDATOR_PROPOSALS,
+];
+
+/// Writes snapshot of the state at the block with given height on the main chain, by default the
+/// last block that can't be reverted by a reorg of `max_reorg_depth` blocks.
+pub fn create_snapshot(
+    store: Arc<Store>,
+    height: Option<BlockIndex>,
+    max_reorg_depth: Option<BlockIndex>,
+    path: &Path,
+) -> Result<(Tip, SnapshotStats), Box<dyn std::error::Error>> {
+    let mut chain_store = ChainStore::new(store.clone());
+    let head = chain_store.head()?;
+    let height =
+        height.unwrap_or_else(|| head.height.saturating_sub(max_reorg_depth.unwrap_or(0)));
+    if height > head.height {
+        return Err(format!("Height {} is above the head {}", height, head.height).into());
+    }
+    let hash = chain_store.get_block_hash_by_height(height)?;
+    let tip = Tip::from_header(chain_store.get_block_header(&hash)?);
+    let state_root = *chain_store.get_post_state_root(&hash)?;
+    let genesis_hash = chain_store.get_block_hash_by_height(0)?;
+    info!(target: "near", "Creating snapshot at #{} {}, state root {}", height, hash, state_root);
+
+    let mut records = vec![];
+    for column in SNAPSHOT_COLUMNS.iter() {
+        records.extend(
+            store.iter(*column).map(|(key, value)| (*column, key.to_vec(), value.to_vec())),
+        );
+    }
+    let block_records = [(COL_BLOCK, genesis_hash), (COL_BLOCK, hash), (COL_STATE_REF, hash)];
+    for (column, hash) in block_records.iter() {
+        if let Some(value) = store.get(*column, hash.as_ref())? {
+            records.push((*column, hash.as_ref().to_vec(), value));
+        }
+    }
+    // Receipts the block sends to each shard are keyed by the block hash and the shard.
+    records.extend(
+        store
+            .iter_prefix(COL_RECEIPTS, hash.as_ref())
+            .map(|(key, value)| (COL_RECEIPTS, key.to_vec(), value.to_vec())),
+    );
+
+    let trie = Trie::new(store);
+    let header = SnapshotHeader { state_root, metadata: tip.try_to_vec()? };
+    let writer = BufWriter::new(File::create(path)?);
+    let stats = write_snapshot(&trie, &header, records, writer)?;
+    Ok((tip, stats))
+}
+
+/// Loads snapshot into the store, which must not have any chain data yet, and sets the heads of
+/// the chain to the block of the snapshot.
+pub fn load_snapshot(
+    store: Arc<Store>,
+    path: &Path,
+) -> Result<(Tip, SnapshotStats), Box<dyn std::error::Error>> {
+    let mut chain_store = ChainStore::new(store.clone());
+    if chain_store.head().is_ok() {
+        return Err("Store already has chain data, snapshot must be loaded into a new one".into());
+    }
+    let trie = Arc::new(Trie::new(store.clone()));
+    let reader = BufReader::new(File::open(path)?);
+    let (header, stats) = restore_snapshot(trie, &store, reader)?;
+    let tip = Tip::try_from_slice(&header.metadata)?;
+
+    let mut store_update = chain_store.store_update();
+    store_update.save_head(&tip)?;
+    store_update.save_body_tail(&tip);
+    store_update.save_sync_head(&tip);
+    store_update.commit()?;
+    Ok((tip, stats))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempdir::TempDir;
+
+    use near_chain::{Block, Chain, Provenance};
+    use near_crypto::{InMemorySigner, KeyType};
+    use near_primitives::test_utils::init_test_logger;
+    use near_store::test_utils::create_test_store;
+
+    use crate::{GenesisConfig, NightshadeRuntime};
+
+    use super::*;
+
+    fn setup_chain(home_dir: &Path, store: Arc<Store>, genesis_config: &GenesisConfig) -> Chain {
+        let runtime =
+            Arc::new(NightshadeRuntime::new(home_dir, store.clone(), genesis_config.clone()));
+        Chain::new(store, runtime, genesis_config.genesis_time).unwrap()
+    }
+
+    #[test]
+    fn test_snapshot_restore() {
+        init_test_logger();
+        let dir = TempDir::new("snapshot").unwrap();
+        let genesis_config = GenesisConfig::test(vec!["test0"]);
+        let signer = Arc::new(InMemorySigner::from_seed("test0", KeyType::ED25519, "test0"));
+        let store = create_test_store();
+        let mut chain = setup_chain(dir.path(), store.clone(), &genesis_config);
+        for _ in 0..5 {
+            let prev = chain.head_header().unwrap();
+            let block = Block::empty(&prev, signer.clone());
+            chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+        }
+        let path = dir.path().join("snapshot.gz");
+        let (tip, _) = create_snapshot(store, None, Some(2), &path).unwrap();
+        assert_eq!(tip.height, 3);
+
+        let new_store = create_test_store();
+        let (restored_tip, stats) = load_snapshot(new_store.clone(), &path).unwrap();
+        assert_eq!(restored_tip, tip);
+        assert!(stats.num_state_items > 0);
+        // Restoring into a store with chain data fails.
+        assert!(load_snapshot(new_store.clone(), &path).is_err());
+
+        // New node continues the chain from the block of the snapshot.
+        let mut new_chain = setup_chain(dir.path(), new_store, &genesis_config);
+        assert_eq!(new_chain.head().unwrap(), tip);
+        let prev = new_chain.head_header().unwrap();
+        let block = Block::empty(&prev, signer.clone());
+        let head = new_chain.process_block(block, Provenance::PRODUCED, |_, _, _| {}).unwrap();
+        assert_eq!(head.unwrap().height, 4);
+    }
+}
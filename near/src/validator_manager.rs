@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
@@ -12,6 +13,7 @@ use near_primitives::hash::CryptoHash;
 use near_primitives::types::{
     AccountId, Balance, BlockIndex, ShardId, ValidatorId, ValidatorStake,
 };
+use near_primitives::views::UnstakeInfoView;
 use near_store::{Store, StoreUpdate, COL_LAST_EPOCH_PROPOSALS, COL_PROPOSALS, COL_VALIDATORS};
 
 const LAST_EPOCH_KEY: &[u8] = b"LAST_EPOCH";
@@ -243,6 +245,69 @@ fn get_epoch_block_proposer_info(
     (block_index_to_validator, validator_to_num_blocks)
 }
 
+/// Expected block production during a single epoch of the simulated schedule.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EpochSchedule {
+    /// Index of the first block of the epoch.
+    pub epoch_start: BlockIndex,
+    /// Validators with their stakes for the epoch.
+    pub validators: Vec<ValidatorStake>,
+    /// Number of blocks each validator is scheduled to produce during the epoch.
+    pub num_blocks: BTreeMap<AccountId, u64>,
+}
+
+/// Simulates block producer schedule over `num_epochs` epochs, starting from given initial
+/// validators. `proposals` are staked in the first epoch and take effect two epochs later,
+/// same as on chain. Assumes that all validators produce all their blocks and nobody is kicked out.
+pub fn simulate_schedule(
+    epoch_config: ValidatorEpochConfig,
+    initial_validators: Vec<ValidatorStake>,
+    proposals: Vec<ValidatorStake>,
+    num_epochs: u64,
+) -> Result<Vec<EpochSchedule>, ValidatorError> {
+    if epoch_config.epoch_length == 0 {
+        return Err(ValidatorError::Other("Epoch length must be positive".to_string()));
+    }
+    let mut assignment = proposals_to_assignments(
+        epoch_config.clone(),
+        &ValidatorAssignment::default(),
+        initial_validators,
+        HashMap::new(),
+    )?;
+    let mut proposals = Some(proposals);
+    let mut result = vec![];
+    for epoch in 0..num_epochs {
+        let epoch_start = epoch * epoch_config.epoch_length;
+        let epoch_end = epoch_start + epoch_config.epoch_length - 1;
+        let (_, validator_to_num_blocks) =
+            get_epoch_block_proposer_info(&assignment, epoch_start, epoch_end);
+        let num_blocks = validator_to_num_blocks
+            .into_iter()
+            .map(|(i, num)| (assignment.validators[i].account_id.clone(), num as u64))
+            .collect();
+        result.push(EpochSchedule {
+            epoch_start,
+            validators: assignment.validators.clone(),
+            num_blocks,
+        });
+        // Assignment of the first two epochs is defined by the initial validators.
+        if epoch >= 1 {
+            let validator_kickout = assignment
+                .validators
+                .iter()
+                .map(|validator| (validator.account_id.clone(), false))
+                .collect();
+            assignment = proposals_to_assignments(
+                epoch_config.clone(),
+                &assignment,
+                proposals.take().unwrap_or_default(),
+                validator_kickout,
+            )?;
+        }
+    }
+    Ok(result)
+}
+
 /// Epoch config, determines validator assignment for given epoch.
 /// Can change from epoch to epoch depending on the sharding and other parameters, etc.
 #[derive(Clone)]
@@ -409,6 +474,42 @@ impl ValidatorManager {
         Ok(self.validator_info.get(hash).unwrap())
     }
 
+    /// Same as `get_index_info` for the readers holding a shared reference, blocks which info
+    /// isn't cached are read from the store without caching them.
+    fn read_index_info(
+        &self,
+        hash: &CryptoHash,
+    ) -> Result<Cow<ValidatorIndexInfo>, ValidatorError> {
+        if let Some(info) = self.validator_info.get(hash) {
+            return Ok(Cow::Borrowed(info));
+        }
+        self.store
+            .get_ser(COL_PROPOSALS, hash.as_ref())
+            .map_err(|err| ValidatorError::Other(err.to_string()))?
+            .map(Cow::Owned)
+            .ok_or(ValidatorError::MissingBlock(*hash))
+    }
+
+    /// Same as `get_validators` for the readers holding a shared reference.
+    pub fn read_validators(
+        &self,
+        epoch_hash: &CryptoHash,
+    ) -> Result<Cow<ValidatorAssignment>, ValidatorError> {
+        if let Some(assignment) = self.epoch_validators.get(epoch_hash) {
+            return Ok(Cow::Borrowed(assignment));
+        }
+        self.store
+            .get_ser(COL_VALIDATORS, epoch_hash.as_ref())
+            .map_err(|err| ValidatorError::Other(err.to_string()))?
+            .map(Cow::Owned)
+            .ok_or(ValidatorError::EpochOutOfBounds)
+    }
+
+    fn read_prev_epoch_hash(&self, epoch_hash: &CryptoHash) -> Result<CryptoHash, ValidatorError> {
+        let parent_hash = self.read_index_info(epoch_hash)?.prev_hash;
+        self.read_index_info(&parent_hash).map(|info| info.epoch_start_hash)
+    }
+
     pub fn get_epoch_offset(
         &mut self,
         parent_hash: CryptoHash,
@@ -658,12 +759,73 @@ impl ValidatorManager {
         Ok(store_update)
     }
 
+    /// Computes when pending stake changes of the account take effect, looking from the block
+    /// with given hash. Stake of the epoch's validators is locked until it's no longer used by
+    /// the current or two previous assignments, same as in the runtime. Assumes that no new
+    /// proposals are made and nobody is kicked out.
+    pub fn get_unstake_info(
+        &self,
+        last_hash: &CryptoHash,
+        account_id: &AccountId,
+    ) -> Result<UnstakeInfoView, ValidatorError> {
+        let epoch_hash = self.read_index_info(last_hash)?.epoch_start_hash;
+        let epoch_start = self.read_index_info(&epoch_hash)?.index;
+        let prev_epoch_hash = self.read_prev_epoch_hash(&epoch_hash)?;
+        let prev_prev_epoch_hash = self.read_prev_epoch_hash(&prev_epoch_hash)?;
+        let mut stakes = vec![];
+        for hash in [prev_prev_epoch_hash, prev_epoch_hash, epoch_hash].iter() {
+            stakes.push(*self.read_validators(hash)?.stake_change.get(account_id).unwrap_or(&0));
+        }
+
+        // The latest proposal of the current epoch takes effect in the next assignment,
+        // otherwise the current stake is rolled over.
+        let mut final_stake = stakes[2];
+        let mut hash = *last_hash;
+        'blocks: loop {
+            let info = self.read_index_info(&hash)?;
+            if info.epoch_start_hash != epoch_hash || info.prev_hash == hash {
+                break;
+            }
+            for proposal in info.proposals.iter().rev() {
+                if &proposal.account_id == account_id {
+                    final_stake = proposal.amount;
+                    break 'blocks;
+                }
+            }
+            hash = info.prev_hash;
+        }
+        // `stakes[i]` is the stake in the assignment computed at the start of epoch `i`, where the
+        // current epoch is 2. Assignment computed at the start of an epoch is used in the next one.
+        stakes.extend_from_slice(&[final_stake; 3]);
+        let locked = |epoch: usize| stakes[epoch - 2..=epoch].iter().cloned().max().unwrap();
+        let epoch_length = self.config.epoch_length;
+        let epoch_height = |epoch: usize| epoch_start + (epoch as u64 - 2) * epoch_length;
+
+        let validation_end_height = if stakes[1] > 0 && final_stake == 0 {
+            (3..stakes.len()).find(|epoch| stakes[epoch - 1] == 0).map(epoch_height)
+        } else {
+            None
+        };
+        let unlock_height = if locked(2) > final_stake {
+            (3..stakes.len()).find(|epoch| locked(*epoch) == final_stake).map(epoch_height)
+        } else {
+            None
+        };
+        Ok(UnstakeInfoView {
+            account_id: account_id.clone(),
+            locked: locked(2),
+            final_stake,
+            validation_end_height,
+            unlock_height,
+        })
+    }
+
     pub fn get_block_proposer_info(
-        &mut self,
+        &self,
         epoch_hash: CryptoHash,
         height: BlockIndex,
     ) -> Result<ValidatorStake, Box<dyn std::error::Error>> {
-        let validator_assignment = self.get_validators(epoch_hash)?;
+        let validator_assignment = self.read_validators(&epoch_hash)?;
         if height < validator_assignment.expected_epoch_start {
             return Err(Box::new(ValidatorError::EpochOutOfBounds));
         }
@@ -719,6 +881,26 @@ mod test {
         assert!(find_threshold(&[1, 1, 2], 100).is_err());
     }
 
+    #[test]
+    fn test_simulate_schedule() {
+        let schedule = simulate_schedule(
+            config(4, 1, 2, 0, 0.9),
+            vec![stake("test1", 1_000_000)],
+            vec![stake("test2", 1_000_000)],
+            3,
+        )
+        .unwrap();
+        assert_eq!(schedule.len(), 3);
+        let only_test1: BTreeMap<_, _> = vec![("test1".to_string(), 4)].into_iter().collect();
+        assert_eq!(schedule[0].num_blocks, only_test1);
+        assert_eq!(schedule[1].num_blocks, only_test1);
+        assert_eq!(schedule[2].epoch_start, 8);
+        assert_eq!(
+            schedule[2].num_blocks,
+            vec![("test1".to_string(), 2), ("test2".to_string(), 2)].into_iter().collect()
+        );
+    }
+
     #[test]
     fn test_proposals_to_assignments() {
         assert_eq!(
@@ -1116,6 +1298,56 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_unstake_info() {
+        let store = create_test_store();
+        let config = config(2, 1, 2, 0, 0.9);
+        let amount_staked = 1_000_000;
+        let validators = vec![stake("test1", amount_staked), stake("test2", amount_staked)];
+        let mut vm =
+            ValidatorManager::new(config.clone(), validators.clone(), store.clone()).unwrap();
+        let (h0, h1, h2, h3) = (hash(&[0]), hash(&[1]), hash(&[2]), hash(&[3]));
+        let (test1, test2) = ("test1".to_string(), "test2".to_string());
+        vm.add_proposals(CryptoHash::default(), h0, 0, vec![], vec![], vec![])
+            .unwrap()
+            .commit()
+            .unwrap();
+        vm.add_proposals(h0, h1, 1, vec![stake("test1", 0)], vec![], vec![])
+            .unwrap()
+            .commit()
+            .unwrap();
+        // test1 stops validating at height 4 and gets the stake back at height 6.
+        let unstake_info = UnstakeInfoView {
+            account_id: test1.clone(),
+            locked: amount_staked,
+            final_stake: 0,
+            validation_end_height: Some(4),
+            unlock_height: Some(6),
+        };
+        assert_eq!(vm.get_unstake_info(&h1, &test1).unwrap(), unstake_info);
+        assert_eq!(
+            vm.get_unstake_info(&h1, &test2).unwrap(),
+            UnstakeInfoView {
+                account_id: test2.clone(),
+                locked: amount_staked,
+                final_stake: amount_staked,
+                validation_end_height: None,
+                unlock_height: None,
+            }
+        );
+
+        // Crossing the epoch boundary doesn't change the schedule.
+        vm.finalize_epoch(&h0, &h1, &h2).unwrap();
+        vm.add_proposals(h1, h2, 2, vec![], vec![], vec![]).unwrap().commit().unwrap();
+        vm.add_proposals(h2, h3, 3, vec![], vec![], vec![]).unwrap().commit().unwrap();
+        assert_eq!(vm.get_unstake_info(&h2, &test1).unwrap(), unstake_info);
+        assert_eq!(vm.get_unstake_info(&h3, &test1).unwrap(), unstake_info);
+
+        // Same after restart, from the data in the store.
+        let vm = ValidatorManager::new(config, validators, store).unwrap();
+        assert_eq!(vm.get_unstake_info(&h3, &test1).unwrap(), unstake_info);
+    }
+
     #[test]
     fn test_validator_change_of_stake() {
         let store = create_test_store();
@@ -22,13 +22,7 @@ fn runtime_hanldle_fork() {
     let runtime =
         Arc::new(NightshadeRuntime::new(tmp_dir.path(), store.clone(), genesis_config.clone()));
 
-    let mut chain = Chain::new(
-        store,
-        runtime,
-        genesis_config.genesis_time,
-        genesis_config.transaction_validity_period,
-    )
-    .unwrap();
+    let mut chain = Chain::new(store, runtime, genesis_config.genesis_time).unwrap();
     let block_hash = chain.get_header_by_height(0).unwrap().hash;
 
     let tx1 = SignedTransaction::send_money(
@@ -34,10 +34,64 @@ pub struct Config {
     /// Maximum number of registers that can be used simultaneously.
     pub max_number_registers: u64,
 
-    /// Maximum number of log entries.
-    pub max_number_logs: u64,
-    /// Maximum length of a single log, in bytes.
-    pub max_log_len: u64,
+    /// Limits on the resources used by a single function call and block. They are top level fields
+    /// of the config, as the limits on logs were before the other limits were added.
+    #[serde(flatten)]
+    pub limits: RuntimeLimitsConfig,
+
+    /// Gas cost of creating a storage iterator.
+    #[serde(default = "default_storage_iter_create_base_cost")]
+    pub storage_iter_create_base_cost: Gas,
+    /// Gas cost per byte of the prefix or range bounds of a created storage iterator.
+    #[serde(default = "default_storage_iter_create_byte_cost")]
+    pub storage_iter_create_byte_cost: Gas,
+    /// Gas cost of advancing a storage iterator.
+    #[serde(default = "default_storage_iter_next_base_cost")]
+    pub storage_iter_next_base_cost: Gas,
+    /// Gas cost per byte of the key and value returned by advancing a storage iterator.
+    #[serde(default = "default_storage_iter_next_byte_cost")]
+    pub storage_iter_next_byte_cost: Gas,
+
+    /// Gas cost of a single batched storage call.
+    #[serde(default = "default_storage_batch_base_cost")]
+    pub storage_batch_base_cost: Gas,
+    /// Gas cost per key read or written by a batched storage call.
+    #[serde(default = "default_storage_batch_key_cost")]
+    pub storage_batch_key_cost: Gas,
+    /// Gas cost per byte of keys and values read or written by a batched storage call.
+    #[serde(default = "default_storage_batch_byte_cost")]
+    pub storage_batch_byte_cost: Gas,
+}
+
+// Costs of storage iterators and batched storage calls that are missing from the config, as in
+// the genesis files written before they were added, have their default values.
+
+fn default_storage_iter_create_base_cost() -> Gas {
+    Config::default().storage_iter_create_base_cost
+}
+
+fn default_storage_iter_create_byte_cost() -> Gas {
+    Config::default().storage_iter_create_byte_cost
+}
+
+fn default_storage_iter_next_base_cost() -> Gas {
+    Config::default().storage_iter_next_base_cost
+}
+
+fn default_storage_iter_next_byte_cost() -> Gas {
+    Config::default().storage_iter_next_byte_cost
+}
+
+fn default_storage_batch_base_cost() -> Gas {
+    Config::default().storage_batch_base_cost
+}
+
+fn default_storage_batch_key_cost() -> Gas {
+    Config::default().storage_batch_key_cost
+}
+
+fn default_storage_batch_byte_cost() -> Gas {
+    Config::default().storage_batch_byte_cost
 }
 
 impl Default for Config {
@@ -55,9 +109,53 @@ impl Default for Config {
             max_register_size: 2u64.pow(20) * 100,
             // By default there is at most 100 registers.
             max_number_registers: 100,
+            limits: Default::default(),
+            storage_iter_create_base_cost: 100,
+            storage_iter_create_byte_cost: 1,
+            storage_iter_next_base_cost: 100,
+            storage_iter_next_byte_cost: 1,
+            storage_batch_base_cost: 100,
+            storage_batch_key_cost: 10,
+            storage_batch_byte_cost: 1,
+            runtime_fees: Default::default(),
+        }
+    }
+}
+
+/// Protocol limits that are enforced during the execution of transactions and receipts. Limits
+/// that are missing from the config have their default values.
+#[derive(Clone, Debug, Hash, Serialize, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct RuntimeLimitsConfig {
+    /// Max amount of gas that can be burnt by the transactions and receipts of a single chunk.
+    pub max_gas_per_block: Gas,
+    /// Maximum length of a chain of receipts created by function calls, receipts of transactions
+    /// have depth zero.
+    pub max_receipt_depth: u64,
+    /// Maximum number of promises that can be created by a single function call.
+    pub max_promises_per_function_call: u64,
+    /// Maximum number of log entries.
+    pub max_number_logs: u64,
+    /// Maximum length of a single log, in bytes.
+    pub max_log_len: u64,
+    /// Maximum length of a storage key, in bytes.
+    pub max_storage_key_len: u64,
+    /// Maximum length of a storage value, in bytes.
+    pub max_storage_value_len: u64,
+}
+
+impl Default for RuntimeLimitsConfig {
+    fn default() -> RuntimeLimitsConfig {
+        RuntimeLimitsConfig {
+            max_gas_per_block: 10u64.pow(15),
+            max_receipt_depth: 64,
+            max_promises_per_function_call: 100,
             max_number_logs: 100,
             max_log_len: 500,
-            runtime_fees: Default::default(),
+            // By default keys are limited by 2KiB.
+            max_storage_key_len: 2u64.pow(11),
+            // By default values are limited by 4MiB.
+            max_storage_value_len: 2u64.pow(22),
         }
     }
 }
@@ -16,8 +16,13 @@ pub enum HostError {
     CannotReturnJointPromise,
     InvalidPromiseResultIndex,
     InvalidRegisterId,
+    InvalidStorageBatch,
     IteratorWasInvalidated,
     MemoryAccessViolation,
+    TooManyLogs,
+    TooManyPromises,
+    KeyLengthExceeded,
+    ValueLengthExceeded,
 }
 
 impl From<ExternalError> for HostError {
@@ -47,8 +52,13 @@ impl std::fmt::Display for HostError {
             CannotReturnJointPromise => write!(f, "Returning joint promise is currently prohibited."),
             InvalidPromiseResultIndex => write!(f, "Accessed invalid promise result index."),
             InvalidRegisterId => write!(f, "Accessed invalid register id"),
+            InvalidStorageBatch => write!(f, "Storage batch is not a sequence of length-prefixed keys and values"),
             IteratorWasInvalidated => write!(f, "Iterator was invalidated after its creation by performing a mutable operation on trie"),
             MemoryAccessViolation => write!(f, "Accessed memory outside the bounds."),
+            TooManyLogs => write!(f, "Exceeded the maximum number of logs per function call."),
+            TooManyPromises => write!(f, "Exceeded the maximum number of promises per function call."),
+            KeyLengthExceeded => write!(f, "Storage key is longer than allowed."),
+            ValueLengthExceeded => write!(f, "Storage value is longer than allowed."),
         }
     }
 }
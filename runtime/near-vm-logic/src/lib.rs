@@ -8,9 +8,9 @@ pub mod mocks;
 pub mod serde_with;
 
 pub mod types;
-pub use config::Config;
+pub use config::{Config, RuntimeLimitsConfig};
 pub use context::VMContext;
 pub use dependencies::{External, ExternalError, MemoryLike};
 pub use errors::HostError;
-pub use logic::{VMLogic, VMOutcome};
+pub use logic::{VMLogic, VMOutcome, EVENT_LOG_PREFIX, LOG_PREFIX};
 pub use types::ReturnData;
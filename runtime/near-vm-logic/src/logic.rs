@@ -12,6 +12,15 @@ use std::mem::size_of;
 
 type Result<T> = ::std::result::Result<T, HostError>;
 
+/// Prefix of every log message emitted by the contract.
+pub const LOG_PREFIX: &str = "LOG: ";
+
+/// Prefix of the log message that carries a structured event, encoded as a JSON object with
+/// `standard`, `version`, `event` and optional `data` fields. For example:
+/// `EVENT_JSON:{"standard":"nep141","version":"1.0.0","event":"ft_transfer","data":[...]}`.
+/// Such logs are parsed by the node and exposed as events in the execution outcomes.
+pub const EVENT_LOG_PREFIX: &str = "EVENT_JSON:";
+
 pub struct VMLogic<'a> {
     /// Provides access to the components outside the Wasm runtime for operations on the trie and
     /// receipts creation.
@@ -47,6 +56,8 @@ pub struct VMLogic<'a> {
     valid_iterators: HashSet<IteratorIndex>,
     /// Iterators that became invalidated by mutating the trie.
     invalid_iterators: HashSet<IteratorIndex>,
+    /// Iterators that reached their end. They keep returning no values and are not invalidated.
+    exhausted_iterators: HashSet<IteratorIndex>,
 
     /// The DAG of promises, indexed by promise id.
     promises: Vec<Promise>,
@@ -96,6 +107,7 @@ impl<'a> VMLogic<'a> {
             registers: HashMap::new(),
             valid_iterators: HashSet::new(),
             invalid_iterators: HashSet::new(),
+            exhausted_iterators: HashSet::new(),
             promises: vec![],
             receipt_to_account: HashMap::new(),
         }
@@ -437,14 +449,25 @@ impl<'a> VMLogic<'a> {
         Ok(())
     }
 
+    /// Checks that one more receipt can be created without exceeding the promises limit.
+    fn check_can_create_receipt(&self) -> Result<()> {
+        if self.receipt_to_account.len() as u64 >= self.config.limits.max_promises_per_function_call
+        {
+            return Err(HostError::TooManyPromises);
+        }
+        Ok(())
+    }
+
     /// Creates a promise that will execute a method on account with given arguments and attaches
     /// the given amount and gas. `amount_ptr` point to slices of bytes representing `u128`.
     ///
     /// # Errors
     ///
-    /// If `account_id_len + account_id_ptr` or `method_name_len + method_name_ptr` or
-    /// `arguments_len + arguments_ptr` or `amount_ptr + 16` points outside the memory of the guest
-    /// or host, with `MemoryAccessViolation`.
+    /// * If `account_id_len + account_id_ptr` or `method_name_len + method_name_ptr` or
+    ///   `arguments_len + arguments_ptr` or `amount_ptr + 16` points outside the memory of the
+    ///   guest or host, with `MemoryAccessViolation`;
+    /// * If the number of created promises exceeds `max_promises_per_function_call` returns
+    ///   `TooManyPromises`.
     ///
     /// # Returns
     ///
@@ -468,6 +491,7 @@ impl<'a> VMLogic<'a> {
             return Err(HostError::EmptyMethodName);
         }
         let arguments = Self::memory_get(self.memory, arguments_ptr, arguments_len)?;
+        self.check_can_create_receipt()?;
         let sir = account_id == self.context.current_account_id;
         let num_bytes = method_name_len + arguments_len;
         self.pay_gas_for_contract_call(sir, gas, num_bytes, &[])?;
@@ -495,7 +519,9 @@ impl<'a> VMLogic<'a> {
     /// * If `promise_idx` does not correspond to an existing promise returns `InvalidPromiseIndex`;
     /// * If `account_id_len + account_id_ptr` or `method_name_len + method_name_ptr` or
     ///   `arguments_len + arguments_ptr` or `amount_ptr + 16` points outside the memory of the
-    ///   guest or host, with `MemoryAccessViolation`.
+    ///   guest or host, with `MemoryAccessViolation`;
+    /// * If the number of created promises exceeds `max_promises_per_function_call` returns
+    ///   `TooManyPromises`.
     ///
     /// # Returns
     ///
@@ -528,6 +554,7 @@ impl<'a> VMLogic<'a> {
             PromiseToReceipts::Receipt(receipt_idx) => vec![*receipt_idx],
             PromiseToReceipts::NotReceipt(receipt_indices) => receipt_indices.clone(),
         };
+        self.check_can_create_receipt()?;
 
         let sir = account_id == self.context.current_account_id;
         let num_bytes = method_name_len + arguments_len;
@@ -719,18 +746,20 @@ impl<'a> VMLogic<'a> {
     /// # Errors
     ///
     /// * If string extends outside the memory of the guest with `MemoryAccessViolation`;
-    /// * If string is not UTF-8 returns `BadUtf8`.
+    /// * If string is not UTF-8 returns `BadUtf8`;
+    /// * If the number of logs exceeds `max_number_logs` returns `TooManyLogs`.
     pub fn log_utf8(&mut self, len: u64, ptr: u64) -> Result<()> {
+        self.check_can_add_log()?;
         let mut buf;
         if len != std::u64::MAX {
-            if len > self.config.max_log_len {
+            if len > self.config.limits.max_log_len {
                 return Err(HostError::BadUTF8);
             }
             buf = Self::memory_get(self.memory, ptr, len)?;
         } else {
             buf = vec![];
-            for i in 0..=self.config.max_log_len {
-                if i == self.config.max_log_len {
+            for i in 0..=self.config.limits.max_log_len {
+                if i == self.config.limits.max_log_len {
                     return Err(HostError::BadUTF8);
                 }
                 Self::try_fit_mem(self.memory, ptr, i)?;
@@ -742,18 +771,27 @@ impl<'a> VMLogic<'a> {
             }
         }
         let str = String::from_utf8(buf).map_err(|_| HostError::BadUTF8)?;
-        let message = format!("LOG: {}", str);
+        let message = format!("{}{}", LOG_PREFIX, str);
         self.logs.push(message);
         Ok(())
     }
 
-    /// Helper function to read UTF-16 from guest memory.
+    /// Checks that one more log can be added without exceeding the logs limit.
+    fn check_can_add_log(&self) -> Result<()> {
+        if self.logs.len() as u64 >= self.config.limits.max_number_logs {
+            return Err(HostError::TooManyLogs);
+        }
+        Ok(())
+    }
+
+    /// Helper function to read UTF-16 from guest memory. Strings longer than `max_log_len` bytes
+    /// are rejected with `BadUTF16`, since they are only read to be logged.
     pub fn get_utf16(&mut self, _len: u64, ptr: u64) -> Result<String> {
         let mut slice = [0u8; 4];
         let buf = Self::memory_get(self.memory, ptr - 4, 4)?;
         slice.copy_from_slice(&buf);
         let len: u32 = u32::from_le_bytes(slice);
-        if len % 2 != 0 {
+        if len % 2 != 0 || u64::from(len) > self.config.limits.max_log_len {
             return Err(HostError::BadUTF16);
         }
         let buffer = Self::memory_get(self.memory, ptr, len as _)?;
@@ -771,17 +809,26 @@ impl<'a> VMLogic<'a> {
     /// # Errors
     ///
     /// * If string extends outside the memory of the guest with `MemoryAccessViolation`;
-    /// * If string is not UTF-16 returns `BadUtf16`.
+    /// * If string is not UTF-16 or is longer than `max_log_len` returns `BadUtf16`;
+    /// * If the number of logs exceeds `max_number_logs` returns `TooManyLogs`.
     pub fn log_utf16(&mut self, len: u64, ptr: u64) -> Result<()> {
+        self.check_can_add_log()?;
         let str = self.get_utf16(len, ptr)?;
-        let message = format!("LOG: {}", str);
+        let message = format!("{}{}", LOG_PREFIX, str);
         self.logs.push(message);
         Ok(())
     }
 
     /// Special import kept for compatibility with AssemblyScript contracts. Not called by smart
     /// contracts directly, but instead called by the code generated by AssemblyScript.
+    ///
+    /// # Errors
+    ///
+    /// * If the message or the file name is longer than `max_log_len` returns `BadUtf16`;
+    /// * If the number of logs exceeds `max_number_logs` returns `TooManyLogs`;
+    /// * Otherwise returns `GuestPanic`.
     pub fn abort(&mut self, msg_ptr: u32, filename_ptr: u32, line: u32, col: u32) -> Result<()> {
+        self.check_can_add_log()?;
         let msg = self.get_utf16(std::u64::MAX, msg_ptr as _)?;
         let filename = self.get_utf16(std::u64::MAX, filename_ptr as _)?;
 
@@ -854,7 +901,9 @@ impl<'a> VMLogic<'a> {
     /// * If `key_len + key_ptr` or `value_len + value_ptr` exceeds the memory container or points
     ///   to an unused register it returns `MemoryAccessViolation`;
     /// * If returning the preempted value into the registers exceed the memory container it returns
-    ///   `MemoryAccessViolation`.
+    ///   `MemoryAccessViolation`;
+    /// * If the key or the value is longer than allowed returns `KeyLengthExceeded` or
+    ///   `ValueLengthExceeded`.
     pub fn storage_write(
         &mut self,
         key_len: u64,
@@ -863,6 +912,7 @@ impl<'a> VMLogic<'a> {
         value_ptr: u64,
         register_id: u64,
     ) -> Result<u64> {
+        Self::check_storage_entry_len(self.config, key_len, value_len)?;
         let Self { memory, registers, config, valid_iterators, invalid_iterators, ext, .. } = self;
         // All iterators that were valid now become invalid
         for invalidated_iter_idx in valid_iterators.drain() {
@@ -949,6 +999,162 @@ impl<'a> VMLogic<'a> {
         }
     }
 
+    /// Checks the lengths of the key and the value that are about to be written against the limits.
+    fn check_storage_entry_len(config: &Config, key_len: u64, value_len: u64) -> Result<()> {
+        if key_len > config.limits.max_storage_key_len {
+            return Err(HostError::KeyLengthExceeded);
+        }
+        if value_len > config.limits.max_storage_value_len {
+            return Err(HostError::ValueLengthExceeded);
+        }
+        Ok(())
+    }
+
+    /// Splits batch into the items, each encoded as `u32` little-endian length followed by the bytes.
+    fn decode_storage_batch(mut data: &[u8]) -> Result<Vec<&[u8]>> {
+        let mut items = vec![];
+        while !data.is_empty() {
+            if data.len() < size_of::<u32>() {
+                return Err(HostError::InvalidStorageBatch);
+            }
+            let mut len_bytes = [0u8; size_of::<u32>()];
+            len_bytes.copy_from_slice(&data[..size_of::<u32>()]);
+            let len = u32::from_le_bytes(len_bytes) as usize;
+            data = &data[size_of::<u32>()..];
+            if data.len() < len {
+                return Err(HostError::InvalidStorageBatch);
+            }
+            items.push(&data[..len]);
+            data = &data[len..];
+        }
+        Ok(items)
+    }
+
+    /// Charges gas for a batched storage call touching given number of keys and bytes.
+    fn pay_storage_batch(&mut self, num_keys: u64, num_bytes: u64) -> Result<()> {
+        let key_cost = num_keys.checked_mul(self.config.storage_batch_key_cost);
+        let byte_cost = num_bytes.checked_mul(self.config.storage_batch_byte_cost);
+        let gas = key_cost
+            .and_then(|key_cost| byte_cost.and_then(|byte_cost| key_cost.checked_add(byte_cost)))
+            .and_then(|gas| gas.checked_add(self.config.storage_batch_base_cost))
+            .ok_or(HostError::IntegerOverflow)?;
+        self.deduct_gas(gas, gas)
+    }
+
+    /// Charges gas for given number of bytes read by a batched storage call.
+    fn pay_storage_batch_bytes(&mut self, num_bytes: u64) -> Result<()> {
+        let gas = num_bytes
+            .checked_mul(self.config.storage_batch_byte_cost)
+            .ok_or(HostError::IntegerOverflow)?;
+        self.deduct_gas(gas, gas)
+    }
+
+    /// Reads values stored under many keys in a single call.
+    /// Keys are encoded as a sequence of `u32` little-endian key length followed by the key bytes.
+    /// The values are written into the `register_id` in the same order, each encoded as `u32`
+    /// little-endian value length followed by the value bytes. Missing values are encoded with
+    /// length `u32::max_value()` and no bytes. Returns the number of keys that are present.
+    ///
+    /// Costs `storage_batch_base_cost` gas, plus `storage_batch_key_cost` per key and
+    /// `storage_batch_byte_cost` per byte of the keys and the read values. The keys are paid
+    /// before any of them is read and each value is paid as soon as it's read.
+    ///
+    /// # Errors
+    ///
+    /// * If `keys_len + keys_ptr` exceeds the memory container it returns `MemoryAccessViolation`;
+    /// * If keys are not encoded correctly returns `InvalidStorageBatch`;
+    /// * If the cost exceeds the remaining gas returns `GasExceeded` or `GasLimitExceeded`;
+    /// * If the read values exceed the register limits returns `MemoryAccessViolation`.
+    pub fn storage_read_batch(
+        &mut self,
+        keys_len: u64,
+        keys_ptr: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        let data = Self::memory_get(self.memory, keys_ptr, keys_len)?;
+        let keys = Self::decode_storage_batch(&data)?;
+        self.pay_storage_batch(keys.len() as u64, keys_len)?;
+        let mut result = vec![];
+        let mut num_present = 0;
+        for key in keys.iter() {
+            match self.ext.storage_get(key)? {
+                Some(value) => {
+                    if value.len() >= u32::max_value() as usize {
+                        return Err(HostError::MemoryAccessViolation);
+                    }
+                    self.pay_storage_batch_bytes(4 + value.len() as u64)?;
+                    result.extend_from_slice(&(value.len() as u32).to_le_bytes());
+                    result.extend_from_slice(&value);
+                    num_present += 1;
+                }
+                None => {
+                    self.pay_storage_batch_bytes(4)?;
+                    result.extend_from_slice(&u32::max_value().to_le_bytes());
+                }
+            }
+        }
+        let Self { registers, config, .. } = self;
+        Self::internal_write_register(registers, config, register_id, &result)?;
+        Ok(num_present)
+    }
+
+    /// Writes many key-value pairs in a single call.
+    /// Entries are encoded as a sequence of `u32` little-endian key length, key bytes, `u32`
+    /// little-endian value length and value bytes. Entries are written in order, so the last
+    /// value for a repeated key wins. Returns the number of keys that were already in use.
+    /// Same as `storage_write`, invalidates all the iterators.
+    ///
+    /// Costs `storage_batch_base_cost` gas, plus `storage_batch_key_cost` per entry and
+    /// `storage_batch_byte_cost` per byte of the entries.
+    ///
+    /// # Errors
+    ///
+    /// * If `entries_len + entries_ptr` exceeds the memory container it returns
+    ///   `MemoryAccessViolation`;
+    /// * If entries are not encoded correctly returns `InvalidStorageBatch`;
+    /// * If any key or value is longer than allowed returns `KeyLengthExceeded` or
+    ///   `ValueLengthExceeded`;
+    /// * If the cost exceeds the remaining gas returns `GasExceeded` or `GasLimitExceeded`.
+    pub fn storage_write_batch(&mut self, entries_len: u64, entries_ptr: u64) -> Result<u64> {
+        let data = Self::memory_get(self.memory, entries_ptr, entries_len)?;
+        let items = Self::decode_storage_batch(&data)?;
+        if items.len() % 2 != 0 {
+            return Err(HostError::InvalidStorageBatch);
+        }
+        for entry in items.chunks(2) {
+            let (key_len, value_len) = (entry[0].len() as u64, entry[1].len() as u64);
+            Self::check_storage_entry_len(self.config, key_len, value_len)?;
+        }
+        self.pay_storage_batch((items.len() / 2) as u64, entries_len)?;
+
+        // All iterators that were valid now become invalid
+        for invalidated_iter_idx in self.valid_iterators.drain() {
+            self.ext.storage_iter_drop(invalidated_iter_idx)?;
+            self.invalid_iterators.insert(invalidated_iter_idx);
+        }
+        let storage_config = &self.config.runtime_fees.storage_usage_config;
+        let mut num_evicted = 0;
+        for entry in items.chunks(2) {
+            let (key, value) = (entry[0], entry[1]);
+            let key_len = key.len() as u64;
+            let value_len = value.len() as u64;
+            match self.ext.storage_set(key, value)? {
+                Some(old_value) => {
+                    self.current_storage_usage -=
+                        (old_value.len() as u64) * storage_config.value_cost_per_byte;
+                    self.current_storage_usage += value_len * storage_config.value_cost_per_byte;
+                    num_evicted += 1;
+                }
+                None => {
+                    self.current_storage_usage += value_len * storage_config.value_cost_per_byte;
+                    self.current_storage_usage += key_len * storage_config.key_cost_per_byte;
+                    self.current_storage_usage += storage_config.data_record_cost;
+                }
+            }
+        }
+        Ok(num_evicted)
+    }
+
     /// Checks if there is a key-value pair.
     /// * If key is used returns `1`, even if the value is zero bytes;
     /// * Otherwise returns `0`.
@@ -962,17 +1168,30 @@ impl<'a> VMLogic<'a> {
         Ok(res as u64)
     }
 
+    /// Charges gas for creating a storage iterator with bounds of the given total length.
+    fn pay_storage_iter_create(&mut self, bounds_len: u64) -> Result<()> {
+        let gas = bounds_len
+            .checked_mul(self.config.storage_iter_create_byte_cost)
+            .and_then(|gas| gas.checked_add(self.config.storage_iter_create_base_cost))
+            .ok_or(HostError::IntegerOverflow)?;
+        self.deduct_gas(gas, gas)
+    }
+
     /// Creates an iterator object inside the host. Returns the identifier that uniquely
     /// differentiates the given iterator from other iterators that can be simultaneously created.
     /// * It iterates over the keys that have the provided prefix. The order of iteration is defined
     ///   by the lexicographic order of the bytes in the keys;
     /// * If there are no keys, it creates an empty iterator, see below on empty iterators.
     ///
+    /// Costs `storage_iter_create_base_cost + storage_iter_create_byte_cost * prefix_len` gas.
+    ///
     /// # Errors
     ///
-    /// If `prefix_len + prefix_ptr` exceeds the memory container it returns `MemoryAccessViolation`.
+    /// * If `prefix_len + prefix_ptr` exceeds the memory container it returns `MemoryAccessViolation`;
+    /// * If the creation cost exceeds the remaining gas returns `GasExceeded` or `GasLimitExceeded`.
     pub fn storage_iter_prefix(&mut self, prefix_len: u64, prefix_ptr: u64) -> Result<u64> {
         let prefix = Self::memory_get(self.memory, prefix_ptr, prefix_len)?;
+        self.pay_storage_iter_create(prefix_len)?;
         let iterator_index = self.ext.storage_iter(&prefix)?;
         self.valid_iterators.insert(iterator_index);
         Ok(iterator_index)
@@ -983,10 +1202,17 @@ impl<'a> VMLogic<'a> {
     /// empty iterator. Note, this definition allows for `start` or `end` keys to not actually exist
     /// on the given trie.
     ///
+    /// Range iterators are used to paginate over the large state across several calls: the next
+    /// page starts at the last returned key with a zero byte appended.
+    ///
+    /// Costs `storage_iter_create_base_cost + storage_iter_create_byte_cost * (start_len + end_len)`
+    /// gas.
+    ///
     /// # Errors
     ///
-    /// If `start_len + start_ptr` or `end_len + end_ptr` exceeds the memory container or points to
-    /// an unused register it returns `MemoryAccessViolation`.
+    /// * If `start_len + start_ptr` or `end_len + end_ptr` exceeds the memory container or points
+    ///   to an unused register it returns `MemoryAccessViolation`;
+    /// * If the creation cost exceeds the remaining gas returns `GasExceeded` or `GasLimitExceeded`.
     pub fn storage_iter_range(
         &mut self,
         start_len: u64,
@@ -996,6 +1222,8 @@ impl<'a> VMLogic<'a> {
     ) -> Result<u64> {
         let start_key = Self::memory_get(self.memory, start_ptr, start_len)?;
         let end_key = Self::memory_get(self.memory, end_ptr, end_len)?;
+        let bounds_len = start_len.checked_add(end_len).ok_or(HostError::IntegerOverflow)?;
+        self.pay_storage_iter_create(bounds_len)?;
         let iterator_index = self.ext.storage_iter_range(&start_key, &end_key)?;
         self.valid_iterators.insert(iterator_index);
         Ok(iterator_index)
@@ -1004,46 +1232,58 @@ impl<'a> VMLogic<'a> {
     /// Advances iterator and saves the next key and value in the register.
     /// * If iterator is not empty (after calling next it points to a key-value), copies the key
     ///   into `key_register_id` and value into `value_register_id` and returns `1`;
-    /// * If iterator is empty returns `0`;
+    /// * If iterator is empty returns `0`. Once exhausted the iterator keeps returning `0` and is
+    ///   not affected by the subsequent writes;
     /// This allows us to iterate over the keys that have zero bytes stored in values.
     ///
+    /// Costs `storage_iter_next_base_cost` gas, plus `storage_iter_next_byte_cost` per byte of the
+    /// returned key and value.
+    ///
     /// # Errors
     ///
     /// * If `key_register_id == value_register_id` returns `MemoryAccessViolation`;
+    /// * If the cost exceeds the remaining gas returns `GasExceeded` or `GasLimitExceeded`;
     /// * If the registers exceed the memory limit returns `MemoryAccessViolation`;
     /// * If `iterator_id` does not correspond to an existing iterator returns `InvalidIteratorId`;
-    /// * If between the creation of the iterator and calling `storage_iter_next` the range over
-    ///   which it iterates was modified returns `IteratorWasInvalidated`. Specifically, if
-    ///   `storage_write` or `storage_remove` was invoked on the key key such that:
-    ///   * in case of `storage_iter_prefix`. `key` has the given prefix and:
-    ///     * Iterator was not called next yet.
-    ///     * `next` was already called on the iterator and it is currently pointing at the `key`
-    ///       `curr` such that `curr <= key`.
-    ///   * in case of `storage_iter_range`. `start<=key<end` and:
-    ///     * Iterator was not called `next` yet.
-    ///     * `next` was already called on the iterator and it is currently pointing at the key
-    ///       `curr` such that `curr<=key<end`.
+    /// * If `storage_write` or `storage_remove` was called after the creation of the iterator and
+    ///   before it got exhausted returns `IteratorWasInvalidated`, regardless of the written key.
     pub fn storage_iter_next(
         &mut self,
         iterator_id: u64,
         key_register_id: u64,
         value_register_id: u64,
     ) -> Result<u64> {
-        let Self { ext, registers, config, valid_iterators, invalid_iterators, .. } = self;
-        if invalid_iterators.contains(&iterator_id) {
+        if self.invalid_iterators.contains(&iterator_id) {
             return Err(HostError::IteratorWasInvalidated);
-        } else if !valid_iterators.contains(&iterator_id) {
+        } else if !self.valid_iterators.contains(&iterator_id)
+            && !self.exhausted_iterators.contains(&iterator_id)
+        {
             return Err(HostError::InvalidIteratorIndex);
         }
+        let base_cost = self.config.storage_iter_next_base_cost;
+        self.deduct_gas(base_cost, base_cost)?;
+        if self.exhausted_iterators.contains(&iterator_id) {
+            return Ok(0);
+        }
 
-        let value = ext.storage_iter_next(iterator_id)?;
+        let value = self.ext.storage_iter_next(iterator_id)?;
         match value {
             Some((key, value)) => {
+                let byte_cost = ((key.len() + value.len()) as u64)
+                    .checked_mul(self.config.storage_iter_next_byte_cost)
+                    .ok_or(HostError::IntegerOverflow)?;
+                self.deduct_gas(byte_cost, byte_cost)?;
+                let Self { registers, config, .. } = self;
                 Self::internal_write_register(registers, config, key_register_id, &key)?;
                 Self::internal_write_register(registers, config, value_register_id, &value)?;
                 Ok(1)
             }
-            None => Ok(0),
+            None => {
+                self.ext.storage_iter_drop(iterator_id)?;
+                self.valid_iterators.remove(&iterator_id);
+                self.exhausted_iterators.insert(iterator_id);
+                Ok(0)
+            }
         }
     }
 
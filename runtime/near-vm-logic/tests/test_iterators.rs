@@ -0,0 +1,99 @@
+use crate::fixtures::get_context;
+use near_vm_logic::mocks::mock_external::MockedExternal;
+use near_vm_logic::mocks::mock_memory::MockedMemory;
+use near_vm_logic::{Config, HostError, VMLogic};
+
+mod fixtures;
+
+fn write(logic: &mut VMLogic, key: &[u8], value: &[u8]) {
+    logic
+        .storage_write(
+            key.len() as _,
+            key.as_ptr() as _,
+            value.len() as _,
+            value.as_ptr() as _,
+            0,
+        )
+        .unwrap();
+}
+
+#[test]
+fn test_iterator_next_after_end() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let config = Config::default();
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    write(&mut logic, b"foo1", b"bar1");
+    write(&mut logic, b"foo2", b"bar2");
+    write(&mut logic, b"other", b"value");
+
+    let prefix = b"foo";
+    let iter_id = logic.storage_iter_prefix(prefix.len() as _, prefix.as_ptr() as _).unwrap();
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(1));
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(1));
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(0));
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(0));
+
+    // Exhausted iterator is not affected by the writes.
+    write(&mut logic, b"foo3", b"bar3");
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(0));
+}
+
+#[test]
+fn test_iterator_invalidated_by_write() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let config = Config::default();
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    write(&mut logic, b"foo1", b"bar1");
+    write(&mut logic, b"foo2", b"bar2");
+
+    let start = b"foo";
+    let end = b"foo2";
+    let iter_id = logic
+        .storage_iter_range(
+            start.len() as _,
+            start.as_ptr() as _,
+            end.len() as _,
+            end.as_ptr() as _,
+        )
+        .unwrap();
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(1));
+    write(&mut logic, b"other", b"value");
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Err(HostError::IteratorWasInvalidated));
+    assert_eq!(logic.storage_iter_next(iter_id + 1, 1, 2), Err(HostError::InvalidIteratorIndex));
+}
+
+#[test]
+fn test_iterator_gas() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let config = Config::default();
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    write(&mut logic, b"foo1", b"bar1");
+
+    let prefix = b"foo";
+    let iter_id = logic.storage_iter_prefix(prefix.len() as _, prefix.as_ptr() as _).unwrap();
+    let create_cost = config.storage_iter_create_base_cost
+        + config.storage_iter_create_byte_cost * prefix.len() as u64;
+    assert_eq!(logic.used_gas().unwrap(), create_cost);
+
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(1));
+    let next_cost = config.storage_iter_next_base_cost + config.storage_iter_next_byte_cost * 8;
+    assert_eq!(logic.used_gas().unwrap(), create_cost + next_cost);
+
+    assert_eq!(logic.storage_iter_next(iter_id, 1, 2), Ok(0));
+    assert_eq!(
+        logic.used_gas().unwrap(),
+        create_cost + next_cost + config.storage_iter_next_base_cost
+    );
+}
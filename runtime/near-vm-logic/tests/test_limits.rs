@@ -0,0 +1,133 @@
+use crate::fixtures::get_context;
+use near_vm_logic::mocks::mock_external::MockedExternal;
+use near_vm_logic::mocks::mock_memory::MockedMemory;
+use near_vm_logic::{Config, HostError, VMLogic};
+
+mod fixtures;
+
+fn create_promise(logic: &mut VMLogic) -> Result<u64, HostError> {
+    let account_id = b"bob.near";
+    let method_name = b"foo";
+    let arguments = b"";
+    let amount = 0u128.to_le_bytes();
+    logic.promise_create(
+        account_id.len() as _,
+        account_id.as_ptr() as _,
+        method_name.len() as _,
+        method_name.as_ptr() as _,
+        arguments.len() as _,
+        arguments.as_ptr() as _,
+        amount.as_ptr() as _,
+        0,
+    )
+}
+
+#[test]
+fn test_max_number_logs() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let mut config = Config::default();
+    config.limits.max_number_logs = 2;
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    let message = b"hello";
+    assert_eq!(logic.log_utf8(message.len() as _, message.as_ptr() as _), Ok(()));
+    assert_eq!(logic.log_utf8(message.len() as _, message.as_ptr() as _), Ok(()));
+    assert_eq!(
+        logic.log_utf8(message.len() as _, message.as_ptr() as _),
+        Err(HostError::TooManyLogs)
+    );
+}
+
+/// Encodes the string as UTF-16 preceded by its length in bytes, as AssemblyScript does.
+fn utf16_string(s: &str) -> Vec<u8> {
+    let bytes: Vec<u8> = s.encode_utf16().flat_map(|c| c.to_le_bytes().to_vec()).collect();
+    let mut buf = (bytes.len() as u32).to_le_bytes().to_vec();
+    buf.extend(bytes);
+    buf
+}
+
+#[test]
+fn test_utf16_log_limits() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let mut config = Config::default();
+    config.limits.max_number_logs = 2;
+    config.limits.max_log_len = 4;
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    let short = utf16_string("hi");
+    let long = utf16_string("hey");
+    let (short_ptr, long_ptr) = (short.as_ptr() as u64 + 4, long.as_ptr() as u64 + 4);
+    assert_eq!(logic.log_utf16(std::u64::MAX, long_ptr), Err(HostError::BadUTF16));
+    assert_eq!(logic.log_utf16(std::u64::MAX, short_ptr), Ok(()));
+    assert_eq!(logic.log_utf16(std::u64::MAX, short_ptr), Ok(()));
+    assert_eq!(logic.log_utf16(std::u64::MAX, short_ptr), Err(HostError::TooManyLogs));
+    // The limit is checked before the message is read from the memory.
+    assert_eq!(logic.abort(0, 0, 1, 1), Err(HostError::TooManyLogs));
+}
+
+#[test]
+fn test_max_storage_key_value_len() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let mut config = Config::default();
+    config.limits.max_storage_key_len = 4;
+    config.limits.max_storage_value_len = 6;
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    let (key, value) = (b"key1", b"value1");
+    assert_eq!(
+        logic.storage_write(
+            key.len() as _,
+            key.as_ptr() as _,
+            value.len() as _,
+            value.as_ptr() as _,
+            0
+        ),
+        Ok(0)
+    );
+    let long_key = b"key12";
+    assert_eq!(
+        logic.storage_write(
+            long_key.len() as _,
+            long_key.as_ptr() as _,
+            value.len() as _,
+            value.as_ptr() as _,
+            0
+        ),
+        Err(HostError::KeyLengthExceeded)
+    );
+    let long_value = b"value12";
+    assert_eq!(
+        logic.storage_write(
+            key.len() as _,
+            key.as_ptr() as _,
+            long_value.len() as _,
+            long_value.as_ptr() as _,
+            0
+        ),
+        Err(HostError::ValueLengthExceeded)
+    );
+}
+
+#[test]
+fn test_max_promises_per_function_call() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let mut config = Config::default();
+    config.limits.max_promises_per_function_call = 2;
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    assert_eq!(create_promise(&mut logic), Ok(0));
+    assert_eq!(create_promise(&mut logic), Ok(1));
+    assert_eq!(create_promise(&mut logic), Err(HostError::TooManyPromises));
+}
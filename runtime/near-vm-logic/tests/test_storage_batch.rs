@@ -0,0 +1,87 @@
+use crate::fixtures::get_context;
+use near_vm_logic::mocks::mock_external::MockedExternal;
+use near_vm_logic::mocks::mock_memory::MockedMemory;
+use near_vm_logic::{Config, HostError, VMLogic};
+
+mod fixtures;
+
+fn encode(items: &[&[u8]]) -> Vec<u8> {
+    let mut res = vec![];
+    for item in items {
+        res.extend_from_slice(&(item.len() as u32).to_le_bytes());
+        res.extend_from_slice(item);
+    }
+    res
+}
+
+#[test]
+fn test_storage_batch_write_read() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let config = Config::default();
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    let entries = encode(&[b"key1", b"value1", b"key2", b"", b"key1", b"value2"]);
+    assert_eq!(logic.storage_write_batch(entries.len() as _, entries.as_ptr() as _), Ok(1));
+    let write_cost = config.storage_batch_base_cost
+        + config.storage_batch_key_cost * 3
+        + config.storage_batch_byte_cost * entries.len() as u64;
+    assert_eq!(logic.used_gas().unwrap(), write_cost);
+
+    let keys = encode(&[b"key1", b"key2", b"key3"]);
+    assert_eq!(logic.storage_read_batch(keys.len() as _, keys.as_ptr() as _, 0), Ok(2));
+    let mut expected = encode(&[b"value2", b""]);
+    expected.extend_from_slice(&u32::max_value().to_le_bytes());
+    let read_cost = config.storage_batch_base_cost
+        + config.storage_batch_key_cost * 3
+        + config.storage_batch_byte_cost * (keys.len() + expected.len()) as u64;
+    assert_eq!(logic.used_gas().unwrap(), write_cost + read_cost);
+    let buffer = vec![0u8; expected.len()];
+    assert_eq!(logic.register_len(0), Ok(expected.len() as u64));
+    logic.read_register(0, buffer.as_ptr() as _).unwrap();
+    assert_eq!(buffer, expected);
+}
+
+#[test]
+fn test_storage_batch_read_out_of_gas() {
+    let mut ext = MockedExternal::new();
+    let mut context = get_context(vec![]);
+    let config = Config::default();
+    let keys = encode(&[b"key1", b"key2", b"key3"]);
+    // Enough gas for the keys only.
+    context.prepaid_gas = config.storage_batch_base_cost
+        + config.storage_batch_key_cost * 3
+        + config.storage_batch_byte_cost * (keys.len() as u64 + 1);
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    assert_eq!(
+        logic.storage_read_batch(keys.len() as _, keys.as_ptr() as _, 0),
+        Err(HostError::GasExceeded)
+    );
+    assert_eq!(logic.register_len(0), Ok(u64::max_value()));
+}
+
+#[test]
+fn test_storage_batch_invalid() {
+    let mut ext = MockedExternal::new();
+    let context = get_context(vec![]);
+    let config = Config::default();
+    let promise_results = vec![];
+    let mut memory = MockedMemory::new();
+    let mut logic = VMLogic::new(&mut ext, context, &config, &promise_results, &mut memory);
+
+    let entries = encode(&[b"key1"]);
+    assert_eq!(
+        logic.storage_write_batch(entries.len() as _, entries.as_ptr() as _),
+        Err(HostError::InvalidStorageBatch)
+    );
+    let keys = vec![5, 0, 0, 0, 1];
+    assert_eq!(
+        logic.storage_read_batch(keys.len() as _, keys.as_ptr() as _, 0),
+        Err(HostError::InvalidStorageBatch)
+    );
+}
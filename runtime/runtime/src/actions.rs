@@ -119,6 +119,8 @@ pub(crate) fn action_function_call(
         &action_receipt.signer_public_key,
         action_receipt.gas_price,
         action_hash,
+        action_receipt.depth + 1,
+        config.wasm_config.limits.max_receipt_depth,
     );
     // Output data receipts are ignored if the function call is not the last action in the batch.
     let output_data_receivers: Vec<_> = if is_last_action {
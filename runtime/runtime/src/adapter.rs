@@ -1,5 +1,6 @@
 use near_crypto::{PublicKey, ReadablePublicKey};
 use near_primitives::account::{AccessKey, Account};
+use near_primitives::serialize::from_base;
 use near_primitives::types::{AccountId, BlockIndex, MerkleHash};
 use near_primitives::views::{
     AccessKeyInfoView, CallResult, QueryError, QueryResponse, ViewStateResult,
@@ -42,6 +43,8 @@ pub trait ViewRuntimeAdapter {
         state_root: MerkleHash,
         account_id: &AccountId,
         prefix: &[u8],
+        start: &[u8],
+        limit: Option<usize>,
     ) -> Result<ViewStateResult, Box<dyn std::error::Error>>;
 }
 
@@ -77,12 +80,18 @@ pub fn query_client(
                 Err(err) => Ok(QueryResponse::Error(QueryError { error: err.to_string(), logs })),
             }
         }
-        "contract" => match adapter.view_state(state_root, &AccountId::from(path_parts[1]), data) {
-            Ok(result) => Ok(QueryResponse::ViewState(result)),
-            Err(err) => {
-                Ok(QueryResponse::Error(QueryError { error: err.to_string(), logs: vec![] }))
+        // `contract/<account_id>[/<limit>[/<start key in base58>]]`, data is the key prefix.
+        "contract" => {
+            let result = contract_page(&path_parts[2..]).and_then(|(start, limit)| {
+                adapter.view_state(state_root, &AccountId::from(path_parts[1]), data, &start, limit)
+            });
+            match result {
+                Ok(result) => Ok(QueryResponse::ViewState(result)),
+                Err(err) => {
+                    Ok(QueryResponse::Error(QueryError { error: err.to_string(), logs: vec![] }))
+                }
             }
-        },
+        }
         "access_key" => {
             let result = if path_parts.len() == 2 {
                 adapter.view_access_keys(state_root, &AccountId::from(path_parts[1])).map(|r| {
@@ -114,3 +123,19 @@ pub fn query_client(
         _ => Err(format!("Unknown path {}", path).into()),
     }
 }
+
+/// Parses the optional limit and start key of the contract data query.
+fn contract_page(parts: &[&str]) -> Result<(Vec<u8>, Option<usize>), Box<dyn std::error::Error>> {
+    let limit = match parts.get(0) {
+        Some(limit) => match limit.parse::<usize>() {
+            Ok(limit) if limit > 0 => Some(limit),
+            _ => return Err(format!("Invalid limit {}", limit).into()),
+        },
+        None => None,
+    };
+    let start = match parts.get(1) {
+        Some(start) => from_base(start)?,
+        None => vec![],
+    };
+    Ok((start, limit))
+}
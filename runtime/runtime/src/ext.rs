@@ -23,6 +23,9 @@ pub struct RuntimeExt<'a> {
     gas_price: Balance,
     base_data_id: &'a CryptoHash,
     data_count: u64,
+    /// Depth of the created receipts.
+    receipt_depth: u64,
+    max_receipt_depth: u64,
 }
 
 impl<'a> RuntimeExt<'a> {
@@ -33,6 +36,8 @@ impl<'a> RuntimeExt<'a> {
         signer_public_key: &'a PublicKey,
         gas_price: Balance,
         base_data_id: &'a CryptoHash,
+        receipt_depth: u64,
+        max_receipt_depth: u64,
     ) -> Self {
         RuntimeExt {
             trie_update,
@@ -45,6 +50,8 @@ impl<'a> RuntimeExt<'a> {
             gas_price,
             base_data_id,
             data_count: 0,
+            receipt_depth,
+            max_receipt_depth,
         }
     }
 
@@ -134,9 +141,6 @@ impl<'a> External for RuntimeExt<'a> {
             Some(iter) => iter.next(),
             None => return Err(ExternalError::InvalidIteratorIndex),
         };
-        if result.is_none() {
-            self.iters.remove(&iterator_idx);
-        }
         Ok(result.map(|key| {
             (
                 key[self.storage_prefix.len()..].to_vec(),
@@ -159,6 +163,9 @@ impl<'a> External for RuntimeExt<'a> {
         attached_deposit: u128,
         prepaid_gas: u64,
     ) -> Result<u64, ExternalError> {
+        if self.receipt_depth > self.max_receipt_depth {
+            return Err(ExternalError::ReceiptDepthExceeded);
+        }
         let mut input_data_ids = vec![];
         for receipt_index in receipt_indices {
             let data_id = self.new_data_id();
@@ -184,6 +191,7 @@ impl<'a> External for RuntimeExt<'a> {
                 gas: prepaid_gas,
                 deposit: attached_deposit,
             })],
+            depth: self.receipt_depth,
         };
         let new_receipt_index = self.action_receipts.len() as u64;
         self.action_receipts.push((receiver_id, new_receipt));
@@ -195,3 +203,40 @@ impl<'a> External for RuntimeExt<'a> {
         Ok(value_hash.as_ref().to_vec())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use near_crypto::KeyType;
+    use near_primitives::types::MerkleHash;
+    use near_store::test_utils::create_trie;
+
+    use super::*;
+
+    #[test]
+    fn test_max_receipt_depth() {
+        let mut trie_update = TrieUpdate::new(create_trie(), MerkleHash::default());
+        let account_id = "alice.near".to_string();
+        let public_key = PublicKey::empty(KeyType::ED25519);
+        let base_data_id = CryptoHash::default();
+        let mut create_receipt = |receipt_depth| -> Result<Vec<Receipt>, ExternalError> {
+            let mut ext = RuntimeExt::new(
+                &mut trie_update,
+                &account_id,
+                &account_id,
+                &public_key,
+                0,
+                &base_data_id,
+                receipt_depth,
+                2,
+            );
+            ext.receipt_create(vec![], "bob.near".to_string(), b"foo".to_vec(), vec![], 0, 0)?;
+            Ok(ext.into_receipts(&account_id))
+        };
+        let receipts = create_receipt(2).unwrap();
+        match &receipts[0].receipt {
+            ReceiptEnum::Action(action_receipt) => assert_eq!(action_receipt.depth, 2),
+            _ => panic!("the receipt should be an action receipt"),
+        }
+        assert_eq!(create_receipt(3), Err(ExternalError::ReceiptDepthExceeded));
+    }
+}
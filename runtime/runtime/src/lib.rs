@@ -17,7 +17,8 @@ use near_primitives::hash::CryptoHash;
 use near_primitives::receipt::{ActionReceipt, DataReceipt, Receipt, ReceiptEnum, ReceivedData};
 use near_primitives::serialize::from_base64;
 use near_primitives::transaction::{
-    Action, LogEntry, SignedTransaction, TransactionLog, TransactionResult, TransactionStatus,
+    Action, LogEntry, SignedTransaction, Transaction, TransactionLog, TransactionResult,
+    TransactionStatus,
 };
 use near_primitives::types::{
     AccountId, Balance, BlockIndex, Gas, MerkleHash, Nonce, ShardId, ValidatorStake,
@@ -28,6 +29,7 @@ use near_primitives::utils::{
     ACCOUNT_DATA_SEPARATOR,
 };
 use near_runtime_fees::RuntimeFeesConfig;
+use near_store::flat_state::StateChanges;
 use near_store::{
     get, get_account, get_receipt, get_received_data, set, set_access_key, set_account, set_code,
     set_receipt, set_received_data, StoreUpdate, TrieChanges, TrieUpdate,
@@ -55,6 +57,9 @@ mod store;
 
 pub const ETHASH_CACHE_PATH: &str = "ethash_cache";
 
+/// Price of a unit of gas paid by transactions.
+pub const TRANSACTION_GAS_PRICE: Balance = 1;
+
 #[derive(Debug)]
 pub struct ApplyState {
     /// Previous Merkle root of the state.
@@ -73,6 +78,8 @@ pub struct ApplyResult {
     pub root: MerkleHash,
     pub shard_id: ShardId,
     pub trie_changes: TrieChanges,
+    /// Values of the state keys set by the applied transactions and receipts.
+    pub state_changes: StateChanges,
     pub validator_proposals: Vec<ValidatorStake>,
     pub new_receipts: HashMap<ShardId, Vec<Receipt>>,
     pub tx_result: Vec<TransactionLog>,
@@ -147,54 +154,60 @@ impl Runtime {
         debug!(target: "runtime", "{}", log_str);
     }
 
-    /// Processes signed transaction, charges fees and generates the receipt
-    fn apply_signed_transaction(
+    /// Returns gas burnt for sending the transaction and the total cost of the transaction that is
+    /// charged from the signer: fees, prepaid gas and deposits.
+    pub fn transaction_cost(
         &self,
-        state_update: &mut TrieUpdate,
-        apply_state: &ApplyState,
-        signed_transaction: &SignedTransaction,
-    ) -> Result<Receipt, Box<dyn std::error::Error>> {
-        let VerificationData { signer_id, mut signer, public_key, mut access_key } = {
-            let verifier = TransactionVerifier::new(state_update);
-            verifier.verify_transaction(signed_transaction)?
-        };
-        let receiver_id = &signed_transaction.transaction.receiver_id;
-        let sender_id = &signed_transaction.transaction.signer_id;
-        let sender_is_receiver = receiver_id == sender_id;
-
-        apply_rent(&signer_id, &mut signer, apply_state.block_index, &self.config);
-        access_key.nonce = signed_transaction.transaction.nonce;
-
-        let mut total_cost_gas: Gas = safe_add_gas(
+        transaction: &Transaction,
+    ) -> Result<(Gas, Balance), Box<dyn std::error::Error>> {
+        let sender_is_receiver = transaction.receiver_id == transaction.signer_id;
+        let gas_burnt: Gas = safe_add_gas(
             self.config
                 .transaction_costs
                 .action_receipt_creation_config
                 .send_fee(sender_is_receiver),
-            self.config.transaction_costs.action_receipt_creation_config.exec_fee(),
-        )?;
-        total_cost_gas = safe_add_gas(
-            total_cost_gas,
             total_send_fees(
                 &self.config.transaction_costs,
                 sender_is_receiver,
-                &signed_transaction.transaction.actions,
+                &transaction.actions,
             )?,
         )?;
-        total_cost_gas = safe_add_gas(
-            total_cost_gas,
-            total_exec_fees(
-                &self.config.transaction_costs,
-                &signed_transaction.transaction.actions,
-            )?,
+        let mut total_cost_gas: Gas = safe_add_gas(
+            gas_burnt,
+            self.config.transaction_costs.action_receipt_creation_config.exec_fee(),
         )?;
         total_cost_gas = safe_add_gas(
             total_cost_gas,
-            total_prepaid_gas(&signed_transaction.transaction.actions)?,
+            total_exec_fees(&self.config.transaction_costs, &transaction.actions)?,
         )?;
-        let gas_price = 1;
-        let mut total_cost = safe_gas_to_balance(gas_price, total_cost_gas)?;
-        total_cost =
-            safe_add_balance(total_cost, total_deposit(&signed_transaction.transaction.actions)?)?;
+        total_cost_gas = safe_add_gas(total_cost_gas, total_prepaid_gas(&transaction.actions)?)?;
+        let mut total_cost = safe_gas_to_balance(TRANSACTION_GAS_PRICE, total_cost_gas)?;
+        total_cost = safe_add_balance(total_cost, total_deposit(&transaction.actions)?)?;
+        Ok((gas_burnt, total_cost))
+    }
+
+    /// Processes signed transaction, charges fees and generates the receipt.
+    /// Returns the receipt together with the gas burnt for sending it. If the block gas limit is
+    /// exceeded, only the fee for sending the transaction is charged and no receipt is generated.
+    fn apply_signed_transaction(
+        &self,
+        state_update: &mut TrieUpdate,
+        apply_state: &ApplyState,
+        signed_transaction: &SignedTransaction,
+        gas_limit_exceeded: bool,
+    ) -> Result<(Option<Receipt>, Gas), Box<dyn std::error::Error>> {
+        let VerificationData { signer_id, mut signer, public_key, mut access_key } = {
+            let verifier = TransactionVerifier::new(state_update);
+            verifier.verify_transaction(signed_transaction)?
+        };
+        apply_rent(&signer_id, &mut signer, apply_state.block_index, &self.config);
+        access_key.nonce = signed_transaction.transaction.nonce;
+
+        let (gas_burnt, mut total_cost) = self.transaction_cost(&signed_transaction.transaction)?;
+        if gas_limit_exceeded {
+            total_cost = safe_gas_to_balance(TRANSACTION_GAS_PRICE, gas_burnt)?;
+        }
+        let gas_price = TRANSACTION_GAS_PRICE;
         signer.amount = signer.amount.checked_sub(total_cost).ok_or_else(|| {
             format!(
                 "Sender {} does not have enough balance {} for operation costing {}",
@@ -220,8 +233,11 @@ impl Runtime {
             return Err(format!("Failed to execute, because the account {} wouldn't have enough to pay required rent", signer_id).into());
         }
         set_account(state_update, &signer_id, &signer);
+        if gas_limit_exceeded {
+            return Ok((None, gas_burnt));
+        }
 
-        Ok(Receipt {
+        let receipt = Receipt {
             predecessor_id: signer_id.clone(),
             receiver_id: signed_transaction.transaction.receiver_id.clone(),
             receipt_id: create_nonce_with_nonce(&signed_transaction.get_hash(), 0),
@@ -233,8 +249,10 @@ impl Runtime {
                 output_data_receivers: vec![],
                 input_data_ids: vec![],
                 actions: signed_transaction.transaction.actions.clone(),
+                depth: 0,
             }),
-        })
+        };
+        Ok((Some(receipt), gas_burnt))
     }
 
     pub fn process_transaction(
@@ -244,11 +262,24 @@ impl Runtime {
         signed_transaction: &SignedTransaction,
         new_local_receipts: &mut Vec<Receipt>,
         new_receipts: &mut HashMap<ShardId, Vec<Receipt>>,
+        gas_limit_exceeded: bool,
     ) -> TransactionLog {
         let mut result = TransactionResult::default();
-        match self.apply_signed_transaction(state_update, apply_state, signed_transaction) {
-            Ok(receipt) => {
+        match self.apply_signed_transaction(
+            state_update,
+            apply_state,
+            signed_transaction,
+            gas_limit_exceeded,
+        ) {
+            Ok((None, gas_burnt)) => {
+                result.gas_burnt = Some(gas_burnt);
+                state_update.commit();
+                result.logs.push("Runtime error: block gas limit exceeded".to_string());
+                result.status = TransactionStatus::Failed;
+            }
+            Ok((Some(receipt), gas_burnt)) => {
                 result.receipts.push(receipt.receipt_id);
+                result.gas_burnt = Some(gas_burnt);
                 if receipt.receiver_id == signed_transaction.transaction.signer_id {
                     new_local_receipts.push(receipt);
                 } else {
@@ -439,6 +470,7 @@ impl Runtime {
 
         // Calculating and generating refunds
         self.generate_refund_receipts(receipt, action_receipt, &mut result);
+        let gas_burnt = result.gas_burnt;
 
         // Moving validator proposals
         validator_proposals.append(&mut result.validator_proposals);
@@ -526,6 +558,7 @@ impl Runtime {
                 logs: result.logs,
                 receipts: transaction_new_receipt_ids,
                 result: transaction_result,
+                gas_burnt: Some(gas_burnt),
             },
         }
     }
@@ -689,6 +722,24 @@ impl Runtime {
         let mut local_receipts = vec![];
         let mut tx_result = vec![];
         let mut largest_tx_nonce = HashMap::new();
+        let max_gas_per_block = self.config.wasm_config.limits.max_gas_per_block;
+        let mut total_gas_burnt: Gas = 0;
+
+        // Receipts can't be postponed, so they are processed before the transactions, which are
+        // failed once the receipts and the transactions burn the gas limit of the block.
+        for receipt in prev_receipts.iter().flatten() {
+            if let Some(log) = self.process_receipt(
+                &mut state_update,
+                apply_state,
+                receipt,
+                &mut new_receipts,
+                &mut validator_proposals,
+            ) {
+                total_gas_burnt =
+                    total_gas_burnt.saturating_add(log.result.gas_burnt.unwrap_or_default());
+                tx_result.push(log);
+            }
+        }
 
         for signed_transaction in transactions {
             let signer_id = signed_transaction.transaction.signer_id.clone();
@@ -706,30 +757,39 @@ impl Runtime {
                 }
             };
 
-            tx_result.push(self.process_transaction(
+            let log = self.process_transaction(
                 &mut state_update,
                 apply_state,
                 signed_transaction,
                 &mut local_receipts,
                 &mut new_receipts,
-            ));
-        }
+                total_gas_burnt >= max_gas_per_block,
+            );
+            total_gas_burnt =
+                total_gas_burnt.saturating_add(log.result.gas_burnt.unwrap_or_default());
+            tx_result.push(log);
 
-        for receipt in local_receipts.iter().chain(prev_receipts.iter().flatten()) {
-            self.process_receipt(
-                &mut state_update,
-                apply_state,
-                receipt,
-                &mut new_receipts,
-                &mut validator_proposals,
-            )
-            .into_iter()
-            .for_each(|res| tx_result.push(res));
+            // The receipt of a transaction to the signer is processed right away, so that its gas
+            // is counted before the next transaction.
+            for receipt in local_receipts.drain(..) {
+                if let Some(log) = self.process_receipt(
+                    &mut state_update,
+                    apply_state,
+                    &receipt,
+                    &mut new_receipts,
+                    &mut validator_proposals,
+                ) {
+                    total_gas_burnt =
+                        total_gas_burnt.saturating_add(log.result.gas_burnt.unwrap_or_default());
+                    tx_result.push(log);
+                }
+            }
         }
-        let trie_changes = state_update.finalize()?;
+        let (trie_changes, state_changes) = state_update.finalize_with_state_changes()?;
         Ok(ApplyResult {
             root: trie_changes.new_root,
             trie_changes,
+            state_changes,
             validator_proposals,
             shard_id: apply_state.shard_id,
             new_receipts,
@@ -886,10 +946,12 @@ impl Runtime {
 
 #[cfg(test)]
 mod tests {
+    use near_crypto::{InMemorySigner, KeyType};
     use near_primitives::hash::hash;
     use near_primitives::types::MerkleHash;
+    use near_store::get_access_key;
     use near_store::test_utils::create_trie;
-    use testlib::runtime_utils::bob_account;
+    use testlib::runtime_utils::{alice_account, bob_account, get_runtime_and_trie};
 
     use super::*;
 
@@ -918,4 +980,59 @@ mod tests {
         let get_res = get_account(&new_state_update, &account_id).unwrap();
         assert_eq!(test_account, get_res);
     }
+
+    #[test]
+    fn test_block_gas_limit_charges_send_fee() {
+        let (mut runtime, trie, root) = get_runtime_and_trie();
+        runtime.config.wasm_config.limits.max_gas_per_block = 1;
+        let signer = Arc::new(InMemorySigner::from_seed(
+            &alice_account(),
+            KeyType::ED25519,
+            &alice_account(),
+        ));
+        let transactions: Vec<_> = (1..=2)
+            .map(|nonce| {
+                SignedTransaction::send_money(
+                    nonce,
+                    alice_account(),
+                    bob_account(),
+                    signer.clone(),
+                    100,
+                    CryptoHash::default(),
+                )
+            })
+            .collect();
+        let state_update = TrieUpdate::new(trie.clone(), root);
+        let balance = get_account(&state_update, &alice_account()).unwrap().amount;
+        let apply_state = ApplyState {
+            root,
+            shard_id: 0,
+            block_index: 0,
+            parent_block_hash: CryptoHash::default(),
+            epoch_length: 60,
+        };
+        let apply_result = runtime.apply(state_update, &apply_state, &[], &transactions).unwrap();
+
+        // The first transaction reaches the limit, the second one only pays for sending.
+        let results: Vec<_> = apply_result.tx_result.iter().map(|log| &log.result).collect();
+        assert_eq!(results[0].status, TransactionStatus::Completed);
+        assert_eq!(results[1].status, TransactionStatus::Failed);
+        assert!(results[1].receipts.is_empty());
+        assert_eq!(apply_result.new_receipts.values().flatten().count(), 1);
+        let (gas_burnt, total_cost) =
+            runtime.transaction_cost(&transactions[1].transaction).unwrap();
+        assert_eq!(results[1].gas_burnt, Some(gas_burnt));
+
+        let (store_update, new_root) = apply_result.trie_changes.into(trie.clone()).unwrap();
+        store_update.commit().unwrap();
+        let state_update = TrieUpdate::new(trie, new_root);
+        let account = get_account(&state_update, &alice_account()).unwrap();
+        assert_eq!(
+            account.amount,
+            balance - total_cost - gas_burnt as Balance * TRANSACTION_GAS_PRICE
+        );
+        let access_key =
+            get_access_key(&state_update, &alice_account(), &signer.public_key).unwrap();
+        assert_eq!(access_key.nonce, 2);
+    }
 }
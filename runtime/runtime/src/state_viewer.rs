@@ -54,25 +54,39 @@ impl TrieViewer {
         Ok(get_access_key(state_update, account_id, public_key))
     }
 
+    /// Contract data of the account with keys starting with `prefix`. If `start` is not empty,
+    /// the data is returned from this key, which must start with the prefix. If `limit` is given,
+    /// at most `limit` values are returned, together with the key to continue from.
     pub fn view_state(
         &self,
         state_update: &TrieUpdate,
         account_id: &AccountId,
         prefix: &[u8],
+        start: &[u8],
+        limit: Option<usize>,
     ) -> Result<ViewStateResult, Box<dyn std::error::Error>> {
         if !is_valid_account_id(account_id) {
             return Err(format!("Account ID '{}' is not valid", account_id).into());
         }
+        if !start.is_empty() && !start.starts_with(prefix) {
+            return Err("Start key must start with the prefix".into());
+        }
         let mut values = HashMap::default();
+        let mut next_key = None;
         let mut query = prefix_for_data(account_id);
         let acc_sep_len = query.len();
         query.extend_from_slice(prefix);
-        state_update.for_keys_with_prefix(&query, |key| {
-            if let Some(value) = state_update.get(key) {
+        let start = if start.is_empty() { start } else { &start[prefix.len()..] };
+        for key in state_update.iter_from(&query, start)? {
+            if limit.map_or(false, |limit| values.len() >= limit) {
+                next_key = Some(key[acc_sep_len..].to_vec());
+                break;
+            }
+            if let Some(value) = state_update.get(&key) {
                 values.insert(key[acc_sep_len..].to_vec(), value.to_vec());
             }
-        });
-        Ok(ViewStateResult { values })
+        }
+        Ok(ViewStateResult { values, next_key })
     }
 
     pub fn call_function(
@@ -105,6 +119,9 @@ impl TrieViewer {
                     &public_key,
                     0,
                     &empty_hash,
+                    // Receipts of the view calls are discarded, so their depth is not limited.
+                    0,
+                    u64::max_value(),
                 );
 
                 let context = VMContext {
@@ -233,17 +250,57 @@ mod tests {
         let ethash_provider =
             EthashProvider::new(TempDir::new("runtime_user_test_ethash").unwrap().path());
         let trie_viewer = TrieViewer::new(Arc::new(Mutex::new(ethash_provider)));
-        let result = trie_viewer.view_state(&state_update, &alice_account(), b"").unwrap();
+        let result =
+            trie_viewer.view_state(&state_update, &alice_account(), b"", b"", None).unwrap();
         assert_eq!(
             result.values,
             [(b"test123".to_vec(), b"123".to_vec())].iter().cloned().collect()
         );
-        let result = trie_viewer.view_state(&state_update, &alice_account(), b"test321").unwrap();
+        let result =
+            trie_viewer.view_state(&state_update, &alice_account(), b"test321", b"", None).unwrap();
         assert_eq!(result.values, [].iter().cloned().collect());
-        let result = trie_viewer.view_state(&state_update, &alice_account(), b"test123").unwrap();
+        let result =
+            trie_viewer.view_state(&state_update, &alice_account(), b"test123", b"", None).unwrap();
         assert_eq!(
             result.values,
             [(b"test123".to_vec(), b"123".to_vec())].iter().cloned().collect()
         )
     }
+
+    #[test]
+    fn test_view_state_pages() {
+        let (_, trie, root) = get_runtime_and_trie();
+        let mut state_update = TrieUpdate::new(trie.clone(), root);
+        for key in vec![&b"test1"[..], b"test2", b"test3", b"other"] {
+            state_update.set(key_for_data(&alice_account(), key), DBValue::from_slice(key));
+        }
+        let (db_changes, new_root) = state_update.finalize().unwrap().into(trie.clone()).unwrap();
+        db_changes.commit().unwrap();
+
+        let state_update = TrieUpdate::new(trie, new_root);
+        let ethash_provider =
+            EthashProvider::new(TempDir::new("runtime_user_test_ethash").unwrap().path());
+        let trie_viewer = TrieViewer::new(Arc::new(Mutex::new(ethash_provider)));
+        let result =
+            trie_viewer.view_state(&state_update, &alice_account(), b"test", b"", Some(2)).unwrap();
+        assert_eq!(
+            result.values,
+            [(b"test1".to_vec(), b"test1".to_vec()), (b"test2".to_vec(), b"test2".to_vec())]
+                .iter()
+                .cloned()
+                .collect()
+        );
+        assert_eq!(result.next_key, Some(b"test3".to_vec()));
+        let result = trie_viewer
+            .view_state(&state_update, &alice_account(), b"test", b"test3", Some(2))
+            .unwrap();
+        assert_eq!(
+            result.values,
+            [(b"test3".to_vec(), b"test3".to_vec())].iter().cloned().collect()
+        );
+        assert_eq!(result.next_key, None);
+        assert!(trie_viewer
+            .view_state(&state_update, &alice_account(), b"test", b"other", Some(2))
+            .is_err());
+    }
 }
@@ -1,6 +1,6 @@
 use near_crypto::PublicKey;
 use near_primitives::account::{AccessKey, AccessKeyPermission, Account};
-use near_primitives::transaction::{Action, SignedTransaction};
+use near_primitives::transaction::{Action, InvalidTxError, SignedTransaction};
 use near_primitives::types::AccountId;
 use near_primitives::utils::is_valid_account_id;
 use near_store::{get_access_key, get_account, TrieUpdate};
@@ -24,52 +24,43 @@ impl<'a> TransactionVerifier<'a> {
     pub fn verify_transaction(
         &self,
         signed_transaction: &SignedTransaction,
-    ) -> Result<VerificationData, String> {
+    ) -> Result<VerificationData, InvalidTxError> {
         let transaction = &signed_transaction.transaction;
         let signer_id = &transaction.signer_id;
         if !is_valid_account_id(&signer_id) {
-            return Err(format!(
-                "Invalid signer account ID {:?} according to requirements",
-                signer_id
-            ));
+            return Err(InvalidTxError::InvalidSignerId(signer_id.clone()));
         }
         let signer = match get_account(self.state_update, signer_id) {
             Some(signer) => signer,
             None => {
-                return Err(format!("Signer {:?} does not exist", signer_id));
+                return Err(InvalidTxError::SignerDoesNotExist(signer_id.clone()));
             }
         };
         let access_key =
             match get_access_key(self.state_update, &signer_id, &transaction.public_key) {
                 Some(access_key) => access_key,
                 None => {
-                    return Err(format!(
+                    return Err(InvalidTxError::InvalidAccessKey(format!(
                         "Signer {:?} doesn't have access key with the given public_key {}",
                         signer_id, &transaction.public_key,
-                    ));
+                    )));
                 }
             };
 
         if transaction.nonce <= access_key.nonce {
-            return Err(format!(
-                "Transaction nonce {} must be larger than nonce of the used access key {}",
-                transaction.nonce, access_key.nonce,
-            ));
+            return Err(InvalidTxError::InvalidNonce {
+                nonce: transaction.nonce,
+                access_key_nonce: access_key.nonce,
+            });
         }
 
         if !is_valid_account_id(&transaction.receiver_id) {
-            return Err(format!(
-                "Invalid receiver account ID {:?} according to requirements",
-                transaction.receiver_id
-            ));
+            return Err(InvalidTxError::InvalidReceiverId(transaction.receiver_id.clone()));
         }
 
         let hash = signed_transaction.get_hash();
         if !signed_transaction.signature.verify(hash.as_ref(), &transaction.public_key) {
-            return Err(format!(
-                "Transaction is not signed with a public key of the signer {:?}",
-                signer_id,
-            ));
+            return Err(InvalidTxError::InvalidSignature);
         }
 
         // TODO: Calculate transaction cost
@@ -83,18 +74,18 @@ impl<'a> TransactionVerifier<'a> {
             }),
             AccessKeyPermission::FunctionCall(ref function_call_permission) => {
                 if transaction.actions.len() != 1 {
-                    return Err(
+                    return Err(InvalidTxError::InvalidAccessKey(
                         "Transaction has more than 1 actions and is using function call access key"
                             .to_string(),
-                    );
+                    ));
                 }
                 if let Some(Action::FunctionCall(ref function_call)) = transaction.actions.get(0) {
                     if transaction.receiver_id != function_call_permission.receiver_id {
-                        return Err(format!(
+                        return Err(InvalidTxError::InvalidAccessKey(format!(
                             "Transaction receiver_id {:?} doesn't match the access key receiver_id {:?}",
                             &transaction.receiver_id,
                             &function_call_permission.receiver_id,
-                        ));
+                        )));
                     }
                     if !function_call_permission.method_names.is_empty() {
                         if function_call_permission
@@ -103,10 +94,10 @@ impl<'a> TransactionVerifier<'a> {
                             .find(|method_name| &function_call.method_name == *method_name)
                             .is_none()
                         {
-                            return Err(format!(
+                            return Err(InvalidTxError::InvalidAccessKey(format!(
                                 "Transaction method name {:?} isn't allowed by the access key",
                                 &function_call.method_name
-                            ));
+                            )));
                         }
                     }
                     Ok(VerificationData {
@@ -116,7 +107,9 @@ impl<'a> TransactionVerifier<'a> {
                         access_key,
                     })
                 } else {
-                    Err("The used access key requires exactly one FunctionCall action".to_string())
+                    Err(InvalidTxError::InvalidAccessKey(
+                        "The used access key requires exactly one FunctionCall action".to_string(),
+                    ))
                 }
             }
         }
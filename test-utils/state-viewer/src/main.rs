@@ -1,26 +1,37 @@
-use std::convert::TryFrom;
+use std::convert::{TryFrom, TryInto};
 use std::path::Path;
 use std::sync::Arc;
 
 use borsh::BorshDeserialize;
-use clap::{App, Arg, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
 
 use ansi_term::Color::Red;
-use near::{get_default_home, get_store_path, load_config, NearConfig, NightshadeRuntime};
+use near::{
+    get_configured_store_path, get_default_home, load_config, simulate_validator_schedule,
+    GenesisConfig, NearConfig, NightshadeRuntime,
+};
 use near_chain::{ChainStore, ChainStoreAccess, RuntimeAdapter};
-use near_crypto::PublicKey;
+use near_crypto::{KeyType, PublicKey};
 use near_network::peer_store::PeerStore;
 use near_primitives::account::{AccessKey, Account};
+use near_primitives::block::BlockHeader;
 use near_primitives::hash::{hash, CryptoHash};
-use near_primitives::receipt::{Receipt, ReceivedData};
+use near_primitives::receipt::ReceivedData;
 use near_primitives::serialize::{from_base64, to_base, to_base64};
 use near_primitives::test_utils::init_integration_logger;
-use near_primitives::types::BlockIndex;
-use near_primitives::utils::{col, ACCOUNT_DATA_SEPARATOR};
+use near_primitives::types::{AccountId, Balance, BlockIndex, ValidatorStake};
+use near_primitives::utils::{
+    col, key_for_account, key_for_code, prefix_for_access_key, prefix_for_data,
+    ACCOUNT_DATA_SEPARATOR,
+};
 use near_store::test_utils::create_test_store;
-use near_store::{create_store, DBValue, Store, TrieIterator};
+use near_store::{
+    create_store_with_options, decode_postponed_receipt, DBValue, Store, Trie, TrieIterator,
+    COL_BLOCK, COL_BLOCK_HEADER, COL_BLOCK_INDEX, COL_NAMES, COL_STATE_REF, NUM_COLS,
+};
 use node_runtime::StateRecord;
-use std::collections::HashMap;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap, HashSet};
 
 fn to_printable(blob: &[u8]) -> String {
     if blob.len() > 60 {
@@ -79,7 +90,7 @@ fn kv_to_state_record(key: Vec<u8>, value: DBValue) -> Option<StateRecord> {
         col::POSTPONED_RECEIPT_ID => None,
         col::PENDING_DATA_COUNT => None,
         col::POSTPONED_RECEIPT => {
-            let receipt = Receipt::try_from_slice(&value).unwrap();
+            let receipt = decode_postponed_receipt(&value).unwrap();
             Some(StateRecord::PostponedReceipt(receipt.into()))
         }
         _ => unreachable!(),
@@ -124,20 +135,79 @@ fn print_state_entry(key: Vec<u8>, value: DBValue) {
     }
 }
 
+/// Prints all the trie entries with given key prefix.
+fn print_state_with_prefix(trie: &Trie, state_root: &CryptoHash, prefix: &[u8]) {
+    for item in trie.iter_prefix(state_root, prefix).unwrap() {
+        let (key, value) = item.unwrap();
+        print_state_entry(key, value);
+    }
+}
+
+/// Prints the account record, access keys, contract code and data of a single account.
+fn print_account_state(trie: &Trie, state_root: &CryptoHash, account_id: &AccountId) {
+    let account_key = key_for_account(account_id);
+    match trie.get(state_root, &account_key) {
+        Some(value) => print_state_entry(account_key, DBValue::from_vec(value)),
+        None => {
+            println!("Account {:?} does not exist", account_id);
+            return;
+        }
+    }
+    print_state_with_prefix(trie, state_root, &prefix_for_access_key(account_id));
+    match trie.get(state_root, &key_for_code(account_id)) {
+        Some(code) => {
+            println!("Code for {:?}: hash {}, {} bytes", account_id, hash(&code), code.len())
+        }
+        None => println!("No code for {:?}", account_id),
+    }
+    print_state_with_prefix(trie, state_root, &prefix_for_data(account_id));
+}
+
+/// Loads the state after the block with given hash, or after the head if no hash is given.
 fn load_trie(
     store: Arc<Store>,
     home_dir: &Path,
     near_config: &NearConfig,
+    block_hash: Option<CryptoHash>,
 ) -> (NightshadeRuntime, CryptoHash, BlockIndex) {
     let mut chain_store = ChainStore::new(store.clone());
 
     let runtime = NightshadeRuntime::new(&home_dir, store, near_config.genesis_config.clone());
-    let head = chain_store.head().unwrap();
-    let last_header = chain_store.get_block_header(&head.last_block_hash).unwrap().clone();
-    let state_root = chain_store.get_post_state_root(&head.last_block_hash).unwrap();
+    let block_hash = block_hash.unwrap_or_else(|| chain_store.head().unwrap().last_block_hash);
+    let last_header = chain_store.get_block_header(&block_hash).unwrap().clone();
+    let state_root = chain_store.get_post_state_root(&block_hash).unwrap();
     (runtime, *state_root, last_header.inner.height)
 }
 
+/// Resolves the block from `height` or `block_hash` arguments, `None` means the head.
+fn block_hash_from_args(store: Arc<Store>, args: &ArgMatches) -> Option<CryptoHash> {
+    if let Some(block_hash) = args.value_of("block_hash") {
+        return Some(CryptoHash::try_from(block_hash.to_string()).expect("Failed to parse hash"));
+    }
+    args.value_of("height").map(|height| {
+        let height = height.parse::<BlockIndex>().unwrap();
+        let mut chain_store = ChainStore::new(store);
+        chain_store
+            .get_block_hash_by_height(height)
+            .unwrap_or_else(|_| panic!("No block at height {} on the canonical chain", height))
+    })
+}
+
+/// Arguments to select the block to view the state at.
+fn block_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("height")
+            .long("height")
+            .help("Height of the block to view the state after, head by default")
+            .takes_value(true),
+        Arg::with_name("block_hash")
+            .long("block_hash")
+            .conflicts_with("height")
+            .help("Hash of the block to view the state after, head by default")
+            .takes_value(true),
+    ]
+}
+
 pub fn format_hash(h: CryptoHash) -> String {
     to_base(&h)[..7].to_string()
 }
@@ -225,6 +295,446 @@ fn replay_chain(
     }
 }
 
+/// Re-executes blocks of the canonical chain against a fresh store, starting from genesis, and
+/// checks that the resulting state roots and transaction results match the stored ones.
+/// Blocks before `start_index` are applied to rebuild the state but not reported.
+fn apply_range(
+    store: Arc<Store>,
+    home_dir: &Path,
+    near_config: &NearConfig,
+    start_index: BlockIndex,
+    end_index: BlockIndex,
+) {
+    let mut chain_store = ChainStore::new(store.clone());
+    let new_store = create_test_store();
+    let runtime =
+        NightshadeRuntime::new(&home_dir, new_store.clone(), near_config.genesis_config.clone());
+    let (genesis_store_update, state_roots) = runtime.genesis_state();
+    genesis_store_update.commit().unwrap();
+    let genesis_hash = chain_store.get_block_hash_by_height(0).unwrap();
+    runtime
+        .add_validator_proposals(CryptoHash::default(), genesis_hash, 0, vec![], vec![], vec![])
+        .unwrap();
+    let mut state_root = state_roots[0];
+    let mut receipts = vec![];
+    let mut num_mismatches = 0;
+    for index in 1..=end_index {
+        let block_hash = match chain_store.get_block_hash_by_height(index) {
+            Ok(block_hash) => block_hash,
+            Err(_) => continue,
+        };
+        let block = chain_store.get_block(&block_hash).unwrap().clone();
+        let (trie_changes, new_state_root, tx_results, new_receipts, validator_proposals) =
+            runtime
+                .apply_transactions(
+                    0,
+                    &state_root,
+                    index,
+                    &block.header.inner.prev_hash,
+                    &block_hash,
+                    &vec![receipts],
+                    &block.transactions,
+                )
+                .unwrap();
+        let mut store_update = new_store.store_update();
+        trie_changes.insertions_into(&mut store_update).unwrap();
+        store_update.commit().unwrap();
+        runtime
+            .add_validator_proposals(
+                block.header.inner.prev_hash,
+                block_hash,
+                index,
+                validator_proposals,
+                vec![],
+                vec![],
+            )
+            .unwrap();
+
+        let expected_state_root = *chain_store.get_post_state_root(&block_hash).unwrap();
+        let num_mismatched_results = tx_results
+            .iter()
+            .filter(|tx_result| {
+                chain_store
+                    .get_transaction_result(&tx_result.hash)
+                    .map(|stored| stored != &tx_result.result)
+                    .unwrap_or(true)
+            })
+            .count();
+        if new_state_root != expected_state_root || num_mismatched_results > 0 {
+            num_mismatches += 1;
+            println!(
+                "{: >3} {} {}: state root {} (stored {}), {} of {} results differ",
+                index,
+                format_hash(block_hash),
+                Red.bold().paint("MISMATCH"),
+                new_state_root,
+                expected_state_root,
+                num_mismatched_results,
+                tx_results.len()
+            );
+        } else if index >= start_index {
+            println!(
+                "{: >3} {} OK: state root {}, {} txs, {} results",
+                index,
+                format_hash(block_hash),
+                new_state_root,
+                block.transactions.len(),
+                tx_results.len()
+            );
+        }
+        state_root = new_state_root;
+        receipts = new_receipts.get(&0).cloned().unwrap_or_default();
+    }
+    println!("Applied blocks up to {}, {} mismatches", end_index, num_mismatches);
+}
+
+/// Identifies the state record, so that records of two states can be matched with each other.
+fn state_record_key(record: &StateRecord) -> String {
+    match record {
+        StateRecord::Account { account_id, .. } => format!("account {}", account_id),
+        StateRecord::Data { key, .. } => format!("data {}", key),
+        StateRecord::Contract { account_id, .. } => format!("contract {}", account_id),
+        StateRecord::AccessKey { account_id, public_key, .. } => {
+            format!("access key {} {}", account_id, public_key)
+        }
+        StateRecord::PostponedReceipt(receipt) => {
+            format!("postponed receipt {}", CryptoHash::from(receipt.receipt_id.clone()))
+        }
+        StateRecord::ReceivedData { account_id, data_id, .. } => {
+            format!("received data {} {}", account_id, CryptoHash::from(data_id.clone()))
+        }
+    }
+}
+
+/// Printable value of the state record. Contract code is replaced with its hash.
+fn state_record_value(record: &StateRecord) -> String {
+    match record {
+        StateRecord::Contract { code, .. } => {
+            format!("code hash {}", hash(&from_base64(code).unwrap()))
+        }
+        _ => serde_json::to_string(record).unwrap(),
+    }
+}
+
+/// Prints records added, removed and changed between two genesis files.
+/// Returns the number of differences.
+fn diff_state(first_path: &Path, second_path: &Path) -> usize {
+    let load = |path: &Path| -> BTreeMap<String, String> {
+        GenesisConfig::from_file(&path.to_path_buf())
+            .records
+            .iter()
+            .flatten()
+            .map(|record| (state_record_key(record), state_record_value(record)))
+            .collect()
+    };
+    let first = load(first_path);
+    let second = load(second_path);
+    let (mut num_added, mut num_removed, mut num_changed) = (0, 0, 0);
+    for (key, value) in first.iter() {
+        match second.get(key) {
+            None => {
+                println!("- {}: {}", key, value);
+                num_removed += 1;
+            }
+            Some(new_value) if new_value != value => {
+                println!("~ {}\n    was: {}\n    now: {}", key, value, new_value);
+                num_changed += 1;
+            }
+            Some(_) => {}
+        }
+    }
+    for (key, value) in second.iter() {
+        if !first.contains_key(key) {
+            println!("+ {}: {}", key, value);
+            num_added += 1;
+        }
+    }
+    println!(
+        "{} records added, {} removed, {} changed, {} unchanged",
+        num_added,
+        num_removed,
+        num_changed,
+        first.len() - num_removed - num_changed
+    );
+    num_added + num_removed + num_changed
+}
+
+/// Prints result of the transaction or receipt, then recursively of the receipts it generated.
+fn print_transaction(chain_store: &mut ChainStore, hash: &CryptoHash, depth: usize) {
+    let indent = "  ".repeat(depth);
+    let result = match chain_store.get_transaction_result(hash) {
+        Ok(result) => result.clone(),
+        Err(err) => {
+            println!("{}{}: {}", indent, hash, err);
+            return;
+        }
+    };
+    match chain_store.get_receipt_origin(hash) {
+        Ok(origin) => println!(
+            "{}{} (receipt of transaction {} in block #{})",
+            indent, hash, origin.transaction_hash, origin.block_index
+        ),
+        Err(_) => println!("{}{}", indent, hash),
+    }
+    println!("{}{:?}", indent, result);
+    for receipt_id in result.receipts.iter() {
+        print_transaction(chain_store, receipt_id, depth + 1);
+    }
+}
+
+/// Checks consistency of the chain data and integrity of all stored state tries.
+/// Returns the number of problems found.
+fn check_store(store: Arc<Store>) -> usize {
+    let mut problems = vec![];
+
+    let mut headers = HashMap::new();
+    for (key, value) in store.iter(COL_BLOCK_HEADER) {
+        match BlockHeader::try_from_slice(&value) {
+            Ok(header) => {
+                if header.hash().as_ref() != &key[..] {
+                    problems.push(format!(
+                        "Header stored under {} has hash {}",
+                        to_base(&key),
+                        header.hash()
+                    ));
+                }
+                headers.insert(header.hash(), header);
+            }
+            Err(err) => {
+                problems.push(format!("Failed to decode header {}: {}", to_base(&key), err))
+            }
+        }
+    }
+    for header in headers.values() {
+        if header.inner.height > 0 && !headers.contains_key(&header.inner.prev_hash) {
+            problems.push(format!(
+                "Header {} at {} has missing previous header {}",
+                header.hash(),
+                header.inner.height,
+                header.inner.prev_hash
+            ));
+        }
+    }
+    println!("Checked {} headers", headers.len());
+
+    let mut num_indexed = 0;
+    for (key, value) in store.iter(COL_BLOCK_INDEX) {
+        if key.len() != 8 {
+            problems.push(format!("Height index has malformed key {}", to_base(&key)));
+            continue;
+        }
+        let mut height_bytes = [0; 8];
+        height_bytes.copy_from_slice(&key);
+        let height = BlockIndex::from_le_bytes(height_bytes);
+        let hash = match CryptoHash::try_from_slice(&value) {
+            Ok(hash) => hash,
+            Err(err) => {
+                problems.push(format!("Failed to decode height index {}: {}", height, err));
+                continue;
+            }
+        };
+        match headers.get(&hash) {
+            Some(header) if header.inner.height == height => num_indexed += 1,
+            Some(header) => problems.push(format!(
+                "Height index {} points to header {} at {}",
+                height, hash, header.inner.height
+            )),
+            None => {
+                problems.push(format!("Height index {} points to missing header {}", height, hash))
+            }
+        }
+    }
+    println!("Checked {} height index entries", num_indexed);
+
+    for (key, _) in store.iter(COL_BLOCK) {
+        match CryptoHash::try_from(&key[..]) {
+            Ok(hash) if !headers.contains_key(&hash) => {
+                problems.push(format!("Block {} has no header", hash))
+            }
+            Ok(_) => {}
+            Err(_) => problems.push(format!("Block stored under malformed key {}", to_base(&key))),
+        }
+    }
+
+    let trie = Trie::new(store.clone());
+    let mut visited = HashSet::new();
+    let mut num_state_roots = 0;
+    let mut num_nodes = 0;
+    for (key, value) in store.iter(COL_STATE_REF) {
+        let block_hash = match CryptoHash::try_from(&key[..]) {
+            Ok(block_hash) => block_hash,
+            Err(_) => {
+                problems.push(format!("State root stored under malformed key {}", to_base(&key)));
+                continue;
+            }
+        };
+        if !headers.contains_key(&block_hash) {
+            problems.push(format!("State root of block {} has no header", block_hash));
+        }
+        let state_root = match CryptoHash::try_from_slice(&value) {
+            Ok(state_root) => state_root,
+            Err(err) => {
+                problems.push(format!(
+                    "Failed to decode state root of block {}: {}",
+                    block_hash, err
+                ));
+                continue;
+            }
+        };
+        match trie.check_integrity(&state_root, &mut visited) {
+            Ok(num_checked) => num_nodes += num_checked,
+            Err(err) => problems.push(format!(
+                "State {} after block {} is corrupted: {}",
+                state_root, block_hash, err
+            )),
+        }
+        num_state_roots += 1;
+    }
+    println!("Checked {} state roots with {} trie nodes", num_state_roots, num_nodes);
+
+    let chain_store = ChainStore::new(store);
+    match chain_store.head() {
+        Ok(head) if !headers.contains_key(&head.last_block_hash) => {
+            problems.push(format!("Head {} has no header", head.last_block_hash))
+        }
+        Ok(_) => {}
+        Err(err) => problems.push(format!("Failed to read head: {}", err)),
+    }
+
+    for problem in problems.iter() {
+        println!("{} {}", Red.bold().paint("ERROR"), problem);
+    }
+    println!("Found {} problems", problems.len());
+    problems.len()
+}
+
+/// Total size of the files in the directory and all its subdirectories.
+fn dir_size(path: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(path)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        size += if metadata.is_dir() { dir_size(&entry.path())? } else { metadata.len() };
+    }
+    Ok(size)
+}
+
+/// Prints tips of the known forks at most `num_heights` below the head, starting from the
+/// heaviest one, with the weight they lack to overtake the head.
+fn print_forks(store: Arc<Store>, num_heights: BlockIndex) {
+    let mut chain_store = ChainStore::new(store);
+    let head = chain_store.head().unwrap();
+    let tips = chain_store.get_fork_tips(num_heights).unwrap();
+    println!(
+        "Found {} tips within {} heights below the head #{}",
+        tips.len(),
+        num_heights,
+        head.height
+    );
+    for tip in tips {
+        let marker = if tip.last_block_hash == head.last_block_hash { " (head)" } else { "" };
+        println!(
+            "{: >8} {} | weight {: >10} | behind head {: >6}{}",
+            tip.height,
+            format_hash(tip.last_block_hash),
+            tip.total_weight.to_num(),
+            head.total_weight.to_num().saturating_sub(tip.total_weight.to_num()),
+            marker
+        );
+    }
+}
+
+/// Prints number of keys and size of the data in each column, the largest entries and the size
+/// of the database on disk.
+fn print_db_stats(store: Arc<Store>, store_path: &str, num_largest: usize) {
+    let mut largest = BinaryHeap::new();
+    let mut total_size = 0;
+    println!("{: >20} | {: >10} | {: >14} | {: >14}", "column", "keys", "key bytes", "value bytes");
+    for col in 0..NUM_COLS {
+        let (mut num_keys, mut keys_size, mut values_size) = (0u64, 0u64, 0u64);
+        for (key, value) in store.iter(Some(col)) {
+            num_keys += 1;
+            keys_size += key.len() as u64;
+            values_size += value.len() as u64;
+            largest.push(Reverse((key.len() + value.len(), col, key)));
+            if largest.len() > num_largest {
+                largest.pop();
+            }
+        }
+        total_size += keys_size + values_size;
+        println!(
+            "{: >20} | {: >10} | {: >14} | {: >14}",
+            COL_NAMES[col as usize], num_keys, keys_size, values_size
+        );
+    }
+    println!("Total size of keys and values: {}", total_size);
+    match dir_size(Path::new(store_path)) {
+        Ok(size) => println!("Size of the database on disk: {}", size),
+        Err(err) => println!("Failed to get size of the database on disk: {}", err),
+    }
+    println!("Largest entries:");
+    for Reverse((size, col, key)) in largest.into_sorted_vec() {
+        println!("{: >10} | {: >20} | {}", size, COL_NAMES[col as usize], to_printable(&key));
+    }
+}
+
+/// Parses proposal in the form `account_id:amount`. Existing validators keep their keys.
+fn parse_proposal(near_config: &NearConfig, proposal: &str) -> ValidatorStake {
+    let mut parts = proposal.splitn(2, ':');
+    let account_id = parts.next().unwrap().to_string();
+    let amount = parts
+        .next()
+        .and_then(|amount| amount.parse::<Balance>().ok())
+        .unwrap_or_else(|| {
+            panic!("Failed to parse proposal {}, expected account_id:amount", proposal)
+        });
+    let public_key = near_config
+        .genesis_config
+        .validators
+        .iter()
+        .find(|account_info| account_info.account_id == account_id)
+        .map(|account_info| account_info.public_key.clone().try_into().unwrap())
+        .unwrap_or_else(|| PublicKey::empty(KeyType::ED25519));
+    ValidatorStake { account_id, public_key, amount }
+}
+
+fn simulate_schedule(
+    near_config: &NearConfig,
+    num_epochs: u64,
+    proposals: Vec<ValidatorStake>,
+    block_reward: Balance,
+) {
+    let schedule =
+        simulate_validator_schedule(&near_config.genesis_config, proposals, num_epochs).unwrap();
+    let mut total_blocks = HashMap::new();
+    for (epoch, epoch_schedule) in schedule.iter().enumerate() {
+        println!("Epoch {} starting at block {}", epoch, epoch_schedule.epoch_start);
+        for validator in epoch_schedule.validators.iter() {
+            let num_blocks =
+                epoch_schedule.num_blocks.get(&validator.account_id).cloned().unwrap_or(0);
+            println!(
+                "  {: >20} | stake {: >30} | blocks {: >6} ({:.2}%)",
+                validator.account_id,
+                validator.amount,
+                num_blocks,
+                100.0 * num_blocks as f64 / near_config.genesis_config.epoch_length as f64
+            );
+            *total_blocks.entry(validator.account_id.clone()).or_insert(0) += num_blocks;
+        }
+    }
+    let mut total_blocks: Vec<_> = total_blocks.into_iter().collect();
+    total_blocks.sort();
+    println!("Total over {} epochs:", num_epochs);
+    for (account_id, num_blocks) in total_blocks {
+        println!(
+            "  {: >20} | blocks {: >8} | expected reward {}",
+            account_id,
+            num_blocks,
+            block_reward * Balance::from(num_blocks)
+        );
+    }
+}
+
 fn main() {
     init_integration_logger();
 
@@ -238,15 +748,27 @@ fn main() {
                 .takes_value(true),
         )
         .subcommand(SubCommand::with_name("peers"))
-        .subcommand(SubCommand::with_name("state"))
+        .subcommand(SubCommand::with_name("state").args(&block_args()))
+        .subcommand(
+            SubCommand::with_name("account")
+                .arg(
+                    Arg::with_name("account_id")
+                        .required(true)
+                        .help("Account to print the state of")
+                        .takes_value(true),
+                )
+                .help("print account record, access keys, contract code and data of the account"),
+        )
         .subcommand(
-            SubCommand::with_name("dump_state").arg(
-                Arg::with_name("output")
-                    .long("output")
-                    .required(true)
-                    .help("Output path for new genesis given current blockchain state")
-                    .takes_value(true),
-            ),
+            SubCommand::with_name("dump_state")
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .required(true)
+                        .help("Output path for new genesis given current blockchain state")
+                        .takes_value(true),
+                )
+                .args(&block_args()),
         )
         .subcommand(
             SubCommand::with_name("chain")
@@ -284,12 +806,113 @@ fn main() {
                 )
                 .help("replay headers from chain"),
         )
+        .subcommand(
+            SubCommand::with_name("apply_range")
+                .arg(
+                    Arg::with_name("start_index")
+                        .long("start_index")
+                        .default_value("1")
+                        .help("First block to report, earlier blocks are only applied")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("end_index")
+                        .long("end_index")
+                        .required(true)
+                        .help("Last block to apply")
+                        .takes_value(true),
+                )
+                .help("re-execute blocks from genesis and check state roots and results"),
+        )
+        .subcommand(
+            SubCommand::with_name("tx")
+                .arg(
+                    Arg::with_name("hash")
+                        .required(true)
+                        .help("Hash of the transaction or receipt")
+                        .takes_value(true),
+                )
+                .help("print result of the transaction and of all the receipts it generated"),
+        )
+        .subcommand(
+            SubCommand::with_name("diff_state")
+                .arg(
+                    Arg::with_name("first")
+                        .required(true)
+                        .help("Genesis file with the original state")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("second")
+                        .required(true)
+                        .help("Genesis file with the state to compare against")
+                        .takes_value(true),
+                )
+                .help("print records added, removed and changed between two genesis files"),
+        )
+        .subcommand(
+            SubCommand::with_name("forks")
+                .arg(
+                    Arg::with_name("heights")
+                        .long("heights")
+                        .default_value("100")
+                        .help("Number of heights below the head to look for forks at")
+                        .takes_value(true),
+                )
+                .help("print tips of the known forks with their total weights"),
+        )
+        .subcommand(
+            SubCommand::with_name("db_stats")
+                .arg(
+                    Arg::with_name("top")
+                        .long("top")
+                        .default_value("10")
+                        .help("Number of largest entries to print")
+                        .takes_value(true),
+                )
+                .help("print number of keys and data size per column and the largest entries"),
+        )
+        .subcommand(
+            SubCommand::with_name("check")
+                .help("check chain data consistency and integrity of all stored state tries"),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate_schedule")
+                .arg(
+                    Arg::with_name("epochs")
+                        .long("epochs")
+                        .default_value("10")
+                        .help("Number of epochs to simulate")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("stake")
+                        .long("stake")
+                        .multiple(true)
+                        .help("Stake proposal made in the first epoch, in the form account_id:amount")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("block_reward")
+                        .long("block_reward")
+                        .default_value("0")
+                        .help("Reward per produced block, used to estimate expected rewards")
+                        .takes_value(true),
+                )
+                .help("simulate block producer schedule starting from genesis validators"),
+        )
         .get_matches();
 
     let home_dir = matches.value_of("home").map(|dir| Path::new(dir)).unwrap();
-    let mut near_config = load_config(home_dir);
+    let mut near_config = load_config(home_dir).unwrap_or_else(|errors| {
+        for err in errors.iter() {
+            eprintln!("{}", err);
+        }
+        panic!("Found {} problems with config in {}", errors.len(), home_dir.display());
+    });
 
-    let store = create_store(&get_store_path(&home_dir));
+    let store_path = get_configured_store_path(&home_dir, &near_config.store_config);
+    let store = create_store_with_options(&store_path, &near_config.store_config.options);
 
     match matches.subcommand() {
         ("peers", Some(_args)) => {
@@ -298,8 +921,10 @@ fn main() {
                 println!("{} {:?}", peer_id, peer_info);
             }
         }
-        ("state", Some(_args)) => {
-            let (runtime, state_root, height) = load_trie(store, &home_dir, &near_config);
+        ("state", Some(args)) => {
+            let block_hash = block_hash_from_args(store.clone(), args);
+            let (runtime, state_root, height) =
+                load_trie(store, &home_dir, &near_config, block_hash);
             println!("Storage root is {}, block height is {}", state_root, height);
             let trie = TrieIterator::new(&runtime.trie, &state_root).unwrap();
             for item in trie {
@@ -307,8 +932,16 @@ fn main() {
                 print_state_entry(key, value);
             }
         }
+        ("account", Some(args)) => {
+            let account_id = args.value_of("account_id").unwrap().to_string();
+            let (runtime, state_root, height) = load_trie(store, &home_dir, &near_config, None);
+            println!("Storage root is {}, block height is {}", state_root, height);
+            print_account_state(&runtime.trie, &state_root, &account_id);
+        }
         ("dump_state", Some(args)) => {
-            let (runtime, state_root, height) = load_trie(store, home_dir, &near_config);
+            let block_hash = block_hash_from_args(store.clone(), args);
+            let (runtime, state_root, height) =
+                load_trie(store, home_dir, &near_config, block_hash);
             let output_path = args.value_of("output").map(|path| Path::new(path)).unwrap();
             println!("Saving state at {} @ {} into {}", state_root, height, output_path.display());
             near_config.genesis_config.records = vec![vec![]];
@@ -333,6 +966,47 @@ fn main() {
             let end_index = args.value_of("end_index").map(|s| s.parse::<u64>().unwrap()).unwrap();
             replay_chain(store, home_dir, &near_config, start_index, end_index);
         }
+        ("apply_range", Some(args)) => {
+            let start_index =
+                args.value_of("start_index").map(|s| s.parse::<u64>().unwrap()).unwrap();
+            let end_index = args.value_of("end_index").map(|s| s.parse::<u64>().unwrap()).unwrap();
+            apply_range(store, home_dir, &near_config, start_index, end_index);
+        }
+        ("tx", Some(args)) => {
+            let hash = args.value_of("hash").map(|s| s.to_string()).unwrap();
+            let hash = CryptoHash::try_from(hash).expect("Failed to parse hash");
+            print_transaction(&mut ChainStore::new(store), &hash, 0);
+        }
+        ("diff_state", Some(args)) => {
+            let first = args.value_of("first").map(|path| Path::new(path)).unwrap();
+            let second = args.value_of("second").map(|path| Path::new(path)).unwrap();
+            if diff_state(first, second) > 0 {
+                std::process::exit(1);
+            }
+        }
+        ("forks", Some(args)) => {
+            let num_heights = args.value_of("heights").map(|s| s.parse::<u64>().unwrap()).unwrap();
+            print_forks(store, num_heights);
+        }
+        ("db_stats", Some(args)) => {
+            let top = args.value_of("top").map(|s| s.parse::<usize>().unwrap()).unwrap();
+            print_db_stats(store, &store_path, top);
+        }
+        ("check", Some(_args)) => {
+            if check_store(store) > 0 {
+                std::process::exit(1);
+            }
+        }
+        ("simulate_schedule", Some(args)) => {
+            let num_epochs = args.value_of("epochs").map(|s| s.parse::<u64>().unwrap()).unwrap();
+            let proposals = args
+                .values_of("stake")
+                .map(|values| values.map(|value| parse_proposal(&near_config, value)).collect())
+                .unwrap_or_default();
+            let block_reward =
+                args.value_of("block_reward").map(|s| s.parse::<Balance>().unwrap()).unwrap();
+            simulate_schedule(&near_config, num_epochs, proposals, block_reward);
+        }
         (_, _) => unreachable!(),
     }
 }
@@ -13,6 +13,7 @@ pub mod runtime_utils;
 pub mod standard_test_cases;
 pub mod test_helpers;
 pub mod user;
+pub mod workload;
 
 /// Compute genesis hash from genesis config.
 pub fn genesis_hash(genesis_config: &GenesisConfig) -> CryptoHash {
@@ -26,8 +27,6 @@ pub fn genesis_header(genesis_config: &GenesisConfig) -> BlockHeader {
     let genesis_time = genesis_config.genesis_time.clone();
     let runtime =
         Arc::new(NightshadeRuntime::new(dir.path(), store.clone(), genesis_config.clone()));
-    let chain =
-        Chain::new(store, runtime, genesis_time, genesis_config.transaction_validity_period)
-            .unwrap();
+    let chain = Chain::new(store, runtime, genesis_time).unwrap();
     chain.genesis().clone()
 }
@@ -3,7 +3,8 @@ use std::sync::Arc;
 use near::config::{TESTING_INIT_BALANCE, TESTING_INIT_STAKE};
 use near_crypto::{InMemorySigner, KeyType};
 use near_primitives::account::{AccessKey, AccessKeyPermission, FunctionCallPermission};
-use near_primitives::hash::hash;
+use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::transaction::SignedTransaction;
 use near_primitives::types::Balance;
 use near_primitives::views::AccountView;
 use near_primitives::views::FinalTransactionStatus;
@@ -14,6 +15,7 @@ use crate::runtime_utils::{
     alice_account, bob_account, default_code_hash, encode_int, eve_dot_alice_account,
 };
 use crate::user::User;
+use crate::workload::Workload;
 
 /// The amount to send with function call.
 const FUNCTION_CALL_AMOUNT: Balance = 1_000_000_000_000;
@@ -35,6 +37,33 @@ fn add_access_key(
     assert_ne!(root, new_root);
 }
 
+/// Sends more money than the signer has. Nodes validating transactions refuse it, the runtime
+/// includes it and it fails, in both cases nothing is charged.
+fn send_money_over_balance(node: &impl Node, node_user: &dyn User) {
+    let account_id = node.account_id().unwrap();
+    let transaction = SignedTransaction::send_money(
+        node_user.get_access_key_nonce_for_signer(&account_id).unwrap() + 1,
+        account_id,
+        bob_account(),
+        node_user.signer(),
+        TESTING_INIT_BALANCE + 1,
+        node_user.get_best_block_hash().unwrap_or(CryptoHash::default()),
+    );
+    let result = node_user.commit_transaction(transaction);
+    if node_user.validates_transactions() {
+        match result {
+            Err(err) => assert!(err.contains("NotEnoughBalance"), "{}", err),
+            Ok(transaction_result) => {
+                panic!("Transaction wasn't refused: {:?}", transaction_result.status)
+            }
+        }
+    } else {
+        let transaction_result = result.unwrap();
+        assert_eq!(transaction_result.status, FinalTransactionStatus::Failed);
+        assert_eq!(transaction_result.transactions.len(), 1);
+    }
+}
+
 pub fn test_smart_contract_simple(node: impl Node) {
     let node_user = node.user();
     let root = node_user.get_state_root();
@@ -46,6 +75,22 @@ pub fn test_smart_contract_simple(node: impl Node) {
     assert_ne!(root, new_root);
 }
 
+/// Receipts of the function call, including the refund, point to the originating transaction.
+/// Only the view client records the origin, so the node has to be queried through RPC.
+pub fn test_receipt_origin(node: impl Node) {
+    let node_user = node.user();
+    let transaction_result =
+        node_user.function_call(alice_account(), bob_account(), "run_test", vec![], 1000000, 0);
+    assert_eq!(transaction_result.status, FinalTransactionStatus::Completed);
+    let (transaction, receipts) = transaction_result.transactions.split_first().unwrap();
+    assert!(transaction.result.origin.is_none());
+    assert!(!receipts.is_empty());
+    for receipt in receipts {
+        let origin = receipt.result.origin.as_ref().expect("Receipt origin is missing");
+        assert_eq!(origin.transaction_hash, transaction.hash);
+    }
+}
+
 pub fn test_smart_contract_self_call(node: impl Node) {
     let account_id = &node.account_id().unwrap();
     let node_user = node.user();
@@ -154,10 +199,7 @@ pub fn test_nonce_updated_when_tx_failed(node: impl Node) {
     let account_id = &node.account_id().unwrap();
     let node_user = node.user();
     let root = node_user.get_state_root();
-    let transaction_result =
-        node_user.send_money(account_id.clone(), bob_account(), TESTING_INIT_BALANCE + 1);
-    assert_eq!(transaction_result.status, FinalTransactionStatus::Failed);
-    assert_eq!(transaction_result.transactions.len(), 1);
+    send_money_over_balance(&node, node_user.as_ref());
     assert_eq!(node_user.get_access_key_nonce_for_signer(account_id).unwrap(), 0);
     let new_root = node_user.get_state_root();
     assert_eq!(root, new_root);
@@ -244,10 +286,7 @@ pub fn test_send_money_over_balance(node: impl Node) {
     let account_id = &node.account_id().unwrap();
     let node_user = node.user();
     let root = node_user.get_state_root();
-    let money_used = TESTING_INIT_BALANCE + 1;
-    let transaction_result = node_user.send_money(account_id.clone(), bob_account(), money_used);
-    assert_eq!(transaction_result.status, FinalTransactionStatus::Failed);
-    assert_eq!(transaction_result.transactions.len(), 1);
+    send_money_over_balance(&node, node_user.as_ref());
     let new_root = node_user.get_state_root();
     assert_eq!(root, new_root);
     let result1 = node_user.view_account(account_id).unwrap();
@@ -917,3 +956,20 @@ pub fn test_delete_account_while_staking(node: impl Node) {
     assert_eq!(transaction_result.transactions.len(), 2);
     assert!(node.user().view_account(&eve_dot_alice_account()).is_ok());
 }
+
+pub fn test_random_workload(node: impl Node) {
+    let node_user = node.user();
+    let signers = vec![(node.account_id().unwrap(), node.signer())];
+    let mut workload = Workload::new(0, signers, node_user.as_ref()).unwrap();
+    let num_steps = 40;
+    let mut num_applied = 0;
+    for _ in 0..num_steps {
+        let (_, success) = workload.step(node_user.as_ref()).unwrap();
+        if success {
+            num_applied += 1;
+        }
+    }
+    assert!(num_applied * 2 >= num_steps, "Only {} of {} steps applied", num_applied, num_steps);
+    assert!(workload.model().balances.len() > 1);
+    assert_eq!(workload.model().check(node_user.as_ref()), Ok(()));
+}
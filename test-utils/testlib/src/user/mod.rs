@@ -72,6 +72,12 @@ pub trait User {
 
     fn set_signer(&mut self, signer: Arc<dyn Signer>);
 
+    /// Whether the node validates submitted transactions against the state and refuses the
+    /// invalid ones instead of including them.
+    fn validates_transactions(&self) -> bool {
+        true
+    }
+
     fn sign_and_commit_actions(
         &self,
         signer_id: AccountId,
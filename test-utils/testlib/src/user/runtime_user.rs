@@ -167,7 +167,7 @@ impl User for RuntimeUser {
     fn view_state(&self, account_id: &AccountId, prefix: &[u8]) -> Result<ViewStateResult, String> {
         let state_update = self.client.read().expect(POISONED_LOCK_ERR).get_state_update();
         self.trie_viewer
-            .view_state(&state_update, account_id, prefix)
+            .view_state(&state_update, account_id, prefix, b"", None)
             .map_err(|err| err.to_string())
     }
 
@@ -243,4 +243,8 @@ impl User for RuntimeUser {
     fn set_signer(&mut self, signer: Arc<dyn Signer>) {
         self.signer = signer;
     }
+
+    fn validates_transactions(&self) -> bool {
+        false
+    }
 }
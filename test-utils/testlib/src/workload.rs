@@ -0,0 +1,306 @@
+//! Randomized workload for multi-node tests. Generates account creations, transfers, contract
+//! deployments and calls, and keeps a model of the state they should result in, so that tests can
+//! check that all the nodes converge to it.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use near_crypto::{InMemorySigner, KeyType, Signer};
+use near_primitives::account::AccessKey;
+use near_primitives::hash::{hash, CryptoHash};
+use near_primitives::transaction::{
+    Action, AddKeyAction, CreateAccountAction, DeployContractAction, FunctionCallAction,
+    SignedTransaction, TransferAction,
+};
+use near_primitives::types::{AccountId, Balance, Nonce};
+use near_primitives::views::FinalTransactionStatus;
+
+use crate::node::Node;
+use crate::user::User;
+
+/// Gas attached to the contract calls.
+const CALL_GAS: u64 = 1_000_000;
+/// Balance given to the newly created accounts.
+const NEW_ACCOUNT_AMOUNT: Balance = 1_000_000_000;
+/// Maximum amount of a single transfer.
+const MAX_TRANSFER_AMOUNT: Balance = 1_000_000;
+/// Number of different keys the contract calls write to.
+const NUM_STORAGE_KEYS: u64 = 16;
+
+/// Code of the contract deployed by the workload.
+pub fn test_contract_code() -> Vec<u8> {
+    let mut path = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+    path.push("../../runtime/near-vm-runner/tests/res/test_contract_rs.wasm");
+    fs::read(path).unwrap()
+}
+
+/// Single step of the workload.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WorkloadAction {
+    CreateAccount { signer_id: AccountId, new_account_id: AccountId, amount: Balance },
+    Transfer { signer_id: AccountId, receiver_id: AccountId, amount: Balance },
+    DeployContract { signer_id: AccountId },
+    /// Calls `write_key_value` of the contract deployed to the signer's own account.
+    WriteKeyValue { signer_id: AccountId, key: u64, value: u64 },
+}
+
+impl WorkloadAction {
+    pub fn signer_id(&self) -> &AccountId {
+        match self {
+            WorkloadAction::CreateAccount { signer_id, .. }
+            | WorkloadAction::Transfer { signer_id, .. }
+            | WorkloadAction::DeployContract { signer_id }
+            | WorkloadAction::WriteKeyValue { signer_id, .. } => signer_id,
+        }
+    }
+}
+
+/// Expected state of the accounts touched by the workload.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct WorkloadModel {
+    /// Upper bound of the balance of every account. Transaction fees and storage rent are only
+    /// ever subtracted, so the actual balance can only be lower.
+    pub balances: BTreeMap<AccountId, Balance>,
+    /// Accounts with the test contract deployed.
+    pub contracts: BTreeSet<AccountId>,
+    /// Values written by the contract calls, by contract account and key.
+    pub storage: BTreeMap<AccountId, BTreeMap<u64, u64>>,
+}
+
+impl WorkloadModel {
+    /// Updates the model with the effect of a successfully executed action.
+    pub fn apply(&mut self, action: &WorkloadAction) {
+        match action {
+            WorkloadAction::CreateAccount { signer_id, new_account_id, amount } => {
+                *self.balances.get_mut(signer_id).unwrap() -= amount;
+                self.balances.insert(new_account_id.clone(), *amount);
+            }
+            WorkloadAction::Transfer { signer_id, receiver_id, amount } => {
+                *self.balances.get_mut(signer_id).unwrap() -= amount;
+                *self.balances.get_mut(receiver_id).unwrap() += amount;
+            }
+            WorkloadAction::DeployContract { signer_id } => {
+                self.contracts.insert(signer_id.clone());
+            }
+            WorkloadAction::WriteKeyValue { signer_id, key, value } => {
+                self.storage.entry(signer_id.clone()).or_default().insert(*key, *value);
+            }
+        }
+    }
+
+    /// Checks that the state seen through the user matches the model.
+    pub fn check(&self, user: &dyn User) -> Result<(), String> {
+        let code_hash = hash(&test_contract_code());
+        for (account_id, max_balance) in self.balances.iter() {
+            let account = user.view_account(account_id)?;
+            if account.amount > *max_balance {
+                return Err(format!(
+                    "Account {} has balance {}, expected at most {}",
+                    account_id, account.amount, max_balance
+                ));
+            }
+            if self.contracts.contains(account_id)
+                && CryptoHash::from(account.code_hash) != code_hash
+            {
+                return Err(format!("Account {} doesn't have the test contract", account_id));
+            }
+        }
+        for (account_id, values) in self.storage.iter() {
+            let state = user.view_state(account_id, &[])?;
+            for (key, value) in values.iter() {
+                match state.values.get(&key.to_le_bytes().to_vec()) {
+                    Some(stored) if stored[..] == value.to_le_bytes()[..] => {}
+                    stored => {
+                        return Err(format!(
+                            "Contract {} has {:?} at key {}, expected {}",
+                            account_id, stored, key, value
+                        ))
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Generator of the random transactions from a set of accounts, that tracks their expected state.
+pub struct Workload {
+    rng: StdRng,
+    signers: BTreeMap<AccountId, Arc<dyn Signer>>,
+    nonces: BTreeMap<AccountId, Nonce>,
+    model: WorkloadModel,
+    code: Vec<u8>,
+    num_created_accounts: u64,
+}
+
+impl Workload {
+    /// Creates workload signing with the given accounts. Their balances and nonces are read
+    /// through the user.
+    pub fn new(
+        seed: u64,
+        signers: Vec<(AccountId, Arc<dyn Signer>)>,
+        user: &dyn User,
+    ) -> Result<Self, String> {
+        let mut model = WorkloadModel::default();
+        let mut nonces = BTreeMap::new();
+        for (account_id, signer) in signers.iter() {
+            model.balances.insert(account_id.clone(), user.view_balance(account_id)?);
+            let nonce = user
+                .get_access_key(account_id, &signer.public_key())?
+                .ok_or_else(|| format!("Access key of {} doesn't exist", account_id))?
+                .nonce;
+            nonces.insert(account_id.clone(), nonce);
+        }
+        Ok(Workload {
+            rng: SeedableRng::seed_from_u64(seed),
+            signers: signers.into_iter().collect(),
+            nonces,
+            model,
+            code: test_contract_code(),
+            num_created_accounts: 0,
+        })
+    }
+
+    /// Expected state of the accounts after all the committed steps.
+    pub fn model(&self) -> &WorkloadModel {
+        &self.model
+    }
+
+    /// Picks the next random action.
+    pub fn next_action(&mut self) -> WorkloadAction {
+        let account_ids: Vec<_> = self.signers.keys().cloned().collect();
+        let signer_id = account_ids[self.rng.gen_range(0, account_ids.len())].clone();
+        match self.rng.gen_range(0, 10) {
+            0 | 1 => {
+                self.num_created_accounts += 1;
+                WorkloadAction::CreateAccount {
+                    new_account_id: format!("workload{}.{}", self.num_created_accounts, signer_id),
+                    signer_id,
+                    amount: NEW_ACCOUNT_AMOUNT,
+                }
+            }
+            2..=5 if account_ids.len() > 1 => {
+                let receiver_id = loop {
+                    let receiver_id = &account_ids[self.rng.gen_range(0, account_ids.len())];
+                    if *receiver_id != signer_id {
+                        break receiver_id.clone();
+                    }
+                };
+                let amount = self.rng.gen_range(1, MAX_TRANSFER_AMOUNT);
+                WorkloadAction::Transfer { signer_id, receiver_id, amount }
+            }
+            _ if self.model.contracts.contains(&signer_id) => WorkloadAction::WriteKeyValue {
+                signer_id,
+                key: self.rng.gen_range(0, NUM_STORAGE_KEYS),
+                value: self.rng.gen(),
+            },
+            _ => WorkloadAction::DeployContract { signer_id },
+        }
+    }
+
+    /// Builds and signs transaction for the action.
+    pub fn transaction(
+        &mut self,
+        action: &WorkloadAction,
+        block_hash: CryptoHash,
+    ) -> SignedTransaction {
+        let signer_id = action.signer_id().clone();
+        let (receiver_id, actions) = match action {
+            WorkloadAction::CreateAccount { new_account_id, amount, .. } => (
+                new_account_id.clone(),
+                vec![
+                    Action::CreateAccount(CreateAccountAction {}),
+                    Action::Transfer(TransferAction { deposit: *amount }),
+                    Action::AddKey(AddKeyAction {
+                        public_key: Workload::new_account_signer(new_account_id).public_key,
+                        access_key: AccessKey::full_access(),
+                    }),
+                ],
+            ),
+            WorkloadAction::Transfer { receiver_id, amount, .. } => {
+                (receiver_id.clone(), vec![Action::Transfer(TransferAction { deposit: *amount })])
+            }
+            WorkloadAction::DeployContract { .. } => (
+                signer_id.clone(),
+                vec![Action::DeployContract(DeployContractAction { code: self.code.clone() })],
+            ),
+            WorkloadAction::WriteKeyValue { key, value, .. } => {
+                let mut args = key.to_le_bytes().to_vec();
+                args.extend_from_slice(&value.to_le_bytes());
+                (
+                    signer_id.clone(),
+                    vec![Action::FunctionCall(FunctionCallAction {
+                        method_name: "write_key_value".to_string(),
+                        args,
+                        gas: CALL_GAS,
+                        deposit: 0,
+                    })],
+                )
+            }
+        };
+        let nonce = self.nonces.get_mut(&signer_id).unwrap();
+        *nonce += 1;
+        SignedTransaction::from_actions(
+            *nonce,
+            signer_id.clone(),
+            receiver_id,
+            self.signers[&signer_id].clone(),
+            actions,
+            block_hash,
+        )
+    }
+
+    /// Generates the next random transaction, commits it through the user and updates the model
+    /// if it succeeded. Returns the action and whether it was applied.
+    pub fn step(&mut self, user: &dyn User) -> Result<(WorkloadAction, bool), String> {
+        let block_hash = user.get_best_block_hash().ok_or("Failed to get the best block")?;
+        let action = self.next_action();
+        let transaction = self.transaction(&action, block_hash);
+        let result = user.commit_transaction(transaction)?;
+        let success = result.status == FinalTransactionStatus::Completed;
+        if success {
+            self.model.apply(&action);
+            if let WorkloadAction::CreateAccount { new_account_id, .. } = &action {
+                let signer = Arc::new(Workload::new_account_signer(new_account_id));
+                self.signers.insert(new_account_id.clone(), signer);
+                self.nonces.insert(new_account_id.clone(), 0);
+            }
+        }
+        Ok((action, success))
+    }
+
+    fn new_account_signer(account_id: &AccountId) -> InMemorySigner {
+        InMemorySigner::from_seed(account_id, KeyType::ED25519, account_id)
+    }
+}
+
+/// Checks that all the running nodes have the same view of the accounts in the model, and that
+/// it matches the model. Nodes should be caught up with each other before calling this.
+pub fn check_convergence(
+    nodes: &[Arc<RwLock<dyn Node>>],
+    model: &WorkloadModel,
+) -> Result<(), String> {
+    let users: Vec<_> = nodes
+        .iter()
+        .filter(|node| node.read().unwrap().is_running())
+        .map(|node| node.read().unwrap().user())
+        .collect();
+    for user in users.iter() {
+        model.check(user.as_ref())?;
+    }
+    for account_id in model.balances.keys() {
+        let views = users
+            .iter()
+            .map(|user| user.view_account(account_id))
+            .collect::<Result<Vec<_>, _>>()?;
+        if views.iter().any(|view| view != &views[0]) {
+            return Err(format!("Nodes disagree on account {}: {:?}", account_id, views));
+        }
+    }
+    Ok(())
+}
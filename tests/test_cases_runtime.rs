@@ -315,4 +315,10 @@ mod test {
         let node = create_runtime_node();
         test_delete_account_while_staking(node);
     }
+
+    #[test]
+    fn test_random_workload_runtime() {
+        let node = create_runtime_node();
+        test_random_workload(node);
+    }
 }
@@ -62,6 +62,11 @@ mod test {
         run_testnet_test!(test_smart_contract_simple);
     }
 
+    #[test]
+    fn test_receipt_origin_testnet() {
+        run_testnet_test!(test_receipt_origin);
+    }
+
     #[test]
     fn test_smart_contract_self_call_testnet() {
         run_testnet_test!(test_smart_contract_self_call);
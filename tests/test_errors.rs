@@ -46,38 +46,66 @@ fn test_check_tx_error_log() {
     let tx_result = node.user().commit_transaction(tx).unwrap_err();
     assert_eq!(
         tx_result,
-        "RpcError { code: -32000, message: \"Server error\", data: Some(String(\"Signer \\\"bob.near\\\" doesn\\'t have access key with the given public_key ed25519:22skMptHjFWNyuEWY22ftn2AbLPSYpmYwGJRGwpNHbTV\")) }".to_string()
+        "RpcError { code: -32000, message: \"Server error\", data: Some(Object({\"InvalidAccessKey\": String(\"Signer \\\"bob.near\\\" doesn\\'t have access key with the given public_key ed25519:22skMptHjFWNyuEWY22ftn2AbLPSYpmYwGJRGwpNHbTV\")})) }".to_string()
     );
 }
 
+#[test]
+fn test_check_tx_structured_errors() {
+    let node = start_node();
+    let signer = Arc::new(InMemorySigner::from_seed("alice.near", KeyType::ED25519, "alice.near"));
+    let block_hash = node.user().get_best_block_hash().unwrap();
+    let send_money = |nonce, deposit| {
+        SignedTransaction::send_money(
+            nonce,
+            "alice.near".to_string(),
+            "bob.near".to_string(),
+            signer.clone(),
+            deposit,
+            block_hash,
+        )
+    };
+
+    let err = node.user().add_transaction(send_money(0, 1)).unwrap_err();
+    assert!(err.contains("InvalidNonce"), "{}", err);
+    let err = node.user().commit_transaction(send_money(1, TESTING_INIT_BALANCE + 1)).unwrap_err();
+    assert!(err.contains("NotEnoughBalance"), "{}", err);
+    let mut tx = send_money(1, 1);
+    tx.transaction.receiver_id = "carol.near".to_string();
+    tx.init();
+    let err = node.user().commit_transaction(tx).unwrap_err();
+    assert!(err.contains("InvalidSignature"), "{}", err);
+}
+
 #[test]
 fn test_deliver_tx_error_log() {
     let node = start_node();
     let signer = Arc::new(InMemorySigner::from_seed("alice.near", KeyType::ED25519, "alice.near"));
     let block_hash = node.user().get_best_block_hash().unwrap();
     let cost = testlib::fees_utils::create_account_transfer_full_key_cost();
-    let tx = SignedTransaction::from_actions(
-        1,
-        "alice.near".to_string(),
-        "test.near".to_string(),
-        signer.clone(),
-        vec![
-            Action::CreateAccount(CreateAccountAction {}),
-            Action::Transfer(TransferAction { deposit: TESTING_INIT_BALANCE + 1 }),
-            Action::AddKey(AddKeyAction {
-                public_key: signer.public_key.clone(),
-                access_key: AccessKey::full_access(),
-            }),
-        ],
-        block_hash,
-    );
-
-    let tx_result = node.user().commit_transaction(tx).unwrap();
-    assert_eq!(
-        tx_result.transactions[0].result.logs[0],
-        format!(
-        "Runtime error: Sender alice.near does not have enough balance 999999950000000 for operation costing {}",
-            TESTING_INIT_BALANCE + 1 + cost
+    let deposit = TESTING_INIT_BALANCE / 2;
+    let tx = |nonce| {
+        SignedTransaction::from_actions(
+            nonce,
+            "alice.near".to_string(),
+            format!("test{}.near", nonce),
+            signer.clone(),
+            vec![
+                Action::CreateAccount(CreateAccountAction {}),
+                Action::Transfer(TransferAction { deposit }),
+                Action::AddKey(AddKeyAction {
+                    public_key: signer.public_key.clone(),
+                    access_key: AccessKey::full_access(),
+                }),
+            ],
+            block_hash,
         )
-    );
+    };
+
+    // Each transaction passes the check against the current state, but not both can be paid for.
+    node.user().add_transaction(tx(1)).unwrap();
+    let tx_result = node.user().commit_transaction(tx(2)).unwrap();
+    let log = &tx_result.transactions[0].result.logs[0];
+    assert!(log.starts_with("Runtime error: Sender alice.near does not have enough balance"));
+    assert!(log.ends_with(&format!("for operation costing {}", deposit + cost)));
 }
@@ -11,6 +11,7 @@ mod test {
     use near_primitives::types::AccountId;
     use testlib::node::{create_nodes, sample_queryable_node, sample_two_nodes, Node, NodeConfig};
     use testlib::test_helpers::{heavy_test, wait, wait_for_catchup};
+    use testlib::workload::{check_convergence, Workload};
 
     fn warmup() {
         Command::new("cargo")
@@ -207,6 +208,56 @@ mod test {
         }
     }
 
+    fn test_kill_1_workload(num_nodes: usize, num_steps: usize, test_prefix: &str) {
+        warmup();
+        // Run random workload while node #2 is down, then check all the nodes converge.
+        let crash = 2;
+        let nodes: Vec<_> =
+            create_nodes(num_nodes, test_prefix).into_iter().map(Node::new_sharable).collect();
+        for node in nodes.iter() {
+            node.write().unwrap().start();
+        }
+        let signers: Vec<_> = nodes
+            .iter()
+            .map(|node| {
+                let node = node.read().unwrap();
+                (node.account_id().unwrap(), node.signer())
+            })
+            .collect();
+        let mut workload =
+            Workload::new(rand::random(), signers, nodes[0].read().unwrap().user().as_ref())
+                .unwrap();
+
+        let mut num_applied = 0;
+        for step in 0..num_steps {
+            if step == num_steps / 3 {
+                println!("Killing node {}", crash);
+                nodes[crash].write().unwrap().kill();
+            }
+            if step == 2 * num_steps / 3 {
+                println!("Restarting node {}", crash);
+                nodes[crash].write().unwrap().start();
+            }
+            let k = sample_queryable_node(&nodes);
+            let (action, success) =
+                workload.step(nodes[k].read().unwrap().user().as_ref()).unwrap();
+            println!("STEP #{} {:?}: {}", step, action, success);
+            if success {
+                num_applied += 1;
+            }
+        }
+        assert!(
+            num_applied * 2 >= num_steps,
+            "Only {} of {} steps applied",
+            num_applied,
+            num_steps
+        );
+
+        wait_for_catchup(&nodes);
+        wait(|| check_convergence(&nodes, workload.model()).is_ok(), 1000, 60000);
+        assert_eq!(check_convergence(&nodes, workload.model()), Ok(()));
+    }
+
     #[test]
     fn test_4_20_kill1() {
         heavy_test(|| test_kill_1(4, 10, "4_10_kill1"));
@@ -217,4 +268,9 @@ mod test {
     fn test_4_20_kill2() {
         heavy_test(|| test_kill_2(4, 5, "4_10_kill2"));
     }
+
+    #[test]
+    fn test_4_30_kill1_workload() {
+        heavy_test(|| test_kill_1_workload(4, 30, "4_30_kill1_workload"));
+    }
 }